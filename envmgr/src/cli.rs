@@ -1,24 +1,72 @@
 use clap::{Parser, ValueEnum};
 
+use crate::{
+    config::{AliasConfig, validate::is_valid_env_var_key},
+    environment::TargetFilter,
+    integrations::IntegrationKind,
+};
+
 /// Shells supported by envmgr hooks.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
 pub enum Shell {
     Fish,
+    #[value(name = "powershell")]
+    PowerShell,
+}
+
+fn parse_glob(pattern: &str) -> Result<globset::Glob, String> {
+    TargetFilter::glob(pattern).map_err(|e| e.to_string())
 }
 
 /// Quote a string for safe use in fish shell commands.
 fn fish_quote(value: &str) -> String {
-    // Basic single-quote escaping for fish: ' -> '\''
+    // Inside fish single quotes only \\ and \' are escapes: ' -> \' and \ -> \\
     // Also sanitize newlines and carriage returns (replace with spaces)
     if value.is_empty() {
         "''".to_string()
     } else {
         let sanitized = value.replace(['\n', '\r'], " ");
-        let escaped = sanitized.replace('\'', "\\'");
+        let escaped = sanitized.replace('\\', "\\\\").replace('\'', "\\'");
         format!("'{}'", escaped)
     }
 }
 
+/// Characters PowerShell takes as a single quote: the ASCII one and U+2018 to U+201B
+const POWERSHELL_SINGLE_QUOTES: [char; 5] = ['\'', '\u{2018}', '\u{2019}', '\u{201A}', '\u{201B}'];
+
+/// Quote a string for safe use in PowerShell commands.
+fn powershell_quote(value: &str) -> String {
+    // Single-quoted strings are verbatim in PowerShell: `$` and backticks are not
+    // interpreted, the only escape is doubling the quote itself. Any of the quote
+    // characters ends the string, so each is doubled.
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('\'');
+    for c in value.chars() {
+        if POWERSHELL_SINGLE_QUOTES.contains(&c) {
+            quoted.push(c);
+        }
+        quoted.push(c);
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// `$env:<key>`, or `${env:<key>}` with backticks escaping `{`, `}` and backticks when
+/// `key` isn't a plain variable name, so it can't end the variable early
+fn powershell_env_var(key: &str) -> String {
+    if is_valid_env_var_key(key) {
+        return format!("$env:{key}");
+    }
+    let mut escaped = String::with_capacity(key.len());
+    for c in key.chars() {
+        if matches!(c, '{' | '}' | '`') {
+            escaped.push('`');
+        }
+        escaped.push(c);
+    }
+    format!("${{env:{escaped}}}")
+}
+
 impl Shell {
     /// Generate a shell command to set an environment variable.
     pub fn set_env_var_cmd(&self, key: &str, value: &str) -> String {
@@ -27,6 +75,9 @@ impl Shell {
                 // Fish: export (-x) and make global (-g)
                 format!("set -gx {} {}", key, fish_quote(value))
             }
+            Shell::PowerShell => {
+                format!("{} = {}", powershell_env_var(key), powershell_quote(value))
+            }
         }
     }
     /// Generate a shell command to unset an environment variable.
@@ -36,8 +87,67 @@ impl Shell {
                 // Fish: erase the global/exported variable if set
                 format!("set -e -g {}", key)
             }
+            Shell::PowerShell if is_valid_env_var_key(key) => {
+                format!("Remove-Item Env:{key} -ErrorAction SilentlyContinue")
+            }
+            Shell::PowerShell => format!(
+                "Remove-Item -LiteralPath {} -ErrorAction SilentlyContinue",
+                powershell_quote(&format!("Env:{key}"))
+            ),
         }
     }
+
+    /// Generate a shell command defining `alias`.
+    pub fn set_alias_cmd(&self, alias: &AliasConfig) -> String {
+        match self {
+            Shell::Fish if alias.abbr => {
+                format!("abbr -a -g {} {}", alias.name, fish_quote(&alias.command))
+            }
+            // Fish: a function wrapping the command, arguments are appended
+            Shell::Fish => format!("alias {} {}", alias.name, fish_quote(&alias.command)),
+            // PowerShell aliases can't take arguments, a function can. The body is
+            // passed as a verbatim string so its quotes and pipes stay part of it.
+            Shell::PowerShell => format!(
+                "Set-Item -Path Function:global:{} -Value ([scriptblock]::Create({}))",
+                alias.name,
+                powershell_quote(&format!("{} @args", alias.command))
+            ),
+        }
+    }
+    /// Generate a shell command removing `alias`.
+    pub fn unset_alias_cmd(&self, alias: &AliasConfig) -> String {
+        match self {
+            Shell::Fish if alias.abbr => format!("abbr -e {}", alias.name),
+            Shell::Fish => format!("functions -e {}", alias.name),
+            Shell::PowerShell => format!(
+                "Remove-Item Function:global:{} -ErrorAction SilentlyContinue",
+                alias.name
+            ),
+        }
+    }
+
+    /// Hook re-applying the current environment on every prompt, printed by `envmgr hook`
+    pub fn hook_script(&self, bin_name: &str) -> String {
+        match self {
+            Shell::Fish => indoc::indoc! {r#"
+            # envmgr fish hook
+
+            # Re-apply env on prompt draw
+            function __envmgr_export_eval --on-event fish_prompt
+                command BIN_NAME use --hook | source
+            end"#},
+            Shell::PowerShell => indoc::indoc! {r#"
+            # envmgr PowerShell hook
+
+            # Re-apply env on prompt draw
+            $global:__envmgr_original_prompt = $function:prompt
+            function global:prompt {
+                & BIN_NAME use --shell powershell --hook | Out-String | Invoke-Expression
+                & $global:__envmgr_original_prompt
+            }"#},
+        }
+        .replace("BIN_NAME", bin_name)
+    }
 }
 
 #[cfg(test)]
@@ -59,6 +169,12 @@ mod tests {
         assert_eq!(fish_quote("it's"), r#"'it\'s'"#);
     }
 
+    #[test]
+    fn test_fish_quote_with_backslashes() {
+        assert_eq!(fish_quote(r"C:\temp\"), r"'C:\\temp\\'");
+        assert_eq!(fish_quote(r"\'"), r"'\\\''");
+    }
+
     #[test]
     fn test_fish_quote_with_newline() {
         assert_eq!(fish_quote("line1\nline2"), "'line1 line2'");
@@ -106,14 +222,165 @@ mod tests {
         let shell = Shell::Fish;
         assert_eq!(shell.unset_env_var_cmd("MY_VAR"), "set -e -g MY_VAR");
     }
+
+    #[test]
+    fn test_switch_accepts_dash_for_previous() {
+        let args = Args::try_parse_from(["envmgr", "switch", "-"]).unwrap();
+        assert!(matches!(args.command, Command::Switch { name: Some(ref n), .. } if n == "-"));
+    }
+
+    #[test]
+    fn test_switch_only_parses_integration_list() {
+        let args = Args::try_parse_from(["envmgr", "switch", "work", "--only", "gh_cli,tailscale"])
+            .unwrap();
+        let Command::Switch { only, .. } = args.command else {
+            panic!("expected switch");
+        };
+        assert_eq!(
+            only,
+            Some(vec![IntegrationKind::GhCli, IntegrationKind::Tailscale])
+        );
+
+        let err = Args::try_parse_from(["envmgr", "switch", "work", "--only", "gh"]).unwrap_err();
+        assert!(err.to_string().contains(
+            "possible values: op_ssh, gh_cli, git, kube, aws, gcloud, npm, ssh, gpg, mise, maven, python, cargo, tailscale"
+        ));
+        assert!(
+            Args::try_parse_from(["envmgr", "switch", "--no-integrations", "--only", "gh_cli"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_dry_run_is_global() {
+        let args = Args::try_parse_from(["envmgr", "prune", "--dry-run"]).unwrap();
+        assert!(args.dry_run && matches!(args.command, Command::Prune));
+        let args = Args::try_parse_from(["envmgr", "--dry-run", "switch", "work"]).unwrap();
+        assert!(args.dry_run);
+    }
+
+    #[test]
+    fn test_powershell_quote_simple() {
+        assert_eq!(powershell_quote("hello"), "'hello'");
+        assert_eq!(powershell_quote(""), "''");
+    }
+
+    #[test]
+    fn test_powershell_quote_with_single_quotes() {
+        assert_eq!(powershell_quote("it's"), "'it''s'");
+    }
+
+    #[test]
+    fn test_powershell_quote_keeps_dollar_and_backtick_literal() {
+        assert_eq!(powershell_quote("$HOME`n"), "'$HOME`n'");
+    }
+
+    #[test]
+    fn test_powershell_quote_doubles_every_single_quote() {
+        for quote in ['\u{2018}', '\u{2019}', '\u{201A}', '\u{201B}'] {
+            let value = format!("a{quote}; Remove-Item ~ -Recurse; {quote}");
+            assert_eq!(
+                powershell_quote(&value),
+                format!("'a{quote}{quote}; Remove-Item ~ -Recurse; {quote}{quote}'"),
+                "U+{:04X}",
+                quote as u32
+            );
+        }
+    }
+
+    #[test]
+    fn test_powershell_keys_cannot_break_out() {
+        let shell = Shell::PowerShell;
+        assert_eq!(
+            shell.set_env_var_cmd("X; Remove-Item ~", "v"),
+            "${env:X; Remove-Item ~} = 'v'"
+        );
+        assert_eq!(
+            shell.set_env_var_cmd("X}; iex `$y {", "v"),
+            "${env:X`}; iex ``$y `{} = 'v'"
+        );
+        assert_eq!(
+            shell.unset_env_var_cmd("X'; iex $y"),
+            "Remove-Item -LiteralPath 'Env:X''; iex $y' -ErrorAction SilentlyContinue"
+        );
+    }
+
+    #[test]
+    fn test_powershell_set_env_var_cmd() {
+        let shell = Shell::PowerShell;
+        assert_eq!(
+            shell.set_env_var_cmd("PATH", "C:\\tools;$env:PATH"),
+            "$env:PATH = 'C:\\tools;$env:PATH'"
+        );
+    }
+
+    #[test]
+    fn test_powershell_unset_env_var_cmd() {
+        let shell = Shell::PowerShell;
+        assert_eq!(
+            shell.unset_env_var_cmd("MY_VAR"),
+            "Remove-Item Env:MY_VAR -ErrorAction SilentlyContinue"
+        );
+    }
 }
 
 #[derive(Parser, Debug)]
 pub struct Args {
+    /// Config directory to use instead of ~/.config/envmgr.
+    /// Can also be set with ENVMGR_CONFIG_DIR; the flag wins.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config_dir: Option<std::path::PathBuf>,
+    /// Print what `switch`, `link`, `unlink`, `use`, `prune` and `merge` would change instead of changing it.
+    /// Nothing is written to the home directory, external tools or the state.
+    #[arg(long, global = true)]
+    pub dry_run: bool,
     #[command(subcommand)]
     pub command: Command,
 }
 
+impl Command {
+    /// Whether the command was asked for JSON output, so errors are reported as JSON too
+    pub fn wants_json(&self) -> bool {
+        matches!(
+            self,
+            Command::Diff { json: true, .. }
+                | Command::List { json: true, .. }
+                | Command::Show { json: true, .. }
+                | Command::Status { json: true }
+                | Command::History { json: true }
+                | Command::Prompt { json: true, .. }
+                | Command::Notices { json: true, .. }
+                | Command::Backups {
+                    action: BackupsCommand::List { json: true }
+                }
+                | Command::Files {
+                    action: FilesCommand::Conflicts { json: true, .. }
+                }
+        )
+    }
+
+    /// Whether the banner about new notices may be shown before running the command.
+    ///
+    /// Not for commands the shell runs on its own or whose output is parsed.
+    pub fn shows_notices_banner(&self) -> bool {
+        !self.wants_json()
+            && !matches!(
+                self,
+                Command::Hook { .. }
+                    | Command::Use { .. }
+                    | Command::Prompt { .. }
+                    | Command::Notices { .. }
+                    | Command::Daemon { .. }
+                    | Command::Watch { .. }
+                    | Command::Completions { .. }
+                    | Command::Direnv {
+                        action: DirenvCommand::Lib
+                    }
+                    | Command::CompleteEnvs
+            )
+    }
+}
+
 #[derive(clap::Subcommand, Debug)]
 pub enum Command {
     /// Initialize the environment manager
@@ -125,39 +392,515 @@ pub enum Command {
     /// Output shell hook for integration
     ///
     /// For fish shell, run: `envmgr hook fish | source`
-    /// Other shells not yet supported.
+    /// For PowerShell, run: `envmgr hook powershell | Out-String | Invoke-Expression`
     Hook {
         /// Target shell to output hook for
         #[arg(value_enum)]
         shell: Shell,
     },
     /// Add a new environment
+    ///
+    /// Prompts for anything not given as a flag unless `--no-interactive` is set.
     Add {
         /// Name of the new environment
         name: String,
+        /// Key (directory name) of the environment, derived from the name by default
+        #[arg(long)]
+        key: Option<String>,
+        /// GitHub host for the gh CLI integration
+        #[arg(long)]
+        gh_host: Option<String>,
+        /// GitHub user for the gh CLI integration
+        #[arg(long)]
+        gh_user: Option<String>,
+        /// Tailnet for the Tailscale integration
+        #[arg(long)]
+        tailnet: Option<String>,
+        /// 1Password vault of the SSH key
+        #[arg(long)]
+        op_vault: Option<String>,
+        /// 1Password item of the SSH key
+        #[arg(long)]
+        op_item: Option<String>,
+        /// 1Password account of the SSH key
+        #[arg(long)]
+        op_account: Option<String>,
+        /// Named gcloud configuration activated on switch
+        #[arg(long)]
+        gcloud_configuration: Option<String>,
+        /// Never prompt; fail when a required value is missing
+        #[arg(long)]
+        no_interactive: bool,
+        /// Pre-populate integrations from the current gh, tailscale, 1Password and gcloud setup
+        #[arg(long)]
+        from_current: bool,
+        /// Pre-fill the integration settings from an environment or installed remote template
+        ///
+        /// Env vars and files are not copied; every value can be adjusted before writing.
+        #[arg(long, value_name = "ENV", conflicts_with = "from_current")]
+        template: Option<String>,
+    },
+    /// Show the effective configuration of an environment, base layered in
+    ///
+    /// Values of variables marked `secret: true` are shown as `********`.
+    Show {
+        /// Environment to show, the current one by default (`base` allowed)
+        key: Option<String>,
+        /// Output the details as JSON
+        #[arg(long)]
+        json: bool,
+        /// Add tool versions, gh users and the tailnet, from the daemon's cache while fresh
+        #[arg(long)]
+        live: bool,
+    },
+    /// Show the current environment and the links envmgr manages, by environment
+    Status {
+        /// Output the status as JSON
+        #[arg(long)]
+        json: bool,
     },
     /// List all environments
-    List,
+    ///
+    /// The table layout defaults to `list:` in global.yaml; the flags override it.
+    List {
+        /// Output a JSON array of environment summaries, unaffected by the layout settings
+        #[arg(long)]
+        json: bool,
+        /// Order of the environments
+        #[arg(long, value_enum)]
+        sort: Option<crate::commands::list::ListSort>,
+        /// Show the environments in sections
+        #[arg(long, value_enum)]
+        group_by: Option<crate::commands::list::ListGroup>,
+        /// Comma-separated columns to show
+        #[arg(
+            long,
+            value_delimiter = ',',
+            value_parser = clap::builder::PossibleValuesParser::new(crate::commands::list::column_names()),
+        )]
+        columns: Option<Vec<String>>,
+    },
     /// Remove an environment
     Remove {
         /// Name of the environment to remove
         name: String,
     },
+    /// Merge one environment into another and archive the source
+    ///
+    /// The merged result is shown as a diff and only applied after confirmation.
+    Merge {
+        /// Environment to merge from; archived afterwards
+        source: String,
+        /// Environment to merge into
+        dest: String,
+        /// Resolve all conflicts in favour of one side instead of prompting
+        #[arg(long, value_enum)]
+        prefer: Option<crate::commands::merge::Prefer>,
+        /// Apply the merge without asking for confirmation
+        #[arg(long, short)]
+        yes: bool,
+        /// Apply without asking, but only if the plan still matches the token
+        /// printed by a previous `--dry-run`
+        #[arg(long, value_name = "TOKEN", conflicts_with = "yes")]
+        confirm_token: Option<String>,
+    },
     /// Activate the current environment
-    Use,
+    Use {
+        /// Shell to emit commands for
+        #[arg(long, value_enum, default_value_t = Shell::Fish)]
+        shell: Shell,
+        /// Don't run `value_from_command` or read `value_from_file`, keep the values those
+        /// variables already have
+        #[arg(long)]
+        skip_dynamic: bool,
+        /// Set `op://` secret references to a placeholder instead of reading them with `op`
+        #[arg(long)]
+        no_secrets: bool,
+        /// Run by the prompt hook: errors are also recorded for `envmgr notices`
+        #[arg(long, hide = true)]
+        hook: bool,
+    },
+    /// Write the effective env vars of an environment to a file for containers and CI
+    ///
+    /// Variables are merged from the base and the environment exactly like `use` does.
+    /// Dynamic values are resolved; secret references are left out unless
+    /// `--resolve-secrets` is given.
+    ExportEnv {
+        /// Environment to export, the current one by default (`base` allowed)
+        key: Option<String>,
+        /// Format of the file
+        #[arg(long, value_enum, default_value_t = crate::commands::export_env::ExportFormat::Dotenv)]
+        format: crate::commands::export_env::ExportFormat,
+        /// File to write, created readable only by you; stdout by default
+        #[arg(short, long)]
+        output: Option<std::path::PathBuf>,
+        /// Read `op://` references with `op` and include their values
+        #[arg(long)]
+        resolve_secrets: bool,
+        /// Include variables marked `secret: true`, which are left out by default
+        #[arg(long)]
+        include_secrets: bool,
+    },
+    /// Load an environment's variables in project dirs through direnv
+    Direnv {
+        #[command(subcommand)]
+        action: DirenvCommand,
+    },
+    /// Print the current environment for shell prompts
+    ///
+    /// Only reads the state file, so it is cheap enough to run on every prompt draw.
+    /// A trailing `!` marks a danger environment.
+    Prompt {
+        /// Output `{key, name, danger, verified, stack_depth}` as JSON
+        #[arg(long)]
+        json: bool,
+        #[command(subcommand)]
+        generate: Option<PromptCommand>,
+    },
     /// Link files for the active environment
-    Link,
+    Link {
+        /// Only remove managed links without creating new ones, e.g. before handing a machine back
+        #[arg(long, conflicts_with = "adopt_backups")]
+        prune_only: bool,
+        /// Move real files in the way of links into the state dir's `backups/` and link
+        /// anyway. They are put back when the link is removed.
+        #[arg(long)]
+        adopt_backups: bool,
+        /// With --dry-run, also show the source of each link and the links that are
+        /// already in place
+        #[arg(long, short)]
+        verbose: bool,
+        /// Only link and clean up targets whose path relative to the home dir matches this
+        /// glob, e.g. `.config/fish/**`. Can be given more than once.
+        #[arg(long, value_name = "GLOB", value_parser = parse_glob, conflicts_with = "prune_only")]
+        only: Vec<globset::Glob>,
+        /// Leave targets matching this glob as they are, applied after `--only`. Can be
+        /// given more than once.
+        #[arg(long, value_name = "GLOB", value_parser = parse_glob, conflicts_with = "prune_only")]
+        exclude: Vec<globset::Glob>,
+    },
+    /// Remove managed links and copies, keeping the rest with `--env`
+    Unlink {
+        /// Only remove those of this environment, `base` included
+        #[arg(long)]
+        env: Option<String>,
+    },
+    /// Show past environment switches, newest first
+    History {
+        /// Output the raw entries as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// List or restore files that linking moved aside
+    Backups {
+        #[command(subcommand)]
+        action: BackupsCommand,
+    },
+    /// Inspect how base and an environment's files layer
+    Files {
+        #[command(subcommand)]
+        action: FilesCommand,
+    },
+    /// Show problems the prompt hook ran into since they were last cleared
+    ///
+    /// Errors of `use` run by the hook are easy to miss; they are kept here, one
+    /// entry per error code and environment, until `notices clear`.
+    Notices {
+        #[command(subcommand)]
+        action: Option<NoticesCommand>,
+        /// Output the entries as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the JSON Schema of a config file, e.g. for the YAML language server
+    Schema {
+        /// Which file to print the schema for
+        #[arg(value_enum, default_value_t = crate::commands::schema::SchemaKind::Environment)]
+        kind: crate::commands::schema::SchemaKind,
+        /// Write all schemas to ~/.config/envmgr/schemas instead of printing one.
+        /// Environments created by `add` afterwards reference it.
+        #[arg(long)]
+        write: bool,
+    },
+    /// Inspect, test or apply a single integration without a full switch
+    Integrations {
+        #[command(subcommand)]
+        action: IntegrationsCommand,
+    },
+    /// Remove dangling managed links and forget managed files that no longer exist
+    ///
+    /// With the global `--dry-run`, only prints what would be done.
+    Prune,
     /// Switch to a different environment
+    ///
+    /// Without a name, an interactive picker over all environments is shown.
+    /// Use `-` to return to the previously active environment.
     Switch {
-        /// Name of the environment to switch to
-        name: String,
+        /// Name of the environment to switch to, or `-` for the previous one
+        name: Option<String>,
+        /// Don't link the environment's files or write its 1Password documents
+        #[arg(long)]
+        no_link: bool,
+        /// Don't run any integrations
+        #[arg(long, conflicts_with = "only")]
+        no_integrations: bool,
+        /// Only run these integrations, e.g. `--only gh_cli,tailscale`
+        #[arg(long, value_enum, value_delimiter = ',', value_name = "INTEGRATIONS")]
+        only: Option<Vec<IntegrationKind>>,
+        /// Move real files in the way of links aside instead of skipping them, see `link`
+        #[arg(long, conflicts_with = "no_link")]
+        adopt_backups: bool,
+        /// Don't run the environment's `hooks/` scripts
+        #[arg(long)]
+        no_hooks: bool,
+        /// Run login commands integrations need, like `tailscale login` for a tailnet
+        /// or `gh auth login` for a user this machine has no account for. Implied
+        /// when attached to a terminal.
+        #[arg(long)]
+        login: bool,
+        /// Check every `op_ssh` key resolves to a 1Password SSH Key item before switching
+        #[arg(long)]
+        check_op_keys: bool,
+        /// Run the remaining integrations when one fails and report every error at the
+        /// end, instead of rolling back the ones that ran and staying in the current
+        /// environment
+        #[arg(long)]
+        continue_on_error: bool,
+    },
+    /// Check the current environment's setup for problems
+    ///
+    /// Checks that every `op_ssh` key resolves to exactly one 1Password SSH Key item,
+    /// whether the `gh_cli` users and the `tailscale` tailnet are the active ones,
+    /// which `mise` versions still need to be installed, and on Linux whether
+    /// `~/.config/environment.d/50-envmgr.conf` matches the environment.
+    Doctor {
+        /// Don't ask the 1Password CLI about the `op_ssh` keys
+        #[arg(long)]
+        skip_op_keys: bool,
+    },
+    /// Guided first run: set up base, a demo environment, the hook, and switch to it
+    ///
+    /// Each step explains what it changes on disk and can be skipped. Progress is
+    /// saved, so running it again continues where it stopped.
+    Walkthrough {
+        /// Work in a throwaway directory instead of your real config and home
+        #[arg(long)]
+        sandbox: bool,
+        /// Start over instead of continuing
+        #[arg(long)]
+        restart: bool,
+    },
+    /// Install, list and update environment templates shared through git
+    Template {
+        #[command(subcommand)]
+        action: TemplateCommand,
+    },
+    /// Create or replay sanitized snapshots of the config and state for bug reports
+    DebugBundle {
+        #[command(subcommand)]
+        action: DebugBundleCommand,
+    },
+    /// Periodically refresh the probe cache in the background
+    ///
+    /// Slow probes (tool versions, gh users, tailnet, drift hashes) are cached
+    /// in the state directory so `prompt`, `show --live` and `doctor` can read them cheaply.
+    Daemon {
+        /// Seconds between refreshes
+        #[arg(long, default_value_t = 300)]
+        interval: u64,
+        /// Print a systemd user unit running the daemon instead of starting it
+        #[arg(long)]
+        systemd_unit: bool,
+    },
+    /// Re-link the active environment whenever its config changes
+    ///
+    /// Watches config.yaml, local.yaml, files.yaml and files/ of base and the
+    /// active environment, plus global.yaml. Environment variables are picked
+    /// up by the next `envmgr use`, which the shell hook runs at every prompt.
+    Watch {
+        /// Milliseconds without changes before re-linking
+        #[arg(long, default_value_t = 500)]
+        debounce_ms: u64,
+        /// Shell command to run after each successful re-link
+        #[arg(long, value_name = "CMD")]
+        exec: Option<String>,
+    },
+    /// Explain an error code in detail
+    ///
+    /// Error messages are prefixed with a code such as `[E020]`.
+    Explain {
+        /// Error code to explain, e.g. E020
+        code: String,
+    },
+    /// Validate the base and all environment configs
+    ///
+    /// Exits with a non-zero status when any error is found, suitable for
+    /// pre-commit hooks.
+    Validate,
+    /// Compare two environments
+    ///
+    /// Shows env vars, files and integration settings that differ between
+    /// the effective configuration of both environments.
+    Diff {
+        /// Environment to compare from (`base` allowed)
+        env_a: String,
+        /// Environment to compare to (`base` allowed)
+        env_b: String,
+        /// Output the diff as JSON
+        #[arg(long)]
+        json: bool,
     },
-    /// Health check command
-    Doctor,
     /// Generate shell completions
     Completions {
         /// Target shell to generate completions for
         #[arg(value_enum)]
         shell: clap_complete::Shell,
     },
+    /// List environment keys, one per line, for shell completions
+    // Not `__complete-envs`: clap_complete's bash generator panics on a `__` prefix
+    #[command(name = "complete-envs", hide = true)]
+    CompleteEnvs,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum BackupsCommand {
+    /// Show backups, newest first
+    List {
+        /// Output the entries as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Put the latest backup of a file back, replacing envmgr's link
+    Restore {
+        /// The original path or the backup file
+        path: std::path::PathBuf,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum FilesCommand {
+    /// List targets both base and the environment provide, with the source that wins
+    Conflicts {
+        /// Environment to compare with base, the current one by default
+        #[arg(long)]
+        env: Option<String>,
+        /// Output the conflicts as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum NoticesCommand {
+    /// Acknowledge and remove all notices
+    Clear,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum DirenvCommand {
+    /// Write `use envmgr <env>` into a project's .envrc, between marker comments
+    ///
+    /// Lines outside the markers are left as they are. Needs the library of
+    /// `envmgr direnv lib` installed, and a `direnv allow` afterwards.
+    Generate {
+        /// Environment the .envrc loads (`base` allowed)
+        env: String,
+        /// Project dir or .envrc file, the current dir by default
+        #[arg(long)]
+        path: Option<std::path::PathBuf>,
+    },
+    /// Print the direnv library defining `use envmgr`
+    ///
+    /// Run: `envmgr direnv lib > ~/.config/direnv/lib/envmgr.sh`
+    Lib,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum TemplateCommand {
+    /// Clone a template repo, or update it when already installed
+    Install {
+        /// Git url of a repo with a config.yaml at its root
+        url: String,
+        /// Alias to install the template as, derived from the url by default
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// List local (environment) and remote templates with their pinned commits
+    List,
+    /// Pull remote templates, keeping the cached clone when offline
+    Update {
+        /// Template to update, all remote templates by default
+        alias: Option<String>,
+    },
+    /// Let an environment created from a remote template apply its integrations
+    Trust {
+        /// Environment to trust, once its config has been reviewed
+        env: String,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum DebugBundleCommand {
+    /// Package the config dir and state file, with secrets redacted, as a .tar.gz
+    ///
+    /// Contents of `files/` are replaced by size and hash stubs unless `--include-files`
+    /// is given. Dotenv files keep only their keys, and anything that looks like a
+    /// secret is left out either way.
+    Create {
+        /// Bundle to write
+        out: std::path::PathBuf,
+        /// Include the contents of `files/` dirs
+        #[arg(long)]
+        include_files: bool,
+    },
+    /// Rebuild a config and state dir from a bundle in an empty directory
+    ///
+    /// Prints the environment variables that point envmgr at the result.
+    Replay {
+        /// Bundle to replay
+        bundle: std::path::PathBuf,
+        /// Directory to create the config and state dirs in
+        dir: std::path::PathBuf,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum IntegrationsCommand {
+    /// List integrations, whether their tools are installed and which environments use them
+    List,
+    /// Show what applying an integration would change, without changing anything
+    Test {
+        /// Integration to test
+        #[arg(value_enum)]
+        name: IntegrationKind,
+        /// Environment to test against, the current one by default
+        #[arg(long)]
+        env: Option<String>,
+    },
+    /// Apply only one integration, leaving env vars, files and the current environment alone
+    Run {
+        /// Integration to apply
+        #[arg(value_enum)]
+        name: IntegrationKind,
+        /// Environment whose configuration is applied, the current one by default
+        #[arg(long)]
+        env: Option<String>,
+    },
+    /// Let `switch` apply an integration again after repeated failures quarantined it
+    Unquarantine {
+        /// Integration to re-enable
+        #[arg(value_enum)]
+        name: IntegrationKind,
+        /// Environment it was quarantined in, the current one by default
+        #[arg(long)]
+        env: Option<String>,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum PromptCommand {
+    /// Print a `[custom.envmgr]` module to paste into starship.toml
+    StarshipConfig,
+    /// Print a command segment to paste into an oh-my-posh config
+    OhMyPoshConfig,
 }
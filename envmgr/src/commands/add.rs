@@ -0,0 +1,1332 @@
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+use crate::{
+    commands::{schema::installed_environment_schema, template::TemplateRegistry},
+    config::{BASE_ENV_NAME, ENV_CONFIG_FILE_NAME, EnvironmentConfig, envmgr_config_dir},
+    error::{EnvMgrError, EnvMgrResult},
+    integrations::{
+        gcloud::{Gcloud, GcloudConfig},
+        gh_cli::{GhCli, GhCliConfig, GhCliHostUser},
+        one_password_ssh_agent::{
+            OnePasswordSSHAgent, OnePasswordSSHAgentConfig, OnePasswordSSHKey, OpItemList,
+            OpSshKeyItem, SshKeyItemSource,
+        },
+        tailscale::{Tailscale, TailscaleConfig},
+    },
+    prompt::{Prompter, open_in_editor},
+    runner::SystemRunner,
+};
+
+const DEFAULT_GH_HOST: &str = "github.com";
+
+/// Values for `envmgr add`, pre-filled from CLI flags
+#[derive(Debug, Clone, Default)]
+pub struct AddOptions {
+    pub name: String,
+    pub key: Option<String>,
+    pub gh_host: Option<String>,
+    pub gh_user: Option<String>,
+    pub tailnet: Option<String>,
+    pub op_vault: Option<String>,
+    pub op_item: Option<String>,
+    pub op_account: Option<String>,
+    pub gcloud_configuration: Option<String>,
+    /// Never prompt; fail on missing values instead
+    pub no_interactive: bool,
+    /// Pre-populate integrations from the live machine configuration
+    pub from_current: bool,
+    /// Environment key or remote template alias whose integration settings pre-fill the prompts
+    pub template: Option<String>,
+}
+
+impl AddOptions {
+    fn has_integration_flags(&self) -> bool {
+        self.gh_host.is_some()
+            || self.gh_user.is_some()
+            || self.tailnet.is_some()
+            || self.op_vault.is_some()
+            || self.op_item.is_some()
+            || self.op_account.is_some()
+            || self.gcloud_configuration.is_some()
+    }
+}
+
+/// Integration settings detected on the current machine
+#[derive(Debug, Clone, Default)]
+pub struct CurrentSetup {
+    pub gh_cli: Option<GhCliConfig>,
+    pub tailscale: Option<TailscaleConfig>,
+    pub op_ssh: Option<OnePasswordSSHAgentConfig>,
+    pub gcloud: Option<GcloudConfig>,
+}
+
+impl CurrentSetup {
+    /// Read the active gh users, tailnet, 1Password SSH keys and gcloud configuration,
+    /// skipping unavailable tools
+    pub fn detect() -> Self {
+        let mut current = Self::default();
+        match GhCli::active_users() {
+            Ok(users) if !users.is_empty() => {
+                current.gh_cli = Some(GhCliConfig {
+                    hosts: users
+                        .into_iter()
+                        .map(|(host, user)| GhCliHostUser {
+                            host,
+                            user,
+                            git_protocol: None,
+                        })
+                        .collect(),
+                    gh_config: Default::default(),
+                });
+            }
+            Ok(_) => info!("No active gh users found, skipping gh_cli"),
+            Err(e) => info!("Could not read gh users, skipping gh_cli: {e}"),
+        }
+        match Tailscale::active_tailnet(&SystemRunner) {
+            Ok(Some(tailnet)) => {
+                current.tailscale = Some(TailscaleConfig {
+                    tailnet,
+                    ..Default::default()
+                })
+            }
+            Ok(None) => info!("No active tailnet found, skipping tailscale"),
+            Err(e) => info!("Could not read the active tailnet, skipping tailscale: {e}"),
+        }
+        match OnePasswordSSHAgent::current_keys() {
+            Ok(keys) if !keys.is_empty() => {
+                current.op_ssh = Some(OnePasswordSSHAgentConfig { keys });
+            }
+            Ok(_) => info!("No 1Password SSH keys configured, skipping op_ssh"),
+            Err(e) => info!("Could not read the 1Password agent config, skipping op_ssh: {e}"),
+        }
+        match Gcloud::active_configuration(&SystemRunner) {
+            Ok(gcloud) => current.gcloud = gcloud,
+            Err(e) => info!("Could not read the gcloud configurations, skipping gcloud: {e}"),
+        }
+        current
+    }
+}
+
+/// Values the interactive prompts offer to pick from, so tests can hand in canned ones
+pub trait ChoiceSource {
+    /// The host/user pairs gh is logged in with
+    fn gh_users(&self) -> Vec<GhCliHostUser>;
+    /// The tailnets tailscale has an account for
+    fn tailnets(&self) -> Vec<String>;
+    /// The SSH Key items in 1Password
+    fn op_ssh_keys(&self) -> Vec<OpSshKeyItem>;
+}
+
+/// [`ChoiceSource`] asking gh, tailscale and op, offering nothing to pick from when one
+/// is missing or fails so the value is typed instead
+pub struct MachineChoices;
+
+impl ChoiceSource for MachineChoices {
+    fn gh_users(&self) -> Vec<GhCliHostUser> {
+        GhCli::authenticated_users().unwrap_or_else(|e| {
+            info!("Could not read the gh users to pick from: {e}");
+            vec![]
+        })
+    }
+
+    fn tailnets(&self) -> Vec<String> {
+        Tailscale::tailnets(&SystemRunner).unwrap_or_else(|e| {
+            info!("Could not list the tailnets to pick from: {e}");
+            vec![]
+        })
+    }
+
+    fn op_ssh_keys(&self) -> Vec<OpSshKeyItem> {
+        OpItemList::new(&SystemRunner)
+            .ssh_key_items(None)
+            .unwrap_or_else(|e| {
+                info!("Could not list the 1Password SSH keys to pick from: {e}");
+                vec![]
+            })
+    }
+}
+
+/// What `envmgr add` ended up doing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddOutcome {
+    Created(PathBuf),
+    /// The key was taken and the user opened the existing environment instead
+    OpenedExisting(PathBuf),
+    Aborted,
+}
+
+/// Result of the interactive part of `add`, before anything is written
+enum Draft {
+    New(String, Box<EnvironmentConfig>),
+    OpenExisting(String),
+    Aborted,
+}
+
+/// Create a new environment directory with its config.yaml
+pub fn add_environment(opts: &AddOptions, prompter: &mut dyn Prompter) -> EnvMgrResult<AddOutcome> {
+    let envs_dir = EnvironmentConfig::get_all_envs_dir();
+    let detected = opts.from_current.then(CurrentSetup::detect);
+    let registry = TemplateRegistry::new(&envmgr_config_dir(), &SystemRunner);
+    let template = opts
+        .template
+        .as_deref()
+        .map(|name| registry.load(name))
+        .transpose()?;
+    let (template_config, origin) = match template {
+        Some(template) => (Some(template.config), template.origin),
+        None => (None, None),
+    };
+    let draft = build_environment(
+        opts,
+        detected,
+        template_config,
+        prompter,
+        &MachineChoices,
+        &envs_dir,
+    )?;
+    match draft {
+        Draft::New(key, config) => {
+            let schema = installed_environment_schema();
+            let env_dir = write_environment(&envs_dir, &key, &config, schema.as_deref())?;
+            if let Some(origin) = origin {
+                origin.write(&env_dir)?;
+                info!(
+                    "Created from remote template {} at {}; its integrations apply once you review it and run `envmgr template trust {key}`",
+                    origin.template, origin.commit
+                );
+            }
+            info!(
+                "Created environment {} ({key}) in {}",
+                config.name,
+                env_dir.display()
+            );
+            Ok(AddOutcome::Created(env_dir))
+        }
+        Draft::OpenExisting(key) => {
+            let config_path = envs_dir.join(&key).join(ENV_CONFIG_FILE_NAME);
+            open_in_editor(&config_path)?;
+            Ok(AddOutcome::OpenedExisting(config_path))
+        }
+        Draft::Aborted => Ok(AddOutcome::Aborted),
+    }
+}
+
+/// Derive a key from a display name, e.g. `Client X` -> `client-x`
+pub fn slugify(name: &str) -> String {
+    let mut slug = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_matches('-').to_string()
+}
+
+/// Check that `key` can be used for a new environment in `envs_dir`
+pub fn validate_env_key(key: &str, envs_dir: &Path) -> EnvMgrResult<()> {
+    if key.is_empty()
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(EnvMgrError::InvalidEnvironmentKey(format!(
+            "'{key}' may only contain letters, digits, '-' and '_'"
+        )));
+    }
+    if key == BASE_ENV_NAME {
+        return Err(EnvMgrError::InvalidEnvironmentKey(format!(
+            "'{BASE_ENV_NAME}' is reserved for the base environment"
+        )));
+    }
+    let env_dir = envs_dir.join(key);
+    if env_dir.exists() {
+        return Err(EnvMgrError::EnvironmentAlreadyExists {
+            key: key.to_string(),
+            name: EnvironmentConfig::load_shared_from_file(&env_dir)
+                .ok()
+                .map(|c| c.name),
+        });
+    }
+    Ok(())
+}
+
+/// Pick the key for the new environment.
+///
+/// When interactive and the key is taken, offers a different key, opening the
+/// existing environment or aborting. Overwriting is deliberately not offered.
+fn resolve_key(
+    opts: &AddOptions,
+    prompter: &mut dyn Prompter,
+    envs_dir: &Path,
+) -> EnvMgrResult<Result<String, Draft>> {
+    if opts.no_interactive {
+        let key = opts.key.clone().unwrap_or_else(|| slugify(&opts.name));
+        validate_env_key(&key, envs_dir)?;
+        return Ok(Ok(key));
+    }
+
+    let mut key = match &opts.key {
+        Some(key) => key.clone(),
+        None => prompter.input("Environment key", Some(&slugify(&opts.name)))?,
+    };
+    loop {
+        let existing_name = match validate_env_key(&key, envs_dir) {
+            Ok(()) => return Ok(Ok(key)),
+            Err(EnvMgrError::EnvironmentAlreadyExists { name, .. }) => name,
+            Err(e) => return Err(e),
+        };
+        let items = [
+            "Choose a different key".to_string(),
+            "Open the existing environment in $EDITOR".to_string(),
+            "Abort".to_string(),
+        ];
+        let prompt = match existing_name {
+            Some(name) => format!("Environment '{key}' already exists ({name})"),
+            None => format!("Environment '{key}' already exists"),
+        };
+        match prompter.select(&prompt, &items, 0)? {
+            Some(0) => {
+                key = prompter.input(&format!("Environment key ('{key}' is taken)"), None)?;
+            }
+            Some(1) => return Ok(Err(Draft::OpenExisting(key))),
+            _ => return Ok(Err(Draft::Aborted)),
+        }
+    }
+}
+
+fn build_environment(
+    opts: &AddOptions,
+    detected: Option<CurrentSetup>,
+    template: Option<EnvironmentConfig>,
+    prompter: &mut dyn Prompter,
+    choices: &dyn ChoiceSource,
+    envs_dir: &Path,
+) -> EnvMgrResult<Draft> {
+    let interactive = !opts.no_interactive;
+
+    let key = match resolve_key(opts, prompter, envs_dir)? {
+        Ok(key) => key,
+        Err(draft) => return Ok(draft),
+    };
+
+    // Without any integration flags, detected values or template, interactively offer every integration
+    let ask_all =
+        interactive && !opts.has_integration_flags() && detected.is_none() && template.is_none();
+    let detected = detected.unwrap_or_default();
+    // Only the template's integrations are carried over, each value editable when interactive
+    let (template_gh, template_tailscale, template_op, template_gcloud) = match &template {
+        Some(template) => (
+            template.gh_cli.clone(),
+            template.tailscale.clone(),
+            template.op_ssh.clone(),
+            template.gcloud.clone(),
+        ),
+        None => (None, None, None, None),
+    };
+    let (
+        template_git,
+        template_kube,
+        template_aws,
+        template_npm,
+        template_ssh,
+        template_gpg,
+        template_mise,
+        template_maven,
+        template_python,
+        template_cargo,
+    ) = match template {
+        Some(template) => (
+            template.git,
+            template.kube,
+            template.aws,
+            template.npm,
+            template.ssh,
+            template.gpg,
+            template.mise,
+            template.maven,
+            template.python,
+            template.cargo,
+        ),
+        None => (None, None, None, None, None, None, None, None, None, None),
+    };
+
+    let gh_cli = match (&opts.gh_host, &opts.gh_user) {
+        (None, None) => match detected.gh_cli {
+            Some(gh_cli) => {
+                let users = gh_cli
+                    .hosts
+                    .iter()
+                    .map(|h| format!("{}@{}", h.user, h.host))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                accept_detected(prompter, interactive, &format!("gh users {users}"))?
+                    .then_some(gh_cli)
+            }
+            None => match template_gh {
+                Some(gh_cli) if interactive => Some(GhCliConfig {
+                    hosts: gh_cli
+                        .hosts
+                        .iter()
+                        .map(|initial| prompt_gh_cli_host(prompter, None, None, Some(initial)))
+                        .collect::<EnvMgrResult<_>>()?,
+                    gh_config: gh_cli.gh_config,
+                }),
+                Some(gh_cli) => Some(gh_cli),
+                None if ask_all && prompter.confirm("Configure a GitHub CLI user?", false)? => {
+                    Some(prompt_gh_cli_config(
+                        prompter,
+                        None,
+                        None,
+                        &choices.gh_users(),
+                    )?)
+                }
+                None => None,
+            },
+        },
+        (Some(host), Some(user)) => Some(GhCliConfig {
+            hosts: vec![GhCliHostUser {
+                host: host.clone(),
+                user: user.clone(),
+                git_protocol: None,
+            }],
+            gh_config: Default::default(),
+        }),
+        (host, user) if interactive => Some(prompt_gh_cli_config(
+            prompter,
+            host.as_deref(),
+            user.as_deref(),
+            &choices.gh_users(),
+        )?),
+        _ => {
+            return Err(EnvMgrError::MissingArgument(
+                "--gh-host and --gh-user must be given together".into(),
+            ));
+        }
+    };
+
+    let tailscale = match &opts.tailnet {
+        Some(tailnet) => Some(TailscaleConfig {
+            tailnet: tailnet.clone(),
+            ..Default::default()
+        }),
+        None => match detected.tailscale {
+            Some(tailscale) => accept_detected(
+                prompter,
+                interactive,
+                &format!("tailnet {}", tailscale.tailnet),
+            )?
+            .then_some(tailscale),
+            None => match template_tailscale {
+                Some(tailscale) if interactive => Some(prompt_tailscale_config(
+                    prompter,
+                    None,
+                    Some(&tailscale.tailnet),
+                    &choices.tailnets(),
+                )?),
+                Some(tailscale) => Some(tailscale),
+                None if ask_all && prompter.confirm("Configure a Tailscale tailnet?", false)? => {
+                    Some(prompt_tailscale_config(
+                        prompter,
+                        None,
+                        None,
+                        &choices.tailnets(),
+                    )?)
+                }
+                None => None,
+            },
+        },
+    };
+
+    let op_ssh = if opts.op_vault.is_some() || opts.op_item.is_some() || opts.op_account.is_some() {
+        Some(OnePasswordSSHAgentConfig {
+            keys: vec![OnePasswordSSHKey {
+                vault: opts.op_vault.clone(),
+                item: opts.op_item.clone(),
+                account: opts.op_account.clone(),
+            }],
+        })
+    } else if let Some(op_ssh) = detected.op_ssh {
+        let description = format!("{} 1Password SSH key(s)", op_ssh.keys.len());
+        accept_detected(prompter, interactive, &description)?.then_some(op_ssh)
+    } else if let Some(op_ssh) = template_op {
+        if interactive {
+            Some(prompt_op_ssh_config(prompter, &op_ssh.keys, &[])?)
+        } else {
+            Some(op_ssh)
+        }
+    } else if ask_all && prompter.confirm("Configure 1Password SSH agent keys?", false)? {
+        Some(prompt_op_ssh_config(prompter, &[], &choices.op_ssh_keys())?)
+    } else {
+        None
+    };
+
+    let gcloud = match &opts.gcloud_configuration {
+        Some(configuration) => Some(GcloudConfig {
+            configuration: configuration.clone(),
+            ..Default::default()
+        }),
+        None => match detected.gcloud {
+            Some(gcloud) => accept_detected(prompter, interactive, &format!("gcloud {gcloud}"))?
+                .then_some(gcloud),
+            None => match template_gcloud {
+                Some(gcloud) if interactive => {
+                    Some(prompt_gcloud_config(prompter, None, Some(&gcloud))?)
+                }
+                Some(gcloud) => Some(gcloud),
+                None if ask_all
+                    && prompter.confirm("Configure a gcloud configuration?", false)? =>
+                {
+                    Some(prompt_gcloud_config(prompter, None, None)?)
+                }
+                None => None,
+            },
+        },
+    };
+
+    Ok(Draft::New(
+        key,
+        Box::new(EnvironmentConfig {
+            name: opts.name.clone(),
+            env_vars: vec![],
+            op_ssh,
+            op_documents: vec![],
+            gh_cli,
+            git: template_git,
+            kube: template_kube,
+            aws: template_aws,
+            gcloud,
+            npm: template_npm,
+            ssh: template_ssh,
+            gpg: template_gpg,
+            mise: template_mise,
+            maven: template_maven,
+            python: template_python,
+            cargo: template_cargo,
+            tailscale,
+            locale: None,
+            timezone: None,
+            propagate_to_systemd_user: None,
+            danger: false,
+            unset_vars: vec![],
+            aliases: vec![],
+            inherit_base: true,
+            link_mode: Default::default(),
+            link_modes: Default::default(),
+            link_dirs: Default::default(),
+            description: None,
+            tags: vec![],
+            group: None,
+        }),
+    ))
+}
+
+/// Confirm a detected value, or just report it when not interactive
+fn accept_detected(
+    prompter: &mut dyn Prompter,
+    interactive: bool,
+    description: &str,
+) -> EnvMgrResult<bool> {
+    if interactive {
+        prompter.confirm(&format!("Use detected {description}?"), true)
+    } else {
+        info!("Using detected {description}");
+        Ok(true)
+    }
+}
+
+fn non_empty(value: String) -> Option<String> {
+    let value = value.trim().to_string();
+    (!value.is_empty()).then_some(value)
+}
+
+/// Let the user pick one of `items`, followed by an `other` entry to type the value
+/// instead. `None` for that entry, a cancelled picker or nothing to pick from.
+fn pick(
+    prompter: &mut dyn Prompter,
+    prompt: &str,
+    mut items: Vec<String>,
+    default: usize,
+    other: &str,
+) -> EnvMgrResult<Option<usize>> {
+    let count = items.len();
+    if count == 0 {
+        return Ok(None);
+    }
+    items.push(format!("{other} (type it)"));
+    Ok(prompter
+        .select(prompt, &items, default)?
+        .filter(|index| *index < count))
+}
+
+/// Pick one of the `known` host/user pairs gh is logged in with, those of `host` when
+/// given, or ask for the values not fixed by `host`/`user`
+pub fn prompt_gh_cli_config(
+    prompter: &mut dyn Prompter,
+    host: Option<&str>,
+    user: Option<&str>,
+    known: &[GhCliHostUser],
+) -> EnvMgrResult<GhCliConfig> {
+    let candidates: Vec<&GhCliHostUser> = match user {
+        Some(_) => vec![],
+        None => known
+            .iter()
+            .filter(|known| host.is_none_or(|host| known.host == host))
+            .collect(),
+    };
+    let labels = candidates
+        .iter()
+        .map(|known| format!("{}@{}", known.user, known.host))
+        .collect();
+    let host_user = match pick(prompter, "GitHub user", labels, 0, "Another user")? {
+        Some(index) => candidates[index].clone(),
+        None => prompt_gh_cli_host(prompter, host, user, None)?,
+    };
+    Ok(GhCliConfig {
+        hosts: vec![host_user],
+        gh_config: Default::default(),
+    })
+}
+
+/// Ask for the values not fixed by `host`/`user`, pre-filled from `initial`
+pub fn prompt_gh_cli_host(
+    prompter: &mut dyn Prompter,
+    host: Option<&str>,
+    user: Option<&str>,
+    initial: Option<&GhCliHostUser>,
+) -> EnvMgrResult<GhCliHostUser> {
+    let host = match host {
+        Some(host) => host.to_string(),
+        None => prompter.input(
+            "GitHub host",
+            Some(initial.map_or(DEFAULT_GH_HOST, |i| i.host.as_str())),
+        )?,
+    };
+    let user = match user {
+        Some(user) => user.to_string(),
+        None => prompter.input(
+            &format!("GitHub user on {host}"),
+            initial.map(|i| i.user.as_str()),
+        )?,
+    };
+    // The template's protocol goes with its host
+    let git_protocol = initial
+        .filter(|i| i.host == host)
+        .and_then(|i| i.git_protocol.clone());
+    Ok(GhCliHostUser {
+        host,
+        user,
+        git_protocol,
+    })
+}
+
+/// Pick one of the `known` tailnets or type one, pre-selected or pre-filled from `initial`
+pub fn prompt_tailscale_config(
+    prompter: &mut dyn Prompter,
+    tailnet: Option<&str>,
+    initial: Option<&str>,
+    known: &[String],
+) -> EnvMgrResult<TailscaleConfig> {
+    // An initial tailnet without an account here pre-selects typing it
+    let default = initial.map_or(0, |initial| {
+        (known.iter().position(|tailnet| tailnet == initial)).unwrap_or(known.len())
+    });
+    let tailnet = match tailnet {
+        Some(tailnet) => tailnet.to_string(),
+        None => match pick(
+            prompter,
+            "Tailnet",
+            known.to_vec(),
+            default,
+            "Another tailnet",
+        )? {
+            Some(index) => known[index].clone(),
+            None => prompter.input("Tailnet", initial)?,
+        },
+    };
+    Ok(TailscaleConfig {
+        tailnet,
+        ..Default::default()
+    })
+}
+
+/// Ask for the values not fixed by `configuration`, pre-filled from `initial`
+pub fn prompt_gcloud_config(
+    prompter: &mut dyn Prompter,
+    configuration: Option<&str>,
+    initial: Option<&GcloudConfig>,
+) -> EnvMgrResult<GcloudConfig> {
+    let configuration = match configuration {
+        Some(configuration) => configuration.to_string(),
+        None => prompter.input(
+            "gcloud configuration",
+            initial.map(|i| i.configuration.as_str()),
+        )?,
+    };
+    let mut ask = |prompt: &str, initial: Option<&Option<String>>| {
+        let default = initial.and_then(|v| v.as_deref()).unwrap_or("");
+        prompter.input(prompt, Some(default)).map(non_empty)
+    };
+    Ok(GcloudConfig {
+        project: ask(
+            "gcloud project (empty to keep the configuration's)",
+            initial.map(|i| &i.project),
+        )?,
+        account: ask(
+            "gcloud account (empty to keep the configuration's)",
+            initial.map(|i| &i.account),
+        )?,
+        configuration,
+    })
+}
+
+/// Ask for each of the `initial` keys with its values pre-filled, then for more keys.
+///
+/// Without `initial` keys the `known` SSH Key items are offered to tick first, and at
+/// least one key is asked for when none is ticked.
+pub fn prompt_op_ssh_config(
+    prompter: &mut dyn Prompter,
+    initial: &[OnePasswordSSHKey],
+    known: &[OpSshKeyItem],
+) -> EnvMgrResult<OnePasswordSSHAgentConfig> {
+    let mut keys = vec![];
+    if initial.is_empty() && !known.is_empty() {
+        let vault = |item: &OpSshKeyItem| match item.vault.name.is_empty() {
+            true => item.vault.id.clone(),
+            false => item.vault.name.clone(),
+        };
+        let labels: Vec<String> = known
+            .iter()
+            .map(|item| format!("{} ({})", item.title, vault(item)))
+            .collect();
+        let ticked = prompter.multi_select(
+            "1Password SSH keys (none to type them)",
+            &labels,
+            &vec![false; labels.len()],
+        )?;
+        keys.extend(
+            ticked
+                .unwrap_or_default()
+                .into_iter()
+                .map(|index| OnePasswordSSHKey {
+                    vault: Some(vault(&known[index])),
+                    item: Some(known[index].title.clone()),
+                    account: None,
+                }),
+        );
+    }
+    for key in initial {
+        keys.push(prompt_op_ssh_key(prompter, Some(key))?);
+    }
+    if keys.is_empty() {
+        keys.push(prompt_op_ssh_key(prompter, None)?);
+    }
+    while prompter.confirm("Add another SSH key?", false)? {
+        keys.push(prompt_op_ssh_key(prompter, None)?);
+    }
+    Ok(OnePasswordSSHAgentConfig { keys })
+}
+
+fn prompt_op_ssh_key(
+    prompter: &mut dyn Prompter,
+    initial: Option<&OnePasswordSSHKey>,
+) -> EnvMgrResult<OnePasswordSSHKey> {
+    let mut ask = |prompt: &str, initial: Option<&Option<String>>| {
+        let default = initial.and_then(|v| v.as_deref()).unwrap_or("");
+        prompter.input(prompt, Some(default)).map(non_empty)
+    };
+    Ok(OnePasswordSSHKey {
+        vault: ask("1Password vault (empty for any)", initial.map(|k| &k.vault))?,
+        item: ask("1Password item (empty for any)", initial.map(|k| &k.item))?,
+        account: ask(
+            "1Password account (empty for any)",
+            initial.map(|k| &k.account),
+        )?,
+    })
+}
+
+/// Write `config` as `<envs_dir>/<key>/config.yaml`, creating an empty `files/` dir.
+///
+/// With a `schema`, the file starts with a modeline for the YAML language server.
+pub fn write_environment(
+    envs_dir: &Path,
+    key: &str,
+    config: &EnvironmentConfig,
+    schema: Option<&Path>,
+) -> EnvMgrResult<PathBuf> {
+    let env_dir = envs_dir.join(key);
+    std::fs::create_dir_all(env_dir.join(crate::config::FILES_DIR_NAME))?;
+    let mut content = String::new();
+    if let Some(schema) = schema {
+        content.push_str(&format!(
+            "# yaml-language-server: $schema={}\n",
+            schema.display()
+        ));
+    }
+    content.push_str(&serde_norway::to_string(config)?);
+    std::fs::write(env_dir.join(ENV_CONFIG_FILE_NAME), content)?;
+    Ok(env_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::integrations::git::GitConfig;
+    use crate::integrations::one_password_ssh_agent::OpVaultRef;
+    use crate::prompt::{Answer, ReplayPrompter};
+
+    /// Canned [`ChoiceSource`], with nothing to pick from by default
+    #[derive(Default)]
+    struct Choices {
+        gh_users: Vec<GhCliHostUser>,
+        tailnets: Vec<String>,
+        op_ssh_keys: Vec<OpSshKeyItem>,
+    }
+
+    impl ChoiceSource for Choices {
+        fn gh_users(&self) -> Vec<GhCliHostUser> {
+            self.gh_users.clone()
+        }
+
+        fn tailnets(&self) -> Vec<String> {
+            self.tailnets.clone()
+        }
+
+        fn op_ssh_keys(&self) -> Vec<OpSshKeyItem> {
+            self.op_ssh_keys.clone()
+        }
+    }
+
+    fn machine_choices() -> Choices {
+        let op_key = |title: &str, vault: &str| OpSshKeyItem {
+            id: format!("id-{title}"),
+            title: title.to_string(),
+            vault: OpVaultRef {
+                id: format!("id-{vault}"),
+                name: vault.to_string(),
+            },
+        };
+        Choices {
+            gh_users: vec![
+                GhCliHostUser {
+                    host: "github.com".to_string(),
+                    user: "me".to_string(),
+                    git_protocol: None,
+                },
+                GhCliHostUser {
+                    host: "ghe.corp.com".to_string(),
+                    user: "me-corp".to_string(),
+                    git_protocol: None,
+                },
+            ],
+            tailnets: vec!["home.ts.net".to_string(), "corp.ts.net".to_string()],
+            op_ssh_keys: vec![op_key("Laptop", "Private"), op_key("Deploy", "Work")],
+        }
+    }
+
+    fn temp_envs_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn new_draft(draft: EnvMgrResult<Draft>) -> (String, EnvironmentConfig) {
+        match draft.unwrap() {
+            Draft::New(key, config) => (key, *config),
+            _ => panic!("expected a new environment draft"),
+        }
+    }
+
+    /// An environments dir that already holds `client-x`
+    fn envs_dir_with_existing(name: &str) -> PathBuf {
+        let dir = temp_envs_dir(name);
+        let existing = EnvironmentConfig {
+            name: "Client X".to_string(),
+            ..Default::default()
+        };
+        write_environment(&dir, "client-x", &existing, None).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_existing_key_loops_back_to_key_prompt() {
+        let dir = envs_dir_with_existing("envmgr_test_add_existing_new_key");
+        let opts = AddOptions {
+            name: "Client X".to_string(),
+            tailnet: Some("client.ts.net".to_string()),
+            ..Default::default()
+        };
+        let mut prompter = ReplayPrompter::new([
+            Answer::Default,
+            Answer::Select(Some(0)),
+            Answer::Input("client-x-2"),
+        ]);
+
+        let (key, _) = new_draft(build_environment(
+            &opts,
+            None,
+            None,
+            &mut prompter,
+            &Choices::default(),
+            &dir,
+        ));
+
+        prompter.assert_exhausted();
+        assert_eq!(key, "client-x-2");
+        assert_eq!(
+            prompter.prompts[1],
+            "Environment 'client-x' already exists (Client X)"
+        );
+        assert_eq!(prompter.prompts[2], "Environment key ('client-x' is taken)");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_existing_key_open_or_abort() {
+        let dir = envs_dir_with_existing("envmgr_test_add_existing_open_abort");
+        let opts = AddOptions {
+            name: "Client X".to_string(),
+            key: Some("client-x".to_string()),
+            ..Default::default()
+        };
+
+        let mut prompter = ReplayPrompter::new([Answer::Select(Some(1))]);
+        let draft =
+            build_environment(&opts, None, None, &mut prompter, &Choices::default(), &dir).unwrap();
+        assert!(matches!(draft, Draft::OpenExisting(key) if key == "client-x"));
+
+        let mut prompter = ReplayPrompter::new([Answer::Select(Some(2))]);
+        let draft =
+            build_environment(&opts, None, None, &mut prompter, &Choices::default(), &dir).unwrap();
+        assert!(matches!(draft, Draft::Aborted));
+
+        // Escape aborts as well
+        let mut prompter = ReplayPrompter::new([Answer::Select(None)]);
+        let draft =
+            build_environment(&opts, None, None, &mut prompter, &Choices::default(), &dir).unwrap();
+        assert!(matches!(draft, Draft::Aborted));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_existing_key_non_interactive_message() {
+        let dir = envs_dir_with_existing("envmgr_test_add_existing_non_interactive");
+        let opts = AddOptions {
+            name: "Client X".to_string(),
+            no_interactive: true,
+            ..Default::default()
+        };
+        let mut prompter = ReplayPrompter::new([]);
+
+        let Err(e) = build_environment(&opts, None, None, &mut prompter, &Choices::default(), &dir)
+        else {
+            panic!("expected an error");
+        };
+
+        let message = e.to_string();
+        assert!(message.contains("'client-x' already exists as \"Client X\""));
+        assert!(message.contains("--key"));
+        assert!(message.contains("envmgr remove client-x"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_slugify() {
+        assert_eq!(slugify("Client X"), "client-x");
+        assert_eq!(slugify("  ACME, Inc. "), "acme-inc");
+    }
+
+    #[test]
+    fn test_validate_env_key() {
+        let dir = temp_envs_dir("envmgr_test_add_validate_key");
+        fs::create_dir_all(dir.join("work")).unwrap();
+
+        assert!(validate_env_key("client-x", &dir).is_ok());
+        assert!(matches!(
+            validate_env_key("base", &dir),
+            Err(EnvMgrError::InvalidEnvironmentKey(_))
+        ));
+        assert!(matches!(
+            validate_env_key("has space", &dir),
+            Err(EnvMgrError::InvalidEnvironmentKey(_))
+        ));
+        assert!(matches!(
+            validate_env_key("work", &dir),
+            Err(EnvMgrError::EnvironmentAlreadyExists { .. })
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_non_interactive_flags_never_prompt() {
+        let dir = temp_envs_dir("envmgr_test_add_non_interactive");
+        let opts = AddOptions {
+            name: "Client X".to_string(),
+            key: Some("client-x".to_string()),
+            gh_host: Some("github.com".to_string()),
+            gh_user: Some("me".to_string()),
+            tailnet: Some("client.ts.net".to_string()),
+            op_vault: Some("Work".to_string()),
+            op_item: Some("SSH Key".to_string()),
+            no_interactive: true,
+            ..Default::default()
+        };
+        let mut prompter = ReplayPrompter::new([]);
+
+        let (key, config) = new_draft(build_environment(
+            &opts,
+            None,
+            None,
+            &mut prompter,
+            &Choices::default(),
+            &dir,
+        ));
+
+        assert!(prompter.prompts.is_empty());
+        assert_eq!(key, "client-x");
+        assert_eq!(config.gh_cli.unwrap().hosts[0].user, "me");
+        assert_eq!(config.tailscale.unwrap().tailnet, "client.ts.net");
+        assert_eq!(
+            config.op_ssh.unwrap().keys[0].item.as_deref(),
+            Some("SSH Key")
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_non_interactive_missing_value_fails() {
+        let dir = temp_envs_dir("envmgr_test_add_missing_value");
+        let opts = AddOptions {
+            name: "Client X".to_string(),
+            gh_host: Some("github.com".to_string()),
+            no_interactive: true,
+            ..Default::default()
+        };
+        let mut prompter = ReplayPrompter::new([]);
+
+        let result = build_environment(&opts, None, None, &mut prompter, &Choices::default(), &dir);
+
+        assert!(matches!(result, Err(EnvMgrError::MissingArgument(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_interactive_default_flow() {
+        let dir = temp_envs_dir("envmgr_test_add_interactive");
+        let opts = AddOptions {
+            name: "Client X".to_string(),
+            ..Default::default()
+        };
+        let mut prompter = ReplayPrompter::new([
+            Answer::Default,
+            Answer::Confirm(true),
+            Answer::Default,
+            Answer::Input("me"),
+            Answer::Confirm(false),
+            Answer::Confirm(false),
+            Answer::Confirm(false),
+        ]);
+
+        let (key, config) = new_draft(build_environment(
+            &opts,
+            None,
+            None,
+            &mut prompter,
+            &Choices::default(),
+            &dir,
+        ));
+
+        prompter.assert_exhausted();
+        assert_eq!(key, "client-x");
+        let hosts = config.gh_cli.unwrap().hosts;
+        assert_eq!(hosts[0].host, DEFAULT_GH_HOST);
+        assert_eq!(hosts[0].user, "me");
+        assert!(config.tailscale.is_none());
+        assert!(config.gcloud.is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_interactive_pickers_offer_what_the_tools_know() {
+        let dir = temp_envs_dir("envmgr_test_add_pickers");
+        let opts = AddOptions {
+            name: "Client X".to_string(),
+            key: Some("client-x".to_string()),
+            ..Default::default()
+        };
+        let mut prompter = ReplayPrompter::new([
+            Answer::Confirm(true),
+            Answer::Select(Some(1)),
+            Answer::Confirm(true),
+            // The entry after the tailnets types one instead
+            Answer::Select(Some(2)),
+            Answer::Input("client.ts.net"),
+            Answer::Confirm(true),
+            Answer::MultiSelect(Some(vec![1])),
+            Answer::Confirm(false),
+            Answer::Confirm(false),
+        ]);
+
+        let (_, config) = new_draft(build_environment(
+            &opts,
+            None,
+            None,
+            &mut prompter,
+            &machine_choices(),
+            &dir,
+        ));
+
+        prompter.assert_exhausted();
+        let hosts = config.gh_cli.unwrap().hosts;
+        assert_eq!(
+            (hosts[0].host.as_str(), hosts[0].user.as_str()),
+            ("ghe.corp.com", "me-corp")
+        );
+        assert_eq!(config.tailscale.unwrap().tailnet, "client.ts.net");
+        assert_eq!(
+            config.op_ssh.unwrap().keys,
+            [OnePasswordSSHKey {
+                vault: Some("Work".to_string()),
+                item: Some("Deploy".to_string()),
+                account: None,
+            }]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pickers_fall_back_to_typing() {
+        let known = machine_choices();
+        // Only the users of the given host are offered, cancelling types one
+        let mut prompter = ReplayPrompter::new([Answer::Select(None), Answer::Input("other")]);
+        let gh_cli =
+            prompt_gh_cli_config(&mut prompter, Some("github.com"), None, &known.gh_users).unwrap();
+        prompter.assert_exhausted();
+        assert_eq!(gh_cli.hosts[0].user, "other");
+        let mut prompter = ReplayPrompter::new([Answer::Input("me")]);
+        prompt_gh_cli_config(&mut prompter, Some("gitlab.com"), None, &known.gh_users).unwrap();
+        prompter.assert_exhausted();
+
+        // Ticking none asks for a key
+        let mut prompter = ReplayPrompter::new([
+            Answer::MultiSelect(Some(vec![])),
+            Answer::Input("Vault"),
+            Answer::Input(""),
+            Answer::Input(""),
+            Answer::Confirm(false),
+        ]);
+        let op_ssh = prompt_op_ssh_config(&mut prompter, &[], &known.op_ssh_keys).unwrap();
+        prompter.assert_exhausted();
+        assert_eq!(op_ssh.keys[0].vault.as_deref(), Some("Vault"));
+        assert_eq!(op_ssh.keys[0].item, None);
+    }
+
+    #[test]
+    fn test_from_current_confirms_detected_values() {
+        let dir = temp_envs_dir("envmgr_test_add_from_current");
+        let opts = AddOptions {
+            name: "Client X".to_string(),
+            key: Some("client-x".to_string()),
+            from_current: true,
+            ..Default::default()
+        };
+        let detected = CurrentSetup {
+            gh_cli: Some(GhCliConfig {
+                hosts: vec![GhCliHostUser {
+                    host: "github.com".to_string(),
+                    user: "me".to_string(),
+                    git_protocol: None,
+                }],
+                ..Default::default()
+            }),
+            tailscale: Some(TailscaleConfig {
+                tailnet: "client.ts.net".to_string(),
+                ..Default::default()
+            }),
+            op_ssh: None,
+            gcloud: None,
+        };
+        let mut prompter = ReplayPrompter::new([Answer::Confirm(true), Answer::Confirm(false)]);
+
+        let (_, config) = new_draft(build_environment(
+            &opts,
+            Some(detected),
+            None,
+            &mut prompter,
+            &Choices::default(),
+            &dir,
+        ));
+
+        prompter.assert_exhausted();
+        assert_eq!(prompter.prompts[0], "Use detected gh users me@github.com?");
+        assert_eq!(config.gh_cli.unwrap().hosts[0].user, "me");
+        // Declined, and no generic tailscale/op prompts follow
+        assert!(config.tailscale.is_none());
+        assert!(config.op_ssh.is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn work_template() -> EnvironmentConfig {
+        EnvironmentConfig {
+            name: "Work".to_string(),
+            env_vars: vec![crate::config::EnvVarsConfig {
+                key: "WORK_ONLY".to_string(),
+                value: "1".to_string(),
+                dynamic: None,
+                order: 0,
+                secret: false,
+                when: None,
+                integration: None,
+            }],
+            op_ssh: Some(OnePasswordSSHAgentConfig {
+                keys: vec![OnePasswordSSHKey {
+                    vault: Some("Work".to_string()),
+                    item: None,
+                    account: None,
+                }],
+            }),
+            gh_cli: Some(GhCliConfig {
+                hosts: vec![GhCliHostUser {
+                    host: "github.example.com".to_string(),
+                    user: "me-work".to_string(),
+                    git_protocol: None,
+                }],
+                ..Default::default()
+            }),
+            git: Some(GitConfig {
+                user_email: Some("me@work.example".to_string()),
+                ..Default::default()
+            }),
+            gcloud: Some(GcloudConfig {
+                configuration: "work".to_string(),
+                project: Some("work-prod".to_string()),
+                account: None,
+            }),
+            tailscale: Some(TailscaleConfig {
+                tailnet: "work.ts.net".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_template_prefills_editable_prompts() {
+        let dir = temp_envs_dir("envmgr_test_add_template");
+        let opts = AddOptions {
+            name: "Client Y".to_string(),
+            key: Some("client-y".to_string()),
+            template: Some("work".to_string()),
+            ..Default::default()
+        };
+        let mut prompter = ReplayPrompter::new([
+            Answer::Default,
+            Answer::Input("me-client"),
+            Answer::Input("client.ts.net"),
+            Answer::Default,
+            Answer::Default,
+            Answer::Input("Client"),
+            Answer::Confirm(false),
+            Answer::Input("client"),
+            Answer::Default,
+            Answer::Input("me@client.example"),
+        ]);
+
+        let (_, config) = new_draft(build_environment(
+            &opts,
+            None,
+            Some(work_template()),
+            &mut prompter,
+            &Choices::default(),
+            &dir,
+        ));
+
+        prompter.assert_exhausted();
+        assert_eq!(prompter.prompts[1], "GitHub user on github.example.com");
+        assert_eq!(config.name, "Client Y");
+        assert!(config.env_vars.is_empty());
+        let host = &config.gh_cli.unwrap().hosts[0];
+        assert_eq!(host.host, "github.example.com");
+        assert_eq!(host.user, "me-client");
+        assert_eq!(config.tailscale.unwrap().tailnet, "client.ts.net");
+        let key = &config.op_ssh.unwrap().keys[0];
+        assert_eq!(key.vault.as_deref(), Some("Work"));
+        assert_eq!(key.item, None);
+        assert_eq!(key.account.as_deref(), Some("Client"));
+        assert_eq!(
+            config.gcloud.unwrap(),
+            GcloudConfig {
+                configuration: "client".to_string(),
+                project: Some("work-prod".to_string()),
+                account: Some("me@client.example".to_string()),
+            }
+        );
+
+        // Without prompts the template's integrations are copied as they are
+        let opts = AddOptions {
+            no_interactive: true,
+            ..opts
+        };
+        let mut prompter = ReplayPrompter::new([]);
+        let (_, config) = new_draft(build_environment(
+            &opts,
+            None,
+            Some(work_template()),
+            &mut prompter,
+            &Choices::default(),
+            &dir,
+        ));
+        assert!(prompter.prompts.is_empty());
+        assert_eq!(config.tailscale.unwrap().tailnet, "work.ts.net");
+        assert_eq!(
+            config.git.unwrap().user_email.as_deref(),
+            Some("me@work.example")
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_environment_roundtrip() {
+        let dir = temp_envs_dir("envmgr_test_add_write");
+        let config = EnvironmentConfig {
+            name: "Client X".to_string(),
+            tailscale: Some(TailscaleConfig {
+                tailnet: "client.ts.net".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let env_dir = write_environment(
+            &dir,
+            "client-x",
+            &config,
+            Some(Path::new("/schemas/environment.schema.json")),
+        )
+        .unwrap();
+
+        assert!(env_dir.join("files").is_dir());
+        let content = fs::read_to_string(env_dir.join("config.yaml")).unwrap();
+        let parsed: EnvironmentConfig = serde_norway::from_str(&content).unwrap();
+        assert_eq!(parsed.name, "Client X");
+        assert!(!content.contains("gh_cli"));
+        assert!(
+            content
+                .starts_with("# yaml-language-server: $schema=/schemas/environment.schema.json\n")
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,44 @@
+//! `envmgr backups`: real files linking moved aside, and putting them back.
+
+use log::info;
+
+use crate::{
+    commands::history::relative_time, daemon::unix_now, environment::EnvironmentManager,
+    error::EnvMgrResult, state::State,
+};
+
+pub fn print_backups(json: bool) -> EnvMgrResult<()> {
+    let state = State::get_state()?;
+    let backups: Vec<_> = state.backups.iter().rev().collect();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&backups)?);
+        return Ok(());
+    }
+    if backups.is_empty() {
+        println!("No backups");
+        return Ok(());
+    }
+    let now = unix_now();
+    for backup in backups {
+        let missing = match backup.path.symlink_metadata() {
+            Ok(_) => "",
+            Err(_) => " (missing)",
+        };
+        println!(
+            "{:>10}  {}  [{}]\n            {}{missing}",
+            relative_time(now, backup.created_at),
+            backup.target.display(),
+            backup.env_key,
+            backup.path.display()
+        );
+    }
+    Ok(())
+}
+
+pub fn restore_backup(path: &std::path::Path, dry_run: bool) -> EnvMgrResult<()> {
+    let target = EnvironmentManager::restore_backup(path, dry_run)?;
+    if !dry_run {
+        info!("Restored {}", target.display());
+    }
+    Ok(())
+}
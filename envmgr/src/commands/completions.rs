@@ -0,0 +1,121 @@
+//! Completion of environment keys on top of the static clap completions.
+//!
+//! The generated scripts call the hidden `complete-envs` subcommand, which only
+//! lists directories and so stays fast enough to run on every TAB.
+
+use std::path::Path;
+
+use indoc::indoc;
+
+use crate::config::{BASE_ENV_NAME, EnvironmentConfig};
+
+/// Subcommands whose positional arguments are environment keys
+const ENV_KEY_SUBCOMMANDS: &[&str] = &["switch", "remove", "diff", "merge", "show"];
+
+/// Keys of all environments, `base` first. Empty when the config dir is missing.
+pub fn env_keys(base_dir: &Path, envs_dir: &Path) -> Vec<String> {
+    let mut keys = vec![];
+    if let Ok(entries) = std::fs::read_dir(envs_dir) {
+        keys = entries
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().is_dir())
+            .filter_map(|e| e.file_name().to_str().map(String::from))
+            .filter(|key| !key.starts_with('.'))
+            .collect();
+        keys.sort();
+    }
+    if base_dir.is_dir() {
+        keys.insert(0, BASE_ENV_NAME.to_string());
+    }
+    keys
+}
+
+/// Print environment keys one per line for the completion scripts
+pub fn print_env_keys() {
+    for key in env_keys(
+        &EnvironmentConfig::get_base_env_dir(),
+        &EnvironmentConfig::get_all_envs_dir(),
+    ) {
+        println!("{key}");
+    }
+}
+
+/// Shell code completing environment keys, appended to the generated completions
+pub fn dynamic_completions(shell: clap_complete::Shell, bin_name: &str) -> Option<String> {
+    let script = match shell {
+        clap_complete::Shell::Fish => indoc! {r#"
+            complete -c BIN_NAME -n "__fish_seen_subcommand_from SUBCOMMANDS" -f -a "(BIN_NAME complete-envs 2>/dev/null)"
+        "#},
+        clap_complete::Shell::Bash => indoc! {r#"
+            _BIN_FN_envs() {
+                case " SUBCOMMANDS " in
+                    *" ${COMP_WORDS[1]} "*)
+                        if [[ ${COMP_CWORD} -ge 2 && ${COMP_WORDS[COMP_CWORD]} != -* ]]; then
+                            COMPREPLY=($(compgen -W "$(BIN_NAME complete-envs 2>/dev/null)" -- "${COMP_WORDS[COMP_CWORD]}"))
+                            return 0
+                        fi
+                        ;;
+                esac
+                _BIN_FN "$@"
+            }
+            complete -F _BIN_FN_envs -o bashdefault -o default BIN_NAME
+        "#},
+        clap_complete::Shell::Zsh => indoc! {r#"
+            _BIN_FN_envs() {
+                if (( CURRENT >= 3 )) && [[ " SUBCOMMANDS " == *" ${words[2]} "* && ${words[CURRENT]} != -* ]]; then
+                    compadd -- ${(f)"$(BIN_NAME complete-envs 2>/dev/null)"}
+                else
+                    _BIN_FN "$@"
+                fi
+            }
+            compdef _BIN_FN_envs BIN_NAME
+        "#},
+        _ => return None,
+    };
+    Some(
+        script
+            .replace("SUBCOMMANDS", &ENV_KEY_SUBCOMMANDS.join(" "))
+            .replace("BIN_FN", &bin_name.replace('-', "__"))
+            .replace("BIN_NAME", bin_name),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_env_keys() {
+        let dir = std::env::temp_dir().join("envmgr_test_complete_env_keys");
+        let _ = fs::remove_dir_all(&dir);
+        let base_dir = dir.join("base");
+        let envs_dir = dir.join("environments");
+
+        // Missing config dir completes nothing
+        assert!(env_keys(&base_dir, &envs_dir).is_empty());
+
+        for key in ["work", "personal", ".archive"] {
+            fs::create_dir_all(envs_dir.join(key)).unwrap();
+        }
+        fs::write(envs_dir.join("stray-file"), "").unwrap();
+        fs::create_dir_all(&base_dir).unwrap();
+
+        assert_eq!(env_keys(&base_dir, &envs_dir), ["base", "personal", "work"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_dynamic_completions_call_hidden_subcommand() {
+        let fish = dynamic_completions(clap_complete::Shell::Fish, "envmgr").unwrap();
+        assert!(fish.contains("__fish_seen_subcommand_from switch remove diff merge show"));
+        assert!(fish.contains("(envmgr complete-envs 2>/dev/null)"));
+
+        let bash = dynamic_completions(clap_complete::Shell::Bash, "envmgr").unwrap();
+        assert!(bash.contains("complete -F _envmgr_envs"));
+        assert!(bash.contains("_envmgr \"$@\""));
+
+        assert!(dynamic_completions(clap_complete::Shell::Elvish, "envmgr").is_none());
+    }
+}
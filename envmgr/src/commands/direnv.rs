@@ -0,0 +1,206 @@
+//! `envmgr direnv`: `.envrc` files that load an environment's variables through direnv.
+//!
+//! The `.envrc` only holds `use envmgr <env>` between marker comments; the `use_envmgr`
+//! function printed by `envmgr direnv lib` runs `envmgr export-env` whenever direnv
+//! loads it, so the variables never go stale in the repo.
+
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+use crate::{
+    commands::export_env::sh_quote,
+    config::{
+        BASE_ENV_NAME, ENV_CONFIG_FILE_NAME, ENVS_DIR_NAME, GLOBAL_CONFIG_FILE_NAME,
+        LOCAL_CONFIG_FILE_NAME, dotenv::DOTENV_FILE_NAMES,
+    },
+    environment::Environment,
+    error::EnvMgrResult,
+    integrations::marked_block::Markers,
+};
+
+const BLOCK_START: &str =
+    "# >>> envmgr: written by `envmgr direnv generate`, changes are overwritten";
+const BLOCK_END: &str = "# <<< envmgr";
+const MARKERS: Markers = Markers {
+    begin: BLOCK_START,
+    end: BLOCK_END,
+};
+pub const ENVRC_FILE_NAME: &str = ".envrc";
+
+/// The marked block that loads `key`
+pub fn render_block(key: &str) -> String {
+    let is_plain = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    let key = if !key.is_empty() && key.chars().all(is_plain) {
+        key.to_string()
+    } else {
+        sh_quote(key)
+    };
+    format!("{BLOCK_START}\nuse envmgr {key}\n{BLOCK_END}\n")
+}
+
+/// `content` with `block` in place of envmgr's block. Without one yet, the block goes
+/// first, so whatever the rest of the file exports wins over the environment.
+pub fn with_block(content: &str, block: &str) -> String {
+    if let Some(replaced) = MARKERS.replace(content, block) {
+        return replaced;
+    }
+    if content.is_empty() {
+        return block.to_string();
+    }
+    format!("{block}\n{content}")
+}
+
+/// The `.envrc` to write for `path`: the file itself, or the one in it when it is a dir
+fn envrc_path(path: &Path) -> PathBuf {
+    if path.is_dir() {
+        path.join(ENVRC_FILE_NAME)
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// Write the block loading `key` into the `.envrc` at `path`, returning whether it changed
+pub fn write_envrc(path: &Path, key: &str) -> EnvMgrResult<bool> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let updated = with_block(&content, &render_block(key));
+    if updated == content {
+        return Ok(false);
+    }
+    std::fs::write(path, updated)?;
+    Ok(true)
+}
+
+/// `envmgr direnv generate`: load `key` from the `.envrc` in `path`, the current dir by default
+pub fn generate_envrc(key: &str, path: Option<&Path>) -> EnvMgrResult<()> {
+    // Fails early for a typo rather than when direnv first loads the file
+    Environment::load(key)?;
+    let path = envrc_path(path.unwrap_or(Path::new(".")));
+    if !write_envrc(&path, key)? {
+        info!("{} already loads {key}", path.display());
+        return Ok(());
+    }
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+    info!(
+        "Wrote `use envmgr {key}` to {}; run `direnv allow {}` to load it, and install \
+         `envmgr direnv lib` into ~/.config/direnv/lib/ if you haven't",
+        path.display(),
+        dir.unwrap_or(Path::new(".")).display()
+    );
+    Ok(())
+}
+
+/// The direnv library defining `use envmgr`, for `~/.config/direnv/lib/envmgr.sh`.
+///
+/// The config dir is fixed at generation, so direnv watches and exports the same
+/// environments this `envmgr` invocation sees.
+pub fn direnv_lib(bin_name: &str, config_dir: &Path) -> String {
+    let [preferred, other] = DOTENV_FILE_NAMES;
+    format!(
+        indoc::indoc! {r#"
+            # envmgr library for direnv, save as ~/.config/direnv/lib/envmgr.sh
+            #
+            # `use envmgr <env>` in an .envrc exports the variables of the environment, base
+            # and global.yaml layered in, and reloads when their config changes. Further
+            # arguments go to `envmgr export-env`, e.g. `use envmgr work --resolve-secrets`.
+            use_envmgr() {{
+              local env=${{1:?usage: use envmgr <env> [export-env flags]}}
+              shift
+              local config_dir={config_dir}
+              local dir exports
+              watch_file "$config_dir/{global}" "$config_dir/{local}"
+              for dir in "$config_dir/{base}" "$config_dir/{envs}/$env"; do
+                watch_file "$dir/{config}" "$dir/{local}" "$dir/{dotenv}" "$dir/{other_dotenv}"
+              done
+              exports=$({bin} --config-dir "$config_dir" export-env "$env" --format shell "$@") || return
+              eval "$exports"
+            }}
+        "#},
+        config_dir = sh_quote(&config_dir.to_string_lossy()),
+        global = GLOBAL_CONFIG_FILE_NAME,
+        local = LOCAL_CONFIG_FILE_NAME,
+        base = BASE_ENV_NAME,
+        envs = ENVS_DIR_NAME,
+        config = ENV_CONFIG_FILE_NAME,
+        dotenv = preferred,
+        other_dotenv = other,
+        bin = sh_quote(bin_name),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    const USER_ENVRC: &str = indoc::indoc! {r#"
+        # project settings
+        layout python3
+        export DATABASE_URL=postgres://localhost/dev
+    "#};
+
+    #[test]
+    fn test_envrc_round_trip_keeps_user_content() {
+        let path = envrc_path(&project_dir("envmgr_test_envrc_round_trip"));
+        std::fs::write(&path, USER_ENVRC).unwrap();
+
+        assert!(write_envrc(&path, "work").unwrap());
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            written,
+            format!("{}\n{USER_ENVRC}", render_block("work")),
+            "the block goes first, so the user's exports win"
+        );
+
+        // Regenerating is a no-op, switching the environment only touches the block
+        assert!(!write_envrc(&path, "work").unwrap());
+        assert!(write_envrc(&path, "client a").unwrap());
+        let rewritten = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            rewritten,
+            format!("{BLOCK_START}\nuse envmgr 'client a'\n{BLOCK_END}\n\n{USER_ENVRC}")
+        );
+
+        // User edits below the block survive another regeneration
+        let edited = format!("{rewritten}export EXTRA=1\n");
+        std::fs::write(&path, &edited).unwrap();
+        assert!(write_envrc(&path, "work").unwrap());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n{USER_ENVRC}export EXTRA=1\n", render_block("work"))
+        );
+    }
+
+    #[test]
+    fn test_missing_envrc_gets_only_the_block() {
+        let path = envrc_path(&project_dir("envmgr_test_envrc_missing"));
+
+        assert!(write_envrc(&path, "work").unwrap());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            render_block("work")
+        );
+    }
+
+    #[test]
+    fn test_direnv_lib_uses_the_config_dir() {
+        let lib = direnv_lib("envmgr", Path::new("/home/me/.config/envmgr"));
+
+        assert!(lib.contains("use_envmgr() {\n"));
+        assert!(lib.contains("local config_dir='/home/me/.config/envmgr'\n"));
+        assert!(lib.contains(
+            r#"exports=$('envmgr' --config-dir "$config_dir" export-env "$env" --format shell "$@") || return"#
+        ));
+        assert!(lib.contains(r#"watch_file "$dir/config.yaml" "$dir/local.yaml""#));
+    }
+}
@@ -0,0 +1,380 @@
+//! `envmgr doctor`: checks of the current environment that need the tools it configures.
+
+use std::{fmt::Write as _, path::Path};
+
+use crate::{
+    config::{EnvVarsConfig, GlobalConfig},
+    daemon::{ProbeCache, current_probes, unix_now},
+    environment::{Environment, systemd_user_vars},
+    error::{EnvMgrError, EnvMgrResult},
+    fs::RealFs,
+    integrations::{
+        ConfiguredIntegration, IntegrationKind, quarantine::IntegrationFailures, registry,
+    },
+    runner::SystemRunner,
+    state::{CopiedFile, State},
+    systemd,
+};
+
+/// Which checks `doctor` runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DoctorOptions {
+    /// Ask the 1Password CLI whether the `op_ssh` keys resolve
+    pub op_keys: bool,
+}
+
+/// Check the current environment and print the findings; any problem fails it
+pub fn doctor(opts: &DoctorOptions) -> EnvMgrResult<()> {
+    let state = State::get_state()?;
+    let env = Environment::load(&state.current_env_key)?;
+    let mut out = String::new();
+    let mut problems = 0;
+    for integration in registry(&RealFs, &SystemRunner, false)? {
+        problems += match integration.kind() {
+            IntegrationKind::OpSsh => check_op_keys(&env, opts, integration.as_ref(), &mut out),
+            _ => integration.check(&env, &mut out),
+        };
+    }
+    check_probes(
+        &current_probes(&SystemRunner),
+        &state.copied_files,
+        unix_now(),
+        &mut out,
+    );
+    check_quarantine(&state.integration_failures, &mut out);
+    if cfg!(target_os = "linux")
+        && let Some(file) = systemd::environment_d_path()
+    {
+        let expected = systemd_user_vars(&env, &GlobalConfig::load()?)?;
+        check_systemd(&env.key, expected.as_deref(), &file, &mut out);
+    }
+    print!("{out}");
+    if problems > 0 {
+        return Err(EnvMgrError::OpSshKey(format!(
+            "{problems} op_ssh key(s) of {} don't resolve",
+            env.key
+        )));
+    }
+    Ok(())
+}
+
+/// Write the `op_ssh` findings of `integration` for `env` to `out`, returning the number
+/// of problems. Asking op about every key can prompt, so it only happens with `op_keys`.
+fn check_op_keys(
+    env: &Environment,
+    opts: &DoctorOptions,
+    integration: &dyn ConfiguredIntegration,
+    out: &mut String,
+) -> usize {
+    if integration.is_configured_in(env) && !opts.op_keys {
+        let _ = writeln!(out, "op_ssh: skipped");
+        return 0;
+    }
+    integration.check(env, out)
+}
+
+/// Write the probes to `out`, with a warning for each copy edited since it was written
+fn check_probes(probes: &ProbeCache, copies: &[CopiedFile], now: u64, out: &mut String) {
+    out.push_str(&probes.render(now));
+    for target in probes.edited_copies(copies) {
+        let _ = writeln!(
+            out,
+            "  warning: {} was edited since it was copied, switches leave it alone",
+            target.display()
+        );
+    }
+}
+
+/// Write the quarantined integrations of every environment to `out`, with how to lift
+/// each quarantine. Switches skip them quietly after the first notice, so only a warning.
+fn check_quarantine(records: &[IntegrationFailures], out: &mut String) {
+    let quarantined: Vec<_> = records.iter().filter(|r| r.quarantined).collect();
+    if quarantined.is_empty() {
+        let _ = writeln!(out, "quarantine: no integration is quarantined");
+        return;
+    }
+    let _ = writeln!(
+        out,
+        "quarantine: {} integration(s) skipped by switches",
+        quarantined.len()
+    );
+    for record in quarantined {
+        let _ = writeln!(
+            out,
+            "  warning: {integration} in {env} failed {failures} times in a row, fix it and \
+             run `envmgr integrations unquarantine {integration} --env {env}`",
+            integration = record.integration,
+            env = record.env,
+            failures = record.failures,
+        );
+    }
+}
+
+/// Write whether the environment.d `file` holds the `expected` variables of `env_key` to
+/// `out`. Dynamic values are only checked for presence, resolving them could prompt.
+fn check_systemd(env_key: &str, expected: Option<&[EnvVarsConfig]>, file: &Path, out: &mut String) {
+    let content = match std::fs::read_to_string(file) {
+        Ok(content) => Some(content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            let _ = writeln!(
+                out,
+                "systemd: could not read {}\n  warning: {e}",
+                file.display()
+            );
+            return;
+        }
+    };
+    let (expected, content) = match (expected, content) {
+        (None, None) => {
+            let _ = writeln!(out, "systemd: not enabled for {env_key}");
+            return;
+        }
+        (None, Some(_)) => {
+            let _ = writeln!(
+                out,
+                "systemd: not enabled for {env_key}\n  \
+                 warning: {} is left over and still loaded at login, remove it",
+                file.display()
+            );
+            return;
+        }
+        (Some(_), None) => {
+            let _ = writeln!(
+                out,
+                "systemd: {} is missing\n  warning: it is written on the next `envmgr switch`",
+                file.display()
+            );
+            return;
+        }
+        (Some(expected), Some(content)) => (expected, content),
+    };
+    let written = systemd::parse_environment_d(&content);
+    let mut stale = vec![];
+    for var in expected {
+        match written.get(&var.key) {
+            None => stale.push(format!("{} is missing", var.key)),
+            Some(value) if var.dynamic.is_none() && *value != var.value => {
+                stale.push(format!("{} has an outdated value", var.key))
+            }
+            Some(_) => {}
+        }
+    }
+    for key in written.keys() {
+        if !expected.iter().any(|var| &var.key == key) {
+            stale.push(format!("{key} is no longer propagated"));
+        }
+    }
+    if stale.is_empty() {
+        let _ = writeln!(out, "systemd: {} is current", file.display());
+        return;
+    }
+    let _ = writeln!(
+        out,
+        "systemd: {} is out of date, it is rewritten on the next `envmgr switch`",
+        file.display()
+    );
+    for problem in stale {
+        let _ = writeln!(out, "  warning: {problem}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrations::one_password_ssh_agent::{
+        OnePasswordSSHAgentConfig, OnePasswordSSHAgentIntegration, OnePasswordSSHKey, OpItemList,
+        OpSshKeyItem, SshKeyItemSource,
+    };
+
+    /// The default account has the items of `ITEMS`, every other one is signed out
+    struct CannedItems;
+
+    const ITEMS: &str = r#"[
+      {"id": "k1", "title": "GitHub", "vault": {"id": "v1", "name": "Work"}},
+      {"id": "k2", "title": "GitHub", "vault": {"id": "v2", "name": "Private"}}
+    ]"#;
+
+    impl SshKeyItemSource for CannedItems {
+        fn ssh_key_items(&self, account: Option<&str>) -> Result<Vec<OpSshKeyItem>, String> {
+            match account {
+                None => OpItemList::parse(ITEMS),
+                Some(account) => Err(format!("account {account} is not signed in")),
+            }
+        }
+    }
+
+    /// The op_ssh integration, looking the keys up in [`CannedItems`]
+    fn op_ssh() -> OnePasswordSSHAgentIntegration<'static> {
+        OnePasswordSSHAgentIntegration {
+            path: std::env::temp_dir().join("envmgr_test_doctor_agent.toml"),
+            fs: &RealFs,
+            keys: Box::new(CannedItems),
+        }
+    }
+
+    fn key(vault: Option<&str>, item: Option<&str>, account: Option<&str>) -> OnePasswordSSHKey {
+        OnePasswordSSHKey {
+            vault: vault.map(str::to_string),
+            item: item.map(str::to_string),
+            account: account.map(str::to_string),
+        }
+    }
+
+    fn env() -> Environment {
+        Environment {
+            key: "work".to_string(),
+            name: "Work".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_check_op_keys_reports_problems_and_warnings() {
+        let env = Environment {
+            one_password_ssh: Some(OnePasswordSSHAgentConfig {
+                keys: vec![
+                    key(Some("Work"), Some("GitHub"), None),
+                    key(Some("Wrok"), None, None),
+                    key(None, Some("GitHub"), None),
+                    key(Some("Work"), None, Some("corp")),
+                ],
+            }),
+            ..env()
+        };
+        let opts = DoctorOptions { op_keys: true };
+        let mut out = String::new();
+
+        let problems = check_op_keys(&env, &opts, &op_ssh(), &mut out);
+
+        assert_eq!(problems, 2);
+        assert_eq!(
+            out,
+            "op_ssh: 1 of 4 key(s) resolve\n  \
+             error: no SSH Key item matches vault 'Wrok'\n  \
+             error: item 'GitHub' matches 2 items: GitHub (Work), GitHub (Private)\n  \
+             warning: could not check the keys of corp: account corp is not signed in\n"
+        );
+
+        let mut out = String::new();
+        let skipped = check_op_keys(&env, &DoctorOptions { op_keys: false }, &op_ssh(), &mut out);
+        assert_eq!((skipped, out.as_str()), (0, "op_ssh: skipped\n"));
+    }
+
+    #[test]
+    fn test_check_systemd_compares_the_environment_d_file() {
+        let dir = std::env::temp_dir().join("envmgr_test_doctor_systemd");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("50-envmgr.conf");
+        let var = |key: &str, value: &str| EnvVarsConfig {
+            key: key.to_string(),
+            value: value.to_string(),
+            ..Default::default()
+        };
+        let expected = [var("KUBECONFIG", "/k/work"), var("AWS_PROFILE", "work")];
+        let check = |expected: Option<&[EnvVarsConfig]>| {
+            let mut out = String::new();
+            check_systemd("work", expected, &file, &mut out);
+            out.replace(&file.display().to_string(), "FILE")
+        };
+
+        assert_eq!(check(None), "systemd: not enabled for work\n");
+        assert_eq!(
+            check(Some(&expected)),
+            "systemd: FILE is missing\n  warning: it is written on the next `envmgr switch`\n"
+        );
+
+        let set = |vars: &[(&str, &str)]| {
+            let set: Vec<(String, String)> = vars
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+            std::fs::write(&file, systemd::render_environment_d("work", &set)).unwrap();
+        };
+        set(&[("KUBECONFIG", "/k/work"), ("AWS_PROFILE", "work")]);
+        assert_eq!(check(Some(&expected)), "systemd: FILE is current\n");
+
+        set(&[("KUBECONFIG", "/k/home"), ("OLD", "1")]);
+        assert_eq!(
+            check(Some(&expected)),
+            "systemd: FILE is out of date, it is rewritten on the next `envmgr switch`\n  \
+             warning: KUBECONFIG has an outdated value\n  \
+             warning: AWS_PROFILE is missing\n  \
+             warning: OLD is no longer propagated\n"
+        );
+        assert_eq!(
+            check(None),
+            "systemd: not enabled for work\n  \
+             warning: FILE is left over and still loaded at login, remove it\n"
+        );
+    }
+
+    #[test]
+    fn test_check_probes_warns_about_edited_copies() {
+        let copy = |target: &str, hash: &str| CopiedFile {
+            target: target.into(),
+            source: "files/x".into(),
+            hash: hash.into(),
+        };
+        let probes = ProbeCache {
+            refreshed_at: 1000,
+            gh_users: [("github.com".to_string(), "alice".to_string())].into(),
+            drift_hashes: [
+                ("/home/a/.npmrc".into(), "edited".to_string()),
+                ("/home/a/.netrc".into(), "same".to_string()),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let mut out = String::new();
+        check_probes(
+            &probes,
+            &[
+                copy("/home/a/.npmrc", "written"),
+                copy("/home/a/.netrc", "same"),
+                copy("/home/a/.unprobed", "written"),
+            ],
+            1120,
+            &mut out,
+        );
+        assert_eq!(
+            out,
+            "Probed 2m ago\n  gh user on github.com: alice\n  \
+             warning: /home/a/.npmrc was edited since it was copied, switches leave it alone\n"
+        );
+    }
+
+    #[test]
+    fn test_check_quarantine_lists_quarantined_pairs() {
+        let record = |env: &str, integration: &str, failures, quarantined| IntegrationFailures {
+            env: env.to_string(),
+            integration: integration.to_string(),
+            failures,
+            config_hash: "h1".to_string(),
+            quarantined,
+        };
+
+        let mut out = String::new();
+        check_quarantine(&[record("work", "gh_cli", 1, false)], &mut out);
+        assert_eq!(out, "quarantine: no integration is quarantined\n");
+
+        let mut out = String::new();
+        check_quarantine(
+            &[
+                record("work", "tailscale", 3, true),
+                record("work", "gh_cli", 1, false),
+                record("home", "kube", 5, true),
+            ],
+            &mut out,
+        );
+        assert_eq!(
+            out,
+            "quarantine: 2 integration(s) skipped by switches\n  \
+             warning: tailscale in work failed 3 times in a row, fix it and \
+             run `envmgr integrations unquarantine tailscale --env work`\n  \
+             warning: kube in home failed 5 times in a row, fix it and \
+             run `envmgr integrations unquarantine kube --env home`\n"
+        );
+    }
+}
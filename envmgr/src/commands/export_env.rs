@@ -0,0 +1,261 @@
+//! `envmgr export-env`: an environment's effective env vars as a file, for reuse in
+//! containers and CI.
+
+use std::{collections::HashMap, fmt::Write as _, path::PathBuf, time::Duration};
+
+use log::{info, warn};
+
+use crate::{
+    config::{DynamicValue, EnvVarsConfig},
+    environment::{DynamicOptions, EnvironmentManager, resolve_dynamic_values},
+    error::{EnvMgrError, EnvMgrResult},
+    platform,
+    state::State,
+};
+
+/// File format written by `export-env`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// `KEY="value"` lines, with `\`, `"`, `$` and newlines escaped
+    Dotenv,
+    /// `KEY=value` lines for `docker run --env-file`, which takes values verbatim
+    Docker,
+    /// Lines to append to `$GITHUB_ENV`, multiline values in heredoc form
+    GithubActions,
+    /// `export KEY='value'` lines to `eval` in a POSIX shell, e.g. from direnv
+    Shell,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportOptions {
+    /// Environment to export, the current one by default
+    pub key: Option<String>,
+    pub format: ExportFormat,
+    /// File to write, stdout by default
+    pub output: Option<PathBuf>,
+    /// Read `op://` references with `op` instead of leaving those variables out
+    pub resolve_secrets: bool,
+    /// Write variables marked `secret: true` instead of leaving them out
+    pub include_secrets: bool,
+    /// How long a `value_from_command` may run
+    pub timeout: Duration,
+}
+
+pub fn export_env(opts: &ExportOptions) -> EnvMgrResult<()> {
+    let key = match &opts.key {
+        Some(key) => key.clone(),
+        None => State::get_state()?.current_env_key,
+    };
+    let mut vars = EnvironmentManager::effective_env_vars(&key)?;
+    if !opts.resolve_secrets {
+        let is_secret = |var: &EnvVarsConfig| matches!(var.dynamic, Some(DynamicValue::Secret(_)));
+        let secrets: Vec<&str> = vars
+            .iter()
+            .filter(|var| is_secret(var))
+            .map(|var| var.key.as_str())
+            .collect();
+        if !secrets.is_empty() {
+            warn!(
+                "Leaving out secret reference(s) {}, pass --resolve-secrets to read them with op",
+                secrets.join(", ")
+            );
+        }
+        vars.retain(|var| !is_secret(var));
+    }
+    if !opts.include_secrets {
+        let secrets: Vec<&str> = vars
+            .iter()
+            .filter(|var| var.secret)
+            .map(|var| var.key.as_str())
+            .collect();
+        if !secrets.is_empty() {
+            warn!(
+                "Leaving out secret variable(s) {}, pass --include-secrets to write them",
+                secrets.join(", ")
+            );
+        }
+        vars.retain(|var| !var.secret);
+    }
+
+    // Nothing was applied before, so a failing source can't fall back to anything
+    let dynamic = DynamicOptions {
+        skip: false,
+        timeout: opts.timeout,
+        secrets: true,
+    };
+    let (vars, errors) = resolve_dynamic_values(vars, &HashMap::new(), &dynamic);
+    if !errors.is_empty() {
+        return Err(EnvMgrError::UnresolvedEnvVars(errors));
+    }
+
+    let content = render_env_file(&vars, opts.format)?;
+    match &opts.output {
+        Some(path) => {
+            // Created private before anything is written, values may be credentials
+            std::fs::File::create(path)?;
+            platform::set_mode(path, 0o600)?;
+            std::fs::write(path, content)?;
+            info!("Wrote {} variable(s) to {}", vars.len(), path.display());
+        }
+        None => print!("{content}"),
+    }
+    Ok(())
+}
+
+/// Render `vars` in `format`, one variable per line (or heredoc)
+pub fn render_env_file(vars: &[EnvVarsConfig], format: ExportFormat) -> EnvMgrResult<String> {
+    let mut out = String::new();
+    for var in vars {
+        let (key, value) = (&var.key, &var.value);
+        match format {
+            ExportFormat::Dotenv => {
+                let _ = writeln!(out, "{key}=\"{}\"", escape_dotenv(value));
+            }
+            ExportFormat::Docker if value.contains(['\n', '\r']) => {
+                return Err(EnvMgrError::Export(format!(
+                    "{key} spans multiple lines, which docker env files can't hold; \
+                     use --format dotenv or pass it with `docker run -e`"
+                )));
+            }
+            ExportFormat::Docker => {
+                let _ = writeln!(out, "{key}={value}");
+            }
+            ExportFormat::GithubActions if value.contains(['\n', '\r']) => {
+                let delimiter = heredoc_delimiter(value);
+                let _ = writeln!(out, "{key}<<{delimiter}\n{value}\n{delimiter}");
+            }
+            ExportFormat::GithubActions => {
+                let _ = writeln!(out, "{key}={value}");
+            }
+            ExportFormat::Shell => {
+                let _ = writeln!(out, "export {key}={}", sh_quote(value));
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn escape_dotenv(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str(r"\\"),
+            '"' => escaped.push_str(r#"\""#),
+            '$' => escaped.push_str(r"\$"),
+            '\n' => escaped.push_str(r"\n"),
+            '\r' => escaped.push_str(r"\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `value` in POSIX shell single quotes, which take everything but `'` literally
+pub(crate) fn sh_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// A heredoc delimiter that doesn't occur in `value`.
+///
+/// Derived from the value rather than fixed, so a value can't end the heredoc early
+/// and inject further variables into `$GITHUB_ENV`.
+fn heredoc_delimiter(value: &str) -> String {
+    use sha2::{Digest, Sha256};
+
+    let digest = hex::encode(Sha256::digest(value.as_bytes()));
+    let mut delimiter = format!("ENVMGR_EOF_{}", &digest[..16]);
+    while value.contains(&delimiter) {
+        delimiter.push('_');
+    }
+    delimiter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::dotenv::parse_dotenv;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<EnvVarsConfig> {
+        pairs
+            .iter()
+            .map(|(key, value)| EnvVarsConfig {
+                key: key.to_string(),
+                value: value.to_string(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_dotenv_round_trips() {
+        let exported = vars(&[
+            ("PLAIN", "value"),
+            ("TRICKY", r#"say "hi" to $HOME \ again"#),
+            ("MULTI", "line one\r\nline two"),
+            ("EMPTY", ""),
+        ]);
+        let content = render_env_file(&exported, ExportFormat::Dotenv).unwrap();
+        assert_eq!(
+            content,
+            indoc::indoc! {r#"
+                PLAIN="value"
+                TRICKY="say \"hi\" to \$HOME \\ again"
+                MULTI="line one\r\nline two"
+                EMPTY=""
+            "#}
+        );
+
+        let (parsed, errors) = parse_dotenv(&content);
+        assert!(errors.is_empty());
+        let original: Vec<(String, String)> = exported
+            .into_iter()
+            .map(|var| (var.key, var.value))
+            .collect();
+        assert_eq!(parsed, original);
+    }
+
+    #[test]
+    fn test_docker_takes_values_verbatim() {
+        let content = render_env_file(
+            &vars(&[("A", "has \"quotes\" and $DOLLAR"), ("B", "")]),
+            ExportFormat::Docker,
+        )
+        .unwrap();
+        assert_eq!(content, "A=has \"quotes\" and $DOLLAR\nB=\n");
+
+        let err = render_env_file(&vars(&[("KEY", "a\nb")]), ExportFormat::Docker).unwrap_err();
+        assert!(err.to_string().contains("KEY spans multiple lines"));
+    }
+
+    #[test]
+    fn test_shell_quotes_everything() {
+        let content = render_env_file(
+            &vars(&[("A", "it's $HOME\nand `this`"), ("B", "")]),
+            ExportFormat::Shell,
+        )
+        .unwrap();
+        assert_eq!(
+            content,
+            "export A='it'\\''s $HOME\nand `this`'\nexport B=''\n"
+        );
+    }
+
+    #[test]
+    fn test_github_actions_heredoc_for_multiline() {
+        let content = render_env_file(
+            &vars(&[("SINGLE", "x=y"), ("PEM", "-----BEGIN-----\nabc")]),
+            ExportFormat::GithubActions,
+        )
+        .unwrap();
+        let delimiter = heredoc_delimiter("-----BEGIN-----\nabc");
+        assert_eq!(
+            content,
+            format!("SINGLE=x=y\nPEM<<{delimiter}\n-----BEGIN-----\nabc\n{delimiter}\n")
+        );
+        assert!(delimiter.starts_with("ENVMGR_EOF_"));
+
+        // A value can't close the heredoc early
+        let sneaky = format!("{delimiter}\nINJECTED=1");
+        assert!(!sneaky.contains(&heredoc_delimiter(&sneaky)));
+    }
+}
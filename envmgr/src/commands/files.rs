@@ -0,0 +1,42 @@
+//! `envmgr files`: how base and an environment's files layer.
+
+use crate::{
+    config::BASE_ENV_NAME,
+    environment::{Environment, FileLayer, layer_files_with_conflicts},
+    error::EnvMgrResult,
+    state::State,
+};
+
+/// Print the targets base and the environment `key` (the current one by default) both
+/// provide, with both sources and which one is linked
+pub fn print_file_conflicts(key: Option<&str>, json: bool) -> EnvMgrResult<()> {
+    let current = State::get_state()?.current_env_key;
+    let key = key.unwrap_or(&current);
+    let base = Environment::load_base_environment()?;
+    let environment = match key {
+        BASE_ENV_NAME => None,
+        key => Some(Environment::load_environment_by_key(key)?),
+    };
+    let (_, conflicts) = layer_files_with_conflicts(&base, environment.as_ref())?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&conflicts)?);
+        return Ok(());
+    }
+    if conflicts.is_empty() {
+        println!("No files provided by both {BASE_ENV_NAME} and {key}");
+        return Ok(());
+    }
+    for conflict in &conflicts {
+        let (base_wins, env_wins) = match conflict.winner {
+            FileLayer::Base => (" (wins)", ""),
+            FileLayer::Environment => ("", " (wins)"),
+        };
+        println!("{}", conflict.target.display());
+        println!(
+            "    {BASE_ENV_NAME}: {}{base_wins}",
+            conflict.base_source.display()
+        );
+        println!("    {key}: {}{env_wins}", conflict.env_source.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,53 @@
+//! `envmgr history`: past environment switches, newest first.
+
+use crate::{daemon::unix_now, error::EnvMgrResult, state::State};
+
+/// Render `timestamp` relative to `now`, e.g. `3h ago`
+pub fn relative_time(now: u64, timestamp: u64) -> String {
+    let elapsed = now.saturating_sub(timestamp);
+    match elapsed {
+        0..60 => "just now".to_string(),
+        60..3600 => format!("{}m ago", elapsed / 60),
+        3600..86400 => format!("{}h ago", elapsed / 3600),
+        _ => format!("{}d ago", elapsed / 86400),
+    }
+}
+
+pub fn print_history(json: bool) -> EnvMgrResult<()> {
+    let state = State::get_state()?;
+    let entries: Vec<_> = state.history.iter().rev().collect();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
+    if entries.is_empty() {
+        println!("No switches recorded yet");
+        return Ok(());
+    }
+    let now = unix_now();
+    for entry in entries {
+        println!(
+            "{:>10}  {} -> {}",
+            relative_time(now, entry.timestamp),
+            entry.from,
+            entry.to
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relative_time() {
+        assert_eq!(relative_time(1000, 1000), "just now");
+        assert_eq!(relative_time(1000 + 59, 1000), "just now");
+        assert_eq!(relative_time(1000 + 150, 1000), "2m ago");
+        assert_eq!(relative_time(1000 + 7200, 1000), "2h ago");
+        assert_eq!(relative_time(1000 + 3 * 86400, 1000), "3d ago");
+        // Clock went backwards
+        assert_eq!(relative_time(1000, 2000), "just now");
+    }
+}
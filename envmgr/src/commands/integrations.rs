@@ -0,0 +1,243 @@
+//! `envmgr integrations`: inspect and apply a single integration without a full switch.
+
+use std::{ffi::OsStr, io::IsTerminal};
+
+use log::info;
+
+use crate::{
+    commands::template::integrations_trusted,
+    environment::{Environment, EnvironmentManager},
+    error::{EnvMgrError, EnvMgrResult},
+    integrations::{
+        IntegrationKind, IntegrationSelection, execute_integrations, find_in_path,
+        plan_integrations, quarantine,
+    },
+    state::State,
+};
+
+/// One row of `envmgr integrations list`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IntegrationStatus {
+    pub kind: IntegrationKind,
+    /// Required tool and whether it was found on `PATH`
+    pub tool: Option<(&'static str, bool)>,
+    /// Keys of the environments configuring the integration
+    pub environments: Vec<String>,
+}
+
+/// Status of every integration across `environments`, looking tools up in `path_var`
+pub fn integration_statuses(
+    environments: &[(bool, Environment)],
+    path_var: &OsStr,
+) -> Vec<IntegrationStatus> {
+    IntegrationKind::ALL
+        .into_iter()
+        .map(|kind| IntegrationStatus {
+            kind,
+            tool: kind
+                .required_tool()
+                .map(|tool| (tool, find_in_path(tool, path_var).is_some())),
+            environments: environments
+                .iter()
+                .filter(|(_, env)| kind.is_configured_in(env))
+                .map(|(_, env)| env.key.clone())
+                .collect(),
+        })
+        .collect()
+}
+
+pub fn print_integrations() -> EnvMgrResult<()> {
+    let environments = EnvironmentManager::list_environments()?;
+    let path_var = std::env::var_os("PATH").unwrap_or_default();
+    for status in integration_statuses(&environments, &path_var) {
+        let tool = match status.tool {
+            Some((tool, true)) => format!("{tool} found"),
+            Some((tool, false)) => format!("{tool} MISSING"),
+            None => "no tool needed".to_string(),
+        };
+        let environments = if status.environments.is_empty() {
+            "-".to_string()
+        } else {
+            status.environments.join(", ")
+        };
+        println!("{:<10} {:<16} {environments}", status.kind, tool);
+    }
+    for record in State::get_state()?
+        .integration_failures
+        .iter()
+        .filter(|r| r.quarantined)
+    {
+        println!(
+            "quarantined: {} in {} after {} failures",
+            record.integration, record.env, record.failures
+        );
+    }
+    Ok(())
+}
+
+/// Load `env_key`, or the current environment when not given
+fn load_target(env_key: Option<&str>) -> EnvMgrResult<Environment> {
+    match env_key {
+        Some(key) => Environment::load(key),
+        None => Environment::load(&State::get_state()?.current_env_key),
+    }
+}
+
+/// Run the read-only probes of `kind` and print what applying it would do
+pub fn test_integration(kind: IntegrationKind, env_key: Option<&str>) -> EnvMgrResult<()> {
+    let env = load_target(env_key)?;
+    if !kind.is_configured_in(&env) {
+        println!("{kind} is not configured in {}", env.key);
+        return Ok(());
+    }
+    println!("{kind} for {}:", env.key);
+    for action in kind.describe_actions(&env) {
+        println!("  {action}");
+    }
+    Ok(())
+}
+
+/// Apply only `kind` for an environment, leaving env vars, files and the current env alone
+pub fn run_integration(kind: IntegrationKind, env_key: Option<&str>) -> EnvMgrResult<()> {
+    let env = load_target(env_key)?;
+    if !integrations_trusted(&env.env_dir())? {
+        return Err(EnvMgrError::Template(format!(
+            "{} was created from a remote template and is not trusted yet; review its config, then run `envmgr template trust {}`",
+            env.key, env.key
+        )));
+    }
+    let planned = plan_integrations(&env, &IntegrationSelection::Only(vec![kind]));
+    if planned.is_empty() {
+        return Err(EnvMgrError::IntegrationNotConfigured {
+            integration: kind.to_string(),
+            env: env.key,
+        });
+    }
+    for action in kind.describe_actions(&env) {
+        info!("{kind}: {action}");
+    }
+    // Logins need the user, so only offer them when someone is there to answer
+    let login = std::io::stdin().is_terminal() && std::io::stderr().is_terminal();
+    execute_integrations(&env, &planned, |kind, env| kind.apply(env, login))?;
+    Ok(())
+}
+
+/// Lift the quarantine of `kind` for an environment, the current one by default
+pub fn unquarantine_integration(kind: IntegrationKind, env_key: Option<&str>) -> EnvMgrResult<()> {
+    let mut state = State::get_state()?;
+    let env_key = env_key.unwrap_or(&state.current_env_key).to_string();
+    if quarantine::unquarantine(&mut state.integration_failures, &env_key, kind) {
+        state.store_state()?;
+        println!("{kind} is no longer quarantined in {env_key}");
+    } else {
+        println!("{kind} is not quarantined in {env_key}");
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::integrations::tailscale::TailscaleConfig;
+
+    #[test]
+    fn test_integration_statuses_detect_tools() {
+        let bin = std::env::temp_dir().join("envmgr_test_integration_statuses");
+        let _ = fs::remove_dir_all(&bin);
+        fs::create_dir_all(&bin).unwrap();
+        fs::write(bin.join("tailscale"), "").unwrap();
+        crate::platform::set_mode(&bin.join("tailscale"), 0o755).unwrap();
+        let env = |key: &str, tailscale| Environment {
+            key: key.to_string(),
+            name: key.to_string(),
+            tailscale,
+            ..Default::default()
+        };
+        let environments = vec![
+            (true, env("base", None)),
+            (false, env("work", Some(TailscaleConfig::default()))),
+        ];
+
+        let statuses = integration_statuses(&environments, bin.as_os_str());
+
+        assert_eq!(
+            statuses,
+            vec![
+                IntegrationStatus {
+                    kind: IntegrationKind::OpSsh,
+                    tool: None,
+                    environments: vec![],
+                },
+                IntegrationStatus {
+                    kind: IntegrationKind::GhCli,
+                    tool: Some(("gh", false)),
+                    environments: vec![],
+                },
+                IntegrationStatus {
+                    kind: IntegrationKind::Git,
+                    tool: None,
+                    environments: vec![],
+                },
+                IntegrationStatus {
+                    kind: IntegrationKind::Kube,
+                    tool: Some(("kubectl", false)),
+                    environments: vec![],
+                },
+                IntegrationStatus {
+                    kind: IntegrationKind::Aws,
+                    tool: Some(("aws", false)),
+                    environments: vec![],
+                },
+                IntegrationStatus {
+                    kind: IntegrationKind::Gcloud,
+                    tool: Some(("gcloud", false)),
+                    environments: vec![],
+                },
+                IntegrationStatus {
+                    kind: IntegrationKind::Npm,
+                    tool: None,
+                    environments: vec![],
+                },
+                IntegrationStatus {
+                    kind: IntegrationKind::Ssh,
+                    tool: None,
+                    environments: vec![],
+                },
+                IntegrationStatus {
+                    kind: IntegrationKind::Gpg,
+                    tool: Some(("gpg", false)),
+                    environments: vec![],
+                },
+                IntegrationStatus {
+                    kind: IntegrationKind::Mise,
+                    tool: Some(("mise", false)),
+                    environments: vec![],
+                },
+                IntegrationStatus {
+                    kind: IntegrationKind::Maven,
+                    tool: None,
+                    environments: vec![],
+                },
+                IntegrationStatus {
+                    kind: IntegrationKind::Python,
+                    tool: None,
+                    environments: vec![],
+                },
+                IntegrationStatus {
+                    kind: IntegrationKind::Cargo,
+                    tool: None,
+                    environments: vec![],
+                },
+                IntegrationStatus {
+                    kind: IntegrationKind::Tailscale,
+                    tool: Some(("tailscale", true)),
+                    environments: vec!["work".to_string()],
+                },
+            ]
+        );
+
+        fs::remove_dir_all(&bin).unwrap();
+    }
+}
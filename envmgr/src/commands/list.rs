@@ -0,0 +1,469 @@
+//! `envmgr list`: environment summaries as a table.
+//!
+//! Sorting, grouping and columns come from `list:` in global.yaml, each overridable by
+//! a flag. They only shape the table; `list --json` always prints every summary.
+
+use std::{collections::BTreeSet, fmt::Write as _};
+
+use crate::{
+    commands::history::relative_time,
+    config::GlobalConfig,
+    daemon::unix_now,
+    environment::{EnvSummary, EnvironmentManager},
+    error::{EnvMgrError, EnvMgrResult},
+};
+
+/// Order of the rows, within each group
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum ListSort {
+    /// Case-insensitive by name
+    Name,
+    #[default]
+    Key,
+    /// Most recently switched to first, never used last
+    LastUsed,
+    /// By first tag, untagged last
+    Tag,
+}
+
+/// Sections of the table
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum ListGroup {
+    #[default]
+    None,
+    /// One section per tag; an environment with several tags shows up in each
+    Tag,
+    /// One section per `group`
+    Group,
+}
+
+/// A column `list` can show
+pub struct Column {
+    /// Name used in `columns:` and `--columns`
+    pub name: &'static str,
+    pub header: &'static str,
+    /// The [`EnvSummary`] field the column shows
+    pub field: &'static str,
+    /// Cell text for a summary, given the current unix time
+    pub value: fn(&EnvSummary, u64) -> String,
+}
+
+/// Every column, in the order `--help` lists them. A new summary field needs one entry here.
+pub const COLUMNS: &[Column] = &[
+    Column {
+        name: "status",
+        header: "",
+        field: "current",
+        value: |summary, _| if summary.current { "*" } else { "" }.to_string(),
+    },
+    Column {
+        name: "key",
+        header: "KEY",
+        field: "key",
+        value: |summary, _| summary.key.clone(),
+    },
+    Column {
+        name: "name",
+        header: "NAME",
+        field: "name",
+        value: |summary, _| summary.name.clone(),
+    },
+    Column {
+        name: "description",
+        header: "DESCRIPTION",
+        field: "description",
+        value: |summary, _| summary.description.clone().unwrap_or_default(),
+    },
+    Column {
+        name: "tags",
+        header: "TAGS",
+        field: "tags",
+        value: |summary, _| summary.tags.join(","),
+    },
+    Column {
+        name: "group",
+        header: "GROUP",
+        field: "group",
+        value: |summary, _| summary.group.clone().unwrap_or_default(),
+    },
+    Column {
+        name: "var_count",
+        header: "VARS",
+        field: "env_var_count",
+        value: |summary, _| summary.env_var_count.to_string(),
+    },
+    Column {
+        name: "files_count",
+        header: "FILES",
+        field: "files_count",
+        value: |summary, _| summary.files_count.to_string(),
+    },
+    Column {
+        name: "integrations",
+        header: "INTEGRATIONS",
+        field: "integrations",
+        value: |summary, _| summary.integrations.join(","),
+    },
+    Column {
+        name: "last_used",
+        header: "LAST USED",
+        field: "last_used",
+        value: |summary, now| match summary.last_used {
+            Some(timestamp) => relative_time(now, timestamp),
+            None => "never".to_string(),
+        },
+    },
+];
+
+/// Names of all columns, for `--columns`
+pub fn column_names() -> impl Iterator<Item = &'static str> {
+    COLUMNS.iter().map(|column| column.name)
+}
+
+fn column(name: &str) -> EnvMgrResult<&'static Column> {
+    COLUMNS
+        .iter()
+        .find(|column| column.name == name)
+        .ok_or_else(|| {
+            EnvMgrError::Config(config::ConfigError::Message(format!(
+                "unknown list column '{name}', expected one of {}",
+                column_names().collect::<Vec<_>>().join(", ")
+            )))
+        })
+}
+
+/// How the table is laid out, `list:` in global.yaml
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema,
+)]
+pub struct ListConfig {
+    #[serde(default)]
+    pub sort: ListSort,
+    #[serde(default)]
+    pub group: ListGroup,
+    /// Columns from left to right, see `envmgr list --help` for the names
+    #[serde(default = "default_columns")]
+    pub columns: Vec<String>,
+}
+
+fn default_columns() -> Vec<String> {
+    ["status", "key", "name"].map(String::from).to_vec()
+}
+
+impl Default for ListConfig {
+    fn default() -> Self {
+        Self {
+            sort: ListSort::default(),
+            group: ListGroup::default(),
+            columns: default_columns(),
+        }
+    }
+}
+
+/// Flags of `envmgr list`, each replacing its `list:` setting
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ListOptions {
+    pub json: bool,
+    pub sort: Option<ListSort>,
+    pub group: Option<ListGroup>,
+    pub columns: Option<Vec<String>>,
+}
+
+pub fn print_list(opts: &ListOptions) -> EnvMgrResult<()> {
+    let summaries = EnvironmentManager::list_summaries()?;
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&summaries)?);
+        return Ok(());
+    }
+    let config = GlobalConfig::load()?.list;
+    let layout = ListConfig {
+        sort: opts.sort.unwrap_or(config.sort),
+        group: opts.group.unwrap_or(config.group),
+        columns: opts.columns.clone().unwrap_or(config.columns),
+    };
+    print!("{}", render_table(summaries, &layout, unix_now())?);
+    Ok(())
+}
+
+/// Sort `summaries` in place according to `sort`, ties broken by key
+pub fn sort_summaries(summaries: &mut [EnvSummary], sort: ListSort) {
+    summaries.sort_by(|a, b| {
+        let order = match sort {
+            ListSort::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+            ListSort::Key => std::cmp::Ordering::Equal,
+            // `None` sorts before `Some`, reversed it comes last
+            ListSort::LastUsed => b.last_used.cmp(&a.last_used),
+            ListSort::Tag => match (a.tags.first(), b.tags.first()) {
+                (Some(a), Some(b)) => a.cmp(b),
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (None, None) => std::cmp::Ordering::Equal,
+            },
+        };
+        order.then_with(|| a.key.cmp(&b.key))
+    });
+}
+
+/// Split sorted `summaries` into titled sections, keeping their order within each
+pub fn group_summaries(
+    summaries: &[EnvSummary],
+    group: ListGroup,
+) -> Vec<(Option<String>, Vec<&EnvSummary>)> {
+    let (labels, unlabeled): (fn(&EnvSummary) -> Vec<&str>, &str) = match group {
+        ListGroup::None => return vec![(None, summaries.iter().collect())],
+        ListGroup::Tag => (
+            |s| s.tags.iter().map(String::as_str).collect(),
+            "(untagged)",
+        ),
+        ListGroup::Group => (|s| s.group.as_deref().into_iter().collect(), "(no group)"),
+    };
+    let titles: BTreeSet<&str> = summaries.iter().flat_map(labels).collect();
+    let mut sections: Vec<(Option<String>, Vec<&EnvSummary>)> = titles
+        .into_iter()
+        .map(|title| {
+            let members = summaries
+                .iter()
+                .filter(|s| labels(s).contains(&title))
+                .collect();
+            (Some(title.to_string()), members)
+        })
+        .collect();
+    let rest: Vec<&EnvSummary> = summaries.iter().filter(|s| labels(s).is_empty()).collect();
+    if !rest.is_empty() {
+        sections.push((Some(unlabeled.to_string()), rest));
+    }
+    sections
+}
+
+/// The table `list` prints, columns aligned across all sections
+pub fn render_table(
+    mut summaries: Vec<EnvSummary>,
+    layout: &ListConfig,
+    now: u64,
+) -> EnvMgrResult<String> {
+    let columns = layout
+        .columns
+        .iter()
+        .map(|name| column(name))
+        .collect::<EnvMgrResult<Vec<_>>>()?;
+    sort_summaries(&mut summaries, layout.sort);
+    let sections: Vec<(Option<String>, Vec<Vec<String>>)> =
+        group_summaries(&summaries, layout.group)
+            .into_iter()
+            .map(|(title, members)| {
+                let rows = members
+                    .into_iter()
+                    .map(|s| columns.iter().map(|c| (c.value)(s, now)).collect())
+                    .collect();
+                (title, rows)
+            })
+            .collect();
+
+    let header: Vec<String> = columns.iter().map(|c| c.header.to_string()).collect();
+    let mut widths: Vec<usize> = header.iter().map(|h| h.chars().count()).collect();
+    for row in sections.iter().flat_map(|(_, rows)| rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let indent = if layout.group == ListGroup::None {
+        ""
+    } else {
+        "  "
+    };
+    let line = |row: &[String]| {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        format!("{indent}{}", cells.join("  "))
+            .trim_end()
+            .to_string()
+    };
+
+    let mut out = String::new();
+    let _ = writeln!(out, "{}", line(&header));
+    for (title, rows) in &sections {
+        if let Some(title) = title {
+            let _ = writeln!(out, "{title}:");
+        }
+        for row in rows {
+            let _ = writeln!(out, "{}", line(row));
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(
+        key: &str,
+        tags: &[&str],
+        group: Option<&str>,
+        last_used: Option<u64>,
+    ) -> EnvSummary {
+        EnvSummary {
+            key: key.to_string(),
+            name: key.to_uppercase(),
+            description: None,
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            group: group.map(String::from),
+            current: key == "base",
+            env_var_count: 1,
+            files_count: 0,
+            integrations: vec![],
+            last_used,
+        }
+    }
+
+    fn fixture() -> Vec<EnvSummary> {
+        vec![
+            summary("base", &[], None, None),
+            summary("prod", &["cloud", "work"], Some("clients"), Some(100)),
+            summary("dev", &["work"], Some("clients"), Some(300)),
+            summary("home", &["personal"], None, Some(200)),
+        ]
+    }
+
+    fn layout(sort: ListSort, group: ListGroup, columns: &[&str]) -> ListConfig {
+        ListConfig {
+            sort,
+            group,
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    fn keys(summaries: &[EnvSummary]) -> Vec<&str> {
+        summaries.iter().map(|s| s.key.as_str()).collect()
+    }
+
+    #[test]
+    fn test_sort_keys() {
+        let mut summaries = fixture();
+        sort_summaries(&mut summaries, ListSort::Key);
+        assert_eq!(keys(&summaries), ["base", "dev", "home", "prod"]);
+        sort_summaries(&mut summaries, ListSort::LastUsed);
+        assert_eq!(keys(&summaries), ["dev", "home", "prod", "base"]);
+        sort_summaries(&mut summaries, ListSort::Tag);
+        assert_eq!(keys(&summaries), ["prod", "home", "dev", "base"]);
+        summaries[0].name = "alpha".into();
+        sort_summaries(&mut summaries, ListSort::Name);
+        assert_eq!(keys(&summaries), ["prod", "base", "dev", "home"]);
+    }
+
+    #[test]
+    fn test_render_ungrouped_default_columns() {
+        let table = render_table(fixture(), &ListConfig::default(), 400).unwrap();
+        assert_eq!(
+            table,
+            indoc::indoc! {"
+                   KEY   NAME
+                *  base  BASE
+                   dev   DEV
+                   home  HOME
+                   prod  PROD
+            "}
+        );
+    }
+
+    #[test]
+    fn test_render_grouped_by_tag_sorted_by_last_used() {
+        let table = render_table(
+            fixture(),
+            &layout(ListSort::LastUsed, ListGroup::Tag, &["key", "last_used"]),
+            400,
+        )
+        .unwrap();
+        assert_eq!(
+            table,
+            indoc::indoc! {"
+                  KEY   LAST USED
+                cloud:
+                  prod  5m ago
+                personal:
+                  home  3m ago
+                work:
+                  dev   1m ago
+                  prod  5m ago
+                (untagged):
+                  base  never
+            "}
+        );
+    }
+
+    #[test]
+    fn test_render_grouped_by_group_sorted_by_tag() {
+        let table = render_table(
+            fixture(),
+            &layout(ListSort::Tag, ListGroup::Group, &["key", "tags"]),
+            400,
+        )
+        .unwrap();
+        assert_eq!(
+            table,
+            indoc::indoc! {"
+                  KEY   TAGS
+                clients:
+                  prod  cloud,work
+                  dev   work
+                (no group):
+                  home  personal
+                  base
+            "}
+        );
+    }
+
+    #[test]
+    fn test_unknown_column_is_an_error() {
+        let err = render_table(
+            fixture(),
+            &layout(ListSort::Key, ListGroup::None, &["nope"]),
+            0,
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("unknown list column 'nope'"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_every_summary_field_has_a_column() {
+        let json = serde_json::to_value(&fixture()[1]).unwrap();
+        let fields: Vec<&String> = json.as_object().unwrap().keys().collect();
+        for field in &fields {
+            assert!(
+                COLUMNS.iter().any(|column| column.field == field.as_str()),
+                "EnvSummary field '{field}' has no list column"
+            );
+        }
+        assert_eq!(COLUMNS.len(), fields.len());
+    }
+}
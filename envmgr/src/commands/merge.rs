@@ -0,0 +1,799 @@
+//! `envmgr merge <source> <dest>`
+//!
+//! The merged environment is staged next to the destination, shown as a diff and
+//! only swapped into place after confirmation. Until that final swap neither
+//! environment is touched; the source is then moved into `.archive/`.
+//!
+//! A dry run prints a confirmation token for the shown plan, which automation can
+//! pass back with `--confirm-token` to apply exactly that plan without prompting.
+
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use crate::{
+    config::{BASE_ENV_NAME, ENV_CONFIG_FILE_NAME, EnvironmentConfig, FILES_DIR_NAME, LinkKind},
+    daemon::unix_now,
+    environment::{EnvironmentDiff, discover_files_in_dir, hash_file},
+    error::{EnvMgrError, EnvMgrResult},
+    integrations::{
+        gh_cli::GhCliConfig, git::GitConfig, one_password_ssh_agent::OnePasswordSSHAgentConfig,
+    },
+    plan::{plan_token, verify_token},
+    prompt::Prompter,
+};
+
+const ARCHIVE_DIR_NAME: &str = ".archive";
+
+/// Which side wins a conflict
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Prefer {
+    Source,
+    Dest,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MergeOptions {
+    /// Resolve every conflict this way instead of prompting
+    pub prefer: Option<Prefer>,
+    /// Apply without asking for confirmation
+    pub yes: bool,
+    /// Only show the plan and its confirmation token
+    pub dry_run: bool,
+    /// Apply without asking, but only if the plan still matches this token from a dry run
+    pub confirm_token: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeOutcome {
+    Merged {
+        archived_to: PathBuf,
+    },
+    /// Dry run: the plan was shown, nothing was changed
+    Planned {
+        token: String,
+    },
+    Aborted,
+}
+
+/// Merge environment `source` into `dest` and archive `source`
+pub fn merge_environments(
+    source: &str,
+    dest: &str,
+    opts: &MergeOptions,
+    prompter: &mut dyn Prompter,
+) -> EnvMgrResult<MergeOutcome> {
+    merge_environments_in(
+        &EnvironmentConfig::get_all_envs_dir(),
+        source,
+        dest,
+        opts,
+        prompter,
+    )
+}
+
+fn merge_environments_in(
+    envs_dir: &Path,
+    source: &str,
+    dest: &str,
+    opts: &MergeOptions,
+    prompter: &mut dyn Prompter,
+) -> EnvMgrResult<MergeOutcome> {
+    for key in [source, dest] {
+        if key == BASE_ENV_NAME {
+            return Err(EnvMgrError::InvalidEnvironmentKey(format!(
+                "the '{BASE_ENV_NAME}' environment cannot be merged"
+            )));
+        }
+        if !envs_dir.join(key).is_dir() {
+            return Err(EnvMgrError::EnvironmentNotFound(key.to_string()));
+        }
+    }
+    if source == dest {
+        return Err(EnvMgrError::InvalidEnvironmentKey(format!(
+            "cannot merge '{source}' into itself"
+        )));
+    }
+
+    let staging = envs_dir.join(format!(".merge-{dest}"));
+    let mut resolver = Resolver {
+        prefer: opts.prefer,
+        prompter,
+        source,
+        dest,
+    };
+
+    let mut planned_token = None;
+    let staged = stage(envs_dir, &staging, source, dest, &mut resolver).and_then(|staged| {
+        if !staged {
+            return Ok(false);
+        }
+        let diff = EnvironmentDiff::between_dirs((dest, &envs_dir.join(dest)), (dest, &staging))?;
+        println!("{}", diff.render());
+        let plan = format!(
+            "merge {source} into {dest}\n{}",
+            serde_json::to_string(&diff)?
+        );
+        if opts.dry_run {
+            planned_token = Some(plan_token(&plan));
+            return Ok(false);
+        }
+        if let Some(token) = &opts.confirm_token {
+            verify_token(&plan, token)?;
+            return Ok(true);
+        }
+        Ok(opts.yes
+            || resolver
+                .prompter
+                .confirm(&format!("Merge '{source}' into '{dest}'?"), false)?)
+    });
+
+    match staged {
+        Ok(true) => {
+            let archived_to = commit(envs_dir, &staging, source, dest)?;
+            info!(
+                "Merged {source} into {dest}, archived {source} to {}",
+                archived_to.display()
+            );
+            Ok(MergeOutcome::Merged { archived_to })
+        }
+        Ok(false) => {
+            if staging.exists() {
+                std::fs::remove_dir_all(&staging)?;
+            }
+            Ok(match planned_token {
+                Some(token) => MergeOutcome::Planned { token },
+                None => MergeOutcome::Aborted,
+            })
+        }
+        Err(e) => {
+            let _ = std::fs::remove_dir_all(&staging);
+            Err(e)
+        }
+    }
+}
+
+struct Resolver<'a> {
+    prefer: Option<Prefer>,
+    prompter: &'a mut dyn Prompter,
+    source: &'a str,
+    dest: &'a str,
+}
+
+impl Resolver<'_> {
+    /// Decide a conflict, `None` when the user cancels the merge
+    fn resolve(
+        &mut self,
+        what: &str,
+        source_value: &str,
+        dest_value: &str,
+    ) -> EnvMgrResult<Option<Prefer>> {
+        if let Some(prefer) = self.prefer {
+            return Ok(Some(prefer));
+        }
+        let items = [
+            format!("keep {}: {dest_value}", self.dest),
+            format!("take {}: {source_value}", self.source),
+        ];
+        Ok(self
+            .prompter
+            .select(&format!("Conflict in {what}"), &items, 0)?
+            .map(|i| if i == 0 { Prefer::Dest } else { Prefer::Source }))
+    }
+}
+
+/// Build the merged environment in `staging`. Returns `false` when cancelled.
+fn stage(
+    envs_dir: &Path,
+    staging: &Path,
+    source: &str,
+    dest: &str,
+    resolver: &mut Resolver,
+) -> EnvMgrResult<bool> {
+    let source_dir = envs_dir.join(source);
+    let dest_dir = envs_dir.join(dest);
+
+    let Some(merged) = merge_configs(
+        EnvironmentConfig::load_shared_from_file(&source_dir)?,
+        EnvironmentConfig::load_shared_from_file(&dest_dir)?,
+        resolver,
+    )?
+    else {
+        return Ok(false);
+    };
+
+    if staging.exists() {
+        std::fs::remove_dir_all(staging)?;
+    }
+    copy_dir_recursive(&dest_dir, staging)?;
+
+    let source_files = source_dir.join(FILES_DIR_NAME);
+    let mut source_paths = discover_files_in_dir(&source_files)?;
+    source_paths.sort();
+    for source_path in source_paths {
+        let relative = source_path
+            .strip_prefix(&source_files)
+            .unwrap_or(&source_path);
+        let target = staging.join(FILES_DIR_NAME).join(relative);
+        if target.exists() {
+            if hash_file(&target)? == hash_file(&source_path)? {
+                continue;
+            }
+            match resolver.resolve(
+                &format!("file {}", relative.display()),
+                "its version",
+                "current version",
+            )? {
+                None => return Ok(false),
+                Some(Prefer::Dest) => continue,
+                Some(Prefer::Source) => {}
+            }
+        }
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::copy(&source_path, &target)?;
+    }
+
+    std::fs::write(
+        staging.join(ENV_CONFIG_FILE_NAME),
+        serde_norway::to_string(&merged)?,
+    )?;
+    Ok(true)
+}
+
+fn merge_configs(
+    source: EnvironmentConfig,
+    mut dest: EnvironmentConfig,
+    resolver: &mut Resolver,
+) -> EnvMgrResult<Option<EnvironmentConfig>> {
+    for var in source.env_vars {
+        match dest.env_vars.iter_mut().find(|v| v.key == var.key) {
+            None => dest.env_vars.push(var),
+            Some(existing) if existing.value == var.value => {}
+            Some(existing) => {
+                match resolver.resolve(
+                    &format!("env var {}", var.key),
+                    &var.display_value(),
+                    &existing.display_value(),
+                )? {
+                    None => return Ok(None),
+                    // The winning side's order comes along with its value
+                    Some(Prefer::Source) => *existing = var,
+                    Some(Prefer::Dest) => {}
+                }
+            }
+        }
+    }
+
+    if let Some(source_op) = source.op_ssh {
+        let op = dest
+            .op_ssh
+            .get_or_insert_with(OnePasswordSSHAgentConfig::default);
+        for key in source_op.keys {
+            if !op.keys.contains(&key) {
+                op.keys.push(key);
+            }
+        }
+    }
+
+    for document in source.op_documents {
+        match dest
+            .op_documents
+            .iter_mut()
+            .find(|d| d.target == document.target)
+        {
+            None => dest.op_documents.push(document),
+            Some(existing) if *existing == document => {}
+            Some(existing) => {
+                match resolver.resolve(
+                    &format!("op document {}", document.target),
+                    &document.to_string(),
+                    &existing.to_string(),
+                )? {
+                    None => return Ok(None),
+                    Some(Prefer::Source) => *existing = document,
+                    Some(Prefer::Dest) => {}
+                }
+            }
+        }
+    }
+
+    if let Some(source_gh) = source.gh_cli {
+        let gh = dest.gh_cli.get_or_insert_with(GhCliConfig::default);
+        for host in source_gh.hosts {
+            if !gh.hosts.contains(&host) {
+                gh.hosts.push(host);
+            }
+        }
+        for (key, value) in source_gh.gh_config {
+            match gh.gh_config.get(&key) {
+                None => {
+                    gh.gh_config.insert(key, value);
+                }
+                Some(existing) if *existing != value => {
+                    match resolver.resolve(&format!("gh_cli.gh_config.{key}"), &value, existing)? {
+                        None => return Ok(None),
+                        Some(Prefer::Source) => {
+                            gh.gh_config.insert(key, value);
+                        }
+                        Some(Prefer::Dest) => {}
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    match (source.tailscale, &dest.tailscale) {
+        (Some(source_ts), None) => dest.tailscale = Some(source_ts),
+        (Some(source_ts), Some(dest_ts)) if source_ts != *dest_ts => {
+            match resolver.resolve("tailscale", &source_ts.to_string(), &dest_ts.to_string())? {
+                None => return Ok(None),
+                Some(Prefer::Source) => dest.tailscale = Some(source_ts),
+                Some(Prefer::Dest) => {}
+            }
+        }
+        _ => {}
+    }
+
+    match (source.kube, &dest.kube) {
+        (Some(source_kube), None) => dest.kube = Some(source_kube),
+        (Some(source_kube), Some(dest_kube)) if source_kube != *dest_kube => {
+            match resolver.resolve("kube", &source_kube.to_string(), &dest_kube.to_string())? {
+                None => return Ok(None),
+                Some(Prefer::Source) => dest.kube = Some(source_kube),
+                Some(Prefer::Dest) => {}
+            }
+        }
+        _ => {}
+    }
+
+    match (source.aws, &dest.aws) {
+        (Some(source_aws), None) => dest.aws = Some(source_aws),
+        (Some(source_aws), Some(dest_aws)) if source_aws != *dest_aws => {
+            match resolver.resolve("aws", &source_aws.to_string(), &dest_aws.to_string())? {
+                None => return Ok(None),
+                Some(Prefer::Source) => dest.aws = Some(source_aws),
+                Some(Prefer::Dest) => {}
+            }
+        }
+        _ => {}
+    }
+
+    match (source.gcloud, &dest.gcloud) {
+        (Some(source_gcloud), None) => dest.gcloud = Some(source_gcloud),
+        (Some(source_gcloud), Some(dest_gcloud)) if source_gcloud != *dest_gcloud => match resolver
+            .resolve(
+                "gcloud",
+                &source_gcloud.to_string(),
+                &dest_gcloud.to_string(),
+            )? {
+            None => return Ok(None),
+            Some(Prefer::Source) => dest.gcloud = Some(source_gcloud),
+            Some(Prefer::Dest) => {}
+        },
+        _ => {}
+    }
+
+    match (source.npm, &dest.npm) {
+        (Some(source_npm), None) => dest.npm = Some(source_npm),
+        (Some(source_npm), Some(dest_npm)) if source_npm != *dest_npm => {
+            match resolver.resolve("npm", &source_npm.to_string(), &dest_npm.to_string())? {
+                None => return Ok(None),
+                Some(Prefer::Source) => dest.npm = Some(source_npm),
+                Some(Prefer::Dest) => {}
+            }
+        }
+        _ => {}
+    }
+
+    match (source.ssh, &dest.ssh) {
+        (Some(source_ssh), None) => dest.ssh = Some(source_ssh),
+        (Some(source_ssh), Some(dest_ssh)) if source_ssh != *dest_ssh => {
+            match resolver.resolve("ssh", &source_ssh.to_string(), &dest_ssh.to_string())? {
+                None => return Ok(None),
+                Some(Prefer::Source) => dest.ssh = Some(source_ssh),
+                Some(Prefer::Dest) => {}
+            }
+        }
+        _ => {}
+    }
+
+    match (source.gpg, &dest.gpg) {
+        (Some(source_gpg), None) => dest.gpg = Some(source_gpg),
+        (Some(source_gpg), Some(dest_gpg)) if source_gpg != *dest_gpg => {
+            match resolver.resolve("gpg", &source_gpg.to_string(), &dest_gpg.to_string())? {
+                None => return Ok(None),
+                Some(Prefer::Source) => dest.gpg = Some(source_gpg),
+                Some(Prefer::Dest) => {}
+            }
+        }
+        _ => {}
+    }
+
+    match (source.mise, &dest.mise) {
+        (Some(source_mise), None) => dest.mise = Some(source_mise),
+        (Some(source_mise), Some(dest_mise)) if source_mise != *dest_mise => {
+            match resolver.resolve("mise", &source_mise.to_string(), &dest_mise.to_string())? {
+                None => return Ok(None),
+                Some(Prefer::Source) => dest.mise = Some(source_mise),
+                Some(Prefer::Dest) => {}
+            }
+        }
+        _ => {}
+    }
+
+    match (source.maven, &dest.maven) {
+        (Some(source_maven), None) => dest.maven = Some(source_maven),
+        (Some(source_maven), Some(dest_maven)) if source_maven != *dest_maven => {
+            match resolver.resolve("maven", &source_maven.to_string(), &dest_maven.to_string())? {
+                None => return Ok(None),
+                Some(Prefer::Source) => dest.maven = Some(source_maven),
+                Some(Prefer::Dest) => {}
+            }
+        }
+        _ => {}
+    }
+
+    match (source.python, &dest.python) {
+        (Some(source_python), None) => dest.python = Some(source_python),
+        (Some(source_python), Some(dest_python)) if source_python != *dest_python => match resolver
+            .resolve(
+                "python",
+                &source_python.to_string(),
+                &dest_python.to_string(),
+            )? {
+            None => return Ok(None),
+            Some(Prefer::Source) => dest.python = Some(source_python),
+            Some(Prefer::Dest) => {}
+        },
+        _ => {}
+    }
+
+    match (source.cargo, &dest.cargo) {
+        (Some(source_cargo), None) => dest.cargo = Some(source_cargo),
+        (Some(source_cargo), Some(dest_cargo)) if source_cargo != *dest_cargo => {
+            match resolver.resolve("cargo", &source_cargo.to_string(), &dest_cargo.to_string())? {
+                None => return Ok(None),
+                Some(Prefer::Source) => dest.cargo = Some(source_cargo),
+                Some(Prefer::Dest) => {}
+            }
+        }
+        _ => {}
+    }
+
+    let mut values = vec![
+        ("locale", source.locale, &mut dest.locale),
+        ("timezone", source.timezone, &mut dest.timezone),
+    ];
+    if let Some(source_git) = source.git {
+        let git = dest.git.get_or_insert_with(GitConfig::default);
+        for include in source_git.includes {
+            if !git.includes.contains(&include) {
+                git.includes.push(include);
+            }
+        }
+        values.extend([
+            ("git.user_name", source_git.user_name, &mut git.user_name),
+            ("git.user_email", source_git.user_email, &mut git.user_email),
+            (
+                "git.signing_key",
+                source_git.signing_key,
+                &mut git.signing_key,
+            ),
+        ]);
+    }
+    for (label, source_value, dest_value) in values {
+        match (source_value, &dest_value) {
+            (Some(value), None) => *dest_value = Some(value),
+            (Some(value), Some(existing)) if value != *existing => {
+                match resolver.resolve(label, &value, existing)? {
+                    None => return Ok(None),
+                    Some(Prefer::Source) => *dest_value = Some(value),
+                    Some(Prefer::Dest) => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    dest.propagate_to_systemd_user = dest
+        .propagate_to_systemd_user
+        .or(source.propagate_to_systemd_user);
+    dest.danger |= source.danger;
+    // Keeping base out is the cautious side, like `danger`
+    dest.inherit_base &= source.inherit_base;
+    if dest.link_mode == LinkKind::Symlink {
+        dest.link_mode = source.link_mode;
+    }
+    for (path, kind) in source.link_modes {
+        dest.link_modes.entry(path).or_insert(kind);
+    }
+    for dir in source.link_dirs {
+        if !dest.link_dirs.contains(&dir) {
+            dest.link_dirs.push(dir);
+        }
+    }
+    dest.description = dest.description.or(source.description);
+    dest.group = dest.group.or(source.group);
+    for tag in source.tags {
+        if !dest.tags.contains(&tag) {
+            dest.tags.push(tag);
+        }
+    }
+    // A variable one side sets can't also be unset, setting it wins
+    for key in source.unset_vars {
+        if !dest.unset_vars.contains(&key) && !dest.env_vars.iter().any(|var| var.key == key) {
+            dest.unset_vars.push(key);
+        }
+    }
+    dest.unset_vars
+        .retain(|key| !dest.env_vars.iter().any(|var| &var.key == key));
+    for alias in source.aliases {
+        match dest.aliases.iter_mut().find(|a| a.name == alias.name) {
+            None => dest.aliases.push(alias),
+            Some(existing) if *existing == alias => {}
+            Some(existing) => {
+                match resolver.resolve(
+                    &format!("alias {}", alias.name),
+                    &alias.command,
+                    &existing.command,
+                )? {
+                    None => return Ok(None),
+                    Some(Prefer::Source) => *existing = alias,
+                    Some(Prefer::Dest) => {}
+                }
+            }
+        }
+    }
+
+    Ok(Some(dest))
+}
+
+/// Swap the staged environment into place and archive the source.
+///
+/// Each step is a rename; when one fails the earlier ones are rolled back.
+fn commit(envs_dir: &Path, staging: &Path, source: &str, dest: &str) -> EnvMgrResult<PathBuf> {
+    let source_dir = envs_dir.join(source);
+    let dest_dir = envs_dir.join(dest);
+    let old_dest = envs_dir.join(format!(".merge-{dest}.old"));
+    let archive_dir = envs_dir.join(ARCHIVE_DIR_NAME);
+    let archived_to = archive_dir.join(format!("{source}-{}", unix_now()));
+    std::fs::create_dir_all(&archive_dir)?;
+
+    std::fs::rename(&dest_dir, &old_dest)?;
+    if let Err(e) = std::fs::rename(staging, &dest_dir) {
+        std::fs::rename(&old_dest, &dest_dir)?;
+        return Err(e.into());
+    }
+    if let Err(e) = std::fs::rename(&source_dir, &archived_to) {
+        std::fs::rename(&dest_dir, staging)?;
+        std::fs::rename(&old_dest, &dest_dir)?;
+        return Err(e.into());
+    }
+    if let Err(e) = std::fs::remove_dir_all(&old_dest) {
+        warn!(
+            "Could not remove previous version of {dest} at {}: {e}",
+            old_dest.display()
+        );
+    }
+    Ok(archived_to)
+}
+
+fn copy_dir_recursive(from: &Path, to: &Path) -> EnvMgrResult<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir_recursive(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::config::EnvVarsConfig;
+    use crate::integrations::tailscale::TailscaleConfig;
+    use crate::prompt::{Answer, ReplayPrompter};
+
+    fn env_config(name: &str, vars: &[(&str, &str)], tailnet: Option<&str>) -> EnvironmentConfig {
+        EnvironmentConfig {
+            name: name.to_string(),
+            env_vars: vars
+                .iter()
+                .map(|(key, value)| EnvVarsConfig {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                    dynamic: None,
+                    order: 0,
+                    secret: false,
+                    when: None,
+                    integration: None,
+                })
+                .collect(),
+            tailscale: tailnet.map(|tailnet| TailscaleConfig {
+                tailnet: tailnet.to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// An environments dir holding `old` and `new` with some overlapping content
+    fn setup_envs(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let old = env_config("Old", &[("SHARED", "from-old"), ("ONLY_OLD", "1")], None);
+        let new = env_config("New", &[("SHARED", "from-new")], Some("new.ts.net"));
+        super::super::add::write_environment(&dir, "old", &old, None).unwrap();
+        super::super::add::write_environment(&dir, "new", &new, None).unwrap();
+
+        fs::create_dir_all(dir.join("old/files/.config")).unwrap();
+        fs::write(dir.join("old/files/.config/only-old"), "old").unwrap();
+        fs::write(dir.join("old/files/.gitconfig"), "old gitconfig").unwrap();
+        fs::write(dir.join("new/files/.gitconfig"), "new gitconfig").unwrap();
+        dir
+    }
+
+    fn load(dir: &Path, key: &str) -> EnvironmentConfig {
+        EnvironmentConfig::load_from_file(&dir.join(key)).unwrap()
+    }
+
+    #[test]
+    fn test_var_conflict_is_prompted() {
+        let dir = setup_envs("envmgr_test_merge_var_conflict");
+        let mut prompter = ReplayPrompter::new([
+            // SHARED: take the source value
+            Answer::Select(Some(1)),
+            // .gitconfig: keep the destination version
+            Answer::Select(Some(0)),
+            Answer::Confirm(true),
+        ]);
+
+        let outcome =
+            merge_environments_in(&dir, "old", "new", &MergeOptions::default(), &mut prompter)
+                .unwrap();
+
+        prompter.assert_exhausted();
+        assert!(prompter.prompts[0].contains("env var SHARED"));
+        let merged = load(&dir, "new");
+        assert_eq!(merged.name, "New");
+        let vars: Vec<_> = merged
+            .env_vars
+            .iter()
+            .map(|v| (v.key.as_str(), v.value.as_str()))
+            .collect();
+        assert_eq!(vars, [("SHARED", "from-old"), ("ONLY_OLD", "1")]);
+        assert_eq!(merged.tailscale.unwrap().tailnet, "new.ts.net");
+
+        let MergeOutcome::Merged { archived_to } = outcome else {
+            panic!("merge was not applied");
+        };
+        assert!(!dir.join("old").exists());
+        assert!(archived_to.join("config.yaml").is_file());
+        assert!(!dir.join(".merge-new").exists());
+        assert!(!dir.join(".merge-new.old").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_files_are_unioned() {
+        let dir = setup_envs("envmgr_test_merge_file_union");
+        let opts = MergeOptions {
+            prefer: Some(Prefer::Source),
+            yes: true,
+            ..MergeOptions::default()
+        };
+        let mut prompter = ReplayPrompter::new([]);
+
+        merge_environments_in(&dir, "old", "new", &opts, &mut prompter).unwrap();
+
+        assert!(prompter.prompts.is_empty());
+        assert_eq!(
+            fs::read_to_string(dir.join("new/files/.config/only-old")).unwrap(),
+            "old"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("new/files/.gitconfig")).unwrap(),
+            "old gitconfig"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_abort_leaves_originals_intact() {
+        let dir = setup_envs("envmgr_test_merge_abort");
+        let opts = MergeOptions {
+            prefer: Some(Prefer::Dest),
+            yes: false,
+            ..MergeOptions::default()
+        };
+        let mut prompter = ReplayPrompter::new([Answer::Confirm(false)]);
+
+        let outcome = merge_environments_in(&dir, "old", "new", &opts, &mut prompter).unwrap();
+
+        assert_eq!(outcome, MergeOutcome::Aborted);
+        assert_eq!(load(&dir, "old").env_vars.len(), 2);
+        assert_eq!(load(&dir, "new").env_vars[0].value, "from-new");
+        assert!(!dir.join("new/files/.config").exists());
+        assert!(!dir.join(".merge-new").exists());
+        assert!(!dir.join(ARCHIVE_DIR_NAME).exists());
+
+        // Cancelling a conflict prompt aborts as well
+        let mut prompter = ReplayPrompter::new([Answer::Select(None)]);
+        let outcome =
+            merge_environments_in(&dir, "old", "new", &MergeOptions::default(), &mut prompter)
+                .unwrap();
+        assert_eq!(outcome, MergeOutcome::Aborted);
+        assert!(dir.join("old").is_dir());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn plan(dir: &Path) -> String {
+        let opts = MergeOptions {
+            prefer: Some(Prefer::Source),
+            dry_run: true,
+            ..MergeOptions::default()
+        };
+        match merge_environments_in(dir, "old", "new", &opts, &mut ReplayPrompter::new([])) {
+            Ok(MergeOutcome::Planned { token }) => token,
+            other => panic!("expected a plan, got {other:?}"),
+        }
+    }
+
+    fn apply_with_token(dir: &Path, token: &str) -> EnvMgrResult<MergeOutcome> {
+        let opts = MergeOptions {
+            prefer: Some(Prefer::Source),
+            confirm_token: Some(token.to_string()),
+            ..MergeOptions::default()
+        };
+        merge_environments_in(dir, "old", "new", &opts, &mut ReplayPrompter::new([]))
+    }
+
+    #[test]
+    fn test_confirm_token_applies_reviewed_plan() {
+        let dir = setup_envs("envmgr_test_merge_confirm_token");
+
+        let token = plan(&dir);
+        assert!(dir.join("old").is_dir());
+        assert!(!dir.join(".merge-new").exists());
+        assert_eq!(plan(&dir), token, "plans must be deterministic");
+
+        let outcome = apply_with_token(&dir, &token).unwrap();
+
+        assert!(matches!(outcome, MergeOutcome::Merged { .. }));
+        assert_eq!(load(&dir, "new").env_vars[0].value, "from-old");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_confirm_token_rejected_after_config_change() {
+        let dir = setup_envs("envmgr_test_merge_stale_token");
+        let token = plan(&dir);
+        let changed = env_config("Old", &[("SHARED", "edited")], None);
+        super::super::add::write_environment(&dir, "old", &changed, None).unwrap();
+
+        let result = apply_with_token(&dir, &token);
+
+        assert!(matches!(
+            result,
+            Err(EnvMgrError::ConfirmTokenMismatch { .. })
+        ));
+        assert_eq!(load(&dir, "new").env_vars[0].value, "from-new");
+        assert!(dir.join("old").is_dir());
+        assert!(!dir.join(".merge-new").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,20 @@
+pub mod add;
+pub mod backups;
+pub mod completions;
+pub mod debug_bundle;
+pub mod direnv;
+pub mod doctor;
+pub mod export_env;
+pub mod files;
+pub mod history;
+pub mod integrations;
+pub mod list;
+pub mod merge;
+pub mod notices;
+pub mod prompt;
+pub mod prune;
+pub mod schema;
+pub mod show;
+pub mod status;
+pub mod template;
+pub mod walkthrough;
@@ -0,0 +1,48 @@
+//! `envmgr notices`: problems the prompt hook ran into, see [`crate::notices`].
+
+use log::info;
+
+use crate::{
+    commands::history::relative_time, daemon::unix_now, error::EnvMgrResult, notices::Notices,
+};
+
+pub fn print_notices(json: bool) -> EnvMgrResult<()> {
+    let mut notices = Notices::load();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&notices.entries)?);
+        return Ok(());
+    }
+    if notices.entries.is_empty() {
+        println!("No notices");
+        return Ok(());
+    }
+    let now = unix_now();
+    for entry in &notices.entries {
+        let subject = match entry.subject.as_str() {
+            "" => String::new(),
+            subject => format!(" in {subject}"),
+        };
+        println!(
+            "[{}]{subject}: {}x, first {}, last {}",
+            entry.code,
+            entry.count,
+            relative_time(now, entry.first_seen),
+            relative_time(now, entry.last_seen)
+        );
+        println!("    {}", entry.message);
+    }
+    // Listed here, so the next command doesn't point at them again
+    if notices.unannounced() > 0 {
+        notices.announced_at = now;
+        notices.store()?;
+    }
+    Ok(())
+}
+
+/// Acknowledge all notices
+pub fn clear_notices() -> EnvMgrResult<()> {
+    let count = Notices::load().entries.len();
+    Notices::default().store()?;
+    info!("Cleared {count} notice(s)");
+    Ok(())
+}
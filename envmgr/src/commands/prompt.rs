@@ -0,0 +1,211 @@
+//! `envmgr prompt`: the current environment for shell prompts.
+//!
+//! Everything comes from the state file, which `switch` keeps up to date, so a
+//! prompt redraw never loads an environment config. The gh user and tailnet come from
+//! the daemon's probe cache; without it only the gh hosts file is read, a redraw can't
+//! wait for tailscale.
+
+use crate::{
+    daemon::{PROBE_MAX_AGE, ProbeCache, unix_now},
+    error::EnvMgrResult,
+    platform,
+    state::State,
+};
+
+/// How long a prompt engine may reuse the output; a switch shows up after at most this
+const CACHE_HINT_SECS: u32 = 2;
+
+/// What `envmgr prompt --json` prints
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct PromptInfo {
+    pub key: String,
+    pub name: String,
+    pub danger: bool,
+    /// Always false for now: nothing verifies environments yet
+    pub verified: bool,
+    /// Always 0 for now: environments can't be stacked yet
+    pub stack_depth: u32,
+    /// Active gh user on github.com
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gh_user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tailnet: Option<String>,
+}
+
+impl PromptInfo {
+    pub fn from_state(state: &State) -> Self {
+        Self {
+            key: state.current_env_key.clone(),
+            // States written before the name was recorded only know the key
+            name: state
+                .current_env_name
+                .clone()
+                .unwrap_or_else(|| state.current_env_key.clone()),
+            danger: state.current_env_danger,
+            verified: false,
+            stack_depth: 0,
+            gh_user: None,
+            tailnet: None,
+        }
+    }
+
+    pub fn with_probes(self, probes: &ProbeCache) -> Self {
+        Self {
+            gh_user: probes.gh_users.get("github.com").cloned(),
+            tailnet: probes.tailnet.clone(),
+            ..self
+        }
+    }
+
+    /// The key, with a trailing `!` for danger environments
+    pub fn render_plain(&self) -> String {
+        if self.danger {
+            format!("{}!", self.key)
+        } else {
+            self.key.clone()
+        }
+    }
+}
+
+pub fn print_prompt(json: bool) -> EnvMgrResult<()> {
+    let mut info = PromptInfo::from_state(&State::get_state()?);
+    if let Some(state_dir) = platform::state_dir() {
+        let probes = ProbeCache::load_or_probe(
+            &state_dir,
+            unix_now(),
+            PROBE_MAX_AGE,
+            ProbeCache::probe_files,
+        );
+        info = info.with_probes(&probes);
+    }
+    if json {
+        println!("{}", serde_json::to_string(&info)?);
+    } else {
+        println!("{}", info.render_plain());
+    }
+    Ok(())
+}
+
+/// `bin_name prompt` as a POSIX shell command line
+fn prompt_command(bin_name: &str) -> String {
+    format!("'{}' prompt", bin_name.replace('\'', r"'\''"))
+}
+
+/// A `[custom.envmgr]` module for starship.toml
+pub fn starship_config(bin_name: &str) -> String {
+    // A JSON string is also a valid TOML basic string, and unlike `toml` it never
+    // switches to a literal string
+    let command = serde_json::Value::String(prompt_command(bin_name));
+    format!(
+        indoc::indoc! {r#"
+            # envmgr segment for ~/.config/starship.toml
+            # `envmgr prompt` only reads envmgr's state file, so it is cheap enough to run on
+            # every prompt; starship doesn't cache custom modules. A trailing `!` marks a
+            # danger environment.
+            [custom.envmgr]
+            command = {command}
+            when = true
+            shell = ["sh"]
+            format = "[$output]($style) "
+            style = "bold yellow"
+        "#},
+        command = command,
+    )
+}
+
+/// A `command` segment for an oh-my-posh config
+pub fn oh_my_posh_config(bin_name: &str) -> EnvMgrResult<String> {
+    let segment = serde_json::json!({
+        "type": "command",
+        "style": "plain",
+        "foreground": "yellow",
+        "template": " {{ .Output }} ",
+        "properties": {
+            "shell": "sh",
+            "command": prompt_command(bin_name),
+        },
+        "cache": {
+            "duration": format!("{CACHE_HINT_SECS}s"),
+            "strategy": "session",
+        },
+    });
+    Ok(serde_json::to_string_pretty(&segment)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prompt_info_json_shape() {
+        let state = State {
+            current_env_key: "prod".into(),
+            current_env_name: Some("Production".into()),
+            current_env_danger: true,
+            ..State::default()
+        };
+        let info = PromptInfo::from_state(&state);
+
+        assert_eq!(
+            serde_json::to_string(&info).unwrap(),
+            r#"{"key":"prod","name":"Production","danger":true,"verified":false,"stack_depth":0}"#
+        );
+        assert_eq!(info.render_plain(), "prod!");
+
+        let probes = ProbeCache {
+            gh_users: [("github.com".to_string(), "alice".to_string())].into(),
+            tailnet: Some("corp.ts.net".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_string(&info.with_probes(&probes)).unwrap(),
+            r#"{"key":"prod","name":"Production","danger":true,"verified":false,"stack_depth":0,"gh_user":"alice","tailnet":"corp.ts.net"}"#
+        );
+
+        let legacy = PromptInfo::from_state(&State::default());
+        assert_eq!(legacy.name, "base");
+        assert_eq!(legacy.render_plain(), "base");
+    }
+
+    #[test]
+    fn test_starship_config_golden() {
+        assert_eq!(
+            starship_config("/opt/my tools/envmgr"),
+            indoc::indoc! {r#"
+                # envmgr segment for ~/.config/starship.toml
+                # `envmgr prompt` only reads envmgr's state file, so it is cheap enough to run on
+                # every prompt; starship doesn't cache custom modules. A trailing `!` marks a
+                # danger environment.
+                [custom.envmgr]
+                command = "'/opt/my tools/envmgr' prompt"
+                when = true
+                shell = ["sh"]
+                format = "[$output]($style) "
+                style = "bold yellow"
+            "#}
+        );
+        assert!(starship_config("it's").contains(r#"command = "'it'\\''s' prompt""#));
+    }
+
+    #[test]
+    fn test_oh_my_posh_config_golden() {
+        assert_eq!(
+            oh_my_posh_config("envmgr").unwrap(),
+            indoc::indoc! {r#"
+                {
+                  "cache": {
+                    "duration": "2s",
+                    "strategy": "session"
+                  },
+                  "foreground": "yellow",
+                  "properties": {
+                    "command": "'envmgr' prompt",
+                    "shell": "sh"
+                  },
+                  "style": "plain",
+                  "template": " {{ .Output }} ",
+                  "type": "command"
+                }"#}
+        );
+    }
+}
@@ -0,0 +1,189 @@
+//! `envmgr prune`: clean up managed files left behind by deleted environments.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use log::info;
+
+use crate::{
+    config::envmgr_config_dir,
+    error::EnvMgrResult,
+    state::{ManagedFile, State},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PruneAction {
+    /// Managed symlink whose source no longer exists
+    RemoveLink(PathBuf),
+    /// State entry whose target no longer exists
+    DropEntry(PathBuf),
+    /// Left alone, with the reason
+    Skip(PathBuf, &'static str),
+}
+
+impl fmt::Display for PruneAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PruneAction::RemoveLink(path) => write!(f, "remove dangling link {}", path.display()),
+            PruneAction::DropEntry(path) => write!(f, "forget missing {}", path.display()),
+            PruneAction::Skip(path, reason) => write!(f, "skip {} ({reason})", path.display()),
+        }
+    }
+}
+
+/// Decide what to do with each managed file. Healthy links get no action.
+///
+/// A dangling link is only removed while it points at its recorded source, or for
+/// entries without one, anywhere inside `owner_root`.
+pub fn plan_prune(managed_files: &[ManagedFile], owner_root: &Path) -> Vec<PruneAction> {
+    let mut actions = vec![];
+    for managed in managed_files {
+        let path = &managed.target;
+        if path.is_symlink() {
+            // `exists` follows the link, so this is a dangling link
+            if !path.exists() {
+                let unrecorded = managed.source.as_os_str().is_empty();
+                match std::fs::read_link(path) {
+                    Ok(source) if !unrecorded && source == managed.source => {
+                        actions.push(PruneAction::RemoveLink(path.clone()));
+                    }
+                    Ok(source) if unrecorded && source.starts_with(owner_root) => {
+                        actions.push(PruneAction::RemoveLink(path.clone()));
+                    }
+                    _ if !unrecorded => actions.push(PruneAction::Skip(
+                        path.clone(),
+                        "link no longer points at its source",
+                    )),
+                    _ => actions.push(PruneAction::Skip(
+                        path.clone(),
+                        "link points outside envmgr",
+                    )),
+                }
+            }
+        } else if path.exists() {
+            actions.push(PruneAction::Skip(path.clone(), "not a symlink"));
+        } else {
+            actions.push(PruneAction::DropEntry(path.clone()));
+        }
+    }
+    actions
+}
+
+/// Carry out `actions`, removing links and their state entries
+pub fn apply_prune(state: &mut State, actions: &[PruneAction]) -> EnvMgrResult<()> {
+    for action in actions {
+        match action {
+            PruneAction::RemoveLink(path) => {
+                std::fs::remove_file(path)?;
+                state.managed_files.retain(|f| f.target != *path);
+                state.linked_dirs.retain(|f| f != path);
+                state.mirrored_links.retain(|m| m.target != *path);
+            }
+            PruneAction::DropEntry(path) => {
+                state.managed_files.retain(|f| f.target != *path);
+                state.linked_dirs.retain(|f| f != path);
+                state.mirrored_links.retain(|m| m.target != *path);
+            }
+            PruneAction::Skip(..) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Prune the current state, printing every action. Nothing changes with `dry_run`.
+pub fn prune(dry_run: bool) -> EnvMgrResult<Vec<PruneAction>> {
+    let mut state = State::get_state()?;
+    // Re-created symlinks of `files/` may dangle on purpose
+    let managed: Vec<ManagedFile> = state
+        .managed_files
+        .iter()
+        .filter(|managed| {
+            !state
+                .mirrored_links
+                .iter()
+                .any(|mirrored| mirrored.target == managed.target)
+        })
+        .cloned()
+        .collect();
+    let actions = plan_prune(&managed, &envmgr_config_dir());
+    for action in &actions {
+        println!("{}{action}", if dry_run { "would " } else { "" });
+    }
+    if actions.is_empty() {
+        info!("Nothing to prune");
+    } else if !dry_run {
+        apply_prune(&mut state, &actions)?;
+        state.store_state()?;
+    }
+    Ok(actions)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::platform;
+
+    #[test]
+    fn test_plan_and_apply_prune() {
+        let dir = std::env::temp_dir().join("envmgr_test_prune_plan");
+        let _ = fs::remove_dir_all(&dir);
+        let owner_root = dir.join("config");
+        fs::create_dir_all(&owner_root).unwrap();
+
+        let healthy_source = owner_root.join("healthy");
+        fs::write(&healthy_source, "").unwrap();
+        let healthy = dir.join("healthy-link");
+        let dangling = dir.join("dangling-link");
+        let moved = dir.join("moved-link");
+        let foreign = dir.join("foreign-link");
+        let real_file = dir.join("real-file");
+        let missing = dir.join("missing");
+        platform::symlink(&healthy_source, &healthy).unwrap();
+        platform::symlink(&owner_root.join("deleted-env/file"), &dangling).unwrap();
+        platform::symlink(&owner_root.join("deleted-env/other"), &moved).unwrap();
+        platform::symlink(&dir.join("not-ours"), &foreign).unwrap();
+        fs::write(&real_file, "").unwrap();
+
+        let mut state = State {
+            managed_files: vec![
+                ManagedFile::legacy(healthy.clone()),
+                ManagedFile::legacy(dangling.clone()),
+                ManagedFile {
+                    target: moved.clone(),
+                    source: owner_root.join("deleted-env/moved"),
+                    env_key: "deleted-env".to_string(),
+                },
+                ManagedFile::legacy(foreign.clone()),
+                ManagedFile::legacy(real_file.clone()),
+                ManagedFile::legacy(missing.clone()),
+            ],
+            ..State::default()
+        };
+
+        let actions = plan_prune(&state.managed_files, &owner_root);
+        assert_eq!(
+            actions,
+            [
+                PruneAction::RemoveLink(dangling.clone()),
+                PruneAction::Skip(moved.clone(), "link no longer points at its source"),
+                PruneAction::Skip(foreign.clone(), "link points outside envmgr"),
+                PruneAction::Skip(real_file.clone(), "not a symlink"),
+                PruneAction::DropEntry(missing),
+            ]
+        );
+
+        apply_prune(&mut state, &actions).unwrap();
+        assert!(!dangling.is_symlink());
+        assert!(foreign.is_symlink());
+        assert_eq!(
+            state.managed_target_paths(),
+            [healthy, moved, foreign, real_file]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
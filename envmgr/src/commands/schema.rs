@@ -0,0 +1,108 @@
+//! `envmgr schema`: JSON Schemas for editor integration, e.g. the YAML language server.
+
+use std::path::{Path, PathBuf};
+
+use crate::{
+    config::{EnvironmentConfig, GlobalConfig, envmgr_config_dir, files_manifest::FilesManifest},
+    error::EnvMgrResult,
+    state::State,
+};
+
+const SCHEMAS_DIR_NAME: &str = "schemas";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SchemaKind {
+    /// An environment's config.yaml
+    Environment,
+    /// global.yaml
+    Global,
+    /// An environment's files.yaml
+    Files,
+    /// The state file
+    State,
+}
+
+impl SchemaKind {
+    pub const ALL: [SchemaKind; 4] = [
+        SchemaKind::Environment,
+        SchemaKind::Global,
+        SchemaKind::Files,
+        SchemaKind::State,
+    ];
+
+    pub fn schema(self) -> schemars::Schema {
+        match self {
+            SchemaKind::Environment => schemars::schema_for!(EnvironmentConfig),
+            SchemaKind::Global => schemars::schema_for!(GlobalConfig),
+            SchemaKind::Files => schemars::schema_for!(FilesManifest),
+            SchemaKind::State => schemars::schema_for!(State),
+        }
+    }
+
+    pub fn file_name(self) -> &'static str {
+        match self {
+            SchemaKind::Environment => "environment.schema.json",
+            SchemaKind::Global => "global.schema.json",
+            SchemaKind::Files => "files.schema.json",
+            SchemaKind::State => "state.schema.json",
+        }
+    }
+
+    pub fn to_json(self) -> EnvMgrResult<String> {
+        Ok(serde_json::to_string_pretty(&self.schema())?)
+    }
+}
+
+/// `~/.config/envmgr/schemas`
+pub fn schemas_dir() -> PathBuf {
+    envmgr_config_dir().join(SCHEMAS_DIR_NAME)
+}
+
+/// The written environment schema, if `envmgr schema --write` has been run
+pub fn installed_environment_schema() -> Option<PathBuf> {
+    let path = schemas_dir().join(SchemaKind::Environment.file_name());
+    path.is_file().then_some(path)
+}
+
+/// Write the schemas for `kinds` into `dir`, returning the written paths
+pub fn write_schemas(dir: &Path, kinds: &[SchemaKind]) -> EnvMgrResult<Vec<PathBuf>> {
+    std::fs::create_dir_all(dir)?;
+    let mut written = vec![];
+    for kind in kinds {
+        let path = dir.join(kind.file_name());
+        std::fs::write(&path, kind.to_json()?)?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_environment_schema_has_config_fields() {
+        let json: serde_json::Value =
+            serde_json::from_str(&SchemaKind::Environment.to_json().unwrap()).unwrap();
+        let properties = json["properties"].as_object().unwrap();
+        for field in ["name", "env_vars", "op_ssh", "gh_cli", "tailscale"] {
+            assert!(properties.contains_key(field), "missing {field}");
+        }
+        assert_eq!(json["required"], serde_json::json!(["name"]));
+    }
+
+    #[test]
+    fn test_write_schemas() {
+        let dir = std::env::temp_dir().join("envmgr_test_write_schemas");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let written = write_schemas(&dir, &SchemaKind::ALL).unwrap();
+
+        assert_eq!(written.len(), 4);
+        for path in written {
+            let content = std::fs::read_to_string(path).unwrap();
+            serde_json::from_str::<serde_json::Value>(&content).unwrap();
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
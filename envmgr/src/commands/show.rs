@@ -0,0 +1,292 @@
+//! `envmgr show`: the effective configuration of one environment, secrets masked.
+
+use std::{fmt::Write as _, path::PathBuf};
+
+use crate::{
+    config::{AliasConfig, BASE_ENV_NAME, EnvVarsConfig, GlobalConfig},
+    daemon::{ProbeCache, current_probes, unix_now},
+    environment::{Environment, layer_aliases, layer_env_vars, layer_files},
+    error::EnvMgrResult,
+    runner::SystemRunner,
+    state::State,
+};
+
+/// An env var as `show` displays it
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct ShownEnvVar {
+    pub key: String,
+    /// The configured value or its dynamic source, `********` for a secret
+    pub value: String,
+    pub secret: bool,
+    /// The integration exporting the variable, e.g. `aws`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub integration: Option<String>,
+}
+
+impl From<&EnvVarsConfig> for ShownEnvVar {
+    fn from(var: &EnvVarsConfig) -> Self {
+        Self {
+            key: var.key.clone(),
+            value: var.display_value(),
+            secret: var.secret,
+            integration: var.integration.map(String::from),
+        }
+    }
+}
+
+/// What `use` and `switch` would apply for an environment, base layered in
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct EnvironmentDetails {
+    pub key: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    pub group: Option<String>,
+    pub danger: bool,
+    pub current: bool,
+    /// In the order `use` emits them, dynamic values unresolved
+    pub env_vars: Vec<ShownEnvVar>,
+    pub unset_vars: Vec<String>,
+    pub aliases: Vec<AliasConfig>,
+    /// Link targets
+    pub files: Vec<PathBuf>,
+    pub integrations: Vec<String>,
+    /// Tool versions, gh users and tailnet of this machine, with `--live`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub live: Option<ProbeCache>,
+}
+
+impl EnvironmentDetails {
+    /// Details of `environment` over `base` and the `global` env vars, or of `base` alone
+    /// when it is `None`
+    pub fn new(
+        global: &[EnvVarsConfig],
+        base: &Environment,
+        environment: Option<&Environment>,
+        mut files: Vec<PathBuf>,
+        current: bool,
+    ) -> Self {
+        let env = environment.unwrap_or(base);
+        let (vars, unset_vars) = layer_env_vars(global, base, environment);
+        files.sort();
+        Self {
+            key: env.key.clone(),
+            name: env.name.clone(),
+            description: env.description.clone(),
+            tags: env.tags.clone(),
+            group: env.group.clone(),
+            danger: env.danger,
+            current,
+            env_vars: vars.iter().map(ShownEnvVar::from).collect(),
+            unset_vars,
+            aliases: layer_aliases(base, environment),
+            files,
+            integrations: env
+                .configured_integrations()
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            live: None,
+        }
+    }
+
+    /// Render the details for terminal output
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let current = if self.current { " (current)" } else { "" };
+        let danger = if self.danger { " !" } else { "" };
+        let _ = writeln!(out, "{} [{}]{current}{danger}", self.name, self.key);
+        if let Some(description) = &self.description {
+            let _ = writeln!(out, "{description}");
+        }
+        if !self.tags.is_empty() {
+            let _ = writeln!(out, "Tags: {}", self.tags.join(", "));
+        }
+        if let Some(group) = &self.group {
+            let _ = writeln!(out, "Group: {group}");
+        }
+
+        if !self.env_vars.is_empty() {
+            let _ = writeln!(out, "\nEnvironment variables:");
+            for var in &self.env_vars {
+                let from = match &var.integration {
+                    Some(integration) => format!(" (from {integration})"),
+                    None => String::new(),
+                };
+                let _ = writeln!(out, "  {}={}{from}", var.key, var.value);
+            }
+        }
+        if !self.unset_vars.is_empty() {
+            let _ = writeln!(out, "\nUnset: {}", self.unset_vars.join(", "));
+        }
+        if !self.aliases.is_empty() {
+            let _ = writeln!(out, "\nAliases:");
+            for alias in &self.aliases {
+                let kind = if alias.abbr { " (abbr)" } else { "" };
+                let _ = writeln!(out, "  {} = {}{kind}", alias.name, alias.command);
+            }
+        }
+        if !self.files.is_empty() {
+            let _ = writeln!(out, "\nFiles:");
+            for file in &self.files {
+                let _ = writeln!(out, "  {}", file.display());
+            }
+        }
+        if !self.integrations.is_empty() {
+            let _ = writeln!(out, "\nIntegrations: {}", self.integrations.join(", "));
+        }
+        if let Some(live) = &self.live {
+            let _ = write!(out, "\n{}", live.render(unix_now()));
+        }
+        out
+    }
+}
+
+/// Print the environment `key`, the current one by default, with `live` the probes too
+pub fn show_environment(key: Option<&str>, json: bool, live: bool) -> EnvMgrResult<()> {
+    let current = State::get_state()?.current_env_key;
+    let key = key.unwrap_or(&current);
+    let base = Environment::load_base_environment()?;
+    let environment = match key {
+        BASE_ENV_NAME => None,
+        key => Some(Environment::load_environment_by_key(key)?),
+    };
+    let files = layer_files(&base, environment.as_ref())?;
+    let global_vars = GlobalConfig::load()?.env_vars_for_this_host();
+    let details = EnvironmentDetails {
+        live: live.then(|| current_probes(&SystemRunner)),
+        ..EnvironmentDetails::new(
+            &global_vars,
+            &base,
+            environment.as_ref(),
+            files.into_keys().collect(),
+            key == current,
+        )
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&details)?);
+    } else {
+        print!("{}", details.render());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn environment(key: &str, env_vars: Vec<EnvVarsConfig>) -> Environment {
+        Environment {
+            key: key.to_string(),
+            name: key.to_uppercase(),
+            env_vars,
+            ..Default::default()
+        }
+    }
+
+    fn var(key: &str, value: &str, secret: bool) -> EnvVarsConfig {
+        EnvVarsConfig {
+            key: key.to_string(),
+            value: value.to_string(),
+            secret,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_secrets_are_masked() {
+        let base = environment("base", vec![var("EDITOR", "hx", false)]);
+        let work = environment(
+            "work",
+            vec![
+                var("API_TOKEN", "hunter2-raw-token", true),
+                var("REGION", "eu", false),
+            ],
+        );
+        let details = EnvironmentDetails::new(&[], &base, Some(&work), vec![], true);
+
+        assert_eq!(
+            details.render(),
+            indoc::indoc! {"
+                WORK [work] (current)
+
+                Environment variables:
+                  EDITOR=hx
+                  API_TOKEN=********
+                  REGION=eu
+            "}
+        );
+        let json = serde_json::to_string(&details).unwrap();
+        assert!(!json.contains("hunter2"), "{json}");
+        assert!(
+            json.contains(r#""value":"********","secret":true"#),
+            "{json}"
+        );
+        // Debug output, e.g. in logs, is masked as well
+        assert!(!format!("{:?}", work.env_vars).contains("hunter2"));
+    }
+
+    #[test]
+    fn test_integration_vars_are_attributed() {
+        let base = environment("base", vec![]);
+        let work = environment(
+            "work",
+            vec![
+                EnvVarsConfig {
+                    integration: Some("aws"),
+                    ..var("AWS_PROFILE", "client-admin", false)
+                },
+                var("REGION", "eu", false),
+            ],
+        );
+        let details = EnvironmentDetails::new(&[], &base, Some(&work), vec![], false);
+
+        assert!(
+            details
+                .render()
+                .contains("  AWS_PROFILE=client-admin (from aws)\n  REGION=eu\n")
+        );
+        let json = serde_json::to_string(&details.env_vars).unwrap();
+        assert!(json.contains(r#""integration":"aws""#), "{json}");
+        assert_eq!(json.matches("integration").count(), 1);
+    }
+
+    #[test]
+    fn test_environment_without_base() {
+        let base = environment("base", vec![var("EDITOR", "hx", false)]);
+        let mut client = environment("client", vec![var("REGION", "us", false)]);
+        client.inherit_base = false;
+        client.unset_vars = vec!["EDITOR".into(), "PAGER".into()];
+
+        let details = EnvironmentDetails::new(&[], &base, Some(&client), vec![], false);
+        assert_eq!(
+            details.env_vars,
+            vec![ShownEnvVar::from(&var("REGION", "us", false))]
+        );
+        assert_eq!(details.unset_vars, vec!["EDITOR", "PAGER"]);
+    }
+
+    #[test]
+    fn test_live_probes_are_shown_when_asked_for() {
+        let base = environment("base", vec![]);
+        let details = EnvironmentDetails::new(&[], &base, None, vec![], true);
+        assert!(!serde_json::to_string(&details).unwrap().contains("live"));
+
+        let details = EnvironmentDetails {
+            live: Some(ProbeCache {
+                refreshed_at: unix_now(),
+                tool_versions: [("gh".to_string(), "gh version 2.40.0".to_string())].into(),
+                tailnet: Some("corp.ts.net".into()),
+                ..Default::default()
+            }),
+            ..details
+        };
+        assert!(
+            details
+                .render()
+                .ends_with("\nProbed just now\n  gh: gh version 2.40.0\n  tailnet: corp.ts.net\n"),
+            "{}",
+            details.render()
+        );
+    }
+}
@@ -0,0 +1,214 @@
+//! `envmgr status`: the current environment and the links envmgr manages for it.
+
+use std::{
+    fmt::Write as _,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::EnvMgrResult,
+    state::{ManagedFile, State},
+};
+
+/// What is found at a managed link's target now
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkHealth {
+    /// Still the link envmgr left there
+    Linked,
+    /// Something else has replaced it
+    Changed,
+    Missing,
+}
+
+/// A managed link as `status` shows it
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct LinkStatus {
+    pub target: PathBuf,
+    /// Empty when the state predates recorded sources
+    pub source: PathBuf,
+    /// The environment the link belongs to, empty when unknown
+    pub env_key: String,
+    pub health: LinkHealth,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Status {
+    pub current_env_key: String,
+    pub current_env_name: Option<String>,
+    /// By environment, then target
+    pub links: Vec<LinkStatus>,
+}
+
+impl Status {
+    pub fn new(state: &State) -> Self {
+        let mut links: Vec<LinkStatus> = state
+            .managed_files
+            .iter()
+            .map(|managed| {
+                // Re-created symlinks point where their original does
+                let expected = state
+                    .mirrored_links
+                    .iter()
+                    .find(|mirrored| mirrored.target == managed.target)
+                    .map_or(managed.source.as_path(), |mirrored| &mirrored.link);
+                LinkStatus {
+                    target: managed.target.clone(),
+                    source: managed.source.clone(),
+                    env_key: managed.env_key.clone(),
+                    health: link_health(managed, expected),
+                }
+            })
+            .collect();
+        links.sort_by(|a, b| (&a.env_key, &a.target).cmp(&(&b.env_key, &b.target)));
+        Self {
+            current_env_key: state.current_env_key.clone(),
+            current_env_name: state.current_env_name.clone(),
+            links,
+        }
+    }
+
+    /// Render the status for terminal output, links grouped by the environment
+    /// managing them
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = match &self.current_env_name {
+            Some(name) => writeln!(
+                out,
+                "Current environment: {name} [{}]",
+                self.current_env_key
+            ),
+            None => writeln!(out, "Current environment: {}", self.current_env_key),
+        };
+        if self.links.is_empty() {
+            let _ = writeln!(out, "\nNo managed links");
+        }
+        let mut group = None;
+        for link in &self.links {
+            if group != Some(&link.env_key) {
+                let owner = match link.env_key.as_str() {
+                    "" => "an unrecorded environment",
+                    key => key,
+                };
+                let _ = writeln!(out, "\nManaged by {owner}:");
+                group = Some(&link.env_key);
+            }
+            let health = match link.health {
+                LinkHealth::Linked => "",
+                LinkHealth::Changed => " (changed)",
+                LinkHealth::Missing => " (missing)",
+            };
+            let _ = match link.source.as_os_str().is_empty() {
+                true => writeln!(out, "  {}{health}", link.target.display()),
+                false => writeln!(
+                    out,
+                    "  {} -> {}{health}",
+                    link.target.display(),
+                    link.source.display()
+                ),
+            };
+        }
+        out
+    }
+}
+
+fn link_health(managed: &ManagedFile, expected: &Path) -> LinkHealth {
+    match std::fs::read_link(&managed.target) {
+        // Without a recorded source any link counts
+        Ok(_) if expected.as_os_str().is_empty() => LinkHealth::Linked,
+        Ok(current) if current == expected => LinkHealth::Linked,
+        Ok(_) => LinkHealth::Changed,
+        Err(_) if managed.target.symlink_metadata().is_ok() => LinkHealth::Changed,
+        Err(_) => LinkHealth::Missing,
+    }
+}
+
+/// Print the current environment and its managed links
+pub fn print_status(json: bool) -> EnvMgrResult<()> {
+    let status = Status::new(&State::get_state()?);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+    } else {
+        print!("{}", status.render());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_links_are_grouped_by_environment() {
+        let link = |target: &str, source: &str, env_key: &str, health| LinkStatus {
+            target: target.into(),
+            source: source.into(),
+            env_key: env_key.to_string(),
+            health,
+        };
+        let status = Status {
+            current_env_key: "work".to_string(),
+            current_env_name: Some("Work".to_string()),
+            links: vec![
+                link("/home/.bashrc", "", "", LinkHealth::Linked),
+                link(
+                    "/home/.baserc",
+                    "/cfg/base/files/.baserc",
+                    "base",
+                    LinkHealth::Linked,
+                ),
+                link(
+                    "/home/.gitconfig",
+                    "/cfg/environments/work/files/.gitconfig",
+                    "work",
+                    LinkHealth::Changed,
+                ),
+            ],
+        };
+
+        assert_eq!(
+            status.render(),
+            "Current environment: Work [work]\n\
+             \n\
+             Managed by an unrecorded environment:\n  /home/.bashrc\n\
+             \n\
+             Managed by base:\n  /home/.baserc -> /cfg/base/files/.baserc\n\
+             \n\
+             Managed by work:\n  /home/.gitconfig -> /cfg/environments/work/files/.gitconfig (changed)\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_health_of_links() {
+        let dir = std::env::temp_dir().join("envmgr_test_status_health");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source");
+        let linked = dir.join("linked");
+        let moved = dir.join("moved");
+        let real = dir.join("real");
+        crate::platform::symlink(&source, &linked).unwrap();
+        crate::platform::symlink(&dir.join("elsewhere"), &moved).unwrap();
+        std::fs::write(&real, "").unwrap();
+        let managed = |target: &Path| ManagedFile {
+            target: target.to_path_buf(),
+            source: source.clone(),
+            env_key: "base".to_string(),
+        };
+
+        assert_eq!(link_health(&managed(&linked), &source), LinkHealth::Linked);
+        assert_eq!(link_health(&managed(&moved), &source), LinkHealth::Changed);
+        assert_eq!(link_health(&managed(&real), &source), LinkHealth::Changed);
+        assert_eq!(
+            link_health(&managed(&dir.join("gone")), &source),
+            LinkHealth::Missing
+        );
+        assert_eq!(
+            link_health(&ManagedFile::legacy(moved.clone()), Path::new("")),
+            LinkHealth::Linked
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,386 @@
+//! Templates for `envmgr add --template`.
+//!
+//! Any environment is a local template. Remote templates are git repos with a
+//! `config.yaml` at their root, cloned to `templates/remote/<alias>/` in the config
+//! dir. Environments created from a remote template are marked untrusted: their
+//! integrations are held back until `envmgr template trust`, since ssh, git, kube, aws,
+//! cargo and npm config can each make a tool run a command.
+
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use crate::{
+    commands::add::slugify,
+    config::{BASE_ENV_NAME, ENV_CONFIG_FILE_NAME, EnvironmentConfig},
+    error::{EnvMgrError, EnvMgrResult},
+    git::Git,
+    runner::CommandRunner,
+};
+
+pub const TEMPLATES_DIR_NAME: &str = "templates";
+const REMOTE_DIR_NAME: &str = "remote";
+/// Written into environments created from a remote template
+pub const TEMPLATE_ORIGIN_FILE_NAME: &str = "template.toml";
+
+/// Where an environment's template came from, kept in its [`TEMPLATE_ORIGIN_FILE_NAME`]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TemplateOrigin {
+    pub template: String,
+    pub url: String,
+    /// Commit of the template the environment was created from
+    pub commit: String,
+    /// Whether the environment's integrations may be applied
+    pub trusted: bool,
+}
+
+impl TemplateOrigin {
+    pub fn load(env_dir: &Path) -> EnvMgrResult<Option<Self>> {
+        let path = env_dir.join(TEMPLATE_ORIGIN_FILE_NAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(toml::from_str(&std::fs::read_to_string(path)?)?))
+    }
+
+    pub fn write(&self, env_dir: &Path) -> EnvMgrResult<()> {
+        std::fs::write(
+            env_dir.join(TEMPLATE_ORIGIN_FILE_NAME),
+            toml::to_string(self)?,
+        )?;
+        Ok(())
+    }
+}
+
+/// Whether the integrations of the environment in `env_dir` may be applied: not when it
+/// was created from a remote template that nobody trusted yet
+pub fn integrations_trusted(env_dir: &Path) -> EnvMgrResult<bool> {
+    Ok(TemplateOrigin::load(env_dir)?.is_none_or(|origin| origin.trusted))
+}
+
+/// Mark environment `key`, created from a remote template, as reviewed
+pub fn trust_environment(key: &str) -> EnvMgrResult<TemplateOrigin> {
+    let env_dir = EnvironmentConfig::get_env_dir_by_key(key);
+    if key == BASE_ENV_NAME || !env_dir.join(ENV_CONFIG_FILE_NAME).is_file() {
+        return Err(EnvMgrError::EnvironmentNotFound(key.to_string()));
+    }
+    let mut origin = TemplateOrigin::load(&env_dir)?.ok_or_else(|| {
+        EnvMgrError::Template(format!("{key} was not created from a remote template"))
+    })?;
+    origin.trusted = true;
+    origin.write(&env_dir)?;
+    Ok(origin)
+}
+
+/// A template resolved for `add --template`
+#[derive(Debug, Clone)]
+pub struct Template {
+    pub config: EnvironmentConfig,
+    /// Set for remote templates only
+    pub origin: Option<TemplateOrigin>,
+}
+
+/// An installed remote template
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteTemplate {
+    pub alias: String,
+    pub url: String,
+    pub commit: String,
+}
+
+/// Remote templates below one config dir
+pub struct TemplateRegistry<'r> {
+    remote_dir: PathBuf,
+    git: Git<'r>,
+}
+
+/// Alias for a template repo, e.g. `https://host/org/onboarding.git` -> `onboarding`
+pub fn alias_from_url(url: &str) -> String {
+    let last = url
+        .trim_end_matches('/')
+        .rsplit(['/', ':'])
+        .next()
+        .unwrap_or_default();
+    slugify(last.strip_suffix(".git").unwrap_or(last))
+}
+
+impl<'r> TemplateRegistry<'r> {
+    pub fn new(config_dir: &Path, runner: &'r dyn CommandRunner) -> Self {
+        Self {
+            remote_dir: config_dir.join(TEMPLATES_DIR_NAME).join(REMOTE_DIR_NAME),
+            git: Git::new(runner),
+        }
+    }
+
+    fn remote(&self, alias: &str) -> EnvMgrResult<RemoteTemplate> {
+        let dir = self.remote_dir.join(alias);
+        Ok(RemoteTemplate {
+            alias: alias.to_string(),
+            url: self.git.remote_url(&dir)?,
+            commit: self.git.head_commit(&dir)?,
+        })
+    }
+
+    /// Pull the clone of `alias`, keeping the cached one with a warning when that fails
+    fn pull_or_keep(&self, alias: &str) -> EnvMgrResult<RemoteTemplate> {
+        if let Err(e) = self.git.pull(&self.remote_dir.join(alias)) {
+            warn!("Could not update template '{alias}', using the cached clone: {e}");
+        }
+        self.remote(alias)
+    }
+
+    /// Clone `url` as `alias` (derived from the url by default), or update an existing clone of it
+    pub fn install(&self, url: &str, alias: Option<&str>) -> EnvMgrResult<RemoteTemplate> {
+        let alias = alias.map_or_else(|| alias_from_url(url), str::to_string);
+        if alias.is_empty()
+            || !alias
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(EnvMgrError::Template(format!(
+                "'{alias}' is not a valid template alias, pick one with --name"
+            )));
+        }
+
+        let dir = self.remote_dir.join(&alias);
+        if dir.exists() {
+            let installed = self.git.remote_url(&dir)?;
+            if installed != url {
+                return Err(EnvMgrError::Template(format!(
+                    "'{alias}' is already installed from {installed}, pick another alias with --name"
+                )));
+            }
+            info!("Template '{alias}' is already installed, updating it");
+            return self.pull_or_keep(&alias);
+        }
+
+        std::fs::create_dir_all(&self.remote_dir)?;
+        if let Err(e) = self.git.clone(url, &dir) {
+            let _ = std::fs::remove_dir_all(&dir);
+            return Err(e);
+        }
+        if !dir.join(ENV_CONFIG_FILE_NAME).is_file() {
+            std::fs::remove_dir_all(&dir)?;
+            return Err(EnvMgrError::Template(format!(
+                "{url} has no {ENV_CONFIG_FILE_NAME} at its root"
+            )));
+        }
+        self.remote(&alias)
+    }
+
+    /// Installed remote templates, sorted by alias
+    pub fn remotes(&self) -> EnvMgrResult<Vec<RemoteTemplate>> {
+        if !self.remote_dir.is_dir() {
+            return Ok(vec![]);
+        }
+        let mut aliases = vec![];
+        for entry in std::fs::read_dir(&self.remote_dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                aliases.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+        aliases.sort();
+        aliases.iter().map(|alias| self.remote(alias)).collect()
+    }
+
+    /// Pull `alias`, or every remote template without one
+    pub fn update(&self, alias: Option<&str>) -> EnvMgrResult<Vec<RemoteTemplate>> {
+        match alias {
+            Some(alias) if !self.remote_dir.join(alias).is_dir() => Err(EnvMgrError::Template(
+                format!("no remote template '{alias}' is installed"),
+            )),
+            Some(alias) => Ok(vec![self.pull_or_keep(alias)?]),
+            None => self
+                .remotes()?
+                .iter()
+                .map(|remote| self.pull_or_keep(&remote.alias))
+                .collect(),
+        }
+    }
+
+    /// Resolve `name` as a remote template alias first, then as an environment key
+    pub fn load(&self, name: &str) -> EnvMgrResult<Template> {
+        let dir = self.remote_dir.join(name);
+        if dir.join(ENV_CONFIG_FILE_NAME).is_file() {
+            let remote = self.remote(name)?;
+            return Ok(Template {
+                config: EnvironmentConfig::load_shared_from_file(&dir)?,
+                // Integrations of a remote template only apply once reviewed
+                origin: Some(TemplateOrigin {
+                    template: remote.alias,
+                    url: remote.url,
+                    commit: remote.commit,
+                    trusted: false,
+                }),
+            });
+        }
+        match EnvironmentConfig::load_env_config_by_key(name) {
+            Ok(config) if name != BASE_ENV_NAME => Ok(Template {
+                config,
+                origin: None,
+            }),
+            Ok(_) | Err(EnvMgrError::EnvironmentNotFound(_)) => Err(EnvMgrError::Template(
+                format!("no environment or remote template named '{name}'"),
+            )),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Print local (environment) and remote templates
+pub fn print_templates(registry: &TemplateRegistry) -> EnvMgrResult<()> {
+    let envs_dir = EnvironmentConfig::get_all_envs_dir();
+    let mut local = vec![];
+    if envs_dir.is_dir() {
+        for entry in std::fs::read_dir(&envs_dir)? {
+            let entry = entry?;
+            let key = entry.file_name().to_string_lossy().to_string();
+            if entry.file_type()?.is_dir() && !key.starts_with('.') {
+                local.push(key);
+            }
+        }
+    }
+    local.sort();
+    for key in local {
+        println!("local   {key}");
+    }
+    for remote in registry.remotes()? {
+        println!(
+            "remote  {}  {}  {}",
+            remote.alias,
+            &remote.commit[..remote.commit.len().min(12)],
+            remote.url
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process::Command};
+
+    use super::*;
+    use crate::runner::SystemRunner;
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args([
+                "-c",
+                "user.name=envmgr",
+                "-c",
+                "user.email=envmgr@example.com",
+            ])
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {args:?} failed");
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
+
+    /// A bare repo acting as the remote plus a working clone pushing to it
+    fn remote_repo(name: &str) -> (PathBuf, PathBuf, PathBuf) {
+        let root = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&root);
+        let bare = root.join("onboarding.git");
+        let work = root.join("work");
+        fs::create_dir_all(&bare).unwrap();
+        git(&bare, &["init", "--quiet", "--bare"]);
+        git(
+            &root,
+            &["clone", "--quiet", &bare.to_string_lossy(), "work"],
+        );
+        fs::write(
+            work.join(ENV_CONFIG_FILE_NAME),
+            "name: Onboarding\ntailscale:\n  tailnet: client.ts.net\n",
+        )
+        .unwrap();
+        fs::create_dir_all(work.join("hooks")).unwrap();
+        fs::write(work.join("hooks/on_switch.sh"), "echo hi\n").unwrap();
+        git(&work, &["add", "-A"]);
+        git(&work, &["commit", "--quiet", "-m", "initial"]);
+        git(&work, &["push", "--quiet", "origin", "HEAD"]);
+        (root, bare, work)
+    }
+
+    #[test]
+    fn test_alias_from_url() {
+        assert_eq!(
+            alias_from_url("https://git.example.com/platform/onboarding.git"),
+            "onboarding"
+        );
+        assert_eq!(
+            alias_from_url("git@github.com:org/Client_Setup.git"),
+            "client-setup"
+        );
+        assert_eq!(alias_from_url("/srv/templates/work/"), "work");
+    }
+
+    #[test]
+    fn test_install_update_and_offline_fallback() {
+        let (root, bare, work) = remote_repo("envmgr_test_template_install");
+        let config_dir = root.join("config");
+        let registry = TemplateRegistry::new(&config_dir, &SystemRunner);
+        let url = bare.to_string_lossy().to_string();
+
+        let installed = registry.install(&url, None).unwrap();
+        assert_eq!(installed.alias, "onboarding");
+        assert_eq!(installed.commit, git(&work, &["rev-parse", "HEAD"]));
+        assert!(
+            config_dir
+                .join("templates/remote/onboarding/config.yaml")
+                .is_file()
+        );
+        assert!(matches!(
+            registry.install("/elsewhere/onboarding.git", None),
+            Err(EnvMgrError::Template(_))
+        ));
+
+        fs::write(work.join(ENV_CONFIG_FILE_NAME), "name: Onboarding v2\n").unwrap();
+        git(&work, &["commit", "--quiet", "-am", "v2"]);
+        git(&work, &["push", "--quiet", "origin", "HEAD"]);
+        let updated = registry.update(Some("onboarding")).unwrap();
+        assert_eq!(updated[0].commit, git(&work, &["rev-parse", "HEAD"]));
+        assert_eq!(
+            registry.load("onboarding").unwrap().config.name,
+            "Onboarding v2"
+        );
+
+        // Remote gone: the cached clone keeps working
+        fs::remove_dir_all(&bare).unwrap();
+        let offline = registry.update(None).unwrap();
+        assert_eq!(offline[0].commit, updated[0].commit);
+        assert!(matches!(
+            registry.update(Some("missing")),
+            Err(EnvMgrError::Template(_))
+        ));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_remote_template_instantiates_untrusted() {
+        let (root, bare, work) = remote_repo("envmgr_test_template_trust");
+        let registry = TemplateRegistry::new(&root.join("config"), &SystemRunner);
+        registry
+            .install(&bare.to_string_lossy(), Some("client"))
+            .unwrap();
+
+        let template = registry.load("client").unwrap();
+
+        assert_eq!(template.config.tailscale.unwrap().tailnet, "client.ts.net");
+        let origin = template.origin.unwrap();
+        assert!(!origin.trusted);
+        assert_eq!(origin.commit, git(&work, &["rev-parse", "HEAD"]));
+
+        let env_dir = root.join("environments/client-y");
+        fs::create_dir_all(&env_dir).unwrap();
+        origin.write(&env_dir).unwrap();
+        assert_eq!(TemplateOrigin::load(&env_dir).unwrap(), Some(origin));
+        assert_eq!(TemplateOrigin::load(&root).unwrap(), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,655 @@
+//! `envmgr walkthrough`: a guided first run explaining base, environments, vars,
+//! files, the hook and switching by doing each of them.
+//!
+//! Progress is kept in the state, so an interrupted walkthrough continues where it
+//! stopped. Every step can be skipped.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    cli::Shell,
+    commands::add::write_environment,
+    config::{
+        BASE_ENV_NAME, CONFIG_DIR_ENV_VAR, ENV_CONFIG_FILE_NAME, ENVS_DIR_NAME, EnvVarsConfig,
+        EnvironmentConfig, FILES_DIR_NAME, envmgr_config_dir,
+    },
+    daemon::unix_now,
+    environment::{Environment, EnvironmentManager, SwitchOptions, discover_files_in_dir},
+    error::{EnvMgrError, EnvMgrResult},
+    fs::RealFs,
+    integrations::IntegrationSelection,
+    platform,
+    prompt::Prompter,
+    state::{ManagedFile, State},
+};
+
+/// Key of the environment the walkthrough creates
+pub const DEMO_KEY: &str = "demo";
+const DEMO_VAR: &str = "ENVMGR_DEMO";
+const DEMO_VALUE: &str = "hello from the demo environment";
+const DEMO_FILE: &str = ".envmgr-demo";
+
+/// One step of the walkthrough, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Init,
+    CreateEnvironment,
+    AddVar,
+    AddFile,
+    InstallHook,
+    Switch,
+    Verify,
+}
+
+impl Step {
+    pub const ALL: [Step; 7] = [
+        Step::Init,
+        Step::CreateEnvironment,
+        Step::AddVar,
+        Step::AddFile,
+        Step::InstallHook,
+        Step::Switch,
+        Step::Verify,
+    ];
+
+    /// Name recorded in the state once the step is done or skipped
+    pub fn id(self) -> &'static str {
+        match self {
+            Step::Init => "init",
+            Step::CreateEnvironment => "create-environment",
+            Step::AddVar => "add-var",
+            Step::AddFile => "add-file",
+            Step::InstallHook => "install-hook",
+            Step::Switch => "switch",
+            Step::Verify => "verify",
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            Step::Init => "Set up the base environment",
+            Step::CreateEnvironment => "Create a demo environment",
+            Step::AddVar => "Add an env var to it",
+            Step::AddFile => "Add a file to it",
+            Step::InstallHook => "Install the fish hook",
+            Step::Switch => "Switch to the demo environment",
+            Step::Verify => "Check the result",
+        }
+    }
+
+    fn explanation(self) -> &'static str {
+        match self {
+            Step::Init => {
+                "The base environment holds what every environment shares. Everything in it \
+                 applies unless the active environment overrides it."
+            }
+            Step::CreateEnvironment => {
+                "Environments (work, personal, a client) are directories under `environments/`, \
+                 each with a config.yaml and a files/ directory. Only one is active at a time."
+            }
+            Step::AddVar => {
+                "Env vars live in config.yaml. They are not exported by `switch`, but by \
+                 `envmgr use`, which prints shell commands; the hook runs it for you."
+            }
+            Step::AddFile => {
+                "Files under files/ are symlinked into your home directory, at the same \
+                 relative path, while the environment is active."
+            }
+            Step::InstallHook => {
+                "The hook makes every shell run `envmgr use` before drawing the prompt, so \
+                 variables follow switches without restarting the shell."
+            }
+            Step::Switch => {
+                "`envmgr switch` makes an environment active: it records it in the state, \
+                 links its files and runs its integrations."
+            }
+            Step::Verify => "Everything the previous steps changed, checked on disk.",
+        }
+    }
+}
+
+/// Where the walkthrough reads and writes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalkthroughRoot {
+    pub config_dir: PathBuf,
+    pub state_dir: PathBuf,
+    pub home: PathBuf,
+    sandbox: bool,
+}
+
+impl WalkthroughRoot {
+    /// The user's own config, state and home directories
+    pub fn real() -> EnvMgrResult<Self> {
+        Ok(Self {
+            config_dir: envmgr_config_dir(),
+            state_dir: platform::state_dir().ok_or(EnvMgrError::DirError("state".into()))?,
+            home: dirs::home_dir().ok_or(EnvMgrError::DirError("home".into()))?,
+            sandbox: false,
+        })
+    }
+
+    /// Throwaway config, state and home directories under `dir`
+    pub fn sandbox(dir: &Path) -> Self {
+        Self {
+            config_dir: dir.join("config"),
+            state_dir: dir.join("state"),
+            home: dir.join("home"),
+            sandbox: true,
+        }
+    }
+
+    /// Where `--sandbox` puts its root, stable so an interrupted run can be resumed
+    pub fn default_sandbox_dir() -> PathBuf {
+        std::env::temp_dir().join("envmgr-walkthrough")
+    }
+
+    /// Command prefix pointing envmgr at the sandbox, to look around after the walkthrough
+    pub fn explore_hint(&self, bin_name: &str) -> String {
+        format!(
+            "{CONFIG_DIR_ENV_VAR}={} {}={} HOME={} {bin_name} list",
+            self.config_dir.display(),
+            platform::STATE_DIR_ENV_VAR,
+            self.state_dir.display(),
+            self.home.display()
+        )
+    }
+
+    fn demo_dir(&self) -> PathBuf {
+        self.config_dir.join(ENVS_DIR_NAME).join(DEMO_KEY)
+    }
+
+    fn hook_file(&self) -> PathBuf {
+        self.home.join(".config/fish/conf.d/10-envmgr.fish")
+    }
+
+    fn load_state(&self) -> EnvMgrResult<State> {
+        if self.sandbox {
+            State::load_from_dir(&RealFs, &self.state_dir, false)
+        } else {
+            State::get_state()
+        }
+    }
+
+    fn store_state(&self, state: &State) -> EnvMgrResult<()> {
+        if self.sandbox {
+            fs::create_dir_all(&self.state_dir)?;
+            state.store_in_dir(&RealFs, &self.state_dir, false)
+        } else {
+            state.store_state()
+        }
+    }
+}
+
+/// How a walkthrough run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkthroughOutcome {
+    Completed,
+    /// Stopped before `next`, which the next run starts with
+    Paused {
+        next: Step,
+    },
+}
+
+/// Run the steps not done yet, asking before each one.
+///
+/// `restart` forgets earlier progress first.
+pub fn run_walkthrough(
+    root: &WalkthroughRoot,
+    bin_name: &str,
+    restart: bool,
+    prompter: &mut dyn Prompter,
+) -> EnvMgrResult<WalkthroughOutcome> {
+    let mut state = root.load_state()?;
+    if restart && !state.walkthrough_steps.is_empty() {
+        state.walkthrough_steps.clear();
+        root.store_state(&state)?;
+    }
+    let choices = [
+        "Do it".to_string(),
+        "Skip this step".to_string(),
+        "Stop here and continue later".to_string(),
+    ];
+    for (i, step) in Step::ALL.into_iter().enumerate() {
+        if state.walkthrough_steps.iter().any(|id| id == step.id()) {
+            continue;
+        }
+        let title = format!("Step {}/{}: {}", i + 1, Step::ALL.len(), step.title());
+        println!("\n{title}\n{}", step.explanation());
+        match prompter.select(&title, &choices, 0)? {
+            Some(0) => {
+                for change in run_step(step, root, bin_name)? {
+                    println!("  {change}");
+                }
+            }
+            Some(1) => println!("  Skipped"),
+            _ => {
+                println!("Run `{bin_name} walkthrough` again to continue from here");
+                return Ok(WalkthroughOutcome::Paused { next: step });
+            }
+        }
+        // The switch step stores the state itself
+        state = root.load_state()?;
+        state.walkthrough_steps.push(step.id().to_string());
+        root.store_state(&state)?;
+    }
+    Ok(WalkthroughOutcome::Completed)
+}
+
+/// Perform `step`, returning what it changed on disk
+fn run_step(step: Step, root: &WalkthroughRoot, bin_name: &str) -> EnvMgrResult<Vec<String>> {
+    match step {
+        Step::Init => init(root),
+        Step::CreateEnvironment => create_environment(root),
+        Step::AddVar => add_var(root),
+        Step::AddFile => add_file(root),
+        Step::InstallHook => install_hook(root, bin_name),
+        Step::Switch => switch(root),
+        Step::Verify => Ok(verify(root)),
+    }
+}
+
+fn init(root: &WalkthroughRoot) -> EnvMgrResult<Vec<String>> {
+    let config_file = root
+        .config_dir
+        .join(BASE_ENV_NAME)
+        .join(ENV_CONFIG_FILE_NAME);
+    if config_file.exists() {
+        return Ok(vec![format!(
+            "{} already exists, nothing changed",
+            config_file.display()
+        )]);
+    }
+    let config: EnvironmentConfig = serde_norway::from_str("name: Base")?;
+    let dir = write_environment(&root.config_dir, BASE_ENV_NAME, &config, None)?;
+    Ok(vec![
+        format!("Created {}", config_file.display()),
+        format!(
+            "Created {}, for files every environment links",
+            dir.join(FILES_DIR_NAME).display()
+        ),
+    ])
+}
+
+fn create_environment(root: &WalkthroughRoot) -> EnvMgrResult<Vec<String>> {
+    let dir = root.demo_dir();
+    if dir.join(ENV_CONFIG_FILE_NAME).exists() {
+        return Ok(vec![format!(
+            "{} already exists, nothing changed",
+            dir.display()
+        )]);
+    }
+    let config: EnvironmentConfig = serde_norway::from_str("name: Demo")?;
+    let envs_dir = root.config_dir.join(ENVS_DIR_NAME);
+    write_environment(&envs_dir, DEMO_KEY, &config, None)?;
+    Ok(vec![
+        format!("Created {}", dir.join(ENV_CONFIG_FILE_NAME).display()),
+        format!("Created {}", dir.join(FILES_DIR_NAME).display()),
+    ])
+}
+
+fn load_demo_config(root: &WalkthroughRoot) -> EnvMgrResult<Option<EnvironmentConfig>> {
+    match fs::read_to_string(root.demo_dir().join(ENV_CONFIG_FILE_NAME)) {
+        Ok(content) => Ok(Some(serde_norway::from_str(&content)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn no_demo_environment() -> Vec<String> {
+    vec!["The demo environment was skipped, nothing changed".to_string()]
+}
+
+fn add_var(root: &WalkthroughRoot) -> EnvMgrResult<Vec<String>> {
+    let Some(mut config) = load_demo_config(root)? else {
+        return Ok(no_demo_environment());
+    };
+    if config.env_vars.iter().any(|var| var.key == DEMO_VAR) {
+        return Ok(vec![format!("{DEMO_VAR} is already set, nothing changed")]);
+    }
+    config.env_vars.push(EnvVarsConfig {
+        key: DEMO_VAR.to_string(),
+        value: DEMO_VALUE.to_string(),
+        ..Default::default()
+    });
+    let envs_dir = root.config_dir.join(ENVS_DIR_NAME);
+    let dir = write_environment(&envs_dir, DEMO_KEY, &config, None)?;
+    Ok(vec![format!(
+        "Added {DEMO_VAR}: \"{DEMO_VALUE}\" to env_vars in {}",
+        dir.join(ENV_CONFIG_FILE_NAME).display()
+    )])
+}
+
+fn add_file(root: &WalkthroughRoot) -> EnvMgrResult<Vec<String>> {
+    let files_dir = root.demo_dir().join(FILES_DIR_NAME);
+    if !files_dir.is_dir() {
+        return Ok(no_demo_environment());
+    }
+    let file = files_dir.join(DEMO_FILE);
+    fs::write(
+        &file,
+        "Linked by envmgr while the demo environment is active\n",
+    )?;
+    Ok(vec![format!(
+        "Wrote {}, it will be linked as {}",
+        file.display(),
+        root.home.join(DEMO_FILE).display()
+    )])
+}
+
+fn install_hook(root: &WalkthroughRoot, bin_name: &str) -> EnvMgrResult<Vec<String>> {
+    let path = root.hook_file();
+    let script = format!("{}\n", Shell::Fish.hook_script(bin_name));
+    let mut changes = match fs::read_to_string(&path) {
+        Ok(existing) if existing == script => {
+            vec![format!(
+                "{} already holds the hook, nothing changed",
+                path.display()
+            )]
+        }
+        Ok(existing) if !existing.starts_with("# envmgr fish hook") => {
+            vec![format!(
+                "{} exists and isn't an envmgr hook, left it alone",
+                path.display()
+            )]
+        }
+        _ => {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&path, script)?;
+            vec![format!(
+                "Wrote {}, fish loads it in every new shell",
+                path.display()
+            )]
+        }
+    };
+    changes.push(format!(
+        "PowerShell users add `{bin_name} hook powershell | Out-String | Invoke-Expression` \
+         to their $PROFILE instead"
+    ));
+    Ok(changes)
+}
+
+fn switch(root: &WalkthroughRoot) -> EnvMgrResult<Vec<String>> {
+    let Some(config) = load_demo_config(root)? else {
+        return Ok(no_demo_environment());
+    };
+    if !root.sandbox {
+        EnvironmentManager::switch_environment_by_key(
+            DEMO_KEY,
+            &SwitchOptions {
+                integrations: IntegrationSelection::Only(vec![]),
+                ..SwitchOptions::default()
+            },
+        )?;
+        return Ok(vec![
+            format!("{DEMO_KEY} is now the current environment"),
+            "Linked its files and those of the base environment".to_string(),
+        ]);
+    }
+
+    // The sandbox can't go through the manager, which only knows the real directories
+    let config_file = root.demo_dir().join(ENV_CONFIG_FILE_NAME);
+    let environment = Environment::load_from_config(DEMO_KEY, &config, &config_file)?;
+    let mut state = root.load_state()?;
+    let from = state.set_current(&environment);
+    state.record_switch(&from, DEMO_KEY, unix_now());
+    state.previous_env_key = Some(from.clone());
+    let mut changes = vec![format!(
+        "Recorded {DEMO_KEY} as the current environment (was {from}) in {}",
+        root.state_dir.display()
+    )];
+
+    let files_dir = root.demo_dir().join(FILES_DIR_NAME);
+    let mut sources = discover_files_in_dir(&files_dir)?;
+    sources.sort();
+    for source in sources {
+        let Ok(relative) = source.strip_prefix(&files_dir) else {
+            continue;
+        };
+        let target = root.home.join(relative);
+        if target.symlink_metadata().is_ok() {
+            changes.push(format!("{} already exists, not linked", target.display()));
+            continue;
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        platform::symlink(&source, &target)?;
+        changes.push(format!(
+            "Linked {} -> {}",
+            target.display(),
+            source.display()
+        ));
+        state.managed_files.push(ManagedFile {
+            target,
+            source,
+            env_key: DEMO_KEY.to_string(),
+        });
+    }
+    root.store_state(&state)?;
+    Ok(changes)
+}
+
+/// One line per thing the walkthrough sets up, whether it is in place
+fn verify(root: &WalkthroughRoot) -> Vec<String> {
+    let demo_config = load_demo_config(root).ok().flatten();
+    let current = root.load_state().map(|state| state.current_env_key).ok();
+    let link = fs::read_link(root.home.join(DEMO_FILE)).ok();
+    let checks = [
+        (
+            root.config_dir
+                .join(BASE_ENV_NAME)
+                .join(ENV_CONFIG_FILE_NAME)
+                .exists(),
+            "base environment exists".to_string(),
+        ),
+        (
+            demo_config.is_some(),
+            format!("{DEMO_KEY} environment exists"),
+        ),
+        (
+            demo_config
+                .iter()
+                .any(|config| config.env_vars.iter().any(|var| var.key == DEMO_VAR)),
+            format!("{DEMO_KEY} sets {DEMO_VAR}"),
+        ),
+        (
+            root.demo_dir()
+                .join(FILES_DIR_NAME)
+                .join(DEMO_FILE)
+                .is_file(),
+            format!("{DEMO_KEY} has {DEMO_FILE}"),
+        ),
+        (root.hook_file().is_file(), "fish hook is installed".into()),
+        (
+            current.as_deref() == Some(DEMO_KEY),
+            format!("{DEMO_KEY} is the current environment"),
+        ),
+        (
+            link == Some(root.demo_dir().join(FILES_DIR_NAME).join(DEMO_FILE)),
+            format!("~/{DEMO_FILE} is linked"),
+        ),
+    ];
+    checks
+        .into_iter()
+        .map(|(ok, what)| format!("[{}] {what}", if ok { "ok" } else { "--" }))
+        .collect()
+}
+
+/// Run the walkthrough on the real directories, or in the default sandbox
+pub fn walkthrough(
+    sandbox: bool,
+    restart: bool,
+    bin_name: &str,
+    prompter: &mut dyn Prompter,
+) -> EnvMgrResult<()> {
+    let root = if sandbox {
+        WalkthroughRoot::sandbox(&WalkthroughRoot::default_sandbox_dir())
+    } else {
+        WalkthroughRoot::real()?
+    };
+    if run_walkthrough(&root, bin_name, restart, prompter)? == WalkthroughOutcome::Completed {
+        println!("\nWalkthrough complete.");
+        if sandbox {
+            println!(
+                "Look around the sandbox with:\n  {}",
+                root.explore_hint(bin_name)
+            );
+        } else {
+            println!("Remove the demo with `{bin_name} switch base` and deleting its directory");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::prompt::{Answer, ReplayPrompter};
+
+    const DO: Answer = Answer::Select(Some(0));
+    const SKIP: Answer = Answer::Select(Some(1));
+    const STOP: Answer = Answer::Select(Some(2));
+
+    fn sandbox(name: &str) -> (PathBuf, WalkthroughRoot) {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        let root = WalkthroughRoot::sandbox(&dir);
+        (dir, root)
+    }
+
+    #[test]
+    fn test_sandbox_walkthrough_end_to_end() {
+        let (dir, root) = sandbox("envmgr_test_walkthrough_e2e");
+        let mut prompter = ReplayPrompter::new([DO, DO, DO, DO, DO, DO, DO]);
+
+        let outcome = run_walkthrough(&root, "envmgr", false, &mut prompter).unwrap();
+
+        assert_eq!(outcome, WalkthroughOutcome::Completed);
+        prompter.assert_exhausted();
+        assert_eq!(prompter.prompts[0], "Step 1/7: Set up the base environment");
+
+        // Init
+        let base: EnvironmentConfig = serde_norway::from_str(
+            &fs::read_to_string(root.config_dir.join("base/config.yaml")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(base.name, "Base");
+        assert!(root.config_dir.join("base/files").is_dir());
+        // Demo environment with its var and file
+        let demo = load_demo_config(&root).unwrap().unwrap();
+        assert_eq!(demo.name, "Demo");
+        assert_eq!(demo.env_vars.len(), 1);
+        assert_eq!(demo.env_vars[0].key, DEMO_VAR);
+        assert_eq!(demo.env_vars[0].value, DEMO_VALUE);
+        let demo_file = root.demo_dir().join("files").join(DEMO_FILE);
+        assert!(demo_file.is_file());
+        // Hook
+        assert_eq!(
+            fs::read_to_string(root.hook_file()).unwrap(),
+            format!("{}\n", Shell::Fish.hook_script("envmgr"))
+        );
+        // Switch
+        let state = State::load_from_dir(&RealFs, &root.state_dir, false).unwrap();
+        assert_eq!(state.current_env_key, DEMO_KEY);
+        assert_eq!(state.current_env_name.as_deref(), Some("Demo"));
+        assert_eq!(state.previous_env_key.as_deref(), Some(BASE_ENV_NAME));
+        assert_eq!(state.history.len(), 1);
+        assert_eq!(fs::read_link(root.home.join(DEMO_FILE)).unwrap(), demo_file);
+        assert_eq!(state.managed_target_paths(), [root.home.join(DEMO_FILE)]);
+        // Verify
+        assert!(verify(&root).iter().all(|line| line.starts_with("[ok]")));
+        let steps: Vec<&str> = Step::ALL.iter().map(|step| step.id()).collect();
+        assert_eq!(state.walkthrough_steps, steps);
+
+        // Nothing left to do
+        let mut prompter = ReplayPrompter::new([]);
+        let outcome = run_walkthrough(&root, "envmgr", false, &mut prompter).unwrap();
+        assert_eq!(outcome, WalkthroughOutcome::Completed);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_walkthrough_resumes_where_it_stopped() {
+        let (dir, root) = sandbox("envmgr_test_walkthrough_resume");
+
+        let mut prompter = ReplayPrompter::new([DO, DO, STOP]);
+        let outcome = run_walkthrough(&root, "envmgr", false, &mut prompter).unwrap();
+        assert_eq!(outcome, WalkthroughOutcome::Paused { next: Step::AddVar });
+        assert!(root.demo_dir().is_dir());
+        assert!(
+            load_demo_config(&root)
+                .unwrap()
+                .unwrap()
+                .env_vars
+                .is_empty()
+        );
+
+        let mut prompter = ReplayPrompter::new([DO, SKIP, SKIP, SKIP, DO]);
+        let outcome = run_walkthrough(&root, "envmgr", false, &mut prompter).unwrap();
+        assert_eq!(outcome, WalkthroughOutcome::Completed);
+        assert_eq!(prompter.prompts[0], "Step 3/7: Add an env var to it");
+        assert!(!root.demo_dir().join("files").join(DEMO_FILE).exists());
+        assert!(!root.hook_file().exists());
+        assert_eq!(
+            State::load_from_dir(&RealFs, &root.state_dir, false)
+                .unwrap()
+                .current_env_key,
+            BASE_ENV_NAME
+        );
+        assert_eq!(
+            verify(&root),
+            [
+                "[ok] base environment exists",
+                "[ok] demo environment exists",
+                "[ok] demo sets ENVMGR_DEMO",
+                "[--] demo has .envmgr-demo",
+                "[--] fish hook is installed",
+                "[--] demo is the current environment",
+                "[--] ~/.envmgr-demo is linked",
+            ]
+        );
+
+        // A restart asks again, and leaves what already exists alone
+        let mut prompter = ReplayPrompter::new([DO, STOP]);
+        let outcome = run_walkthrough(&root, "envmgr", true, &mut prompter).unwrap();
+        assert_eq!(
+            outcome,
+            WalkthroughOutcome::Paused {
+                next: Step::CreateEnvironment
+            }
+        );
+        assert_eq!(
+            init(&root).unwrap(),
+            [format!(
+                "{} already exists, nothing changed",
+                root.config_dir.join("base/config.yaml").display()
+            )]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_steps_after_skipped_environment_change_nothing() {
+        let (dir, root) = sandbox("envmgr_test_walkthrough_skipped_env");
+        let mut prompter = ReplayPrompter::new([DO, SKIP, DO, DO, SKIP, DO, DO]);
+
+        run_walkthrough(&root, "envmgr", false, &mut prompter).unwrap();
+
+        assert!(!root.demo_dir().exists());
+        assert!(!root.home.exists());
+        assert_eq!(
+            State::load_from_dir(&RealFs, &root.state_dir, false)
+                .unwrap()
+                .current_env_key,
+            BASE_ENV_NAME
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,164 @@
+//! `when:` conditions restricting config entries to some machines.
+//!
+//! A config dir synced between machines can carry entries for each of them; entries
+//! whose condition doesn't hold on this machine are left out when it is loaded.
+
+/// Operating systems a condition can name
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Os {
+    Linux,
+    Macos,
+    Windows,
+}
+
+impl Os {
+    /// The OS envmgr was built for, `None` for any other
+    pub fn current() -> Option<Self> {
+        match std::env::consts::OS {
+            "linux" => Some(Os::Linux),
+            "macos" => Some(Os::Macos),
+            "windows" => Some(Os::Windows),
+            _ => None,
+        }
+    }
+}
+
+/// Restricts an entry to machines matching all of the given fields
+#[derive(
+    Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema,
+)]
+#[serde(deny_unknown_fields)]
+pub struct Condition {
+    /// Hostname, case-insensitive; `*` matches any run of characters and `?` one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<Os>,
+}
+
+/// The machine conditions are checked against
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Host {
+    pub hostname: String,
+    pub os: Option<Os>,
+}
+
+impl Host {
+    pub fn current() -> Self {
+        Self {
+            hostname: gethostname::gethostname().to_string_lossy().into_owned(),
+            os: Os::current(),
+        }
+    }
+}
+
+impl Condition {
+    pub fn matches(&self, host: &Host) -> bool {
+        let hostname = match &self.hostname {
+            Some(pattern) => glob_matches(
+                &pattern.to_ascii_lowercase(),
+                &host.hostname.to_ascii_lowercase(),
+            ),
+            None => true,
+        };
+        let os = match self.os {
+            Some(os) => host.os == Some(os),
+            None => true,
+        };
+        hostname && os
+    }
+
+    /// Why the condition can't hold on any machine, if it can't
+    pub fn never_matches(&self) -> Option<&'static str> {
+        match &self.hostname {
+            Some(hostname) if hostname.trim().is_empty() => Some("hostname is empty"),
+            _ => None,
+        }
+    }
+}
+
+/// Whether `text` matches `pattern`, where `*` stands for any run of characters and `?`
+/// for exactly one
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text position it currently stands in for
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            // Let the last `*` swallow one more character and retry
+            _ => match backtrack {
+                Some((star, star_t)) => {
+                    backtrack = Some((star, star_t + 1));
+                    p = star + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host(hostname: &str, os: Os) -> Host {
+        Host {
+            hostname: hostname.to_string(),
+            os: Some(os),
+        }
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("my-laptop", "my-laptop"));
+        assert!(!glob_matches("my-laptop", "my-laptop2"));
+        assert!(glob_matches("*-laptop", "work-laptop"));
+        assert!(glob_matches("work-*", "work-"));
+        assert!(glob_matches("w*k*p", "work-laptop"));
+        assert!(glob_matches("desk?", "desk1"));
+        assert!(!glob_matches("desk?", "desk"));
+        assert!(glob_matches("*", ""));
+        assert!(!glob_matches("", "host"));
+    }
+
+    #[test]
+    fn test_condition_needs_all_fields() {
+        let laptop = host("My-Laptop", Os::Linux);
+        let condition = |hostname: Option<&str>, os: Option<Os>| Condition {
+            hostname: hostname.map(String::from),
+            os,
+        };
+
+        assert!(condition(None, None).matches(&laptop));
+        assert!(condition(Some("my-laptop"), None).matches(&laptop));
+        assert!(condition(Some("*laptop"), Some(Os::Linux)).matches(&laptop));
+        assert!(!condition(Some("*laptop"), Some(Os::Macos)).matches(&laptop));
+        assert!(!condition(Some("desktop"), Some(Os::Linux)).matches(&laptop));
+        assert!(!condition(None, Some(Os::Windows)).matches(&laptop));
+    }
+
+    #[test]
+    fn test_never_matches() {
+        let empty = Condition {
+            hostname: Some(" ".into()),
+            os: None,
+        };
+        assert_eq!(empty.never_matches(), Some("hostname is empty"));
+        assert_eq!(Condition::default().never_matches(), None);
+    }
+}
@@ -0,0 +1,270 @@
+//! `.env` files next to an environment's `config.yaml`.
+//!
+//! Understands the common subset of the format: `KEY=VALUE` lines, `#` comments, an
+//! optional `export ` prefix, and single or double quoted values. Double quotes
+//! support `\n`, `\r`, `\t`, `\"`, `\\` and `\$` escapes; both kinds of quotes may
+//! span lines. Values are taken literally, `${VAR}` is not expanded.
+
+use std::path::{Path, PathBuf};
+
+use super::validate::is_valid_env_var_key;
+use crate::error::{EnvMgrError, EnvMgrResult};
+
+/// Names of the dotenv file in an environment directory, in order of preference
+pub const DOTENV_FILE_NAMES: [&str; 2] = ["env.dotenv", "vars.env"];
+
+/// A malformed line in a dotenv file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DotenvError {
+    /// 1-based line number
+    pub line: usize,
+    pub message: String,
+}
+
+/// The dotenv file of the environment in `env_dir`, if it has one
+pub fn dotenv_file(env_dir: &Path) -> Option<PathBuf> {
+    DOTENV_FILE_NAMES
+        .iter()
+        .map(|name| env_dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// Read the variables of the dotenv file in `env_dir`, failing on the first malformed line
+pub fn load_dotenv(env_dir: &Path) -> EnvMgrResult<Vec<(String, String)>> {
+    let Some(file) = dotenv_file(env_dir) else {
+        return Ok(vec![]);
+    };
+    let (vars, errors) = parse_dotenv(&std::fs::read_to_string(&file)?);
+    match errors.into_iter().next() {
+        Some(error) => Err(EnvMgrError::Dotenv {
+            file,
+            line: error.line,
+            message: error.message,
+        }),
+        None => Ok(vars),
+    }
+}
+
+/// Parse dotenv `content` into its variables, in file order, and the malformed lines.
+///
+/// A key set twice keeps the last value, like a shell sourcing the file would.
+pub fn parse_dotenv(content: &str) -> (Vec<(String, String)>, Vec<DotenvError>) {
+    let lines: Vec<&str> = content
+        .split('\n')
+        .map(|line| line.strip_suffix('\r').unwrap_or(line))
+        .collect();
+    let mut vars: Vec<(String, String)> = vec![];
+    let mut errors = vec![];
+    let mut index = 0;
+    while index < lines.len() {
+        let number = index + 1;
+        match parse_entry(&lines, &mut index) {
+            Ok(Some((key, value))) => {
+                vars.retain(|(existing, _)| *existing != key);
+                vars.push((key, value));
+            }
+            Ok(None) => {}
+            Err(message) => errors.push(DotenvError {
+                line: number,
+                message,
+            }),
+        }
+        index += 1;
+    }
+    (vars, errors)
+}
+
+/// Parse the entry starting at `lines[*index]`, leaving `index` on its last line
+fn parse_entry(lines: &[&str], index: &mut usize) -> Result<Option<(String, String)>, String> {
+    let line = lines[*index].trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+    let Some((key, rest)) = line.split_once('=') else {
+        return Err(format!("expected KEY=VALUE, found '{line}'"));
+    };
+    let key = key.trim_end();
+    if !is_valid_env_var_key(key) {
+        return Err(format!("'{key}' is not a valid env var name"));
+    }
+    let rest = rest.trim_start();
+    let value = match rest.chars().next() {
+        Some(quote @ ('"' | '\'')) => {
+            let (value, after) = parse_quoted(lines, index, &rest[1..], quote)?;
+            let after = after.trim_start();
+            if !after.is_empty() && !after.starts_with('#') {
+                return Err(format!("unexpected '{after}' after the closing quote"));
+            }
+            value
+        }
+        // An unquoted value ends at a comment preceded by whitespace
+        _ => match rest.find(" #").or_else(|| rest.find("\t#")) {
+            Some(end) => rest[..end].trim_end().to_string(),
+            None => rest.trim_end().to_string(),
+        },
+    };
+    Ok(Some((key.to_string(), value)))
+}
+
+/// Read a quoted value starting right after the opening `quote`, returning it and
+/// whatever follows the closing quote on its line
+fn parse_quoted<'a>(
+    lines: &[&'a str],
+    index: &mut usize,
+    start: &'a str,
+    quote: char,
+) -> Result<(String, &'a str), String> {
+    let mut value = String::new();
+    let mut text = start;
+    loop {
+        let mut chars = text.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                c if c == quote => return Ok((value, &text[i + 1..])),
+                '\\' if quote == '"' => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 'r')) => value.push('\r'),
+                    Some((_, 't')) => value.push('\t'),
+                    Some((_, c @ ('"' | '\\' | '$'))) => value.push(c),
+                    Some((_, c)) => {
+                        value.push('\\');
+                        value.push(c);
+                    }
+                    // A backslash ending the line stands for itself
+                    None => value.push('\\'),
+                },
+                c => value.push(c),
+            }
+        }
+        *index += 1;
+        match lines.get(*index) {
+            Some(next) => {
+                value.push('\n');
+                text = next;
+            }
+            None => return Err(format!("unterminated {quote} quote")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(content: &str) -> Vec<(String, String)> {
+        let (vars, errors) = parse_dotenv(content);
+        assert_eq!(errors, []);
+        vars
+    }
+
+    fn pairs(vars: &[(String, String)]) -> Vec<(&str, &str)> {
+        vars.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect()
+    }
+
+    #[test]
+    fn test_plain_comments_and_export() {
+        let parsed = vars(indoc::indoc! {"
+            # database
+            DB_HOST=localhost
+            export DB_PORT = 5432
+            EMPTY=
+            URL=http://example.com/#anchor # the site
+
+            DB_HOST=db.internal
+        "});
+        assert_eq!(
+            pairs(&parsed),
+            [
+                ("DB_PORT", "5432"),
+                ("EMPTY", ""),
+                ("URL", "http://example.com/#anchor"),
+                ("DB_HOST", "db.internal"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_quoting() {
+        let parsed = vars(indoc::indoc! {r#"
+            SINGLE='it has $HOME and \n # inside'
+            DOUBLE="say \"hi\"\tthere \$HOME \\ \q" # comment
+            MULTI="-----BEGIN KEY-----
+            abc
+            -----END KEY-----"
+            ESCAPED_NEWLINES="one\ntwo"
+        "#});
+        assert_eq!(
+            pairs(&parsed),
+            [
+                ("SINGLE", r"it has $HOME and \n # inside"),
+                ("DOUBLE", "say \"hi\"\tthere $HOME \\ \\q"),
+                ("MULTI", "-----BEGIN KEY-----\nabc\n-----END KEY-----"),
+                ("ESCAPED_NEWLINES", "one\ntwo"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_crlf_file() {
+        let parsed = vars("A=1\r\nB=\"two\"\r\n# c\r\nC='x\r\ny'\r\n");
+        assert_eq!(pairs(&parsed), [("A", "1"), ("B", "two"), ("C", "x\ny")]);
+    }
+
+    #[test]
+    fn test_malformed_lines_are_reported_with_line_numbers() {
+        let (parsed, errors) = parse_dotenv(indoc::indoc! {r#"
+            GOOD=1
+            just some text
+            MY-VAR=2
+            TRAILING="quoted" junk
+            ALSO_GOOD=3
+            OPEN="never closed
+            LAST=4
+        "#});
+        assert_eq!(pairs(&parsed), [("GOOD", "1"), ("ALSO_GOOD", "3")]);
+        assert_eq!(
+            errors,
+            [
+                DotenvError {
+                    line: 2,
+                    message: "expected KEY=VALUE, found 'just some text'".into()
+                },
+                DotenvError {
+                    line: 3,
+                    message: "'MY-VAR' is not a valid env var name".into()
+                },
+                DotenvError {
+                    line: 4,
+                    message: "unexpected 'junk' after the closing quote".into()
+                },
+                DotenvError {
+                    line: 6,
+                    message: "unterminated \" quote".into()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load_prefers_env_dotenv() {
+        let dir = std::env::temp_dir().join("envmgr_test_load_dotenv");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(load_dotenv(&dir).unwrap().is_empty());
+
+        std::fs::write(dir.join("vars.env"), "FROM=vars\n").unwrap();
+        assert_eq!(pairs(&load_dotenv(&dir).unwrap()), [("FROM", "vars")]);
+        std::fs::write(dir.join("env.dotenv"), "FROM=dotenv\nbroken\n").unwrap();
+        let err = load_dotenv(&dir).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{}:2: expected KEY=VALUE, found 'broken'",
+                dir.join("env.dotenv").display()
+            )
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
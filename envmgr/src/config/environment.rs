@@ -1,22 +1,217 @@
-use std::path::Path;
+use std::{collections::BTreeMap, path::Path};
 
 use config::Config;
 
-use super::envmgr_config_dir;
-use crate::error::EnvMgrResult;
+use super::{
+    condition::{Condition, Host, glob_matches},
+    envmgr_config_dir,
+    locale::{NameCheck, check_timezone, zoneinfo_dir},
+};
+use crate::{
+    environment::{SECRET_PLACEHOLDER, SECRET_REFERENCE_PREFIX},
+    error::{EnvMgrError, EnvMgrResult},
+};
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 pub struct EnvironmentConfig {
     pub name: String,
-    #[serde(default)]
+    /// One line shown by `envmgr list`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Free-form labels `envmgr list` can sort and group by
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// `envmgr list --group-by group` shows environments with the same group together
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// A list of entries, or a `KEY: value` mapping for plain values
+    #[serde(default, deserialize_with = "deserialize_env_vars")]
+    #[schemars(with = "EnvVarsField")]
     pub env_vars: Vec<EnvVarsConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub op_ssh: Option<crate::integrations::one_password_ssh_agent::OnePasswordSSHAgentConfig>,
+    /// 1Password documents written on switching to the environment and removed on
+    /// switching away, e.g. kubeconfigs that must not live in the config dir
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub op_documents: Vec<crate::integrations::one_password_documents::OnePasswordDocument>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub gh_cli: Option<crate::integrations::gh_cli::GhCliConfig>,
+    /// Git identity, included from `~/.gitconfig` while the environment is active
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git: Option<crate::integrations::git::GitConfig>,
+    /// Kubeconfig exported as `KUBECONFIG`, and the context and namespace made current
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kube: Option<crate::integrations::kube::KubeConfig>,
+    /// AWS profile exported as `AWS_PROFILE`, checked to exist on switch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aws: Option<crate::integrations::aws::AwsConfig>,
+    /// gcloud configuration activated on switch, with its project and account
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gcloud: Option<crate::integrations::gcloud::GcloudConfig>,
+    /// npm registries and tokens written to `~/.npmrc`, or an npmrc linked there
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub npm: Option<crate::integrations::npm::NpmConfig>,
+    /// ssh config fragment written to `~/.ssh/envmgr_env.conf`, which `~/.ssh/config` includes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssh: Option<crate::integrations::ssh::SshConfig>,
+    /// Key set as `default-key` in `gpg.conf` on switch, optionally also git's signing key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gpg: Option<crate::integrations::gpg::GpgConfig>,
+    /// Global tool versions for mise, or asdf's `.tool-versions`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mise: Option<crate::integrations::mise::MiseConfig>,
+    /// Maven settings.xml linked to `~/.m2/settings.xml` and Gradle properties
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maven: Option<crate::integrations::maven::MavenConfig>,
+    /// Package indexes written to pip.conf and uv.toml
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub python: Option<crate::integrations::python::PythonConfig>,
+    /// Private Cargo registries and their tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cargo: Option<crate::integrations::cargo::CargoConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub tailscale: Option<crate::integrations::tailscale::TailscaleConfig>,
+    /// Locale exported as `LANG` and `LC_ALL`, e.g. `de_DE.UTF-8`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    /// IANA timezone exported as `TZ`, e.g. `Europe/Budapest`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    /// Overrides `propagate_to_systemd_user` from `global.yaml` for this environment
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub propagate_to_systemd_user: Option<bool>,
+    /// Mistakes here are costly (production access); prompts can highlight it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub danger: bool,
+    /// Variables removed from the shell while this environment is active, dropping any
+    /// value the base environment gives them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unset_vars: Vec<String>,
+    /// Shell aliases defined while this environment is active
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<AliasConfig>,
+    /// Layer the base environment's env vars, unset vars, aliases and files under this
+    /// one. Off for environments that must not pick up anything personal from base.
+    #[serde(default = "default_true", skip_serializing_if = "is_true")]
+    pub inherit_base: bool,
+    /// How the files of this environment are put into the home directory
+    #[serde(default, skip_serializing_if = "LinkKind::is_symlink")]
+    pub link_mode: LinkKind,
+    /// Exceptions to `link_mode`, keyed by path relative to `files/`. `*` and `?` work
+    /// like in hostname conditions; an exact path wins, then the longest pattern.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub link_modes: BTreeMap<String, LinkKind>,
+    /// Directories below `files/` linked as a whole instead of file by file, e.g.
+    /// `.config/nvim`. Files added to them show up without linking again.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub link_dirs: Vec<String>,
+}
+
+/// How a file of an environment ends up at its target
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkKind {
+    /// A symlink to the file in the config dir
+    #[default]
+    Symlink,
+    /// A copy, for tools that replace or sync their config files and break symlinks.
+    /// It is only rewritten or removed while it still has the content envmgr wrote.
+    Copy,
+}
+
+impl LinkKind {
+    fn is_symlink(&self) -> bool {
+        *self == LinkKind::Symlink
+    }
+
+    /// The kind for `relative`, a path below `files/`, per `link_mode` and `link_modes`
+    pub fn for_path(default: Self, overrides: &BTreeMap<String, Self>, relative: &str) -> Self {
+        if let Some(kind) = overrides.get(relative) {
+            return *kind;
+        }
+        overrides
+            .iter()
+            .filter(|(pattern, _)| glob_matches(pattern, relative))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map_or(default, |(_, kind)| *kind)
+    }
+}
+
+/// What an empty `config.yaml` gives, apart from the name
+impl Default for EnvironmentConfig {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            description: None,
+            tags: vec![],
+            group: None,
+            env_vars: vec![],
+            op_ssh: None,
+            op_documents: vec![],
+            gh_cli: None,
+            git: None,
+            kube: None,
+            aws: None,
+            gcloud: None,
+            npm: None,
+            ssh: None,
+            gpg: None,
+            mise: None,
+            maven: None,
+            python: None,
+            cargo: None,
+            tailscale: None,
+            locale: None,
+            timezone: None,
+            propagate_to_systemd_user: None,
+            danger: false,
+            unset_vars: vec![],
+            aliases: vec![],
+            inherit_base: true,
+            link_mode: LinkKind::default(),
+            link_modes: BTreeMap::new(),
+            link_dirs: vec![],
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn is_true(value: &bool) -> bool {
+    *value
 }
 
-const ENVS_DIR_NAME: &str = "environments";
-const ENV_CONFIG_FILE_NAME: &str = "config.yaml";
+/// A command shorthand, e.g. `k` for `kubectl --context client-abc`
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema,
+)]
+pub struct AliasConfig {
+    pub name: String,
+    /// Command line the alias stands for; arguments given to the alias are appended
+    pub command: String,
+    /// In fish, an abbreviation (`abbr`) that expands as you type instead of a function.
+    /// Shells without abbreviations get an alias.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub abbr: bool,
+}
+
+pub(crate) const ENVS_DIR_NAME: &str = "environments";
+pub(crate) const ENV_CONFIG_FILE_NAME: &str = "config.yaml";
+/// Machine-local overrides next to a config file, never meant to be committed
+pub(crate) const LOCAL_CONFIG_FILE_NAME: &str = "local.yaml";
+pub(crate) const FILES_DIR_NAME: &str = "files";
 pub const BASE_ENV_NAME: &str = "base";
 
 impl EnvironmentConfig {
@@ -36,7 +231,46 @@ impl EnvironmentConfig {
         envmgr_config_dir().join(ENVS_DIR_NAME)
     }
 
-    fn load_from_file(config_dir: &Path) -> EnvMgrResult<Self> {
+    /// Load `config.yaml` from `config_dir`, with `local.yaml` applied on top when present
+    pub(crate) fn load_from_file(config_dir: &Path) -> EnvMgrResult<Self> {
+        let mut config = Self::load_shared_from_file(config_dir)?;
+        if let Some(overrides) = LocalOverrides::load(config_dir)? {
+            overrides.apply(&mut config);
+        }
+        // Only the timezone is checked here: it is a cheap lookup, while `locale -a` is
+        // too slow for every prompt and is left to `validate` and `switch`
+        if let Some(timezone) = &config.timezone
+            && let Some(zoneinfo_dir) = zoneinfo_dir()
+            && let NameCheck::Invalid { suggestion } = check_timezone(timezone, &zoneinfo_dir)
+        {
+            return Err(EnvMgrError::InvalidTimezone {
+                timezone: timezone.clone(),
+                suggestion,
+            });
+        }
+        Ok(config)
+    }
+
+    /// Env vars derived from `locale` and `timezone`, to be overridden by explicit `env_vars`
+    pub fn locale_env_vars(&self) -> Vec<EnvVarsConfig> {
+        let var = |key: &str, value: &String| EnvVarsConfig {
+            key: key.to_string(),
+            value: value.clone(),
+            ..Default::default()
+        };
+        let mut vars = vec![];
+        if let Some(locale) = &self.locale {
+            vars.push(var("LANG", locale));
+            vars.push(var("LC_ALL", locale));
+        }
+        if let Some(timezone) = &self.timezone {
+            vars.push(var("TZ", timezone));
+        }
+        vars
+    }
+
+    /// Load only the shared `config.yaml`, e.g. before writing it back
+    pub(crate) fn load_shared_from_file(config_dir: &Path) -> EnvMgrResult<Self> {
         let config: Self = Config::builder()
             .add_source(config::File::from(config_dir.join(ENV_CONFIG_FILE_NAME)))
             .build()?
@@ -51,12 +285,675 @@ impl EnvironmentConfig {
 
     pub fn load_env_config_by_key(key: &str) -> EnvMgrResult<Self> {
         let env_path = Self::get_env_dir_by_key(key);
+        if !env_path.is_dir() {
+            return Err(EnvMgrError::EnvironmentNotFound(key.to_string()));
+        }
         Self::load_from_file(&env_path)
     }
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
+#[derive(Clone, Default, PartialEq, Eq, serde::Serialize, schemars::JsonSchema)]
+#[serde(into = "EnvVarsConfigFile")]
+#[schemars(try_from = "EnvVarsConfigFile")]
 pub struct EnvVarsConfig {
     pub key: String,
+    /// The literal value; empty for a dynamic variable until `use` resolves it
     pub value: String,
+    /// Where the value comes from when it isn't written in the config
+    pub dynamic: Option<DynamicValue>,
+    pub order: i32,
+    /// Emitted by `use` like any other value, but masked wherever envmgr shows it
+    pub secret: bool,
+    /// Machines the variable applies to, all when `None`
+    pub when: Option<Condition>,
+    /// Config key of the integration exporting the variable, `None` for configured ones
+    pub integration: Option<&'static str>,
+}
+
+/// Masks the value of a secret, so it can't end up in debug logs
+impl std::fmt::Debug for EnvVarsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EnvVarsConfig")
+            .field("key", &self.key)
+            .field("value", &self.display_value())
+            .field("dynamic", &self.dynamic)
+            .field("order", &self.order)
+            .field("secret", &self.secret)
+            .field("when", &self.when)
+            .field("integration", &self.integration)
+            .finish()
+    }
+}
+
+/// A value that is only known at `use` time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DynamicValue {
+    /// Run through the shell, the trimmed stdout is the value
+    Command(String),
+    /// Read from this path, without the trailing newline
+    File(String),
+    /// A `value` that is an `op://` secret reference, read with the 1Password CLI
+    Secret(String),
+}
+
+impl std::fmt::Display for DynamicValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DynamicValue::Command(command) => write!(f, "$({command})"),
+            DynamicValue::File(path) => write!(f, "$(< {path})"),
+            DynamicValue::Secret(reference) => f.write_str(reference),
+        }
+    }
+}
+
+impl EnvVarsConfig {
+    /// The value as configured, dynamic sources shown instead of being resolved
+    pub fn describe_value(&self) -> String {
+        match &self.dynamic {
+            Some(dynamic) => dynamic.to_string(),
+            None => self.value.clone(),
+        }
+    }
+
+    /// Whether the variable is set on `host`
+    pub fn applies_to(&self, host: &Host) -> bool {
+        self.when.as_ref().is_none_or(|when| when.matches(host))
+    }
+
+    /// [`Self::describe_value`] for human-readable output, `********` for a secret
+    pub fn display_value(&self) -> String {
+        match self.secret {
+            true => SECRET_PLACEHOLDER.to_string(),
+            false => self.describe_value(),
+        }
+    }
+}
+
+/// How an env var is written in `config.yaml`; exactly one value source must be set
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
+#[schemars(rename = "EnvVarsConfig")]
+struct EnvVarsConfigFile {
+    key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    /// Shell command run at `use` time whose trimmed stdout becomes the value
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value_from_command: Option<String>,
+    /// File read at `use` time whose content, without the trailing newline, becomes the value.
+    /// `~` and `${HOME}` are expanded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value_from_file: Option<String>,
+    /// Position among the variables `use` sets, lower first; ties keep config order
+    #[serde(default, skip_serializing_if = "is_default_order")]
+    order: i32,
+    /// Show `********` instead of the value in `show`, `diff` and logs, and leave the
+    /// variable out of `export-env` unless `--include-secrets` is given
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    secret: bool,
+    /// Only set the variable on matching machines, e.g. `{hostname: "*-laptop", os: linux}`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    when: Option<Condition>,
+}
+
+fn is_default_order(order: &i32) -> bool {
+    *order == 0
+}
+
+impl TryFrom<EnvVarsConfigFile> for EnvVarsConfig {
+    type Error = String;
+
+    fn try_from(file: EnvVarsConfigFile) -> Result<Self, Self::Error> {
+        let (value, dynamic) = match (file.value, file.value_from_command, file.value_from_file) {
+            (Some(value), None, None) if value.starts_with(SECRET_REFERENCE_PREFIX) => {
+                (String::new(), Some(DynamicValue::Secret(value)))
+            }
+            (Some(value), None, None) => (value, None),
+            (None, Some(command), None) => (String::new(), Some(DynamicValue::Command(command))),
+            (None, None, Some(path)) => (String::new(), Some(DynamicValue::File(path))),
+            (None, None, None) => {
+                return Err(format!(
+                    "env var {} needs one of `value`, `value_from_command` or `value_from_file`",
+                    file.key
+                ));
+            }
+            _ => {
+                return Err(format!(
+                    "env var {} sets more than one of `value`, `value_from_command` and `value_from_file`, keep only one",
+                    file.key
+                ));
+            }
+        };
+        Ok(Self {
+            key: file.key,
+            value,
+            dynamic,
+            order: file.order,
+            secret: file.secret,
+            when: file.when,
+            integration: None,
+        })
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for EnvVarsConfig {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EnvVarVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for EnvVarVisitor {
+            type Value = EnvVarsConfig;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("an env var with a `key` and a value")
+            }
+
+            // Checked inside the map rather than with `try_from`, so parse errors keep
+            // pointing at the entry (`env_vars[0]`) instead of the whole list
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                map: A,
+            ) -> Result<Self::Value, A::Error> {
+                let file = <EnvVarsConfigFile as serde::Deserialize>::deserialize(
+                    serde::de::value::MapAccessDeserializer::new(map),
+                )?;
+                EnvVarsConfig::try_from(file).map_err(serde::de::Error::custom)
+            }
+        }
+
+        deserializer.deserialize_map(EnvVarVisitor)
+    }
+}
+
+/// The forms `env_vars` can be written in, for the schema only
+#[allow(dead_code)]
+#[derive(schemars::JsonSchema)]
+#[schemars(untagged)]
+pub(super) enum EnvVarsField {
+    List(Vec<EnvVarsConfig>),
+    Map(std::collections::BTreeMap<String, String>),
+}
+
+/// Read `env_vars` written as a list of entries or as a `KEY: value` mapping.
+///
+/// Both end up as the same entries, so serializing always writes the list form.
+pub(super) fn deserialize_env_vars<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<EnvVarsConfig>, D::Error> {
+    struct EnvVarsVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for EnvVarsVisitor {
+        type Value = Vec<EnvVarsConfig>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("a list of env vars or a mapping of keys to values")
+        }
+
+        fn visit_unit<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+            Ok(vec![])
+        }
+
+        fn visit_none<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+            Ok(vec![])
+        }
+
+        fn visit_seq<A: serde::de::SeqAccess<'de>>(
+            self,
+            mut seq: A,
+        ) -> Result<Self::Value, A::Error> {
+            let mut vars = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(var) = seq.next_element()? {
+                vars.push(var);
+            }
+            Ok(vars)
+        }
+
+        fn visit_map<A: serde::de::MapAccess<'de>>(
+            self,
+            mut map: A,
+        ) -> Result<Self::Value, A::Error> {
+            let mut vars = Vec::with_capacity(map.size_hint().unwrap_or(0));
+            while let Some((key, value)) = map.next_entry::<String, String>()? {
+                let file = EnvVarsConfigFile {
+                    key,
+                    value: Some(value),
+                    ..Default::default()
+                };
+                vars.push(EnvVarsConfig::try_from(file).map_err(serde::de::Error::custom)?);
+            }
+            Ok(vars)
+        }
+    }
+
+    deserializer.deserialize_any(EnvVarsVisitor)
+}
+
+impl From<EnvVarsConfig> for EnvVarsConfigFile {
+    fn from(var: EnvVarsConfig) -> Self {
+        let (value, value_from_command, value_from_file) = match var.dynamic {
+            None => (Some(var.value), None, None),
+            Some(DynamicValue::Command(command)) => (None, Some(command), None),
+            Some(DynamicValue::File(path)) => (None, None, Some(path)),
+            Some(DynamicValue::Secret(reference)) => (Some(reference), None, None),
+        };
+        Self {
+            key: var.key,
+            value,
+            value_from_command,
+            value_from_file,
+            order: var.order,
+            secret: var.secret,
+            when: var.when,
+        }
+    }
+}
+
+/// Contents of an environment's `local.yaml`.
+///
+/// Everything but the environment's identity can be overridden, merged like an
+/// environment over base: env vars by key and aliases by name, unset vars, link dirs
+/// and link modes are added, and every other field replaces the one from `config.yaml`.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LocalOverrides {
+    /// Only here to reject it with a clear message
+    #[serde(default)]
+    name: Option<String>,
+    pub description: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub group: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_env_vars")]
+    pub env_vars: Vec<EnvVarsConfig>,
+    pub op_ssh: Option<crate::integrations::one_password_ssh_agent::OnePasswordSSHAgentConfig>,
+    pub op_documents: Option<Vec<crate::integrations::one_password_documents::OnePasswordDocument>>,
+    pub gh_cli: Option<crate::integrations::gh_cli::GhCliConfig>,
+    pub git: Option<crate::integrations::git::GitConfig>,
+    pub kube: Option<crate::integrations::kube::KubeConfig>,
+    pub aws: Option<crate::integrations::aws::AwsConfig>,
+    pub gcloud: Option<crate::integrations::gcloud::GcloudConfig>,
+    pub npm: Option<crate::integrations::npm::NpmConfig>,
+    pub ssh: Option<crate::integrations::ssh::SshConfig>,
+    pub gpg: Option<crate::integrations::gpg::GpgConfig>,
+    pub mise: Option<crate::integrations::mise::MiseConfig>,
+    pub maven: Option<crate::integrations::maven::MavenConfig>,
+    pub python: Option<crate::integrations::python::PythonConfig>,
+    pub cargo: Option<crate::integrations::cargo::CargoConfig>,
+    pub tailscale: Option<crate::integrations::tailscale::TailscaleConfig>,
+    pub locale: Option<String>,
+    pub timezone: Option<String>,
+    pub propagate_to_systemd_user: Option<bool>,
+    pub danger: Option<bool>,
+    #[serde(default)]
+    pub unset_vars: Vec<String>,
+    #[serde(default)]
+    pub aliases: Vec<AliasConfig>,
+    pub inherit_base: Option<bool>,
+    pub link_mode: Option<LinkKind>,
+    #[serde(default)]
+    pub link_modes: BTreeMap<String, LinkKind>,
+    #[serde(default)]
+    pub link_dirs: Vec<String>,
+}
+
+impl LocalOverrides {
+    pub fn file_path(config_dir: &Path) -> std::path::PathBuf {
+        config_dir.join(LOCAL_CONFIG_FILE_NAME)
+    }
+
+    /// Read `local.yaml` from `config_dir`, `None` when there is none
+    pub fn load(config_dir: &Path) -> EnvMgrResult<Option<Self>> {
+        let path = Self::file_path(config_dir);
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let invalid = |reason: String| EnvMgrError::InvalidLocalOverride(path.clone(), reason);
+        let overrides: Self =
+            serde_norway::from_str(&content).map_err(|e| invalid(e.to_string()))?;
+        if overrides.name.is_some() {
+            return Err(invalid(
+                "`name` identifies the environment and can only be set in config.yaml".into(),
+            ));
+        }
+        Ok(Some(overrides))
+    }
+
+    /// Apply the overrides on top of `config`, local values winning
+    pub fn apply(self, config: &mut EnvironmentConfig) {
+        // Like an environment's unset vars drop the values base gives them
+        for key in self.unset_vars {
+            config.env_vars.retain(|var| var.key != key);
+            if !config.unset_vars.contains(&key) {
+                config.unset_vars.push(key);
+            }
+        }
+        for var in self.env_vars {
+            config.unset_vars.retain(|key| *key != var.key);
+            match config.env_vars.iter_mut().find(|v| v.key == var.key) {
+                Some(existing) => {
+                    existing.value = var.value;
+                    existing.dynamic = var.dynamic;
+                    existing.secret |= var.secret;
+                    existing.when = var.when;
+                    // An override without its own order keeps the one it overrides
+                    if var.order != 0 {
+                        existing.order = var.order;
+                    }
+                }
+                None => config.env_vars.push(var),
+            }
+        }
+        if self.op_ssh.is_some() {
+            config.op_ssh = self.op_ssh;
+        }
+        if let Some(documents) = self.op_documents {
+            config.op_documents = documents;
+        }
+        if self.gh_cli.is_some() {
+            config.gh_cli = self.gh_cli;
+        }
+        if self.git.is_some() {
+            config.git = self.git;
+        }
+        if self.kube.is_some() {
+            config.kube = self.kube;
+        }
+        if self.aws.is_some() {
+            config.aws = self.aws;
+        }
+        if self.gcloud.is_some() {
+            config.gcloud = self.gcloud;
+        }
+        if self.npm.is_some() {
+            config.npm = self.npm;
+        }
+        if self.ssh.is_some() {
+            config.ssh = self.ssh;
+        }
+        if self.gpg.is_some() {
+            config.gpg = self.gpg;
+        }
+        if self.mise.is_some() {
+            config.mise = self.mise;
+        }
+        if self.maven.is_some() {
+            config.maven = self.maven;
+        }
+        if self.python.is_some() {
+            config.python = self.python;
+        }
+        if self.cargo.is_some() {
+            config.cargo = self.cargo;
+        }
+        if self.tailscale.is_some() {
+            config.tailscale = self.tailscale;
+        }
+        for alias in self.aliases {
+            config
+                .aliases
+                .retain(|existing| existing.name != alias.name);
+            config.aliases.push(alias);
+        }
+        for dir in self.link_dirs {
+            if !config.link_dirs.contains(&dir) {
+                config.link_dirs.push(dir);
+            }
+        }
+        config.link_modes.extend(self.link_modes);
+        if self.description.is_some() {
+            config.description = self.description;
+        }
+        if let Some(tags) = self.tags {
+            config.tags = tags;
+        }
+        if self.group.is_some() {
+            config.group = self.group;
+        }
+        if self.locale.is_some() {
+            config.locale = self.locale;
+        }
+        if self.timezone.is_some() {
+            config.timezone = self.timezone;
+        }
+        if self.propagate_to_systemd_user.is_some() {
+            config.propagate_to_systemd_user = self.propagate_to_systemd_user;
+        }
+        if let Some(danger) = self.danger {
+            config.danger = danger;
+        }
+        if let Some(inherit_base) = self.inherit_base {
+            config.inherit_base = inherit_base;
+        }
+        if let Some(link_mode) = self.link_mode {
+            config.link_mode = link_mode;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn env_dir(name: &str, config: &str, local: Option<&str>) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(ENV_CONFIG_FILE_NAME), config).unwrap();
+        if let Some(local) = local {
+            fs::write(dir.join(LOCAL_CONFIG_FILE_NAME), local).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_local_overrides_win() {
+        let dir = env_dir(
+            "envmgr_test_local_overrides_win",
+            "name: Work\nenv_vars:\n  - key: KUBECONFIG\n    value: /shared\n  - key: EDITOR\n    value: vim\ntailscale:\n  tailnet: corp.ts.net\n",
+            Some(
+                "env_vars:\n  - key: KUBECONFIG\n    value: /home/me/kube\n  - key: LOCAL_ONLY\n    value: '1'\n",
+            ),
+        );
+
+        let config = EnvironmentConfig::load_from_file(&dir).unwrap();
+
+        let vars: Vec<_> = config
+            .env_vars
+            .iter()
+            .map(|v| (v.key.as_str(), v.value.as_str()))
+            .collect();
+        assert_eq!(
+            vars,
+            [
+                ("KUBECONFIG", "/home/me/kube"),
+                ("EDITOR", "vim"),
+                ("LOCAL_ONLY", "1")
+            ]
+        );
+        assert_eq!(config.name, "Work");
+        assert_eq!(config.tailscale.unwrap().tailnet, "corp.ts.net");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_local_overrides_merge_unset_vars_and_aliases() {
+        let dir = env_dir(
+            "envmgr_test_local_overrides_merge",
+            indoc::indoc! {"
+                name: Work
+                env_vars:
+                  HTTP_PROXY: http://proxy.corp:3128
+                  EDITOR: vim
+                unset_vars: [AWS_PROFILE, PAGER]
+                aliases:
+                  - name: k
+                    command: kubectl --context corp
+                  - name: tf
+                    command: terraform
+                link_dirs: [.config/nvim]
+            "},
+            Some(indoc::indoc! {"
+                env_vars:
+                  PAGER: less
+                unset_vars: [HTTP_PROXY]
+                aliases:
+                  - name: k
+                    command: kubectl --context home
+                  - name: g
+                    command: git
+                link_mode: copy
+                link_dirs: [.config/fish]
+                danger: true
+                locale: de_DE.UTF-8
+            "}),
+        );
+
+        let config = EnvironmentConfig::load_from_file(&dir).unwrap();
+
+        let keys: Vec<_> = config.env_vars.iter().map(|v| v.key.as_str()).collect();
+        assert_eq!(keys, ["EDITOR", "PAGER"]);
+        assert_eq!(config.unset_vars, ["AWS_PROFILE", "HTTP_PROXY"]);
+        let aliases: Vec<_> = config
+            .aliases
+            .iter()
+            .map(|a| (a.name.as_str(), a.command.as_str()))
+            .collect();
+        assert_eq!(
+            aliases,
+            [
+                ("tf", "terraform"),
+                ("k", "kubectl --context home"),
+                ("g", "git")
+            ]
+        );
+        assert_eq!(config.link_mode, LinkKind::Copy);
+        assert_eq!(config.link_dirs, [".config/nvim", ".config/fish"]);
+        assert!(config.danger);
+        assert!(config.inherit_base);
+        assert_eq!(config.locale.as_deref(), Some("de_DE.UTF-8"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_local_overrides() {
+        let dir = env_dir("envmgr_test_local_overrides_missing", "name: Work\n", None);
+        assert!(LocalOverrides::load(&dir).unwrap().is_none());
+        assert_eq!(
+            EnvironmentConfig::load_from_file(&dir).unwrap().name,
+            "Work"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_local_overrides_reject_identity_fields() {
+        let dir = env_dir(
+            "envmgr_test_local_overrides_identity",
+            "name: Work\n",
+            Some("name: Not Work\n"),
+        );
+        assert!(matches!(
+            EnvironmentConfig::load_from_file(&dir),
+            Err(EnvMgrError::InvalidLocalOverride(..))
+        ));
+
+        fs::write(dir.join(LOCAL_CONFIG_FILE_NAME), "key: other\n").unwrap();
+        assert!(matches!(
+            LocalOverrides::load(&dir),
+            Err(EnvMgrError::InvalidLocalOverride(..))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_env_vars_as_mapping() {
+        let dir = env_dir(
+            "envmgr_test_env_vars_mapping",
+            "name: Work\nenv_vars:\n  KUBECONFIG: /shared\n  AWS_PROFILE: work\n  API_TOKEN: op://Work/API/credential\n",
+            Some("env_vars:\n  AWS_PROFILE: admin\n"),
+        );
+
+        let config = EnvironmentConfig::load_from_file(&dir).unwrap();
+
+        let vars: Vec<_> = config
+            .env_vars
+            .iter()
+            .map(|v| (v.key.as_str(), v.describe_value()))
+            .collect();
+        assert_eq!(
+            vars,
+            [
+                ("KUBECONFIG", "/shared".to_string()),
+                ("AWS_PROFILE", "admin".to_string()),
+                ("API_TOKEN", "op://Work/API/credential".to_string()),
+            ]
+        );
+        assert!(matches!(
+            config.env_vars[2].dynamic,
+            Some(DynamicValue::Secret(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_env_vars_round_trip() {
+        let list = indoc::indoc! {"
+            name: Work
+            env_vars:
+            - key: EDITOR
+              value: hx
+            - key: TOKEN
+              value_from_command: cat token
+              secret: true
+        "};
+        let config: EnvironmentConfig = serde_norway::from_str(list).unwrap();
+        assert_eq!(serde_norway::to_string(&config).unwrap(), list);
+
+        // The mapping form is written back as the equivalent list
+        let map = "name: Work\nenv_vars:\n  EDITOR: hx\n  PAGER: less\n";
+        let config: EnvironmentConfig = serde_norway::from_str(map).unwrap();
+        let written = serde_norway::to_string(&config).unwrap();
+        assert_eq!(
+            written,
+            "name: Work\nenv_vars:\n- key: EDITOR\n  value: hx\n- key: PAGER\n  value: less\n"
+        );
+        let reread: EnvironmentConfig = serde_norway::from_str(&written).unwrap();
+        assert_eq!(reread.env_vars, config.env_vars);
+
+        let empty: EnvironmentConfig =
+            serde_norway::from_str("name: Work\nenv_vars: {}\n").unwrap();
+        assert!(empty.env_vars.is_empty());
+    }
+
+    #[test]
+    fn test_link_kind_for_path() {
+        let overrides = BTreeMap::from([
+            (".config/Code/*".to_string(), LinkKind::Copy),
+            (
+                ".config/Code/User/keybindings.json".to_string(),
+                LinkKind::Symlink,
+            ),
+            (
+                ".config/Code/User/snippets/*".to_string(),
+                LinkKind::Symlink,
+            ),
+        ]);
+        let kind = |relative| LinkKind::for_path(LinkKind::Symlink, &overrides, relative);
+
+        assert_eq!(kind(".gitconfig"), LinkKind::Symlink);
+        assert_eq!(kind(".config/Code/User/settings.json"), LinkKind::Copy);
+        assert_eq!(
+            kind(".config/Code/User/keybindings.json"),
+            LinkKind::Symlink
+        );
+        assert_eq!(
+            kind(".config/Code/User/snippets/rust.json"),
+            LinkKind::Symlink
+        );
+        assert_eq!(
+            LinkKind::for_path(LinkKind::Copy, &BTreeMap::new(), ".gitconfig"),
+            LinkKind::Copy
+        );
+    }
 }
@@ -0,0 +1,190 @@
+//! `files.yaml`: links that don't follow the `files/<path>` -> `~/<path>` convention.
+//!
+//! An optional list next to an environment's `config.yaml`, e.g.
+//! `- { source: kube/config-abc, target: ~/.kube/config }`. A file listed there is
+//! linked at its manifest target instead of its conventional one.
+
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::{EnvMgrError, EnvMgrResult};
+
+pub(crate) const FILES_MANIFEST_FILE_NAME: &str = "files.yaml";
+
+/// One entry of `files.yaml`
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema,
+)]
+#[serde(deny_unknown_fields)]
+pub struct FileMapping {
+    /// Path of the file relative to the environment's `files/` directory
+    pub source: PathBuf,
+    /// Where it is linked; `~` is the home directory, which relative paths are relative to
+    pub target: String,
+    /// Allow a target outside the home directory, e.g. under `/etc`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub allow_outside_home: bool,
+}
+
+/// The whole `files.yaml`
+#[derive(
+    Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema,
+)]
+#[serde(transparent)]
+pub struct FilesManifest(pub Vec<FileMapping>);
+
+impl FilesManifest {
+    pub fn file_path(env_dir: &Path) -> PathBuf {
+        env_dir.join(FILES_MANIFEST_FILE_NAME)
+    }
+
+    /// The manifest of the environment in `env_dir`, empty when it has none
+    pub fn load(env_dir: &Path) -> EnvMgrResult<Self> {
+        let path = Self::file_path(env_dir);
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        // An empty file is no entries rather than a parse error
+        if content.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        serde_norway::from_str(&content)
+            .map_err(|e| EnvMgrError::InvalidFilesManifest(path, e.to_string()))
+    }
+}
+
+impl FileMapping {
+    /// The source below `files_dir`, or why it can't be one
+    pub fn resolve_source(&self, files_dir: &Path) -> Result<PathBuf, String> {
+        let below = self
+            .source
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        if !below || self.source.as_os_str().is_empty() {
+            return Err(format!(
+                "source {} must be a path inside files/",
+                self.source.display()
+            ));
+        }
+        Ok(files_dir.join(&self.source))
+    }
+
+    /// The absolute target with `~` expanded, or why it isn't allowed
+    pub fn resolve_target(&self, home: &Path) -> Result<PathBuf, String> {
+        let expanded = match self.target.strip_prefix('~') {
+            Some("") => home.to_path_buf(),
+            Some(rest) if rest.starts_with(['/', '\\']) => home.join(&rest[1..]),
+            _ => home.join(&self.target),
+        };
+        // Resolve `..` without touching the filesystem, the target may not exist yet
+        let mut target = PathBuf::new();
+        for component in expanded.components() {
+            match component {
+                Component::ParentDir => {
+                    target.pop();
+                }
+                Component::CurDir => {}
+                component => target.push(component),
+            }
+        }
+        if target == home {
+            return Err(format!("target {} is the home directory", self.target));
+        }
+        if !self.allow_outside_home && !target.starts_with(home) {
+            return Err(format!(
+                "target {} is outside the home directory, set `allow_outside_home: true` to link it anyway",
+                target.display()
+            ));
+        }
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(target: &str, allow_outside_home: bool) -> FileMapping {
+        FileMapping {
+            source: "kube/config-abc".into(),
+            target: target.to_string(),
+            allow_outside_home,
+        }
+    }
+
+    #[test]
+    fn test_resolve_target() {
+        let home = Path::new("/home/me");
+        assert_eq!(
+            mapping("~/.kube/config", false).resolve_target(home),
+            Ok(PathBuf::from("/home/me/.kube/config"))
+        );
+        assert_eq!(
+            mapping(".kube/./config", false).resolve_target(home),
+            Ok(PathBuf::from("/home/me/.kube/config"))
+        );
+        assert_eq!(
+            mapping("/home/me/.kube/config", false).resolve_target(home),
+            Ok(PathBuf::from("/home/me/.kube/config"))
+        );
+        assert!(
+            mapping("~/../other/.kube/config", false)
+                .resolve_target(home)
+                .is_err()
+        );
+        assert!(
+            mapping("/etc/app.conf", false)
+                .resolve_target(home)
+                .is_err()
+        );
+        assert!(mapping("~", false).resolve_target(home).is_err());
+        assert_eq!(
+            mapping("/etc/app.conf", true).resolve_target(home),
+            Ok(PathBuf::from("/etc/app.conf"))
+        );
+    }
+
+    #[test]
+    fn test_resolve_source_stays_in_files_dir() {
+        let files_dir = Path::new("/config/work/files");
+        let entry = |source: &str| FileMapping {
+            source: source.into(),
+            ..mapping("~/x", false)
+        };
+        assert_eq!(
+            entry("kube/config-abc").resolve_source(files_dir),
+            Ok(files_dir.join("kube/config-abc"))
+        );
+        assert!(entry("../config.yaml").resolve_source(files_dir).is_err());
+        assert!(entry("/etc/passwd").resolve_source(files_dir).is_err());
+        assert!(entry("").resolve_source(files_dir).is_err());
+    }
+
+    #[test]
+    fn test_load_manifest() {
+        let dir = std::env::temp_dir().join("envmgr_test_files_manifest");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(FilesManifest::load(&dir).unwrap(), FilesManifest::default());
+
+        std::fs::write(
+            FilesManifest::file_path(&dir),
+            "- { source: kube/config-abc, target: ~/.kube/config }\n\
+             - source: app.conf\n  target: /etc/app.conf\n  allow_outside_home: true\n",
+        )
+        .unwrap();
+        let manifest = FilesManifest::load(&dir).unwrap();
+        assert_eq!(manifest.0.len(), 2);
+        assert_eq!(manifest.0[0], mapping("~/.kube/config", false));
+        assert!(manifest.0[1].allow_outside_home);
+
+        std::fs::write(FilesManifest::file_path(&dir), "- { source: a, dest: b }\n").unwrap();
+        assert!(matches!(
+            FilesManifest::load(&dir),
+            Err(EnvMgrError::InvalidFilesManifest(..))
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
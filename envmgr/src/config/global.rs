@@ -1,10 +1,265 @@
-use super::envmgr_config_dir;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use config::Config;
+
+use super::{
+    EnvVarsConfig, LOCAL_CONFIG_FILE_NAME,
+    condition::Host,
+    environment::{EnvVarsField, deserialize_env_vars},
+    envmgr_config_dir,
+};
+use crate::{
+    error::EnvMgrResult,
+    fs::{Fs, RealFs},
+    integrations::IntegrationKind,
+};
+
+pub(crate) const GLOBAL_CONFIG_FILE_NAME: &str = "global.yaml";
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
-pub struct GlobalConfig {}
+pub struct GlobalConfig {
+    /// Keep writing the legacy state file next to the current one so older
+    /// envmgr binaries can still read it after a downgrade.
+    #[serde(default = "default_true")]
+    pub legacy_state_dual_write: bool,
+    /// Seconds a `value_from_command` may run during `use` before it is killed
+    #[serde(default = "default_value_command_timeout_secs")]
+    pub value_command_timeout_secs: u64,
+    /// Push variables into the systemd user manager on `switch`, so apps started by the
+    /// desktop session see them. Environments can override this.
+    #[serde(default)]
+    pub propagate_to_systemd_user: bool,
+    /// The only variables pushed into the systemd user manager
+    #[serde(default)]
+    pub systemd_user_allowlist: Vec<String>,
+    /// Push the variables `use` sets and unsets into a running tmux server's global
+    /// environment, so panes opened afterwards get them
+    #[serde(default)]
+    pub propagate_to_tmux: bool,
+    /// Also redraw the status line of every tmux client after updating tmux
+    #[serde(default)]
+    pub tmux_refresh_client: bool,
+    /// Consecutive failures after which `switch` skips an integration for an environment,
+    /// 0 never quarantines
+    #[serde(default = "default_quarantine_after_failures")]
+    pub quarantine_after_failures: u32,
+    /// Seconds an integration may take during `switch` before it is reported as hung,
+    /// not counting logins that wait for the user
+    #[serde(default = "default_integration_timeout_secs")]
+    pub integration_timeout_secs: u64,
+    /// `integration_timeout_secs` of single integrations by config key, e.g.
+    /// `{tailscale: 30}`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub integration_timeouts: BTreeMap<String, u64>,
+    /// When `switch` runs integrations by config key, lower first, e.g. `{mise: 1}`.
+    /// Those of the same order run at the same time. git is 1 by default since it can
+    /// take gpg's key, every other one 0.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub integration_order: BTreeMap<String, i32>,
+    /// Move real files blocking a link into the state dir's `backups/` instead of skipping
+    /// them, like `--adopt-backups` does
+    #[serde(default)]
+    pub adopt_backups: bool,
+    /// Default layout of `envmgr list`
+    #[serde(default)]
+    pub list: crate::commands::list::ListConfig,
+    /// Env vars set in every environment, e.g. `EDITOR`. Base and the environment
+    /// override them, and their `unset_vars` drop them.
+    #[serde(default, deserialize_with = "deserialize_env_vars")]
+    #[schemars(with = "EnvVarsField")]
+    pub global_env_vars: Vec<EnvVarsConfig>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_value_command_timeout_secs() -> u64 {
+    10
+}
+
+fn default_quarantine_after_failures() -> u32 {
+    3
+}
+
+fn default_integration_timeout_secs() -> u64 {
+    10
+}
+
+impl Default for GlobalConfig {
+    fn default() -> Self {
+        Self {
+            legacy_state_dual_write: true,
+            value_command_timeout_secs: default_value_command_timeout_secs(),
+            propagate_to_systemd_user: false,
+            systemd_user_allowlist: Vec::new(),
+            propagate_to_tmux: false,
+            tmux_refresh_client: false,
+            quarantine_after_failures: default_quarantine_after_failures(),
+            integration_timeout_secs: default_integration_timeout_secs(),
+            integration_timeouts: BTreeMap::new(),
+            integration_order: BTreeMap::new(),
+            adopt_backups: false,
+            list: Default::default(),
+            global_env_vars: Vec::new(),
+        }
+    }
+}
 
 impl GlobalConfig {
     pub fn get_config_file_path() -> std::path::PathBuf {
-        envmgr_config_dir().join("global.yaml")
+        envmgr_config_dir().join(GLOBAL_CONFIG_FILE_NAME)
+    }
+
+    /// Load the global config, falling back to defaults when the file is missing.
+    ///
+    /// A `local.yaml` in the config dir overrides individual settings for this machine.
+    pub fn load() -> EnvMgrResult<Self> {
+        Self::load_from_dir(&envmgr_config_dir())
+    }
+
+    fn load_from_dir(config_dir: &Path) -> EnvMgrResult<Self> {
+        Self::load_layers(&[
+            config_dir.join(GLOBAL_CONFIG_FILE_NAME),
+            config_dir.join(LOCAL_CONFIG_FILE_NAME),
+        ])
+    }
+
+    /// Load only the shared `global.yaml`, e.g. before writing it back with [`Self::save`]
+    pub fn load_shared() -> EnvMgrResult<Self> {
+        Self::load_layers(&[Self::get_config_file_path()])
+    }
+
+    /// Later files override earlier ones; defaults when none of them exists
+    fn load_layers(paths: &[PathBuf]) -> EnvMgrResult<Self> {
+        if !paths.iter().any(|path| path.exists()) {
+            return Ok(Self::default());
+        }
+        let config: Self = paths
+            .iter()
+            .fold(Config::builder(), |builder, path| {
+                builder.add_source(config::File::from(path.as_path()).required(false))
+            })
+            .build()?
+            .try_deserialize()?;
+        Ok(config)
+    }
+
+    /// Write `global.yaml`. Settings loaded from `local.yaml` would end up in it, so
+    /// save a config from [`Self::load_shared`] rather than [`Self::load`].
+    pub fn save(&self) -> EnvMgrResult<()> {
+        self.save_to_dir(&RealFs, &envmgr_config_dir())
+    }
+
+    fn save_to_dir(&self, fs: &dyn Fs, config_dir: &Path) -> EnvMgrResult<()> {
+        let content = serde_norway::to_string(self)?;
+        fs.write_atomic(
+            &config_dir.join(GLOBAL_CONFIG_FILE_NAME),
+            content.as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    /// The global env vars that apply on this machine
+    pub fn env_vars_for_this_host(&self) -> Vec<EnvVarsConfig> {
+        let host = Host::current();
+        self.global_env_vars
+            .iter()
+            .filter(|var| var.applies_to(&host))
+            .cloned()
+            .collect()
+    }
+
+    /// How long `kind` may take during `switch`, see `integration_timeout_secs`
+    pub fn integration_timeout(&self, kind: IntegrationKind) -> Duration {
+        let secs = self.integration_timeouts.get(kind.config_key());
+        Duration::from_secs(*secs.unwrap_or(&self.integration_timeout_secs))
+    }
+
+    /// When `switch` runs `kind`, see `integration_order`
+    pub fn integration_order(&self, kind: IntegrationKind) -> i32 {
+        match self.integration_order.get(kind.config_key()) {
+            Some(order) => *order,
+            None => kind.default_order(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_global_local_overrides() {
+        let dir = std::env::temp_dir().join("envmgr_test_global_local");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        assert!(
+            GlobalConfig::load_from_dir(&dir)
+                .unwrap()
+                .legacy_state_dual_write
+        );
+
+        fs::write(
+            dir.join(GLOBAL_CONFIG_FILE_NAME),
+            "legacy_state_dual_write: true\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join(LOCAL_CONFIG_FILE_NAME),
+            "legacy_state_dual_write: false\n",
+        )
+        .unwrap();
+        assert!(
+            !GlobalConfig::load_from_dir(&dir)
+                .unwrap()
+                .legacy_state_dual_write
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_global_env_vars_load_and_save() {
+        let dir = std::env::temp_dir().join("envmgr_test_global_env_vars");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        assert!(
+            GlobalConfig::load_from_dir(&dir)
+                .unwrap()
+                .global_env_vars
+                .is_empty()
+        );
+
+        fs::write(
+            dir.join(GLOBAL_CONFIG_FILE_NAME),
+            "global_env_vars:\n  EDITOR: hx\n  PAGER: less\n",
+        )
+        .unwrap();
+        let mut config = GlobalConfig::load_from_dir(&dir).unwrap();
+        let keys: Vec<&str> = config
+            .global_env_vars
+            .iter()
+            .map(|var| var.key.as_str())
+            .collect();
+        assert_eq!(keys, ["EDITOR", "PAGER"]);
+
+        config.global_env_vars.pop();
+        config.save_to_dir(&RealFs, &dir).unwrap();
+        let reloaded = GlobalConfig::load_from_dir(&dir).unwrap();
+        assert_eq!(reloaded.global_env_vars, config.global_env_vars);
+        assert_eq!(
+            reloaded.value_command_timeout_secs,
+            config.value_command_timeout_secs
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,242 @@
+//! Validation of the `timezone` and `locale` fields against what the system provides.
+
+use std::{
+    cell::OnceCell,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{fuzzy::closest_match, runner::CommandRunner};
+
+const DEFAULT_ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+/// Standard override of the tzdata location, honored by libc as well
+const TZDIR_ENV_VAR: &str = "TZDIR";
+
+/// Outcome of checking a timezone or locale name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NameCheck {
+    Valid,
+    Invalid {
+        suggestion: Option<String>,
+    },
+    /// The system can't tell, e.g. no tzdata directory or no `locale` command
+    Unverifiable(String),
+}
+
+/// The tzdata directory, `None` when the system has none (e.g. Windows)
+pub fn zoneinfo_dir() -> Option<PathBuf> {
+    let dir = std::env::var_os(TZDIR_ENV_VAR)
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_ZONEINFO_DIR));
+    dir.is_dir().then_some(dir)
+}
+
+/// Check `timezone` (e.g. `Europe/Budapest`) against the zone files in `zoneinfo_dir`
+pub fn check_timezone(timezone: &str, zoneinfo_dir: &Path) -> NameCheck {
+    if !zoneinfo_dir.is_dir() {
+        return NameCheck::Unverifiable(format!("no tzdata at {}", zoneinfo_dir.display()));
+    }
+    let relative = Path::new(timezone);
+    let is_plain = !timezone.is_empty()
+        && relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+    if is_plain && zoneinfo_dir.join(relative).is_file() {
+        return NameCheck::Valid;
+    }
+    let zones = zone_names(zoneinfo_dir);
+    NameCheck::Invalid {
+        suggestion: closest_match(timezone, zones.iter().map(String::as_str)).map(String::from),
+    }
+}
+
+/// Zone names below `dir`, skipping the metadata files and `posix/`/`right/` duplicates
+fn zone_names(dir: &Path) -> Vec<String> {
+    fn walk(root: &Path, dir: &Path, names: &mut Vec<String>) {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                let name = entry.file_name();
+                if dir != root || (name != "posix" && name != "right") {
+                    walk(root, &path, names);
+                }
+            } else if let Ok(relative) = path.strip_prefix(root)
+                && let Some(name) = relative.to_str()
+                // Zone names start with an uppercase letter, tzdata's own files don't
+                && name.starts_with(|c: char| c.is_ascii_uppercase())
+                && !name.contains('.')
+            {
+                names.push(name.replace('\\', "/"));
+            }
+        }
+    }
+
+    let mut names = vec![];
+    walk(dir, dir, &mut names);
+    names.sort();
+    names
+}
+
+/// Locales reported by `locale -a`, queried at most once
+pub struct LocaleCatalog<'r> {
+    runner: &'r dyn CommandRunner,
+    locales: OnceCell<Result<Vec<String>, String>>,
+}
+
+impl<'r> LocaleCatalog<'r> {
+    pub fn new(runner: &'r dyn CommandRunner) -> Self {
+        Self {
+            runner,
+            locales: OnceCell::new(),
+        }
+    }
+
+    fn locales(&self) -> &Result<Vec<String>, String> {
+        self.locales.get_or_init(|| {
+            let output = self
+                .runner
+                .run("locale", &["-a"])
+                .map_err(|e| format!("`locale -a` is unavailable: {e}"))?;
+            if !output.success {
+                return Err(format!("`locale -a` failed: {}", output.stderr.trim()));
+            }
+            Ok(output
+                .stdout
+                .lines()
+                .map(|l| l.trim().to_string())
+                .collect())
+        })
+    }
+
+    /// Check `locale` (e.g. `de_DE.UTF-8`), treating codeset spellings like `utf8` and `UTF-8` alike
+    pub fn check(&self, locale: &str) -> NameCheck {
+        if matches!(locale, "C" | "POSIX" | "C.UTF-8" | "C.utf8") {
+            return NameCheck::Valid;
+        }
+        let locales = match self.locales() {
+            Ok(locales) => locales,
+            Err(reason) => return NameCheck::Unverifiable(reason.clone()),
+        };
+        let wanted = normalize_locale(locale);
+        if locales.iter().any(|l| normalize_locale(l) == wanted) {
+            return NameCheck::Valid;
+        }
+        NameCheck::Invalid {
+            suggestion: closest_match(locale, locales.iter().map(String::as_str)).map(String::from),
+        }
+    }
+}
+
+fn normalize_locale(locale: &str) -> String {
+    locale.to_lowercase().replace('-', "")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io};
+
+    use super::*;
+    use crate::runner::CommandOutput;
+
+    /// Answers every command with a canned `locale -a` output, or fails like a missing binary
+    struct CannedLocale(Option<&'static str>);
+
+    impl CommandRunner for CannedLocale {
+        fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+            assert_eq!((program, args), ("locale", &["-a"][..]));
+            match self.0 {
+                Some(stdout) => Ok(CommandOutput {
+                    success: true,
+                    stdout: stdout.to_string(),
+                    stderr: String::new(),
+                }),
+                None => Err(io::ErrorKind::NotFound.into()),
+            }
+        }
+    }
+
+    fn fake_zoneinfo(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        for zone in [
+            "Europe/Budapest",
+            "Europe/Berlin",
+            "UTC",
+            "posix/Europe/Budapest",
+        ] {
+            let path = dir.join(zone);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "TZif").unwrap();
+        }
+        fs::write(dir.join("zone.tab"), "").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_check_timezone() {
+        let dir = fake_zoneinfo("envmgr_test_zoneinfo");
+
+        assert_eq!(check_timezone("Europe/Budapest", &dir), NameCheck::Valid);
+        assert_eq!(check_timezone("UTC", &dir), NameCheck::Valid);
+        assert_eq!(
+            check_timezone("Europe/Buda", &dir),
+            NameCheck::Invalid {
+                suggestion: Some("Europe/Budapest".to_string())
+            }
+        );
+        assert_eq!(
+            check_timezone("../zoneinfo/UTC", &dir),
+            NameCheck::Invalid { suggestion: None }
+        );
+        assert_eq!(
+            check_timezone("Europe", &dir),
+            NameCheck::Invalid {
+                suggestion: Some("Europe/Berlin".to_string())
+            }
+        );
+        assert_eq!(
+            zone_names(&dir),
+            ["Europe/Berlin", "Europe/Budapest", "UTC"]
+        );
+        assert!(matches!(
+            check_timezone("UTC", &dir.join("missing")),
+            NameCheck::Unverifiable(_)
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_locale() {
+        let runner = CannedLocale(Some("C\nC.utf8\nPOSIX\nen_US.utf8\nde_DE.utf8\n"));
+        let catalog = LocaleCatalog::new(&runner);
+
+        assert_eq!(catalog.check("en_US.UTF-8"), NameCheck::Valid);
+        assert_eq!(catalog.check("de_DE.utf8"), NameCheck::Valid);
+        assert_eq!(
+            catalog.check("de_DR.utf8"),
+            NameCheck::Invalid {
+                suggestion: Some("de_DE.utf8".to_string())
+            }
+        );
+        assert_eq!(
+            catalog.check("klingon"),
+            NameCheck::Invalid { suggestion: None }
+        );
+    }
+
+    #[test]
+    fn test_check_locale_without_locale_command() {
+        let runner = CannedLocale(None);
+        let catalog = LocaleCatalog::new(&runner);
+
+        assert!(matches!(
+            catalog.check("en_US.UTF-8"),
+            NameCheck::Unverifiable(reason) if reason.contains("unavailable")
+        ));
+        assert_eq!(catalog.check("C"), NameCheck::Valid);
+    }
+}
@@ -1,10 +1,48 @@
+pub mod condition;
+pub mod dotenv;
 mod environment;
+pub mod files_manifest;
 mod global;
+pub mod locale;
+pub mod validate;
 
-pub use environment::{BASE_ENV_NAME, EnvVarsConfig, EnvironmentConfig};
+pub use environment::{
+    AliasConfig, BASE_ENV_NAME, DynamicValue, EnvVarsConfig, EnvironmentConfig, LinkKind,
+    LocalOverrides,
+};
+pub(crate) use environment::{
+    ENV_CONFIG_FILE_NAME, ENVS_DIR_NAME, FILES_DIR_NAME, LOCAL_CONFIG_FILE_NAME,
+};
+pub(crate) use global::GLOBAL_CONFIG_FILE_NAME;
 pub use global::GlobalConfig;
 
-pub fn envmgr_config_dir() -> std::path::PathBuf {
+use std::{path::PathBuf, sync::OnceLock};
+
+/// Environment variable overriding the config directory
+pub const CONFIG_DIR_ENV_VAR: &str = "ENVMGR_CONFIG_DIR";
+
+static CONFIG_DIR_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Use `dir` as the config directory for the rest of the process, e.g. from `--config-dir`.
+///
+/// Takes precedence over `ENVMGR_CONFIG_DIR`. Only the first call has an effect.
+pub fn set_config_dir_override(dir: PathBuf) {
+    let dir = std::path::absolute(&dir).unwrap_or(dir);
+    if CONFIG_DIR_OVERRIDE.set(dir).is_err() {
+        log::warn!("Config directory override was already set, ignoring");
+    }
+}
+
+/// envmgr's config directory: the `--config-dir` override, `ENVMGR_CONFIG_DIR`,
+/// or `envmgr` in the platform's local config directory
+pub fn envmgr_config_dir() -> PathBuf {
+    if let Some(dir) = CONFIG_DIR_OVERRIDE.get() {
+        return dir.clone();
+    }
+    if let Some(dir) = std::env::var_os(CONFIG_DIR_ENV_VAR).filter(|d| !d.is_empty()) {
+        let dir = PathBuf::from(dir);
+        return std::path::absolute(&dir).unwrap_or(dir);
+    }
     let config_local_dir = dirs::config_local_dir().expect("Could not determine home directory");
     config_local_dir.join("envmgr")
 }
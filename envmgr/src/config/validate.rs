@@ -0,0 +1,1231 @@
+use std::{
+    ffi::OsString,
+    fmt::Write as _,
+    path::{Component, Path, PathBuf},
+};
+
+use super::{
+    BASE_ENV_NAME, EnvironmentConfig, LocalOverrides,
+    dotenv::{DOTENV_FILE_NAMES, dotenv_file, parse_dotenv},
+    environment::{ENV_CONFIG_FILE_NAME, FILES_DIR_NAME},
+    files_manifest::FilesManifest,
+    locale::{LocaleCatalog, NameCheck, check_timezone, zoneinfo_dir},
+};
+use crate::{
+    environment::discover_files_in_dir,
+    error::{EnvMgrError, EnvMgrResult},
+    integrations::{
+        find_in_path,
+        kube::{KubeConfig, Kubeconfig},
+    },
+    runner::SystemRunner,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct ValidationIssue {
+    pub file: PathBuf,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// Collected results of validating one or more environment directories
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct ValidationReport {
+    pub checked: Vec<PathBuf>,
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+    pub fn error(&mut self, file: &Path, message: impl Into<String>) {
+        self.push(file, Severity::Error, message);
+    }
+
+    pub fn warning(&mut self, file: &Path, message: impl Into<String>) {
+        self.push(file, Severity::Warning, message);
+    }
+
+    fn push(&mut self, file: &Path, severity: Severity, message: impl Into<String>) {
+        self.issues.push(ValidationIssue {
+            file: file.to_path_buf(),
+            severity,
+            message: message.into(),
+        });
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.issues
+            .iter()
+            .filter(|i| i.severity == Severity::Error)
+            .count()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.error_count() > 0
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for issue in &self.issues {
+            let label = match issue.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+            };
+            let _ = writeln!(out, "{label}: {}: {}", issue.file.display(), issue.message);
+        }
+        let warnings = self.issues.len() - self.error_count();
+        let _ = writeln!(
+            out,
+            "Checked {} environment(s): {} error(s), {} warning(s)",
+            self.checked.len(),
+            self.error_count(),
+            warnings
+        );
+        out
+    }
+}
+
+/// Validate the base environment and every directory under `environments/`
+pub fn validate_all() -> EnvMgrResult<ValidationReport> {
+    let mut report = ValidationReport::default();
+    let system = SystemNames {
+        zoneinfo_dir: zoneinfo_dir(),
+        locales: LocaleCatalog::new(&SystemRunner),
+        path_var: std::env::var_os("PATH"),
+    };
+    let base_dir = EnvironmentConfig::get_base_env_dir();
+    validate_env_dir(&base_dir, BASE_ENV_NAME, &system, &mut report);
+    let base_link_dirs = read_config(&base_dir)
+        .map(|config| config.link_dirs)
+        .unwrap_or_default();
+
+    let envs_dir = EnvironmentConfig::get_all_envs_dir();
+    if envs_dir.is_dir() {
+        let mut entries = std::fs::read_dir(&envs_dir)?
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().is_dir() && !e.file_name().to_string_lossy().starts_with('.'))
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let key = entry.file_name().to_string_lossy().into_owned();
+            if key == BASE_ENV_NAME {
+                report.error(
+                    &entry.path(),
+                    format!(
+                        "environment key '{BASE_ENV_NAME}' is reserved for the base environment"
+                    ),
+                );
+            }
+            validate_env_dir(&entry.path(), &key, &system, &mut report);
+            if let Some(home) = dirs::home_dir() {
+                validate_files_inside_base_link_dirs(
+                    &entry.path(),
+                    &base_link_dirs,
+                    &home,
+                    &mut report,
+                );
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// What the system offers to check `timezone` and `locale` against
+pub struct SystemNames<'r> {
+    /// `None` when the system has no tzdata, which makes timezones unverifiable
+    pub zoneinfo_dir: Option<PathBuf>,
+    pub locales: LocaleCatalog<'r>,
+    /// `PATH` to look for the tools integrations need in; `None` doesn't look
+    pub path_var: Option<OsString>,
+}
+
+/// Validate a single environment directory, recording issues in `report`
+pub fn validate_env_dir(
+    env_dir: &Path,
+    key: &str,
+    system: &SystemNames,
+    report: &mut ValidationReport,
+) {
+    let config_path = env_dir.join(ENV_CONFIG_FILE_NAME);
+    report.checked.push(config_path.clone());
+
+    let content = match std::fs::read_to_string(&config_path) {
+        Ok(content) => content,
+        Err(e) => {
+            report.error(&config_path, format!("could not read config: {e}"));
+            return;
+        }
+    };
+    // serde_norway reports the YAML path and line/column of the offending node
+    let config: EnvironmentConfig = match serde_norway::from_str(&content) {
+        Ok(config) => config,
+        Err(e) => {
+            report.error(&config_path, format!("parse error: {e}"));
+            return;
+        }
+    };
+
+    validate_env_config(&config, &config_path, system, report);
+    let config_link_dirs = config.link_dirs.clone();
+    // The kubeconfig is machine-local, so only the effective one is checked
+    let mut kube = config.kube.clone().map(|kube| (kube, config_path.clone()));
+
+    match LocalOverrides::load(env_dir) {
+        Ok(Some(overrides)) => {
+            if let Some(local_kube) = &overrides.kube {
+                kube = Some((local_kube.clone(), LocalOverrides::file_path(env_dir)));
+            }
+            let mut config = config;
+            overrides.apply(&mut config);
+            validate_env_config(&config, &LocalOverrides::file_path(env_dir), system, report);
+        }
+        Ok(None) => {}
+        Err(e) => report.error(&LocalOverrides::file_path(env_dir), e.to_string()),
+    }
+
+    validate_dotenv(env_dir, report);
+
+    let files_dir = env_dir.join(FILES_DIR_NAME);
+    if files_dir.exists() && !files_dir.is_dir() {
+        report.error(
+            &files_dir,
+            format!("'{FILES_DIR_NAME}' in environment '{key}' must be a directory"),
+        );
+    }
+    let files = discover_files_in_dir(&files_dir).unwrap_or_default();
+    for file in files
+        .iter()
+        .filter(|file| file.is_symlink() && !file.exists())
+    {
+        let link = std::fs::read_link(file).unwrap_or_default();
+        report.warning(
+            file,
+            format!(
+                "dangling symlink to {}, it is re-created as it is when linking",
+                link.display()
+            ),
+        );
+    }
+
+    for dir in &config_link_dirs {
+        if is_valid_link_dir(dir) && !files_dir.join(dir.trim_matches('/')).is_dir() {
+            report.warning(
+                &config_path,
+                format!("link_dirs entry {dir} is not a directory in {FILES_DIR_NAME}/"),
+            );
+        }
+    }
+
+    if let Some(home) = dirs::home_dir() {
+        validate_files_manifest(env_dir, &home, report);
+        if let Some((kube, file)) = &kube {
+            validate_kube(kube, file, &home, report);
+        }
+    }
+}
+
+/// Check the kubeconfig of `kube` exists and defines its context, by reading the YAML
+fn validate_kube(kube: &KubeConfig, file: &Path, home: &Path, report: &mut ValidationReport) {
+    if kube.kubeconfig.is_none() && kube.context.is_none() && kube.namespace.is_none() {
+        report.error(
+            file,
+            "kube needs at least one of kubeconfig, context or namespace",
+        );
+        return;
+    }
+    let path = kube.kubeconfig_path(home);
+    let kubeconfig = match Kubeconfig::load(&path) {
+        Ok(kubeconfig) => kubeconfig,
+        Err(EnvMgrError::Kube(message)) => {
+            report.error(file, format!("kube: {message}"));
+            return;
+        }
+        Err(e) => {
+            report.error(
+                file,
+                format!("kube: could not read {}: {e}", path.display()),
+            );
+            return;
+        }
+    };
+    if let Some(context) = &kube.context
+        && !kubeconfig.context_names().contains(&context.as_str())
+    {
+        report.error(
+            file,
+            format!(
+                "kube.context '{context}' is not defined in {}",
+                path.display()
+            ),
+        );
+    }
+}
+
+/// The config in `env_dir`, `None` when it can't be read
+fn read_config(env_dir: &Path) -> Option<EnvironmentConfig> {
+    let content = std::fs::read_to_string(env_dir.join(ENV_CONFIG_FILE_NAME)).ok()?;
+    serde_norway::from_str(&content).ok()
+}
+
+/// Report files of the environment in `env_dir` that would go inside a directory base
+/// links as a whole; linking them would write into base's `files/`
+fn validate_files_inside_base_link_dirs(
+    env_dir: &Path,
+    base_link_dirs: &[String],
+    home: &Path,
+    report: &mut ValidationReport,
+) {
+    if base_link_dirs.is_empty() || read_config(env_dir).is_none_or(|c| !c.inherit_base) {
+        return;
+    }
+    let files_dir = env_dir.join(FILES_DIR_NAME);
+    let files = discover_files_in_dir(&files_dir).unwrap_or_default();
+    let manifest = FilesManifest::load(env_dir).unwrap_or_default();
+    let manifest_targets: Vec<PathBuf> = manifest
+        .0
+        .iter()
+        .filter_map(|mapping| mapping.resolve_target(home).ok())
+        .collect();
+    for dir in base_link_dirs.iter().filter(|dir| is_valid_link_dir(dir)) {
+        let dir = dir.trim_matches('/');
+        let inside = files
+            .iter()
+            .filter_map(|file| file.strip_prefix(&files_dir).ok())
+            .filter(|relative| relative.starts_with(dir))
+            .map(|relative| home.join(relative))
+            .chain(
+                manifest_targets
+                    .iter()
+                    .filter(|target| target.starts_with(home.join(dir)))
+                    .cloned(),
+            );
+        for target in inside {
+            report.error(
+                &files_dir,
+                format!(
+                    "{} is inside {dir}, which base links as a whole; move the file into base or drop {dir} from base's link_dirs",
+                    target.display()
+                ),
+            );
+        }
+    }
+}
+
+/// Whether `dir` can be a `link_dirs` entry: a relative path that stays inside `files/`
+fn is_valid_link_dir(dir: &str) -> bool {
+    let path = Path::new(dir.trim_end_matches('/'));
+    !dir.trim_matches('/').is_empty()
+        && path
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)))
+}
+
+/// Whether `path` is relative and stays inside the directory it is relative to
+fn is_inside_env_dir(path: &str) -> bool {
+    let path = Path::new(path);
+    path.is_relative() && !path.components().any(|c| c == Component::ParentDir)
+}
+
+/// Check the entries of `files.yaml` and that no two files end up at one target
+fn validate_files_manifest(env_dir: &Path, home: &Path, report: &mut ValidationReport) {
+    let file = FilesManifest::file_path(env_dir);
+    let manifest = match FilesManifest::load(env_dir) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            report.error(&file, e.to_string());
+            return;
+        }
+    };
+    let files_dir = env_dir.join(FILES_DIR_NAME);
+    let mut resolved: Vec<(PathBuf, PathBuf)> = vec![];
+    for (i, mapping) in manifest.0.iter().enumerate() {
+        let source = match mapping.resolve_source(&files_dir) {
+            Ok(source) => source,
+            Err(message) => {
+                report.error(&file, format!("files[{i}]: {message}"));
+                continue;
+            }
+        };
+        // A dangling symlink is still re-created
+        if source.symlink_metadata().is_err() {
+            report.error(
+                &file,
+                format!("files[{i}]: source {} does not exist", source.display()),
+            );
+        }
+        match mapping.resolve_target(home) {
+            Ok(target) => resolved.push((source, target)),
+            Err(message) => report.error(&file, format!("files[{i}]: {message}")),
+        }
+    }
+
+    for (i, (source, target)) in resolved.iter().enumerate() {
+        if resolved[..i].iter().any(|(_, other)| other == target) {
+            report.error(
+                &file,
+                format!("{} is the target of more than one entry", target.display()),
+            );
+            continue;
+        }
+        // A file not in the manifest that the convention links at the same target
+        let conventional = target
+            .strip_prefix(home)
+            .map(|relative| files_dir.join(relative));
+        if let Ok(conventional) = conventional
+            && conventional != *source
+            && conventional.is_file()
+            && !resolved.iter().any(|(moved, _)| *moved == conventional)
+        {
+            report.warning(
+                &file,
+                format!(
+                    "{} is also the target of {}, the manifest entry wins",
+                    target.display(),
+                    conventional.display()
+                ),
+            );
+        }
+    }
+}
+
+fn validate_dotenv(env_dir: &Path, report: &mut ValidationReport) {
+    let Some(file) = dotenv_file(env_dir) else {
+        return;
+    };
+    let [preferred, other] = DOTENV_FILE_NAMES;
+    if env_dir.join(other).is_file() && env_dir.join(preferred).is_file() {
+        report.warning(
+            &env_dir.join(other),
+            format!("ignored because {preferred} exists too"),
+        );
+    }
+    match std::fs::read_to_string(&file) {
+        Ok(content) => {
+            for error in parse_dotenv(&content).1 {
+                report.error(&file, format!("line {}: {}", error.line, error.message));
+            }
+        }
+        Err(e) => report.error(&file, format!("could not read dotenv file: {e}")),
+    }
+}
+
+fn validate_env_config(
+    config: &EnvironmentConfig,
+    file: &Path,
+    system: &SystemNames,
+    report: &mut ValidationReport,
+) {
+    for (i, var) in config.env_vars.iter().enumerate() {
+        if !is_valid_env_var_key(&var.key) {
+            report.error(
+                file,
+                format!("env var key '{}' is not a valid identifier", var.key),
+            );
+        }
+        if let Some(reason) = var.when.as_ref().and_then(|when| when.never_matches()) {
+            report.warning(
+                file,
+                format!("env var {} is never set: its `when` {reason}", var.key),
+            );
+        }
+        // Later entries win; one with a `when` only does on some machines
+        if config.env_vars[i + 1..]
+            .iter()
+            .any(|later| later.key == var.key && later.when.is_none())
+        {
+            report.warning(
+                file,
+                format!(
+                    "env_vars[{i}]: {} is set again further down, which always wins",
+                    var.key
+                ),
+            );
+        }
+    }
+    for dir in &config.link_dirs {
+        if !is_valid_link_dir(dir) {
+            report.error(
+                file,
+                format!("link_dirs entry '{dir}' must be a relative path inside {FILES_DIR_NAME}/"),
+            );
+        }
+    }
+    for key in &config.unset_vars {
+        if !is_valid_env_var_key(key) {
+            report.error(
+                file,
+                format!("unset_vars entry '{key}' is not a valid identifier"),
+            );
+        } else if config.env_vars.iter().any(|var| &var.key == key) {
+            report.error(file, format!("'{key}' is both in env_vars and unset_vars"));
+        }
+    }
+    for (i, alias) in config.aliases.iter().enumerate() {
+        if !is_valid_alias_name(&alias.name) {
+            report.error(
+                file,
+                format!("aliases[{i}]: '{}' is not a valid alias name", alias.name),
+            );
+        } else if config.aliases[..i].iter().any(|a| a.name == alias.name) {
+            report.error(file, format!("alias '{}' is defined twice", alias.name));
+        }
+        if alias.command.trim().is_empty() {
+            report.error(file, format!("aliases[{i}]: command must not be empty"));
+        }
+    }
+
+    if let Some(tailscale) = &config.tailscale
+        && tailscale.tailnet.trim().is_empty()
+    {
+        report.error(file, "tailscale.tailnet must not be empty");
+    }
+    if let Some(exit_node) = config.tailscale.as_ref().and_then(|t| t.exit_node.as_ref())
+        && exit_node != exit_node.trim()
+    {
+        report.error(
+            file,
+            "tailscale.exit_node must not have surrounding whitespace, use '' for none",
+        );
+    }
+
+    if let Some(gh_cli) = &config.gh_cli {
+        if gh_cli.hosts.is_empty() {
+            report.error(file, "gh_cli.hosts must contain at least one host");
+        }
+        for (i, host) in gh_cli.hosts.iter().enumerate() {
+            if host.host.trim().is_empty() || host.user.trim().is_empty() {
+                report.error(
+                    file,
+                    format!("gh_cli.hosts[{i}] needs a non-empty host and user"),
+                );
+            }
+            if let Some(protocol) = &host.git_protocol
+                && !matches!(protocol.as_str(), "ssh" | "https")
+            {
+                report.error(
+                    file,
+                    format!(
+                        "gh_cli.hosts[{i}].git_protocol must be ssh or https, not '{protocol}'"
+                    ),
+                );
+            }
+        }
+        for (key, value) in &gh_cli.gh_config {
+            let valid_key = !key.is_empty()
+                && key.split('.').count() <= 2
+                && key
+                    .split('.')
+                    .all(|part| !part.is_empty() && !part.contains(char::is_whitespace));
+            if !valid_key {
+                report.error(
+                    file,
+                    format!("gh_cli.gh_config key '{key}' must be a config.yml key like editor or aliases.co"),
+                );
+            }
+            if value.contains('\n') {
+                report.error(
+                    file,
+                    format!("gh_cli.gh_config.{key} must be a single line"),
+                );
+            }
+        }
+    }
+
+    if let Some(git) = &config.git {
+        for (field, value) in [
+            ("user_name", &git.user_name),
+            ("user_email", &git.user_email),
+            ("signing_key", &git.signing_key),
+        ] {
+            if value.as_ref().is_some_and(|value| value.trim().is_empty()) {
+                report.error(file, format!("git.{field} must not be empty"));
+            }
+        }
+        if git
+            .user_email
+            .as_ref()
+            .is_some_and(|email| !email.contains('@'))
+        {
+            report.warning(file, "git.user_email does not look like an email address");
+        }
+    }
+
+    if let Some(aws) = &config.aws {
+        for (field, value) in [
+            ("profile", Some(&aws.profile)),
+            ("region", aws.region.as_ref()),
+        ] {
+            if value.is_some_and(|value| value.trim().is_empty()) {
+                report.error(file, format!("aws.{field} must not be empty"));
+            }
+        }
+    }
+
+    if let Some(gcloud) = &config.gcloud {
+        for (field, value) in [
+            ("configuration", Some(&gcloud.configuration)),
+            ("project", gcloud.project.as_ref()),
+            ("account", gcloud.account.as_ref()),
+        ] {
+            if value.is_some_and(|value| value.trim().is_empty()) {
+                report.error(file, format!("gcloud.{field} must not be empty"));
+            }
+        }
+    }
+
+    if let Some(npm) = &config.npm {
+        if let Some(source) = &npm.npmrc_source {
+            if !is_inside_env_dir(source) {
+                report.error(
+                    file,
+                    format!("npm.npmrc_source {source} must be relative to the environment dir"),
+                );
+            }
+            if npm.registry.is_some()
+                || !npm.scope_registries.is_empty()
+                || !npm.auth_tokens.is_empty()
+            {
+                report.error(
+                    file,
+                    "npm.npmrc_source can't be combined with registry, scope_registries or auth_tokens",
+                );
+            }
+        }
+        for scope in npm.scope_registries.keys() {
+            if !scope.starts_with('@') || scope.len() < 2 {
+                report.error(
+                    file,
+                    format!("npm.scope_registries key {scope} must be a scope like @corp"),
+                );
+            }
+        }
+        for registry in npm.auth_tokens.keys() {
+            if !registry.starts_with("https://") && !registry.starts_with("http://") {
+                report.error(
+                    file,
+                    format!("npm.auth_tokens key {registry} must be a registry URL"),
+                );
+            }
+        }
+    }
+
+    if let Some(gpg) = &config.gpg {
+        if gpg.default_key.trim().is_empty() {
+            report.error(file, "gpg.default_key must not be empty");
+        }
+        if gpg.also_set_git_signing_key
+            && let Some(signing_key) = config.git.as_ref().and_then(|git| git.signing_key.as_ref())
+            && *signing_key != gpg.default_key
+        {
+            report.warning(
+                file,
+                format!(
+                    "git.signing_key {signing_key} wins over gpg.default_key {} as the signing key",
+                    gpg.default_key
+                ),
+            );
+        }
+    }
+
+    if let Some(ssh) = &config.ssh {
+        match &ssh.config_file {
+            Some(config_file) if !is_inside_env_dir(config_file) => report.error(
+                file,
+                format!("ssh.config_file {config_file} must be relative to the environment dir"),
+            ),
+            Some(config_file) => {
+                let env_dir = file.parent().unwrap_or(Path::new("."));
+                if !env_dir.join(config_file).is_file() {
+                    report.error(
+                        file,
+                        format!("ssh.config_file {config_file} does not exist"),
+                    );
+                }
+            }
+            None if ssh.config.is_none() => {
+                report.error(file, "ssh needs config_file or config");
+            }
+            None => {}
+        }
+    }
+
+    if let Some(mise) = &config.mise {
+        for (tool, version) in &mise.globals {
+            if tool.is_empty() || tool.contains(|c: char| c.is_whitespace() || c == '=') {
+                report.error(
+                    file,
+                    format!("mise.globals key '{tool}' must be a tool name like node"),
+                );
+            }
+            if version.is_empty() || version.contains(char::is_whitespace) {
+                report.error(
+                    file,
+                    format!(
+                        "mise.globals.{tool} must be a single version like 20, not '{version}'"
+                    ),
+                );
+            }
+        }
+        let tool = mise.format.tool();
+        if let Some(path_var) = &system.path_var
+            && find_in_path(tool, path_var).is_none()
+        {
+            report.warning(
+                file,
+                format!(
+                    "{tool} is not installed, the mise integration writes versions nothing reads"
+                ),
+            );
+        }
+    }
+
+    if let Some(maven) = &config.maven {
+        match &maven.settings_source {
+            Some(source) if !is_inside_env_dir(source) => report.error(
+                file,
+                format!("maven.settings_source {source} must be relative to the environment dir"),
+            ),
+            Some(source) => {
+                let env_dir = file.parent().unwrap_or(Path::new("."));
+                if !env_dir.join(source).is_file() {
+                    report.error(
+                        file,
+                        format!("maven.settings_source {source} does not exist"),
+                    );
+                }
+            }
+            None if maven.gradle_properties.is_empty() => {
+                report.error(file, "maven needs settings_source or gradle_properties");
+            }
+            None => {}
+        }
+        for key in maven.gradle_properties.keys() {
+            if key.is_empty() || key.contains(|c: char| c.is_whitespace() || c == '=' || c == ':') {
+                report.error(
+                    file,
+                    format!("maven.gradle_properties key '{key}' must be a property name"),
+                );
+            }
+        }
+    }
+
+    if let Some(python) = &config.python {
+        let urls = std::iter::once(("index_url", &python.index_url)).chain(
+            python
+                .extra_index_urls
+                .iter()
+                .map(|url| ("extra_index_urls", url)),
+        );
+        for (field, url) in urls {
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                report.error(file, format!("python.{field} '{url}' must be an index URL"));
+            }
+        }
+        if python
+            .netrc_machine
+            .as_ref()
+            .is_some_and(|machine| machine.trim().is_empty())
+        {
+            report.error(file, "python.netrc_machine must not be empty");
+        }
+    }
+
+    if let Some(cargo) = &config.cargo {
+        for (name, registry) in &cargo.registries {
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                report.error(
+                    file,
+                    format!("cargo.registries name '{name}' must be a registry name"),
+                );
+            }
+            if !registry.index.contains("://") {
+                report.error(
+                    file,
+                    format!(
+                        "cargo.registries.{name}.index '{}' must be an index URL",
+                        registry.index
+                    ),
+                );
+            }
+        }
+        if let Some(default) = &cargo.default_registry
+            && !cargo.registries.contains_key(default)
+        {
+            report.warning(
+                file,
+                format!(
+                    "cargo.default_registry '{default}' is not one of cargo.registries, it \
+                     must be in your own ~/.cargo/config.toml"
+                ),
+            );
+        }
+    }
+
+    if let Some(op_ssh) = &config.op_ssh {
+        for (i, key) in op_ssh.keys.iter().enumerate() {
+            if key.vault.is_none() && key.item.is_none() && key.account.is_none() {
+                report.error(
+                    file,
+                    format!("op_ssh.keys[{i}] needs at least one of vault, item or account"),
+                );
+            }
+        }
+    }
+
+    if let Some(timezone) = &config.timezone {
+        let check = match &system.zoneinfo_dir {
+            Some(dir) => check_timezone(timezone, dir),
+            None => NameCheck::Unverifiable("no tzdata found".into()),
+        };
+        report_name_check(report, file, "timezone", timezone, check);
+    }
+    if let Some(locale) = &config.locale {
+        report_name_check(report, file, "locale", locale, system.locales.check(locale));
+    }
+}
+
+fn report_name_check(
+    report: &mut ValidationReport,
+    file: &Path,
+    field: &str,
+    value: &str,
+    check: NameCheck,
+) {
+    match check {
+        NameCheck::Valid => {}
+        NameCheck::Invalid { suggestion } => report.error(
+            file,
+            format!(
+                "{field} '{value}' is not known to this system{}",
+                suggestion
+                    .map(|s| format!(", did you mean '{s}'?"))
+                    .unwrap_or_default()
+            ),
+        ),
+        NameCheck::Unverifiable(reason) => report.warning(
+            file,
+            format!("could not verify {field} '{value}': {reason}"),
+        ),
+    }
+}
+
+/// Whether `name` can be used unquoted as an alias or function name in every
+/// supported shell (`[A-Za-z0-9_][A-Za-z0-9_.-]*`)
+pub fn is_valid_alias_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphanumeric() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+/// Whether `key` is a portable environment variable name (`[A-Za-z_][A-Za-z0-9_]*`)
+pub fn is_valid_env_var_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn env_dir_with_config(name: &str, config: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(ENV_CONFIG_FILE_NAME), config).unwrap();
+        dir
+    }
+
+    /// No tzdata; `locale -a` is only run by tests that configure a locale
+    fn system() -> SystemNames<'static> {
+        SystemNames {
+            zoneinfo_dir: None,
+            locales: LocaleCatalog::new(&SystemRunner),
+            path_var: None,
+        }
+    }
+
+    #[test]
+    fn test_is_valid_env_var_key() {
+        assert!(is_valid_env_var_key("PATH"));
+        assert!(is_valid_env_var_key("_private1"));
+        assert!(!is_valid_env_var_key(""));
+        assert!(!is_valid_env_var_key("1ABC"));
+        assert!(!is_valid_env_var_key("MY-VAR"));
+    }
+
+    #[test]
+    fn test_is_valid_alias_name() {
+        assert!(is_valid_alias_name("k"));
+        assert!(is_valid_alias_name("git-st"));
+        assert!(is_valid_alias_name("2fa.sh"));
+        assert!(!is_valid_alias_name(""));
+        assert!(!is_valid_alias_name("-rf"));
+        assert!(!is_valid_alias_name("k;rm"));
+        assert!(!is_valid_alias_name("two words"));
+    }
+
+    #[test]
+    fn test_validate_valid_env() {
+        let dir = env_dir_with_config(
+            "envmgr_test_validate_valid",
+            "name: Work\nenv_vars:\n  - key: FOO\n    value: bar\n",
+        );
+        let mut report = ValidationReport::default();
+        validate_env_dir(&dir, "work", &system(), &mut report);
+
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_parse_error_has_location() {
+        let dir = env_dir_with_config(
+            "envmgr_test_validate_parse_error",
+            "name: Work\nenv_vars:\n  - key: FOO\n",
+        );
+        let mut report = ValidationReport::default();
+        validate_env_dir(&dir, "work", &system(), &mut report);
+
+        assert!(report.has_errors());
+        let message = &report.issues[0].message;
+        assert!(message.contains("env_vars[0]"), "{message}");
+        assert!(message.contains("line"), "{message}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_structural_checks() {
+        let dir = env_dir_with_config(
+            "envmgr_test_validate_structural",
+            "name: Work\nenv_vars:\n  - key: BAD-KEY\n    value: x\n  - key: FOO\n    value: x\nunset_vars: [FOO, 2BAD]\ntailscale:\n  tailnet: ''\n  exit_node: ' exit-fra'\ngh_cli:\n  hosts: []\n  gh_config: {a.b.c: x}\ngit:\n  user_name: ''\naws:\n  profile: ''\ngcloud:\n  configuration: ''\nnpm:\n  registry: https://npm.example/\n  npmrc_source: ../npmrc\n  scope_registries: {corp: https://npm.example/}\nssh: {}\ngpg:\n  default_key: ''\nmise:\n  globals: {'': '20', node: 'twenty two'}\nmaven:\n  settings_source: maven/settings.xml\n  gradle_properties: {'proxy host': x}\npython:\n  index_url: pypi.corp.example\n  netrc_machine: ''\ncargo:\n  registries: {'my corp': {index: cargo.corp.example}}\n  default_registry: corp\naliases:\n  - {name: 'k k', command: kubectl}\n  - {name: gs, command: ''}\n  - {name: gs, command: git status}\n",
+        );
+        fs::write(dir.join(FILES_DIR_NAME), "not a directory").unwrap();
+        let mut report = ValidationReport::default();
+        validate_env_dir(&dir, "work", &system(), &mut report);
+
+        assert_eq!(report.error_count(), 27, "{:?}", report.issues);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_warns_when_mise_is_not_installed() {
+        let dir = env_dir_with_config(
+            "envmgr_test_validate_mise",
+            "name: Work\nmise:\n  globals: {node: '20'}\n  format: asdf\n",
+        );
+        let system = SystemNames {
+            path_var: Some(dir.join("bin").into_os_string()),
+            ..system()
+        };
+        let mut report = ValidationReport::default();
+        validate_env_dir(&dir, "work", &system, &mut report);
+
+        let messages: Vec<(Severity, &str)> = report
+            .issues
+            .iter()
+            .map(|i| (i.severity, i.message.as_str()))
+            .collect();
+        assert_eq!(
+            messages,
+            [(
+                Severity::Warning,
+                "asdf is not installed, the mise integration writes versions nothing reads"
+            )]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_warns_about_conditions_that_never_match() {
+        let dir = env_dir_with_config(
+            "envmgr_test_validate_when",
+            "name: Work\nenv_vars:\n  - key: A\n    value: x\n    when: {hostname: ''}\n  - key: B\n    value: x\n    when: {hostname: 'laptop-*', os: macos}\n",
+        );
+        let mut report = ValidationReport::default();
+        validate_env_dir(&dir, "work", &system(), &mut report);
+
+        assert!(!report.has_errors(), "{:?}", report.issues);
+        let messages: Vec<&str> = report.issues.iter().map(|i| i.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            ["env var A is never set: its `when` hostname is empty"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_warns_about_duplicate_env_vars() {
+        let dir = env_dir_with_config(
+            "envmgr_test_validate_duplicate_vars",
+            "name: Work\nenv_vars:\n  - {key: A, value: x}\n  - {key: B, value: x}\n  - {key: A, value: y}\n  - {key: B, value: y, when: {os: linux}}\n",
+        );
+        let mut report = ValidationReport::default();
+        validate_env_dir(&dir, "work", &system(), &mut report);
+
+        let messages: Vec<(Severity, &str)> = report
+            .issues
+            .iter()
+            .map(|i| (i.severity, i.message.as_str()))
+            .collect();
+        assert_eq!(
+            messages,
+            [(
+                Severity::Warning,
+                "env_vars[0]: A is set again further down, which always wins"
+            )]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_reports_dotenv_lines() {
+        let dir = env_dir_with_config("envmgr_test_validate_dotenv", "name: Work\n");
+        fs::write(dir.join("env.dotenv"), "GOOD=1\noops\nBAD-KEY=2\n").unwrap();
+        fs::write(dir.join("vars.env"), "IGNORED=1\n").unwrap();
+        let mut report = ValidationReport::default();
+        validate_env_dir(&dir, "work", &system(), &mut report);
+
+        let messages: Vec<(Severity, &str)> = report
+            .issues
+            .iter()
+            .map(|i| (i.severity, i.message.as_str()))
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                (Severity::Warning, "ignored because env.dotenv exists too"),
+                (Severity::Error, "line 2: expected KEY=VALUE, found 'oops'"),
+                (
+                    Severity::Error,
+                    "line 3: 'BAD-KEY' is not a valid env var name"
+                ),
+            ]
+        );
+        assert_eq!(report.issues[1].file, dir.join("env.dotenv"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_files_manifest() {
+        let dir = env_dir_with_config("envmgr_test_validate_files_manifest", "name: Work\n");
+        let files_dir = dir.join(FILES_DIR_NAME);
+        fs::create_dir_all(files_dir.join(".kube")).unwrap();
+        fs::write(files_dir.join("kube-config"), "").unwrap();
+        fs::write(files_dir.join(".kube/config"), "").unwrap();
+        fs::write(files_dir.join("app.conf"), "").unwrap();
+        fs::write(
+            FilesManifest::file_path(&dir),
+            indoc::indoc! {"
+                - { source: kube-config, target: ~/.kube/config }
+                - { source: app.conf, target: /etc/app.conf }
+                - { source: missing, target: ~/.missing }
+                - { source: ../config.yaml, target: ~/.work }
+                - { source: app.conf, target: ~/.kube/config }
+            "},
+        )
+        .unwrap();
+        let mut report = ValidationReport::default();
+        validate_files_manifest(&dir, Path::new("/home/me"), &mut report);
+
+        let messages: Vec<(Severity, &str)> = report
+            .issues
+            .iter()
+            .map(|i| (i.severity, i.message.as_str()))
+            .collect();
+        let conventional = files_dir.join(".kube/config");
+        assert_eq!(
+            messages,
+            [
+                (
+                    Severity::Error,
+                    "files[1]: target /etc/app.conf is outside the home directory, set `allow_outside_home: true` to link it anyway"
+                ),
+                (
+                    Severity::Error,
+                    &*format!(
+                        "files[2]: source {} does not exist",
+                        files_dir.join("missing").display()
+                    )
+                ),
+                (
+                    Severity::Error,
+                    "files[3]: source ../config.yaml must be a path inside files/"
+                ),
+                (
+                    Severity::Warning,
+                    &*format!(
+                        "/home/me/.kube/config is also the target of {}, the manifest entry wins",
+                        conventional.display()
+                    )
+                ),
+                (
+                    Severity::Error,
+                    "/home/me/.kube/config is the target of more than one entry"
+                ),
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_link_dirs() {
+        let dir = env_dir_with_config(
+            "envmgr_test_validate_link_dirs",
+            "name: Work\nlink_dirs: [.config/nvim, ../outside, .config/missing]\n",
+        );
+        fs::create_dir_all(dir.join("files/.config/nvim")).unwrap();
+        let mut report = ValidationReport::default();
+        validate_env_dir(&dir, "work", &system(), &mut report);
+
+        let messages: Vec<(Severity, &str)> = report
+            .issues
+            .iter()
+            .map(|i| (i.severity, i.message.as_str()))
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                (
+                    Severity::Error,
+                    "link_dirs entry '../outside' must be a relative path inside files/"
+                ),
+                (
+                    Severity::Warning,
+                    "link_dirs entry .config/missing is not a directory in files/"
+                ),
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_rejects_files_inside_base_link_dirs() {
+        let dir = env_dir_with_config("envmgr_test_validate_base_link_dirs", "name: Work\n");
+        fs::create_dir_all(dir.join("files/.config/nvim/lua")).unwrap();
+        fs::write(dir.join("files/.config/nvim/lua/work.lua"), "").unwrap();
+        fs::write(dir.join("files/.gitconfig"), "").unwrap();
+        let home = Path::new("/home/me");
+        let base_link_dirs = [".config/nvim/".to_string()];
+
+        let mut report = ValidationReport::default();
+        validate_files_inside_base_link_dirs(&dir, &base_link_dirs, home, &mut report);
+        let messages: Vec<&str> = report.issues.iter().map(|i| i.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "/home/me/.config/nvim/lua/work.lua is inside .config/nvim, which base links as a whole; move the file into base or drop .config/nvim from base's link_dirs"
+            ]
+        );
+
+        // Without base layered under it, the environment's files can go anywhere
+        fs::write(
+            dir.join(ENV_CONFIG_FILE_NAME),
+            "name: Work\ninherit_base: false\n",
+        )
+        .unwrap();
+        let mut report = ValidationReport::default();
+        validate_files_inside_base_link_dirs(&dir, &base_link_dirs, home, &mut report);
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_timezone_and_locale() {
+        struct NoLocaleCommand;
+        impl crate::runner::CommandRunner for NoLocaleCommand {
+            fn run(
+                &self,
+                _program: &str,
+                _args: &[&str],
+            ) -> std::io::Result<crate::runner::CommandOutput> {
+                Err(std::io::ErrorKind::NotFound.into())
+            }
+        }
+
+        let dir = env_dir_with_config(
+            "envmgr_test_validate_timezone",
+            "name: Work\ntimezone: Europe/Buda\nlocale: en_US.UTF-8\n",
+        );
+        let zoneinfo = dir.join("zoneinfo");
+        fs::create_dir_all(zoneinfo.join("Europe")).unwrap();
+        fs::write(zoneinfo.join("Europe/Budapest"), "TZif").unwrap();
+        let system = SystemNames {
+            zoneinfo_dir: Some(zoneinfo),
+            locales: LocaleCatalog::new(&NoLocaleCommand),
+            path_var: None,
+        };
+        let mut report = ValidationReport::default();
+        validate_env_dir(&dir, "work", &system, &mut report);
+
+        assert_eq!(report.error_count(), 1, "{:?}", report.issues);
+        assert!(
+            report.issues[0]
+                .message
+                .contains("did you mean 'Europe/Budapest'?")
+        );
+        assert_eq!(report.issues[1].severity, Severity::Warning);
+        assert!(report.issues[1].message.contains("locale 'en_US.UTF-8'"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_kube() {
+        let home = std::env::temp_dir().join("envmgr_test_validate_kube");
+        let _ = fs::remove_dir_all(&home);
+        fs::create_dir_all(home.join(".kube")).unwrap();
+        fs::write(
+            home.join(".kube/config-client"),
+            "current-context: staging\ncontexts:\n  - name: staging\n    context: {cluster: s}\n",
+        )
+        .unwrap();
+        let file = home.join(ENV_CONFIG_FILE_NAME);
+        let kube = |kubeconfig: &str, context: &str| KubeConfig {
+            kubeconfig: Some(kubeconfig.to_string()),
+            context: Some(context.to_string()),
+            namespace: None,
+        };
+        let messages = |kube: &KubeConfig| {
+            let mut report = ValidationReport::default();
+            validate_kube(kube, &file, &home, &mut report);
+            report
+                .issues
+                .into_iter()
+                .map(|issue| issue.message)
+                .collect::<Vec<_>>()
+        };
+
+        assert!(messages(&kube("~/.kube/config-client", "staging")).is_empty());
+        assert_eq!(
+            messages(&kube("~/.kube/config-client", "prod")),
+            [format!(
+                "kube.context 'prod' is not defined in {}",
+                home.join(".kube/config-client").display()
+            )]
+        );
+        assert_eq!(
+            messages(&kube("~/.kube/missing", "staging")),
+            [format!(
+                "kube: kubeconfig {} does not exist",
+                home.join(".kube/missing").display()
+            )]
+        );
+        assert_eq!(
+            messages(&KubeConfig::default()),
+            ["kube needs at least one of kubeconfig, context or namespace"]
+        );
+        fs::remove_dir_all(&home).unwrap();
+    }
+}
@@ -0,0 +1,408 @@
+//! Background refresh of slow probes.
+//!
+//! `envmgr daemon` periodically writes a probe cache into the state directory.
+//! Readers (`prompt`, `show --live`, `doctor`) use [`ProbeCache::load_or_probe`],
+//! which returns the cached probes while fresh and probes directly otherwise
+//! (e.g. when no daemon is running).
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use indoc::indoc;
+use log::{debug, info, warn};
+
+use crate::{
+    commands::history::relative_time,
+    environment::hash_file,
+    error::EnvMgrResult,
+    integrations::{gh_cli::GhCli, tailscale::Tailscale},
+    runner::{CommandRunner, SystemRunner},
+    state::{CopiedFile, State},
+};
+
+const PROBE_CACHE_FILE_NAME: &str = "probes.toml";
+/// How old a cache readers still use: two refreshes at the default interval
+pub const PROBE_MAX_AGE: Duration = Duration::from_secs(600);
+/// Tools whose versions are recorded, with the arguments that print the version
+const PROBED_TOOLS: &[(&str, &[&str])] = &[
+    ("gh", &["--version"]),
+    ("op", &["--version"]),
+    ("tailscale", &["version"]),
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ProbeCache {
+    /// Seconds since the unix epoch when the probes ran
+    pub refreshed_at: u64,
+    #[serde(default)]
+    pub tool_versions: BTreeMap<String, String>,
+    /// Active gh user per host
+    #[serde(default)]
+    pub gh_users: BTreeMap<String, String>,
+    pub tailnet: Option<String>,
+    /// SHA-256 of the content behind each managed file
+    #[serde(default)]
+    pub drift_hashes: BTreeMap<PathBuf, String>,
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl ProbeCache {
+    pub fn cache_file_path(state_dir: &Path) -> PathBuf {
+        state_dir.join(PROBE_CACHE_FILE_NAME)
+    }
+
+    /// Run all probes now, commands through `runner`. Probes for missing tools are left
+    /// empty.
+    pub fn probe(now: u64, runner: &dyn CommandRunner) -> Self {
+        let mut cache = Self::probe_files(now);
+        cache.probe_commands(runner);
+        match State::get_state() {
+            Ok(state) => {
+                let copies = state.copied_files.into_iter().map(|copied| copied.target);
+                let files = state
+                    .managed_files
+                    .into_iter()
+                    .map(|managed| managed.target)
+                    .filter(|file| !state.linked_dirs.contains(file));
+                for file in files.chain(copies) {
+                    if let Ok(hash) = hash_file(&file) {
+                        cache.drift_hashes.insert(file, hash);
+                    }
+                }
+            }
+            Err(e) => debug!("Could not read state for drift hashes: {e}"),
+        }
+        cache
+    }
+
+    /// Only the probes that read a file, cheap enough for every prompt redraw
+    pub fn probe_files(now: u64) -> Self {
+        let mut cache = Self {
+            refreshed_at: now,
+            ..Default::default()
+        };
+        match GhCli::active_users() {
+            Ok(users) => cache.gh_users = users,
+            Err(e) => debug!("Could not probe gh users: {e}"),
+        }
+        cache
+    }
+
+    /// Tool versions and the active tailnet
+    fn probe_commands(&mut self, runner: &dyn CommandRunner) {
+        for (tool, args) in PROBED_TOOLS {
+            match runner.run(tool, args) {
+                Ok(output) if output.success => {
+                    if let Some(line) = output.stdout.lines().next() {
+                        self.tool_versions
+                            .insert(tool.to_string(), line.trim().to_string());
+                    }
+                }
+                _ => debug!("Could not determine version of {tool}"),
+            }
+        }
+        match Tailscale::active_tailnet(runner) {
+            Ok(tailnet) => self.tailnet = tailnet,
+            Err(e) => debug!("Could not probe tailnet: {e}"),
+        }
+    }
+
+    /// Copies whose content changed since the switch that wrote them
+    pub fn edited_copies<'c>(&self, copies: &'c [CopiedFile]) -> Vec<&'c Path> {
+        copies
+            .iter()
+            .filter(|copy| {
+                self.drift_hashes
+                    .get(&copy.target)
+                    .is_some_and(|hash| *hash != copy.hash)
+            })
+            .map(|copy| copy.target.as_path())
+            .collect()
+    }
+
+    /// The probes for terminal output, one per line
+    pub fn render(&self, now: u64) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "Probed {}", relative_time(now, self.refreshed_at));
+        for (tool, version) in &self.tool_versions {
+            let _ = writeln!(out, "  {tool}: {version}");
+        }
+        for (host, user) in &self.gh_users {
+            let _ = writeln!(out, "  gh user on {host}: {user}");
+        }
+        if let Some(tailnet) = &self.tailnet {
+            let _ = writeln!(out, "  tailnet: {tailnet}");
+        }
+        out
+    }
+
+    /// Whether the cache is at most `max_age` old at `now`
+    pub fn is_fresh(&self, now: u64, max_age: Duration) -> bool {
+        self.refreshed_at <= now && now - self.refreshed_at <= max_age.as_secs()
+    }
+
+    pub fn load(state_dir: &Path) -> Option<Self> {
+        let content = std::fs::read(Self::cache_file_path(state_dir)).ok()?;
+        toml::from_slice(&content).ok()
+    }
+
+    /// Write the cache through a temporary file so readers never see partial content
+    pub fn store(&self, state_dir: &Path) -> EnvMgrResult<()> {
+        let path = Self::cache_file_path(state_dir);
+        let tmp_path = path.with_extension("toml.tmp");
+        std::fs::write(&tmp_path, toml::to_string_pretty(self)?)?;
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// Return the cached probes when fresh, otherwise probe directly
+    pub fn load_or_probe(
+        state_dir: &Path,
+        now: u64,
+        max_age: Duration,
+        probe: impl FnOnce(u64) -> Self,
+    ) -> Self {
+        match Self::load(state_dir) {
+            Some(cache) if cache.is_fresh(now, max_age) => cache,
+            _ => {
+                debug!("Probe cache missing or stale, probing directly");
+                probe(now)
+            }
+        }
+    }
+}
+
+/// The probes for readers: the daemon's cache while fresh, direct probes through `runner`
+/// otherwise
+pub fn current_probes(runner: &dyn CommandRunner) -> ProbeCache {
+    let now = unix_now();
+    match crate::platform::state_dir() {
+        Some(state_dir) => ProbeCache::load_or_probe(&state_dir, now, PROBE_MAX_AGE, |now| {
+            ProbeCache::probe(now, runner)
+        }),
+        None => ProbeCache::probe(now, runner),
+    }
+}
+
+/// Refresh the probe cache every `interval` until `stop` is set.
+///
+/// Sleeping and refreshing are injected so the loop can be tested without real time passing.
+/// Returns the number of refreshes performed.
+pub fn run_loop(
+    interval: Duration,
+    stop: &AtomicBool,
+    mut sleep: impl FnMut(Duration, &AtomicBool),
+    mut refresh: impl FnMut() -> EnvMgrResult<()>,
+) -> usize {
+    let mut refreshes = 0;
+    while !stop.load(Ordering::SeqCst) {
+        if let Err(e) = refresh() {
+            warn!("Probe refresh failed: {e}");
+        }
+        refreshes += 1;
+        sleep(interval, stop);
+    }
+    refreshes
+}
+
+/// Sleep for `duration`, waking up early when `stop` gets set
+pub fn interruptible_sleep(duration: Duration, stop: &AtomicBool) {
+    let step = Duration::from_millis(250);
+    let mut slept = Duration::ZERO;
+    while slept < duration && !stop.load(Ordering::SeqCst) {
+        std::thread::sleep(step);
+        slept += step;
+    }
+}
+
+/// Run the daemon in the foreground until SIGINT/SIGTERM
+pub fn run(interval: Duration) -> EnvMgrResult<()> {
+    let state_dir = crate::platform::state_dir().ok_or(crate::error::EnvMgrError::DirError(
+        "Could not determine state directory".into(),
+    ))?;
+    std::fs::create_dir_all(&state_dir)?;
+
+    static STOP: AtomicBool = AtomicBool::new(false);
+    ctrlc::set_handler(|| STOP.store(true, Ordering::SeqCst))
+        .map_err(|e| crate::error::EnvMgrError::Other(e.into()))?;
+
+    info!(
+        "Refreshing probe cache every {}s in {}",
+        interval.as_secs(),
+        ProbeCache::cache_file_path(&state_dir).display()
+    );
+    run_loop(interval, &STOP, interruptible_sleep, || {
+        ProbeCache::probe(unix_now(), &SystemRunner).store(&state_dir)
+    });
+    info!("Daemon stopped");
+    Ok(())
+}
+
+/// A systemd user unit running the daemon, for `~/.config/systemd/user/envmgr.service`
+pub fn systemd_unit(bin_path: &str, interval: Duration) -> String {
+    indoc! {"
+        [Unit]
+        Description=envmgr probe cache refresh
+
+        [Service]
+        ExecStart=BIN_PATH daemon --interval INTERVAL
+        Restart=on-failure
+
+        [Install]
+        WantedBy=default.target
+    "}
+    .replace("BIN_PATH", bin_path)
+    .replace("INTERVAL", &interval.as_secs().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::runner::CommandOutput;
+
+    fn cache_at(refreshed_at: u64) -> ProbeCache {
+        ProbeCache {
+            refreshed_at,
+            tailnet: Some("cached.ts.net".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_is_fresh() {
+        let cache = cache_at(1000);
+        let max_age = Duration::from_secs(60);
+        assert!(cache.is_fresh(1000, max_age));
+        assert!(cache.is_fresh(1060, max_age));
+        assert!(!cache.is_fresh(1061, max_age));
+        // A timestamp from the future is not trusted
+        assert!(!cache.is_fresh(999, max_age));
+    }
+
+    #[test]
+    fn test_load_or_probe_uses_fresh_cache() {
+        let dir = std::env::temp_dir().join("envmgr_test_probe_cache_fresh");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        cache_at(1000).store(&dir).unwrap();
+
+        let cache = ProbeCache::load_or_probe(&dir, 1010, Duration::from_secs(60), |_| {
+            panic!("must not probe while the cache is fresh")
+        });
+        assert_eq!(cache.tailnet.as_deref(), Some("cached.ts.net"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_or_probe_falls_back_when_stale_or_missing() {
+        let dir = std::env::temp_dir().join("envmgr_test_probe_cache_stale");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let probed = ProbeCache::load_or_probe(&dir, 5000, Duration::from_secs(60), cache_at);
+        assert_eq!(probed.refreshed_at, 5000);
+
+        cache_at(1000).store(&dir).unwrap();
+        let probed =
+            ProbeCache::load_or_probe(&dir, 5000, Duration::from_secs(60), |now| ProbeCache {
+                refreshed_at: now,
+                ..Default::default()
+            });
+        assert_eq!(probed.refreshed_at, 5000);
+        assert_eq!(probed.tailnet, None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// gh and tailscale are installed, op is not
+    struct CannedTools;
+
+    impl CommandRunner for CannedTools {
+        fn run(&self, program: &str, args: &[&str]) -> std::io::Result<CommandOutput> {
+            let stdout = match (program, args.join(" ").as_str()) {
+                ("gh", "--version") => {
+                    "gh version 2.40.0 (2023-12-07)\nhttps://github.com/cli/cli\n"
+                }
+                ("tailscale", "version") => "1.56.1\n  tailscale commit: abc\n",
+                ("tailscale", "switch --list") => {
+                    "ID    Tailnet        Account\n1a2b  corp.ts.net    alice@corp.example*\n"
+                }
+                _ => return Err(std::io::ErrorKind::NotFound.into()),
+            };
+            Ok(CommandOutput {
+                success: true,
+                stdout: stdout.to_string(),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[test]
+    fn test_probe_commands_go_through_the_runner() {
+        let mut cache = cache_at(1000);
+        cache.tailnet = None;
+        cache.probe_commands(&CannedTools);
+        assert_eq!(
+            cache.tool_versions,
+            BTreeMap::from([
+                (
+                    "gh".to_string(),
+                    "gh version 2.40.0 (2023-12-07)".to_string()
+                ),
+                ("tailscale".to_string(), "1.56.1".to_string()),
+            ])
+        );
+        assert_eq!(cache.tailnet.as_deref(), Some("corp.ts.net"));
+    }
+
+    #[test]
+    fn test_run_loop_stops_without_real_sleep() {
+        let stop = AtomicBool::new(false);
+        let sleeps = Cell::new(0);
+        let refreshes = run_loop(
+            Duration::from_secs(300),
+            &stop,
+            |interval, stop| {
+                assert_eq!(interval, Duration::from_secs(300));
+                sleeps.set(sleeps.get() + 1);
+                if sleeps.get() == 3 {
+                    stop.store(true, Ordering::SeqCst);
+                }
+            },
+            || Ok(()),
+        );
+        assert_eq!(refreshes, 3);
+    }
+
+    #[test]
+    fn test_run_loop_survives_refresh_errors() {
+        let stop = AtomicBool::new(false);
+        let refreshes = run_loop(
+            Duration::from_secs(1),
+            &stop,
+            |_, stop| stop.store(true, Ordering::SeqCst),
+            || Err(crate::error::EnvMgrError::DirError("boom".into())),
+        );
+        assert_eq!(refreshes, 1);
+    }
+
+    #[test]
+    fn test_systemd_unit() {
+        let unit = systemd_unit("/usr/bin/envmgr", Duration::from_secs(120));
+        assert!(unit.contains("ExecStart=/usr/bin/envmgr daemon --interval 120"));
+    }
+}
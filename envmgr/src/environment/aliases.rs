@@ -0,0 +1,142 @@
+//! Shell aliases emitted by `envmgr use` after the env vars.
+//!
+//! Every `use` defines all configured aliases again, so a new shell gets them too.
+//! Aliases applied before that are no longer configured, or changed between alias and
+//! abbreviation, are removed first, the way they were defined.
+
+use log::warn;
+
+use crate::config::{AliasConfig, validate::is_valid_alias_name};
+
+/// One alias command emitted by `envmgr use`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AliasChange {
+    Remove(AliasConfig),
+    Define(AliasConfig),
+}
+
+/// Merge `layers` (base first) by name, sorted by name. Aliases with names that can't
+/// be put into a shell command are left out.
+pub fn merge_alias_layers(layers: &[&[AliasConfig]]) -> Vec<AliasConfig> {
+    let mut merged: Vec<AliasConfig> = vec![];
+    for alias in layers.iter().flat_map(|layer| layer.iter()) {
+        if !is_valid_alias_name(&alias.name) {
+            warn!("Skipping alias '{}': not a valid alias name", alias.name);
+            continue;
+        }
+        merged.retain(|existing| existing.name != alias.name);
+        merged.push(alias.clone());
+    }
+    merged.sort_by(|a, b| a.name.cmp(&b.name));
+    merged
+}
+
+/// Commands taking a shell that has `applied` defined to `aliases`
+pub fn plan_alias_changes(aliases: &[AliasConfig], applied: &[AliasConfig]) -> Vec<AliasChange> {
+    applied
+        .iter()
+        .filter(|old| {
+            !aliases
+                .iter()
+                .any(|alias| alias.name == old.name && alias.abbr == old.abbr)
+        })
+        .map(|old| AliasChange::Remove(old.clone()))
+        .chain(
+            aliases
+                .iter()
+                .map(|alias| AliasChange::Define(alias.clone())),
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Shell;
+
+    fn alias(name: &str, command: &str) -> AliasConfig {
+        AliasConfig {
+            name: name.to_string(),
+            command: command.to_string(),
+            abbr: false,
+        }
+    }
+
+    fn abbr(name: &str, command: &str) -> AliasConfig {
+        AliasConfig {
+            abbr: true,
+            ..alias(name, command)
+        }
+    }
+
+    fn render(changes: &[AliasChange], shell: Shell) -> String {
+        changes
+            .iter()
+            .map(|change| match change {
+                AliasChange::Remove(alias) => shell.unset_alias_cmd(alias),
+                AliasChange::Define(alias) => shell.set_alias_cmd(alias),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_environment_overrides_base_by_name() {
+        let base = [alias("ll", "ls -l"), alias("k", "kubectl")];
+        let env = [
+            alias("k", "kubectl --context client-abc"),
+            alias("bad name", "x"),
+        ];
+
+        assert_eq!(
+            merge_alias_layers(&[&base, &env]),
+            [
+                alias("k", "kubectl --context client-abc"),
+                alias("ll", "ls -l")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_removes_stale_and_changed_kind() {
+        let aliases = [alias("gs", "git status"), abbr("k", "kubectl")];
+        let applied = [
+            alias("k", "kubectl"),
+            alias("gs", "git status -s"),
+            abbr("tf", "terraform"),
+        ];
+
+        assert_eq!(
+            render(&plan_alias_changes(&aliases, &applied), Shell::Fish),
+            indoc::indoc! {"
+                functions -e k
+                abbr -e tf
+                alias gs 'git status'
+                abbr -a -g k 'kubectl'"}
+        );
+    }
+
+    #[test]
+    fn test_quotes_and_pipes_stay_in_the_body() {
+        let aliases = [
+            alias("pods", r#"kubectl get pods | grep -v "Completed""#),
+            alias("hist", r"git log --format='%h %s' | sed 's/\t/ /'"),
+            alias("tailslash", r"echo C:\"),
+        ];
+
+        assert_eq!(
+            render(&plan_alias_changes(&aliases, &[]), Shell::Fish),
+            indoc::indoc! {r#"
+                alias pods 'kubectl get pods | grep -v "Completed"'
+                alias hist 'git log --format=\'%h %s\' | sed \'s/\\t/ /\''
+                alias tailslash 'echo C:\\'"#}
+        );
+        assert_eq!(
+            render(&plan_alias_changes(&aliases, &[]), Shell::PowerShell),
+            indoc::indoc! {r#"
+                Set-Item -Path Function:global:pods -Value ([scriptblock]::Create('kubectl get pods | grep -v "Completed" @args'))
+                Set-Item -Path Function:global:hist -Value ([scriptblock]::Create('git log --format=''%h %s'' | sed ''s/\t/ /'' @args'))
+                Set-Item -Path Function:global:tailslash -Value ([scriptblock]::Create('echo C:\ @args'))"#}
+        );
+    }
+}
@@ -0,0 +1,107 @@
+//! Real files moved aside so a link can take their place, and put back when it goes.
+//!
+//! Backups live in the state directory as `backups/<env>/<timestamp>/<path>`, where
+//! `<path>` is the target relative to the home directory.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{fs::Fs, state::Backup};
+
+const BACKUPS_DIR_NAME: &str = "backups";
+
+pub(crate) fn backups_dir(state_dir: &Path) -> PathBuf {
+    state_dir.join(BACKUPS_DIR_NAME)
+}
+
+/// Where a backup of `target` taken at `timestamp` for `env_key` goes, not taking the
+/// path of an existing backup
+pub(crate) fn backup_path(
+    state_dir: &Path,
+    env_key: &str,
+    timestamp: u64,
+    home: &Path,
+    target: &Path,
+) -> PathBuf {
+    // Targets outside home keep their absolute path, minus the root
+    let relative: PathBuf = match target.strip_prefix(home) {
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => target
+            .components()
+            .filter(|c| matches!(c, std::path::Component::Normal(_)))
+            .collect(),
+    };
+    let env_dir = backups_dir(state_dir).join(env_key);
+    let mut path = env_dir.join(timestamp.to_string()).join(&relative);
+    let mut n = 1;
+    while path.symlink_metadata().is_ok() {
+        path = env_dir.join(format!("{timestamp}-{n}")).join(&relative);
+        n += 1;
+    }
+    path
+}
+
+/// Move the file at `from` to `to`, creating the parents of `to`. Between filesystems
+/// the content is copied and the original removed.
+pub(crate) fn move_file(fs: &dyn Fs, from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs.create_dir_all(parent)?;
+    }
+    match fs.rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            let content = fs
+                .read(from)?
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            fs.write_atomic(to, &content)?;
+            fs.remove_file(from)
+        }
+        result => result,
+    }
+}
+
+/// Index of the latest backup matching `path`, its original location or the backup itself
+pub(crate) fn latest_backup(backups: &[Backup], path: &Path) -> Option<usize> {
+    backups
+        .iter()
+        .rposition(|backup| backup.target == path || backup.path == path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backup_paths_of_nested_and_repeated_targets() {
+        let dir = std::env::temp_dir().join("envmgr_test_backup_paths");
+        let _ = std::fs::remove_dir_all(&dir);
+        let (state_dir, home) = (dir.join("state"), dir.join("home"));
+        let target = home.join(".config/app/settings.toml");
+
+        let first = backup_path(&state_dir, "work", 1700000000, &home, &target);
+        assert_eq!(
+            first,
+            state_dir.join("backups/work/1700000000/.config/app/settings.toml")
+        );
+        std::fs::create_dir_all(first.parent().unwrap()).unwrap();
+        std::fs::write(&first, "").unwrap();
+        // A second backup within the same second gets its own directory
+        assert_eq!(
+            backup_path(&state_dir, "work", 1700000000, &home, &target),
+            state_dir.join("backups/work/1700000000-1/.config/app/settings.toml")
+        );
+        assert_eq!(
+            backup_path(
+                &state_dir,
+                "work",
+                1700000000,
+                &home,
+                Path::new("/etc/app.conf")
+            ),
+            state_dir.join("backups/work/1700000000/etc/app.conf")
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
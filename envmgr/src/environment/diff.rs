@@ -0,0 +1,655 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::path::Path;
+
+use crate::{
+    config::{BASE_ENV_NAME, ENV_CONFIG_FILE_NAME, EnvironmentConfig, FILES_DIR_NAME},
+    environment::{Environment, SECRET_PLACEHOLDER, discover_files_in_dir, hash_file, layer_files},
+    error::EnvMgrResult,
+    integrations::{
+        aws::AwsConfig, cargo::CargoConfig, gcloud::GcloudConfig, git::GitConfig, gpg::GpgConfig,
+        kube::KubeConfig, maven::MavenConfig, mise::MiseConfig, npm::NpmConfig,
+        python::PythonConfig, ssh::SshConfig, tailscale::TailscaleConfig,
+    },
+};
+
+/// A value that is present on both sides of a diff but differs
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct ValueChange<T> {
+    pub a: T,
+    pub b: T,
+}
+
+/// Difference between two key/value maps
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct MapDiff {
+    pub only_in_a: BTreeMap<String, String>,
+    pub only_in_b: BTreeMap<String, String>,
+    pub changed: BTreeMap<String, ValueChange<String>>,
+}
+
+impl MapDiff {
+    pub fn compute(a: &BTreeMap<String, String>, b: &BTreeMap<String, String>) -> Self {
+        let mut diff = Self::default();
+        for (key, value_a) in a {
+            match b.get(key) {
+                None => {
+                    diff.only_in_a.insert(key.clone(), value_a.clone());
+                }
+                Some(value_b) if value_b != value_a => {
+                    diff.changed.insert(
+                        key.clone(),
+                        ValueChange {
+                            a: value_a.clone(),
+                            b: value_b.clone(),
+                        },
+                    );
+                }
+                Some(_) => {}
+            }
+        }
+        for (key, value_b) in b {
+            if !a.contains_key(key) {
+                diff.only_in_b.insert(key.clone(), value_b.clone());
+            }
+        }
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty() && self.changed.is_empty()
+    }
+
+    /// Replace the values of `keys` by `********`, keeping whether they differ
+    fn mask(&mut self, keys: &BTreeSet<String>) {
+        let masked = || SECRET_PLACEHOLDER.to_string();
+        for (key, value) in self.only_in_a.iter_mut().chain(self.only_in_b.iter_mut()) {
+            if keys.contains(key) {
+                *value = masked();
+            }
+        }
+        for (key, change) in &mut self.changed {
+            if keys.contains(key) {
+                *change = ValueChange {
+                    a: masked(),
+                    b: masked(),
+                };
+            }
+        }
+    }
+}
+
+/// Difference between two sets of values
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct SetDiff {
+    pub only_in_a: Vec<String>,
+    pub only_in_b: Vec<String>,
+}
+
+impl SetDiff {
+    pub fn compute(a: &BTreeSet<String>, b: &BTreeSet<String>) -> Self {
+        Self {
+            only_in_a: a.difference(b).cloned().collect(),
+            only_in_b: b.difference(a).cloned().collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty()
+    }
+}
+
+/// Difference between the integration blocks of two environments
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct IntegrationsDiff {
+    /// Active gh user per host
+    pub gh_cli: MapDiff,
+    /// 1Password SSH agent keys, rendered as `vault/item@account`
+    pub op_ssh_keys: SetDiff,
+    pub tailnet: Option<ValueChange<Option<String>>>,
+    /// Tailscale exit node, accept routes and shields up
+    pub tailscale_settings: Option<ValueChange<Option<String>>>,
+    /// Git identity, rendered as `Name <email>`
+    pub git_identity: Option<ValueChange<Option<String>>>,
+    /// Kubernetes context, namespace and kubeconfig
+    pub kube: Option<ValueChange<Option<String>>>,
+    /// AWS profile, region and config file
+    pub aws: Option<ValueChange<Option<String>>>,
+    /// gcloud configuration, project and account
+    pub gcloud: Option<ValueChange<Option<String>>>,
+    /// npm registries, or the linked npmrc; tokens are only counted
+    pub npm: Option<ValueChange<Option<String>>>,
+    /// ssh config file and `Host` patterns
+    pub ssh: Option<ValueChange<Option<String>>>,
+    /// gpg default key
+    pub gpg: Option<ValueChange<Option<String>>>,
+    /// Tool versions of the `mise` integration
+    pub mise: Option<ValueChange<Option<String>>>,
+    /// Settings of the `maven` integration
+    pub maven: Option<ValueChange<Option<String>>>,
+    /// Indexes of the `python` integration
+    pub python: Option<ValueChange<Option<String>>>,
+    /// Registries of the `cargo` integration
+    pub cargo: Option<ValueChange<Option<String>>>,
+}
+
+impl IntegrationsDiff {
+    fn between(env_a: &Environment, env_b: &Environment) -> Self {
+        let tailnet_a = env_a.tailscale.as_ref().map(|t| t.tailnet.clone());
+        let tailnet_b = env_b.tailscale.as_ref().map(|t| t.tailnet.clone());
+        let tailscale_settings_a = env_a.tailscale.as_ref().and_then(TailscaleConfig::settings);
+        let tailscale_settings_b = env_b.tailscale.as_ref().and_then(TailscaleConfig::settings);
+        let identity_a = env_a.git.as_ref().and_then(GitConfig::identity);
+        let identity_b = env_b.git.as_ref().and_then(GitConfig::identity);
+        let kube_a = env_a.kube.as_ref().map(KubeConfig::to_string);
+        let kube_b = env_b.kube.as_ref().map(KubeConfig::to_string);
+        let aws_a = env_a.aws.as_ref().map(AwsConfig::to_string);
+        let aws_b = env_b.aws.as_ref().map(AwsConfig::to_string);
+        let gcloud_a = env_a.gcloud.as_ref().map(GcloudConfig::to_string);
+        let gcloud_b = env_b.gcloud.as_ref().map(GcloudConfig::to_string);
+        let npm_a = env_a.npm.as_ref().map(NpmConfig::to_string);
+        let ssh_a = env_a.ssh.as_ref().map(SshConfig::to_string);
+        let gpg_a = env_a.gpg.as_ref().map(GpgConfig::to_string);
+        let mise_a = env_a.mise.as_ref().map(MiseConfig::to_string);
+        let maven_a = env_a.maven.as_ref().map(MavenConfig::to_string);
+        let python_a = env_a.python.as_ref().map(PythonConfig::to_string);
+        let cargo_a = env_a.cargo.as_ref().map(CargoConfig::to_string);
+        let cargo_b = env_b.cargo.as_ref().map(CargoConfig::to_string);
+        let python_b = env_b.python.as_ref().map(PythonConfig::to_string);
+        let maven_b = env_b.maven.as_ref().map(MavenConfig::to_string);
+        let mise_b = env_b.mise.as_ref().map(MiseConfig::to_string);
+        let gpg_b = env_b.gpg.as_ref().map(GpgConfig::to_string);
+        let ssh_b = env_b.ssh.as_ref().map(SshConfig::to_string);
+        let npm_b = env_b.npm.as_ref().map(NpmConfig::to_string);
+        Self {
+            gh_cli: MapDiff::compute(&gh_cli_users(env_a), &gh_cli_users(env_b)),
+            op_ssh_keys: SetDiff::compute(&op_ssh_keys(env_a), &op_ssh_keys(env_b)),
+            tailnet: (tailnet_a != tailnet_b).then_some(ValueChange {
+                a: tailnet_a,
+                b: tailnet_b,
+            }),
+            tailscale_settings: (tailscale_settings_a != tailscale_settings_b).then_some(
+                ValueChange {
+                    a: tailscale_settings_a,
+                    b: tailscale_settings_b,
+                },
+            ),
+            git_identity: (identity_a != identity_b).then_some(ValueChange {
+                a: identity_a,
+                b: identity_b,
+            }),
+            kube: (kube_a != kube_b).then_some(ValueChange {
+                a: kube_a,
+                b: kube_b,
+            }),
+            aws: (aws_a != aws_b).then_some(ValueChange { a: aws_a, b: aws_b }),
+            gcloud: (gcloud_a != gcloud_b).then_some(ValueChange {
+                a: gcloud_a,
+                b: gcloud_b,
+            }),
+            npm: (npm_a != npm_b).then_some(ValueChange { a: npm_a, b: npm_b }),
+            ssh: (ssh_a != ssh_b).then_some(ValueChange { a: ssh_a, b: ssh_b }),
+            gpg: (gpg_a != gpg_b).then_some(ValueChange { a: gpg_a, b: gpg_b }),
+            mise: (mise_a != mise_b).then_some(ValueChange {
+                a: mise_a,
+                b: mise_b,
+            }),
+            maven: (maven_a != maven_b).then_some(ValueChange {
+                a: maven_a,
+                b: maven_b,
+            }),
+            python: (python_a != python_b).then_some(ValueChange {
+                a: python_a,
+                b: python_b,
+            }),
+            cargo: (cargo_a != cargo_b).then_some(ValueChange {
+                a: cargo_a,
+                b: cargo_b,
+            }),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.gh_cli.is_empty()
+            && self.op_ssh_keys.is_empty()
+            && self.tailnet.is_none()
+            && self.tailscale_settings.is_none()
+            && self.git_identity.is_none()
+            && self.kube.is_none()
+            && self.aws.is_none()
+            && self.gcloud.is_none()
+            && self.npm.is_none()
+            && self.ssh.is_none()
+            && self.gpg.is_none()
+            && self.mise.is_none()
+            && self.maven.is_none()
+            && self.python.is_none()
+            && self.cargo.is_none()
+    }
+}
+
+/// Everything that changes when switching from environment A to environment B
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct EnvironmentDiff {
+    pub env_a: String,
+    pub env_b: String,
+    pub env_vars: MapDiff,
+    /// Link targets mapped to the SHA-256 of their source content
+    pub files: MapDiff,
+    pub integrations: IntegrationsDiff,
+}
+
+impl EnvironmentDiff {
+    /// Compare the effective (base + environment) configuration of two environments
+    pub fn between_keys(key_a: &str, key_b: &str) -> EnvMgrResult<Self> {
+        let base = Environment::load_base_environment()?;
+        let env_a = Environment::load(key_a)?;
+        let env_b = Environment::load(key_b)?;
+        Self::between(&base, &env_a, &env_b)
+    }
+
+    fn between(base: &Environment, env_a: &Environment, env_b: &Environment) -> EnvMgrResult<Self> {
+        let mut env_vars = MapDiff::compute(
+            &effective_env_vars(base, env_a),
+            &effective_env_vars(base, env_b),
+        );
+        env_vars.mask(&secret_keys(&[base, env_a, env_b]));
+        Ok(Self {
+            env_a: env_a.key.clone(),
+            env_b: env_b.key.clone(),
+            env_vars,
+            files: MapDiff::compute(
+                &effective_file_hashes(base, env_a)?,
+                &effective_file_hashes(base, env_b)?,
+            ),
+            integrations: IntegrationsDiff::between(env_a, env_b),
+        })
+    }
+
+    /// Compare two environment directories on their own, without layering base.
+    ///
+    /// Files are keyed by their path relative to `files/`.
+    pub(crate) fn between_dirs(
+        (key_a, dir_a): (&str, &Path),
+        (key_b, dir_b): (&str, &Path),
+    ) -> EnvMgrResult<Self> {
+        let env_a = Environment::load_from_config(
+            key_a,
+            &EnvironmentConfig::load_from_file(dir_a)?,
+            &dir_a.join(ENV_CONFIG_FILE_NAME),
+        )?;
+        let env_b = Environment::load_from_config(
+            key_b,
+            &EnvironmentConfig::load_from_file(dir_b)?,
+            &dir_b.join(ENV_CONFIG_FILE_NAME),
+        )?;
+        let mut env_vars = MapDiff::compute(&own_env_vars(&env_a), &own_env_vars(&env_b));
+        env_vars.mask(&secret_keys(&[&env_a, &env_b]));
+        Ok(Self {
+            env_a: key_a.to_string(),
+            env_b: key_b.to_string(),
+            env_vars,
+            files: MapDiff::compute(
+                &relative_file_hashes(&dir_a.join(FILES_DIR_NAME))?,
+                &relative_file_hashes(&dir_b.join(FILES_DIR_NAME))?,
+            ),
+            integrations: IntegrationsDiff::between(&env_a, &env_b),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.env_vars.is_empty() && self.files.is_empty() && self.integrations.is_empty()
+    }
+
+    /// Render the diff for terminal output
+    ///
+    /// Lines prefixed with `-` exist only in A, `+` only in B and `~` in both with different values.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "Comparing {} -> {}", self.env_a, self.env_b);
+        if self.is_empty() {
+            let _ = writeln!(out, "No differences");
+            return out;
+        }
+
+        if !self.env_vars.is_empty() {
+            let _ = writeln!(out, "\nEnvironment variables:");
+            render_map_diff(&mut out, &self.env_vars, |key, value| {
+                format!("{key}={value}")
+            });
+        }
+
+        if !self.files.is_empty() {
+            let _ = writeln!(out, "\nFiles:");
+            for target in self.files.only_in_a.keys() {
+                let _ = writeln!(out, "  - {target}");
+            }
+            for target in self.files.only_in_b.keys() {
+                let _ = writeln!(out, "  + {target}");
+            }
+            for target in self.files.changed.keys() {
+                let _ = writeln!(out, "  ~ {target} (content differs)");
+            }
+        }
+
+        if !self.integrations.is_empty() {
+            let _ = writeln!(out, "\nIntegrations:");
+            if !self.integrations.gh_cli.is_empty() {
+                let _ = writeln!(out, "  gh_cli:");
+                render_map_diff(&mut out, &self.integrations.gh_cli, |host, user| {
+                    format!("  {host}: {user}")
+                });
+            }
+            if !self.integrations.op_ssh_keys.is_empty() {
+                let _ = writeln!(out, "  op_ssh:");
+                for key in &self.integrations.op_ssh_keys.only_in_a {
+                    let _ = writeln!(out, "    - {key}");
+                }
+                for key in &self.integrations.op_ssh_keys.only_in_b {
+                    let _ = writeln!(out, "    + {key}");
+                }
+            }
+            if let Some(ValueChange { a, b }) = &self.integrations.tailnet {
+                let _ = writeln!(
+                    out,
+                    "  tailscale: {} -> {}",
+                    a.as_deref().unwrap_or("(none)"),
+                    b.as_deref().unwrap_or("(none)")
+                );
+            }
+            if let Some(ValueChange { a, b }) = &self.integrations.tailscale_settings {
+                let _ = writeln!(
+                    out,
+                    "  tailscale settings: {} -> {}",
+                    a.as_deref().unwrap_or("(none)"),
+                    b.as_deref().unwrap_or("(none)")
+                );
+            }
+            if let Some(ValueChange { a, b }) = &self.integrations.git_identity {
+                let _ = writeln!(
+                    out,
+                    "  git: {} -> {}",
+                    a.as_deref().unwrap_or("(none)"),
+                    b.as_deref().unwrap_or("(none)")
+                );
+            }
+            if let Some(ValueChange { a, b }) = &self.integrations.kube {
+                let _ = writeln!(
+                    out,
+                    "  kube: {} -> {}",
+                    a.as_deref().unwrap_or("(none)"),
+                    b.as_deref().unwrap_or("(none)")
+                );
+            }
+            if let Some(ValueChange { a, b }) = &self.integrations.aws {
+                let _ = writeln!(
+                    out,
+                    "  aws: {} -> {}",
+                    a.as_deref().unwrap_or("(none)"),
+                    b.as_deref().unwrap_or("(none)")
+                );
+            }
+            if let Some(ValueChange { a, b }) = &self.integrations.gcloud {
+                let _ = writeln!(
+                    out,
+                    "  gcloud: {} -> {}",
+                    a.as_deref().unwrap_or("(none)"),
+                    b.as_deref().unwrap_or("(none)")
+                );
+            }
+            if let Some(ValueChange { a, b }) = &self.integrations.npm {
+                let _ = writeln!(
+                    out,
+                    "  npm: {} -> {}",
+                    a.as_deref().unwrap_or("(none)"),
+                    b.as_deref().unwrap_or("(none)")
+                );
+            }
+            if let Some(ValueChange { a, b }) = &self.integrations.ssh {
+                let _ = writeln!(
+                    out,
+                    "  ssh: {} -> {}",
+                    a.as_deref().unwrap_or("(none)"),
+                    b.as_deref().unwrap_or("(none)")
+                );
+            }
+            if let Some(ValueChange { a, b }) = &self.integrations.gpg {
+                let _ = writeln!(
+                    out,
+                    "  gpg: {} -> {}",
+                    a.as_deref().unwrap_or("(none)"),
+                    b.as_deref().unwrap_or("(none)")
+                );
+            }
+            if let Some(ValueChange { a, b }) = &self.integrations.mise {
+                let _ = writeln!(
+                    out,
+                    "  mise: {} -> {}",
+                    a.as_deref().unwrap_or("(none)"),
+                    b.as_deref().unwrap_or("(none)")
+                );
+            }
+            if let Some(ValueChange { a, b }) = &self.integrations.maven {
+                let _ = writeln!(
+                    out,
+                    "  maven: {} -> {}",
+                    a.as_deref().unwrap_or("(none)"),
+                    b.as_deref().unwrap_or("(none)")
+                );
+            }
+            if let Some(ValueChange { a, b }) = &self.integrations.python {
+                let _ = writeln!(
+                    out,
+                    "  python: {} -> {}",
+                    a.as_deref().unwrap_or("(none)"),
+                    b.as_deref().unwrap_or("(none)")
+                );
+            }
+            if let Some(ValueChange { a, b }) = &self.integrations.cargo {
+                let _ = writeln!(
+                    out,
+                    "  cargo: {} -> {}",
+                    a.as_deref().unwrap_or("(none)"),
+                    b.as_deref().unwrap_or("(none)")
+                );
+            }
+        }
+        out
+    }
+}
+
+fn render_map_diff(out: &mut String, diff: &MapDiff, entry: impl Fn(&str, &str) -> String) {
+    for (key, value) in &diff.only_in_a {
+        let _ = writeln!(out, "  - {}", entry(key, value));
+    }
+    for (key, value) in &diff.only_in_b {
+        let _ = writeln!(out, "  + {}", entry(key, value));
+    }
+    for (key, ValueChange { a, b }) in &diff.changed {
+        let _ = writeln!(out, "  ~ {} -> {b}", entry(key, a));
+    }
+}
+
+fn effective_env_vars(base: &Environment, env: &Environment) -> BTreeMap<String, String> {
+    let mut vars = BTreeMap::new();
+    let layers = if env.key == BASE_ENV_NAME || !env.inherit_base {
+        vec![env]
+    } else {
+        vec![base, env]
+    };
+    for layer in layers {
+        for var in &layer.env_vars {
+            vars.insert(var.key.clone(), var.describe_value());
+        }
+    }
+    vars
+}
+
+/// Keys marked secret in any of `envs`, masked on both sides of a diff
+fn secret_keys(envs: &[&Environment]) -> BTreeSet<String> {
+    envs.iter()
+        .flat_map(|env| &env.env_vars)
+        .filter(|var| var.secret)
+        .map(|var| var.key.clone())
+        .collect()
+}
+
+fn own_env_vars(env: &Environment) -> BTreeMap<String, String> {
+    env.env_vars
+        .iter()
+        .map(|var| (var.key.clone(), var.describe_value()))
+        .collect()
+}
+
+fn relative_file_hashes(files_dir: &Path) -> EnvMgrResult<BTreeMap<String, String>> {
+    let mut hashes = BTreeMap::new();
+    for file in discover_files_in_dir(files_dir)? {
+        let relative = file.strip_prefix(files_dir).unwrap_or(&file);
+        hashes.insert(relative.display().to_string(), hash_file(&file)?);
+    }
+    Ok(hashes)
+}
+
+fn effective_file_hashes(
+    base: &Environment,
+    env: &Environment,
+) -> EnvMgrResult<BTreeMap<String, String>> {
+    let files_map = layer_files(base, (env.key != BASE_ENV_NAME).then_some(env))?;
+    let mut hashes = BTreeMap::new();
+    for (target, source) in files_map {
+        if source.is_dir() {
+            for (relative, hash) in relative_file_hashes(&source)? {
+                hashes.insert(target.join(relative).display().to_string(), hash);
+            }
+        } else {
+            hashes.insert(target.display().to_string(), hash_file(&source)?);
+        }
+    }
+    Ok(hashes)
+}
+
+/// The user per host, followed by the `config.yml` keys as `config.yml:<key>`
+fn gh_cli_users(env: &Environment) -> BTreeMap<String, String> {
+    let Some(gh) = &env.gh_cli else {
+        return BTreeMap::new();
+    };
+    let users = gh.hosts.iter().map(|h| (h.host.clone(), h.to_string()));
+    let settings = gh
+        .gh_config
+        .iter()
+        .map(|(key, value)| (format!("config.yml:{key}"), value.clone()));
+    users.chain(settings).collect()
+}
+
+fn op_ssh_keys(env: &Environment) -> BTreeSet<String> {
+    env.one_password_ssh
+        .iter()
+        .flat_map(|op| &op.keys)
+        .map(|key| {
+            let mut rendered = format!(
+                "{}/{}",
+                key.vault.as_deref().unwrap_or("*"),
+                key.item.as_deref().unwrap_or("*")
+            );
+            if let Some(account) = &key.account {
+                rendered.push('@');
+                rendered.push_str(account);
+            }
+            rendered
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+        entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_map_diff_compute() {
+        let a = map(&[("ONLY_A", "1"), ("SAME", "x"), ("CHANGED", "old")]);
+        let b = map(&[("ONLY_B", "2"), ("SAME", "x"), ("CHANGED", "new")]);
+
+        let diff = MapDiff::compute(&a, &b);
+
+        assert_eq!(diff.only_in_a, map(&[("ONLY_A", "1")]));
+        assert_eq!(diff.only_in_b, map(&[("ONLY_B", "2")]));
+        assert_eq!(
+            diff.changed.get("CHANGED"),
+            Some(&ValueChange {
+                a: "old".to_string(),
+                b: "new".to_string()
+            })
+        );
+        assert!(!diff.changed.contains_key("SAME"));
+    }
+
+    #[test]
+    fn test_mask_keeps_changes_but_hides_values() {
+        let a = map(&[("TOKEN", "old-secret"), ("GONE", "s1"), ("PLAIN", "a")]);
+        let b = map(&[("TOKEN", "new-secret"), ("PLAIN", "b")]);
+        let mut diff = MapDiff::compute(&a, &b);
+        diff.mask(&["TOKEN".to_string(), "GONE".to_string()].into());
+
+        assert_eq!(diff.only_in_a, map(&[("GONE", SECRET_PLACEHOLDER)]));
+        assert_eq!(diff.changed["TOKEN"].a, SECRET_PLACEHOLDER);
+        assert_eq!(diff.changed["TOKEN"].b, SECRET_PLACEHOLDER);
+        assert_eq!(diff.changed["PLAIN"].b, "b");
+        let json = serde_json::to_string(&diff).unwrap();
+        assert!(!json.contains("secret") && !json.contains("s1"), "{json}");
+    }
+
+    #[test]
+    fn test_set_diff_compute() {
+        let a: BTreeSet<String> = ["k1".to_string(), "k2".to_string()].into();
+        let b: BTreeSet<String> = ["k2".to_string(), "k3".to_string()].into();
+
+        let diff = SetDiff::compute(&a, &b);
+
+        assert_eq!(diff.only_in_a, vec!["k1".to_string()]);
+        assert_eq!(diff.only_in_b, vec!["k3".to_string()]);
+    }
+
+    #[test]
+    fn test_render_empty_diff() {
+        let diff = EnvironmentDiff {
+            env_a: "work".to_string(),
+            env_b: "personal".to_string(),
+            env_vars: MapDiff::default(),
+            files: MapDiff::default(),
+            integrations: IntegrationsDiff::default(),
+        };
+        assert!(diff.render().contains("No differences"));
+    }
+
+    #[test]
+    fn test_render_env_var_changes() {
+        let diff = EnvironmentDiff {
+            env_a: "work".to_string(),
+            env_b: "personal".to_string(),
+            env_vars: MapDiff::compute(&map(&[("FOO", "1")]), &map(&[("FOO", "2")])),
+            files: MapDiff::default(),
+            integrations: IntegrationsDiff {
+                tailnet: Some(ValueChange {
+                    a: Some("corp.ts.net".to_string()),
+                    b: None,
+                }),
+                git_identity: Some(ValueChange {
+                    a: Some("Alice <alice@corp.example>".to_string()),
+                    b: Some("Alice <alice@home.example>".to_string()),
+                }),
+                ..Default::default()
+            },
+        };
+        let rendered = diff.render();
+        assert!(rendered.contains("~ FOO=1 -> 2"));
+        assert!(rendered.contains("tailscale: corp.ts.net -> (none)"));
+        assert!(rendered.contains("git: Alice <alice@corp.example> -> Alice <alice@home.example>"));
+    }
+}
@@ -0,0 +1,339 @@
+//! Env var values that are only known at `use` time.
+//!
+//! A failing source doesn't stop `use`: the other variables are still emitted and the
+//! variable keeps the value the shell already has, so one flaky command can't wipe
+//! the rest of the environment.
+
+use std::{
+    collections::HashMap,
+    io::Read,
+    path::PathBuf,
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use super::secrets::{OpCli, SECRET_PLACEHOLDER};
+use crate::{
+    config::{DynamicValue, EnvVarsConfig},
+    runner::SystemRunner,
+};
+
+/// How `use` treats variables with a dynamic value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DynamicOptions {
+    /// Don't run anything, dynamic variables keep the value they already have
+    pub skip: bool,
+    /// How long a `value_from_command` may run before it is killed
+    pub timeout: Duration,
+    /// Resolve `op://` references, otherwise they are set to [`SECRET_PLACEHOLDER`]
+    pub secrets: bool,
+}
+
+/// Replace the dynamic values in `vars` with what their source yields.
+///
+/// Variables that are skipped or whose source fails keep their `previous` value, or
+/// are left out when there is none. Failures come back as one message per variable.
+/// All secret references are resolved with a single `op` call where possible.
+pub fn resolve_dynamic_values(
+    vars: Vec<EnvVarsConfig>,
+    previous: &HashMap<String, String>,
+    opts: &DynamicOptions,
+) -> (Vec<EnvVarsConfig>, Vec<String>) {
+    let references: Vec<&str> = vars
+        .iter()
+        .filter_map(|var| match &var.dynamic {
+            Some(DynamicValue::Secret(reference)) => Some(reference.as_str()),
+            _ => None,
+        })
+        .collect();
+    let secrets = if opts.secrets && !opts.skip && !references.is_empty() {
+        OpCli::new(&SystemRunner).read_all(&references)
+    } else {
+        HashMap::new()
+    };
+    resolve_with(vars, previous, opts.skip, |dynamic| match dynamic {
+        DynamicValue::Command(command) => run_value_command(command, opts.timeout),
+        DynamicValue::File(path) => read_value_file(path),
+        DynamicValue::Secret(_) if !opts.secrets => Ok(SECRET_PLACEHOLDER.to_string()),
+        DynamicValue::Secret(reference) => secrets
+            .get(reference)
+            .cloned()
+            .unwrap_or_else(|| Err(format!("{reference} was not resolved"))),
+    })
+}
+
+fn resolve_with(
+    vars: Vec<EnvVarsConfig>,
+    previous: &HashMap<String, String>,
+    skip: bool,
+    resolve: impl Fn(&DynamicValue) -> Result<String, String>,
+) -> (Vec<EnvVarsConfig>, Vec<String>) {
+    let mut resolved = Vec::with_capacity(vars.len());
+    let mut errors = vec![];
+    for var in vars {
+        let Some(dynamic) = &var.dynamic else {
+            resolved.push(var);
+            continue;
+        };
+        let value = if skip {
+            debug!("Skipping dynamic value of {}", var.key);
+            None
+        } else {
+            match resolve(dynamic) {
+                Ok(value) => Some(value),
+                Err(message) => {
+                    errors.push(format!("{}: {message}", var.key));
+                    None
+                }
+            }
+        };
+        // A secret's value was never recorded, so there is nothing to fall back to
+        let previous = previous
+            .get(&var.key)
+            .filter(|value| *value != SECRET_PLACEHOLDER);
+        if let Some(value) = value.or_else(|| previous.cloned()) {
+            resolved.push(EnvVarsConfig {
+                value,
+                dynamic: None,
+                ..var
+            });
+        }
+    }
+    (resolved, errors)
+}
+
+/// Run `command` through the shell, returning its trimmed stdout.
+///
+/// The command is killed once `timeout` has passed. Errors name the command and,
+/// when it ran to completion, its exit code and stderr.
+pub fn run_value_command(command: &str, timeout: Duration) -> Result<String, String> {
+    let mut child = shell_command(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("`{command}` could not be started: {e}"))?;
+
+    // Drained on threads so a chatty command can't block on a full pipe
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() >= deadline => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!(
+                    "`{command}` timed out after {}s",
+                    timeout.as_secs_f32()
+                ));
+            }
+            Ok(None) => std::thread::sleep(Duration::from_millis(10)),
+            Err(e) => return Err(format!("`{command}` could not be waited on: {e}")),
+        }
+    };
+
+    let stdout = stdout.join().unwrap_or_default();
+    if !status.success() {
+        let stderr = String::from_utf8_lossy(&stderr.join().unwrap_or_default()).into_owned();
+        let code = status
+            .code()
+            .map_or("a signal".to_string(), |code| format!("status {code}"));
+        return Err(match stderr.trim() {
+            "" => format!("`{command}` exited with {code}"),
+            stderr => format!("`{command}` exited with {code}: {stderr}"),
+        });
+    }
+    let stdout =
+        String::from_utf8(stdout).map_err(|_| format!("`{command}` printed non-UTF-8 output"))?;
+    Ok(stdout.trim().to_string())
+}
+
+/// Read the value stored at `path`, expanding a leading `~` to the home directory.
+///
+/// Only the trailing newline is removed, other whitespace is part of the value.
+pub fn read_value_file(path: &str) -> Result<String, String> {
+    let expanded = expand_tilde(path);
+    let content = match std::fs::read(&expanded) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(format!("{} does not exist", expanded.display()));
+        }
+        Err(e) => return Err(format!("{} could not be read: {e}", expanded.display())),
+    };
+    if content.contains(&0) {
+        return Err(format!(
+            "{} looks like a binary file, values must be text",
+            expanded.display()
+        ));
+    }
+    let content = String::from_utf8(content)
+        .map_err(|_| format!("{} is not valid UTF-8 text", expanded.display()))?;
+    let value = content.strip_suffix('\n').unwrap_or(&content);
+    Ok(value.strip_suffix('\r').unwrap_or(value).to_string())
+}
+
+fn expand_tilde(path: &str) -> PathBuf {
+    let rest = path.strip_prefix("~/").or((path == "~").then_some(""));
+    match (rest, dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+fn drain(pipe: Option<impl Read + Send + 'static>) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut buf = vec![];
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+#[cfg(unix)]
+pub(crate) fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(not(unix))]
+pub(crate) fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var(key: &str, dynamic: Option<&str>) -> EnvVarsConfig {
+        EnvVarsConfig {
+            key: key.to_string(),
+            value: if dynamic.is_none() { "literal" } else { "" }.to_string(),
+            dynamic: dynamic.map(|c| DynamicValue::Command(c.to_string())),
+            order: 0,
+            secret: false,
+            when: None,
+            integration: None,
+        }
+    }
+
+    fn values(vars: &[EnvVarsConfig]) -> Vec<(&str, &str)> {
+        vars.iter()
+            .map(|v| (v.key.as_str(), v.value.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_failures_and_skips_keep_previous_values() {
+        let vars = vec![
+            var("PLAIN", None),
+            var("TOKEN", Some("ok")),
+            var("BROKEN", Some("fail")),
+            var("NEW_BROKEN", Some("fail")),
+        ];
+        let previous = HashMap::from([
+            ("TOKEN".to_string(), "old-token".to_string()),
+            ("BROKEN".to_string(), "old-broken".to_string()),
+        ]);
+        let resolve = |dynamic: &DynamicValue| match dynamic {
+            DynamicValue::Command(c) if c == "ok" => Ok("fresh".to_string()),
+            DynamicValue::Command(c) => Err(format!("`{c}` exited with status 1")),
+            DynamicValue::File(_) | DynamicValue::Secret(_) => unreachable!(),
+        };
+
+        let (resolved, errors) = resolve_with(vars.clone(), &previous, false, resolve);
+        assert_eq!(
+            values(&resolved),
+            [
+                ("PLAIN", "literal"),
+                ("TOKEN", "fresh"),
+                ("BROKEN", "old-broken")
+            ]
+        );
+        assert!(resolved.iter().all(|v| v.dynamic.is_none()));
+        assert_eq!(
+            errors,
+            [
+                "BROKEN: `fail` exited with status 1",
+                "NEW_BROKEN: `fail` exited with status 1"
+            ]
+        );
+
+        let (resolved, errors) = resolve_with(vars, &previous, true, |_| unreachable!());
+        assert_eq!(
+            values(&resolved),
+            [
+                ("PLAIN", "literal"),
+                ("TOKEN", "old-token"),
+                ("BROKEN", "old-broken")
+            ]
+        );
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_read_value_file() {
+        let dir = std::env::temp_dir().join("envmgr_test_read_value_file");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        std::fs::write(dir.join("token"), " s3cret \n\n").unwrap();
+        std::fs::write(dir.join("crlf"), "s3cret\r\n").unwrap();
+        std::fs::write(dir.join("binary"), b"\x7fELF\0\0").unwrap();
+        std::fs::write(dir.join("latin1"), b"caf\xe9").unwrap();
+
+        assert_eq!(read_value_file(&path("token")).unwrap(), " s3cret \n");
+        assert_eq!(read_value_file(&path("crlf")).unwrap(), "s3cret");
+        assert!(
+            read_value_file(&path("binary"))
+                .unwrap_err()
+                .ends_with("looks like a binary file, values must be text")
+        );
+        assert!(
+            read_value_file(&path("latin1"))
+                .unwrap_err()
+                .ends_with("is not valid UTF-8 text")
+        );
+        assert_eq!(
+            read_value_file(&path("missing")).unwrap_err(),
+            format!("{} does not exist", path("missing"))
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_expand_tilde() {
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(expand_tilde("~/.token"), home.join(".token"));
+        assert_eq!(expand_tilde("~"), home);
+        assert_eq!(expand_tilde("~other/x"), PathBuf::from("~other/x"));
+        assert_eq!(expand_tilde("/abs/~/x"), PathBuf::from("/abs/~/x"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_value_command() {
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            run_value_command("printf ' token\\n\\n'", timeout).unwrap(),
+            "token"
+        );
+        assert_eq!(
+            run_value_command("echo nope >&2; exit 3", timeout).unwrap_err(),
+            "`echo nope >&2; exit 3` exited with status 3: nope"
+        );
+        assert_eq!(
+            run_value_command("exec sleep 5", Duration::from_millis(100)).unwrap_err(),
+            "`exec sleep 5` timed out after 0.1s"
+        );
+    }
+}
@@ -0,0 +1,206 @@
+//! Hook scripts: executables in an environment's `hooks/` directory run by `switch`.
+//!
+//! `pre-switch` runs before anything changes and aborts the switch when it fails,
+//! `post-link` after the files are linked and `post-switch` last; a failing post hook
+//! is only reported. Hooks get `ENVMGR_ENV`, `ENVMGR_PREV_ENV` and `ENVMGR_CONFIG_DIR`
+//! and run in the environment's directory, their output goes through the logger.
+
+use std::{
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use log::{info, warn};
+
+use super::interpolate::ENV_KEY_VAR;
+use crate::config::CONFIG_DIR_ENV_VAR;
+
+pub(crate) const HOOKS_DIR_NAME: &str = "hooks";
+/// The environment that was current before the switch
+pub const PREV_ENV_KEY_VAR: &str = "ENVMGR_PREV_ENV";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    PreSwitch,
+    PostLink,
+    PostSwitch,
+}
+
+impl Hook {
+    pub fn file_name(self) -> &'static str {
+        match self {
+            Hook::PreSwitch => "pre-switch",
+            Hook::PostLink => "post-link",
+            Hook::PostSwitch => "post-switch",
+        }
+    }
+
+    /// Where the environment in `env_dir` keeps this hook
+    pub fn path(self, env_dir: &Path) -> PathBuf {
+        env_dir.join(HOOKS_DIR_NAME).join(self.file_name())
+    }
+}
+
+impl std::fmt::Display for Hook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.file_name())
+    }
+}
+
+/// What a hook is told about the switch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookEnv<'a> {
+    pub env_key: &'a str,
+    pub prev_env_key: &'a str,
+    pub config_dir: &'a Path,
+}
+
+/// Run `hook` of the environment in `env_dir`, returning whether it has one.
+///
+/// Every line the hook prints is logged as it comes, stdout as info and stderr as a
+/// warning. Errors say why the hook couldn't run or how it exited.
+pub fn run_hook(hook: Hook, env_dir: &Path, env: &HookEnv) -> Result<bool, String> {
+    let path = hook.path(env_dir);
+    if !path.is_file() {
+        return Ok(false);
+    }
+    if !is_executable(&path) {
+        return Err(format!(
+            "{} is not executable, make it so with `chmod +x`",
+            path.display()
+        ));
+    }
+    info!("Running {hook} hook: {}", path.display());
+    let mut child = Command::new(&path)
+        .current_dir(env_dir)
+        .env(ENV_KEY_VAR, env.env_key)
+        .env(PREV_ENV_KEY_VAR, env.prev_env_key)
+        .env(CONFIG_DIR_ENV_VAR, env.config_dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("{} could not be started: {e}", path.display()))?;
+
+    // Streamed on threads so neither pipe can fill up and block the hook
+    let stdout = stream_lines(child.stdout.take(), hook, log::Level::Info);
+    let stderr = stream_lines(child.stderr.take(), hook, log::Level::Warn);
+    let status = child
+        .wait()
+        .map_err(|e| format!("{} could not be waited on: {e}", path.display()));
+    let _ = stdout.join();
+    let _ = stderr.join();
+
+    let status = status?;
+    if !status.success() {
+        let code = status
+            .code()
+            .map_or("a signal".to_string(), |code| format!("status {code}"));
+        return Err(format!("{} exited with {code}", path.display()));
+    }
+    Ok(true)
+}
+
+fn stream_lines(
+    pipe: Option<impl Read + Send + 'static>,
+    hook: Hook,
+    level: log::Level,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let Some(pipe) = pipe else {
+            return;
+        };
+        for line in BufReader::new(pipe).lines() {
+            match line {
+                Ok(line) if level == log::Level::Warn => warn!("[{hook}] {line}"),
+                Ok(line) => info!("[{hook}] {line}"),
+                Err(_) => break,
+            }
+        }
+    })
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .is_ok_and(|metadata| metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(_path: &Path) -> bool {
+    true
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    fn env_dir_with_hook(name: &str, hook: Hook, script: &str, mode: u32) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = hook.path(&dir);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+        dir
+    }
+
+    fn hook_env(config_dir: &Path) -> HookEnv<'_> {
+        HookEnv {
+            env_key: "work",
+            prev_env_key: "base",
+            config_dir,
+        }
+    }
+
+    #[test]
+    fn test_hook_gets_the_switch_in_its_environment() {
+        let dir = env_dir_with_hook(
+            "envmgr_test_hook_env",
+            Hook::PostSwitch,
+            "#!/bin/sh\necho \"$ENVMGR_PREV_ENV -> $ENVMGR_ENV in $ENVMGR_CONFIG_DIR\" > out\n",
+            0o755,
+        );
+
+        assert_eq!(
+            run_hook(Hook::PostSwitch, &dir, &hook_env(Path::new("/cfg"))),
+            Ok(true)
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("out")).unwrap(),
+            "base -> work in /cfg\n"
+        );
+        assert_eq!(
+            run_hook(Hook::PreSwitch, &dir, &hook_env(Path::new("/cfg"))),
+            Ok(false)
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failing_and_non_executable_hooks() {
+        let dir = env_dir_with_hook(
+            "envmgr_test_hook_fails",
+            Hook::PreSwitch,
+            "#!/bin/sh\necho refusing >&2\nexit 3\n",
+            0o755,
+        );
+        let err = run_hook(Hook::PreSwitch, &dir, &hook_env(&dir)).unwrap_err();
+        assert!(err.ends_with("pre-switch exited with status 3"), "{err}");
+
+        std::fs::set_permissions(
+            Hook::PreSwitch.path(&dir),
+            std::fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+        let err = run_hook(Hook::PreSwitch, &dir, &hook_env(&dir)).unwrap_err();
+        assert!(err.contains("is not executable"), "{err}");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
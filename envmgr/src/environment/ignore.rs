@@ -0,0 +1,146 @@
+//! `.envmgrignore`: files in a `files/` directory that are never linked.
+//!
+//! A subset of gitignore syntax, one pattern per line: `#` starts a comment, a trailing
+//! `/` only matches directories, a pattern containing another `/` is matched against the
+//! whole path relative to `files/` and one without against every file and directory
+//! name, and a leading `!` re-includes what an earlier pattern excluded. `*` and `?`
+//! work like in hostname conditions. The last matching pattern decides.
+
+use std::path::Path;
+
+use crate::config::condition::glob_matches;
+
+pub(crate) const IGNORE_FILE_NAME: &str = ".envmgrignore";
+
+/// Applied before the patterns of `.envmgrignore`, which can re-include them with `!`
+const DEFAULT_PATTERNS: [&str; 5] = [".git/", ".DS_Store", "*.swp", "*~", IGNORE_FILE_NAME];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pattern {
+    glob: String,
+    negated: bool,
+    dir_only: bool,
+    /// Matched against the whole relative path rather than the name
+    anchored: bool,
+}
+
+impl Pattern {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let glob = line.trim_start_matches('/').to_string();
+        (!glob.is_empty()).then_some(Self {
+            glob,
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    fn matches(&self, relative: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            glob_matches(&self.glob, relative)
+        } else {
+            let name = relative.rsplit('/').next().unwrap_or(relative);
+            glob_matches(&self.glob, name)
+        }
+    }
+}
+
+/// The ignore patterns of one `files/` directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct IgnoreRules {
+    patterns: Vec<Pattern>,
+}
+
+impl IgnoreRules {
+    /// The defaults followed by the patterns in `content`
+    pub(crate) fn parse(content: &str) -> Self {
+        let patterns = DEFAULT_PATTERNS
+            .into_iter()
+            .chain(content.lines())
+            .filter_map(Pattern::parse)
+            .collect();
+        Self { patterns }
+    }
+
+    /// The rules of the `files/` directory `dir`, the defaults when it has no
+    /// `.envmgrignore`
+    pub(crate) fn load(dir: &Path) -> std::io::Result<Self> {
+        match std::fs::read_to_string(dir.join(IGNORE_FILE_NAME)) {
+            Ok(content) => Ok(Self::parse(&content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::parse("")),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether the entry at `relative`, `/`-separated below `files/`, is ignored.
+    /// Entries inside an ignored directory are never asked about.
+    pub(crate) fn is_ignored(&self, relative: &str, is_dir: bool) -> bool {
+        self.patterns
+            .iter()
+            .rev()
+            .find(|pattern| pattern.matches(relative, is_dir))
+            .is_some_and(|pattern| !pattern.negated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_patterns() {
+        let rules = IgnoreRules::parse("");
+        assert!(rules.is_ignored(".git", true));
+        assert!(rules.is_ignored(".config/nvim/.git", true));
+        assert!(!rules.is_ignored(".git", false));
+        assert!(rules.is_ignored(".config/.DS_Store", false));
+        assert!(rules.is_ignored(".bashrc.swp", false));
+        assert!(rules.is_ignored(".bashrc~", false));
+        assert!(rules.is_ignored(".envmgrignore", false));
+        assert!(!rules.is_ignored(".bashrc", false));
+        assert!(!rules.is_ignored(".gitconfig", false));
+    }
+
+    #[test]
+    fn test_file_patterns() {
+        let rules = IgnoreRules::parse(indoc::indoc! {"
+            # editor leftovers
+            *.bak
+            cache/
+            /README.md
+            .config/app/*.log
+            !keep.bak
+        "});
+        assert!(rules.is_ignored("a/b/c.bak", false));
+        assert!(!rules.is_ignored("a/keep.bak", false));
+        assert!(rules.is_ignored(".config/cache", true));
+        assert!(!rules.is_ignored(".config/cache", false));
+        assert!(rules.is_ignored("README.md", false));
+        assert!(!rules.is_ignored("docs/README.md", false));
+        assert!(rules.is_ignored(".config/app/debug.log", false));
+        assert!(!rules.is_ignored(".config/other/debug.log", false));
+    }
+
+    #[test]
+    fn test_negation_overrides_defaults() {
+        let rules = IgnoreRules::parse("!.vimrc~\n");
+        assert!(!rules.is_ignored(".vimrc~", false));
+        assert!(rules.is_ignored(".zshrc~", false));
+    }
+}
@@ -0,0 +1,105 @@
+//! `${VAR}` expansion in env var values.
+//!
+//! Variables come from the built-ins (`ENVMGR_ENV`, `ENVMGR_CONFIG_DIR`) and then the
+//! process environment. Expanded text is not expanded again, and `$${` is a literal `${`.
+
+/// Built-in variable holding the key of the environment being loaded
+pub const ENV_KEY_VAR: &str = "ENVMGR_ENV";
+
+/// Expand every `${NAME}` in `value` through `lookup`.
+///
+/// Fails with a message naming the problem for unknown variables and unterminated or
+/// empty references, so typos never turn into empty strings.
+pub fn interpolate(value: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        if let Some(escaped) = after.strip_prefix("${") {
+            out.push_str("${");
+            rest = escaped;
+        } else if let Some(reference) = after.strip_prefix('{') {
+            let Some(end) = reference.find('}') else {
+                return Err(format!("unterminated '${{' in '{value}'"));
+            };
+            let name = &reference[..end];
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(format!("invalid variable reference '${{{name}}}'"));
+            }
+            match lookup(name) {
+                Some(expanded) => out.push_str(&expanded),
+                None => return Err(format!("unknown variable '${{{name}}}'")),
+            }
+            rest = &reference[end + 1..];
+        } else {
+            // A lone `$` is kept as is
+            out.push('$');
+            rest = after;
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "HOME" => Some("/home/me".to_string()),
+            ENV_KEY_VAR => Some("client-abc".to_string()),
+            "TRICKY" => Some("${HOME}".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_expands_adjacent_and_embedded_references() {
+        assert_eq!(
+            interpolate("${HOME}/.kube/${ENVMGR_ENV}", lookup).unwrap(),
+            "/home/me/.kube/client-abc"
+        );
+        assert_eq!(
+            interpolate("${ENVMGR_ENV}-profile", lookup).unwrap(),
+            "client-abc-profile"
+        );
+        assert_eq!(
+            interpolate("${HOME}${ENVMGR_ENV}", lookup).unwrap(),
+            "/home/meclient-abc"
+        );
+        assert_eq!(
+            interpolate("plain $5 value", lookup).unwrap(),
+            "plain $5 value"
+        );
+    }
+
+    #[test]
+    fn test_expanded_text_is_not_expanded_again() {
+        assert_eq!(interpolate("x=${TRICKY}", lookup).unwrap(), "x=${HOME}");
+    }
+
+    #[test]
+    fn test_escaped_references() {
+        assert_eq!(interpolate("$${HOME}", lookup).unwrap(), "${HOME}");
+        assert_eq!(
+            interpolate("$${HOME} is ${HOME}", lookup).unwrap(),
+            "${HOME} is /home/me"
+        );
+        assert_eq!(interpolate("$$${HOME}", lookup).unwrap(), "$${HOME}");
+        assert_eq!(interpolate("$${UNKNOWN}", lookup).unwrap(), "${UNKNOWN}");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            interpolate("${HOEM}/x", lookup).unwrap_err(),
+            "unknown variable '${HOEM}'"
+        );
+        assert!(interpolate("${HOME", lookup).is_err());
+        assert!(interpolate("${}", lookup).is_err());
+        // Nested references aren't supported, the inner `${` is not a valid name
+        assert!(interpolate("${${ENVMGR_ENV}}", lookup).is_err());
+    }
+}
@@ -0,0 +1,825 @@
+//! Classification of what is found at a link target path.
+//!
+//! A target may be a chain of symlinks (e.g. `~/.gitconfig -> ~/dotfiles/gitconfig ->
+//! ~/.config/envmgr/base/files/.gitconfig`). Chains are followed hop by hop so the
+//! decision is based on where they end up, while changes only ever touch the first hop.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+};
+
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use rayon::prelude::*;
+
+use crate::state::ManagedFile;
+
+/// Maximum number of symlinks followed before giving up
+pub const MAX_LINK_HOPS: usize = 8;
+
+/// What a single path is, as seen without following it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkNode {
+    /// A symlink with its raw (possibly relative) target
+    Link(PathBuf),
+    /// A regular file, directory or anything else that is not a symlink
+    Other,
+    Missing,
+}
+
+/// Filesystem access needed by [`resolve_chain`], injectable for tests. Shared between
+/// threads, chains of many targets are resolved in parallel.
+pub trait ReadLink: Sync {
+    fn lookup(&self, path: &Path) -> io::Result<LinkNode>;
+}
+
+/// [`ReadLink`] on the real filesystem
+pub struct FsReadLink;
+
+impl ReadLink for FsReadLink {
+    fn lookup(&self, path: &Path) -> io::Result<LinkNode> {
+        // One syscall for the common cases: `read_link` fails with `InvalidInput` on
+        // anything that is not a symlink
+        match std::fs::read_link(path) {
+            Ok(next) => Ok(LinkNode::Link(next)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(LinkNode::Missing),
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => Ok(LinkNode::Other),
+            Err(e) => match std::fs::symlink_metadata(path) {
+                Ok(meta) if !meta.file_type().is_symlink() => Ok(LinkNode::Other),
+                _ => Err(e),
+            },
+        }
+    }
+}
+
+/// Where a target path ends up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainResolution {
+    /// Nothing exists at the target path
+    Absent,
+    /// The target path is a real file or directory
+    NotALink,
+    /// The chain ends at an existing path inside envmgr's config dir
+    Managed { source: PathBuf, hops: usize },
+    /// The chain ends at an existing path outside envmgr's config dir
+    Foreign { source: PathBuf, hops: usize },
+    /// The chain ends at a path that doesn't exist
+    Broken { source: PathBuf, owned: bool },
+    /// The chain loops back on itself
+    Cyclic,
+    /// The chain is longer than [`MAX_LINK_HOPS`]
+    TooDeep,
+    /// A hop could not be read, e.g. for lack of permissions
+    Unreadable { at: PathBuf, kind: io::ErrorKind },
+}
+
+impl ChainResolution {
+    /// Whether envmgr may replace or remove the link at the start of the chain
+    pub fn is_owned(&self) -> bool {
+        matches!(
+            self,
+            ChainResolution::Managed { .. } | ChainResolution::Broken { owned: true, .. }
+        )
+    }
+}
+
+/// Follow the symlink chain starting at `target`, classifying it against `owner_root`
+pub fn resolve_chain(target: &Path, owner_root: &Path, reader: &impl ReadLink) -> ChainResolution {
+    let mut current = target.to_path_buf();
+    let mut seen = HashSet::new();
+    let mut hops = 0;
+    loop {
+        let node = match reader.lookup(&current) {
+            Ok(node) => node,
+            Err(e) => {
+                return ChainResolution::Unreadable {
+                    at: current,
+                    kind: e.kind(),
+                };
+            }
+        };
+        match node {
+            LinkNode::Missing if hops == 0 => return ChainResolution::Absent,
+            LinkNode::Other if hops == 0 => return ChainResolution::NotALink,
+            LinkNode::Missing => {
+                let owned = current.starts_with(owner_root);
+                return ChainResolution::Broken {
+                    source: current,
+                    owned,
+                };
+            }
+            LinkNode::Other if current.starts_with(owner_root) => {
+                return ChainResolution::Managed {
+                    source: current,
+                    hops,
+                };
+            }
+            LinkNode::Other => {
+                return ChainResolution::Foreign {
+                    source: current,
+                    hops,
+                };
+            }
+            LinkNode::Link(next) => {
+                if !seen.insert(current.clone()) {
+                    return ChainResolution::Cyclic;
+                }
+                if hops == MAX_LINK_HOPS {
+                    return ChainResolution::TooDeep;
+                }
+                hops += 1;
+                // Relative link targets are relative to the directory holding the link
+                current = match current.parent() {
+                    Some(parent) if next.is_relative() => parent.join(next),
+                    _ => next,
+                };
+            }
+        }
+    }
+}
+
+/// What linking does at one target path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkAction {
+    /// Nothing is at the target yet
+    Create { target: PathBuf, source: PathBuf },
+    /// An envmgr link to `old`, possibly dangling, is pointed at `source`
+    Update {
+        target: PathBuf,
+        old: PathBuf,
+        source: PathBuf,
+    },
+    /// The link is already correct
+    Keep { target: PathBuf, source: PathBuf },
+    /// A real file is moved to `backup` and linked over
+    Adopt {
+        target: PathBuf,
+        source: PathBuf,
+        backup: PathBuf,
+    },
+    /// Something envmgr must not touch is at the target. `source` is `None` for a stale
+    /// link that would have been removed.
+    Skip {
+        target: PathBuf,
+        source: Option<PathBuf>,
+        resolution: ChainResolution,
+    },
+    /// A managed link that is no longer wanted
+    Remove { target: PathBuf },
+    /// A managed link that is no longer wanted and already gone
+    Forget { target: PathBuf },
+}
+
+impl LinkAction {
+    pub fn target(&self) -> &Path {
+        match self {
+            LinkAction::Create { target, .. }
+            | LinkAction::Update { target, .. }
+            | LinkAction::Keep { target, .. }
+            | LinkAction::Adopt { target, .. }
+            | LinkAction::Skip { target, .. }
+            | LinkAction::Remove { target }
+            | LinkAction::Forget { target } => target,
+        }
+    }
+
+    /// One line for the plan, with the sources of the links when `verbose`. `None` for
+    /// actions that change nothing, unless `verbose`.
+    pub fn describe(&self, verbose: bool) -> Option<String> {
+        let source = |source: &Path| match verbose {
+            true => format!(" -> {}", source.display()),
+            false => String::new(),
+        };
+        let line = match self {
+            LinkAction::Create { target, source: s } => {
+                format!("create {}{}", target.display(), source(s))
+            }
+            LinkAction::Update {
+                target,
+                old,
+                source,
+            } => format!(
+                "update {} ({} -> {})",
+                target.display(),
+                old.display(),
+                source.display()
+            ),
+            LinkAction::Keep { target, source: s } if verbose => {
+                format!("keep {}{}", target.display(), source(s))
+            }
+            LinkAction::Adopt {
+                target,
+                source: s,
+                backup,
+            } => format!(
+                "adopt {}{} (backup {})",
+                target.display(),
+                source(s),
+                backup.display()
+            ),
+            LinkAction::Skip {
+                target,
+                source: s,
+                resolution,
+            } => {
+                let reason = match resolution {
+                    ChainResolution::NotALink => "exists, not a symlink",
+                    ChainResolution::Managed { .. } => "changed since it was linked",
+                    _ => "not managed",
+                };
+                match s {
+                    Some(s) => format!("skip {}{} ({reason})", target.display(), source(s)),
+                    None => format!("skip {} (stale, {reason})", target.display()),
+                }
+            }
+            LinkAction::Remove { target } => format!("remove {} (stale)", target.display()),
+            LinkAction::Forget { target } if verbose => {
+                format!("forget {} (stale, already gone)", target.display())
+            }
+            LinkAction::Keep { .. } | LinkAction::Forget { .. } => return None,
+        };
+        Some(line)
+    }
+}
+
+/// The targets a link run touches, see `link --only` and `--exclude`. Targets are
+/// matched by their path relative to the home directory, `*` doesn't cross a `/` but
+/// `**` does. Everything is touched without any patterns.
+#[derive(Debug, Clone, Default)]
+pub struct TargetFilter {
+    home: PathBuf,
+    only: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+}
+
+impl TargetFilter {
+    pub fn new(home: &Path, only: &[Glob], exclude: &[Glob]) -> Result<Self, globset::Error> {
+        let set = |globs: &[Glob]| -> Result<Option<GlobSet>, globset::Error> {
+            if globs.is_empty() {
+                return Ok(None);
+            }
+            let mut builder = GlobSetBuilder::new();
+            for glob in globs {
+                builder.add(glob.clone());
+            }
+            builder.build().map(Some)
+        };
+        Ok(Self {
+            home: home.to_path_buf(),
+            only: set(only)?,
+            exclude: set(exclude)?,
+        })
+    }
+
+    /// Parse `pattern` the way the filter matches it
+    pub fn glob(pattern: &str) -> Result<Glob, globset::Error> {
+        GlobBuilder::new(pattern).literal_separator(true).build()
+    }
+
+    /// Whether the run links, updates or removes `target`: it matches `only`, if given,
+    /// and then doesn't match `exclude`
+    pub fn touches(&self, target: &Path) -> bool {
+        // Targets outside the home directory are matched by their full path
+        let relative = target.strip_prefix(&self.home).unwrap_or(target);
+        self.only
+            .as_ref()
+            .is_none_or(|only| only.is_match(relative))
+            && !self
+                .exclude
+                .as_ref()
+                .is_some_and(|exclude| exclude.is_match(relative))
+    }
+}
+
+/// Symlinks in `files/` are re-created as they are at their target instead of being
+/// linked to, so a relative one points to the same relative path from there
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Mirrors {
+    /// Target -> the link to create there
+    pub wanted: HashMap<PathBuf, PathBuf>,
+    /// Target -> the link envmgr created there earlier. Such a link is envmgr's as long
+    /// as it is unchanged, wherever it points.
+    pub created: HashMap<PathBuf, PathBuf>,
+}
+
+impl Mirrors {
+    /// The link at `target` if it is still the one envmgr created there
+    pub fn created_link(&self, target: &Path, reader: &impl ReadLink) -> Option<PathBuf> {
+        let created = self.created.get(target)?;
+        match reader.lookup(target) {
+            Ok(LinkNode::Link(current)) if current == *created => Some(current),
+            _ => None,
+        }
+    }
+}
+
+/// Whether the chain of a managed link still ends where it was linked to: at its
+/// recorded source, or for entries without one, anywhere inside the config dir
+fn leads_to_source(managed: &ManagedFile, resolution: &ChainResolution) -> bool {
+    if managed.source.as_os_str().is_empty() {
+        return resolution.is_owned();
+    }
+    match resolution {
+        ChainResolution::Managed { source, .. } | ChainResolution::Broken { source, .. } => {
+            *source == managed.source
+        }
+        _ => false,
+    }
+}
+
+/// The managed links in `managed` that are not in `desired`, as what to do with each.
+///
+/// Only links that still lead to their source, or are unchanged re-created symlinks,
+/// are removed; anything else found at a stale target is skipped.
+pub fn plan_stale_links(
+    managed: &[ManagedFile],
+    desired: &HashMap<PathBuf, PathBuf>,
+    mirrors: &Mirrors,
+    owner_root: &Path,
+    reader: &impl ReadLink,
+) -> Vec<LinkAction> {
+    let mut stale: Vec<&ManagedFile> = managed
+        .iter()
+        .filter(|managed| !desired.contains_key(&managed.target))
+        .collect();
+    stale.sort_by(|a, b| a.target.cmp(&b.target));
+    stale.dedup_by(|a, b| a.target == b.target);
+    stale
+        .into_par_iter()
+        .map(|managed| {
+            let target = managed.target.clone();
+            match resolve_chain(&target, owner_root, reader) {
+                ChainResolution::Absent => LinkAction::Forget { target },
+                _ if mirrors.created_link(&target, reader).is_some() => {
+                    LinkAction::Remove { target }
+                }
+                resolution if leads_to_source(managed, &resolution) => {
+                    LinkAction::Remove { target }
+                }
+                resolution => LinkAction::Skip {
+                    target,
+                    source: None,
+                    resolution,
+                },
+            }
+        })
+        .collect()
+}
+
+/// Plan linking every target of `files_map` (target -> source) not in `copies`, ordered
+/// by path. Stale links are planned by [`plan_stale_links`].
+///
+/// Real files at targets in `backups` (target -> backup path) are adopted, and targets
+/// in `mirrors.wanted` get the link given there. Only `reader` is consulted, so the plan
+/// can be made against a fake filesystem.
+pub fn plan_links(
+    files_map: &HashMap<PathBuf, PathBuf>,
+    copies: &HashSet<PathBuf>,
+    backups: &HashMap<PathBuf, PathBuf>,
+    mirrors: &Mirrors,
+    owner_root: &Path,
+    reader: &impl ReadLink,
+) -> Vec<LinkAction> {
+    let mut links: Vec<(&PathBuf, &PathBuf)> = files_map
+        .iter()
+        .filter(|(target, _)| !copies.contains(*target))
+        .collect();
+    links.sort();
+    // Resolved in parallel, `collect` keeps the order
+    links
+        .into_par_iter()
+        .map(|(target, source)| {
+            let target = target.clone();
+            let created = mirrors.created_link(&target, reader);
+            let resolution = resolve_chain(&target, owner_root, reader);
+            let (source, keep) = match mirrors.wanted.get(&target) {
+                // Compared as written, the chain may well end outside the config dir
+                Some(link) => {
+                    let current = match reader.lookup(&target) {
+                        Ok(LinkNode::Link(current)) => Some(current),
+                        _ => None,
+                    };
+                    (link.clone(), current.as_ref() == Some(link))
+                }
+                None => {
+                    let keep = created.is_none()
+                        && matches!(&resolution, ChainResolution::Managed { source: old, .. } if old == source);
+                    (source.clone(), keep)
+                }
+            };
+            if keep {
+                return LinkAction::Keep { target, source };
+            }
+            if let Some(old) = created {
+                return LinkAction::Update {
+                    target,
+                    old,
+                    source,
+                };
+            }
+            match resolution {
+                ChainResolution::Managed { source: old, .. }
+                | ChainResolution::Broken {
+                    source: old,
+                    owned: true,
+                } => LinkAction::Update {
+                    target,
+                    old,
+                    source,
+                },
+                ChainResolution::Absent => LinkAction::Create { target, source },
+                ChainResolution::NotALink if backups.contains_key(&target) => LinkAction::Adopt {
+                    backup: backups[&target].clone(),
+                    target,
+                    source,
+                },
+                resolution => LinkAction::Skip {
+                    target,
+                    source: Some(source),
+                    resolution,
+                },
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// In-memory filesystem: paths map to nodes or to the error reading them yields
+    #[derive(Default)]
+    struct FakeFs(HashMap<PathBuf, Result<LinkNode, io::ErrorKind>>);
+
+    impl FakeFs {
+        fn link(mut self, from: &str, to: &str) -> Self {
+            self.0.insert(from.into(), Ok(LinkNode::Link(to.into())));
+            self
+        }
+
+        fn file(mut self, path: &str) -> Self {
+            self.0.insert(path.into(), Ok(LinkNode::Other));
+            self
+        }
+
+        fn error(mut self, path: &str, kind: io::ErrorKind) -> Self {
+            self.0.insert(path.into(), Err(kind));
+            self
+        }
+    }
+
+    impl ReadLink for FakeFs {
+        fn lookup(&self, path: &Path) -> io::Result<LinkNode> {
+            match self.0.get(path) {
+                Some(Ok(node)) => Ok(node.clone()),
+                Some(Err(kind)) => Err((*kind).into()),
+                None => Ok(LinkNode::Missing),
+            }
+        }
+    }
+
+    const ROOT: &str = "/cfg/envmgr";
+
+    fn resolve(fs: &FakeFs, target: &str) -> ChainResolution {
+        resolve_chain(Path::new(target), Path::new(ROOT), fs)
+    }
+
+    #[test]
+    fn test_absent_and_real_file() {
+        let fs = FakeFs::default().file("/home/.bashrc");
+        assert_eq!(resolve(&fs, "/home/.profile"), ChainResolution::Absent);
+        assert_eq!(resolve(&fs, "/home/.bashrc"), ChainResolution::NotALink);
+    }
+
+    #[test]
+    fn test_multi_hop_chain_to_managed_source() {
+        let fs = FakeFs::default()
+            .link("/home/.gitconfig", "/home/dotfiles/gitconfig")
+            .link(
+                "/home/dotfiles/gitconfig",
+                "/cfg/envmgr/base/files/.gitconfig",
+            )
+            .file("/cfg/envmgr/base/files/.gitconfig");
+
+        let resolution = resolve(&fs, "/home/.gitconfig");
+
+        assert_eq!(
+            resolution,
+            ChainResolution::Managed {
+                source: "/cfg/envmgr/base/files/.gitconfig".into(),
+                hops: 2
+            }
+        );
+        assert!(resolution.is_owned());
+    }
+
+    #[test]
+    fn test_relative_hops() {
+        let fs = FakeFs::default()
+            .link("/cfg/envmgr/link", "base/files/x")
+            .file("/cfg/envmgr/base/files/x");
+        assert!(matches!(
+            resolve(&fs, "/cfg/envmgr/link"),
+            ChainResolution::Managed { hops: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn test_foreign_and_broken() {
+        let fs = FakeFs::default()
+            .link("/home/.vimrc", "/opt/vimrc")
+            .file("/opt/vimrc")
+            .link("/home/.old", "/cfg/envmgr/deleted/files/.old")
+            .link("/home/.gone", "/opt/gone");
+
+        let foreign = resolve(&fs, "/home/.vimrc");
+        assert!(matches!(foreign, ChainResolution::Foreign { hops: 1, .. }));
+        assert!(!foreign.is_owned());
+
+        let broken_owned = resolve(&fs, "/home/.old");
+        assert!(matches!(
+            broken_owned,
+            ChainResolution::Broken { owned: true, .. }
+        ));
+        assert!(broken_owned.is_owned());
+
+        assert!(matches!(
+            resolve(&fs, "/home/.gone"),
+            ChainResolution::Broken { owned: false, .. }
+        ));
+    }
+
+    #[test]
+    fn test_cycles_and_depth_limit() {
+        let fs = FakeFs::default()
+            .link("/home/a", "/home/b")
+            .link("/home/b", "/home/a")
+            .link("/home/self", "/home/self");
+        assert_eq!(resolve(&fs, "/home/a"), ChainResolution::Cyclic);
+        assert_eq!(resolve(&fs, "/home/self"), ChainResolution::Cyclic);
+
+        let mut fs = FakeFs::default();
+        for i in 0..=MAX_LINK_HOPS {
+            fs = fs.link(&format!("/l{i}"), &format!("/l{}", i + 1));
+        }
+        fs = fs.file(&format!("/l{}", MAX_LINK_HOPS + 1));
+        assert_eq!(resolve(&fs, "/l0"), ChainResolution::TooDeep);
+        assert!(matches!(
+            resolve(&fs, "/l1"),
+            ChainResolution::Foreign { hops, .. } if hops == MAX_LINK_HOPS
+        ));
+    }
+
+    #[test]
+    fn test_permission_error_mid_chain() {
+        let fs = FakeFs::default()
+            .link("/home/.ssh/config", "/secret/config")
+            .error("/secret/config", io::ErrorKind::PermissionDenied);
+
+        let resolution = resolve(&fs, "/home/.ssh/config");
+
+        assert_eq!(
+            resolution,
+            ChainResolution::Unreadable {
+                at: "/secret/config".into(),
+                kind: io::ErrorKind::PermissionDenied
+            }
+        );
+        assert!(!resolution.is_owned());
+    }
+
+    fn describe_all(actions: &[LinkAction], verbose: bool) -> Vec<String> {
+        actions.iter().filter_map(|a| a.describe(verbose)).collect()
+    }
+
+    #[test]
+    fn test_plan_links() {
+        let fs = FakeFs::default()
+            .file("/cfg/envmgr/base/files/.a")
+            .file("/cfg/envmgr/base/files/.b")
+            .file("/cfg/envmgr/work/files/.c")
+            .link("/home/.b", "/cfg/envmgr/base/files/.b")
+            .link("/home/.c", "/cfg/envmgr/base/files/.c")
+            .file("/home/.d")
+            .file("/home/.e")
+            .link("/home/.f", "/opt/f")
+            .file("/opt/f");
+        let files_map: HashMap<PathBuf, PathBuf> = [
+            ("/home/.a", "/cfg/envmgr/base/files/.a"),
+            ("/home/.b", "/cfg/envmgr/base/files/.b"),
+            ("/home/.c", "/cfg/envmgr/work/files/.c"),
+            ("/home/.d", "/cfg/envmgr/base/files/.d"),
+            ("/home/.e", "/cfg/envmgr/base/files/.e"),
+            ("/home/.f", "/cfg/envmgr/base/files/.f"),
+            ("/home/.g", "/cfg/envmgr/base/files/.g"),
+        ]
+        .into_iter()
+        .map(|(target, source)| (target.into(), source.into()))
+        .collect();
+        let copies = HashSet::from([PathBuf::from("/home/.g")]);
+        let backups = HashMap::from([("/home/.e".into(), "/state/backups/.e".into())]);
+
+        let plan = plan_links(
+            &files_map,
+            &copies,
+            &backups,
+            &Mirrors::default(),
+            Path::new(ROOT),
+            &fs,
+        );
+
+        assert_eq!(
+            describe_all(&plan, false),
+            [
+                "create /home/.a",
+                "update /home/.c (/cfg/envmgr/base/files/.c -> /cfg/envmgr/work/files/.c)",
+                "skip /home/.d (exists, not a symlink)",
+                "adopt /home/.e (backup /state/backups/.e)",
+                "skip /home/.f (not managed)",
+            ]
+        );
+        assert_eq!(
+            describe_all(&plan, true)[..2],
+            [
+                "create /home/.a -> /cfg/envmgr/base/files/.a",
+                "keep /home/.b -> /cfg/envmgr/base/files/.b",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_stale_links() {
+        let fs = FakeFs::default()
+            .link("/home/.old", "/cfg/envmgr/base/files/.old")
+            .file("/cfg/envmgr/base/files/.old")
+            .file("/home/.edited")
+            .link("/home/.wanted", "/cfg/envmgr/base/files/.wanted")
+            .link("/home/.deleted", "/cfg/envmgr/work/files/.deleted")
+            .link("/home/.moved", "/cfg/envmgr/base/files/.other")
+            .file("/cfg/envmgr/base/files/.other");
+        // Entries of older state files only know their target
+        let mut managed: Vec<ManagedFile> = [
+            "/home/.old",
+            "/home/.edited",
+            "/home/.gone",
+            "/home/.old",
+            "/home/.wanted",
+        ]
+        .into_iter()
+        .map(|target| ManagedFile::legacy(target.into()))
+        .collect();
+        let recorded = |target: &str, source: &str| ManagedFile {
+            target: target.into(),
+            source: source.into(),
+            env_key: "work".to_string(),
+        };
+        managed.push(recorded(
+            "/home/.deleted",
+            "/cfg/envmgr/work/files/.deleted",
+        ));
+        managed.push(recorded("/home/.moved", "/cfg/envmgr/work/files/.moved"));
+        let desired = HashMap::from([(
+            "/home/.wanted".into(),
+            "/cfg/envmgr/base/files/.wanted".into(),
+        )]);
+
+        let plan = plan_stale_links(
+            &managed,
+            &desired,
+            &Mirrors::default(),
+            Path::new(ROOT),
+            &fs,
+        );
+
+        assert_eq!(plan.len(), 5);
+        assert_eq!(
+            plan[2],
+            LinkAction::Forget {
+                target: "/home/.gone".into()
+            }
+        );
+        assert_eq!(
+            describe_all(&plan, true),
+            [
+                "remove /home/.deleted (stale)",
+                "skip /home/.edited (stale, exists, not a symlink)",
+                "forget /home/.gone (stale, already gone)",
+                "skip /home/.moved (stale, changed since it was linked)",
+                "remove /home/.old (stale)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_mirrored_symlinks() {
+        let fs = FakeFs::default()
+            .link("/home/.same", "../shared/same")
+            .link("/home/.moved", "../shared/old")
+            .link("/home/.theirs", "/opt/theirs")
+            .file("/opt/theirs")
+            .link("/home/.gone", "/opt/gone");
+        let files_map: HashMap<PathBuf, PathBuf> = [".same", ".moved", ".new", ".theirs"]
+            .into_iter()
+            .map(|name| {
+                let target = Path::new("/home").join(name);
+                (target, Path::new("/cfg/envmgr/base/files").join(name))
+            })
+            .collect();
+        let mirrors = Mirrors {
+            wanted: HashMap::from([
+                ("/home/.same".into(), "../shared/same".into()),
+                ("/home/.moved".into(), "../shared/new".into()),
+                ("/home/.new".into(), "/etc/new".into()),
+                ("/home/.theirs".into(), "/etc/theirs".into()),
+            ]),
+            created: HashMap::from([
+                ("/home/.moved".into(), "../shared/old".into()),
+                ("/home/.gone".into(), "/opt/gone".into()),
+            ]),
+        };
+
+        let plan = plan_links(
+            &files_map,
+            &HashSet::new(),
+            &HashMap::new(),
+            &mirrors,
+            Path::new(ROOT),
+            &fs,
+        );
+        assert_eq!(
+            describe_all(&plan, true),
+            [
+                "update /home/.moved (../shared/old -> ../shared/new)",
+                "create /home/.new -> /etc/new",
+                "keep /home/.same -> ../shared/same",
+                "skip /home/.theirs -> /etc/theirs (not managed)",
+            ]
+        );
+
+        // Still envmgr's although it points outside the config dir
+        let managed = [ManagedFile::legacy("/home/.gone".into())];
+        let stale = plan_stale_links(&managed, &files_map, &mirrors, Path::new(ROOT), &fs);
+        assert_eq!(describe_all(&stale, false), ["remove /home/.gone (stale)"]);
+    }
+
+    #[test]
+    fn test_target_filter() {
+        let globs = |patterns: &[&str]| -> Vec<Glob> {
+            patterns
+                .iter()
+                .map(|pattern| TargetFilter::glob(pattern).unwrap())
+                .collect()
+        };
+        let touched = |filter: &TargetFilter, targets: &[&str]| -> Vec<String> {
+            targets
+                .iter()
+                .filter(|target| filter.touches(Path::new(target)))
+                .map(|target| target.to_string())
+                .collect()
+        };
+        let targets = [
+            "/home/.baserc",
+            "/home/.config/fish/config.fish",
+            "/home/.config/fish/conf.d/env.fish",
+            "/home/.config/nvim/init.lua",
+            "/etc/hosts",
+        ];
+        let home = Path::new("/home");
+
+        let all = TargetFilter::default();
+        assert_eq!(touched(&all, &targets).len(), targets.len());
+
+        // `*` stays within a directory, `**` doesn't
+        let shallow = TargetFilter::new(home, &globs(&[".config/fish/*"]), &[]).unwrap();
+        assert_eq!(
+            touched(&shallow, &targets),
+            ["/home/.config/fish/config.fish"]
+        );
+        let deep = TargetFilter::new(home, &globs(&[".config/**"]), &[]).unwrap();
+        assert_eq!(
+            touched(&deep, &targets),
+            [
+                "/home/.config/fish/config.fish",
+                "/home/.config/fish/conf.d/env.fish",
+                "/home/.config/nvim/init.lua",
+            ]
+        );
+
+        // Include first, then exclude
+        let both = TargetFilter::new(
+            home,
+            &globs(&[".config/**"]),
+            &globs(&[".config/nvim/**", "**/conf.d/*"]),
+        )
+        .unwrap();
+        assert_eq!(touched(&both, &targets), ["/home/.config/fish/config.fish"]);
+        let exclude = TargetFilter::new(home, &[], &globs(&[".*rc"])).unwrap();
+        assert!(!exclude.touches(Path::new("/home/.baserc")));
+        assert!(exclude.touches(Path::new("/home/.config/nvim/init.lua")));
+
+        // Outside home the full path is matched
+        let outside = TargetFilter::new(home, &globs(&["/etc/*"]), &[]).unwrap();
+        assert_eq!(touched(&outside, &targets), ["/etc/hosts"]);
+    }
+}
@@ -1,34 +1,489 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use log::{debug, info, warn};
+use globset::Glob;
+use log::{debug, error, info, warn};
+use rayon::prelude::*;
 
 use crate::{
     cli::Shell,
-    config::{BASE_ENV_NAME, EnvVarsConfig, EnvironmentConfig},
-    environment::Environment,
-    error::EnvMgrResult,
-    integrations::one_password_ssh_agent::OnePasswordSSHAgent,
-    state::State,
+    commands::template::integrations_trusted,
+    config::{
+        AliasConfig, BASE_ENV_NAME, DynamicValue, ENVS_DIR_NAME, EnvVarsConfig, EnvironmentConfig,
+        GlobalConfig, LinkKind, envmgr_config_dir,
+    },
+    daemon::unix_now,
+    environment::{
+        DynamicOptions, EnvSummary, Environment, SECRET_PLACEHOLDER,
+        aliases::{AliasChange, merge_alias_layers, plan_alias_changes},
+        backups::{backup_path, latest_backup, move_file},
+        dynamic::resolve_dynamic_values,
+        hash_content,
+        hooks::{Hook, HookEnv, run_hook},
+        links::{
+            ChainResolution, FsReadLink, LinkAction, Mirrors, TargetFilter, plan_links,
+            plan_stale_links, resolve_chain,
+        },
+        vars::{EnvVarChange, merge_env_var_layers, plan_env_var_changes},
+    },
+    error::{EnvMgrError, EnvMgrResult},
+    fs::{Fs, RealFs},
+    integrations::{
+        ApplyOutcome, IntegrationKind, IntegrationOutcome, IntegrationSelection,
+        one_password_documents::{FetchedDocument, OnePasswordDocuments},
+        one_password_ssh_agent::{OnePasswordSSHAgent, OpItemList},
+        plan_integrations, planned_switch_from, quarantine, record_snapshots, recorded_env_vars,
+        registry,
+        rollback::{
+            FailurePolicy, Job, PlannedStep, Step, StepsRun, UndoAction, plan_steps, run_steps,
+        },
+        take_contributions,
+    },
+    platform,
+    runner::SystemRunner,
+    state::{AppliedEnvVar, Backup, CopiedFile, EnvVarSource, ManagedFile, MirroredLink, State},
+    systemd::{self, SystemdOutcome, SystemdUser, plan_systemd_env},
+    tmux::{Tmux, TmuxOutcome, plan_tmux_env},
 };
 
 pub struct EnvironmentManager {
     pub shell: Shell,
 }
 
+/// What [`EnvironmentManager::link_files_with`] does
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkMode {
+    /// Remove stale links and link the active environment's files
+    Link,
+    /// Only remove managed links, creating nothing
+    PruneOnly,
+    /// Only remove the managed links and copies of one environment, `base` included,
+    /// leaving the others as they are
+    Unlink { env_key: String },
+}
+
+/// What a switch does besides changing the current environment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SwitchOptions {
+    /// Only print what would change
+    pub dry_run: bool,
+    /// Link the new environment's files
+    pub link: bool,
+    /// Move real files in the way of links aside, see [`LinkContext::adopt_backups`]
+    pub adopt_backups: bool,
+    pub integrations: IntegrationSelection,
+    /// Run the environment's hook scripts
+    pub hooks: bool,
+    /// Let integrations run login commands that need the user, like `tailscale login`
+    pub login: bool,
+    /// Check the 1Password SSH keys resolve before switching, see
+    /// [`OnePasswordSSHAgent::verify_keys`]
+    pub check_op_keys: bool,
+    /// Whether a failing integration rolls the others back or the switch goes on
+    pub failure_policy: FailurePolicy,
+}
+
+impl Default for SwitchOptions {
+    fn default() -> Self {
+        Self {
+            dry_run: false,
+            link: true,
+            adopt_backups: false,
+            integrations: IntegrationSelection::All,
+            hooks: true,
+            login: false,
+            check_op_keys: false,
+            failure_policy: FailurePolicy::default(),
+        }
+    }
+}
+
+/// How a link run goes about it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LinkOptions {
+    /// Only print the plan, one line per path
+    pub dry_run: bool,
+    /// Move real files in the way of links aside, see [`LinkContext::adopt_backups`]
+    pub adopt_backups: bool,
+    /// Also print the source of each link and the links that are already right
+    pub verbose: bool,
+    /// Only link and clean up targets matching one of these, see [`TargetFilter`]
+    pub only: Vec<Glob>,
+    /// Leave targets matching one of these as they are
+    pub exclude: Vec<Glob>,
+}
+
+/// Where a link run reads and writes
+struct LinkContext<'a> {
+    fs: &'a dyn Fs,
+    /// Links resolving into this directory are envmgr's
+    owner_root: PathBuf,
+    state_dir: PathBuf,
+    dual_write: bool,
+    /// Move real files at link targets into the backups dir instead of skipping them
+    adopt_backups: bool,
+    /// Backups of targets under it are kept by their path relative to it
+    home: PathBuf,
+    /// Dry runs describe links with their sources
+    verbose: bool,
+    /// Targets outside of it are neither linked nor cleaned up, and keep their state
+    filter: TargetFilter,
+}
+
+impl LinkContext<'static> {
+    fn real(opts: &LinkOptions) -> EnvMgrResult<Self> {
+        let global = GlobalConfig::load()?;
+        let home = dirs::home_dir().ok_or(EnvMgrError::DirError("home".into()))?;
+        let filter = TargetFilter::new(&home, &opts.only, &opts.exclude)
+            .map_err(|e| EnvMgrError::Other(Box::new(e)))?;
+        Ok(Self {
+            fs: &RealFs,
+            owner_root: envmgr_config_dir(),
+            state_dir: State::get_state_dir(),
+            dual_write: global.legacy_state_dual_write,
+            adopt_backups: opts.adopt_backups || global.adopt_backups,
+            home,
+            verbose: opts.verbose,
+            filter,
+        })
+    }
+}
+
+impl LinkContext<'_> {
+    fn store(&self, state: &State) -> EnvMgrResult<()> {
+        state.store_in_dir(self.fs, &self.state_dir, self.dual_write)
+    }
+
+    /// The state entry of a link at `target` to `source`
+    fn managed_file(&self, target: PathBuf, source: &Path) -> ManagedFile {
+        ManagedFile {
+            target,
+            source: source.to_path_buf(),
+            env_key: source_env_key(source, &self.owner_root),
+        }
+    }
+}
+
+/// The environment whose files `source` is among, going by its place in the config dir
+/// `owner_root`. Empty for sources outside of it.
+fn source_env_key(source: &Path, owner_root: &Path) -> String {
+    let relative = source.strip_prefix(owner_root).unwrap_or(Path::new(""));
+    let mut dirs = relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy());
+    match (dirs.next(), dirs.next()) {
+        (Some(dir), _) if dir == BASE_ENV_NAME => BASE_ENV_NAME.to_string(),
+        (Some(dir), Some(key)) if dir == ENVS_DIR_NAME => key.into_owned(),
+        _ => String::new(),
+    }
+}
+
+/// Counts of what a link run did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LinkReport {
+    pub created: usize,
+    pub removed: usize,
+    pub skipped: usize,
+}
+
+/// The env vars of `environment` merged over those of `base` and the `global` ones, in
+/// emission order, and the keys to unset.
+///
+/// Base variables named in the environment's `unset_vars` are left out of the merge, and
+/// all of them when the environment doesn't inherit base. Global variables named in any
+/// layer's `unset_vars` are left out too.
+pub(crate) fn layer_env_vars(
+    global: &[EnvVarsConfig],
+    base: &Environment,
+    environment: Option<&Environment>,
+) -> (Vec<EnvVarsConfig>, Vec<String>) {
+    // An environment that doesn't inherit base takes its place
+    let (base, environment) = match environment {
+        Some(environment) if !environment.inherit_base => (environment, None),
+        _ => (base, environment),
+    };
+    let mut unset: Vec<String> = base.unset_vars.clone();
+    let base_vars: Vec<EnvVarsConfig> = match environment {
+        Some(environment) => {
+            unset.extend(environment.unset_vars.iter().cloned());
+            base.env_vars
+                .iter()
+                .filter(|var| !environment.unset_vars.contains(&var.key))
+                .cloned()
+                .collect()
+        }
+        None => base.env_vars.clone(),
+    };
+    let global_vars: Vec<EnvVarsConfig> = global
+        .iter()
+        .filter(|var| !unset.contains(&var.key))
+        .cloned()
+        .collect();
+    let mut layers = vec![global_vars.as_slice(), base_vars.as_slice()];
+    if let Some(environment) = environment {
+        layers.push(environment.env_vars.as_slice());
+    }
+    let vars = merge_env_var_layers(&layers);
+    unset.retain(|key| !vars.iter().any(|var| &var.key == key));
+    unset.sort();
+    unset.dedup();
+    (vars, unset)
+}
+
+/// What `use` does to the variables of a shell that has `previous` applied
+pub(crate) struct EnvVarPlan {
+    /// Unsets first, then a set for every variable, changed or not
+    pub changes: Vec<EnvVarChange>,
+    /// Variables holding a 1Password secret
+    pub secret_keys: Vec<String>,
+    /// Source and order of every variable set, for the next run to unset them in reverse
+    pub origins: BTreeMap<String, AppliedEnvVar>,
+    /// One message per dynamic value that couldn't be resolved
+    pub errors: Vec<String>,
+}
+
+/// Layer the variables like [`layer_env_vars`], resolve the dynamic ones and diff the
+/// result against `previous`, which came from `previous_origins`
+pub(crate) fn plan_use_env_vars(
+    global: &[EnvVarsConfig],
+    base: &Environment,
+    environment: Option<&Environment>,
+    previous: &HashMap<String, String>,
+    previous_origins: &BTreeMap<String, AppliedEnvVar>,
+    dynamic: &DynamicOptions,
+) -> EnvVarPlan {
+    let (merged, unset) = layer_env_vars(global, base, environment);
+    let secret_keys = merged
+        .iter()
+        .filter(|var| matches!(var.dynamic, Some(DynamicValue::Secret(_))))
+        .map(|var| var.key.clone())
+        .collect();
+    let origins = merged
+        .iter()
+        .map(|var| {
+            let origin = AppliedEnvVar {
+                source: env_var_source(var, base, environment),
+                order: var.order,
+            };
+            (var.key.clone(), origin)
+        })
+        .collect();
+    let (vars, errors) = resolve_dynamic_values(merged, previous, dynamic);
+    EnvVarPlan {
+        changes: plan_env_var_changes(&[&vars], previous, previous_origins, &unset),
+        secret_keys,
+        origins,
+        errors,
+    }
+}
+
+/// The layer `var`, merged by [`layer_env_vars`], came from
+fn env_var_source(
+    var: &EnvVarsConfig,
+    base: &Environment,
+    environment: Option<&Environment>,
+) -> EnvVarSource {
+    let defines = |env: &Environment| env.env_vars.iter().any(|own| own.key == var.key);
+    match environment {
+        _ if var.integration.is_some() => EnvVarSource::Integration,
+        Some(environment) if defines(environment) => EnvVarSource::Environment,
+        // An environment that doesn't inherit base is the only layer over the global one
+        Some(environment) if !environment.inherit_base => EnvVarSource::Global,
+        _ if defines(base) => EnvVarSource::Base,
+        _ => EnvVarSource::Global,
+    }
+}
+
+/// The allowlisted variables `switch` pushes into the systemd user manager for
+/// `environment`, dynamic values unresolved, or `None` when propagation is off for it
+pub(crate) fn systemd_user_vars(
+    environment: &Environment,
+    global: &GlobalConfig,
+) -> EnvMgrResult<Option<Vec<EnvVarsConfig>>> {
+    let enabled = environment
+        .propagate_to_systemd_user
+        .unwrap_or(global.propagate_to_systemd_user);
+    if !enabled {
+        return Ok(None);
+    }
+    let base_environment = Environment::load_base_environment()?;
+    let overlay = (environment.key != BASE_ENV_NAME).then_some(environment);
+    let (mut vars, _) =
+        layer_env_vars(&global.env_vars_for_this_host(), &base_environment, overlay);
+    vars.retain(|var| global.systemd_user_allowlist.contains(&var.key));
+    if vars.is_empty() {
+        warn!("No variable is in systemd_user_allowlist, nothing is pushed to systemd");
+    }
+    Ok(Some(vars))
+}
+
+/// The aliases of `environment` merged over those of `base`, by name
+pub(crate) fn layer_aliases(
+    base: &Environment,
+    environment: Option<&Environment>,
+) -> Vec<AliasConfig> {
+    if let Some(environment) = environment.filter(|env| !env.inherit_base) {
+        return merge_alias_layers(&[environment.aliases.as_slice()]);
+    }
+    let mut layers = vec![base.aliases.as_slice()];
+    if let Some(environment) = environment {
+        layers.push(environment.aliases.as_slice());
+    }
+    merge_alias_layers(&layers)
+}
+
+/// Which layer's file ends up linked when base and an environment both provide one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileLayer {
+    Base,
+    Environment,
+}
+
+/// A target both base and an environment provide, directly or through a directory link
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct FileConflict {
+    /// The target of the file that loses, inside the winner's when a directory link wins
+    pub target: PathBuf,
+    pub base_source: PathBuf,
+    pub env_source: PathBuf,
+    pub winner: FileLayer,
+}
+
+/// The targets of `base_files` and `env_files` (target -> source) that overlap, sorted.
+///
+/// The environment wins on the same target and with a directory link over base files,
+/// base wins with a directory link over environment files, as in [`layer_files`].
+pub(crate) fn file_conflicts(
+    base_files: &HashMap<PathBuf, PathBuf>,
+    env_files: &HashMap<PathBuf, PathBuf>,
+) -> Vec<FileConflict> {
+    let mut conflicts = vec![];
+    for (env_target, env_source) in env_files {
+        for (base_target, base_source) in base_files {
+            let (target, winner) = if env_target == base_target {
+                (env_target, FileLayer::Environment)
+            } else if env_source.is_dir() && base_target.starts_with(env_target) {
+                (base_target, FileLayer::Environment)
+            } else if base_source.is_dir() && env_target.starts_with(base_target) {
+                (env_target, FileLayer::Base)
+            } else {
+                continue;
+            };
+            conflicts.push(FileConflict {
+                target: target.clone(),
+                base_source: base_source.clone(),
+                env_source: env_source.clone(),
+                winner,
+            });
+        }
+    }
+    conflicts.sort_by(|a, b| a.target.cmp(&b.target));
+    conflicts
+}
+
+/// The links (target -> source) of `environment` over those of `base`.
+///
+/// Nothing is linked inside a directory linked as a whole: base files there give way to
+/// an environment's directory link, and environment files inside a directory base links
+/// are left out with a warning, `validate` reports them.
+pub(crate) fn layer_files(
+    base: &Environment,
+    environment: Option<&Environment>,
+) -> EnvMgrResult<HashMap<PathBuf, PathBuf>> {
+    Ok(layer_files_with_conflicts(base, environment)?.0)
+}
+
+/// [`layer_files`] along with the [`file_conflicts`] between the two layers
+pub(crate) fn layer_files_with_conflicts(
+    base: &Environment,
+    environment: Option<&Environment>,
+) -> EnvMgrResult<(HashMap<PathBuf, PathBuf>, Vec<FileConflict>)> {
+    let mut files_map = match environment {
+        Some(environment) if !environment.inherit_base => HashMap::new(),
+        _ => base.files_to_link()?,
+    };
+    let mut conflicts = vec![];
+    if let Some(environment) = environment {
+        let env_files = environment.files_to_link()?;
+        conflicts = file_conflicts(&files_map, &env_files);
+        files_map.extend(env_files);
+    }
+    let dir_links: Vec<(PathBuf, PathBuf)> = files_map
+        .iter()
+        .filter(|(_, source)| source.is_dir())
+        .map(|(target, source)| (target.clone(), source.clone()))
+        .collect();
+    let from_env =
+        |source: &Path| environment.is_some_and(|env| source.starts_with(env.files_dir()));
+    files_map.retain(|target, source| {
+        let Some((dir, dir_source)) = dir_links
+            .iter()
+            .find(|(dir, _)| target != dir && target.starts_with(dir))
+        else {
+            return true;
+        };
+        if from_env(source) && !from_env(dir_source) {
+            warn!(
+                "{} is inside {}, which base links as a whole, not linking it",
+                source.display(),
+                dir.display()
+            );
+        }
+        false
+    });
+    Ok((files_map, conflicts))
+}
+
+/// The links envmgr re-created from symlinks in `files/` (target -> link)
+fn created_mirrors(state: &State) -> HashMap<PathBuf, PathBuf> {
+    state
+        .mirrored_links
+        .iter()
+        .map(|mirrored| (mirrored.target.clone(), mirrored.link.clone()))
+        .collect()
+}
+
+/// Targets of `files_map` to copy rather than symlink, per the link mode of the
+/// environment each file comes from
+fn copy_targets(
+    base: &Environment,
+    environment: Option<&Environment>,
+    files_map: &HashMap<PathBuf, PathBuf>,
+) -> HashSet<PathBuf> {
+    files_map
+        .iter()
+        // Symlinks in `files/` are re-created rather than copied
+        .filter(|(_, source)| !source.is_dir() && !source.is_symlink())
+        .filter(|(_, source)| {
+            let owner = environment
+                .filter(|env| source.starts_with(env.files_dir()))
+                .unwrap_or(base);
+            owner.link_kind(source) == LinkKind::Copy
+        })
+        .map(|(target, _)| target.clone())
+        .collect()
+}
+
 impl EnvironmentManager {
     pub fn list_environments() -> EnvMgrResult<Vec<(bool, Environment)>> {
         let state = State::get_state()?;
+        let base = Environment::load_base_environment()?;
+        let mut environments = vec![(state.current_env_key == base.key, base)];
+
         let envs_dir = EnvironmentConfig::get_all_envs_dir();
         if !envs_dir.exists() {
-            return Ok(vec![]);
+            return Ok(environments);
         }
-        let base = Environment::load_base_environment()?;
-
-        let mut environments = vec![(state.current_env_key == base.key, base)];
         for entry in std::fs::read_dir(envs_dir)? {
             let entry = entry?;
+            // Dot directories hold archived and staged environments
             if entry.file_type()?.is_dir()
                 && let Some(env_key) = entry.file_name().to_str()
+                && !env_key.starts_with('.')
             {
                 let env = Environment::load_environment_by_key(env_key)?;
                 environments.push((state.current_env_key == env.key, env));
@@ -37,182 +492,2013 @@ impl EnvironmentManager {
         Ok(environments)
     }
 
-    pub fn use_environment(&self) -> EnvMgrResult<()> {
-        // Unset current environment variables
+    /// Summaries of all environments, base first, for `list`
+    pub fn list_summaries() -> EnvMgrResult<Vec<EnvSummary>> {
+        let state = State::get_state()?;
+        Self::list_environments()?
+            .iter()
+            .map(|(current, env)| {
+                let last_used = state
+                    .history
+                    .iter()
+                    .filter(|entry| entry.to == env.key)
+                    .map(|entry| entry.timestamp)
+                    .max();
+                Ok(EnvSummary::new(
+                    env,
+                    *current,
+                    env.files_count()?,
+                    last_used,
+                ))
+            })
+            .collect()
+    }
+
+    /// Print shell commands applying the current environment's variables.
+    ///
+    /// With `dry_run` the commands are still printed, but the applied variables aren't recorded.
+    /// Dynamic values that can't be resolved are reported after everything else was emitted.
+    pub fn use_environment(&self, dry_run: bool, dynamic: &DynamicOptions) -> EnvMgrResult<()> {
         let mut state = State::get_state()?;
         let target_env_key = state.current_env_key.clone();
+        if let Some(key) = &state.applying {
+            warn!(
+                "The last switch to {key} was interrupted, run `envmgr switch {key}` to finish it"
+            );
+        }
 
-        state.applied_env_vars.clear();
-        // Set new environment variables
-        let base_environment = Environment::load_base_environment()?;
+        // What the shell has now: anything in it the new environment doesn't set is unset
+        let previous = std::mem::take(&mut state.applied_env_vars);
+        let previous_origins = std::mem::take(&mut state.applied_env_var_origins);
+        let mut base_environment = Environment::load_base_environment()?;
 
-        let mut new_vars = HashMap::new();
+        let mut environment = if target_env_key != BASE_ENV_NAME {
+            Some(Environment::load_environment_by_key(&target_env_key)?)
+        } else {
+            None
+        };
+        // What the integrations handed back on switch goes below the environment's own
+        // env vars, like what they export for its config
+        environment
+            .as_mut()
+            .unwrap_or(&mut base_environment)
+            .env_vars
+            .splice(0..0, recorded_env_vars(&state));
+        state.set_current(environment.as_ref().unwrap_or(&base_environment));
 
-        for EnvVarsConfig { key, value } in base_environment.env_vars {
-            new_vars.insert(key, value);
+        let global = GlobalConfig::load()?;
+        let plan = plan_use_env_vars(
+            &global.env_vars_for_this_host(),
+            &base_environment,
+            environment.as_ref(),
+            &previous,
+            &previous_origins,
+            dynamic,
+        );
+        // Unsets come first, then the sets that make up the new applied map
+        for change in &plan.changes {
+            match change {
+                EnvVarChange::Unset(key) => {
+                    println!("{}", self.shell.unset_env_var_cmd(key));
+                }
+                EnvVarChange::Set(key, value) => {
+                    println!("{}", self.shell.set_env_var_cmd(key, value));
+                    // Secrets only go to the shell, never to disk
+                    let recorded = if plan.secret_keys.contains(key) {
+                        SECRET_PLACEHOLDER.to_string()
+                    } else {
+                        value.clone()
+                    };
+                    state.applied_env_vars.insert(key.clone(), recorded);
+                    if let Some(origin) = plan.origins.get(key) {
+                        state.applied_env_var_origins.insert(key.clone(), *origin);
+                    }
+                }
+            }
         }
-
-        if target_env_key != BASE_ENV_NAME {
-            let environment = Environment::load_environment_by_key(&target_env_key)?;
-            state.current_env_key = environment.key.to_string();
-            for EnvVarsConfig { key, value } in environment.env_vars {
-                new_vars.insert(key, value);
+        if global.propagate_to_tmux && !dry_run {
+            let changes = plan_tmux_env(&plan.changes, &previous, &plan.secret_keys);
+            match Tmux::new(&SystemRunner).apply(&changes, global.tmux_refresh_client) {
+                Ok(TmuxOutcome::Applied) => {}
+                Ok(TmuxOutcome::NotRunning) => debug!("No tmux server, not updating tmux"),
+                Err(e) => warn!("Not updating tmux: {e}"),
             }
-        } else {
-            state.current_env_key = BASE_ENV_NAME.to_string();
         }
-
-        // Remove keys that are no longer present
-        let keys_to_remove: Vec<String> = state
-            .applied_env_vars
-            .keys()
-            .filter(|k| !new_vars.contains_key(*k))
-            .cloned()
-            .collect();
-
-        for key in keys_to_remove {
-            println!("{}", self.shell.unset_env_var_cmd(&key));
-            state.applied_env_vars.remove(&key);
+        // Unresolved secrets weren't emitted, but the shell still has them
+        for key in plan.secret_keys {
+            if let Some(value) = previous.get(&key) {
+                if let Some(origin) = previous_origins.get(&key) {
+                    state
+                        .applied_env_var_origins
+                        .entry(key.clone())
+                        .or_insert(*origin);
+                }
+                state.applied_env_vars.entry(key).or_insert(value.clone());
+            }
         }
 
-        // Set all new/updated variables
-        for (key, value) in new_vars {
-            println!("{}", self.shell.set_env_var_cmd(&key, &value));
-            state.applied_env_vars.insert(key, value);
+        let aliases = layer_aliases(&base_environment, environment.as_ref());
+        for change in plan_alias_changes(&aliases, &state.applied_aliases) {
+            match change {
+                AliasChange::Remove(alias) => println!("{}", self.shell.unset_alias_cmd(&alias)),
+                AliasChange::Define(alias) => println!("{}", self.shell.set_alias_cmd(&alias)),
+            }
         }
+        state.applied_aliases = aliases;
 
-        state.store_state()?;
+        if !dry_run {
+            state.store_state()?;
+        }
+        if !plan.errors.is_empty() {
+            return Err(EnvMgrError::UnresolvedEnvVars(plan.errors));
+        }
         Ok(())
     }
 
-    fn switch_environment(environment: &Environment) -> EnvMgrResult<()> {
+    /// Env vars of the environment `key` layered over base and the global ones exactly
+    /// like `use` does, with dynamic values still unresolved
+    pub fn effective_env_vars(key: &str) -> EnvMgrResult<Vec<EnvVarsConfig>> {
+        let base_environment = Environment::load_base_environment()?;
+        let environment = if key != BASE_ENV_NAME {
+            Some(Environment::load_environment_by_key(key)?)
+        } else {
+            None
+        };
+        let global_vars = GlobalConfig::load()?.env_vars_for_this_host();
+        let (vars, _unset) = layer_env_vars(&global_vars, &base_environment, environment.as_ref());
+        Ok(vars)
+    }
+
+    fn switch_environment(environment: &Environment, opts: &SwitchOptions) -> EnvMgrResult<()> {
+        let dry_run = opts.dry_run;
         let mut state = State::get_state()?;
-        if state.current_env_key == environment.key {
+        let original = state.clone();
+        let mut prev_env_key = state.current_env_key.clone();
+        if state.current_env_key != environment.key {
+            info!(
+                "Switching to environment: {} ({})",
+                environment.name, environment.key
+            );
+            let from = state.set_current(environment);
+            state.record_switch(&from, &environment.key, unix_now());
+            if dry_run {
+                print_dry_run(
+                    "state",
+                    format_args!("current_env {from} -> {}", environment.key),
+                );
+            }
+            state.previous_env_key = Some(from);
+        } else if state.applying.as_ref() == Some(&environment.key) {
+            info!(
+                "Finishing the interrupted switch to {} ({})",
+                environment.name, environment.key
+            );
+            if let Some(previous) = &state.previous_env_key {
+                prev_env_key = previous.clone();
+            }
+        } else {
             // No change
             debug!("Environment {} is already active", environment.name);
             return Ok(());
         }
-        info!(
-            "Switching to environment: {} ({})",
-            environment.name, environment.key
-        );
-        state.current_env_key = environment.key.to_string();
+        let config_dir = envmgr_config_dir();
+        let hook_env = HookEnv {
+            env_key: &environment.key,
+            prev_env_key: &prev_env_key,
+            config_dir: &config_dir,
+        };
+        Self::run_switch_hook(Hook::PreSwitch, environment, &hook_env, opts)?;
+        // Fetched before the first change, so a failing fetch leaves everything as it was
+        let documents = match opts.link && !dry_run && !environment.op_documents.is_empty() {
+            true => {
+                let home = dirs::home_dir().ok_or(EnvMgrError::DirError("home".into()))?;
+                OnePasswordDocuments::fetch_all(&environment.op_documents, &home, &SystemRunner)?
+            }
+            false => vec![],
+        };
+        // Checked before the first change too, so a key typo doesn't leave a half switch
+        if opts.check_op_keys
+            && let Some(op_ssh) = &environment.one_password_ssh
+            && plan_integrations(environment, &opts.integrations).contains(&IntegrationKind::OpSsh)
+        {
+            OnePasswordSSHAgent::verify_keys(op_ssh, &OpItemList::new(&SystemRunner))?;
+        }
+        let trusted = integrations_trusted(&environment.env_dir())?;
+        if !dry_run {
+            // Recorded before the first change, so an interrupted switch is noticed
+            state.applying = Some(environment.key.clone());
+            state.store_state()?;
+        }
 
         // Integrations
-        if let Some(op_ssh_config) = environment.one_password_ssh.as_ref() {
-            OnePasswordSSHAgent::on_switch_to(op_ssh_config)?;
+        let mut planned = plan_integrations(environment, &opts.integrations);
+        if let IntegrationSelection::Only(kinds) = &opts.integrations {
+            for kind in kinds.iter().filter(|kind| !planned.contains(kind)) {
+                warn!("{kind} is not configured in {}, skipping", environment.key);
+            }
+        }
+        if !trusted && !planned.is_empty() {
+            warn!(
+                "{} was created from a remote template and is not trusted yet, not applying its integrations; review its config, then run `envmgr template trust {}`",
+                environment.key, environment.key
+            );
+            planned.clear();
+        }
+        planned.retain(|kind| {
+            let hash = quarantine::config_hash(*kind, environment);
+            match quarantine::quarantined(
+                &mut state.integration_failures,
+                &environment.key,
+                *kind,
+                &hash,
+            ) {
+                Some(record) => {
+                    warn!("{}", quarantine::quarantine_notice(record));
+                    false
+                }
+                None => true,
+            }
+        });
+        // The outgoing environment's integrations are undone before the incoming ones run
+        let outgoing = match prev_env_key != environment.key {
+            true => Environment::load(&prev_env_key)
+                .inspect_err(|e| warn!("Not undoing the integrations of {prev_env_key}: {e}"))
+                .ok()
+                // Nothing of an untrusted environment was applied, nor may a rollback apply it
+                .filter(|from| integrations_trusted(&from.env_dir()).unwrap_or(false)),
+            false => None,
+        };
+        let registry = registry(&RealFs, &SystemRunner, opts.login)?;
+        let undo = match &outgoing {
+            Some(from) => planned_switch_from(
+                &registry,
+                from,
+                environment,
+                &opts.integrations,
+                &state.integration_snapshots,
+            ),
+            None => vec![],
+        };
+        // Those undone above are already cleared
+        let unconfigured: Vec<IntegrationKind> = IntegrationKind::ALL
+            .into_iter()
+            .filter(|kind| opts.integrations.contains(*kind))
+            .filter(|kind| !kind.is_configured_in(environment) && !undo.contains(kind))
+            .collect();
+        let mut failed = vec![];
+        if dry_run {
+            for kind in &undo {
+                print_dry_run(
+                    &format!("integration {kind}"),
+                    format_args!("put back what was there before {prev_env_key}"),
+                );
+            }
+            for kind in &planned {
+                for action in kind.describe_actions(environment) {
+                    print_dry_run(&format!("integration {kind}"), action);
+                }
+            }
+            for kind in &unconfigured {
+                for action in kind.describe_clear() {
+                    print_dry_run(&format!("integration {kind}"), action);
+                }
+            }
+        } else {
+            record_snapshots(
+                &registry,
+                environment,
+                &planned,
+                &mut state.integration_snapshots,
+            );
+            // Planned in full first, so a failure knows how to undo each step that ran
+            let restorable: Vec<IntegrationKind> = registry.iter().map(|i| i.kind()).collect();
+            let global = GlobalConfig::load()?;
+            let steps = plan_steps(
+                &undo,
+                &planned,
+                &unconfigured,
+                outgoing.as_ref(),
+                &restorable,
+                |kind| global.integration_order(kind),
+            );
+            let run = Self::run_integration_steps(
+                environment,
+                outgoing.as_ref(),
+                &steps,
+                opts,
+                &global,
+                &mut state,
+            );
+            for (action, e) in &run.failed_undos {
+                warn!("{}: could not be rolled back: {e}", action.kind());
+            }
+            for step in &run.still_running {
+                warn!(
+                    "{}: still running after it timed out, so it was not rolled back; check it once it is done",
+                    step.kind()
+                );
+            }
+            if run.rolled_back(opts.failure_policy) {
+                // The failure still has to count, and snapshots that weren't put back
+                // are still needed
+                let mut stored = original;
+                stored.integration_failures = std::mem::take(&mut state.integration_failures);
+                for (action, _) in &run.failed_undos {
+                    let key = action.kind().config_key();
+                    if let UndoAction::Restore(_) = action
+                        && let Some(snapshot) = state.integration_snapshots.remove(key)
+                    {
+                        stored
+                            .integration_snapshots
+                            .insert(key.to_string(), snapshot);
+                    }
+                }
+                stored.store_state()?;
+                let (step, e) = run
+                    .errors
+                    .into_iter()
+                    .next()
+                    .expect("a rollback has an error");
+                warn!(
+                    "{} failed, rolled back the integrations of the switch to {}",
+                    step.kind(),
+                    environment.key
+                );
+                return Err(e);
+            }
+            let outcomes: Vec<_> = run
+                .outcomes
+                .into_iter()
+                .map(|(step, outcome)| (step.kind(), outcome))
+                .collect();
+            take_contributions(&mut state, &outcomes, &RealFs)?;
+            for (step, e) in run.errors {
+                error!("{}: {e}", step.kind());
+                failed.push(format!("{}: {e}", step.kind()));
+            }
+        }
+        Self::propagate_to_systemd_user(environment, &mut state, dry_run)?;
+        if opts.link {
+            Self::place_documents(environment, &mut state, &documents, dry_run)?;
+        }
+        let link = opts.link && platform::SUPPORTS_LINKING;
+        if !dry_run {
+            if !link {
+                state.applying = None;
+            }
+            state.store_state()?;
+        }
+
+        if !opts.link {
+            info!("Skipping file linking");
+        } else if link {
+            // The dry-run plan of a switch always names the sources
+            let link_opts = LinkOptions {
+                dry_run,
+                adopt_backups: opts.adopt_backups,
+                verbose: true,
+                ..Default::default()
+            };
+            Self::link_state(&mut state, LinkMode::Link, &link_opts)?;
+            Self::run_switch_hook(Hook::PostLink, environment, &hook_env, opts)?;
+        } else {
+            warn!("File linking is not supported on this platform yet, skipping");
+        }
+        Self::run_switch_hook(Hook::PostSwitch, environment, &hook_env, opts)?;
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(EnvMgrError::IntegrationsFailed(failed)),
+        }
+    }
+
+    /// Run the integration `steps` of a switch to `environment` from `outgoing` under
+    /// the failure policy of `opts`, counting the failures of the applied integrations
+    /// toward their quarantine
+    fn run_integration_steps(
+        environment: &Environment,
+        outgoing: Option<&Environment>,
+        steps: &[PlannedStep],
+        opts: &SwitchOptions,
+        global: &GlobalConfig,
+        state: &mut State,
+    ) -> StepsRun {
+        let threshold = global.quarantine_after_failures;
+        let login = opts.login;
+        // Taken before any step runs; a snapshot is only dropped once put back
+        let snapshots = state.integration_snapshots.clone();
+        let snapshot = |kind: IntegrationKind| {
+            (snapshots.get(kind.config_key())).and_then(|snapshot| snapshot.content.clone())
+        };
+        // The jobs run on threads of their own, so they get copies of what they need
+        let start = |step: Step| -> Job<IntegrationOutcome> {
+            match step {
+                Step::SwitchFrom(kind) => {
+                    let Some(from) = outgoing.cloned() else {
+                        return Box::new(|| Ok(IntegrationOutcome::default()));
+                    };
+                    let snapshot = snapshot(kind);
+                    debug!("Undoing integration {kind} of {}", from.key);
+                    Box::new(move || kind.switch_from(&from, snapshot.as_deref(), login))
+                }
+                Step::Apply(kind) => {
+                    let env = environment.clone();
+                    debug!("Applying integration {kind} for {}", env.key);
+                    Box::new(move || kind.apply(&env, login))
+                }
+                Step::Clear(kind) => Box::new(move || Ok(kind.clear()?.into())),
+            }
+        };
+        let undo = |action: UndoAction| -> Job<ApplyOutcome> {
+            match action {
+                UndoAction::Reapply(kind) => {
+                    let Some(from) = outgoing.cloned() else {
+                        return Box::new(|| Ok(ApplyOutcome::AlreadyInDesiredState));
+                    };
+                    Box::new(move || Ok(kind.apply(&from, false)?.apply))
+                }
+                UndoAction::Restore(kind) => {
+                    let env = environment.clone();
+                    let snapshot = snapshot(kind);
+                    Box::new(move || Ok(kind.switch_from(&env, snapshot.as_deref(), false)?.apply))
+                }
+                UndoAction::Clear(kind) => Box::new(move || kind.clear()),
+            }
+        };
+        // Called in step order once the jobs of a batch are done
+        let finish = |step: Step, result: &EnvMgrResult<IntegrationOutcome>| {
+            let failures = &mut state.integration_failures;
+            match (step, result) {
+                (Step::SwitchFrom(kind), Ok(outcome)) => {
+                    let from = outgoing.map_or("", |from| from.key.as_str());
+                    info!("{kind}: {} back to before {from}", outcome.apply);
+                    state.integration_snapshots.remove(kind.config_key());
+                }
+                (Step::Apply(kind), Ok(outcome)) => {
+                    info!("{kind}: {}", outcome.apply);
+                    quarantine::record_success(failures, &environment.key, kind);
+                }
+                (Step::Apply(kind), Err(_)) => {
+                    let hash = quarantine::config_hash(kind, environment);
+                    if quarantine::record_failure(
+                        failures,
+                        &environment.key,
+                        kind,
+                        &hash,
+                        threshold,
+                    ) {
+                        warn!(
+                            "{kind} failed {threshold} times in a row in {}, it is quarantined from now on",
+                            environment.key
+                        );
+                    }
+                }
+                (Step::Clear(kind), Ok(outcome)) if outcome.apply == ApplyOutcome::Changed => {
+                    info!("{kind}: cleared, {} doesn't configure it", environment.key);
+                }
+                _ => {}
+            }
+        };
+        run_steps(
+            steps,
+            opts.failure_policy,
+            |kind| global.integration_timeout(kind),
+            start,
+            finish,
+            undo,
+        )
+    }
+
+    /// Run `hook` of `environment` unless hooks are off. Only a failing pre-switch hook
+    /// is an error, the others are reported as warnings.
+    fn run_switch_hook(
+        hook: Hook,
+        environment: &Environment,
+        hook_env: &HookEnv,
+        opts: &SwitchOptions,
+    ) -> EnvMgrResult<()> {
+        let env_dir = environment.env_dir();
+        if !opts.hooks {
+            debug!("Hooks are disabled, not running {hook}");
+            return Ok(());
+        }
+        if opts.dry_run {
+            let path = hook.path(&env_dir);
+            if path.is_file() {
+                print_dry_run("hook", format_args!("run {}", path.display()));
+            }
+            return Ok(());
+        }
+        match run_hook(hook, &env_dir, hook_env) {
+            Ok(_) => Ok(()),
+            Err(reason) if hook == Hook::PreSwitch => Err(EnvMgrError::HookFailed {
+                hook: hook.to_string(),
+                env: environment.key.clone(),
+                reason,
+            }),
+            Err(reason) => {
+                warn!("The {hook} hook of {} failed: {reason}", environment.key);
+                Ok(())
+            }
+        }
+    }
+
+    /// Write the `fetched` 1Password documents of `environment` and remove the ones of
+    /// the previous environment, see [`OnePasswordDocuments::apply`]
+    fn place_documents(
+        environment: &Environment,
+        state: &mut State,
+        fetched: &[FetchedDocument],
+        dry_run: bool,
+    ) -> EnvMgrResult<()> {
+        if environment.op_documents.is_empty() && state.managed_documents.is_empty() {
+            return Ok(());
+        }
+        if dry_run {
+            let home = dirs::home_dir().ok_or(EnvMgrError::DirError("home".into()))?;
+            let mut targets = HashSet::new();
+            for document in &environment.op_documents {
+                let target = document.target_path(&home);
+                print_dry_run(
+                    "document",
+                    format_args!("write {} from {document}", target.display()),
+                );
+                targets.insert(target);
+            }
+            for managed in &state.managed_documents {
+                if !targets.contains(&managed.target) {
+                    print_dry_run(
+                        "document",
+                        format_args!("remove {} ({})", managed.target.display(), managed.item),
+                    );
+                }
+            }
+            return Ok(());
         }
+        let outcome = OnePasswordDocuments::apply(
+            state,
+            &environment.key,
+            fetched,
+            &RealFs,
+            State::store_state,
+        )?;
+        info!("op_documents: {outcome}");
+        Ok(())
+    }
 
-        if let Some(gh_cli_config) = environment.gh_cli.as_ref() {
-            crate::integrations::gh_cli::GhCli::on_switch_to(gh_cli_config)?;
+    /// Push the allowlisted variables of `environment` into the systemd user manager
+    /// and its environment.d file, and unset the ones an earlier switch pushed, if
+    /// propagation is enabled.
+    fn propagate_to_systemd_user(
+        environment: &Environment,
+        state: &mut State,
+        dry_run: bool,
+    ) -> EnvMgrResult<()> {
+        let global = GlobalConfig::load()?;
+        let vars = systemd_user_vars(environment, &global)?;
+        if vars.is_none() && state.systemd_user_env.is_empty() {
+            return Ok(());
+        }
+        if !cfg!(target_os = "linux") {
+            debug!("No systemd outside Linux, not propagating variables to it");
+            return Ok(());
         }
 
-        if let Some(tailscale_config) = environment.tailscale.as_ref() {
-            crate::integrations::tailscale::Tailscale::on_switch_to(tailscale_config)?;
+        let mut vars = vars.unwrap_or_default();
+        if !vars.is_empty() {
+            let dynamic = DynamicOptions {
+                skip: false,
+                timeout: Duration::from_secs(global.value_command_timeout_secs),
+                secrets: true,
+            };
+            let (resolved, errors) = resolve_dynamic_values(vars, &HashMap::new(), &dynamic);
+            vars = resolved;
+            for message in errors {
+                warn!("Not pushing to systemd: {message}");
+            }
         }
 
-        state.store_state()?;
-        Self::link_files()?;
+        let changes = plan_systemd_env(&vars, &state.systemd_user_env);
+        let file = systemd::environment_d_path();
+        let content = (!changes.set.is_empty())
+            .then(|| systemd::render_environment_d(&environment.key, &changes.set));
+        if dry_run {
+            for key in &changes.unset {
+                print_dry_run("systemd", format_args!("unset {key}"));
+            }
+            for (key, _) in &changes.set {
+                print_dry_run("systemd", format_args!("set {key}"));
+            }
+            if let Some(file) = &file {
+                let verb = if content.is_some() { "write" } else { "remove" };
+                print_dry_run("systemd", format_args!("{verb} {}", file.display()));
+            }
+            return Ok(());
+        }
+        if changes.is_empty() {
+            return Ok(());
+        }
+        match SystemdUser::new(&SystemRunner).apply(&changes)? {
+            SystemdOutcome::Applied => {
+                info!(
+                    "Updated the systemd user environment ({} set, {} unset)",
+                    changes.set.len(),
+                    changes.unset.len()
+                );
+                state.systemd_user_env = changes.applied_keys();
+                if let Some(file) = &file
+                    && systemd::write_environment_d(file, content.as_deref())?
+                {
+                    debug!("Updated {}", file.display());
+                }
+            }
+            SystemdOutcome::Unavailable(reason) => {
+                debug!("No systemd user manager, skipping: {reason}");
+            }
+        }
         Ok(())
     }
 
-    pub fn switch_environment_by_key(key: &str) -> EnvMgrResult<()> {
+    pub fn switch_environment_by_key(key: &str, opts: &SwitchOptions) -> EnvMgrResult<()> {
         let environment = Environment::load_environment_by_key(key)?;
 
         // Switch
-        Self::switch_environment(&environment)?;
+        Self::switch_environment(&environment, opts)?;
 
         Ok(())
     }
 
-    pub fn switch_base_environment() -> EnvMgrResult<()> {
+    /// Switch back to the environment that was active before the last switch
+    pub fn switch_previous_environment(opts: &SwitchOptions) -> EnvMgrResult<()> {
+        let state = State::get_state()?;
+        let previous_key = state
+            .previous_env_key
+            .ok_or(EnvMgrError::NoPreviousEnvironment)?;
+        info!("Returning to previous environment: {previous_key}");
+        Self::switch_environment(&Environment::load(&previous_key)?, opts)
+    }
+
+    pub fn switch_base_environment(opts: &SwitchOptions) -> EnvMgrResult<()> {
         let base_environment = Environment::load_base_environment()?;
 
-        Self::switch_environment(&base_environment)?;
+        Self::switch_environment(&base_environment, opts)?;
 
         Ok(())
     }
 
-    pub fn link_files() -> EnvMgrResult<()> {
-        let mut state = State::get_state()?;
+    /// Link files of the current environment.
+    ///
+    /// With `opts.dry_run` the plan is printed as one `[dry-run] link: ...` line per path,
+    /// e.g. `create ~/.bashrc` or `remove ~/.old (stale)`, ordered by path so runs can be
+    /// diffed, and neither the filesystem nor the state is touched.
+    pub fn link_files(opts: &LinkOptions) -> EnvMgrResult<()> {
+        Self::link_state(&mut State::get_state()?, LinkMode::Link, opts).map(|_| ())
+    }
 
-        let base_environment = Environment::load_base_environment()?;
-        let mut files_map = base_environment.files_to_link()?;
+    /// Link files of the current environment in `mode`, see [`Self::link_files`]
+    pub fn link_files_with(mode: LinkMode, dry_run: bool) -> EnvMgrResult<LinkReport> {
+        let opts = LinkOptions {
+            dry_run,
+            ..Default::default()
+        };
+        Self::link_state(&mut State::get_state()?, mode, &opts)
+    }
 
-        if state.current_env_key != BASE_ENV_NAME {
-            let environment = Environment::load_environment_by_key(&state.current_env_key)?;
-            files_map.extend(environment.files_to_link()?);
+    fn link_state(
+        state: &mut State,
+        mode: LinkMode,
+        opts: &LinkOptions,
+    ) -> EnvMgrResult<LinkReport> {
+        let (files_map, copies) = match mode {
+            LinkMode::Link => {
+                let base_environment = Environment::load_base_environment()?;
+                let environment = match state.current_env_key.as_str() {
+                    BASE_ENV_NAME => None,
+                    key => Some(Environment::load_environment_by_key(key)?),
+                };
+                let (files_map, conflicts) =
+                    layer_files_with_conflicts(&base_environment, environment.as_ref())?;
+                for conflict in conflicts
+                    .iter()
+                    .filter(|conflict| conflict.winner == FileLayer::Environment)
+                {
+                    info!(
+                        "{} overrides {} at {}",
+                        conflict.env_source.display(),
+                        conflict.base_source.display(),
+                        conflict.target.display()
+                    );
+                }
+                let copies = copy_targets(&base_environment, environment.as_ref(), &files_map);
+                (files_map, copies)
+            }
+            // Nothing is desired, so every managed link is stale
+            LinkMode::PruneOnly => (HashMap::new(), HashSet::new()),
+            LinkMode::Unlink { env_key } => Self::keep_all_but(state, &env_key),
+        };
+
+        let ctx = LinkContext::real(opts)?;
+        Self::apply_links(state, files_map, &copies, &ctx, opts.dry_run)
+    }
+
+    /// The managed links and copies of `state` (target -> source) that don't belong to
+    /// `env_key`, and the targets among them that are copies
+    fn keep_all_but(state: &State, env_key: &str) -> (HashMap<PathBuf, PathBuf>, HashSet<PathBuf>) {
+        let mut files_map = HashMap::new();
+        let mut unknown = 0;
+        for managed in &state.managed_files {
+            if managed.env_key == env_key {
+                continue;
+            }
+            // Entries without a source are kept as they link now
+            let source = match managed.source.as_os_str().is_empty() {
+                true => match std::fs::read_link(&managed.target) {
+                    Ok(source) => source,
+                    Err(_) => continue,
+                },
+                false => managed.source.clone(),
+            };
+            if managed.env_key.is_empty() {
+                unknown += 1;
+            }
+            files_map.insert(managed.target.clone(), source);
+        }
+        if unknown > 0 {
+            warn!(
+                "{unknown} managed link(s) were linked before their environment was recorded and are kept, `envmgr link` records it"
+            );
+        }
+        let owner_root = envmgr_config_dir();
+        let mut copies = HashSet::new();
+        for copied in &state.copied_files {
+            if source_env_key(&copied.source, &owner_root) != env_key {
+                files_map.insert(copied.target.clone(), copied.source.clone());
+                copies.insert(copied.target.clone());
+            }
         }
+        (files_map, copies)
+    }
 
-        for managed_file in state
+    /// Make the links in `files_map` (target -> source) the only managed links, copying
+    /// the targets in `copies` instead of symlinking them.
+    ///
+    /// Every link that may exist afterwards is added to `managed_files`, and every copy to
+    /// `copied_files`, and every backup to `backups`, and stored before the first change,
+    /// so an interrupted run leaves no link, copy or backup the state doesn't know about.
+    fn apply_links(
+        state: &mut State,
+        mut files_map: HashMap<PathBuf, PathBuf>,
+        copies: &HashSet<PathBuf>,
+        ctx: &LinkContext,
+        dry_run: bool,
+    ) -> EnvMgrResult<LinkReport> {
+        let fs = ctx.fs;
+        files_map.retain(|target, _| ctx.filter.touches(target));
+        // What envmgr last wrote to each copy, before the placeholders below
+        let written: HashMap<PathBuf, String> = state
+            .copied_files
+            .iter()
+            .map(|copied| (copied.target.clone(), copied.hash.clone()))
+            .collect();
+        // Real files to move aside (target -> backup)
+        let backups: HashMap<PathBuf, PathBuf> = match ctx.adopt_backups {
+            true => {
+                let now = unix_now();
+                files_map
+                    .keys()
+                    .filter(|target| !copies.contains(*target))
+                    .filter(|target| target.symlink_metadata().is_ok_and(|m| m.is_file()))
+                    .map(|target| {
+                        let path = backup_path(
+                            &ctx.state_dir,
+                            &state.current_env_key,
+                            now,
+                            &ctx.home,
+                            target,
+                        );
+                        (target.clone(), path)
+                    })
+                    .collect()
+            }
+            false => HashMap::new(),
+        };
+        // Symlinks in `files/` are re-created as they are
+        let mirrors = Mirrors {
+            wanted: files_map
+                .par_iter()
+                .filter(|(target, _)| !copies.contains(*target))
+                .filter_map(|(target, source)| {
+                    let link = std::fs::read_link(source).ok()?;
+                    Some((target.clone(), link))
+                })
+                .collect(),
+            created: created_mirrors(state),
+        };
+        let managed: Vec<ManagedFile> = state
             .managed_files
             .iter()
-            .filter(|f| !files_map.contains_key(*f))
-        {
-            // Remove previously managed dangling symlink.
-            if managed_file.is_symlink() {
-                info!("Removing stale symlink: {}", managed_file.display());
-                std::fs::remove_file(managed_file)?;
-            } else if managed_file.exists() {
-                warn!(
-                    "Managed file exists and is not a symlink, skipping removal: {}",
-                    managed_file.display()
-                );
+            .filter(|managed| ctx.filter.touches(&managed.target))
+            .cloned()
+            .collect();
+        let stale = plan_stale_links(&managed, &files_map, &mirrors, &ctx.owner_root, &FsReadLink);
+        if !dry_run {
+            // A run interrupted before moving the file recorded a backup that never happened
+            state
+                .backups
+                .retain(|backup| !backups.contains_key(&backup.target) || backup.path.exists());
+            let mut pending: Vec<_> = backups.iter().collect();
+            pending.sort();
+            for (target, path) in pending {
+                state.backups.push(Backup {
+                    target: target.clone(),
+                    path: path.clone(),
+                    env_key: state.current_env_key.clone(),
+                    created_at: unix_now(),
+                });
+            }
+            // In path order, so the state stays the same between runs
+            let mut targets: Vec<_> = files_map.iter().collect();
+            targets.sort();
+            let mut managed: HashMap<PathBuf, usize> = state
+                .managed_files
+                .iter()
+                .enumerate()
+                .map(|(i, managed)| (managed.target.clone(), i))
+                .collect();
+            for (target, source) in targets {
+                if !copies.contains(target) {
+                    // Recorded with the source it is about to link to
+                    let entry = ctx.managed_file(target.clone(), source);
+                    match managed.get(target) {
+                        Some(&i) => state.managed_files[i] = entry,
+                        None => {
+                            managed.insert(target.clone(), state.managed_files.len());
+                            state.managed_files.push(entry);
+                        }
+                    }
+                    if source.is_dir() && !state.linked_dirs.contains(target) {
+                        state.linked_dirs.push(target.clone());
+                    }
+                } else if !written.contains_key(target) {
+                    state.copied_files.push(CopiedFile {
+                        target: target.clone(),
+                        source: source.clone(),
+                        hash: hash_content(&Self::read_source(fs, source)?),
+                    });
+                }
+            }
+            let mut wanted: Vec<_> = mirrors.wanted.iter().collect();
+            wanted.sort();
+            for (target, link) in wanted {
+                let mirrored = MirroredLink {
+                    target: target.clone(),
+                    link: link.clone(),
+                };
+                if !state.mirrored_links.contains(&mirrored) {
+                    state.mirrored_links.push(mirrored);
+                }
             }
+            state.applying = Some(state.current_env_key.clone());
+            ctx.store(state)?;
         }
+        let mut report =
+            Self::remove_stale_links(state, stale, &files_map, &ctx.filter, fs, dry_run)?;
+        let copies_report =
+            Self::remove_stale_copies(state, copies, &ctx.filter, &ctx.owner_root, fs, dry_run)?;
+        report.removed += copies_report.removed;
+        report.skipped += copies_report.skipped;
 
-        state.managed_files.clear();
+        // Planned only now, a removed copy frees its target for the link
+        let mut links: HashMap<PathBuf, LinkAction> = plan_links(
+            &files_map,
+            copies,
+            &backups,
+            &mirrors,
+            &ctx.owner_root,
+            &FsReadLink,
+        )
+        .into_iter()
+        .map(|action| (action.target().to_path_buf(), action))
+        .collect();
+        let mut files: Vec<_> = files_map.into_iter().collect();
+        files.sort();
+        for (target_path, source_path) in files {
+            if let Some(action) = links.remove(&target_path) {
+                Self::perform_link(state, action, &source_path, ctx, dry_run, &mut report)?;
+                continue;
+            }
+            let hash = written.get(&target_path).map(String::as_str);
+            Self::place_copy(
+                state,
+                &target_path,
+                &source_path,
+                hash,
+                ctx,
+                dry_run,
+                &mut report,
+            )?;
+        }
+
+        if !dry_run {
+            // Only the mirrors that are in place now stay envmgr's
+            let managed: HashSet<&PathBuf> = state
+                .managed_files
+                .iter()
+                .map(|managed| &managed.target)
+                .collect();
+            state.mirrored_links.retain(|mirrored| {
+                !ctx.filter.touches(&mirrored.target)
+                    || managed.contains(&mirrored.target)
+                        && mirrors.wanted.get(&mirrored.target) == Some(&mirrored.link)
+            });
+            // Those left alone by a filtered run come first otherwise
+            state.managed_files.sort_by(|a, b| a.target.cmp(&b.target));
+            state.applying = None;
+            ctx.store(state)?;
+        }
+
+        Ok(report)
+    }
+
+    /// Carry out `action` for a wanted link of `files_source`, only printing it with
+    /// `dry_run`
+    fn perform_link(
+        state: &mut State,
+        action: LinkAction,
+        files_source: &Path,
+        ctx: &LinkContext,
+        dry_run: bool,
+        report: &mut LinkReport,
+    ) -> EnvMgrResult<()> {
+        let fs = ctx.fs;
+        if dry_run && let Some(line) = action.describe(ctx.verbose) {
+            print_dry_run("link", line);
+        }
+        let (target, source) = match action {
+            LinkAction::Keep { target, source } => {
+                debug!(
+                    "Symlink already exists and is correct: {} -> {}",
+                    target.display(),
+                    source.display()
+                );
+                state
+                    .managed_files
+                    .push(ctx.managed_file(target, files_source));
+                return Ok(());
+            }
+            LinkAction::Skip {
+                target,
+                resolution: ChainResolution::NotALink,
+                ..
+            } => {
+                // A real file/dir exists at the target and it's not a symlink – do not overwrite
+                warn!(
+                    "Target path exists and is not a symlink, skipping: {}",
+                    target.display()
+                );
+                return Ok(());
+            }
+            LinkAction::Skip {
+                target, resolution, ..
+            } => {
+                warn!(
+                    "Target path is a symlink not managed by envmgr ({resolution:?}), skipping: {}",
+                    target.display()
+                );
+                report.skipped += 1;
+                return Ok(());
+            }
+            _ if dry_run => {
+                report.created += 1;
+                return Ok(());
+            }
+            LinkAction::Update {
+                target,
+                old,
+                source,
+            } => {
+                // Only the link at the target is replaced, intermediate hops are left alone
+                info!(
+                    "Updating symlink: {} (was {}) -> {}",
+                    target.display(),
+                    old.display(),
+                    source.display()
+                );
+                // Renamed over the old link, so the target never goes missing
+                fs.replace_symlink(&source, &target)?;
+                report.created += 1;
+                state
+                    .managed_files
+                    .push(ctx.managed_file(target, files_source));
+                return Ok(());
+            }
+            LinkAction::Create { target, source } => {
+                if let Some(parent) = target.parent()
+                    && !parent.exists()
+                {
+                    info!("Creating parent directory: {}", parent.display());
+                    fs.create_dir_all(parent)?;
+                }
+                (target, source)
+            }
+            LinkAction::Adopt {
+                target,
+                source,
+                backup,
+            } => {
+                info!("Moving {} aside to {}", target.display(), backup.display());
+                move_file(fs, &target, &backup)?;
+                (target, source)
+            }
+            LinkAction::Remove { .. } | LinkAction::Forget { .. } => {
+                unreachable!("stale links are handled by remove_stale_links")
+            }
+        };
 
-        for (target_path, source_path) in files_map {
-            let mut need_link = true;
+        report.created += 1;
+        info!(
+            "Creating symlink: {} -> {}",
+            target.display(),
+            source.display()
+        );
+        fs.symlink(&source, &target).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::AlreadyExists {
+                EnvMgrError::LinkConflict(target.clone())
+            } else {
+                e.into()
+            }
+        })?;
+        state
+            .managed_files
+            .push(ctx.managed_file(target, files_source));
+        Ok(())
+    }
 
-            if target_path.is_symlink() {
-                // Handle both valid and dangling symlinks
-                let existing_link = std::fs::read_link(&target_path)?;
-                if existing_link == source_path {
-                    debug!(
-                        "Symlink already exists and is correct: {} -> {}",
-                        target_path.display(),
-                        source_path.display()
+    /// Carry out the `stale` actions of a plan and clear the entries `filter` touches from
+    /// `state.managed_files`, keeping only the directory links among `desired` of them in
+    /// `state.linked_dirs`.
+    ///
+    /// Only symlinks whose chain still ends at their recorded source are removed; real
+    /// files and links that something else has since replaced are left alone and counted
+    /// as skipped. The latest backup of a target whose link is gone is put back.
+    fn remove_stale_links(
+        state: &mut State,
+        stale: Vec<LinkAction>,
+        desired: &HashMap<PathBuf, PathBuf>,
+        filter: &TargetFilter,
+        fs: &dyn Fs,
+        dry_run: bool,
+    ) -> EnvMgrResult<LinkReport> {
+        let mut report = LinkReport::default();
+        state
+            .managed_files
+            .retain(|managed| !filter.touches(&managed.target));
+        for action in stale {
+            if dry_run && let Some(line) = action.describe(false) {
+                print_dry_run("link", line);
+            }
+            match action {
+                LinkAction::Forget { target } => {
+                    Self::restore_latest_backup(state, &target, fs, dry_run)?;
+                }
+                LinkAction::Remove { target } => {
+                    if !dry_run {
+                        let kind = match state.linked_dirs.contains(&target) {
+                            true => "directory symlink",
+                            false => "symlink",
+                        };
+                        info!("Removing stale {kind}: {}", target.display());
+                        fs.remove_file(&target)?;
+                    }
+                    report.removed += 1;
+                    Self::restore_latest_backup(state, &target, fs, dry_run)?;
+                }
+                LinkAction::Skip {
+                    target,
+                    resolution: ChainResolution::NotALink,
+                    ..
+                } => {
+                    warn!(
+                        "Managed file exists and is not a symlink, skipping removal: {}",
+                        target.display()
                     );
-                    state.managed_files.push(target_path.clone());
-                    need_link = false;
+                    report.skipped += 1;
+                }
+                LinkAction::Skip {
+                    target, resolution, ..
+                } => {
+                    warn!(
+                        "Managed symlink no longer leads to what envmgr linked ({resolution:?}), skipping removal: {}",
+                        target.display()
+                    );
+                    report.skipped += 1;
+                }
+                action => unreachable!("{action:?} is not about a stale link"),
+            }
+        }
+        state.linked_dirs.retain(|dir| {
+            !filter.touches(dir) || desired.get(dir).is_some_and(|source| source.is_dir())
+        });
+        Ok(report)
+    }
+
+    /// Put the latest backup of `path`, a link target or a backup file, back in place of
+    /// envmgr's link and forget it. Returns the restored target.
+    pub fn restore_backup(path: &Path, dry_run: bool) -> EnvMgrResult<PathBuf> {
+        let path = std::path::absolute(path)?;
+        let mut state = State::get_state()?;
+        let index = latest_backup(&state.backups, &path)
+            .ok_or_else(|| EnvMgrError::BackupNotFound(path.clone()))?;
+        let backup = &state.backups[index];
+        if backup.path.symlink_metadata().is_err() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("backup {} no longer exists", backup.path.display()),
+            )
+            .into());
+        }
+        let target = backup.target.clone();
+        let mirrors = Mirrors {
+            created: created_mirrors(&state),
+            ..Mirrors::default()
+        };
+        let mirrored = mirrors.created_link(&target, &FsReadLink).is_some();
+        match resolve_chain(&target, &envmgr_config_dir(), &FsReadLink) {
+            ChainResolution::Absent => {}
+            resolution if resolution.is_owned() || mirrored => {
+                if dry_run {
+                    print_dry_run("link", format_args!("remove {}", target.display()));
                 } else {
-                    info!(
-                        "Updating symlink: {} (was {}) -> {}",
-                        target_path.display(),
-                        existing_link.display(),
-                        source_path.display()
+                    info!("Removing symlink: {}", target.display());
+                    RealFs.remove_file(&target)?;
+                    state
+                        .managed_files
+                        .retain(|managed| managed.target != target);
+                    state.linked_dirs.retain(|dir| *dir != target);
+                    state
+                        .mirrored_links
+                        .retain(|mirrored| mirrored.target != target);
+                }
+            }
+            _ => return Err(EnvMgrError::LinkConflict(target)),
+        }
+        Self::put_back(&mut state, index, &RealFs, dry_run)?;
+        if !dry_run {
+            state.store_state()?;
+        }
+        Ok(target)
+    }
+
+    /// Move the latest backup of `target` back to it and forget the backup
+    fn restore_latest_backup(
+        state: &mut State,
+        target: &Path,
+        fs: &dyn Fs,
+        dry_run: bool,
+    ) -> EnvMgrResult<()> {
+        match state.backups.iter().rposition(|b| b.target == target) {
+            Some(index) => Self::put_back(state, index, fs, dry_run),
+            None => Ok(()),
+        }
+    }
+
+    /// Move `state.backups[index]` back to its target and forget it
+    fn put_back(state: &mut State, index: usize, fs: &dyn Fs, dry_run: bool) -> EnvMgrResult<()> {
+        let backup = &state.backups[index];
+        let target = &backup.target;
+        if dry_run {
+            print_dry_run(
+                "link",
+                format_args!("restore {} <- {}", target.display(), backup.path.display()),
+            );
+            return Ok(());
+        }
+        if backup.path.symlink_metadata().is_err() {
+            warn!(
+                "Backup of {} is gone, forgetting it: {}",
+                target.display(),
+                backup.path.display()
+            );
+        } else {
+            info!(
+                "Restoring {} from {}",
+                target.display(),
+                backup.path.display()
+            );
+            move_file(fs, &backup.path, target)?;
+        }
+        state.backups.remove(index);
+        Ok(())
+    }
+
+    fn read_source(fs: &dyn Fs, source: &Path) -> EnvMgrResult<Vec<u8>> {
+        fs.read(source)?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} no longer exists", source.display()),
+            )
+            .into()
+        })
+    }
+
+    /// Copy `source` to `target`, or bring an earlier copy up to date, and record it.
+    ///
+    /// `written` is the hash of what envmgr last copied there. A target with other content
+    /// was changed by someone else and is left alone.
+    fn place_copy(
+        state: &mut State,
+        target: &Path,
+        source: &Path,
+        written: Option<&str>,
+        ctx: &LinkContext,
+        dry_run: bool,
+        report: &mut LinkReport,
+    ) -> EnvMgrResult<()> {
+        let fs = ctx.fs;
+        let content = Self::read_source(fs, source)?;
+        let hash = hash_content(&content);
+        let action = match resolve_chain(target, &ctx.owner_root, &FsReadLink) {
+            ChainResolution::Absent => "create",
+            ChainResolution::NotALink if target.is_dir() => {
+                warn!(
+                    "Target path is a directory, skipping copy: {}",
+                    target.display()
+                );
+                report.skipped += 1;
+                return Self::record_copy(state, target, source, written, dry_run);
+            }
+            ChainResolution::NotALink => {
+                let current = fs.read(target)?.map(|current| hash_content(&current));
+                match current.as_deref() {
+                    Some(current) if current == hash => {
+                        debug!("Copy is up to date: {}", target.display());
+                        return Self::record_copy(state, target, source, Some(&hash), dry_run);
+                    }
+                    Some(current) if Some(current) == written => "update",
+                    _ => {
+                        if dry_run {
+                            print_dry_run(
+                                "link",
+                                format_args!("skip {} (changed since copied)", target.display()),
+                            );
+                        }
+                        warn!(
+                            "{} was changed since envmgr copied it, not overwriting it",
+                            target.display()
+                        );
+                        report.skipped += 1;
+                        return Self::record_copy(state, target, source, written, dry_run);
+                    }
+                }
+            }
+            // A link of an earlier run in symlink mode
+            resolution if resolution.is_owned() => "replace link with",
+            resolution => {
+                warn!(
+                    "Target path is a symlink not managed by envmgr ({resolution:?}), skipping copy: {}",
+                    target.display()
+                );
+                report.skipped += 1;
+                return Self::record_copy(state, target, source, written, dry_run);
+            }
+        };
+
+        report.created += 1;
+        if dry_run {
+            print_dry_run(
+                "link",
+                format_args!("{action} copy {} <- {}", target.display(), source.display()),
+            );
+            return Ok(());
+        }
+        info!(
+            "Copying {} to {} ({action})",
+            source.display(),
+            target.display()
+        );
+        fs.write_atomic(target, &content)?;
+        Self::record_copy(state, target, source, Some(&hash), dry_run)
+    }
+
+    /// Make `hash` the recorded content of the copy at `target`, forgetting it on `None`
+    fn record_copy(
+        state: &mut State,
+        target: &Path,
+        source: &Path,
+        hash: Option<&str>,
+        dry_run: bool,
+    ) -> EnvMgrResult<()> {
+        if dry_run {
+            return Ok(());
+        }
+        state.copied_files.retain(|copied| copied.target != target);
+        if let Some(hash) = hash {
+            state.copied_files.push(CopiedFile {
+                target: target.to_path_buf(),
+                source: source.to_path_buf(),
+                hash: hash.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Remove copies whose target `filter` touches but is not in `desired`, and forget
+    /// them.
+    ///
+    /// A copy is only removed while it has the content envmgr wrote; edited copies are
+    /// left in place and counted as skipped.
+    fn remove_stale_copies(
+        state: &mut State,
+        desired: &HashSet<PathBuf>,
+        filter: &TargetFilter,
+        owner_root: &Path,
+        fs: &dyn Fs,
+        dry_run: bool,
+    ) -> EnvMgrResult<LinkReport> {
+        let mut report = LinkReport::default();
+        let (keep, stale): (Vec<CopiedFile>, Vec<CopiedFile>) =
+            std::mem::take(&mut state.copied_files)
+                .into_iter()
+                .partition(|copied| {
+                    desired.contains(&copied.target) || !filter.touches(&copied.target)
+                });
+        state.copied_files = keep;
+        for copied in stale {
+            let target = &copied.target;
+            match resolve_chain(target, owner_root, &FsReadLink) {
+                ChainResolution::Absent => continue,
+                ChainResolution::NotALink if !target.is_dir() => {}
+                _ => {
+                    warn!(
+                        "Copied file was replaced, skipping removal: {}",
+                        target.display()
                     );
-                    std::fs::remove_file(&target_path)?;
+                    report.skipped += 1;
+                    continue;
                 }
-            } else if target_path.exists() {
-                // A real file/dir exists at the target and it's not a symlink – do not overwrite
+            }
+            let current = fs.read(target)?.map(|current| hash_content(&current));
+            if current.as_ref() != Some(&copied.hash) {
                 warn!(
-                    "Target path exists and is not a symlink, skipping: {}",
-                    target_path.display()
+                    "{} was changed since envmgr copied it, leaving it in place",
+                    target.display()
                 );
-                need_link = false;
-            } else if let Some(parent) = target_path.parent()
-                && !parent.exists()
-            {
-                info!("Creating parent directory: {}", parent.display());
-                std::fs::create_dir_all(parent)?;
+                report.skipped += 1;
+            } else if dry_run {
+                print_dry_run("link", format_args!("remove copy {}", target.display()));
+                report.removed += 1;
+            } else {
+                info!("Removing stale copy: {}", target.display());
+                fs.remove_file(target)?;
+                report.removed += 1;
             }
+        }
+        Ok(report)
+    }
+}
 
-            if need_link {
-                info!(
-                    "Creating symlink: {} -> {}",
-                    target_path.display(),
-                    source_path.display()
+/// Print a change `--dry-run` would have made, as a stable `[dry-run] <area>: <change>` line
+fn print_dry_run(area: &str, change: impl std::fmt::Display) {
+    println!("[dry-run] {area}: {change}");
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        environment::backups,
+        fs::{CrashingFs, temp_link_path},
+    };
+
+    #[test]
+    fn test_file_conflicts_with_nested_paths() {
+        let dir = std::env::temp_dir().join("envmgr_test_file_conflicts");
+        let _ = fs::remove_dir_all(&dir);
+        let base_files = dir.join("base/files");
+        let env_files = dir.join("work/files");
+        for file in [
+            "base/files/.gitconfig",
+            "base/files/.config/git/ignore",
+            "base/files/.config/nvim/init.lua",
+            "base/files/.config/fish/config.fish",
+            "work/files/.gitconfig",
+            "work/files/.config/git/config",
+            "work/files/.config/nvim/lua/work.lua",
+            "work/files/.config/fish/conf.d/work.fish",
+        ] {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        let home = dir.join("home");
+        let layer = |files_dir: &Path, entries: &[&str]| -> HashMap<PathBuf, PathBuf> {
+            entries
+                .iter()
+                .map(|entry| (home.join(entry), files_dir.join(entry)))
+                .collect()
+        };
+        // Base links nvim as a whole, work links fish as a whole
+        let base = layer(
+            &base_files,
+            &[
+                ".gitconfig",
+                ".config/git/ignore",
+                ".config/nvim",
+                ".config/fish/config.fish",
+            ],
+        );
+        let work = layer(
+            &env_files,
+            &[
+                ".gitconfig",
+                ".config/git/config",
+                ".config/nvim/lua/work.lua",
+                ".config/fish",
+            ],
+        );
+
+        let conflicts: Vec<_> = file_conflicts(&base, &work)
+            .into_iter()
+            .map(|conflict| {
+                (
+                    conflict.target.strip_prefix(&home).unwrap().to_path_buf(),
+                    conflict
+                        .base_source
+                        .strip_prefix(&base_files)
+                        .unwrap()
+                        .to_path_buf(),
+                    conflict
+                        .env_source
+                        .strip_prefix(&env_files)
+                        .unwrap()
+                        .to_path_buf(),
+                    conflict.winner,
+                )
+            })
+            .collect();
+        assert_eq!(
+            conflicts,
+            [
+                (
+                    ".config/fish/config.fish".into(),
+                    ".config/fish/config.fish".into(),
+                    ".config/fish".into(),
+                    FileLayer::Environment
+                ),
+                (
+                    ".config/nvim/lua/work.lua".into(),
+                    ".config/nvim".into(),
+                    ".config/nvim/lua/work.lua".into(),
+                    FileLayer::Base
+                ),
+                (
+                    ".gitconfig".into(),
+                    ".gitconfig".into(),
+                    ".gitconfig".into(),
+                    FileLayer::Environment
+                ),
+            ]
+        );
+        assert_eq!(file_conflicts(&base, &HashMap::new()), []);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_prune_removes_only_owned_links() {
+        let dir = std::env::temp_dir().join("envmgr_test_prune_owned_links");
+        let _ = fs::remove_dir_all(&dir);
+        let owner_root = dir.join("config");
+        let home = dir.join("home");
+        fs::create_dir_all(&owner_root).unwrap();
+        fs::create_dir_all(&home).unwrap();
+
+        let source = owner_root.join("gitconfig");
+        let foreign_source = dir.join("elsewhere");
+        fs::write(&source, "").unwrap();
+        fs::write(&foreign_source, "").unwrap();
+
+        let owned = home.join(".gitconfig");
+        let dangling = home.join(".dangling");
+        let foreign = home.join(".foreign");
+        let real_file = home.join(".real");
+        platform::symlink(&source, &owned).unwrap();
+        platform::symlink(&owner_root.join("deleted"), &dangling).unwrap();
+        platform::symlink(&foreign_source, &foreign).unwrap();
+        fs::write(&real_file, "user content").unwrap();
+
+        let mut state = State {
+            managed_files: [
+                &owned,
+                &dangling,
+                &foreign,
+                &real_file,
+                &home.join(".already-gone"),
+            ]
+            .map(|target| ManagedFile::legacy(target.clone()))
+            .to_vec(),
+            ..State::default()
+        };
+
+        let desired = HashMap::new();
+        let stale = plan_stale_links(
+            &state.managed_files,
+            &desired,
+            &Mirrors::default(),
+            &owner_root,
+            &FsReadLink,
+        );
+        let report = EnvironmentManager::remove_stale_links(
+            &mut state,
+            stale,
+            &desired,
+            &TargetFilter::default(),
+            &RealFs,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(report.removed, 2);
+        assert_eq!(report.skipped, 2);
+        assert!(!owned.is_symlink());
+        assert!(!dangling.is_symlink());
+        assert!(foreign.is_symlink());
+        assert_eq!(fs::read_to_string(&real_file).unwrap(), "user content");
+        assert!(state.managed_files.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stale_removal_keeps_desired_links() {
+        let dir = std::env::temp_dir().join("envmgr_test_prune_keeps_desired");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source");
+        let target = dir.join("target");
+        fs::write(&source, "").unwrap();
+        platform::symlink(&source, &target).unwrap();
+
+        let mut state = State {
+            managed_files: vec![ManagedFile::legacy(target.clone())],
+            ..State::default()
+        };
+        let desired = HashMap::from([(target.clone(), source)]);
+
+        let stale = plan_stale_links(
+            &state.managed_files,
+            &desired,
+            &Mirrors::default(),
+            &dir,
+            &FsReadLink,
+        );
+        let report = EnvironmentManager::remove_stale_links(
+            &mut state,
+            stale,
+            &desired,
+            &TargetFilter::default(),
+            &RealFs,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(report, LinkReport::default());
+        assert!(target.is_symlink());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A config root with one source file and its target under home
+    struct CopyScenario {
+        dir: PathBuf,
+        source: PathBuf,
+        target: PathBuf,
+    }
+
+    impl CopyScenario {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(name);
+            let _ = fs::remove_dir_all(&dir);
+            let source = dir.join("config/base/files/.config/app/settings.json");
+            fs::create_dir_all(source.parent().unwrap()).unwrap();
+            fs::create_dir_all(dir.join("home")).unwrap();
+            fs::write(&source, "v1").unwrap();
+            let target = dir.join("home/.config/app/settings.json");
+            Self {
+                dir,
+                source,
+                target,
+            }
+        }
+
+        /// Link the file, copying it with `copy`, or link nothing without `desired`
+        fn link(&self, state: &mut State, desired: bool, copy: bool) -> LinkReport {
+            self.link_with(state, desired, copy, false)
+        }
+
+        fn link_with(
+            &self,
+            state: &mut State,
+            desired: bool,
+            copy: bool,
+            adopt_backups: bool,
+        ) -> LinkReport {
+            let ctx = LinkContext {
+                fs: &RealFs,
+                owner_root: self.dir.join("config"),
+                state_dir: self.dir.join("state"),
+                dual_write: false,
+                adopt_backups,
+                home: self.dir.join("home"),
+                verbose: false,
+                filter: TargetFilter::default(),
+            };
+            let mut files_map = HashMap::new();
+            let mut copies = HashSet::new();
+            if desired {
+                files_map.insert(self.target.clone(), self.source.clone());
+                if copy {
+                    copies.insert(self.target.clone());
+                }
+            }
+            EnvironmentManager::apply_links(state, files_map, &copies, &ctx, false).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_copies_follow_the_source_but_keep_user_edits() {
+        let scenario = CopyScenario::new("envmgr_test_copy_mode");
+        let target = &scenario.target;
+        let mut state = State::default();
+
+        assert_eq!(scenario.link(&mut state, true, true).created, 1);
+        assert!(!target.is_symlink());
+        assert_eq!(fs::read_to_string(target).unwrap(), "v1");
+        assert!(state.managed_files.is_empty());
+        assert_eq!(state.copied_files.len(), 1);
+        assert_eq!(scenario.link(&mut state, true, true), LinkReport::default());
+
+        fs::write(&scenario.source, "v2").unwrap();
+        assert_eq!(scenario.link(&mut state, true, true).created, 1);
+        assert_eq!(fs::read_to_string(target).unwrap(), "v2");
+
+        // An edited copy survives source changes and switching away
+        fs::write(target, "edited").unwrap();
+        fs::write(&scenario.source, "v3").unwrap();
+        assert_eq!(scenario.link(&mut state, true, true).skipped, 1);
+        assert_eq!(fs::read_to_string(target).unwrap(), "edited");
+        let report = scenario.link(&mut state, false, false);
+        assert_eq!((report.removed, report.skipped), (0, 1));
+        assert_eq!(fs::read_to_string(target).unwrap(), "edited");
+        assert!(state.copied_files.is_empty());
+
+        // An untouched one is removed
+        fs::remove_file(target).unwrap();
+        scenario.link(&mut state, true, true);
+        assert_eq!(scenario.link(&mut state, false, false).removed, 1);
+        assert!(!target.exists());
+        assert!(state.copied_files.is_empty());
+
+        fs::remove_dir_all(&scenario.dir).unwrap();
+    }
+
+    #[test]
+    fn test_changing_link_mode_replaces_the_target() {
+        let scenario = CopyScenario::new("envmgr_test_copy_mode_switch");
+        let target = &scenario.target;
+        let mut state = State::default();
+
+        scenario.link(&mut state, true, false);
+        assert!(target.is_symlink());
+
+        assert_eq!(scenario.link(&mut state, true, true).created, 1);
+        assert!(!target.is_symlink());
+        assert_eq!(fs::read_to_string(target).unwrap(), "v1");
+        assert!(state.managed_files.is_empty());
+
+        let report = scenario.link(&mut state, true, false);
+        assert_eq!((report.removed, report.created), (1, 1));
+        assert!(target.is_symlink());
+        assert!(state.copied_files.is_empty());
+        assert_eq!(state.managed_target_paths(), std::slice::from_ref(target));
+        assert_eq!(state.managed_files[0].source, scenario.source);
+        assert_eq!(state.managed_files[0].env_key, BASE_ENV_NAME);
+
+        fs::remove_dir_all(&scenario.dir).unwrap();
+    }
+
+    #[test]
+    fn test_backups_of_nested_targets_are_restored_when_unlinking() {
+        let scenario = CopyScenario::new("envmgr_test_backup_nested");
+        let target = &scenario.target;
+        fs::create_dir_all(target.parent().unwrap()).unwrap();
+        fs::write(target, "user settings").unwrap();
+        let mut state = State::default();
+
+        // Without opting in, the real file blocks the link
+        scenario.link(&mut state, true, false);
+        assert!(!target.is_symlink());
+        assert!(state.backups.is_empty());
+
+        assert_eq!(scenario.link_with(&mut state, true, false, true).created, 1);
+        assert!(target.is_symlink());
+        let [backup] = state.backups.as_slice() else {
+            panic!("expected one backup, got {:?}", state.backups);
+        };
+        assert_eq!(backup.target, *target);
+        assert_eq!(backup.env_key, BASE_ENV_NAME);
+        let relative = backup
+            .path
+            .strip_prefix(scenario.dir.join("state/backups/base"))
+            .unwrap();
+        assert!(
+            relative.ends_with(".config/app/settings.json"),
+            "{relative:?}"
+        );
+        assert_eq!(fs::read_to_string(&backup.path).unwrap(), "user settings");
+        let backup_path = backup.path.clone();
+
+        assert_eq!(scenario.link(&mut state, false, false).removed, 1);
+        assert!(!target.is_symlink());
+        assert_eq!(fs::read_to_string(target).unwrap(), "user settings");
+        assert!(!backup_path.exists());
+        assert!(state.backups.is_empty());
+
+        fs::remove_dir_all(&scenario.dir).unwrap();
+    }
+
+    #[test]
+    fn test_repeated_backups_restore_the_latest() {
+        let scenario = CopyScenario::new("envmgr_test_backup_repeated");
+        let target = &scenario.target;
+        fs::create_dir_all(target.parent().unwrap()).unwrap();
+        fs::write(target, "first").unwrap();
+        let mut state = State::default();
+        scenario.link_with(&mut state, true, false, true);
+
+        // Something replaced the link with a real file again
+        fs::remove_file(target).unwrap();
+        fs::write(target, "second").unwrap();
+        scenario.link_with(&mut state, true, false, true);
+        assert!(target.is_symlink());
+        assert_eq!(state.backups.len(), 2);
+        assert_ne!(state.backups[0].path, state.backups[1].path);
+
+        scenario.link(&mut state, false, false);
+        assert_eq!(fs::read_to_string(target).unwrap(), "second");
+        // The older one stays until restored by hand
+        let [older] = state.backups.as_slice() else {
+            panic!("expected one backup left, got {:?}", state.backups);
+        };
+        assert_eq!(fs::read_to_string(&older.path).unwrap(), "first");
+        assert_eq!(backups::latest_backup(&state.backups, target), Some(0));
+
+        fs::remove_dir_all(&scenario.dir).unwrap();
+    }
+
+    /// A config root where `base` links two files and `work` overrides one of them and
+    /// adds a nested one, with gh's hosts file standing in for integration writes
+    struct CrashScenario {
+        root: PathBuf,
+    }
+
+    impl CrashScenario {
+        fn new(root: PathBuf) -> Self {
+            let _ = fs::remove_dir_all(&root);
+            let scenario = Self { root };
+            for (path, content) in [
+                ("config/base/files/.baserc", "base"),
+                ("config/base/files/.shared", "shared from base"),
+                ("config/work/files/.shared", "shared from work"),
+                ("config/work/files/.config/app/workrc", "work"),
+                ("home/.config/gh/hosts.yml", "user: personal\n"),
+            ] {
+                let path = scenario.root.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, content).unwrap();
+            }
+            let mut state = State::default();
+            EnvironmentManager::apply_links(
+                &mut state,
+                scenario.desired(BASE_ENV_NAME),
+                &HashSet::new(),
+                &scenario.ctx(&RealFs),
+                false,
+            )
+            .unwrap();
+            scenario
+        }
+
+        fn ctx<'a>(&self, fs: &'a dyn Fs) -> LinkContext<'a> {
+            LinkContext {
+                fs,
+                owner_root: self.root.join("config"),
+                state_dir: self.root.join("state"),
+                dual_write: true,
+                adopt_backups: false,
+                home: self.root.join("home"),
+                verbose: false,
+                filter: TargetFilter::default(),
+            }
+        }
+
+        fn hosts_file(&self) -> PathBuf {
+            self.root.join("home/.config/gh/hosts.yml")
+        }
+
+        /// Links (target -> source) of environment `key`
+        fn desired(&self, key: &str) -> HashMap<PathBuf, PathBuf> {
+            let (config, home) = (self.root.join("config"), self.root.join("home"));
+            let mut links = HashMap::from([
+                (home.join(".baserc"), config.join("base/files/.baserc")),
+                (home.join(".shared"), config.join("base/files/.shared")),
+            ]);
+            if key == "work" {
+                links.insert(home.join(".shared"), config.join("work/files/.shared"));
+                links.insert(
+                    home.join(".config/app/workrc"),
+                    config.join("work/files/.config/app/workrc"),
                 );
-                std::os::unix::fs::symlink(&source_path, &target_path)?;
-                state.managed_files.push(target_path.clone());
             }
+            links
         }
 
-        state.store_state()?;
+        /// The steps of a switch to `work`, resuming an interrupted one like `switch` does
+        fn switch_to_work(&self, fs: &dyn Fs) -> EnvMgrResult<()> {
+            let ctx = self.ctx(fs);
+            let mut state = State::load_from_dir(fs, &ctx.state_dir, ctx.dual_write)?;
+            if state.current_env_key == "work" && state.applying.is_none() {
+                return Ok(());
+            }
+            state.current_env_key = "work".to_string();
+            state.applying = Some("work".to_string());
+            ctx.store(&state)?;
+            crate::integrations::write_if_changed(fs, &self.hosts_file(), "user: work\n")?;
+            let copies = HashSet::new();
+            EnvironmentManager::apply_links(
+                &mut state,
+                self.desired("work"),
+                &copies,
+                &ctx,
+                false,
+            )?;
+            Ok(())
+        }
 
-        Ok(())
+        /// Symlinks under home pointing into the config root, with their sources
+        fn owned_links(&self) -> HashMap<PathBuf, PathBuf> {
+            fn walk(dir: &Path, config: &Path, links: &mut HashMap<PathBuf, PathBuf>) {
+                for entry in fs::read_dir(dir).unwrap() {
+                    let path = entry.unwrap().path();
+                    if path.is_symlink() {
+                        let source = fs::read_link(&path).unwrap();
+                        if source.starts_with(config) {
+                            links.insert(path, source);
+                        }
+                    } else if path.is_dir() {
+                        walk(&path, config, links);
+                    }
+                }
+            }
+            let mut links = HashMap::new();
+            walk(
+                &self.root.join("home"),
+                &self.root.join("config"),
+                &mut links,
+            );
+            links
+        }
+
+        fn load_state(&self) -> State {
+            State::load_from_dir(&RealFs, &self.root.join("state"), true)
+                .expect("the state must stay readable")
+        }
+    }
+
+    #[test]
+    fn test_switch_survives_a_crash_at_every_operation() {
+        let scenario = CrashScenario::new(std::env::temp_dir().join("envmgr_test_crash_switch"));
+        let counting = CrashingFs::default();
+        scenario.switch_to_work(&counting).unwrap();
+        let total = counting.ops();
+        assert!(total > 10, "the scenario should exercise more operations");
+
+        for crash_at in 0..total {
+            let scenario =
+                CrashScenario::new(std::env::temp_dir().join("envmgr_test_crash_switch"));
+            let fs = CrashingFs::crash_at(crash_at);
+            // Best-effort writes swallow the failure, so the result says nothing
+            let _ = scenario.switch_to_work(&fs);
+            assert!(fs.crashed());
+
+            let state = scenario.load_state();
+            let links = scenario.owned_links();
+            // A temporary link killed before its rename is cleaned up by the next run
+            let temp_links: HashSet<PathBuf> = state
+                .managed_files
+                .iter()
+                .map(|managed| temp_link_path(&managed.target))
+                .collect();
+            for target in links.keys().filter(|target| !temp_links.contains(*target)) {
+                assert!(
+                    state.is_managed(target),
+                    "crash at {crash_at}: {} is linked but not managed",
+                    target.display()
+                );
+            }
+            let hosts = fs::read_to_string(scenario.hosts_file()).unwrap();
+            assert!(
+                ["user: personal\n", "user: work\n"].contains(&hosts.as_str()),
+                "crash at {crash_at}: torn hosts file {hosts:?}"
+            );
+            if state.applying.is_none() {
+                assert_eq!(
+                    links,
+                    scenario.desired(&state.current_env_key),
+                    "crash at {crash_at}: {} is half-applied without being reported",
+                    state.current_env_key
+                );
+            }
+
+            // Running the switch again finishes it
+            scenario.switch_to_work(&RealFs).unwrap();
+            let state = scenario.load_state();
+            assert_eq!(state.current_env_key, "work");
+            assert_eq!(state.applying, None, "crash at {crash_at}");
+            let desired = scenario.desired("work");
+            assert_eq!(scenario.owned_links(), desired, "crash at {crash_at}");
+            let mut managed: Vec<(PathBuf, PathBuf)> = state
+                .managed_files
+                .iter()
+                .map(|managed| (managed.target.clone(), managed.source.clone()))
+                .collect();
+            managed.sort();
+            let mut expected: Vec<(PathBuf, PathBuf)> = desired.into_iter().collect();
+            expected.sort();
+            assert_eq!(managed, expected, "crash at {crash_at}");
+            assert_eq!(
+                fs::read_to_string(scenario.hosts_file()).unwrap(),
+                "user: work\n"
+            );
+        }
+
+        fs::remove_dir_all(&scenario.root).unwrap();
+    }
+
+    /// Filesystem operations of linking a tree of `files` files, and of relinking it
+    fn link_tree_ops(files: usize) -> (usize, usize) {
+        let root = std::env::temp_dir().join(format!("envmgr_test_link_ops_{files}"));
+        let _ = fs::remove_dir_all(&root);
+        let (config, home) = (root.join("config"), root.join("home"));
+        let mut files_map = HashMap::new();
+        for file in 0..files {
+            let relative = format!("plugin-{}/{file}.lua", file / 10);
+            let source = config.join("base/files").join(&relative);
+            fs::create_dir_all(source.parent().unwrap()).unwrap();
+            fs::write(&source, "return {}").unwrap();
+            files_map.insert(home.join(&relative), source);
+        }
+        let mut state = State::default();
+        let mut link = || {
+            let counting = CrashingFs::default();
+            let ctx = LinkContext {
+                fs: &counting,
+                owner_root: config.clone(),
+                state_dir: root.join("state"),
+                dual_write: false,
+                adopt_backups: false,
+                home: home.clone(),
+                verbose: false,
+                filter: TargetFilter::default(),
+            };
+            let report = EnvironmentManager::apply_links(
+                &mut state,
+                files_map.clone(),
+                &HashSet::new(),
+                &ctx,
+                false,
+            )
+            .unwrap();
+            (counting.ops(), report.created)
+        };
+        let (first, created) = link();
+        assert_eq!(created, files);
+        let (second, created) = link();
+        assert_eq!(created, 0);
+        fs::remove_dir_all(&root).unwrap();
+        (first, second)
+    }
+
+    #[test]
+    fn test_link_operations_grow_linearly_with_the_tree() {
+        let [(small, relink_small), (medium, _), (large, relink_large)] =
+            [100, 200, 400].map(link_tree_ops);
+
+        // A symlink per file and a directory per ten, plus the state writes
+        assert_eq!(medium - small, 110);
+        assert_eq!(large - medium, 2 * (medium - small));
+        // Relinking an unchanged tree only writes the state
+        assert_eq!(relink_small, relink_large);
     }
 }
@@ -1,52 +1,253 @@
+mod aliases;
+pub(crate) mod backups;
+mod diff;
+mod dynamic;
+mod hooks;
+mod ignore;
+mod interpolate;
+mod links;
 mod manager;
+mod secrets;
+mod vars;
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     path::{Path, PathBuf},
 };
 
+pub use aliases::{AliasChange, merge_alias_layers, plan_alias_changes};
+pub use diff::{EnvironmentDiff, MapDiff, SetDiff, ValueChange};
+pub(crate) use dynamic::shell_command;
+pub use dynamic::{DynamicOptions, resolve_dynamic_values, run_value_command};
+pub use interpolate::{ENV_KEY_VAR, interpolate};
+pub use links::TargetFilter;
 use log::{debug, info, warn};
-pub use manager::EnvironmentManager;
+pub use manager::{
+    EnvironmentManager, FileConflict, FileLayer, LinkMode, LinkOptions, LinkReport, SwitchOptions,
+};
+pub(crate) use manager::{
+    layer_aliases, layer_env_vars, layer_files, layer_files_with_conflicts, systemd_user_vars,
+};
+use rayon::prelude::*;
+pub use secrets::{OpCli, SECRET_PLACEHOLDER, SECRET_REFERENCE_PREFIX};
+pub use vars::{EnvVarChange, merge_env_var_layers, plan_env_var_changes};
+
+use ignore::IgnoreRules;
 
 use crate::{
-    config::{BASE_ENV_NAME, EnvVarsConfig, EnvironmentConfig},
+    config::{
+        AliasConfig, BASE_ENV_NAME, CONFIG_DIR_ENV_VAR, DynamicValue, ENV_CONFIG_FILE_NAME,
+        EnvVarsConfig, EnvironmentConfig, LinkKind, condition::Host, dotenv::load_dotenv,
+        envmgr_config_dir, files_manifest::FilesManifest,
+    },
     error::{EnvMgrError, EnvMgrResult},
+    integrations::{IntegrationKind, gpg::Gpg, integration_env_vars},
 };
 
+#[derive(Clone)]
 pub struct Environment {
     pub key: String,
     pub name: String,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    pub group: Option<String>,
     pub env_vars: Vec<EnvVarsConfig>,
     pub one_password_ssh:
         Option<crate::integrations::one_password_ssh_agent::OnePasswordSSHAgentConfig>,
+    pub op_documents: Vec<crate::integrations::one_password_documents::OnePasswordDocument>,
     pub gh_cli: Option<crate::integrations::gh_cli::GhCliConfig>,
+    pub git: Option<crate::integrations::git::GitConfig>,
+    pub kube: Option<crate::integrations::kube::KubeConfig>,
+    pub aws: Option<crate::integrations::aws::AwsConfig>,
+    pub gcloud: Option<crate::integrations::gcloud::GcloudConfig>,
+    pub npm: Option<crate::integrations::npm::NpmConfig>,
+    pub ssh: Option<crate::integrations::ssh::SshConfig>,
+    pub gpg: Option<crate::integrations::gpg::GpgConfig>,
+    pub mise: Option<crate::integrations::mise::MiseConfig>,
+    pub maven: Option<crate::integrations::maven::MavenConfig>,
+    pub python: Option<crate::integrations::python::PythonConfig>,
+    pub cargo: Option<crate::integrations::cargo::CargoConfig>,
     pub tailscale: Option<crate::integrations::tailscale::TailscaleConfig>,
+    pub propagate_to_systemd_user: Option<bool>,
+    pub danger: bool,
+    pub unset_vars: Vec<String>,
+    pub aliases: Vec<AliasConfig>,
+    /// Whether base is layered under this environment
+    pub inherit_base: bool,
+    pub link_mode: LinkKind,
+    pub link_modes: BTreeMap<String, LinkKind>,
+    /// Directories below `files/` linked as one symlink
+    pub link_dirs: Vec<String>,
+}
+
+/// An environment without anything configured, layering base like one loaded from an
+/// empty `config.yaml`
+impl Default for Environment {
+    fn default() -> Self {
+        Self {
+            key: String::new(),
+            name: String::new(),
+            description: None,
+            tags: vec![],
+            group: None,
+            env_vars: vec![],
+            one_password_ssh: None,
+            op_documents: vec![],
+            gh_cli: None,
+            git: None,
+            kube: None,
+            aws: None,
+            gcloud: None,
+            npm: None,
+            ssh: None,
+            gpg: None,
+            mise: None,
+            maven: None,
+            python: None,
+            cargo: None,
+            tailscale: None,
+            propagate_to_systemd_user: None,
+            danger: false,
+            unset_vars: vec![],
+            aliases: vec![],
+            inherit_base: true,
+            link_mode: LinkKind::default(),
+            link_modes: BTreeMap::new(),
+            link_dirs: vec![],
+        }
+    }
 }
 
 impl Environment {
-    fn load_from_config(key: &str, config: &EnvironmentConfig) -> Self {
+    /// Build the environment from `config`, expanding `${VAR}` in env var values.
+    ///
+    /// Env vars whose `when` condition doesn't hold on this machine are left out.
+    ///
+    /// Variables from a dotenv file next to `config_file` come first, and are dropped
+    /// where the YAML sets the same key. Their values are taken literally.
+    pub(crate) fn load_from_config(
+        key: &str,
+        config: &EnvironmentConfig,
+        config_file: &Path,
+    ) -> EnvMgrResult<Self> {
         debug!("Loading environment: {} ({key})", config.name);
-        Self {
+        let config_dir = envmgr_config_dir();
+        let lookup = |name: &str| match name {
+            ENV_KEY_VAR => Some(key.to_string()),
+            CONFIG_DIR_ENV_VAR => Some(config_dir.to_string_lossy().into_owned()),
+            _ => std::env::var(name).ok(),
+        };
+        let host = Host::current();
+        let yaml_vars: Vec<EnvVarsConfig> = config
+            .locale_env_vars()
+            .into_iter()
+            .chain(integration_env_vars(config))
+            .chain(config.env_vars.iter().cloned())
+            .filter(|var| var.applies_to(&host))
+            .map(|var| {
+                let expand = |text: &str| {
+                    interpolate(text, lookup).map_err(|message| EnvMgrError::Interpolation {
+                        file: config_file.to_path_buf(),
+                        key: var.key.clone(),
+                        message,
+                    })
+                };
+                let value = expand(&var.value)?;
+                let dynamic = match &var.dynamic {
+                    Some(DynamicValue::Command(command)) => {
+                        Some(DynamicValue::Command(expand(command)?))
+                    }
+                    Some(DynamicValue::File(path)) => Some(DynamicValue::File(expand(path)?)),
+                    Some(DynamicValue::Secret(reference)) => {
+                        Some(DynamicValue::Secret(expand(reference)?))
+                    }
+                    None => None,
+                };
+                Ok(EnvVarsConfig {
+                    value,
+                    dynamic,
+                    ..var
+                })
+            })
+            .collect::<EnvMgrResult<_>>()?;
+        let dotenv_vars = match config_file.parent() {
+            Some(env_dir) => load_dotenv(env_dir)?,
+            None => vec![],
+        };
+        let mut env_vars: Vec<EnvVarsConfig> = dotenv_vars
+            .into_iter()
+            .filter(|(key, _)| !yaml_vars.iter().any(|var| var.key == *key))
+            .map(|(key, value)| EnvVarsConfig {
+                key,
+                value,
+                ..Default::default()
+            })
+            .collect();
+        env_vars.extend(yaml_vars);
+        Ok(Self {
             key: key.to_string(),
             name: config.name.clone(),
-            env_vars: config.env_vars.clone(),
+            description: config.description.clone(),
+            tags: config.tags.clone(),
+            group: config.group.clone(),
+            env_vars,
             one_password_ssh: config.op_ssh.clone(),
+            op_documents: config.op_documents.clone(),
             gh_cli: config.gh_cli.clone(),
+            git: Gpg::with_git_signing_key(config.git.clone(), config.gpg.as_ref()),
+            kube: config.kube.clone(),
+            aws: config.aws.clone(),
+            gcloud: config.gcloud.clone(),
+            npm: config.npm.clone(),
+            ssh: config.ssh.clone(),
+            gpg: config.gpg.clone(),
+            mise: config.mise.clone(),
+            maven: config.maven.clone(),
+            python: config.python.clone(),
+            cargo: config.cargo.clone(),
             tailscale: config.tailscale.clone(),
-        }
+            propagate_to_systemd_user: config.propagate_to_systemd_user,
+            danger: config.danger,
+            unset_vars: config.unset_vars.clone(),
+            aliases: config.aliases.clone(),
+            inherit_base: config.inherit_base,
+            link_mode: config.link_mode,
+            link_modes: config.link_modes.clone(),
+            link_dirs: config.link_dirs.clone(),
+        })
     }
 
     pub fn load_base_environment() -> EnvMgrResult<Self> {
         let base_env_config = EnvironmentConfig::load_base_config()?;
-        Ok(Self::load_from_config(BASE_ENV_NAME, &base_env_config))
+        let config_file = EnvironmentConfig::get_base_env_dir().join(ENV_CONFIG_FILE_NAME);
+        Self::load_from_config(BASE_ENV_NAME, &base_env_config, &config_file)
     }
 
     pub fn load_environment_by_key(key: &str) -> EnvMgrResult<Self> {
         let env_config = EnvironmentConfig::load_env_config_by_key(key)?;
-        Ok(Self::load_from_config(key, &env_config))
+        let config_file = EnvironmentConfig::get_env_dir_by_key(key).join(ENV_CONFIG_FILE_NAME);
+        Self::load_from_config(key, &env_config, &config_file)
+    }
+
+    /// Load an environment by key, treating `base` as the base environment
+    pub fn load(key: &str) -> EnvMgrResult<Self> {
+        if key == BASE_ENV_NAME {
+            Self::load_base_environment()
+        } else {
+            Self::load_environment_by_key(key)
+        }
+    }
+
+    /// Config keys of the integrations this environment configures
+    pub fn configured_integrations(&self) -> Vec<&'static str> {
+        IntegrationKind::ALL
+            .into_iter()
+            .filter(|kind| kind.is_configured_in(self))
+            .map(IntegrationKind::config_key)
+            .collect()
     }
 
-    fn env_dir(&self) -> PathBuf {
+    pub(crate) fn env_dir(&self) -> PathBuf {
         if self.key == BASE_ENV_NAME {
             EnvironmentConfig::get_base_env_dir()
         } else {
@@ -55,22 +256,57 @@ impl Environment {
     }
 
     fn files_dir(&self) -> PathBuf {
-        self.env_dir().join("files")
+        self.env_dir().join(crate::config::FILES_DIR_NAME)
+    }
+
+    /// Whether `source`, one of the files this environment links, is copied or symlinked
+    pub(crate) fn link_kind(&self, source: &Path) -> LinkKind {
+        let relative = source.strip_prefix(self.files_dir()).unwrap_or(source);
+        let relative = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        LinkKind::for_path(self.link_mode, &self.link_modes, &relative)
+    }
+
+    /// Number of files in the environment's files directory
+    pub(crate) fn files_count(&self) -> EnvMgrResult<usize> {
+        Ok(discover_files_in_dir(&self.files_dir())?.len())
     }
 
     /// Returns a map of source file paths to target link paths for the environment
     ///
     /// Example: { "/home/user/.bashrc" => "/home/user/.config/envmgr/base/files/.bashrc" }
+    ///
+    /// Files listed in the environment's `files.yaml` go to their manifest target instead,
+    /// which also wins over another file's conventional target. The directories in
+    /// `link_dirs` and directories in the manifest are mapped as a whole.
     pub fn files_to_link(&self) -> EnvMgrResult<HashMap<PathBuf, PathBuf>> {
         let mut file_map = HashMap::new();
         let files_dir = self.files_dir();
+        let home = dirs::home_dir().ok_or(EnvMgrError::DirError("home".into()))?;
+        let link_dirs: Vec<PathBuf> = self
+            .link_dirs
+            .iter()
+            .map(|dir| files_dir.join(dir.trim_matches('/')))
+            .collect();
         if files_dir.exists() && files_dir.is_dir() {
             let files = discover_files_in_dir(&files_dir)?;
             for file in files {
+                if link_dirs.iter().any(|dir| file.starts_with(dir)) {
+                    continue;
+                }
+                if file.is_symlink() && !file.exists() {
+                    let link = std::fs::read_link(&file).unwrap_or_default();
+                    warn!(
+                        "{} is a dangling symlink to {}, linking it anyway",
+                        file.display(),
+                        link.display()
+                    );
+                }
                 if let Ok(target_path) = file.strip_prefix(&files_dir) {
-                    let target_full_path = dirs::home_dir()
-                        .ok_or(EnvMgrError::DirError("home".into()))?
-                        .join(target_path);
+                    let target_full_path = home.join(target_path);
                     debug!(
                         "Mapping file for linking: {} -> {}",
                         target_full_path.display(),
@@ -92,33 +328,231 @@ impl Environment {
                 files_dir.display()
             );
         }
+        for dir in link_dirs {
+            let Ok(relative) = dir.strip_prefix(&files_dir) else {
+                continue;
+            };
+            if !dir.is_dir() {
+                warn!(
+                    "{} in link_dirs of environment {} is not a directory, not linking it",
+                    relative.display(),
+                    self.key
+                );
+                continue;
+            }
+            debug!("Mapping directory for linking: {}", dir.display());
+            file_map.insert(home.join(relative), dir);
+        }
+
+        let manifest_path = FilesManifest::file_path(&self.env_dir());
+        for mapping in FilesManifest::load(&self.env_dir())?.0 {
+            let invalid =
+                |message| EnvMgrError::InvalidFilesManifest(manifest_path.clone(), message);
+            let source = mapping.resolve_source(&files_dir).map_err(invalid)?;
+            let target = mapping.resolve_target(&home).map_err(invalid)?;
+            if source.symlink_metadata().is_err() {
+                warn!(
+                    "{}: {} does not exist, not linking it",
+                    manifest_path.display(),
+                    source.display()
+                );
+                continue;
+            }
+            file_map.retain(|_, linked| !linked.starts_with(&source));
+            debug!(
+                "Mapping file from manifest: {} -> {}",
+                target.display(),
+                source.display()
+            );
+            file_map.insert(target, source);
+        }
         Ok(file_map)
     }
 }
 
-/// Utility function to discover files in a directory (recursively)
-fn discover_files_in_dir(dir: &Path) -> EnvMgrResult<Vec<PathBuf>> {
+/// Stable, serializable overview of an environment for `list --json`
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct EnvSummary {
+    pub key: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    pub group: Option<String>,
+    pub current: bool,
+    pub env_var_count: usize,
+    pub files_count: usize,
+    pub integrations: Vec<String>,
+    /// Unix time of the last switch to the environment, if the history still has one
+    pub last_used: Option<u64>,
+}
+
+impl EnvSummary {
+    pub fn new(
+        env: &Environment,
+        current: bool,
+        files_count: usize,
+        last_used: Option<u64>,
+    ) -> Self {
+        Self {
+            key: env.key.clone(),
+            name: env.name.clone(),
+            description: env.description.clone(),
+            tags: env.tags.clone(),
+            group: env.group.clone(),
+            current,
+            env_var_count: env.env_vars.len(),
+            files_count,
+            integrations: env
+                .configured_integrations()
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            last_used,
+        }
+    }
+}
+
+/// Utility function to discover files in a directory (recursively), leaving out what its
+/// `.envmgrignore` and the default ignore patterns exclude
+///
+/// Symlinks are returned as entries of their own rather than followed. The files come
+/// back sorted by path.
+pub(crate) fn discover_files_in_dir(dir: &Path) -> EnvMgrResult<Vec<PathBuf>> {
     let mut files = Vec::new();
     if dir.exists() && dir.is_dir() {
-        for entry in std::fs::read_dir(dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_file() {
-                files.push(path);
-            } else if path.is_dir() {
-                files.extend(discover_files_in_dir(&path)?);
-            }
+        let rules = IgnoreRules::load(dir)?;
+        files = discover_files_below(dir, "", &rules)?;
+        files.sort();
+    }
+    Ok(files)
+}
+
+/// The files in `dir`, which is `prefix` below the discovered directory.
+///
+/// Subdirectories are walked in parallel, vendored plugin trees easily hold thousands
+/// of files.
+fn discover_files_below(
+    dir: &Path,
+    prefix: &str,
+    rules: &IgnoreRules,
+) -> EnvMgrResult<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let relative = format!("{prefix}{}", entry.file_name().to_string_lossy());
+        // Not followed: a symlink is linked as it is, wherever it points, and the walk
+        // can't leave the directory through one
+        let file_type = entry.file_type()?;
+        if rules.is_ignored(&relative, file_type.is_dir()) {
+            debug!("Ignoring {}", path.display());
+        } else if file_type.is_dir() {
+            dirs.push((path, format!("{relative}/")));
+        } else if file_type.is_file() || file_type.is_symlink() {
+            files.push(path);
         }
     }
+    let nested = dirs
+        .par_iter()
+        .map(|(dir, prefix)| discover_files_below(dir, prefix, rules))
+        .collect::<EnvMgrResult<Vec<_>>>()?;
+    files.extend(nested.into_iter().flatten());
     Ok(files)
 }
 
+/// Compute the hex encoded SHA-256 digest of a file's contents
+pub(crate) fn hash_file(path: &Path) -> EnvMgrResult<String> {
+    Ok(hash_content(&std::fs::read(path)?))
+}
+
+/// The hex encoded SHA-256 digest of `content`
+pub(crate) fn hash_content(content: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+
+    hex::encode(Sha256::digest(content))
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs;
 
     use super::*;
 
+    #[test]
+    fn test_env_summary_shape() {
+        let env = Environment {
+            key: "work".to_string(),
+            name: "Work".to_string(),
+            env_vars: vec![EnvVarsConfig {
+                key: "FOO".to_string(),
+                value: "bar".to_string(),
+                dynamic: None,
+                order: 0,
+                secret: false,
+                when: None,
+                integration: None,
+            }],
+            gh_cli: Some(Default::default()),
+            tailscale: Some(Default::default()),
+            ..Default::default()
+        };
+        let summary = EnvSummary::new(&env, true, 2, Some(1700000000));
+        let json = serde_json::to_value(&summary).unwrap();
+
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "key": "work",
+                "name": "Work",
+                "description": null,
+                "tags": [],
+                "group": null,
+                "current": true,
+                "env_var_count": 1,
+                "files_count": 2,
+                "integrations": ["gh_cli", "tailscale"],
+                "last_used": 1700000000,
+            })
+        );
+    }
+
+    #[test]
+    fn test_vars_for_other_machines_are_left_out() {
+        let hostname = Host::current().hostname;
+        let config: EnvironmentConfig = serde_norway::from_str(&format!(
+            indoc::indoc! {"
+                name: Synced
+                env_vars:
+                  - key: PROJECTS
+                    value: /home/me/src
+                  - key: PROJECTS
+                    value: /data/src
+                    when: {{hostname: '{}'}}
+                  - key: ONLY_ELSEWHERE
+                    value: x
+                    when: {{hostname: 'not-{}*'}}
+            "},
+            hostname.to_uppercase(),
+            hostname
+        ))
+        .unwrap();
+        let env =
+            Environment::load_from_config("synced", &config, Path::new("/nonexistent/config.yaml"))
+                .unwrap();
+
+        let vars: Vec<(&str, &str)> = env
+            .env_vars
+            .iter()
+            .map(|var| (var.key.as_str(), var.value.as_str()))
+            .collect();
+        assert_eq!(
+            vars,
+            [("PROJECTS", "/home/me/src"), ("PROJECTS", "/data/src")]
+        );
+        assert_eq!(merge_env_var_layers(&[&env.env_vars])[0].value, "/data/src");
+    }
+
     #[test]
     fn test_discover_files_in_dir_empty() {
         let temp_dir = std::env::temp_dir().join("envmgr_test_empty");
@@ -160,6 +594,85 @@ mod tests {
         fs::remove_dir_all(&temp_dir).unwrap();
     }
 
+    #[test]
+    fn test_discover_files_in_dir_skips_ignored() {
+        let temp_dir = std::env::temp_dir().join("envmgr_test_ignored");
+        let _ = fs::remove_dir_all(&temp_dir);
+        for file in [
+            ".bashrc",
+            ".bashrc.swp",
+            ".git/config",
+            ".config/nvim/.git/HEAD",
+            ".config/nvim/init.lua",
+            ".config/nvim/init.lua~",
+            ".config/nvim/plugin/cache/index",
+            ".config/nvim/plugin/keep.lua",
+            ".config/.DS_Store",
+            "notes/todo.md",
+        ] {
+            let path = temp_dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        fs::write(
+            temp_dir.join(".envmgrignore"),
+            "# local junk\ncache/\n/notes/\n",
+        )
+        .unwrap();
+
+        let mut files: Vec<PathBuf> = discover_files_in_dir(&temp_dir)
+            .unwrap()
+            .into_iter()
+            .map(|file| file.strip_prefix(&temp_dir).unwrap().to_path_buf())
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                ".bashrc",
+                ".config/nvim/init.lua",
+                ".config/nvim/plugin/keep.lua"
+            ]
+            .map(PathBuf::from)
+        );
+
+        fs::remove_dir_all(&temp_dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_discover_files_in_dir_keeps_symlinks_as_leaves() {
+        use std::os::unix::fs::symlink;
+
+        let temp_dir = std::env::temp_dir().join("envmgr_test_symlinks");
+        let _ = fs::remove_dir_all(&temp_dir);
+        fs::create_dir_all(temp_dir.join("shared/nvim")).unwrap();
+        fs::write(temp_dir.join("shared/nvim/init.lua"), "").unwrap();
+        fs::create_dir_all(temp_dir.join(".config")).unwrap();
+        symlink("../shared/nvim", temp_dir.join(".config/nvim")).unwrap();
+        symlink("/etc/hostname", temp_dir.join(".hostname")).unwrap();
+        symlink("missing", temp_dir.join(".dangling")).unwrap();
+
+        let files: Vec<PathBuf> = discover_files_in_dir(&temp_dir)
+            .unwrap()
+            .into_iter()
+            .map(|file| file.strip_prefix(&temp_dir).unwrap().to_path_buf())
+            .collect();
+        // The linked directory is not walked into, the dangling link is not dropped
+        assert_eq!(
+            files,
+            [
+                ".config/nvim",
+                ".dangling",
+                ".hostname",
+                "shared/nvim/init.lua"
+            ]
+            .map(PathBuf::from)
+        );
+
+        fs::remove_dir_all(&temp_dir).unwrap();
+    }
+
     #[test]
     fn test_discover_files_in_dir_nonexistent() {
         let temp_dir = std::env::temp_dir().join("envmgr_test_nonexistent_dir");
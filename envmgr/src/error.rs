@@ -8,6 +8,8 @@ pub enum EnvMgrError {
     TomlDeserialization(#[from] toml::de::Error),
     #[error("Toml Serialization Error: {0}")]
     TomlSerialization(#[from] toml::ser::Error),
+    #[error("Json Error: {0}")]
+    Json(#[from] serde_json::Error),
     #[error("Could not determine directory: {0}")]
     DirError(String),
     #[error("GhCli Config Error: {0}")]
@@ -16,14 +18,1027 @@ pub enum EnvMgrError {
     SaphyrYaml(#[from] saphyr::ScanError),
     #[error("Saphyr Emit Yaml Error: {0}")]
     SaphyrEmitYaml(#[from] saphyr::EmitError),
+    #[error("Environment '{0}' not found")]
+    EnvironmentNotFound(String),
+    #[error("Invalid environment key: {0}")]
+    InvalidEnvironmentKey(String),
+    #[error(
+        "Environment '{key}' already exists{}; pick another key with --key or run `envmgr remove {key}` first",
+        name.as_ref().map(|n| format!(" as \"{n}\"")).unwrap_or_default()
+    )]
+    EnvironmentAlreadyExists { key: String, name: Option<String> },
+    #[error("Missing required value: {0}")]
+    MissingArgument(String),
+    #[error(
+        "Confirmation token '{token}' does not match the current plan (now '{current}'); review it again with --dry-run"
+    )]
+    ConfirmTokenMismatch { token: String, current: String },
+    #[error("Debug bundle: {0}")]
+    DebugBundle(String),
+    #[error("Template Error: {0}")]
+    Template(String),
+    #[error("Yaml Error: {0}")]
+    Yaml(#[from] serde_norway::Error),
+    #[error(
+        "Unknown timezone '{timezone}'{}",
+        suggestion.as_ref().map(|s| format!(", did you mean '{s}'?")).unwrap_or_default()
+    )]
+    InvalidTimezone {
+        timezone: String,
+        suggestion: Option<String>,
+    },
+    #[error("{file}: env var {key}: {message}", file = file.display())]
+    Interpolation {
+        file: std::path::PathBuf,
+        key: String,
+        message: String,
+    },
+    #[error("{} env var value(s) could not be resolved: {}", .0.len(), .0.join("; "))]
+    UnresolvedEnvVars(Vec<String>),
+    #[error("{file}:{line}: {message}", file = file.display())]
+    Dotenv {
+        file: std::path::PathBuf,
+        line: usize,
+        message: String,
+    },
+    #[error("Export Error: {0}")]
+    Export(String),
+    #[error("Invalid local override {path}: {1}", path = .0.display())]
+    InvalidLocalOverride(std::path::PathBuf, String),
+    #[error("Link conflict: {0} already exists")]
+    LinkConflict(std::path::PathBuf),
+    #[error("No backup of {0}, see `envmgr backups list`")]
+    BackupNotFound(std::path::PathBuf),
+    #[error("Invalid files manifest {path}: {1}", path = .0.display())]
+    InvalidFilesManifest(std::path::PathBuf, String),
+    #[error("The {hook} hook of {env} failed: {reason}")]
+    HookFailed {
+        hook: String,
+        env: String,
+        reason: String,
+    },
+    #[error("Tailscale Error: {0}")]
+    Tailscale(String),
+    #[error("Integration '{integration}' is not configured in environment '{env}'")]
+    IntegrationNotConfigured { integration: String, env: String },
+    #[error("Git Error: {0}")]
+    Git(String),
+    #[error("1Password Error: {0}")]
+    OnePassword(String),
+    #[error("Git Identity Error: {0}")]
+    GitIdentity(String),
+    #[error("Kubernetes Error: {0}")]
+    Kube(String),
+    #[error("AWS Error: {0}")]
+    Aws(String),
+    #[error("gcloud Error: {0}")]
+    Gcloud(String),
+    #[error("npm Error: {0}")]
+    Npm(String),
+    #[error("SSH Error: {0}")]
+    Ssh(String),
+    #[error("GPG Error: {0}")]
+    Gpg(String),
+    #[error("mise Error: {0}")]
+    Mise(String),
+    #[error("Maven Error: {0}")]
+    Maven(String),
+    #[error("Python Error: {0}")]
+    Python(String),
+    #[error("Cargo Error: {0}")]
+    Cargo(String),
+    #[error("{} integration(s) failed: {}", .0.len(), .0.join("; "))]
+    IntegrationsFailed(Vec<String>),
+    #[error("{integration} did not finish within {secs}s, it may be stuck on a hung command")]
+    IntegrationTimeout { integration: String, secs: u64 },
+    #[error("1Password SSH Key Error: {0}")]
+    OpSshKey(String),
+    #[error("tmux Error: {0}")]
+    Tmux(String),
+    #[error("No previous environment to switch back to")]
+    NoPreviousEnvironment,
+    #[error("Prompt Error: {0}")]
+    Prompt(#[from] dialoguer::Error),
+    #[error("Unsupported: {0}")]
+    Unsupported(String),
+    #[error("Validation failed with {0} error(s)")]
+    Validation(usize),
+    #[error("Unknown error code '{0}', known codes: {1}")]
+    UnknownErrorCode(String, String),
     #[error("Other Error: {0}")]
     Other(#[from] Box<dyn std::error::Error + Send + Sync + 'static>),
 }
 
 pub type EnvMgrResult<T> = std::result::Result<T, EnvMgrError>;
 
+/// Stable short codes for error categories, explained by `envmgr explain <code>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum ErrorCode {
+    E001,
+    E002,
+    E003,
+    E004,
+    E005,
+    E006,
+    E007,
+    E008,
+    E009,
+    E010,
+    E011,
+    E012,
+    E013,
+    E014,
+    E015,
+    E016,
+    E017,
+    E018,
+    E019,
+    E020,
+    E021,
+    E022,
+    E023,
+    E024,
+    E030,
+    E031,
+    E032,
+    E033,
+    E034,
+    E035,
+    E036,
+    E037,
+    E038,
+    E039,
+    E040,
+    E050,
+    E060,
+    E070,
+    E071,
+    E072,
+    E073,
+    E074,
+    E075,
+    E076,
+    E077,
+    E078,
+    E079,
+    E099,
+}
+
+impl ErrorCode {
+    pub const ALL: &[ErrorCode] = &[
+        ErrorCode::E001,
+        ErrorCode::E002,
+        ErrorCode::E003,
+        ErrorCode::E004,
+        ErrorCode::E005,
+        ErrorCode::E006,
+        ErrorCode::E007,
+        ErrorCode::E008,
+        ErrorCode::E009,
+        ErrorCode::E010,
+        ErrorCode::E011,
+        ErrorCode::E012,
+        ErrorCode::E013,
+        ErrorCode::E014,
+        ErrorCode::E015,
+        ErrorCode::E016,
+        ErrorCode::E017,
+        ErrorCode::E018,
+        ErrorCode::E019,
+        ErrorCode::E020,
+        ErrorCode::E021,
+        ErrorCode::E022,
+        ErrorCode::E023,
+        ErrorCode::E024,
+        ErrorCode::E030,
+        ErrorCode::E031,
+        ErrorCode::E032,
+        ErrorCode::E033,
+        ErrorCode::E034,
+        ErrorCode::E035,
+        ErrorCode::E036,
+        ErrorCode::E037,
+        ErrorCode::E038,
+        ErrorCode::E039,
+        ErrorCode::E040,
+        ErrorCode::E050,
+        ErrorCode::E060,
+        ErrorCode::E070,
+        ErrorCode::E071,
+        ErrorCode::E072,
+        ErrorCode::E073,
+        ErrorCode::E074,
+        ErrorCode::E075,
+        ErrorCode::E076,
+        ErrorCode::E077,
+        ErrorCode::E078,
+        ErrorCode::E079,
+        ErrorCode::E099,
+    ];
+
+    /// Parse a code like `E020` (case-insensitive)
+    pub fn parse(code: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|c| c.to_string().eq_ignore_ascii_case(code.trim()))
+    }
+
+    /// Longer explanation with causes and the commands to resolve the error
+    pub fn explanation(self) -> &'static str {
+        match self {
+            ErrorCode::E001 => EXPLAIN_E001,
+            ErrorCode::E002 => EXPLAIN_E002,
+            ErrorCode::E003 => EXPLAIN_E003,
+            ErrorCode::E004 => EXPLAIN_E004,
+            ErrorCode::E005 => EXPLAIN_E005,
+            ErrorCode::E006 => EXPLAIN_E006,
+            ErrorCode::E007 => EXPLAIN_E007,
+            ErrorCode::E008 => EXPLAIN_E008,
+            ErrorCode::E009 => EXPLAIN_E009,
+            ErrorCode::E010 => EXPLAIN_E010,
+            ErrorCode::E011 => EXPLAIN_E011,
+            ErrorCode::E012 => EXPLAIN_E012,
+            ErrorCode::E013 => EXPLAIN_E013,
+            ErrorCode::E014 => EXPLAIN_E014,
+            ErrorCode::E015 => EXPLAIN_E015,
+            ErrorCode::E016 => EXPLAIN_E016,
+            ErrorCode::E017 => EXPLAIN_E017,
+            ErrorCode::E018 => EXPLAIN_E018,
+            ErrorCode::E019 => EXPLAIN_E019,
+            ErrorCode::E020 => EXPLAIN_E020,
+            ErrorCode::E021 => EXPLAIN_E021,
+            ErrorCode::E022 => EXPLAIN_E022,
+            ErrorCode::E023 => EXPLAIN_E023,
+            ErrorCode::E024 => EXPLAIN_E024,
+            ErrorCode::E030 => EXPLAIN_E030,
+            ErrorCode::E031 => EXPLAIN_E031,
+            ErrorCode::E032 => EXPLAIN_E032,
+            ErrorCode::E033 => EXPLAIN_E033,
+            ErrorCode::E034 => EXPLAIN_E034,
+            ErrorCode::E035 => EXPLAIN_E035,
+            ErrorCode::E036 => EXPLAIN_E036,
+            ErrorCode::E037 => EXPLAIN_E037,
+            ErrorCode::E038 => EXPLAIN_E038,
+            ErrorCode::E039 => EXPLAIN_E039,
+            ErrorCode::E040 => EXPLAIN_E040,
+            ErrorCode::E050 => EXPLAIN_E050,
+            ErrorCode::E060 => EXPLAIN_E060,
+            ErrorCode::E070 => EXPLAIN_E070,
+            ErrorCode::E071 => EXPLAIN_E071,
+            ErrorCode::E072 => EXPLAIN_E072,
+            ErrorCode::E073 => EXPLAIN_E073,
+            ErrorCode::E074 => EXPLAIN_E074,
+            ErrorCode::E075 => EXPLAIN_E075,
+            ErrorCode::E076 => EXPLAIN_E076,
+            ErrorCode::E077 => EXPLAIN_E077,
+            ErrorCode::E078 => EXPLAIN_E078,
+            ErrorCode::E079 => EXPLAIN_E079,
+            ErrorCode::E099 => EXPLAIN_E099,
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl EnvMgrError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            EnvMgrError::EnvironmentNotFound(_) => ErrorCode::E001,
+            EnvMgrError::NoPreviousEnvironment => ErrorCode::E002,
+            EnvMgrError::Config(_) | EnvMgrError::SaphyrYaml(_) => ErrorCode::E010,
+            EnvMgrError::Validation(_) => ErrorCode::E011,
+            EnvMgrError::UnknownErrorCode(..) => ErrorCode::E003,
+            EnvMgrError::InvalidEnvironmentKey(_) => ErrorCode::E004,
+            EnvMgrError::EnvironmentAlreadyExists { .. } => ErrorCode::E005,
+            EnvMgrError::MissingArgument(_) => ErrorCode::E006,
+            EnvMgrError::ConfirmTokenMismatch { .. } => ErrorCode::E007,
+            EnvMgrError::DebugBundle(_) => ErrorCode::E008,
+            EnvMgrError::Template(_) => ErrorCode::E009,
+            EnvMgrError::TomlDeserialization(_) => ErrorCode::E012,
+            EnvMgrError::InvalidLocalOverride(..) => ErrorCode::E014,
+            EnvMgrError::InvalidTimezone { .. } => ErrorCode::E015,
+            EnvMgrError::Interpolation { .. } => ErrorCode::E016,
+            EnvMgrError::UnresolvedEnvVars(_) => ErrorCode::E017,
+            EnvMgrError::Dotenv { .. } => ErrorCode::E018,
+            EnvMgrError::Export(_) => ErrorCode::E019,
+            EnvMgrError::TomlSerialization(_)
+            | EnvMgrError::SaphyrEmitYaml(_)
+            | EnvMgrError::Yaml(_)
+            | EnvMgrError::Json(_) => ErrorCode::E013,
+            EnvMgrError::LinkConflict(_) => ErrorCode::E020,
+            EnvMgrError::Unsupported(_) => ErrorCode::E021,
+            EnvMgrError::BackupNotFound(_) => ErrorCode::E022,
+            EnvMgrError::InvalidFilesManifest(..) => ErrorCode::E023,
+            EnvMgrError::HookFailed { .. } => ErrorCode::E024,
+            EnvMgrError::GhCliConfig(_) => ErrorCode::E030,
+            EnvMgrError::Tailscale(_) => ErrorCode::E031,
+            EnvMgrError::IntegrationNotConfigured { .. } => ErrorCode::E032,
+            EnvMgrError::Git(_) => ErrorCode::E033,
+            EnvMgrError::OnePassword(_) => ErrorCode::E034,
+            EnvMgrError::GitIdentity(_) => ErrorCode::E035,
+            EnvMgrError::Kube(_) => ErrorCode::E036,
+            EnvMgrError::Aws(_) => ErrorCode::E037,
+            EnvMgrError::Gcloud(_) => ErrorCode::E038,
+            EnvMgrError::Npm(_) => ErrorCode::E039,
+            EnvMgrError::DirError(_) => ErrorCode::E040,
+            EnvMgrError::Io(_) => ErrorCode::E050,
+            EnvMgrError::Prompt(_) => ErrorCode::E060,
+            EnvMgrError::Ssh(_) => ErrorCode::E070,
+            EnvMgrError::Gpg(_) => ErrorCode::E071,
+            EnvMgrError::OpSshKey(_) => ErrorCode::E072,
+            EnvMgrError::Tmux(_) => ErrorCode::E074,
+            EnvMgrError::Maven(_) => ErrorCode::E075,
+            EnvMgrError::Python(_) => ErrorCode::E076,
+            EnvMgrError::Cargo(_) => ErrorCode::E077,
+            EnvMgrError::IntegrationsFailed(_) => ErrorCode::E078,
+            EnvMgrError::IntegrationTimeout { .. } => ErrorCode::E079,
+            EnvMgrError::Mise(_) => ErrorCode::E073,
+            EnvMgrError::Other(_) => ErrorCode::E099,
+        }
+    }
+
+    /// Machine readable form of the error for `--json` output
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "error": {
+                "code": self.code(),
+                "message": self.to_string(),
+            }
+        })
+    }
+}
+
+const EXPLAIN_E001: &str = indoc::indoc! {"
+    E001: Environment not found
+
+    The environment key does not match a directory under
+    ~/.config/envmgr/environments/.
+
+    Causes:
+    - A typo in the key (keys are directory names, not display names)
+    - The environment directory was renamed or deleted
+
+    Resolve:
+      envmgr list                 # show available keys
+      envmgr switch               # pick interactively
+"};
+
+const EXPLAIN_E002: &str = indoc::indoc! {"
+    E002: No previous environment
+
+    `envmgr switch -` returns to the environment that was active before the
+    last switch, but no switch has been recorded yet.
+
+    Resolve:
+      envmgr switch <key>         # switch once, then `switch -` works
+"};
+
+const EXPLAIN_E003: &str = indoc::indoc! {"
+    E003: Unknown error code
+
+    `envmgr explain` was given a code it does not know. Codes are printed in
+    brackets in front of error messages, e.g. `[E001]`.
+
+    Resolve:
+      envmgr explain E001         # use one of the listed codes
+"};
+
+const EXPLAIN_E004: &str = indoc::indoc! {"
+    E004: Invalid environment key
+
+    Environment keys are directory names and may only contain letters,
+    digits, '-' and '_'. The key 'base' is reserved.
+
+    Resolve:
+      envmgr add \"Client X\" --key client-x
+"};
+
+const EXPLAIN_E005: &str = indoc::indoc! {"
+    E005: Environment already exists
+
+    An environment directory with this key already exists.
+
+    Resolve:
+      envmgr list                 # see existing keys
+      envmgr add <name> --key <other-key>
+"};
+
+const EXPLAIN_E006: &str = indoc::indoc! {"
+    E006: Missing required value
+
+    A non-interactive command needs a value that was not given on the
+    command line. envmgr never prompts with --no-interactive.
+
+    Resolve:
+      pass the missing flag, e.g. both --gh-host and --gh-user
+      or drop --no-interactive to be prompted
+"};
+
+const EXPLAIN_E007: &str = indoc::indoc! {"
+    E007: Confirmation token does not match
+
+    A command was run with --confirm-token, but what it would do now differs
+    from the plan the token was issued for. Something changed between the
+    reviewed dry run and this run, e.g. an edited config.yaml or a different
+    --prefer, so nothing was applied.
+
+    Resolve:
+      envmgr --dry-run merge <source> <dest>   # review the new plan and token
+      envmgr merge <source> <dest> --confirm-token <new token>
+"};
+
+const EXPLAIN_E008: &str = indoc::indoc! {"
+    E008: Debug bundle could not be created or replayed
+
+    Causes:
+    - A file still looked like it held a secret after redaction, so no bundle
+      was written rather than risk leaking it
+    - The bundle is damaged, from a newer envmgr, or holds unexpected paths
+    - The replay directory is not empty
+
+    Resolve:
+      Move the secret into 1Password or mark the env var `secret: true`
+      envmgr debug-bundle replay <bundle> <new empty dir>
+"};
+
+const EXPLAIN_E009: &str = indoc::indoc! {"
+    E009: Template could not be installed or found
+
+    Causes:
+    - `add --template <name>` names neither an environment nor an installed
+      remote template
+    - The template repo has no config.yaml at its root
+    - The alias is already taken by a template from a different url
+
+    Resolve:
+      envmgr template list
+      envmgr template install <git-url> --name <other alias>
+"};
+
+const EXPLAIN_E010: &str = indoc::indoc! {"
+    E010: Configuration could not be parsed
+
+    A config.yaml (or the gh hosts file) is not valid YAML or does not match
+    the expected structure.
+
+    Causes:
+    - Indentation or quoting mistakes
+    - Missing required fields such as `name` or an env var `value`
+
+    Resolve:
+      envmgr validate             # reports the file, YAML path and line
+"};
+
+const EXPLAIN_E011: &str = indoc::indoc! {"
+    E011: Validation failed
+
+    `envmgr validate` found at least one error in the configs.
+
+    Resolve:
+      envmgr validate             # fix each reported error and re-run
+"};
+
+const EXPLAIN_E012: &str = indoc::indoc! {"
+    E012: State or TOML file could not be read
+
+    envmgr's state file (or another TOML file it reads) is corrupted or was
+    written by an incompatible version.
+
+    Causes:
+    - Manual edits to the state file
+    - Running a much newer or older envmgr against the same state directory
+
+    Resolve:
+      ls ~/.local/state/envmgr/   # inspect state.toml / state.yaml
+      envmgr link                 # re-create managed links after fixing or
+                                  # removing the broken state file
+"};
+
+const EXPLAIN_E013: &str = indoc::indoc! {"
+    E013: Serialization failed
+
+    envmgr could not serialize data to TOML, YAML or JSON. This usually
+    points at a bug; please report it with the command you ran.
+
+    Resolve:
+      RUST_LOG=debug envmgr <command>   # include this output in a report
+"};
+
+const EXPLAIN_E014: &str = indoc::indoc! {"
+    E014: Invalid local.yaml
+
+    A machine-local local.yaml next to an environment's config.yaml could not
+    be applied. It accepts env_vars and integration blocks, but not `name`:
+    an environment's identity only comes from the shared config.yaml.
+
+    Resolve:
+      envmgr validate             # reports the offending local.yaml
+"};
+
+const EXPLAIN_E015: &str = indoc::indoc! {"
+    E015: Unknown timezone
+
+    An environment's `timezone:` does not name a zone in the system's tzdata
+    (/usr/share/zoneinfo, or $TZDIR). An unknown TZ silently falls back to
+    UTC, so envmgr refuses to load it.
+
+    Resolve:
+      ls /usr/share/zoneinfo/Europe   # list valid names, e.g. Europe/Budapest
+      envmgr validate                 # shows a suggestion for each typo
+"};
+
+const EXPLAIN_E016: &str = indoc::indoc! {"
+    E016: Env var value could not be expanded
+
+    A `value:` references `${NAME}`, but NAME is neither a built-in
+    (ENVMGR_ENV, ENVMGR_CONFIG_DIR) nor set in the environment envmgr runs
+    in, or the reference is malformed (unterminated `${`, empty name).
+
+    Resolve:
+      Fix the typo, export the variable, or write `$${` for a literal `${`
+"};
+
+const EXPLAIN_E017: &str = indoc::indoc! {"
+    E017: Dynamic env var value could not be resolved
+
+    A `value_from_command:` failed, printed something other than UTF-8 text
+    or ran past the timeout, a `value_from_file:` is missing or isn't UTF-8
+    text, or `op` couldn't read an `op://` reference (not signed in, item
+    missing). The other variables were still emitted, and the failing ones
+    kept the value they already had in the shell.
+
+    Resolve:
+      Run the command shown in the message yourself to see what it needs
+      (e.g. `op signin` for `op read`), raise `value_command_timeout_secs`
+      in global.yaml, create the file, run `op signin`, or use
+      `envmgr use --skip-dynamic` to not resolve dynamic values at all
+"};
+
+const EXPLAIN_E018: &str = indoc::indoc! {"
+    E018: Malformed dotenv file
+
+    An environment's env.dotenv or vars.env has a line that isn't
+    `KEY=VALUE`, a key that isn't a valid variable name, text after a
+    closing quote, or a quote that is never closed.
+
+    Resolve:
+      envmgr validate             # list every malformed line
+      Quote values containing spaces followed by `#`, and remove stray text
+"};
+
+const EXPLAIN_E019: &str = indoc::indoc! {"
+    E019: Environment could not be exported
+
+    `envmgr export-env` can't represent a value in the requested format,
+    e.g. a multiline value in a docker env file, which takes every line as
+    a separate `KEY=value`.
+
+    Resolve:
+      envmgr export-env --format dotenv          # escapes newlines
+      envmgr export-env --format github-actions  # uses heredocs
+"};
+
+const EXPLAIN_E020: &str = indoc::indoc! {"
+    E020: Link conflict
+
+    A file appeared at a link target while envmgr was creating the symlink,
+    so it refused to overwrite it.
+
+    Causes:
+    - Another program (or a second envmgr run) wrote the file concurrently
+    - An application recreated its config file while linking was running
+
+    Resolve:
+      ls -l <path>                # inspect the conflicting file
+      mv <path> <path>.bak        # move it aside if envmgr should own it
+      envmgr link
+"};
+
+const EXPLAIN_E021: &str = indoc::indoc! {"
+    E021: Unsupported on this platform
+
+    The operation is not available on this operating system yet. File
+    linking currently requires a Unix-like system.
+
+    Resolve:
+      envmgr use --shell powershell   # env vars still work on Windows
+"};
+
+const EXPLAIN_E022: &str = indoc::indoc! {"
+    E022: Backup not found
+
+    No backup is recorded for the given path. Backups are taken when linking
+    with `--adopt-backups` finds a real file at a link target, and are
+    dropped once restored.
+
+    Resolve:
+      envmgr backups list             # the original paths and backup files
+      envmgr backups restore <path>   # either of the two
+"};
+
+const EXPLAIN_E023: &str = indoc::indoc! {"
+    E023: Invalid files manifest
+
+    An environment's files.yaml could not be read, or one of its entries has a
+    source outside files/ or a target outside the home directory. Targets
+    outside home need `allow_outside_home: true` on the entry.
+
+    Resolve:
+      envmgr validate   # lists every problem of every manifest
+"};
+
+const EXPLAIN_E024: &str = indoc::indoc! {"
+    E024: Hook failed
+
+    The environment's hooks/pre-switch script could not be run or exited with a
+    non-zero status, so the switch was not made. Its output is logged above.
+    Failing post-switch and post-link hooks only print a warning.
+
+    Resolve:
+      chmod +x <env>/hooks/pre-switch   # hooks must be executable
+      envmgr switch <env> --no-hooks    # switch without running any hooks
+"};
+
+const EXPLAIN_E030: &str = indoc::indoc! {"
+    E030: GitHub CLI integration failed
+
+    The gh_cli integration could not select the configured user in
+    ~/.config/gh/hosts.yml.
+
+    Causes:
+    - The user was never logged in to the host on this machine, and
+      the switch wasn't attached to a terminal or given --login
+    - gh auth login logged in a different account
+    - hosts.yml predates gh 2.40 and has no users section
+
+    Resolve:
+      gh auth login --hostname <host>
+      gh auth status              # confirm the user is listed
+      envmgr switch <key>         # or: envmgr switch <key> --login
+"};
+
+const EXPLAIN_E031: &str = indoc::indoc! {"
+    E031: Tailscale integration failed
+
+    The tailscale integration could not list or switch tailnets.
+
+    Causes:
+    - tailscale is not installed or the daemon is not running
+    - The configured tailnet's account was never added on this machine
+
+    Resolve:
+      tailscale switch --list     # check the configured tailnet is listed
+      tailscale login             # add the missing account
+      envmgr switch <key>
+"};
+
+const EXPLAIN_E032: &str = indoc::indoc! {"
+    E032: Integration not configured
+
+    `envmgr integrations run` was asked to apply an integration that the
+    environment's config.yaml (and local.yaml) does not configure.
+
+    Resolve:
+      envmgr integrations list            # see which environments configure it
+      envmgr integrations run <name> --env <key>
+"};
+
+const EXPLAIN_E033: &str = indoc::indoc! {"
+    E033: Git command failed
+
+    A git clone or query for a remote template failed. Failing updates are
+    not errors: the cached clone keeps being used with a warning.
+
+    Causes:
+    - git is not installed or not on PATH
+    - The url is wrong, unreachable, or needs credentials git doesn't have
+
+    Resolve:
+      git clone <git-url>   # check the url and credentials by hand
+"};
+
+const EXPLAIN_E034: &str = indoc::indoc! {"
+    E034: 1Password documents could not be fetched
+
+    A switch fetches every `op_documents` entry with `op document get` before it
+    changes anything, and one of them failed, so the switch was not made.
+
+    Causes:
+    - op (the 1Password CLI) is not installed or not on PATH
+    - op is not signed in, or the CLI integration of the app is off
+    - The vault or item name is wrong, or the item is not a document
+
+    Resolve:
+      op signin
+      op document get <item> --vault <vault>   # check the entry by hand
+      envmgr switch <key> --no-link            # switch without writing files
+"};
+
+const EXPLAIN_E035: &str = indoc::indoc! {"
+    E035: Git identity integration failed
+
+    The git integration writes the environment's identity to
+    ~/.config/git/envmgr.inc and adds an `[include]` of it to ~/.gitconfig,
+    and one of the two could not be read or written.
+
+    Causes:
+    - ~/.gitconfig is not valid UTF-8
+    - ~/.gitconfig or ~/.config/git is not writable
+
+    Resolve:
+      git config --global --list --show-origin   # check ~/.gitconfig parses
+      envmgr integrations run git                # retry the integration
+"};
+
+const EXPLAIN_E036: &str = indoc::indoc! {"
+    E036: Kubernetes integration failed
+
+    The kube integration could not make the configured context and namespace
+    current in the kubeconfig.
+
+    Causes:
+    - The kubeconfig does not exist on this machine or is not valid YAML
+    - The context is not defined in the kubeconfig
+    - kubectl is not installed or not on PATH
+
+    Resolve:
+      kubectl config get-contexts --kubeconfig <kubeconfig>
+      envmgr validate                  # checks the kubeconfig and context
+      envmgr integrations run kube     # retry the integration
+"};
+
+const EXPLAIN_E037: &str = indoc::indoc! {"
+    E037: AWS integration failed
+
+    The aws integration could not find the configured profile or log in to its
+    SSO session.
+
+    Causes:
+    - The AWS config file does not exist on this machine
+    - The profile is not defined in the AWS config file
+    - sso_login is set for a profile without sso_session or sso_start_url
+    - `aws sso login` failed or the AWS CLI is not installed
+
+    Resolve:
+      aws configure list-profiles
+      aws sso login --profile <profile>
+      envmgr integrations run aws      # retry the integration
+"};
+
+const EXPLAIN_E038: &str = indoc::indoc! {"
+    E038: gcloud integration failed
+
+    The gcloud integration could not activate the configured configuration or
+    set its project or account.
+
+    Causes:
+    - The configuration does not exist on this machine, e.g. a typo
+    - gcloud is not installed or not on PATH
+    - `gcloud config set` rejected the project or account
+
+    Resolve:
+      gcloud config configurations list
+      gcloud config configurations create <name>
+      envmgr integrations run gcloud   # retry the integration
+"};
+
+const EXPLAIN_E039: &str = indoc::indoc! {"
+    E039: npm integration failed
+
+    The npm integration could not write its registries to ~/.npmrc or link the
+    configured npmrc there.
+
+    Causes:
+    - An auth token reference could not be read from 1Password
+    - npmrc_source does not exist in the environment dir
+    - ~/.npmrc has settings of its own, so it is not replaced by a link
+
+    Resolve:
+      op read <reference>              # check the token reference
+      mv ~/.npmrc ~/.npmrc.bak         # make room for the link
+      envmgr integrations run npm      # retry the integration
+"};
+
+const EXPLAIN_E040: &str = indoc::indoc! {"
+    E040: Directory could not be determined
+
+    envmgr could not determine a standard directory (home, config or state).
+
+    Causes:
+    - HOME (or the platform equivalent) is unset in the environment
+
+    Resolve:
+      echo $HOME                  # make sure it is set
+"};
+
+const EXPLAIN_E050: &str = indoc::indoc! {"
+    E050: I/O error
+
+    Reading or writing a file failed.
+
+    Causes:
+    - Missing permissions on the config, state or target directory
+    - The disk is full or the path is on a read-only filesystem
+
+    Resolve:
+      ls -ld <path>               # check ownership and permissions
+"};
+
+const EXPLAIN_E060: &str = indoc::indoc! {"
+    E060: Interactive prompt failed
+
+    envmgr needed to ask a question but could not use the terminal.
+
+    Causes:
+    - Running without a TTY (CI, pipes, cron)
+
+    Resolve:
+      pass the value as an argument instead, e.g. `envmgr switch <key>`
+"};
+
+const EXPLAIN_E070: &str = indoc::indoc! {"
+    E070: ssh integration failed
+
+    The ssh integration could not write ~/.ssh/envmgr_env.conf or add its
+    Include line to ~/.ssh/config.
+
+    Causes:
+    - config_file does not exist in the environment dir
+    - ~/.ssh/config is not valid UTF-8 or not writable
+
+    Resolve:
+      ls ~/.config/envmgr/environments/<key>/   # check config_file
+      envmgr integrations run ssh               # retry the integration
+"};
+
+const EXPLAIN_E071: &str = indoc::indoc! {"
+    E071: gpg integration failed
+
+    The gpg integration could not find the configured key or set it as
+    default-key in gpg.conf.
+
+    Causes:
+    - The secret key is not in the keyring on this machine
+    - gpg is not installed or not on PATH
+    - gpg.conf is not valid UTF-8 or not writable
+
+    Resolve:
+      gpg --list-secret-keys --keyid-format long
+      gpg --import <secret key file>
+      envmgr integrations run gpg      # retry the integration
+"};
+
+const EXPLAIN_E072: &str = indoc::indoc! {"
+    E072: 1Password SSH key not found
+
+    A key of `op_ssh` names a vault, item or account that has no SSH Key
+    item in 1Password, or names an item several vaults have. The agent
+    would not serve the key, and ssh would fail later without saying why.
+
+    Causes:
+    - A typo in the vault or item name
+    - The item is in another vault or account than configured
+    - Items of the same name in several vaults, with no vault given
+
+    Resolve:
+      op item list --categories 'SSH Key'       # the names to use
+      envmgr doctor                             # check every key again
+      envmgr switch <key>                       # without --check-op-keys
+"};
+
+const EXPLAIN_E073: &str = indoc::indoc! {"
+    E073: mise integration failed
+
+    The mise integration could not write the tool versions, or could not
+    ask mise or asdf which versions are installed.
+
+    Causes:
+    - ~/.tool-versions is not valid UTF-8 or not writable
+    - mise (or asdf with `format: asdf`) is not installed or not on PATH
+    - `mise ls --json` printed something envmgr doesn't understand
+
+    Resolve:
+      mise ls                          # the versions mise sees
+      mise install                     # install the missing ones
+      envmgr integrations run mise     # retry the integration
+"};
+
+const EXPLAIN_E074: &str = indoc::indoc! {"
+    E074: Could not update tmux
+
+    `propagate_to_tmux` is on and a tmux server is running, but setting a
+    variable in its global environment failed. `envmgr use` only warns
+    about it; the shell itself still got the variables.
+
+    Resolve:
+      tmux show-environment -g             # what new panes inherit
+      tmux kill-server                     # if the server is stuck
+"};
+
+const EXPLAIN_E075: &str = indoc::indoc! {"
+    E075: Maven integration failed
+
+    The maven integration could not link the environment's settings.xml to
+    ~/.m2/settings.xml, or could not write ~/.gradle/gradle.properties.
+
+    Causes:
+    - settings_source points to a file that doesn't exist
+    - both ~/.m2/settings.xml and settings.xml.envmgr-personal exist, so
+      the personal settings have nowhere to go
+    - an op:// gradle property could not be read from 1Password
+    - ~/.gradle/gradle.properties is not valid UTF-8 or not writable
+
+    Resolve:
+      ls -l ~/.m2/                      # what settings.xml points to
+      envmgr integrations run maven     # retry the integration
+"};
+
+const EXPLAIN_E076: &str = indoc::indoc! {"
+    E076: Python integration failed
+
+    The python integration could not write the package indexes to
+    ~/.config/pip/pip.conf or ~/.config/uv/uv.toml.
+
+    Causes:
+    - one of the files is not valid UTF-8 or not writable
+    - uv.toml is not valid TOML, or its `index` is not an array of tables
+
+    Resolve:
+      pip config list                    # the settings pip sees
+      uv pip install --dry-run <pkg>     # whether uv.toml parses
+      envmgr integrations run python     # retry the integration
+"};
+
+const EXPLAIN_E077: &str = indoc::indoc! {"
+    E077: Cargo integration failed
+
+    The cargo integration could not write the registries to
+    ~/.cargo/config.toml or their tokens to ~/.cargo/credentials.toml.
+
+    Causes:
+    - one of the files is not valid TOML or UTF-8, or not writable
+    - a `[registries.<name>]` table or `registry.default` the environment
+      sets is the user's own, not one envmgr added
+    - a `token_ref` 1Password reference could not be read
+
+    Resolve:
+      cargo config get registries        # the registries cargo sees
+      op read op://Vault/Item/token      # check the reference
+      envmgr integrations run cargo      # retry the integration
+"};
+
+const EXPLAIN_E078: &str = indoc::indoc! {"
+    E078: Integrations failed during a switch with --continue-on-error
+
+    `envmgr switch --continue-on-error` ran every integration even though
+    some failed. The environment is the current one now, but the failed
+    integrations may still be set up for the previous one, or only partly
+    for this one. Each one's error is listed.
+
+    Without --continue-on-error the first failure rolls back the
+    integrations that ran and the previous environment stays current.
+
+    Causes:
+    - the same as when the integration fails on its own
+
+    Resolve:
+      envmgr doctor                           # what is not in place
+      envmgr integrations run <integration>   # retry one of them
+"};
+
+const EXPLAIN_E079: &str = indoc::indoc! {"
+    E079: Integration timed out
+
+    An integration was still running when its timeout ran out during
+    `envmgr switch`, so the switch gave up on it instead of waiting. The
+    command it ran may be stuck, e.g. `tailscale` with its daemon not
+    responding or `op` waiting for the 1Password app. Time spent on a login
+    that waits for you doesn't count.
+
+    Resolve:
+      envmgr integrations run <integration>   # run it alone to see where it hangs
+      Raise `integration_timeout_secs` in global.yaml, or the integration's
+      entry of `integration_timeouts`, e.g. `integration_timeouts: {tailscale: 30}`
+"};
+
+const EXPLAIN_E099: &str = indoc::indoc! {"
+    E099: Unexpected error
+
+    An error without a more specific category occurred.
+
+    Resolve:
+      RUST_LOG=debug envmgr <command>   # re-run with debug logging
+"};
+
 #[cfg(test)]
 mod tests {
+    use saphyr::{LoadableYamlNode, Yaml};
+
     use super::*;
 
     #[test]
@@ -43,6 +1058,123 @@ mod tests {
         assert!(env_error.to_string().contains("Toml Deserialization Error"));
     }
 
+    /// One sample of every error variant, so new variants must be added here
+    fn sample_errors() -> Vec<EnvMgrError> {
+        vec![
+            std::io::Error::other("io").into(),
+            config::ConfigError::Message("config".into()).into(),
+            toml::from_str::<toml::Value>("x = [[[").unwrap_err().into(),
+            EnvMgrError::TomlSerialization(<toml::ser::Error as serde::ser::Error>::custom("ser")),
+            serde_json::from_str::<serde_json::Value>("{")
+                .unwrap_err()
+                .into(),
+            EnvMgrError::DirError("home".into()),
+            EnvMgrError::GhCliConfig("gh".into()),
+            Yaml::load_from_str("a: [").unwrap_err().into(),
+            saphyr::EmitError::FmtError(std::fmt::Error).into(),
+            EnvMgrError::EnvironmentNotFound("work".into()),
+            EnvMgrError::LinkConflict("/tmp/x".into()),
+            EnvMgrError::BackupNotFound("/tmp/x".into()),
+            EnvMgrError::InvalidFilesManifest("/tmp/files.yaml".into(), "bad".into()),
+            EnvMgrError::HookFailed {
+                hook: "pre-switch".into(),
+                env: "work".into(),
+                reason: "exited with status 1".into(),
+            },
+            EnvMgrError::Tailscale("ts".into()),
+            EnvMgrError::Git("clone failed".into()),
+            EnvMgrError::OnePassword("op is not signed in".into()),
+            EnvMgrError::GitIdentity("~/.gitconfig is not valid UTF-8".into()),
+            EnvMgrError::Kube("context 'prod' is not in ~/.kube/config".into()),
+            EnvMgrError::Aws("profile 'work' is not in ~/.aws/config".into()),
+            EnvMgrError::Gcloud("configuration 'abc' does not exist".into()),
+            EnvMgrError::Npm("npmrc_source files/npmrc does not exist".into()),
+            EnvMgrError::Ssh("could not read ssh/envmgr.conf".into()),
+            EnvMgrError::Gpg("secret key 0123456789ABCDEF is not in the keyring".into()),
+            EnvMgrError::Mise("~/.tool-versions is not valid UTF-8".into()),
+            EnvMgrError::Maven("settings_source maven/settings.xml does not exist".into()),
+            EnvMgrError::Python("uv.toml is not valid TOML".into()),
+            EnvMgrError::Cargo("config.toml is not valid TOML".into()),
+            EnvMgrError::IntegrationsFailed(vec!["tailscale: tailscale is not installed".into()]),
+            EnvMgrError::IntegrationTimeout {
+                integration: "tailscale".into(),
+                secs: 10,
+            },
+            EnvMgrError::OpSshKey("no SSH Key item matches vault 'Wrok'".into()),
+            EnvMgrError::Tmux("tmux set-environment failed: server exited unexpectedly".into()),
+            EnvMgrError::Template("no template 'x'".into()),
+            EnvMgrError::IntegrationNotConfigured {
+                integration: "tailscale".into(),
+                env: "work".into(),
+            },
+            EnvMgrError::NoPreviousEnvironment,
+            dialoguer::Error::IO(std::io::Error::other("tty")).into(),
+            EnvMgrError::Unsupported("windows".into()),
+            EnvMgrError::Validation(1),
+            EnvMgrError::InvalidLocalOverride("local.yaml".into(), "name".into()),
+            EnvMgrError::InvalidTimezone {
+                timezone: "Europe/Buda".into(),
+                suggestion: Some("Europe/Budapest".into()),
+            },
+            EnvMgrError::Interpolation {
+                file: "config.yaml".into(),
+                key: "KUBECONFIG".into(),
+                message: "unknown variable '${HOEM}'".into(),
+            },
+            EnvMgrError::UnresolvedEnvVars(vec![
+                "GITHUB_TOKEN: `op read op://Work/GitHub/token` exited with status 1".into(),
+            ]),
+            EnvMgrError::Dotenv {
+                file: "env.dotenv".into(),
+                line: 3,
+                message: "expected KEY=VALUE, found 'oops'".into(),
+            },
+            EnvMgrError::Export("KEY spans multiple lines".into()),
+            EnvMgrError::UnknownErrorCode("E999".into(), "E001".into()),
+            EnvMgrError::InvalidEnvironmentKey("base".into()),
+            EnvMgrError::EnvironmentAlreadyExists {
+                key: "work".into(),
+                name: Some("Work".into()),
+            },
+            EnvMgrError::MissingArgument("--gh-user".into()),
+            EnvMgrError::ConfirmTokenMismatch {
+                token: "abc".into(),
+                current: "def".into(),
+            },
+            EnvMgrError::DebugBundle("manifest.json is missing".into()),
+            serde_norway::from_str::<u32>("a").unwrap_err().into(),
+            EnvMgrError::Other("other".into()),
+        ]
+    }
+
+    #[test]
+    fn test_every_emitted_code_has_explanation_and_vice_versa() {
+        let emitted: std::collections::HashSet<ErrorCode> =
+            sample_errors().iter().map(EnvMgrError::code).collect();
+        for code in &emitted {
+            assert!(ErrorCode::ALL.contains(code), "{code} missing from ALL");
+            assert!(code.explanation().starts_with(&code.to_string()));
+        }
+        for code in ErrorCode::ALL {
+            assert!(emitted.contains(code), "{code} is never emitted");
+        }
+        assert!(ErrorCode::ALL.len() >= 10);
+    }
+
+    #[test]
+    fn test_error_code_parse() {
+        assert_eq!(ErrorCode::parse("E020"), Some(ErrorCode::E020));
+        assert_eq!(ErrorCode::parse("e001"), Some(ErrorCode::E001));
+        assert_eq!(ErrorCode::parse("E999"), None);
+    }
+
+    #[test]
+    fn test_error_json_includes_code() {
+        let json = EnvMgrError::EnvironmentNotFound("work".into()).to_json();
+        assert_eq!(json["error"]["code"], "E001");
+        assert_eq!(json["error"]["message"], "Environment 'work' not found");
+    }
+
     #[test]
     fn test_dir_error_message() {
         let error = EnvMgrError::DirError("home".to_string());
@@ -0,0 +1,396 @@
+//! File access behind a trait, so writes can be observed and faked in tests.
+//!
+//! Everything envmgr changes on disk (state, links, integration files) goes through
+//! [`Fs`], which lets the crash tests stop a run after any single operation.
+
+use std::{io, path::Path};
+
+pub trait Fs {
+    /// Contents of `path`, `None` when it doesn't exist
+    fn read(&self, path: &Path) -> io::Result<Option<Vec<u8>>>;
+    /// Create or truncate `path` and write `content`; its parent must exist
+    fn write(&self, path: &Path, content: &[u8]) -> io::Result<()>;
+    /// Move `from` over `to`, atomically on the same filesystem
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// Create a symbolic link at `target` pointing to `source`
+    fn symlink(&self, source: &Path, target: &Path) -> io::Result<()>;
+    /// Remove a file or symlink
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+    /// Set the permission bits of `path`; nothing happens where there are none
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()>;
+
+    /// Replace the contents of `path` through a temporary file, creating its parent
+    /// directories, so it holds either the old or the new content at any time
+    fn write_atomic(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+        let tmp_path = temp_file_path(path);
+        self.write(&tmp_path, content)?;
+        self.rename(&tmp_path, path)
+    }
+
+    /// [`Fs::write_atomic`] with the permission bits `mode`. They are set before the
+    /// content is written, so it is never readable with wider ones.
+    fn write_atomic_with_mode(&self, path: &Path, content: &[u8], mode: u32) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+        let tmp_path = temp_file_path(path);
+        self.write(&tmp_path, b"")?;
+        self.set_mode(&tmp_path, mode)?;
+        self.write(&tmp_path, content)?;
+        self.rename(&tmp_path, path)
+    }
+
+    /// Point the link at `target` to `source` by creating the new link next to it and
+    /// renaming it over `target`, so `target` exists throughout. The temporary link is
+    /// removed again when the rename fails.
+    fn replace_symlink(&self, source: &Path, target: &Path) -> io::Result<()> {
+        let tmp_path = temp_link_path(target);
+        match self.symlink(source, &tmp_path) {
+            // Left behind by a run that was killed before its rename
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                self.remove_file(&tmp_path)?;
+                self.symlink(source, &tmp_path)?;
+            }
+            result => result?,
+        }
+        self.rename(&tmp_path, target).inspect_err(|_| {
+            let _ = self.remove_file(&tmp_path);
+        })
+    }
+}
+
+/// Where [`Fs::write_atomic`] writes the new content before renaming it over `path`
+fn temp_file_path(path: &Path) -> std::path::PathBuf {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    path.with_file_name(tmp_name)
+}
+
+/// Where [`Fs::replace_symlink`] creates the new link before renaming it over `target`
+pub(crate) fn temp_link_path(target: &Path) -> std::path::PathBuf {
+    let mut tmp_name = target.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".envmgr-tmp");
+    target.with_file_name(tmp_name)
+}
+
+/// [`Fs`] on the real filesystem
+pub struct RealFs;
+
+impl Fs for RealFs {
+    fn read(&self, path: &Path) -> io::Result<Option<Vec<u8>>> {
+        match std::fs::read(path) {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        std::fs::write(path, content)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    #[cfg(unix)]
+    fn symlink(&self, source: &Path, target: &Path) -> io::Result<()> {
+        std::os::unix::fs::symlink(source, target)
+    }
+
+    #[cfg(not(unix))]
+    fn symlink(&self, _source: &Path, _target: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "file linking is not supported on this platform yet",
+        ))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    #[cfg(unix)]
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+    }
+
+    #[cfg(not(unix))]
+    fn set_mode(&self, _path: &Path, _mode: u32) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// [`RealFs`] that dies at a chosen operation, like a process killed mid-run.
+///
+/// Operations are numbered from 0. The crashing one has no effect, except that a
+/// write leaves the first half of its content behind (a torn write); it and every
+/// later operation fail.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct CrashingFs {
+    crash_at: Option<usize>,
+    ops: std::cell::Cell<usize>,
+}
+
+#[cfg(test)]
+impl CrashingFs {
+    pub fn crash_at(op: usize) -> Self {
+        Self {
+            crash_at: Some(op),
+            ..Self::default()
+        }
+    }
+
+    /// Operations attempted so far, including the crashing one
+    #[cfg(unix)]
+    pub fn ops(&self) -> usize {
+        self.ops.get()
+    }
+
+    pub fn crashed(&self) -> bool {
+        self.crash_at.is_some_and(|op| self.ops.get() > op)
+    }
+
+    /// Count an operation, `Err` when the process is dead by now
+    fn step(&self, on_crash: impl FnOnce()) -> io::Result<()> {
+        let op = self.ops.get();
+        self.ops.set(op + 1);
+        match self.crash_at {
+            Some(crash_at) if op == crash_at => {
+                on_crash();
+                Err(io::Error::other(format!(
+                    "simulated crash at operation {op}"
+                )))
+            }
+            Some(crash_at) if op > crash_at => Err(io::Error::other("process crashed")),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Fs for CrashingFs {
+    fn read(&self, path: &Path) -> io::Result<Option<Vec<u8>>> {
+        self.step(|| {})?;
+        RealFs.read(path)
+    }
+
+    fn write(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        self.step(|| {
+            let _ = std::fs::write(path, &content[..content.len() / 2]);
+        })?;
+        RealFs.write(path, content)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.step(|| {})?;
+        RealFs.rename(from, to)
+    }
+
+    fn symlink(&self, source: &Path, target: &Path) -> io::Result<()> {
+        self.step(|| {})?;
+        RealFs.symlink(source, target)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.step(|| {})?;
+        RealFs.remove_file(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.step(|| {})?;
+        RealFs.create_dir_all(path)
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        self.step(|| {})?;
+        RealFs.set_mode(path, mode)
+    }
+}
+
+/// In-memory [`Fs`] recording every write, standing in for mtimes in tests
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct MemFs {
+    files: std::cell::RefCell<std::collections::HashMap<std::path::PathBuf, Vec<u8>>>,
+    /// Paths whose content was replaced, recorded when it lands at the path
+    pub writes: std::cell::RefCell<Vec<std::path::PathBuf>>,
+    /// Permission bits set on each path, moving along with renames
+    pub modes: std::cell::RefCell<std::collections::HashMap<std::path::PathBuf, u32>>,
+}
+
+#[cfg(test)]
+impl MemFs {
+    pub fn with_file(self, path: impl Into<std::path::PathBuf>, content: &str) -> Self {
+        self.files
+            .borrow_mut()
+            .insert(path.into(), content.as_bytes().to_vec());
+        self
+    }
+
+    pub fn content(&self, path: &Path) -> Option<String> {
+        self.files
+            .borrow()
+            .get(path)
+            .map(|c| String::from_utf8_lossy(c).into_owned())
+    }
+}
+
+#[cfg(test)]
+impl Fs for MemFs {
+    fn read(&self, path: &Path) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.files.borrow().get(path).cloned())
+    }
+
+    fn write(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        self.files
+            .borrow_mut()
+            .insert(path.to_path_buf(), content.to_vec());
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let content = self.files.borrow_mut().remove(from);
+        let content = content.ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let mut modes = self.modes.borrow_mut();
+        if let Some(mode) = modes.remove(from) {
+            modes.insert(to.to_path_buf(), mode);
+        }
+        self.writes.borrow_mut().push(to.to_path_buf());
+        self.files.borrow_mut().insert(to.to_path_buf(), content);
+        Ok(())
+    }
+
+    fn symlink(&self, _source: &Path, _target: &Path) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.files.borrow_mut().remove(path);
+        Ok(())
+    }
+
+    fn create_dir_all(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        self.modes.borrow_mut().insert(path.to_path_buf(), mode);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, path::PathBuf};
+
+    use super::*;
+
+    /// [`RealFs`] checking after every operation that `watched` is still there
+    struct WatchingFs {
+        watched: PathBuf,
+        ops: RefCell<Vec<&'static str>>,
+    }
+
+    impl WatchingFs {
+        fn step(&self, op: &'static str, result: io::Result<()>) -> io::Result<()> {
+            self.ops.borrow_mut().push(op);
+            assert!(
+                self.watched.symlink_metadata().is_ok(),
+                "{} is missing after {op}",
+                self.watched.display()
+            );
+            result
+        }
+    }
+
+    impl Fs for WatchingFs {
+        fn read(&self, path: &Path) -> io::Result<Option<Vec<u8>>> {
+            RealFs.read(path)
+        }
+
+        fn write(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+            self.step("write", RealFs.write(path, content))
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.step("rename", RealFs.rename(from, to))
+        }
+
+        fn symlink(&self, source: &Path, target: &Path) -> io::Result<()> {
+            self.step("symlink", RealFs.symlink(source, target))
+        }
+
+        fn remove_file(&self, path: &Path) -> io::Result<()> {
+            self.step("remove_file", RealFs.remove_file(path))
+        }
+
+        fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+            self.step("create_dir_all", RealFs.create_dir_all(path))
+        }
+
+        fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+            self.step("set_mode", RealFs.set_mode(path, mode))
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_replace_symlink_keeps_the_target_in_place() {
+        let dir = temp_dir("envmgr_test_replace_symlink");
+        let (old, new, target) = (dir.join("old"), dir.join("new"), dir.join("link"));
+        std::fs::write(&new, "new").unwrap();
+        let fs = WatchingFs {
+            watched: target.clone(),
+            ops: RefCell::default(),
+        };
+
+        // A dangling link is taken over the same way
+        RealFs.symlink(&old, &target).unwrap();
+        fs.replace_symlink(&new, &target).unwrap();
+
+        assert_eq!(*fs.ops.borrow(), ["symlink", "rename"]);
+        assert_eq!(std::fs::read_link(&target).unwrap(), new);
+        assert!(temp_link_path(&target).symlink_metadata().is_err());
+
+        // A temporary link left behind by a killed run is replaced
+        RealFs.symlink(&old, &temp_link_path(&target)).unwrap();
+        fs.replace_symlink(&old, &target).unwrap();
+        assert_eq!(std::fs::read_link(&target).unwrap(), old);
+        assert!(temp_link_path(&target).symlink_metadata().is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replace_symlink_cleans_up_when_the_rename_fails() {
+        let dir = temp_dir("envmgr_test_replace_symlink_fails");
+        // A symlink can't be renamed over a non-empty directory
+        let target = dir.join("config");
+        std::fs::create_dir_all(target.join("app")).unwrap();
+
+        assert!(RealFs.replace_symlink(&dir.join("new"), &target).is_err());
+
+        assert!(temp_link_path(&target).symlink_metadata().is_err());
+        assert!(target.join("app").is_dir());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,61 @@
+//! "Did you mean" suggestions for mistyped names.
+
+/// Edit distance between `a` and `b`, compared case-insensitively
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.to_lowercase().chars().collect();
+    let b: Vec<char> = b.to_lowercase().chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut row = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(ca != cb);
+            row.push(substitution.min(prev[j + 1] + 1).min(row[j] + 1));
+        }
+        prev = row;
+    }
+    prev[b.len()]
+}
+
+/// The candidate closest to `input`, if any is close enough to be a plausible typo.
+///
+/// A candidate starting with `input` (e.g. `Europe/Buda` for `Europe/Budapest`) always counts.
+pub fn closest_match<'a>(
+    input: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<&'a str> {
+    let max_distance = (input.chars().count() / 3).max(2);
+    let lowered = input.to_lowercase();
+    candidates
+        .into_iter()
+        .map(|candidate| {
+            let distance = if candidate.to_lowercase().starts_with(&lowered) {
+                0
+            } else {
+                edit_distance(input, candidate)
+            };
+            (distance, candidate)
+        })
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("UTC", "utc"), 0);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn test_closest_match() {
+        let zones = ["Europe/Budapest", "Europe/Berlin", "America/New_York"];
+        assert_eq!(closest_match("Europe/Buda", zones), Some("Europe/Budapest"));
+        assert_eq!(closest_match("Europe/Berlim", zones), Some("Europe/Berlin"));
+        assert_eq!(closest_match("Asia/Tokyo", zones), None);
+    }
+}
@@ -0,0 +1,61 @@
+//! Thin wrapper around the `git` binary for the clones envmgr keeps in its config dir.
+
+use std::path::Path;
+
+use crate::{
+    error::{EnvMgrError, EnvMgrResult},
+    runner::CommandRunner,
+};
+
+pub struct Git<'r> {
+    runner: &'r dyn CommandRunner,
+}
+
+impl<'r> Git<'r> {
+    pub fn new(runner: &'r dyn CommandRunner) -> Self {
+        Self { runner }
+    }
+
+    /// Run git with `args`, returning trimmed stdout or the failure as [`EnvMgrError::Git`]
+    fn git(&self, args: &[&str]) -> EnvMgrResult<String> {
+        let output = self.runner.run("git", args).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => EnvMgrError::Git("git is not installed".into()),
+            _ => EnvMgrError::Io(e),
+        })?;
+        if !output.success {
+            return Err(EnvMgrError::Git(format!(
+                "git {} failed: {}",
+                args.join(" "),
+                output.stderr.trim()
+            )));
+        }
+        Ok(output.stdout.trim().to_string())
+    }
+
+    fn in_dir(&self, dir: &Path, args: &[&str]) -> EnvMgrResult<String> {
+        let dir = dir.to_string_lossy();
+        let mut full = vec!["-C", dir.as_ref()];
+        full.extend_from_slice(args);
+        self.git(&full)
+    }
+
+    pub fn clone(&self, url: &str, dest: &Path) -> EnvMgrResult<()> {
+        self.git(&["clone", "--quiet", url, &dest.to_string_lossy()])?;
+        Ok(())
+    }
+
+    /// Fast-forward the clone in `dir` to its upstream
+    pub fn pull(&self, dir: &Path) -> EnvMgrResult<()> {
+        self.in_dir(dir, &["pull", "--quiet", "--ff-only"])?;
+        Ok(())
+    }
+
+    /// Full hash of the checked out commit
+    pub fn head_commit(&self, dir: &Path) -> EnvMgrResult<String> {
+        self.in_dir(dir, &["rev-parse", "HEAD"])
+    }
+
+    pub fn remote_url(&self, dir: &Path) -> EnvMgrResult<String> {
+        self.in_dir(dir, &["remote", "get-url", "origin"])
+    }
+}
@@ -0,0 +1,568 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, info};
+
+use crate::{
+    environment::Environment,
+    error::{EnvMgrError, EnvMgrResult},
+    integrations::{
+        ApplyOutcome, Integration, IntegrationKind, IntegrationOutcome, OnUsePluginResult,
+    },
+    runner::CommandRunner,
+};
+
+pub const PROFILE_VAR: &str = "AWS_PROFILE";
+pub const REGION_VAR: &str = "AWS_REGION";
+pub const CONFIG_FILE_VAR: &str = "AWS_CONFIG_FILE";
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema, Default,
+)]
+pub struct AwsConfig {
+    /// Profile exported as `AWS_PROFILE`, checked against the AWS config on switch
+    pub profile: String,
+    /// Exported as `AWS_REGION`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    /// AWS config file exported as `AWS_CONFIG_FILE`, `~/.aws/config` when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_file: Option<String>,
+    /// Run `aws sso login` on switch when the profile's cached SSO token has expired
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sso_login: bool,
+}
+
+impl AwsConfig {
+    /// The config file with a leading `~/` expanded to `home`, `~/.aws/config` when
+    /// none is configured
+    pub fn config_file_path(&self, home: &Path) -> PathBuf {
+        match &self.config_file {
+            Some(path) => match path.strip_prefix("~/") {
+                Some(rest) => home.join(rest),
+                None => PathBuf::from(path),
+            },
+            None => home.join(".aws").join("config"),
+        }
+    }
+}
+
+impl std::fmt::Display for AwsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "profile {}", self.profile)?;
+        if let Some(region) = &self.region {
+            write!(f, ", region {region}")?;
+        }
+        if let Some(config_file) = &self.config_file {
+            write!(f, ", in {config_file}")?;
+        }
+        if self.sso_login {
+            f.write_str(", sso login")?;
+        }
+        Ok(())
+    }
+}
+
+/// The sections of an AWS config file, e.g. `[profile work]` or `[sso-session corp]`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AwsConfigFile {
+    sections: Vec<(String, BTreeMap<String, String>)>,
+}
+
+impl AwsConfigFile {
+    /// Parse the INI format the AWS CLI reads. Comments start with `#` or `;`, keys
+    /// before the first section and indented sub-settings are ignored.
+    pub fn parse(content: &str) -> Self {
+        let mut sections: Vec<(String, BTreeMap<String, String>)> = vec![];
+        for line in content.lines() {
+            let indented = line.starts_with([' ', '\t']);
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(['#', ';']) {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+                sections.push((name, BTreeMap::new()));
+                continue;
+            }
+            if indented {
+                continue;
+            }
+            if let (Some((_, keys)), Some((key, value))) =
+                (sections.last_mut(), line.split_once('='))
+            {
+                keys.insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+        Self { sections }
+    }
+
+    /// Read and parse the config file at `path`
+    pub fn load(path: &Path) -> EnvMgrResult<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => Ok(Self::parse(&content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(EnvMgrError::Aws(format!(
+                "AWS config {} does not exist",
+                path.display()
+            ))),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn section(&self, name: &str) -> Option<&BTreeMap<String, String>> {
+        self.sections
+            .iter()
+            .find(|(section, _)| section == name)
+            .map(|(_, keys)| keys)
+    }
+
+    /// Settings of the profile `name`; `default` may also be written `[default]`
+    pub fn profile(&self, name: &str) -> Option<&BTreeMap<String, String>> {
+        self.section(&format!("profile {name}")).or_else(|| {
+            (name == "default")
+                .then(|| self.section("default"))
+                .flatten()
+        })
+    }
+
+    /// Names of the profiles, in file order
+    pub fn profile_names(&self) -> Vec<&str> {
+        self.sections
+            .iter()
+            .filter_map(|(section, _)| match section.as_str() {
+                "default" => Some("default"),
+                section => section.strip_prefix("profile "),
+            })
+            .collect()
+    }
+
+    /// The SSO start URL of the profile `name`, from its `sso_session` or the legacy
+    /// `sso_start_url` on the profile itself
+    pub fn sso_start_url(&self, name: &str) -> Option<&str> {
+        let profile = self.profile(name)?;
+        match profile.get("sso_session") {
+            Some(session) => self
+                .section(&format!("sso-session {session}"))?
+                .get("sso_start_url")
+                .map(String::as_str),
+            None => profile.get("sso_start_url").map(String::as_str),
+        }
+    }
+}
+
+/// A token the AWS CLI caches in `~/.aws/sso/cache/`
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CachedToken {
+    start_url: Option<String>,
+    access_token: Option<String>,
+    expires_at: Option<String>,
+}
+
+/// Whether no token for `start_url` in `cache_dir` is valid at `now` (Unix seconds)
+pub fn sso_token_expired(cache_dir: &Path, start_url: &str, now: u64) -> bool {
+    let Ok(entries) = std::fs::read_dir(cache_dir) else {
+        return true;
+    };
+    let valid = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .filter_map(|content| serde_json::from_str::<CachedToken>(&content).ok())
+        .filter(|token| token.start_url.as_deref() == Some(start_url))
+        .filter(|token| token.access_token.is_some())
+        .filter_map(|token| parse_utc_timestamp(token.expires_at.as_deref()?))
+        .any(|expires_at| expires_at > now);
+    !valid
+}
+
+/// Unix seconds of a UTC timestamp like `2025-01-31T18:00:00Z`; the AWS CLI also
+/// writes `UTC` instead of `Z`. Fractions of a second are dropped.
+fn parse_utc_timestamp(timestamp: &str) -> Option<u64> {
+    let (date, time) = timestamp.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    let time = time.get(..8)?;
+    let mut time = time.splitn(3, ':').map(|part| part.parse::<i64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    // Days since the epoch of a proleptic Gregorian date
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    u64::try_from(days * 86_400 + hour * 3_600 + minute * 60 + second).ok()
+}
+
+pub struct Aws;
+
+impl Aws {
+    /// `AWS_PROFILE` and, when configured, `AWS_REGION` and `AWS_CONFIG_FILE`, to be
+    /// overridden by explicit `env_vars`
+    pub fn on_use(config: &AwsConfig, home: Option<&Path>) -> OnUsePluginResult {
+        let mut env_vars = vec![(PROFILE_VAR.to_string(), config.profile.clone())];
+        if let Some(region) = &config.region {
+            env_vars.push((REGION_VAR.to_string(), region.clone()));
+        }
+        if let Some(path) = &config.config_file {
+            let value = match home {
+                Some(home) => config.config_file_path(home).to_string_lossy().into_owned(),
+                None => path.clone(),
+            };
+            env_vars.push((CONFIG_FILE_VAR.to_string(), value));
+        }
+        OnUsePluginResult { env_vars }
+    }
+
+    /// The profile's settings, or an error naming the profiles the config does have
+    pub fn check_profile<'a>(
+        config: &AwsConfig,
+        aws_config: &'a AwsConfigFile,
+        path: &Path,
+    ) -> EnvMgrResult<&'a BTreeMap<String, String>> {
+        aws_config.profile(&config.profile).ok_or_else(|| {
+            EnvMgrError::Aws(format!(
+                "profile '{}' is not in {}, it has: {}",
+                config.profile,
+                path.display(),
+                aws_config.profile_names().join(", ")
+            ))
+        })
+    }
+
+    /// Check the profile exists and, with `sso_login`, log in when its cached SSO token
+    /// has expired
+    pub fn on_switch_to(
+        config: &AwsConfig,
+        home: &Path,
+        now: u64,
+        runner: &dyn CommandRunner,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        let path = config.config_file_path(home);
+        let aws_config = AwsConfigFile::load(&path)?;
+        Self::check_profile(config, &aws_config, &path)?;
+        if !config.sso_login {
+            return Ok(ApplyOutcome::AlreadyInDesiredState);
+        }
+        let start_url = aws_config.sso_start_url(&config.profile).ok_or_else(|| {
+            EnvMgrError::Aws(format!(
+                "sso_login is set, but profile '{}' has no sso_session or sso_start_url",
+                config.profile
+            ))
+        })?;
+        let cache_dir = home.join(".aws").join("sso").join("cache");
+        if !sso_token_expired(&cache_dir, start_url, now) {
+            debug!("SSO token for {start_url} is still valid");
+            return Ok(ApplyOutcome::AlreadyInDesiredState);
+        }
+        info!(
+            "SSO token for profile {} expired, logging in",
+            config.profile
+        );
+        let path_value = path.to_string_lossy();
+        let env: &[(&str, &str)] = match config.config_file {
+            Some(_) => &[(CONFIG_FILE_VAR, &path_value)],
+            None => &[],
+        };
+        match runner.run_interactive("aws", &["sso", "login", "--profile", &config.profile], env) {
+            Ok(true) => Ok(ApplyOutcome::Changed),
+            Ok(false) => Err(EnvMgrError::Aws(format!(
+                "aws sso login --profile {} failed",
+                config.profile
+            ))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(EnvMgrError::Aws(
+                "the AWS CLI is not installed, but sso_login needs it".into(),
+            )),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// [`Aws`] behind [`Integration`]
+pub struct AwsIntegration<'a> {
+    pub home: PathBuf,
+    pub runner: &'a dyn CommandRunner,
+}
+
+impl Integration for AwsIntegration<'_> {
+    type Config = AwsConfig;
+
+    fn name(&self) -> IntegrationKind {
+        IntegrationKind::Aws
+    }
+
+    fn config<'e>(&self, env: &'e Environment) -> Option<&'e Self::Config> {
+        env.aws.as_ref()
+    }
+
+    fn on_switch_to(
+        &self,
+        _env: &Environment,
+        config: &Self::Config,
+    ) -> EnvMgrResult<IntegrationOutcome> {
+        Aws::on_switch_to(config, &self.home, crate::daemon::unix_now(), self.runner)
+            .map(Into::into)
+    }
+
+    fn describe_switch_to(&self, _env: &Environment, config: &Self::Config) -> Vec<String> {
+        let mut actions = vec![format!(
+            "export AWS_PROFILE={} on `envmgr use`",
+            config.profile
+        )];
+        let path = config.config_file_path(&self.home);
+        let aws_config = match AwsConfigFile::load(&path).and_then(|aws_config| {
+            Aws::check_profile(config, &aws_config, &path)?;
+            Ok(aws_config)
+        }) {
+            Ok(aws_config) => aws_config,
+            Err(e) => {
+                actions.push(format!("check profile {} ({e})", config.profile));
+                return actions;
+            }
+        };
+        actions.push(match aws_config.sso_start_url(&config.profile) {
+            Some(start_url)
+                if config.sso_login
+                    && sso_token_expired(
+                        &self.home.join(".aws").join("sso").join("cache"),
+                        start_url,
+                        crate::daemon::unix_now(),
+                    ) =>
+            {
+                format!(
+                    "aws sso login --profile {} (SSO token expired)",
+                    config.profile
+                )
+            }
+            None if config.sso_login => format!(
+                "log in to profile {} (it has no SSO session)",
+                config.profile
+            ),
+            _ => format!(
+                "{} (profile {} exists)",
+                ApplyOutcome::AlreadyInDesiredState,
+                config.profile
+            ),
+        });
+        actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io};
+
+    use super::*;
+    use crate::runner::CommandOutput;
+
+    const AWS_CONFIG: &str = indoc::indoc! {"
+        [default]
+        region = eu-west-1
+
+        # Client work
+        [profile client-admin]
+        sso_session = client
+        sso_account_id = 123456789012
+        sso_role_name = Admin
+        s3 =
+          max_concurrent_requests = 20
+
+        [profile legacy]
+        sso_start_url = https://legacy.awsapps.com/start
+
+        [sso-session client]
+        sso_start_url = https://client.awsapps.com/start
+        sso_region = eu-west-1
+    "};
+
+    /// 2025-01-01T00:00:00Z
+    const NOW: u64 = 1_735_689_600;
+
+    #[derive(Default)]
+    struct FakeAws {
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl CommandRunner for FakeAws {
+        fn run(&self, _program: &str, _args: &[&str]) -> io::Result<CommandOutput> {
+            unreachable!("the login is interactive")
+        }
+
+        fn run_interactive(
+            &self,
+            program: &str,
+            args: &[&str],
+            env: &[(&str, &str)],
+        ) -> io::Result<bool> {
+            assert_eq!(program, "aws");
+            assert!(env.is_empty());
+            self.calls.borrow_mut().push(args.join(" "));
+            Ok(true)
+        }
+    }
+
+    fn config(profile: &str, sso_login: bool) -> AwsConfig {
+        AwsConfig {
+            profile: profile.to_string(),
+            sso_login,
+            ..Default::default()
+        }
+    }
+
+    fn temp_home(name: &str) -> PathBuf {
+        let home = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&home);
+        std::fs::create_dir_all(home.join(".aws/sso/cache")).unwrap();
+        std::fs::write(home.join(".aws/config"), AWS_CONFIG).unwrap();
+        home
+    }
+
+    fn write_token(home: &Path, file: &str, start_url: &str, expires_at: &str) {
+        std::fs::write(
+            home.join(".aws/sso/cache").join(file),
+            format!(
+                r#"{{"startUrl": "{start_url}", "region": "eu-west-1", "accessToken": "secret", "expiresAt": "{expires_at}"}}"#
+            ),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_parse_config_file() {
+        let aws_config = AwsConfigFile::parse(AWS_CONFIG);
+        assert_eq!(
+            aws_config.profile_names(),
+            ["default", "client-admin", "legacy"]
+        );
+        assert_eq!(
+            aws_config.profile("default").unwrap()["region"],
+            "eu-west-1"
+        );
+        assert!(aws_config.profile("client").is_none());
+        // Sub-settings don't leak into the profile
+        assert_eq!(aws_config.profile("client-admin").unwrap()["s3"], "");
+        assert_eq!(
+            aws_config.sso_start_url("client-admin"),
+            Some("https://client.awsapps.com/start")
+        );
+        assert_eq!(
+            aws_config.sso_start_url("legacy"),
+            Some("https://legacy.awsapps.com/start")
+        );
+        assert_eq!(aws_config.sso_start_url("default"), None);
+    }
+
+    #[test]
+    fn test_parse_utc_timestamp() {
+        assert_eq!(parse_utc_timestamp("2025-01-01T00:00:00Z"), Some(NOW));
+        assert_eq!(parse_utc_timestamp("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(
+            parse_utc_timestamp("2024-02-29T12:30:15UTC"),
+            Some(1_709_209_815)
+        );
+        assert_eq!(
+            parse_utc_timestamp("2025-01-01T00:00:01.500Z"),
+            Some(NOW + 1)
+        );
+        assert_eq!(parse_utc_timestamp("tomorrow"), None);
+        assert_eq!(parse_utc_timestamp("2025-13-01T00:00:00Z"), None);
+    }
+
+    #[test]
+    fn test_on_use_exports_profile() {
+        let aws = AwsConfig {
+            region: Some("eu-central-1".to_string()),
+            config_file: Some("~/.aws/config-client".to_string()),
+            ..config("client-admin", false)
+        };
+        assert_eq!(
+            Aws::on_use(&aws, Some(Path::new("/home/me"))).env_vars,
+            [
+                ("AWS_PROFILE".to_string(), "client-admin".to_string()),
+                ("AWS_REGION".to_string(), "eu-central-1".to_string()),
+                (
+                    "AWS_CONFIG_FILE".to_string(),
+                    "/home/me/.aws/config-client".to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            Aws::on_use(&config("legacy", false), None).env_vars,
+            [("AWS_PROFILE".to_string(), "legacy".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_switch_checks_the_profile_exists() {
+        let home = temp_home("envmgr_test_aws_profile");
+        let aws = FakeAws::default();
+
+        assert_eq!(
+            Aws::on_switch_to(&config("client-admin", false), &home, NOW, &aws).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        let Err(EnvMgrError::Aws(message)) =
+            Aws::on_switch_to(&config("client", false), &home, NOW, &aws)
+        else {
+            panic!("expected an error for an unknown profile");
+        };
+        assert!(
+            message.contains("it has: default, client-admin, legacy"),
+            "{message}"
+        );
+        let missing = AwsConfig {
+            config_file: Some("~/.aws/missing".to_string()),
+            ..config("client-admin", false)
+        };
+        assert!(matches!(
+            Aws::on_switch_to(&missing, &home, NOW, &aws),
+            Err(EnvMgrError::Aws(_))
+        ));
+        assert!(aws.calls.borrow().is_empty());
+        std::fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn test_sso_login_only_runs_for_an_expired_token() {
+        let home = temp_home("envmgr_test_aws_sso");
+        let aws = FakeAws::default();
+        write_token(
+            &home,
+            "valid.json",
+            "https://client.awsapps.com/start",
+            "2025-01-01T08:00:00Z",
+        );
+        write_token(
+            &home,
+            "expired.json",
+            "https://legacy.awsapps.com/start",
+            "2024-12-31T23:00:00Z",
+        );
+
+        assert_eq!(
+            Aws::on_switch_to(&config("client-admin", true), &home, NOW, &aws).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        assert_eq!(
+            Aws::on_switch_to(&config("legacy", true), &home, NOW, &aws).unwrap(),
+            ApplyOutcome::Changed
+        );
+        assert_eq!(*aws.calls.borrow(), ["sso login --profile legacy"]);
+        assert!(matches!(
+            Aws::on_switch_to(&config("default", true), &home, NOW, &aws),
+            Err(EnvMgrError::Aws(_))
+        ));
+        std::fs::remove_dir_all(&home).unwrap();
+    }
+}
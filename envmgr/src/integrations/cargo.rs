@@ -0,0 +1,645 @@
+//! Private Cargo registries per environment.
+//!
+//! Each registry gets a `[registries.<name>]` table in `$CARGO_HOME/config.toml` for its
+//! index and one in `credentials.toml` for its token. The tables envmgr writes carry a
+//! marker comment, so switching away removes only those and leaves the rest of both
+//! files, comments included, as it was.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+use toml_edit::{DocumentMut, Item, Table, Value, value};
+
+use crate::{
+    environment::{Environment, OpCli, SECRET_REFERENCE_PREFIX},
+    error::{EnvMgrError, EnvMgrResult},
+    fs::Fs,
+    integrations::{ApplyOutcome, Integration, IntegrationKind, IntegrationOutcome},
+    runner::CommandRunner,
+};
+
+/// Comment on every table and value envmgr writes
+const MARKER: &str = "# managed by envmgr, changes are overwritten";
+/// credentials.toml holds the tokens, so it is private
+const CREDENTIALS_MODE: u32 = 0o600;
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema, Default,
+)]
+pub struct CargoRegistry {
+    /// Index URL, e.g. `sparse+https://cargo.corp.example/index/`
+    pub index: String,
+    /// Token for `cargo publish` and private downloads; an `op://` reference is read
+    /// with the 1Password CLI on switch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_ref: Option<String>,
+}
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema, Default,
+)]
+pub struct CargoConfig {
+    /// Registries by the name `cargo --registry` and `Cargo.toml` use
+    pub registries: BTreeMap<String, CargoRegistry>,
+    /// Registry `cargo publish` uses without `--registry`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_registry: Option<String>,
+}
+
+impl std::fmt::Display for CargoConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<&str> = self.registries.keys().map(String::as_str).collect();
+        write!(f, "registries {}", names.join(", "))?;
+        if let Some(default) = &self.default_registry {
+            write!(f, ", default {default}")?;
+        }
+        Ok(())
+    }
+}
+
+fn is_managed_table(item: &Item) -> bool {
+    item.as_table().is_some_and(|table| {
+        table
+            .decor()
+            .prefix()
+            .and_then(|prefix| prefix.as_str())
+            .is_some_and(|prefix| prefix.contains(MARKER))
+    })
+}
+
+fn is_managed_value(item: &Item) -> bool {
+    item.as_value().is_some_and(|value| {
+        value
+            .decor()
+            .suffix()
+            .and_then(|suffix| suffix.as_str())
+            .is_some_and(|suffix| suffix.contains(MARKER))
+    })
+}
+
+/// The table `key` of `document`, added as an implicit one, i.e. without a header of its
+/// own, when it is missing
+fn table_mut<'d>(
+    document: &'d mut DocumentMut,
+    key: &str,
+    file_name: &str,
+) -> EnvMgrResult<&'d mut Table> {
+    document
+        .entry(key)
+        .or_insert_with(|| {
+            let mut table = Table::new();
+            table.set_implicit(true);
+            Item::Table(table)
+        })
+        .as_table_mut()
+        .ok_or_else(|| EnvMgrError::Cargo(format!("`{key}` in {file_name} is not a table")))
+}
+
+/// Drop the implicit table `key` again when nothing is left in it
+fn remove_if_empty(document: &mut DocumentMut, key: &str) {
+    if document
+        .get(key)
+        .and_then(Item::as_table)
+        .is_some_and(|table| table.is_empty() && table.is_implicit())
+    {
+        document.remove(key);
+    }
+}
+
+/// Set `field` of envmgr's `[registries.<name>]` tables in `document` to `values`, by
+/// registry name, removing envmgr's tables of other registries
+fn set_registries(
+    document: &mut DocumentMut,
+    file_name: &str,
+    field: &str,
+    values: &BTreeMap<String, String>,
+) -> EnvMgrResult<()> {
+    let at_top = document.as_table().is_empty();
+    let registries = table_mut(document, "registries", file_name)?;
+    let stale: Vec<String> = registries
+        .iter()
+        .filter(|(name, item)| is_managed_table(item) && !values.contains_key(*name))
+        .map(|(name, _)| name.to_string())
+        .collect();
+    for name in stale {
+        registries.remove(&name);
+    }
+    for (i, (name, wanted)) in values.iter().enumerate() {
+        match registries.get_mut(name) {
+            Some(item) if is_managed_table(item) => {
+                if item.get(field).and_then(Item::as_str) != Some(wanted) {
+                    item[field] = value(wanted);
+                }
+            }
+            Some(_) => {
+                return Err(EnvMgrError::Cargo(format!(
+                    "registries.{name} in {file_name} isn't envmgr's, remove it to manage {name} \
+                     in an environment"
+                )));
+            }
+            None => {
+                let mut table = Table::new();
+                // A blank line before the marker, except at the very top of the file
+                let separator = if at_top && i == 0 { "" } else { "\n" };
+                table
+                    .decor_mut()
+                    .set_prefix(format!("{separator}{MARKER}\n"));
+                table[field] = value(wanted);
+                registries.insert(name, Item::Table(table));
+            }
+        }
+    }
+    remove_if_empty(document, "registries");
+    Ok(())
+}
+
+/// Set `registry.default` in `document` to `default`, or remove it when envmgr set it
+fn set_default_registry(document: &mut DocumentMut, default: Option<&str>) -> EnvMgrResult<()> {
+    let registry = table_mut(document, "registry", "config.toml")?;
+    match (registry.get("default"), default) {
+        (Some(item), _) if !is_managed_value(item) => {
+            if default.is_some() {
+                return Err(EnvMgrError::Cargo(
+                    "registry.default in config.toml isn't envmgr's, remove it to set \
+                     default_registry in an environment"
+                        .into(),
+                ));
+            }
+        }
+        (current, Some(default)) => {
+            if current.and_then(Item::as_str) != Some(default) {
+                let mut default = Value::from(default);
+                default.decor_mut().set_suffix(format!(" {MARKER}"));
+                registry.insert("default", Item::Value(default));
+            }
+        }
+        (Some(_), None) => {
+            registry.remove("default");
+            // The `[registry]` header envmgr wrote for it reads back as the user's table
+            registry.set_implicit(true);
+        }
+        (None, None) => {}
+    }
+    remove_if_empty(document, "registry");
+    Ok(())
+}
+
+fn parse(content: &str, file_name: &str) -> EnvMgrResult<DocumentMut> {
+    content
+        .parse()
+        .map_err(|e| EnvMgrError::Cargo(format!("{file_name} is not valid TOML: {e}")))
+}
+
+/// config.toml `content` with envmgr's registries and default registry set to `config`'s
+pub fn with_config(content: &str, config: &CargoConfig) -> EnvMgrResult<String> {
+    let mut document = parse(content, "config.toml")?;
+    let indexes = config
+        .registries
+        .iter()
+        .map(|(name, registry)| (name.clone(), registry.index.clone()))
+        .collect();
+    set_registries(&mut document, "config.toml", "index", &indexes)?;
+    set_default_registry(&mut document, config.default_registry.as_deref())?;
+    Ok(document.to_string())
+}
+
+/// credentials.toml `content` with envmgr's tokens set to `tokens`, by registry name
+pub fn with_credentials(content: &str, tokens: &BTreeMap<String, String>) -> EnvMgrResult<String> {
+    let mut document = parse(content, "credentials.toml")?;
+    set_registries(&mut document, "credentials.toml", "token", tokens)?;
+    Ok(document.to_string())
+}
+
+pub struct Cargo;
+
+impl Cargo {
+    pub fn cargo_home(home: &Path) -> PathBuf {
+        std::env::var_os("CARGO_HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| home.join(".cargo"))
+    }
+
+    pub fn config_path(cargo_home: &Path) -> PathBuf {
+        cargo_home.join("config.toml")
+    }
+
+    pub fn credentials_path(cargo_home: &Path) -> PathBuf {
+        cargo_home.join("credentials.toml")
+    }
+
+    /// The tokens by registry name, with `op://` references read through `op`
+    pub fn resolve_tokens(
+        config: &CargoConfig,
+        runner: &dyn CommandRunner,
+    ) -> EnvMgrResult<BTreeMap<String, String>> {
+        let references: Vec<&str> = config
+            .registries
+            .values()
+            .filter_map(|registry| registry.token_ref.as_deref())
+            .filter(|token| token.starts_with(SECRET_REFERENCE_PREFIX))
+            .collect();
+        let mut resolved = match references.is_empty() {
+            true => Default::default(),
+            false => OpCli::new(runner).read_all(&references),
+        };
+        let mut tokens = BTreeMap::new();
+        for (name, registry) in &config.registries {
+            let Some(token) = &registry.token_ref else {
+                continue;
+            };
+            let value = match resolved.remove(token) {
+                Some(Ok(value)) => value,
+                Some(Err(e)) => {
+                    return Err(EnvMgrError::Cargo(format!(
+                        "the token of registry {name} could not be read: {e}"
+                    )));
+                }
+                None => token.clone(),
+            };
+            tokens.insert(name.clone(), value);
+        }
+        Ok(tokens)
+    }
+
+    /// Write the registries of `config` to config.toml and their tokens to credentials.toml
+    pub fn on_switch_to(
+        config: &CargoConfig,
+        cargo_home: &Path,
+        fs: &dyn Fs,
+        runner: &dyn CommandRunner,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        let tokens = Self::resolve_tokens(config, runner)?;
+        let config_toml = Self::update(&Self::config_path(cargo_home), None, fs, |content| {
+            with_config(content, config)
+        })?;
+        let credentials = Self::update(
+            &Self::credentials_path(cargo_home),
+            Some(CREDENTIALS_MODE),
+            fs,
+            |content| with_credentials(content, &tokens),
+        )?;
+        Ok(combine(config_toml, credentials))
+    }
+
+    /// Remove envmgr's registries and tokens, leaving the user's own
+    pub fn on_switch_away(cargo_home: &Path, fs: &dyn Fs) -> EnvMgrResult<ApplyOutcome> {
+        let config_toml = Self::update(&Self::config_path(cargo_home), None, fs, |content| {
+            with_config(content, &CargoConfig::default())
+        })?;
+        let credentials = Self::update(
+            &Self::credentials_path(cargo_home),
+            Some(CREDENTIALS_MODE),
+            fs,
+            |content| with_credentials(content, &BTreeMap::new()),
+        )?;
+        Ok(combine(config_toml, credentials))
+    }
+
+    /// Whether config.toml holds the registries of `config` and credentials.toml a token
+    /// of envmgr's for each one with a `token_ref`. Tokens are only read from 1Password
+    /// on switch, so their values aren't compared.
+    pub fn is_converged(config: &CargoConfig, cargo_home: &Path) -> bool {
+        let read = |path: PathBuf| std::fs::read_to_string(path).unwrap_or_default();
+        let config_toml = read(Self::config_path(cargo_home));
+        if with_config(&config_toml, config).ok().as_ref() != Some(&config_toml) {
+            return false;
+        }
+        let Ok(credentials) = parse(&read(Self::credentials_path(cargo_home)), "") else {
+            return false;
+        };
+        let managed: Vec<&str> = credentials
+            .get("registries")
+            .and_then(Item::as_table)
+            .map(|registries| {
+                registries
+                    .iter()
+                    .filter(|(_, item)| is_managed_table(item))
+                    .map(|(name, _)| name)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let wanted: Vec<&str> = config
+            .registries
+            .iter()
+            .filter(|(_, registry)| registry.token_ref.is_some())
+            .map(|(name, _)| name.as_str())
+            .collect();
+        managed == wanted
+    }
+
+    /// Describe what [`Cargo::on_switch_away`] would change
+    pub fn describe_switch_away(cargo_home: &Path) -> Vec<String> {
+        let mut actions = vec![];
+        let config_toml = Self::config_path(cargo_home);
+        if std::fs::read_to_string(&config_toml).is_ok_and(|content| {
+            with_config(&content, &CargoConfig::default()).is_ok_and(|rest| rest != content)
+        }) {
+            actions.push(format!(
+                "remove the envmgr registries from {}",
+                config_toml.display()
+            ));
+        }
+        let credentials = Self::credentials_path(cargo_home);
+        if std::fs::read_to_string(&credentials).is_ok_and(|content| {
+            with_credentials(&content, &BTreeMap::new()).is_ok_and(|rest| rest != content)
+        }) {
+            actions.push(format!(
+                "remove the envmgr tokens from {}",
+                credentials.display()
+            ));
+        }
+        actions
+    }
+
+    /// Rewrite the file at `path` with `edit`, with the permission bits `mode` if given,
+    /// removing it when nothing but whitespace is left
+    fn update(
+        path: &Path,
+        mode: Option<u32>,
+        fs: &dyn Fs,
+        edit: impl FnOnce(&str) -> EnvMgrResult<String>,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        let current = match fs.read(path)? {
+            Some(bytes) => Some(String::from_utf8(bytes).map_err(|_| {
+                EnvMgrError::Cargo(format!("{} is not valid UTF-8", path.display()))
+            })?),
+            None => None,
+        };
+        let updated = edit(current.as_deref().unwrap_or_default())?;
+        if updated.trim().is_empty() {
+            if current.is_none() {
+                return Ok(ApplyOutcome::AlreadyInDesiredState);
+            }
+            debug!(
+                "Removing {}, only envmgr's registries were in it",
+                path.display()
+            );
+            fs.remove_file(path)?;
+            return Ok(ApplyOutcome::Changed);
+        }
+        if current.as_deref() == Some(updated.as_str()) {
+            return Ok(ApplyOutcome::AlreadyInDesiredState);
+        }
+        debug!("Writing the cargo registries to {}", path.display());
+        match mode {
+            Some(mode) => fs.write_atomic_with_mode(path, updated.as_bytes(), mode)?,
+            None => fs.write_atomic(path, updated.as_bytes())?,
+        }
+        Ok(ApplyOutcome::Changed)
+    }
+}
+
+/// Changed when either file changed
+fn combine(config_toml: ApplyOutcome, credentials: ApplyOutcome) -> ApplyOutcome {
+    match (config_toml, credentials) {
+        (ApplyOutcome::AlreadyInDesiredState, ApplyOutcome::AlreadyInDesiredState) => {
+            ApplyOutcome::AlreadyInDesiredState
+        }
+        _ => ApplyOutcome::Changed,
+    }
+}
+
+/// [`Cargo`] behind [`Integration`]
+pub struct CargoIntegration<'a> {
+    pub home: PathBuf,
+    pub fs: &'a dyn Fs,
+    pub runner: &'a dyn CommandRunner,
+}
+
+impl Integration for CargoIntegration<'_> {
+    type Config = CargoConfig;
+
+    fn name(&self) -> IntegrationKind {
+        IntegrationKind::Cargo
+    }
+
+    fn config<'e>(&self, env: &'e Environment) -> Option<&'e Self::Config> {
+        env.cargo.as_ref()
+    }
+
+    fn on_switch_to(
+        &self,
+        _env: &Environment,
+        config: &Self::Config,
+    ) -> EnvMgrResult<IntegrationOutcome> {
+        Cargo::on_switch_to(config, &Cargo::cargo_home(&self.home), self.fs, self.runner)
+            .map(Into::into)
+    }
+
+    fn describe_switch_to(&self, _env: &Environment, config: &Self::Config) -> Vec<String> {
+        let cargo_home = Cargo::cargo_home(&self.home);
+        if Cargo::is_converged(config, &cargo_home) {
+            return vec![format!(
+                "{} ({config})",
+                ApplyOutcome::AlreadyInDesiredState
+            )];
+        }
+        let mut actions = vec![format!(
+            "write the envmgr registries of {} ({config})",
+            Cargo::config_path(&cargo_home).display()
+        )];
+        // Tokens are only read from 1Password on switch
+        if config
+            .registries
+            .values()
+            .any(|registry| registry.token_ref.is_some())
+        {
+            actions.push(format!(
+                "write the envmgr tokens of {}",
+                Cargo::credentials_path(&cargo_home).display()
+            ));
+        }
+        actions
+    }
+
+    /// The registries must not leak into the next environment
+    fn clear(&self) -> EnvMgrResult<ApplyOutcome> {
+        Cargo::on_switch_away(&Cargo::cargo_home(&self.home), self.fs)
+    }
+
+    fn describe_clear(&self) -> Vec<String> {
+        Cargo::describe_switch_away(&Cargo::cargo_home(&self.home))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, fs, io};
+
+    use super::*;
+    use crate::{fs::RealFs, runner::CommandOutput};
+
+    /// Reads `op://Work/cargo/token` as `s3cret`
+    #[derive(Default)]
+    struct FakeOp {
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl CommandRunner for FakeOp {
+        fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+            assert_eq!(program, "op");
+            self.calls.borrow_mut().push(args.join(" "));
+            Ok(match args.last() {
+                Some(&"op://Work/cargo/token") => CommandOutput {
+                    success: true,
+                    stdout: "s3cret".to_string(),
+                    ..Default::default()
+                },
+                _ => CommandOutput {
+                    stderr: "no such item".to_string(),
+                    ..Default::default()
+                },
+            })
+        }
+    }
+
+    fn work() -> CargoConfig {
+        CargoConfig {
+            registries: BTreeMap::from([(
+                "corp".to_string(),
+                CargoRegistry {
+                    index: "sparse+https://cargo.corp.example/index/".to_string(),
+                    token_ref: Some("op://Work/cargo/token".to_string()),
+                },
+            )]),
+            default_registry: Some("corp".to_string()),
+        }
+    }
+
+    const USER_CONFIG: &str = indoc::indoc! {r#"
+        # my cargo settings
+        [build]
+        jobs = 4 # the laptop overheats otherwise
+
+        [registries.mine]
+        # my own registry
+        index = "sparse+https://mine.example/index/"
+
+        [net]
+        git-fetch-with-cli = true
+    "#};
+
+    #[test]
+    fn test_config_keeps_user_content_and_comments() {
+        let written = with_config(USER_CONFIG, &work()).unwrap();
+
+        assert_eq!(
+            written,
+            format!(
+                "# my cargo settings\n[build]\njobs = 4 # the laptop overheats otherwise\n\n\
+                 [registries.mine]\n# my own registry\nindex = \"sparse+https://mine.example/index/\"\n\n\
+                 {MARKER}\n[registries.corp]\nindex = \"sparse+https://cargo.corp.example/index/\"\n\n\
+                 [net]\ngit-fetch-with-cli = true\n\n\
+                 [registry]\ndefault = \"corp\" {MARKER}\n"
+            )
+        );
+        assert_eq!(with_config(&written, &work()).unwrap(), written);
+        assert_eq!(
+            with_config(&written, &CargoConfig::default()).unwrap(),
+            USER_CONFIG
+        );
+    }
+
+    #[test]
+    fn test_user_registries_are_never_replaced() {
+        let mut config = work();
+        config.registries.insert(
+            "mine".to_string(),
+            CargoRegistry {
+                index: "sparse+https://other.example/".to_string(),
+                token_ref: None,
+            },
+        );
+        let Err(EnvMgrError::Cargo(message)) = with_config(USER_CONFIG, &config) else {
+            panic!("expected an error for the user's registry");
+        };
+        assert!(message.contains("registries.mine"), "{message}");
+
+        let user_default = "[registry]\ndefault = \"mine\"\n";
+        assert!(with_config(user_default, &work()).is_err());
+        // Without a default_registry of its own, the user's default stays
+        let config = CargoConfig {
+            default_registry: None,
+            ..work()
+        };
+        assert!(
+            with_config(user_default, &config)
+                .unwrap()
+                .starts_with(user_default)
+        );
+    }
+
+    #[test]
+    fn test_tokens_are_read_from_1password() {
+        let op = FakeOp::default();
+        let mut config = work();
+        config.registries.insert(
+            "plain".to_string(),
+            CargoRegistry {
+                index: "sparse+https://plain.example/".to_string(),
+                token_ref: Some("abc".to_string()),
+            },
+        );
+
+        let tokens = Cargo::resolve_tokens(&config, &op).unwrap();
+
+        assert_eq!(tokens["corp"], "s3cret");
+        assert_eq!(tokens["plain"], "abc");
+        assert_eq!(op.calls.borrow().len(), 1);
+
+        config.registries.get_mut("plain").unwrap().token_ref =
+            Some("op://Work/missing/token".to_string());
+        let Err(EnvMgrError::Cargo(message)) = Cargo::resolve_tokens(&config, &op) else {
+            panic!("expected an error for an unreadable token");
+        };
+        assert!(message.contains("registry plain"), "{message}");
+    }
+
+    #[test]
+    fn test_switch_writes_and_removes_registries() {
+        let cargo_home = std::env::temp_dir().join("envmgr_test_cargo_switch");
+        let _ = fs::remove_dir_all(&cargo_home);
+        fs::create_dir_all(&cargo_home).unwrap();
+        let config_toml = Cargo::config_path(&cargo_home);
+        fs::write(&config_toml, USER_CONFIG).unwrap();
+        let credentials = Cargo::credentials_path(&cargo_home);
+        let op = FakeOp::default();
+
+        assert_eq!(
+            Cargo::on_switch_to(&work(), &cargo_home, &RealFs, &op).unwrap(),
+            ApplyOutcome::Changed
+        );
+        assert_eq!(
+            fs::read_to_string(&credentials).unwrap(),
+            format!("{MARKER}\n[registries.corp]\ntoken = \"s3cret\"\n")
+        );
+        assert!(Cargo::is_converged(&work(), &cargo_home));
+        assert_eq!(
+            Cargo::on_switch_to(&work(), &cargo_home, &RealFs, &op).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&credentials).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, CREDENTIALS_MODE);
+        }
+        assert_eq!(Cargo::describe_switch_away(&cargo_home).len(), 2);
+
+        assert_eq!(
+            Cargo::on_switch_away(&cargo_home, &RealFs).unwrap(),
+            ApplyOutcome::Changed
+        );
+        assert_eq!(fs::read_to_string(&config_toml).unwrap(), USER_CONFIG);
+        // Only envmgr's token was in it
+        assert!(!credentials.exists());
+        assert_eq!(
+            Cargo::on_switch_away(&cargo_home, &RealFs).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        fs::remove_dir_all(&cargo_home).unwrap();
+    }
+}
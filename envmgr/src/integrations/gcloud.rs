@@ -0,0 +1,347 @@
+use log::debug;
+
+use crate::{
+    environment::Environment,
+    error::{EnvMgrError, EnvMgrResult},
+    integrations::{ApplyOutcome, Integration, IntegrationKind, IntegrationOutcome},
+    runner::CommandRunner,
+};
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema, Default,
+)]
+pub struct GcloudConfig {
+    /// Named gcloud configuration activated on switch, e.g. `client-abc`
+    pub configuration: String,
+    /// `core/project` set in the configuration after activating it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
+    /// `core/account` set in the configuration after activating it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+}
+
+impl std::fmt::Display for GcloudConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "configuration {}", self.configuration)?;
+        if let Some(project) = &self.project {
+            write!(f, ", project {project}")?;
+        }
+        if let Some(account) = &self.account {
+            write!(f, ", account {account}")?;
+        }
+        Ok(())
+    }
+}
+
+/// An entry of `gcloud config configurations list --format json`
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+pub struct GcloudConfiguration {
+    pub name: String,
+    #[serde(default)]
+    pub is_active: bool,
+    #[serde(default)]
+    properties: Properties,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+struct Properties {
+    #[serde(default)]
+    core: CoreProperties,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+struct CoreProperties {
+    project: Option<String>,
+    account: Option<String>,
+}
+
+impl GcloudConfiguration {
+    pub fn project(&self) -> Option<&str> {
+        self.properties.core.project.as_deref()
+    }
+
+    pub fn account(&self) -> Option<&str> {
+        self.properties.core.account.as_deref()
+    }
+}
+
+pub struct Gcloud;
+
+impl Gcloud {
+    /// The configurations gcloud knows about
+    pub fn configurations(runner: &dyn CommandRunner) -> EnvMgrResult<Vec<GcloudConfiguration>> {
+        let output = Self::run(
+            runner,
+            &["config", "configurations", "list", "--format", "json"],
+        )?;
+        serde_json::from_str(&output).map_err(|e| {
+            EnvMgrError::Gcloud(format!(
+                "could not parse `gcloud config configurations list`: {e}"
+            ))
+        })
+    }
+
+    /// The active configuration with its project and account, `None` when none is
+    pub fn active_configuration(runner: &dyn CommandRunner) -> EnvMgrResult<Option<GcloudConfig>> {
+        Ok(Self::configurations(runner)?
+            .into_iter()
+            .find(|configuration| configuration.is_active)
+            .map(|configuration| GcloudConfig {
+                project: configuration.project().map(str::to_string),
+                account: configuration.account().map(str::to_string),
+                configuration: configuration.name,
+            }))
+    }
+
+    /// The `gcloud config` arguments that activate the configured configuration and
+    /// set its project and account, empty when all of them already are
+    pub fn plan_commands(
+        config: &GcloudConfig,
+        configurations: &[GcloudConfiguration],
+    ) -> EnvMgrResult<Vec<Vec<String>>> {
+        let Some(target) = configurations
+            .iter()
+            .find(|configuration| configuration.name == config.configuration)
+        else {
+            let names: Vec<&str> = configurations.iter().map(|c| c.name.as_str()).collect();
+            return Err(EnvMgrError::Gcloud(format!(
+                "configuration '{}' does not exist, gcloud has: {}",
+                config.configuration,
+                names.join(", ")
+            )));
+        };
+        let mut commands = vec![];
+        if !target.is_active {
+            commands.push(vec![
+                "configurations".to_string(),
+                "activate".to_string(),
+                config.configuration.clone(),
+            ]);
+        }
+        for (property, wanted, current) in [
+            ("project", &config.project, target.project()),
+            ("account", &config.account, target.account()),
+        ] {
+            if let Some(wanted) = wanted
+                && current != Some(wanted)
+            {
+                commands.push(vec![
+                    "set".to_string(),
+                    property.to_string(),
+                    wanted.clone(),
+                ]);
+            }
+        }
+        Ok(commands)
+    }
+
+    /// Activate the configured configuration, then set its project and account
+    pub fn on_switch_to(
+        config: &GcloudConfig,
+        runner: &dyn CommandRunner,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        let configurations = Self::configurations(runner)?;
+        let commands = Self::plan_commands(config, &configurations)?;
+        if commands.is_empty() {
+            return Ok(ApplyOutcome::AlreadyInDesiredState);
+        }
+        for command in commands {
+            let mut args = vec!["config"];
+            args.extend(command.iter().map(String::as_str));
+            // `set` would otherwise ask before setting a project it can't see
+            args.push("--quiet");
+            Self::run(runner, &args)?;
+        }
+        Ok(ApplyOutcome::Changed)
+    }
+
+    fn run(runner: &dyn CommandRunner, args: &[&str]) -> EnvMgrResult<String> {
+        debug!("Running gcloud {}", args.join(" "));
+        match runner.run("gcloud", args) {
+            Ok(output) if output.success => Ok(output.stdout),
+            Ok(output) => Err(EnvMgrError::Gcloud(format!(
+                "gcloud {} failed: {}",
+                args.join(" "),
+                output.stderr.trim()
+            ))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(EnvMgrError::Gcloud(
+                "gcloud is not installed, but the gcloud integration needs it".into(),
+            )),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// [`Gcloud`] behind [`Integration`]
+pub struct GcloudIntegration<'a> {
+    pub runner: &'a dyn CommandRunner,
+}
+
+impl Integration for GcloudIntegration<'_> {
+    type Config = GcloudConfig;
+
+    fn name(&self) -> IntegrationKind {
+        IntegrationKind::Gcloud
+    }
+
+    fn config<'e>(&self, env: &'e Environment) -> Option<&'e Self::Config> {
+        env.gcloud.as_ref()
+    }
+
+    fn on_switch_to(
+        &self,
+        _env: &Environment,
+        config: &Self::Config,
+    ) -> EnvMgrResult<IntegrationOutcome> {
+        Gcloud::on_switch_to(config, self.runner).map(Into::into)
+    }
+
+    fn describe_switch_to(&self, _env: &Environment, config: &Self::Config) -> Vec<String> {
+        match Gcloud::configurations(self.runner)
+            .and_then(|configurations| Gcloud::plan_commands(config, &configurations))
+        {
+            Ok(commands) if commands.is_empty() => vec![format!(
+                "{} ({config})",
+                ApplyOutcome::AlreadyInDesiredState
+            )],
+            Ok(commands) => commands
+                .iter()
+                .map(|command| format!("gcloud config {}", command.join(" ")))
+                .collect(),
+            Err(e) => vec![format!("switch to {config} ({e})")],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io};
+
+    use super::*;
+    use crate::runner::CommandOutput;
+
+    const CONFIGURATIONS: &str = r#"[
+      {
+        "is_active": true,
+        "name": "default",
+        "properties": {"core": {"account": "me@gmail.com", "project": "hobby"}}
+      },
+      {
+        "is_active": false,
+        "name": "client-abc",
+        "properties": {"core": {"account": "me@client.example", "project": "abc-prod"}}
+      },
+      {"is_active": false, "name": "empty", "properties": {}}
+    ]"#;
+
+    #[derive(Default)]
+    struct FakeGcloud {
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl CommandRunner for FakeGcloud {
+        fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+            assert_eq!(program, "gcloud");
+            let args = args.join(" ");
+            let stdout = match args.as_str() {
+                "config configurations list --format json" => CONFIGURATIONS.to_string(),
+                _ => {
+                    self.calls.borrow_mut().push(args);
+                    String::new()
+                }
+            };
+            Ok(CommandOutput {
+                success: true,
+                stdout,
+                ..Default::default()
+            })
+        }
+    }
+
+    fn config(configuration: &str, project: Option<&str>, account: Option<&str>) -> GcloudConfig {
+        GcloudConfig {
+            configuration: configuration.to_string(),
+            project: project.map(str::to_string),
+            account: account.map(str::to_string),
+        }
+    }
+
+    fn plan(config: &GcloudConfig) -> EnvMgrResult<Vec<String>> {
+        let configurations: Vec<GcloudConfiguration> =
+            serde_json::from_str(CONFIGURATIONS).unwrap();
+        Ok(Gcloud::plan_commands(config, &configurations)?
+            .into_iter()
+            .map(|command| command.join(" "))
+            .collect())
+    }
+
+    #[test]
+    fn test_plan_commands() {
+        assert!(plan(&config("default", None, None)).unwrap().is_empty());
+        assert!(
+            plan(&config("default", Some("hobby"), Some("me@gmail.com")))
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            plan(&config("client-abc", None, None)).unwrap(),
+            ["configurations activate client-abc"]
+        );
+        // The project is already set in client-abc
+        assert_eq!(
+            plan(&config(
+                "client-abc",
+                Some("abc-prod"),
+                Some("ops@client.example")
+            ))
+            .unwrap(),
+            [
+                "configurations activate client-abc",
+                "set account ops@client.example"
+            ]
+        );
+        assert_eq!(
+            plan(&config("empty", Some("p"), None)).unwrap(),
+            ["configurations activate empty", "set project p"]
+        );
+
+        let Err(EnvMgrError::Gcloud(message)) = plan(&config("client-abd", None, None)) else {
+            panic!("expected an error for an unknown configuration");
+        };
+        assert!(
+            message.contains("gcloud has: default, client-abc, empty"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn test_switch_activates_and_sets() {
+        let gcloud = FakeGcloud::default();
+
+        let outcome =
+            Gcloud::on_switch_to(&config("client-abc", Some("abc-dev"), None), &gcloud).unwrap();
+
+        assert_eq!(outcome, ApplyOutcome::Changed);
+        assert_eq!(
+            *gcloud.calls.borrow(),
+            [
+                "config configurations activate client-abc --quiet",
+                "config set project abc-dev --quiet"
+            ]
+        );
+        assert_eq!(
+            Gcloud::on_switch_to(&config("default", None, None), &gcloud).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        assert_eq!(gcloud.calls.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_active_configuration() {
+        assert_eq!(
+            Gcloud::active_configuration(&FakeGcloud::default()).unwrap(),
+            Some(config("default", Some("hobby"), Some("me@gmail.com")))
+        );
+    }
+}
@@ -1,81 +1,1247 @@
-use std::path::PathBuf;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    path::{Path, PathBuf},
+};
 
-use saphyr::{LoadableYamlNode, Yaml, YamlEmitter};
+use log::{debug, info};
+use saphyr::{LoadableYamlNode, MarkedYaml, Scalar, Yaml, YamlData, YamlEmitter};
 
 use crate::{
+    environment::Environment,
     error::{EnvMgrError, EnvMgrResult},
-    integrations::OnSwitchToPluginResult,
+    fs::Fs,
+    integrations::{
+        ApplyOutcome, Integration, IntegrationKind, IntegrationOutcome, write_if_changed,
+    },
+    runner::CommandRunner,
 };
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize, schemars::JsonSchema, Default)]
 pub struct GhCliConfig {
     pub hosts: Vec<GhCliHostUser>,
+    /// Keys set in gh's `config.yml`, e.g. `editor` or `pager`; `aliases.co` sets the
+    /// alias `co`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub gh_config: BTreeMap<String, String>,
 }
 
-#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, schemars::JsonSchema, Default)]
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema, Default,
+)]
 pub struct GhCliHostUser {
     pub host: String,
     pub user: String,
+    /// `ssh` or `https`, written as the host's `git_protocol` in `hosts.yml`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_protocol: Option<String>,
+}
+
+impl std::fmt::Display for GhCliHostUser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.user)?;
+        if let Some(protocol) = &self.git_protocol {
+            write!(f, " ({protocol})")?;
+        }
+        Ok(())
+    }
 }
 
 pub struct GhCli;
 
+/// gh keeps `hosts.yml` private, since it can hold tokens
+const HOSTS_FILE_MODE: u32 = 0o600;
+
+fn string_node(value: &str) -> Yaml<'static> {
+    Yaml::Value(Scalar::String(value.to_string().into()))
+}
+
+/// The accounts `gh auth status` reports as logged in to `host`, from lines like
+/// `Logged in to github.com account alice (keyring)` or, before gh 2.40,
+/// `Logged in to github.com as alice (oauth_token)`
+fn logged_in_accounts(output: &str, host: &str) -> BTreeSet<String> {
+    let mut accounts = BTreeSet::new();
+    for line in output.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some(start) = words.windows(3).position(|w| w == ["Logged", "in", "to"]) else {
+            continue;
+        };
+        if let [logged_host, "account" | "as", account, ..] = &words[start + 3..]
+            && *logged_host == host
+        {
+            accounts.insert(account.to_string());
+        }
+    }
+    accounts
+}
+
+/// The text of a scalar node, `None` for collections
+fn scalar_text(node: &MarkedYaml) -> Option<String> {
+    match &node.data {
+        YamlData::Value(Scalar::Null) => Some(String::new()),
+        YamlData::Value(Scalar::Boolean(b)) => Some(b.to_string()),
+        YamlData::Value(Scalar::Integer(i)) => Some(i.to_string()),
+        YamlData::Value(Scalar::FloatingPoint(f)) => Some(f.to_string()),
+        YamlData::Value(Scalar::String(s)) => Some(s.to_string()),
+        YamlData::Representation(s, _, _) => Some(s.to_string()),
+        _ => None,
+    }
+}
+
+/// The key node named `key` of `mapping`, and its value
+fn mapping_entry<'a, 'input>(
+    mapping: &'a MarkedYaml<'input>,
+    key: &str,
+) -> Option<(&'a MarkedYaml<'input>, &'a MarkedYaml<'input>)> {
+    let YamlData::Mapping(entries) = &mapping.data else {
+        return None;
+    };
+    entries
+        .iter()
+        .find(|(k, _)| matches!(&k.data, YamlData::Value(Scalar::String(s)) if s == key))
+}
+
+/// `key: value` as a line of `config.yml` indented by `indent`, quoted where YAML needs it
+fn config_line(indent: usize, key: &str, value: &str) -> EnvMgrResult<String> {
+    if value.contains('\n') {
+        return Err(EnvMgrError::GhCliConfig(format!(
+            "gh_config.{key} must be a single line"
+        )));
+    }
+    let value = serde_norway::to_string(value)
+        .map_err(|e| EnvMgrError::GhCliConfig(format!("gh_config.{key}: {e}")))?;
+    Ok(format!("{:indent$}{key}: {}", "", value.trim_end()))
+}
+
+/// `content` of gh's `config.yml` with `key` set to `value`, editing single lines so
+/// comments and the other keys stay as they are. `None` when it already is set.
+///
+/// A top-level key replaces the line of its current value, or is appended. A key like
+/// `aliases.co` goes into the `aliases` mapping instead, which is created when missing.
+pub fn with_gh_config(content: &str, key: &str, value: &str) -> EnvMgrResult<Option<String>> {
+    let docs = MarkedYaml::load_from_str(content)?;
+    let root = docs
+        .first()
+        .filter(|doc| !matches!(doc.data, YamlData::Value(Scalar::Null)));
+    let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+    let (parent, name) = match key.split_once('.') {
+        Some((parent, name)) => (Some(parent), name),
+        None => (None, key),
+    };
+    // Markers count lines from 1
+    let line_of = |node: &MarkedYaml| node.span.start.line() - 1;
+
+    let section = match (parent, root) {
+        (None, root) => root.map(|root| (root, 0)),
+        (Some(parent), Some(root)) => match mapping_entry(root, parent) {
+            Some((
+                parent_key,
+                section @ MarkedYaml {
+                    data: YamlData::Mapping(entries),
+                    ..
+                },
+            )) if !entries.is_empty() => {
+                let (first_key, _) = entries.front().expect("mapping is not empty");
+                if first_key.span.start.line() == parent_key.span.start.line() {
+                    return Err(EnvMgrError::GhCliConfig(format!(
+                        "{parent} in gh's config.yml is a flow mapping, which envmgr can't edit"
+                    )));
+                }
+                Some((section, first_key.span.start.col()))
+            }
+            Some((parent_key, section)) => {
+                if !matches!(
+                    section.data,
+                    YamlData::Mapping(_) | YamlData::Value(Scalar::Null)
+                ) {
+                    return Err(EnvMgrError::GhCliConfig(format!(
+                        "{parent} in gh's config.yml is not a mapping"
+                    )));
+                }
+                // `aliases:` or `aliases: {}` without entries
+                let line = line_of(parent_key);
+                lines[line] = format!(
+                    "{:indent$}{parent}:",
+                    "",
+                    indent = parent_key.span.start.col()
+                );
+                lines.insert(
+                    line + 1,
+                    config_line(parent_key.span.start.col() + 4, name, value)?,
+                );
+                return Ok(Some(lines.join("\n") + "\n"));
+            }
+            None => {
+                lines.push(format!("{parent}:"));
+                lines.push(config_line(4, name, value)?);
+                return Ok(Some(lines.join("\n") + "\n"));
+            }
+        },
+        (Some(parent), None) => {
+            lines.push(format!("{parent}:"));
+            lines.push(config_line(4, name, value)?);
+            return Ok(Some(lines.join("\n") + "\n"));
+        }
+    };
+
+    let Some((section, indent)) = section else {
+        lines.push(config_line(0, name, value)?);
+        return Ok(Some(lines.join("\n") + "\n"));
+    };
+    match mapping_entry(section, name) {
+        Some((_, current)) => {
+            let Some(text) = scalar_text(current) else {
+                return Err(EnvMgrError::GhCliConfig(format!(
+                    "{key} in gh's config.yml is not a single value"
+                )));
+            };
+            if text == value {
+                return Ok(None);
+            }
+            let (key_node, _) = mapping_entry(section, name).expect("entry was found");
+            let line = line_of(key_node);
+            if !matches!(current.data, YamlData::Value(Scalar::Null))
+                && current.span.start.line() != key_node.span.start.line()
+            {
+                return Err(EnvMgrError::GhCliConfig(format!(
+                    "{key} in gh's config.yml spans several lines, which envmgr can't edit"
+                )));
+            }
+            lines[line] = config_line(key_node.span.start.col(), name, value)?;
+        }
+        None => {
+            // After the last line of the section; for the root, the end of the file
+            let line = match parent {
+                Some(_) => {
+                    let YamlData::Mapping(entries) = &section.data else {
+                        unreachable!("section is a mapping");
+                    };
+                    entries
+                        .iter()
+                        .map(|(k, v)| match v.data {
+                            // An empty value is marked where the next token starts
+                            YamlData::Value(Scalar::Null) => line_of(k),
+                            _ => line_of(k).max(v.span.end.line() - 1),
+                        })
+                        .max()
+                        .map_or(lines.len(), |last| last + 1)
+                }
+                None => lines.len(),
+            };
+            lines.insert(line, config_line(indent, name, value)?);
+        }
+    }
+    Ok(Some(lines.join("\n") + "\n"))
+}
+
+/// The `gh auth login` invocation that adds an account on `host`
+fn login_command(host: &str) -> String {
+    format!("gh auth login --hostname {host}")
+}
+
 impl GhCli {
-    fn gh_cli_hosts_file_path() -> EnvMgrResult<PathBuf> {
+    pub(crate) fn gh_config_dir() -> EnvMgrResult<PathBuf> {
         let path = dirs::config_dir()
             .ok_or(EnvMgrError::DirError(
                 "Could not determine config directory".into(),
             ))?
-            .join("gh")
-            .join("hosts.yml");
+            .join("gh");
         Ok(path)
     }
 
-    pub fn on_switch_to(config: &GhCliConfig) -> EnvMgrResult<OnSwitchToPluginResult> {
-        let mut gh_cli_hosts_doc =
-            if let Ok(content) = std::fs::read_to_string(Self::gh_cli_hosts_file_path()?) {
-                Yaml::load_from_str(&content)?
-            } else {
-                vec![]
-            };
+    pub(crate) fn gh_cli_hosts_file_path() -> EnvMgrResult<PathBuf> {
+        Ok(Self::gh_config_dir()?.join("hosts.yml"))
+    }
+
+    /// Read the active user per host from the gh hosts file
+    pub fn active_users() -> EnvMgrResult<BTreeMap<String, String>> {
+        let content = std::fs::read_to_string(Self::gh_cli_hosts_file_path()?)?;
+        Ok(Self::parse_active_users(&content)?)
+    }
 
-        if gh_cli_hosts_doc.is_empty() {
-            return Err(EnvMgrError::GhCliConfig(
-                "GH CLI hosts file is empty or missing".into(),
-            ));
+    fn parse_active_users(content: &str) -> Result<BTreeMap<String, String>, saphyr::ScanError> {
+        let docs = Yaml::load_from_str(content)?;
+        let mut users = BTreeMap::new();
+        if let Some(hosts) = docs.first().and_then(|d| d.as_mapping()) {
+            for (host, entry) in hosts {
+                if let (Some(host), Some(user)) = (
+                    host.as_str(),
+                    entry.as_mapping_get("user").and_then(|u| u.as_str()),
+                ) {
+                    users.insert(host.to_string(), user.to_string());
+                }
+            }
         }
+        Ok(users)
+    }
 
-        let gh_cli_hosts = &mut gh_cli_hosts_doc[0];
+    /// Every host/user pair gh is logged in with, active or not, from the gh hosts file
+    pub fn authenticated_users() -> EnvMgrResult<Vec<GhCliHostUser>> {
+        let content = std::fs::read_to_string(Self::gh_cli_hosts_file_path()?)?;
+        Ok(Self::parse_authenticated_users(&content)?)
+    }
 
-        for GhCliHostUser { host, user } in &config.hosts {
-            gh_cli_hosts
-                .as_mapping_get_mut(host)
+    fn parse_authenticated_users(content: &str) -> Result<Vec<GhCliHostUser>, saphyr::ScanError> {
+        let mut known = Self::parse_known_users(content);
+        // Older hosts files only name the active user
+        for (host, user) in Self::parse_active_users(content)? {
+            known.entry(host).or_default().insert(user);
+        }
+        Ok(known
+            .into_iter()
+            .flat_map(|(host, users)| users.into_iter().map(move |user| (host.clone(), user)))
+            .map(|(host, user)| GhCliHostUser {
+                host,
+                user,
+                git_protocol: None,
+            })
+            .collect())
+    }
+
+    /// The users listed per host in `content`, active or not
+    fn parse_known_users(content: &str) -> BTreeMap<String, BTreeSet<String>> {
+        let Ok(docs) = Yaml::load_from_str(content) else {
+            return BTreeMap::new();
+        };
+        let mut known = BTreeMap::new();
+        if let Some(hosts) = docs.first().and_then(|d| d.as_mapping()) {
+            for (host, entry) in hosts {
+                let Some(host) = host.as_str() else {
+                    continue;
+                };
+                let users = entry
+                    .as_mapping_get("users")
+                    .and_then(|users| users.as_mapping())
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|(user, _)| user.as_str().map(str::to_string));
+                known.insert(host.to_string(), users.collect());
+            }
+        }
+        known
+    }
+
+    /// Whether gh holds a token for `user` on `host` even though `hosts.yml` doesn't
+    /// list them, e.g. from `GH_TOKEN`, going by `gh auth status --hostname <host>`
+    pub fn has_token(host: &str, user: &str, runner: &dyn CommandRunner) -> EnvMgrResult<bool> {
+        debug!("Running gh auth status --hostname {host}");
+        let output = match runner.run("gh", &["auth", "status", "--hostname", host]) {
+            Ok(output) => output,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(EnvMgrError::GhCliConfig(format!(
+                    "user '{user}' is not in the GH CLI hosts file for host '{host}', and gh is not installed to log in"
+                )));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // gh exits non-zero when any account has a problem, so go by what it lists.
+        // Older versions print the status to stderr.
+        let output = format!("{}\n{}", output.stdout, output.stderr);
+        Ok(logged_in_accounts(&output, host).contains(user))
+    }
+
+    /// Run `gh auth login --hostname <host>` so the user can add an account
+    fn login(host: &str, user: &str, runner: &dyn CommandRunner) -> EnvMgrResult<()> {
+        info!("User '{user}' is not logged in to {host}, running gh auth login");
+        match runner.run_interactive("gh", &["auth", "login", "--hostname", host], &[]) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(EnvMgrError::GhCliConfig(format!(
+                "{} failed",
+                login_command(host)
+            ))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(EnvMgrError::GhCliConfig(
+                "gh is not installed, but the gh_cli integration needs it".into(),
+            )),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Whether every configured host already has its user active, and its git protocol
+    /// when one is given, in `content`
+    pub fn is_converged(content: &str, config: &GhCliConfig) -> bool {
+        let Ok(docs) = Yaml::load_from_str(content) else {
+            return false;
+        };
+        let Some(hosts) = docs.first() else {
+            return false;
+        };
+        config.hosts.iter().all(|h| {
+            let entry = hosts.as_mapping_get(&h.host);
+            let get = |key: &str| entry.and_then(|e| e.as_mapping_get(key)?.as_str());
+            get("user") == Some(h.user.as_str())
+                && h.git_protocol
+                    .as_deref()
+                    .is_none_or(|protocol| get("git_protocol") == Some(protocol))
+        })
+    }
+
+    /// The hosts file `content` with the configured users made active. Hosts and users
+    /// it doesn't list yet are added, starting from an empty file when it has no hosts.
+    pub fn render_hosts_file(content: &str, config: &GhCliConfig) -> EnvMgrResult<String> {
+        let mut gh_cli_hosts_doc = Yaml::load_from_str(content)?;
+
+        if gh_cli_hosts_doc.is_empty() || gh_cli_hosts_doc[0].is_null() {
+            gh_cli_hosts_doc = vec![Yaml::Mapping(Default::default())];
+        }
+
+        let gh_cli_hosts = gh_cli_hosts_doc[0]
+            .as_mapping_mut()
+            .ok_or(EnvMgrError::GhCliConfig(
+                "GH CLI hosts file is not a mapping of hosts".into(),
+            ))?;
+
+        for GhCliHostUser {
+            host,
+            user,
+            git_protocol,
+        } in &config.hosts
+        {
+            let entry = gh_cli_hosts
+                .entry(string_node(host))
+                .or_insert_with(|| Yaml::Mapping(Default::default()));
+            if entry.is_null() {
+                *entry = Yaml::Mapping(Default::default());
+            }
+            let entry = entry
+                .as_mapping_mut()
                 .ok_or(EnvMgrError::GhCliConfig(format!(
-                    "Host '{host}' not found in GH CLI hosts file"
-                )))?
-                .as_mapping_get_mut("users")
+                    "Host '{host}' in GH CLI hosts file is not a mapping"
+                )))?;
+            // Before gh 2.40 a host had a single user and no `users` section, which gh
+            // migrates by itself on its next run
+            if !entry.is_empty() && !entry.contains_key(&string_node("users")) {
+                return Err(EnvMgrError::GhCliConfig(format!(
+                    "'users' section missing for host '{host}', run `gh auth status` once so gh migrates hosts.yml"
+                )));
+            }
+            let users = entry
+                .entry(string_node("users"))
+                .or_insert_with(|| Yaml::Mapping(Default::default()));
+            if users.is_null() {
+                *users = Yaml::Mapping(Default::default());
+            }
+            users
+                .as_mapping_mut()
                 .ok_or(EnvMgrError::GhCliConfig(format!(
-                    "'users' section missing for host '{host}'"
+                    "'users' section for host '{host}' is not a mapping"
                 )))?
-                .as_mapping_get_mut(user)
-                .ok_or(EnvMgrError::GhCliConfig(format!(
-                    "User '{user}' not found under host '{host}'"
-                )))?;
-
-            if let Some(u) = gh_cli_hosts
-                .as_mapping_get_mut(host)
-                .and_then(|h| h.as_mapping_get_mut("user"))
-            {
-                *u = Yaml::Value(saphyr::Scalar::String(user.clone().into()));
+                .entry(string_node(user))
+                .or_insert(Yaml::Value(Scalar::Null));
+            entry.insert(string_node("user"), string_node(user));
+            if let Some(protocol) = git_protocol {
+                entry.insert(string_node("git_protocol"), string_node(protocol));
             }
         }
+        let gh_cli_hosts = &gh_cli_hosts_doc[0];
         let mut content = String::new();
         YamlEmitter::new(&mut content).dump(gh_cli_hosts)?;
 
         content.push('\n'); // Ensure file ends with a newline
+        Ok(content)
+    }
+
+    /// Make the configured users active in `hosts.yml` of `gh_dir` and set the `gh_config`
+    /// keys in its `config.yml`, see [`Self::switch_hosts`] and [`with_gh_config`]
+    pub fn on_switch_to(
+        config: &GhCliConfig,
+        gh_dir: &Path,
+        fs: &dyn Fs,
+        runner: &dyn CommandRunner,
+        login: bool,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        let hosts = Self::switch_hosts(config, &gh_dir.join("hosts.yml"), fs, runner, login)?;
+        let settings = Self::apply_gh_config(config, &gh_dir.join("config.yml"), fs)?;
+        Ok(match (hosts, settings) {
+            (ApplyOutcome::AlreadyInDesiredState, ApplyOutcome::AlreadyInDesiredState) => {
+                ApplyOutcome::AlreadyInDesiredState
+            }
+            _ => ApplyOutcome::Changed,
+        })
+    }
+
+    /// The configured hosts whose user isn't the active one in the hosts file `content`
+    pub fn inactive_hosts<'c>(content: &str, config: &'c GhCliConfig) -> Vec<&'c GhCliHostUser> {
+        let active = Self::parse_active_users(content).unwrap_or_default();
+        config
+            .hosts
+            .iter()
+            .filter(|h| active.get(&h.host) != Some(&h.user))
+            .collect()
+    }
+
+    /// The `gh_config` keys whose value differs in gh's `config.yml`, all of them when
+    /// it can't be read
+    pub fn pending_gh_config(config: &GhCliConfig) -> Vec<(String, String)> {
+        let content = Self::gh_config_dir()
+            .and_then(|dir| Ok(std::fs::read_to_string(dir.join("config.yml"))?))
+            .unwrap_or_default();
+        config
+            .gh_config
+            .iter()
+            .filter(|(key, value)| !matches!(with_gh_config(&content, key, value), Ok(None)))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    /// Set the `gh_config` keys in `config.yml`, leaving it untouched when they already are
+    fn apply_gh_config(
+        config: &GhCliConfig,
+        path: &Path,
+        fs: &dyn Fs,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        if config.gh_config.is_empty() {
+            return Ok(ApplyOutcome::AlreadyInDesiredState);
+        }
+        let mut content = match fs.read(path)? {
+            Some(bytes) => String::from_utf8(bytes).map_err(|_| {
+                EnvMgrError::GhCliConfig(format!("{} is not valid UTF-8", path.display()))
+            })?,
+            None => String::new(),
+        };
+        let mut changed = false;
+        for (key, value) in &config.gh_config {
+            if let Some(updated) = with_gh_config(&content, key, value)? {
+                debug!("Setting {key} in {}", path.display());
+                content = updated;
+                changed = true;
+            }
+        }
+        if !changed {
+            return Ok(ApplyOutcome::AlreadyInDesiredState);
+        }
+        fs.write_atomic(path, content.as_bytes())?;
+        Ok(ApplyOutcome::Changed)
+    }
+
+    /// Make the configured users active, leaving `hosts.yml` untouched when they already are.
+    ///
+    /// Compared by active user rather than bytes, since gh formats the file differently.
+    /// A user `hosts.yml` doesn't list is added when gh has a token for them anyway;
+    /// otherwise, with `login`, `gh auth login` is run for the user to add the account.
+    fn switch_hosts(
+        config: &GhCliConfig,
+        path: &Path,
+        fs: &dyn Fs,
+        runner: &dyn CommandRunner,
+        login: bool,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        let read = || -> EnvMgrResult<String> {
+            Ok(fs
+                .read(path)?
+                .map(|c| String::from_utf8_lossy(&c).into_owned())
+                .unwrap_or_default())
+        };
+        let mut content = read()?;
+        if !content.trim().is_empty() && Self::is_converged(&content, config) {
+            return Ok(ApplyOutcome::AlreadyInDesiredState);
+        }
+        let mut logged_in = false;
+        for GhCliHostUser { host, user, .. } in &config.hosts {
+            let is_known = |content: &str| {
+                Self::parse_known_users(content)
+                    .get(host)
+                    .is_some_and(|users| users.contains(user))
+            };
+            if is_known(&content) || Self::has_token(host, user, runner)? {
+                continue;
+            }
+            if !login {
+                return Err(EnvMgrError::GhCliConfig(format!(
+                    "user '{user}' is not logged in to host '{host}', run `{}`, or switch with `--login` to be taken through it",
+                    login_command(host)
+                )));
+            }
+            Self::login(host, user, runner)?;
+            logged_in = true;
+            content = read()?;
+            if !is_known(&content) && !Self::has_token(host, user, runner)? {
+                return Err(EnvMgrError::GhCliConfig(format!(
+                    "`{}` didn't log in user '{user}', run it again and pick that account",
+                    login_command(host)
+                )));
+            }
+        }
+        let rendered = Self::render_hosts_file(&content, config)?;
+        if content.trim().is_empty() {
+            debug!("Creating {}", path.display());
+            fs.write_atomic_with_mode(path, rendered.as_bytes(), HOSTS_FILE_MODE)?;
+            return Ok(ApplyOutcome::Changed);
+        }
+        match write_if_changed(fs, path, &rendered)? {
+            ApplyOutcome::AlreadyInDesiredState if logged_in => Ok(ApplyOutcome::Changed),
+            outcome => Ok(outcome),
+        }
+    }
+}
+
+/// [`GhCli`] behind [`Integration`]
+pub struct GhCliIntegration<'a> {
+    /// gh's config directory, with `hosts.yml` and `config.yml`
+    pub gh_dir: PathBuf,
+    pub fs: &'a dyn Fs,
+    pub runner: &'a dyn CommandRunner,
+    /// Run `gh auth login` for users gh has no token for
+    pub login: bool,
+}
+
+impl Integration for GhCliIntegration<'_> {
+    type Config = GhCliConfig;
+
+    fn name(&self) -> IntegrationKind {
+        IntegrationKind::GhCli
+    }
+
+    fn config<'e>(&self, env: &'e Environment) -> Option<&'e Self::Config> {
+        env.gh_cli.as_ref()
+    }
+
+    fn on_switch_to(
+        &self,
+        _env: &Environment,
+        config: &Self::Config,
+    ) -> EnvMgrResult<IntegrationOutcome> {
+        GhCli::on_switch_to(config, &self.gh_dir, self.fs, self.runner, self.login).map(Into::into)
+    }
+
+    fn describe_switch_to(&self, _env: &Environment, config: &Self::Config) -> Vec<String> {
+        let hosts = self
+            .fs
+            .read(&self.gh_dir.join("hosts.yml"))
+            .map(|content| String::from_utf8_lossy(&content.unwrap_or_default()).into_owned())
+            .map_err(|e| e.to_string());
+        let active = hosts
+            .clone()
+            .and_then(|content| GhCli::parse_active_users(&content).map_err(|e| e.to_string()));
+        let hosts = hosts.unwrap_or_default();
+        let settings = GhCli::pending_gh_config(config);
+        if GhCli::is_converged(&hosts, config) && settings.is_empty() {
+            let users = config
+                .hosts
+                .iter()
+                .map(|h| format!("{} uses {h}", h.host))
+                .collect::<Vec<_>>()
+                .join(", ");
+            return vec![format!("{} ({users})", ApplyOutcome::AlreadyInDesiredState)];
+        }
+        let mut actions = vec![];
+        for host_user in &config.hosts {
+            let (host, user) = (&host_user.host, &host_user.user);
+            let protocol = host_user
+                .git_protocol
+                .as_ref()
+                .map(|p| format!(" with git_protocol {p}"))
+                .unwrap_or_default();
+            let single = GhCliConfig {
+                hosts: vec![host_user.clone()],
+                ..Default::default()
+            };
+            actions.push(match active.as_ref().map(|users| users.get(host)) {
+                _ if GhCli::is_converged(&hosts, &single) => {
+                    format!("{host} already uses {host_user}")
+                }
+                Ok(Some(current)) if current == user => {
+                    format!("set git_protocol of {host}{protocol}")
+                }
+                Ok(Some(current)) => format!("switch {host} from {current} to {user}{protocol}"),
+                Ok(None) => format!("switch {host} to {user}{protocol} (host not logged in)"),
+                Err(e) => format!("switch {host} to {user}{protocol} (hosts file unreadable: {e})"),
+            });
+        }
+        actions.extend(
+            settings
+                .into_iter()
+                .map(|(key, value)| format!("set {key} to '{value}' in gh's config.yml")),
+        );
+        actions
+    }
+
+    /// The active user of each configured host that has one, as a JSON object by host
+    fn snapshot(&self, config: &Self::Config) -> EnvMgrResult<Option<String>> {
+        let content = self
+            .fs
+            .read(&self.gh_dir.join("hosts.yml"))?
+            .map(|c| String::from_utf8_lossy(&c).into_owned())
+            .unwrap_or_default();
+        let mut active = GhCli::parse_active_users(&content)?;
+        active.retain(|host, _| config.hosts.iter().any(|h| &h.host == host));
+        if active.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::to_string(&active)?))
+    }
+
+    /// Make the recorded users active again; hosts without one keep their user and
+    /// the `gh_config` keys stay, gh has no notion of unsetting them
+    fn on_switch_from(
+        &self,
+        _config: &Self::Config,
+        snapshot: Option<&str>,
+    ) -> EnvMgrResult<IntegrationOutcome> {
+        let Some(snapshot) = snapshot else {
+            return Ok(IntegrationOutcome::default());
+        };
+        let active: BTreeMap<String, String> = serde_json::from_str(snapshot).map_err(|e| {
+            EnvMgrError::GhCliConfig(format!("the recorded active users are unreadable: {e}"))
+        })?;
+        let previous = GhCliConfig {
+            hosts: active
+                .into_iter()
+                .map(|(host, user)| GhCliHostUser {
+                    host,
+                    user,
+                    git_protocol: None,
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let path = self.gh_dir.join("hosts.yml");
+        GhCli::switch_hosts(&previous, &path, self.fs, self.runner, false).map(Into::into)
+    }
+
+    fn doctor_check(&self, config: &Self::Config, out: &mut String) -> usize {
+        let content = match self.fs.read(&self.gh_dir.join("hosts.yml")) {
+            Ok(content) => String::from_utf8_lossy(&content.unwrap_or_default()).into_owned(),
+            Err(e) => {
+                let _ = writeln!(out, "gh_cli: could not read hosts.yml\n  warning: {e}");
+                return 0;
+            }
+        };
+        let inactive = GhCli::inactive_hosts(&content, config);
+        let _ = writeln!(
+            out,
+            "gh_cli: {} of {} host(s) have their user active",
+            config.hosts.len() - inactive.len(),
+            config.hosts.len()
+        );
+        for host in inactive {
+            let _ = writeln!(
+                out,
+                "  warning: {} is not the active user of {}, run `envmgr integrations run gh_cli`",
+                host.user, host.host
+            );
+        }
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, fs, io};
+
+    use super::*;
+    use crate::{
+        fs::{MemFs, RealFs},
+        runner::CommandOutput,
+    };
+
+    const AUTH_STATUS: &str = indoc::indoc! {"
+        github.com
+          ✓ Logged in to github.com account alice (GH_TOKEN)
+          - Active account: true
+          - Git operations protocol: https
+          - Token: gho_************************************
+    "};
+
+    /// gh with a token for alice on github.com. `gh auth login` adds bob on
+    /// ghe.corp.com to `hosts`, a real hosts file.
+    struct FakeGh {
+        hosts: PathBuf,
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl FakeGh {
+        fn new(hosts: &Path) -> Self {
+            Self {
+                hosts: hosts.to_path_buf(),
+                calls: RefCell::default(),
+            }
+        }
+    }
+
+    impl CommandRunner for FakeGh {
+        fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+            assert_eq!(program, "gh");
+            self.calls.borrow_mut().push(args.join(" "));
+            Ok(match args {
+                ["auth", "status", "--hostname", "github.com"] => CommandOutput {
+                    success: true,
+                    stdout: AUTH_STATUS.to_string(),
+                    ..Default::default()
+                },
+                _ => CommandOutput {
+                    stderr: "You are not logged into any GitHub hosts.".to_string(),
+                    ..Default::default()
+                },
+            })
+        }
+
+        fn run_interactive(
+            &self,
+            program: &str,
+            args: &[&str],
+            _env: &[(&str, &str)],
+        ) -> io::Result<bool> {
+            assert_eq!(program, "gh");
+            self.calls.borrow_mut().push(args.join(" "));
+            let mut content = fs::read_to_string(&self.hosts).unwrap_or_default();
+            content.push_str("ghe.corp.com:\n    users:\n        bob:\n    user: bob\n");
+            fs::create_dir_all(self.hosts.parent().unwrap())?;
+            fs::write(&self.hosts, content)?;
+            Ok(true)
+        }
+    }
+
+    /// As written by gh 2.63, with one alias added
+    const CONFIG_YML: &str = indoc::indoc! {r#"
+        # The current version of the config schema
+        version: 1
+        # What protocol to use when performing git operations. Supported values: ssh, https
+        git_protocol: https
+        # What editor gh should run when creating issues, pull requests, etc. If blank, will refer to environment.
+        editor:
+        # When to interactively prompt. This is a global config that cannot be overridden by hostname. Supported values: enabled, disabled
+        prompt: enabled
+        # A pager program to send command output to, e.g. "less". If blank, will refer to environment. Set the value to "cat" to disable the pager.
+        pager:
+        # Aliases allow you to create nicknames for gh commands
+        aliases:
+            co: pr checkout
+        # The path to a unix socket through which send HTTP connections. If blank, HTTP traffic will be handled by net/http.DefaultTransport.
+        http_unix_socket:
+        # What web browser gh should use when opening URLs. If blank, will refer to environment.
+        browser:
+    "#};
+
+    fn set_all(content: &str, settings: &[(&str, &str)]) -> String {
+        settings
+            .iter()
+            .fold(content.to_string(), |content, (key, value)| {
+                with_gh_config(&content, key, value)
+                    .unwrap()
+                    .unwrap_or(content)
+            })
+    }
+
+    fn temp_hosts_file(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("gh").join("hosts.yml")
+    }
+
+    fn host_user(host: &str, user: &str) -> GhCliHostUser {
+        GhCliHostUser {
+            host: host.to_string(),
+            user: user.to_string(),
+            git_protocol: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_active_users() {
+        let content = indoc::indoc! {"
+            github.com:
+                users:
+                    alice:
+                    alice-work:
+                git_protocol: https
+                user: alice-work
+            ghe.corp.com:
+                users:
+                    bob:
+                user: bob
+        "};
+        let users = GhCli::parse_active_users(content).unwrap();
+        assert_eq!(users.get("github.com"), Some(&"alice-work".to_string()));
+        assert_eq!(users.get("ghe.corp.com"), Some(&"bob".to_string()));
+    }
+
+    #[test]
+    fn test_parse_authenticated_users() {
+        let content = indoc::indoc! {"
+            github.com:
+                users:
+                    alice:
+                    alice-work:
+                user: alice-work
+            ghe.corp.com:
+                oauth_token: gho_legacy
+                user: bob
+        "};
+        let users = GhCli::parse_authenticated_users(content).unwrap();
+        assert_eq!(
+            users,
+            [
+                host_user("ghe.corp.com", "bob"),
+                host_user("github.com", "alice"),
+                host_user("github.com", "alice-work"),
+            ]
+        );
+        assert_eq!(GhCli::parse_authenticated_users("").unwrap(), []);
+    }
+
+    #[test]
+    fn test_switch_writes_only_when_user_changes() {
+        let content = indoc::indoc! {"
+            github.com:
+                users:
+                    alice:
+                    alice-work:
+                user: alice
+        "};
+        let config = |user: &str| GhCliConfig {
+            hosts: vec![GhCliHostUser {
+                host: "github.com".to_string(),
+                user: user.to_string(),
+                git_protocol: None,
+            }],
+            ..Default::default()
+        };
+        let path = GhCli::gh_cli_hosts_file_path().unwrap();
+        let gh = FakeGh::new(&path);
+
+        let fs = MemFs::default().with_file(&path, content);
+        let outcome =
+            GhCli::on_switch_to(&config("alice"), path.parent().unwrap(), &fs, &gh, false).unwrap();
+        assert_eq!(outcome, ApplyOutcome::AlreadyInDesiredState);
+        assert!(fs.writes.borrow().is_empty());
+
+        let outcome = GhCli::on_switch_to(
+            &config("alice-work"),
+            path.parent().unwrap(),
+            &fs,
+            &gh,
+            false,
+        )
+        .unwrap();
+        assert_eq!(outcome, ApplyOutcome::Changed);
+        let users = GhCli::parse_active_users(&fs.content(&path).unwrap()).unwrap();
+        assert_eq!(users.get("github.com"), Some(&"alice-work".to_string()));
+
+        assert!(matches!(
+            GhCli::on_switch_to(&config("mallory"), path.parent().unwrap(), &fs, &gh, false),
+            Err(EnvMgrError::GhCliConfig(_))
+        ));
+        assert_eq!(fs.writes.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_gh_config_round_trip_keeps_comments() {
+        let settings = [
+            ("editor", "nvim"),
+            ("pager", "less -R"),
+            ("aliases.co", "pr checkout"),
+            ("aliases.prs", "pr list --author @me"),
+            ("prompt", "disabled"),
+            ("browser", "firefox: work"),
+        ];
+
+        let updated = set_all(CONFIG_YML, &settings);
+
+        let expected = CONFIG_YML
+            .replace("\neditor:\n", "\neditor: nvim\n")
+            .replace("\npager:\n", "\npager: less -R\n")
+            .replace(
+                "    co: pr checkout\n",
+                "    co: pr checkout\n    prs: pr list --author @me\n",
+            )
+            .replace("prompt: enabled", "prompt: disabled")
+            .replace("\nbrowser:\n", "\nbrowser: 'firefox: work'\n");
+        assert_eq!(updated, expected);
+        // What gh reads back is what was set
+        let docs = Yaml::load_from_str(&updated).unwrap();
+        assert_eq!(docs[0]["browser"].as_str(), Some("firefox: work"));
+        assert_eq!(
+            docs[0]["aliases"]["prs"].as_str(),
+            Some("pr list --author @me")
+        );
+        assert_eq!(docs[0]["version"].as_integer(), Some(1));
+        for (key, value) in settings {
+            assert_eq!(with_gh_config(&updated, key, value).unwrap(), None, "{key}");
+        }
+    }
+
+    #[test]
+    fn test_gh_config_creates_what_is_missing() {
+        assert_eq!(set_all("", &[("editor", "vim")]), "editor: vim\n");
+        assert_eq!(
+            set_all("", &[("aliases.co", "pr checkout")]),
+            "aliases:\n    co: pr checkout\n"
+        );
+        assert_eq!(
+            set_all(
+                "version: 1\naliases: {}\nbrowser:\n",
+                &[("aliases.co", "pr checkout")]
+            ),
+            "version: 1\naliases:\n    co: pr checkout\nbrowser:\n"
+        );
+        assert_eq!(
+            set_all("version: 1\n", &[("editor", ""), ("pager", "true")]),
+            "version: 1\neditor: ''\npager: 'true'\n"
+        );
+        // An empty value that is already empty
+        assert_eq!(with_gh_config("editor:\n", "editor", "").unwrap(), None);
+    }
+
+    #[test]
+    fn test_gh_config_rejects_what_it_cant_edit() {
+        for (content, key) in [
+            ("aliases: {co: pr checkout}\n", "aliases.prs"),
+            ("aliases:\n    co: pr checkout\n", "aliases"),
+            ("editor: vim\n", "editor.x"),
+        ] {
+            assert!(
+                matches!(
+                    with_gh_config(content, key, "x"),
+                    Err(EnvMgrError::GhCliConfig(_))
+                ),
+                "{key} in {content}"
+            );
+        }
+        assert!(with_gh_config("", "editor", "a\nb").is_err());
+    }
+
+    #[test]
+    fn test_switch_sets_git_protocol_and_gh_config() {
+        let gh_dir = GhCli::gh_config_dir().unwrap();
+        let hosts = gh_dir.join("hosts.yml");
+        let fs = MemFs::default()
+            .with_file(
+                &hosts,
+                "ghe.corp.com:\n    users:\n        bob:\n    git_protocol: https\n    user: bob\n",
+            )
+            .with_file(gh_dir.join("config.yml"), CONFIG_YML);
+        let config = GhCliConfig {
+            hosts: vec![GhCliHostUser {
+                git_protocol: Some("ssh".to_string()),
+                ..host_user("ghe.corp.com", "bob")
+            }],
+            gh_config: BTreeMap::from([("editor".to_string(), "vim".to_string())]),
+        };
+        let gh = FakeGh::new(&hosts);
+
+        let outcome = GhCli::on_switch_to(&config, &gh_dir, &fs, &gh, false).unwrap();
+
+        assert_eq!(outcome, ApplyOutcome::Changed);
+        let docs = Yaml::load_from_str(&fs.content(&hosts).unwrap()).unwrap();
+        assert_eq!(
+            docs[0]["ghe.corp.com"]["git_protocol"].as_str(),
+            Some("ssh")
+        );
+        assert_eq!(
+            fs.content(&gh_dir.join("config.yml")).unwrap(),
+            CONFIG_YML.replace("\neditor:\n", "\neditor: vim\n")
+        );
+        assert_eq!(
+            GhCli::on_switch_to(&config, &gh_dir, &fs, &gh, false).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        assert_eq!(fs.writes.borrow().len(), 2);
+        assert!(gh.calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_logged_in_accounts() {
+        assert_eq!(
+            logged_in_accounts(AUTH_STATUS, "github.com"),
+            BTreeSet::from(["alice".to_string()])
+        );
+        assert!(logged_in_accounts(AUTH_STATUS, "ghe.corp.com").is_empty());
+        // gh before 2.40
+        let old = "github.com\n  ✓ Logged in to github.com as alice (oauth_token)\n";
+        assert_eq!(
+            logged_in_accounts(old, "github.com"),
+            BTreeSet::from(["alice".to_string()])
+        );
+        assert!(
+            logged_in_accounts("You are not logged into any GitHub hosts.", "github.com")
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_missing_hosts_file_is_created_for_a_user_with_a_token() {
+        let path = temp_hosts_file("envmgr_test_gh_cli_bootstrap");
+        let gh = FakeGh::new(&path);
+        let config = GhCliConfig {
+            hosts: vec![host_user("github.com", "alice")],
+            ..Default::default()
+        };
+
+        let outcome =
+            GhCli::on_switch_to(&config, path.parent().unwrap(), &RealFs, &gh, false).unwrap();
+
+        assert_eq!(outcome, ApplyOutcome::Changed);
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            GhCli::parse_active_users(&content).unwrap(),
+            BTreeMap::from([("github.com".to_string(), "alice".to_string())])
+        );
+        assert!(GhCli::parse_known_users(&content)["github.com"].contains("alice"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode, HOSTS_FILE_MODE);
+        }
+        assert_eq!(*gh.calls.borrow(), ["auth status --hostname github.com"]);
+        assert_eq!(
+            GhCli::on_switch_to(&config, path.parent().unwrap(), &RealFs, &gh, false).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        fs::remove_dir_all(path.parent().unwrap().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_missing_user_without_login_names_the_command() {
+        let path = temp_hosts_file("envmgr_test_gh_cli_no_login");
+        let gh = FakeGh::new(&path);
+        let config = GhCliConfig {
+            hosts: vec![host_user("ghe.corp.com", "bob")],
+            ..Default::default()
+        };
+
+        let result = GhCli::on_switch_to(&config, path.parent().unwrap(), &RealFs, &gh, false);
+
+        let Err(EnvMgrError::GhCliConfig(message)) = result else {
+            panic!("expected an error for a user without a token");
+        };
+        assert!(message.contains("'bob'"), "{message}");
+        assert!(
+            message.contains("`gh auth login --hostname ghe.corp.com`"),
+            "{message}"
+        );
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_missing_user_logs_in_and_retries() {
+        let path = temp_hosts_file("envmgr_test_gh_cli_login");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(
+            &path,
+            "github.com:\n    users:\n        alice:\n    user: alice\n",
+        )
+        .unwrap();
+        let gh = FakeGh::new(&path);
+        let config = GhCliConfig {
+            hosts: vec![
+                host_user("github.com", "alice"),
+                host_user("ghe.corp.com", "bob"),
+            ],
+            ..Default::default()
+        };
+
+        let outcome =
+            GhCli::on_switch_to(&config, path.parent().unwrap(), &RealFs, &gh, true).unwrap();
+
+        assert_eq!(outcome, ApplyOutcome::Changed);
+        assert_eq!(
+            *gh.calls.borrow(),
+            [
+                "auth status --hostname ghe.corp.com",
+                "auth login --hostname ghe.corp.com"
+            ]
+        );
+        let users = GhCli::parse_active_users(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(users.get("github.com"), Some(&"alice".to_string()));
+        assert_eq!(users.get("ghe.corp.com"), Some(&"bob".to_string()));
+
+        // Logging in as someone else doesn't help
+        let config = GhCliConfig {
+            hosts: vec![host_user("ghe.corp.com", "carol")],
+            ..Default::default()
+        };
+        let result = GhCli::on_switch_to(&config, path.parent().unwrap(), &RealFs, &gh, true);
+        let Err(EnvMgrError::GhCliConfig(message)) = result else {
+            panic!("expected an error when login didn't add the user");
+        };
+        assert!(message.contains("'carol'"), "{message}");
+        fs::remove_dir_all(path.parent().unwrap().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_integration_switches_and_checks_like_gh_cli() {
+        let content =
+            "github.com:\n    users:\n        alice:\n        alice-work:\n    user: alice\n";
+        let path = GhCli::gh_cli_hosts_file_path().unwrap();
+        let gh = FakeGh::new(&path);
+        let config = GhCliConfig {
+            hosts: vec![host_user("github.com", "alice-work")],
+            ..Default::default()
+        };
+        let direct = MemFs::default().with_file(&path, content);
+        let fs = MemFs::default().with_file(&path, content);
+        let integration = GhCliIntegration {
+            gh_dir: path.parent().unwrap().to_path_buf(),
+            fs: &fs,
+            runner: &gh,
+            login: false,
+        };
+
+        let mut out = String::new();
+        assert_eq!(integration.doctor_check(&config, &mut out), 0);
+        assert_eq!(
+            out,
+            "gh_cli: 0 of 1 host(s) have their user active\n  \
+             warning: alice-work is not the active user of github.com, run `envmgr integrations run gh_cli`\n"
+        );
+
+        let expected =
+            GhCli::on_switch_to(&config, path.parent().unwrap(), &direct, &gh, false).unwrap();
+        assert_eq!(
+            integration
+                .on_switch_to(&Environment::default(), &config)
+                .unwrap(),
+            expected.into()
+        );
+        assert_eq!(fs.content(&path), direct.content(&path));
+
+        let mut out = String::new();
+        integration.doctor_check(&config, &mut out);
+        assert_eq!(out, "gh_cli: 1 of 1 host(s) have their user active\n");
+    }
+
+    #[test]
+    fn test_switch_from_makes_the_recorded_users_active() {
+        let content = indoc::indoc! {"
+            github.com:
+                users:
+                    alice:
+                    alice-work:
+                user: alice
+            ghe.corp.com:
+                users:
+                    bob:
+                user: bob
+        "};
+        let path = GhCli::gh_cli_hosts_file_path().unwrap();
+        let gh = FakeGh::new(&path);
+        let config = GhCliConfig {
+            hosts: vec![host_user("github.com", "alice-work")],
+            ..Default::default()
+        };
+        let fs = MemFs::default().with_file(&path, content);
+        let integration = GhCliIntegration {
+            gh_dir: path.parent().unwrap().to_path_buf(),
+            fs: &fs,
+            runner: &gh,
+            login: false,
+        };
 
-        std::fs::write(Self::gh_cli_hosts_file_path()?, content)?;
+        let snapshot = Integration::snapshot(&integration, &config).unwrap();
+        assert_eq!(snapshot.as_deref(), Some(r#"{"github.com":"alice"}"#));
+        integration
+            .on_switch_to(&Environment::default(), &config)
+            .unwrap();
+        let outcome = integration
+            .on_switch_from(&config, snapshot.as_deref())
+            .unwrap();
 
-        Ok(Default::default())
+        assert_eq!(outcome.apply, ApplyOutcome::Changed);
+        let users = GhCli::parse_active_users(&fs.content(&path).unwrap()).unwrap();
+        assert_eq!(users.get("github.com"), Some(&"alice".to_string()));
+        assert_eq!(users.get("ghe.corp.com"), Some(&"bob".to_string()));
+        let outcome = integration.on_switch_from(&config, None).unwrap();
+        assert_eq!(outcome.apply, ApplyOutcome::AlreadyInDesiredState);
     }
 }
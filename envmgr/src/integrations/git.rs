@@ -0,0 +1,382 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    environment::Environment,
+    error::{EnvMgrError, EnvMgrResult},
+    fs::Fs,
+    integrations::{
+        ApplyOutcome, Integration, IntegrationKind, IntegrationOutcome, write_if_changed,
+    },
+};
+
+/// Where the include file lives, relative to the home directory
+const INCLUDE_FILE: &str = ".config/git/envmgr.inc";
+const HEADER: &str = "# Written by envmgr for the active environment, changes are overwritten\n";
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema, Default,
+)]
+pub struct GitConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_email: Option<String>,
+    /// `user.signingkey`, e.g. a GPG key id or an SSH public key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_key: Option<String>,
+    /// Further git config files included while the environment is active
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub includes: Vec<String>,
+}
+
+impl GitConfig {
+    /// `Name <email>`, or whichever of the two is set
+    pub fn identity(&self) -> Option<String> {
+        match (&self.user_name, &self.user_email) {
+            (Some(name), Some(email)) => Some(format!("{name} <{email}>")),
+            (Some(name), None) => Some(name.clone()),
+            (None, Some(email)) => Some(format!("<{email}>")),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Git identity per environment.
+///
+/// The values go into `~/.config/git/envmgr.inc`, which `~/.gitconfig` includes at
+/// its end, so they win over the identity set there.
+pub struct Git;
+
+impl Git {
+    pub fn include_file_path(home: &Path) -> PathBuf {
+        home.join(INCLUDE_FILE)
+    }
+
+    pub fn gitconfig_path(home: &Path) -> PathBuf {
+        home.join(".gitconfig")
+    }
+
+    /// The include file envmgr writes for `config`
+    pub fn render_include_file(config: &GitConfig) -> String {
+        let mut out = HEADER.to_string();
+        let user = [
+            ("name", &config.user_name),
+            ("email", &config.user_email),
+            ("signingkey", &config.signing_key),
+        ];
+        if user.iter().any(|(_, value)| value.is_some()) {
+            out.push_str("[user]\n");
+            for (key, value) in user {
+                if let Some(value) = value {
+                    out.push_str(&format!("\t{key} = {}\n", quote_value(value)));
+                }
+            }
+        }
+        for path in &config.includes {
+            out.push_str(&format!("[include]\n\tpath = {}\n", quote_value(path)));
+        }
+        out
+    }
+
+    /// `gitconfig` with an `[include]` of the include file appended, `None` when it
+    /// already includes it
+    pub fn with_include(gitconfig: &str, home: &Path) -> Option<String> {
+        let absolute = Self::include_file_path(home);
+        let ours = |path: &str| {
+            path == format!("~/{INCLUDE_FILE}") || Path::new(path) == absolute.as_path()
+        };
+        let mut in_include = false;
+        for line in gitconfig.lines().map(str::trim) {
+            if let Some(section) = line.strip_prefix('[') {
+                let name = section.split(']').next().unwrap_or_default();
+                in_include = name.trim().eq_ignore_ascii_case("include");
+                continue;
+            }
+            if !in_include {
+                continue;
+            }
+            if let Some((key, value)) = line.split_once('=')
+                && key.trim().eq_ignore_ascii_case("path")
+                && ours(value.trim().trim_matches('"'))
+            {
+                return None;
+            }
+        }
+        let mut updated = gitconfig.to_string();
+        if !updated.is_empty() && !updated.ends_with('\n') {
+            updated.push('\n');
+        }
+        updated.push_str(&format!("[include]\n\tpath = ~/{INCLUDE_FILE}\n"));
+        Some(updated)
+    }
+
+    /// Make `~/.gitconfig` include the include file, once. A linked `~/.gitconfig`,
+    /// e.g. from a dotfiles repo, is edited where it points instead of being replaced.
+    fn ensure_included(home: &Path, fs: &dyn Fs) -> EnvMgrResult<ApplyOutcome> {
+        let link = Self::gitconfig_path(home);
+        let path = std::fs::canonicalize(&link).unwrap_or(link);
+        let current = match fs.read(&path)? {
+            Some(bytes) => String::from_utf8(bytes).map_err(|_| {
+                EnvMgrError::GitIdentity(format!("{} is not valid UTF-8", path.display()))
+            })?,
+            None => String::new(),
+        };
+        match Self::with_include(&current, home) {
+            Some(updated) => {
+                fs.write_atomic(&path, updated.as_bytes())?;
+                Ok(ApplyOutcome::Changed)
+            }
+            None => Ok(ApplyOutcome::AlreadyInDesiredState),
+        }
+    }
+
+    /// Write the include file for `config` and make sure `~/.gitconfig` includes it
+    pub fn on_switch_to(
+        config: &GitConfig,
+        home: &Path,
+        fs: &dyn Fs,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        let written = write_if_changed(
+            fs,
+            &Self::include_file_path(home),
+            &Self::render_include_file(config),
+        )?;
+        let included = Self::ensure_included(home, fs)?;
+        Ok(match (written, included) {
+            (ApplyOutcome::AlreadyInDesiredState, ApplyOutcome::AlreadyInDesiredState) => {
+                ApplyOutcome::AlreadyInDesiredState
+            }
+            _ => ApplyOutcome::Changed,
+        })
+    }
+
+    /// Blank the include file for an environment without git config. Nothing happens
+    /// when there is none, i.e. the integration was never used.
+    pub fn on_switch_away(home: &Path, fs: &dyn Fs) -> EnvMgrResult<ApplyOutcome> {
+        let path = Self::include_file_path(home);
+        if fs.read(&path)?.is_none() {
+            return Ok(ApplyOutcome::AlreadyInDesiredState);
+        }
+        write_if_changed(fs, &path, HEADER)
+    }
+}
+
+/// [`Git`] behind [`Integration`]
+pub struct GitIntegration<'a> {
+    pub home: PathBuf,
+    pub fs: &'a dyn Fs,
+}
+
+impl Integration for GitIntegration<'_> {
+    type Config = GitConfig;
+
+    fn name(&self) -> IntegrationKind {
+        IntegrationKind::Git
+    }
+
+    fn config<'e>(&self, env: &'e Environment) -> Option<&'e Self::Config> {
+        env.git.as_ref()
+    }
+
+    fn on_switch_to(
+        &self,
+        _env: &Environment,
+        config: &Self::Config,
+    ) -> EnvMgrResult<IntegrationOutcome> {
+        Git::on_switch_to(config, &self.home, self.fs).map(Into::into)
+    }
+
+    fn describe_switch_to(&self, _env: &Environment, config: &Self::Config) -> Vec<String> {
+        let include = Git::include_file_path(&self.home);
+        let rendered = Git::render_include_file(config);
+        let identity = config.identity().unwrap_or_else(|| "no identity".into());
+        let mut actions = vec![match self.fs.read(&include) {
+            Ok(Some(current)) if current == rendered.as_bytes() => {
+                format!("{} ({identity})", ApplyOutcome::AlreadyInDesiredState)
+            }
+            Ok(_) => format!("write {} ({identity})", include.display()),
+            Err(e) => format!(
+                "write {} ({identity}, current file unreadable: {e})",
+                include.display()
+            ),
+        }];
+        let gitconfig = Git::gitconfig_path(&self.home);
+        let included = std::fs::read_to_string(&gitconfig)
+            .is_ok_and(|content| Git::with_include(&content, &self.home).is_none());
+        if !included {
+            actions.push(format!("add an [include] of it to {}", gitconfig.display()));
+        }
+        actions
+    }
+
+    /// The identity must not leak into the next environment
+    fn clear(&self) -> EnvMgrResult<ApplyOutcome> {
+        Git::on_switch_away(&self.home, self.fs)
+    }
+
+    fn describe_clear(&self) -> Vec<String> {
+        let include = Git::include_file_path(&self.home);
+        match self.fs.read(&include) {
+            Ok(Some(current))
+                if current != Git::render_include_file(&Default::default()).as_bytes() =>
+            {
+                vec![format!("blank {}", include.display())]
+            }
+            _ => vec![],
+        }
+    }
+}
+
+/// Quote a git config value when git would otherwise read it differently
+fn quote_value(value: &str) -> String {
+    let needs_quotes =
+        value.is_empty() || value.trim() != value || value.contains(['"', '\\', '#', ';']);
+    if !needs_quotes {
+        return value.to_string();
+    }
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::fs::RealFs;
+
+    fn temp_home(name: &str) -> PathBuf {
+        let home = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&home);
+        fs::create_dir_all(&home).unwrap();
+        home
+    }
+
+    fn config() -> GitConfig {
+        GitConfig {
+            user_name: Some("Alice Example".to_string()),
+            user_email: Some("alice@client.example".to_string()),
+            signing_key: Some("ABCD1234".to_string()),
+            includes: vec!["~/.config/git/client.inc".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_render_include_file() {
+        assert_eq!(
+            Git::render_include_file(&config()),
+            format!(
+                "{HEADER}[user]\n\tname = Alice Example\n\temail = alice@client.example\n\t\
+                 signingkey = ABCD1234\n[include]\n\tpath = ~/.config/git/client.inc\n"
+            )
+        );
+        let config = GitConfig {
+            user_name: Some("Bob \"the\" Builder".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            Git::render_include_file(&config),
+            format!("{HEADER}[user]\n\tname = \"Bob \\\"the\\\" Builder\"\n")
+        );
+        assert_eq!(Git::render_include_file(&GitConfig::default()), HEADER);
+    }
+
+    #[test]
+    fn test_include_is_inserted_once() {
+        let home = temp_home("envmgr_test_git_include");
+        let gitconfig = Git::gitconfig_path(&home);
+        fs::write(&gitconfig, "[user]\n\tname = Alice\n[core]\n\teditor = hx").unwrap();
+
+        let outcome = Git::on_switch_to(&config(), &home, &RealFs).unwrap();
+
+        assert_eq!(outcome, ApplyOutcome::Changed);
+        assert_eq!(
+            fs::read_to_string(&gitconfig).unwrap(),
+            "[user]\n\tname = Alice\n[core]\n\teditor = hx\n[include]\n\tpath = ~/.config/git/envmgr.inc\n"
+        );
+        assert_eq!(
+            fs::read_to_string(Git::include_file_path(&home)).unwrap(),
+            Git::render_include_file(&config())
+        );
+        assert_eq!(
+            Git::on_switch_to(&config(), &home, &RealFs).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        assert_eq!(
+            fs::read_to_string(&gitconfig)
+                .unwrap()
+                .matches("[include]")
+                .count(),
+            1
+        );
+
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn test_missing_gitconfig_is_created() {
+        let home = temp_home("envmgr_test_git_no_gitconfig");
+
+        Git::on_switch_to(&config(), &home, &RealFs).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(Git::gitconfig_path(&home)).unwrap(),
+            "[include]\n\tpath = ~/.config/git/envmgr.inc\n"
+        );
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_linked_gitconfig_stays_linked() {
+        let home = temp_home("envmgr_test_git_linked_gitconfig");
+        let dotfiles = home.join("dotfiles").join("gitconfig");
+        fs::create_dir_all(dotfiles.parent().unwrap()).unwrap();
+        fs::write(&dotfiles, "[user]\n\tname = Alice\n").unwrap();
+        std::os::unix::fs::symlink(&dotfiles, Git::gitconfig_path(&home)).unwrap();
+
+        Git::on_switch_to(&config(), &home, &RealFs).unwrap();
+
+        assert!(Git::gitconfig_path(&home).is_symlink());
+        assert!(fs::read_to_string(&dotfiles).unwrap().contains("[include]"));
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn test_existing_include_is_recognized() {
+        let home = Path::new("/home/alice");
+        for gitconfig in [
+            "[include]\n\tpath = ~/.config/git/envmgr.inc\n",
+            "[Include]\n  path=\"/home/alice/.config/git/envmgr.inc\"\n",
+            "[include]\n\tpath = other.inc\n\tpath = ~/.config/git/envmgr.inc\n[user]\n",
+        ] {
+            assert_eq!(Git::with_include(gitconfig, home), None, "{gitconfig}");
+        }
+        // Same path under another section doesn't count
+        assert!(Git::with_include("[core]\n\tpath = ~/.config/git/envmgr.inc\n", home).is_some());
+    }
+
+    #[test]
+    fn test_switch_away_blanks_include_file() {
+        let home = temp_home("envmgr_test_git_switch_away");
+        assert_eq!(
+            Git::on_switch_away(&home, &RealFs).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        assert!(!Git::include_file_path(&home).exists());
+
+        Git::on_switch_to(&config(), &home, &RealFs).unwrap();
+        assert_eq!(
+            Git::on_switch_away(&home, &RealFs).unwrap(),
+            ApplyOutcome::Changed
+        );
+        assert_eq!(
+            fs::read_to_string(Git::include_file_path(&home)).unwrap(),
+            HEADER
+        );
+        assert_eq!(
+            Git::on_switch_away(&home, &RealFs).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        fs::remove_dir_all(&home).unwrap();
+    }
+}
@@ -0,0 +1,354 @@
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use crate::{
+    environment::Environment,
+    error::{EnvMgrError, EnvMgrResult},
+    fs::Fs,
+    integrations::{
+        ApplyOutcome, Integration, IntegrationKind, IntegrationOutcome, git::GitConfig,
+    },
+    runner::CommandRunner,
+};
+
+/// gpg warns about a home directory or config others can read
+const GPG_CONF_MODE: u32 = 0o600;
+const GNUPG_DIR_MODE: u32 = 0o700;
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema, Default,
+)]
+pub struct GpgConfig {
+    /// Key id or fingerprint set as `default-key` in `gpg.conf`
+    pub default_key: String,
+    /// Also use the key as git's `user.signingkey`, unless `git.signing_key` sets one
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub also_set_git_signing_key: bool,
+}
+
+impl std::fmt::Display for GpgConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "default key {}", self.default_key)?;
+        if self.also_set_git_signing_key {
+            f.write_str(", git signing key")?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `line` of a `gpg.conf` sets `default-key`
+fn is_default_key_line(line: &str) -> bool {
+    line.trim_start()
+        .strip_prefix("default-key")
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+}
+
+/// `content` with `default-key` set to `key`, `None` when it already is. The first
+/// `default-key` line is replaced and further ones are dropped; without one, it is
+/// appended.
+pub fn with_default_key(content: &str, key: &str) -> Option<String> {
+    let wanted = format!("default-key {key}");
+    let mut out = String::new();
+    let mut replaced = false;
+    for line in content.split_inclusive('\n') {
+        if !is_default_key_line(line) {
+            out.push_str(line);
+        } else if !replaced {
+            out.push_str(&wanted);
+            out.push('\n');
+            replaced = true;
+        }
+    }
+    if !replaced {
+        if !out.is_empty() && !out.ends_with('\n') {
+            out.push('\n');
+        }
+        out.push_str(&wanted);
+        out.push('\n');
+    }
+    (out != content).then_some(out)
+}
+
+/// Default signing key per environment, written to `gpg.conf`
+pub struct Gpg;
+
+impl Gpg {
+    /// `$GNUPGHOME`, or `~/.gnupg`
+    pub fn gnupg_dir(home: &Path) -> PathBuf {
+        std::env::var_os("GNUPGHOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| home.join(".gnupg"))
+    }
+
+    pub fn gpg_conf_path(gnupg_dir: &Path) -> PathBuf {
+        gnupg_dir.join("gpg.conf")
+    }
+
+    /// `git` with `gpg`'s key as the signing key, when `gpg` asks for that and `git`
+    /// doesn't set one itself
+    pub fn with_git_signing_key(
+        git: Option<GitConfig>,
+        gpg: Option<&GpgConfig>,
+    ) -> Option<GitConfig> {
+        let Some(gpg) = gpg.filter(|gpg| gpg.also_set_git_signing_key) else {
+            return git;
+        };
+        let mut git = git.unwrap_or_default();
+        git.signing_key
+            .get_or_insert_with(|| gpg.default_key.clone());
+        Some(git)
+    }
+
+    /// Fail unless the secret key `key` is in the keyring
+    pub fn check_key(key: &str, runner: &dyn CommandRunner) -> EnvMgrResult<()> {
+        debug!("Looking up secret key {key}");
+        let output = match runner.run("gpg", &["--list-secret-keys", "--with-colons", key]) {
+            Ok(output) => output,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(EnvMgrError::Gpg(
+                    "gpg is not installed, but the gpg integration needs it".into(),
+                ));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let found = output.success && output.stdout.lines().any(|line| line.starts_with("sec:"));
+        if !found {
+            return Err(EnvMgrError::Gpg(format!(
+                "secret key {key} is not in the keyring, see `gpg --list-secret-keys`"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check the key is in the keyring, then make it `default-key` in `gpg.conf`
+    pub fn on_switch_to(
+        config: &GpgConfig,
+        gnupg_dir: &Path,
+        fs: &dyn Fs,
+        runner: &dyn CommandRunner,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        Self::check_key(&config.default_key, runner)?;
+        let link = Self::gpg_conf_path(gnupg_dir);
+        let path = std::fs::canonicalize(&link).unwrap_or(link);
+        let current = match fs.read(&path)? {
+            Some(bytes) => String::from_utf8(bytes)
+                .map_err(|_| EnvMgrError::Gpg(format!("{} is not valid UTF-8", path.display())))?,
+            None => String::new(),
+        };
+        let Some(updated) = with_default_key(&current, &config.default_key) else {
+            return Ok(ApplyOutcome::AlreadyInDesiredState);
+        };
+        if !gnupg_dir.exists() {
+            fs.create_dir_all(gnupg_dir)?;
+            fs.set_mode(gnupg_dir, GNUPG_DIR_MODE)?;
+        }
+        debug!(
+            "Setting default-key {} in {}",
+            config.default_key,
+            path.display()
+        );
+        fs.write_atomic_with_mode(&path, updated.as_bytes(), GPG_CONF_MODE)?;
+        Ok(ApplyOutcome::Changed)
+    }
+}
+
+/// [`Gpg`] behind [`Integration`]
+pub struct GpgIntegration<'a> {
+    pub home: PathBuf,
+    pub fs: &'a dyn Fs,
+    pub runner: &'a dyn CommandRunner,
+}
+
+impl Integration for GpgIntegration<'_> {
+    type Config = GpgConfig;
+
+    fn name(&self) -> IntegrationKind {
+        IntegrationKind::Gpg
+    }
+
+    fn config<'e>(&self, env: &'e Environment) -> Option<&'e Self::Config> {
+        env.gpg.as_ref()
+    }
+
+    fn on_switch_to(
+        &self,
+        _env: &Environment,
+        config: &Self::Config,
+    ) -> EnvMgrResult<IntegrationOutcome> {
+        Gpg::on_switch_to(config, &Gpg::gnupg_dir(&self.home), self.fs, self.runner).map(Into::into)
+    }
+
+    fn describe_switch_to(&self, _env: &Environment, config: &Self::Config) -> Vec<String> {
+        if let Err(e) = Gpg::check_key(&config.default_key, self.runner) {
+            return vec![format!("set default-key {} ({e})", config.default_key)];
+        }
+        let gpg_conf = Gpg::gpg_conf_path(&Gpg::gnupg_dir(&self.home));
+        let current = std::fs::read_to_string(&gpg_conf).unwrap_or_default();
+        vec![match with_default_key(&current, &config.default_key) {
+            None => format!("{} ({config})", ApplyOutcome::AlreadyInDesiredState),
+            Some(_) => format!(
+                "set default-key {} in {}",
+                config.default_key,
+                gpg_conf.display()
+            ),
+        }]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, fs, io};
+
+    use super::*;
+    use crate::{fs::RealFs, runner::CommandOutput};
+
+    const KEY: &str = "0123456789ABCDEF";
+
+    /// A keyring holding only the secret key [`KEY`]
+    #[derive(Default)]
+    struct FakeGpg {
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl CommandRunner for FakeGpg {
+        fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+            assert_eq!(program, "gpg");
+            self.calls.borrow_mut().push(args.join(" "));
+            Ok(match args.last() {
+                Some(&KEY) => CommandOutput {
+                    success: true,
+                    stdout: format!(
+                        "sec:u:255:22:{KEY}:1700000000:::u:::scESC:::+:::ed25519:::0:\n"
+                    ),
+                    ..Default::default()
+                },
+                _ => CommandOutput {
+                    stderr: "gpg: error reading key: No secret key".to_string(),
+                    ..Default::default()
+                },
+            })
+        }
+    }
+
+    fn temp_gnupg(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        dir.join(".gnupg")
+    }
+
+    fn config(key: &str) -> GpgConfig {
+        GpgConfig {
+            default_key: key.to_string(),
+            also_set_git_signing_key: false,
+        }
+    }
+
+    #[test]
+    fn test_with_default_key() {
+        assert_eq!(
+            with_default_key("", KEY).unwrap(),
+            format!("default-key {KEY}\n")
+        );
+        assert_eq!(
+            with_default_key(
+                "keyid-format long\ndefault-key OLD\nuse-agent\ndefault-key OLDER",
+                KEY
+            )
+            .unwrap(),
+            format!("keyid-format long\ndefault-key {KEY}\nuse-agent\n")
+        );
+        assert_eq!(
+            with_default_key("keyid-format long", KEY).unwrap(),
+            format!("keyid-format long\ndefault-key {KEY}\n")
+        );
+        // Not to be confused with `default-key` itself
+        assert_eq!(
+            with_default_key("default-keyserver-url hkps://keys.example\n", KEY).unwrap(),
+            format!("default-keyserver-url hkps://keys.example\ndefault-key {KEY}\n")
+        );
+        assert_eq!(
+            with_default_key(&format!("use-agent\ndefault-key {KEY}\n"), KEY),
+            None
+        );
+    }
+
+    #[test]
+    fn test_switch_is_idempotent() {
+        let gnupg = temp_gnupg("envmgr_test_gpg_switch");
+        let gpg = FakeGpg::default();
+
+        let outcome = Gpg::on_switch_to(&config(KEY), &gnupg, &RealFs, &gpg).unwrap();
+
+        assert_eq!(outcome, ApplyOutcome::Changed);
+        let conf = Gpg::gpg_conf_path(&gnupg);
+        assert_eq!(
+            fs::read_to_string(&conf).unwrap(),
+            format!("default-key {KEY}\n")
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&conf), GPG_CONF_MODE);
+            assert_eq!(mode(&gnupg), GNUPG_DIR_MODE);
+        }
+
+        fs::write(&conf, "use-agent\ndefault-key OLD\n").unwrap();
+        Gpg::on_switch_to(&config(KEY), &gnupg, &RealFs, &gpg).unwrap();
+        assert_eq!(
+            fs::read_to_string(&conf).unwrap(),
+            format!("use-agent\ndefault-key {KEY}\n")
+        );
+        assert_eq!(
+            Gpg::on_switch_to(&config(KEY), &gnupg, &RealFs, &gpg).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        assert_eq!(
+            gpg.calls.borrow()[0],
+            format!("--list-secret-keys --with-colons {KEY}")
+        );
+        fs::remove_dir_all(gnupg.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_missing_key_fails_without_writing() {
+        let gnupg = temp_gnupg("envmgr_test_gpg_missing_key");
+
+        let result = Gpg::on_switch_to(
+            &config("FEDCBA9876543210"),
+            &gnupg,
+            &RealFs,
+            &FakeGpg::default(),
+        );
+
+        let Err(EnvMgrError::Gpg(message)) = result else {
+            panic!("expected an error for a key that isn't in the keyring");
+        };
+        assert!(message.contains("FEDCBA9876543210"), "{message}");
+        assert!(!gnupg.exists());
+    }
+
+    #[test]
+    fn test_git_signing_key() {
+        let gpg = GpgConfig {
+            also_set_git_signing_key: true,
+            ..config(KEY)
+        };
+        let from_gpg = Gpg::with_git_signing_key(None, Some(&gpg)).unwrap();
+        assert_eq!(from_gpg.signing_key.as_deref(), Some(KEY));
+
+        let git = GitConfig {
+            signing_key: Some("OTHER".to_string()),
+            ..Default::default()
+        };
+        let kept = Gpg::with_git_signing_key(Some(git.clone()), Some(&gpg)).unwrap();
+        assert_eq!(kept.signing_key.as_deref(), Some("OTHER"));
+
+        assert_eq!(Gpg::with_git_signing_key(None, Some(&config(KEY))), None);
+        assert_eq!(
+            Gpg::with_git_signing_key(Some(git.clone()), None),
+            Some(git)
+        );
+    }
+}
@@ -0,0 +1,449 @@
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use crate::{
+    environment::Environment,
+    error::{EnvMgrError, EnvMgrResult},
+    integrations::{
+        ApplyOutcome, Integration, IntegrationKind, IntegrationOutcome, OnUsePluginResult,
+    },
+    runner::CommandRunner,
+};
+
+/// Env var kubectl reads the kubeconfig path from
+pub const KUBECONFIG_VAR: &str = "KUBECONFIG";
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema, Default,
+)]
+pub struct KubeConfig {
+    /// Kubeconfig exported as `KUBECONFIG` while the environment is active, e.g.
+    /// `~/.kube/config-client`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kubeconfig: Option<String>,
+    /// Context made current in the kubeconfig on switch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+    /// Namespace set on the context on switch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+}
+
+impl KubeConfig {
+    /// The kubeconfig with a leading `~/` expanded to `home`, `~/.kube/config` when
+    /// none is configured
+    pub fn kubeconfig_path(&self, home: &Path) -> PathBuf {
+        match &self.kubeconfig {
+            Some(path) => match path.strip_prefix("~/") {
+                Some(rest) => home.join(rest),
+                None => PathBuf::from(path),
+            },
+            None => home.join(".kube").join("config"),
+        }
+    }
+}
+
+impl std::fmt::Display for KubeConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts: Vec<String> = [
+            self.context.as_ref().map(|c| format!("context {c}")),
+            self.namespace.as_ref().map(|n| format!("namespace {n}")),
+            self.kubeconfig.as_ref().map(|k| format!("in {k}")),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        f.write_str(&parts.join(", "))
+    }
+}
+
+/// The parts of a kubeconfig envmgr looks at
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct Kubeconfig {
+    #[serde(rename = "current-context", default)]
+    pub current_context: Option<String>,
+    // kubectl writes `contexts: null` when there are none
+    #[serde(default)]
+    contexts: Option<Vec<NamedContext>>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct NamedContext {
+    name: String,
+    #[serde(default)]
+    context: Option<ContextDetails>,
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+struct ContextDetails {
+    #[serde(default)]
+    namespace: Option<String>,
+}
+
+impl Kubeconfig {
+    pub fn parse(content: &str) -> Result<Self, serde_norway::Error> {
+        if content.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        serde_norway::from_str(content)
+    }
+
+    /// Read and parse the kubeconfig at `path`
+    pub fn load(path: &Path) -> EnvMgrResult<Self> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(EnvMgrError::Kube(format!(
+                    "kubeconfig {} does not exist",
+                    path.display()
+                )));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Self::parse(&content).map_err(|e| {
+            EnvMgrError::Kube(format!("kubeconfig {} is not valid: {e}", path.display()))
+        })
+    }
+
+    /// Names of the contexts, in file order
+    pub fn context_names(&self) -> Vec<&str> {
+        self.contexts
+            .iter()
+            .flatten()
+            .map(|context| context.name.as_str())
+            .collect()
+    }
+
+    pub fn current_context(&self) -> Option<&str> {
+        self.current_context.as_deref().filter(|c| !c.is_empty())
+    }
+
+    /// Namespace of the context `name`, `None` when it sets none
+    pub fn namespace_of(&self, name: &str) -> Option<&str> {
+        self.contexts
+            .iter()
+            .flatten()
+            .find(|context| context.name == name)
+            .and_then(|context| context.context.as_ref()?.namespace.as_deref())
+    }
+}
+
+pub struct Kube;
+
+impl Kube {
+    /// `KUBECONFIG` for the configured kubeconfig, to be overridden by explicit `env_vars`
+    pub fn on_use(config: &KubeConfig, home: Option<&Path>) -> OnUsePluginResult {
+        let env_vars = config
+            .kubeconfig
+            .as_ref()
+            .map(|path| {
+                let value = match home {
+                    Some(home) => config.kubeconfig_path(home).to_string_lossy().into_owned(),
+                    None => path.clone(),
+                };
+                (KUBECONFIG_VAR.to_string(), value)
+            })
+            .into_iter()
+            .collect();
+        OnUsePluginResult { env_vars }
+    }
+
+    /// The `kubectl` arguments that bring `kubeconfig` to the configured context and
+    /// namespace, empty when it already is there
+    pub fn plan_commands(
+        config: &KubeConfig,
+        kubeconfig: &Kubeconfig,
+        path: &Path,
+    ) -> EnvMgrResult<Vec<Vec<String>>> {
+        let mut commands = vec![];
+        let target = match &config.context {
+            Some(context) => {
+                if !kubeconfig.context_names().contains(&context.as_str()) {
+                    return Err(EnvMgrError::Kube(format!(
+                        "context '{context}' is not in {}, it has: {}",
+                        path.display(),
+                        kubeconfig.context_names().join(", ")
+                    )));
+                }
+                if kubeconfig.current_context() != Some(context) {
+                    commands.push(vec!["use-context".to_string(), context.clone()]);
+                }
+                Some(context.as_str())
+            }
+            None => kubeconfig.current_context(),
+        };
+        if let Some(namespace) = &config.namespace {
+            let current = target.and_then(|context| kubeconfig.namespace_of(context));
+            if current != Some(namespace) {
+                let context = match &config.context {
+                    Some(context) => context.clone(),
+                    None => "--current".to_string(),
+                };
+                commands.push(vec![
+                    "set-context".to_string(),
+                    context,
+                    "--namespace".to_string(),
+                    namespace.clone(),
+                ]);
+            }
+        }
+        Ok(commands)
+    }
+
+    /// Make the configured context and namespace current. Only the kubeconfig's
+    /// current context and the context's namespace change, nothing else in it.
+    pub fn on_switch_to(
+        config: &KubeConfig,
+        home: &Path,
+        runner: &dyn CommandRunner,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        if config.context.is_none() && config.namespace.is_none() {
+            // KUBECONFIG is exported by `envmgr use`
+            return Ok(ApplyOutcome::AlreadyInDesiredState);
+        }
+        let path = config.kubeconfig_path(home);
+        let kubeconfig = Kubeconfig::load(&path)?;
+        let commands = Self::plan_commands(config, &kubeconfig, &path)?;
+        if commands.is_empty() {
+            return Ok(ApplyOutcome::AlreadyInDesiredState);
+        }
+        let path_arg = path.to_string_lossy();
+        for command in commands {
+            let mut args = vec!["config"];
+            args.extend(command.iter().map(String::as_str));
+            args.extend(["--kubeconfig", &path_arg]);
+            debug!("Running kubectl {}", args.join(" "));
+            match runner.run("kubectl", &args) {
+                Ok(output) if output.success => {}
+                Ok(output) => {
+                    return Err(EnvMgrError::Kube(format!(
+                        "kubectl config {} failed: {}",
+                        command.join(" "),
+                        output.stderr.trim()
+                    )));
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return Err(EnvMgrError::Kube(
+                        "kubectl is not installed, but the kube context and namespace need it"
+                            .into(),
+                    ));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(ApplyOutcome::Changed)
+    }
+}
+
+/// [`Kube`] behind [`Integration`]
+pub struct KubeIntegration<'a> {
+    pub home: PathBuf,
+    pub runner: &'a dyn CommandRunner,
+}
+
+impl Integration for KubeIntegration<'_> {
+    type Config = KubeConfig;
+
+    fn name(&self) -> IntegrationKind {
+        IntegrationKind::Kube
+    }
+
+    fn config<'e>(&self, env: &'e Environment) -> Option<&'e Self::Config> {
+        env.kube.as_ref()
+    }
+
+    fn on_switch_to(
+        &self,
+        _env: &Environment,
+        config: &Self::Config,
+    ) -> EnvMgrResult<IntegrationOutcome> {
+        Kube::on_switch_to(config, &self.home, self.runner).map(Into::into)
+    }
+
+    fn describe_switch_to(&self, _env: &Environment, config: &Self::Config) -> Vec<String> {
+        let mut actions = vec![];
+        if let Some(kubeconfig) = &config.kubeconfig {
+            actions.push(format!("export KUBECONFIG={kubeconfig} on `envmgr use`"));
+        }
+        if config.context.is_none() && config.namespace.is_none() {
+            return actions;
+        }
+        let path = config.kubeconfig_path(&self.home);
+        match Kubeconfig::load(&path)
+            .and_then(|kubeconfig| Kube::plan_commands(config, &kubeconfig, &path))
+        {
+            Ok(commands) if commands.is_empty() => actions.push(format!(
+                "{} ({config})",
+                ApplyOutcome::AlreadyInDesiredState
+            )),
+            Ok(commands) => actions.extend(
+                commands
+                    .iter()
+                    .map(|command| format!("kubectl config {}", command.join(" "))),
+            ),
+            Err(e) => actions.push(format!("switch to {config} ({e})")),
+        }
+        actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io};
+
+    use super::*;
+    use crate::runner::CommandOutput;
+
+    const KUBECONFIG: &str = indoc::indoc! {"
+        apiVersion: v1
+        kind: Config
+        current-context: staging
+        contexts:
+          - name: staging
+            context:
+              cluster: staging
+              user: me
+          - name: prod-cluster
+            context:
+              cluster: prod
+              user: me
+              namespace: team-a
+        clusters: []
+        users: []
+    "};
+
+    #[derive(Default)]
+    struct FakeKubectl {
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl CommandRunner for FakeKubectl {
+        fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+            assert_eq!(program, "kubectl");
+            self.calls.borrow_mut().push(args.join(" "));
+            Ok(CommandOutput {
+                success: true,
+                ..Default::default()
+            })
+        }
+    }
+
+    fn config(context: Option<&str>, namespace: Option<&str>) -> KubeConfig {
+        KubeConfig {
+            kubeconfig: Some("~/.kube/config-client".to_string()),
+            context: context.map(str::to_string),
+            namespace: namespace.map(str::to_string),
+        }
+    }
+
+    fn plan(config: &KubeConfig) -> EnvMgrResult<Vec<String>> {
+        let kubeconfig = Kubeconfig::parse(KUBECONFIG).unwrap();
+        Ok(Kube::plan_commands(config, &kubeconfig, Path::new("/k"))?
+            .into_iter()
+            .map(|command| command.join(" "))
+            .collect())
+    }
+
+    #[test]
+    fn test_parse_kubeconfig() {
+        let kubeconfig = Kubeconfig::parse(KUBECONFIG).unwrap();
+        assert_eq!(kubeconfig.current_context(), Some("staging"));
+        assert_eq!(kubeconfig.context_names(), ["staging", "prod-cluster"]);
+        assert_eq!(kubeconfig.namespace_of("prod-cluster"), Some("team-a"));
+        assert_eq!(kubeconfig.namespace_of("staging"), None);
+
+        let empty = Kubeconfig::parse("current-context: \"\"\ncontexts: null\n").unwrap();
+        assert_eq!(empty.current_context(), None);
+        assert!(empty.context_names().is_empty());
+        assert!(Kubeconfig::parse("").unwrap().context_names().is_empty());
+    }
+
+    #[test]
+    fn test_plan_commands() {
+        assert_eq!(
+            plan(&config(Some("prod-cluster"), None)).unwrap(),
+            ["use-context prod-cluster"]
+        );
+        // The namespace is already set on prod-cluster
+        assert_eq!(
+            plan(&config(Some("prod-cluster"), Some("team-a"))).unwrap(),
+            ["use-context prod-cluster"]
+        );
+        assert_eq!(
+            plan(&config(Some("prod-cluster"), Some("team-b"))).unwrap(),
+            [
+                "use-context prod-cluster",
+                "set-context prod-cluster --namespace team-b"
+            ]
+        );
+        assert_eq!(
+            plan(&config(None, Some("team-b"))).unwrap(),
+            ["set-context --current --namespace team-b"]
+        );
+        assert!(plan(&config(Some("staging"), None)).unwrap().is_empty());
+
+        let Err(EnvMgrError::Kube(message)) = plan(&config(Some("dev"), None)) else {
+            panic!("expected an error for an unknown context");
+        };
+        assert!(
+            message.contains("it has: staging, prod-cluster"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn test_switch_runs_kubectl_on_the_configured_kubeconfig() {
+        let home = std::env::temp_dir().join("envmgr_test_kube_switch");
+        let _ = std::fs::remove_dir_all(&home);
+        std::fs::create_dir_all(home.join(".kube")).unwrap();
+        std::fs::write(home.join(".kube/config-client"), KUBECONFIG).unwrap();
+        let kubectl = FakeKubectl::default();
+
+        let outcome =
+            Kube::on_switch_to(&config(Some("prod-cluster"), None), &home, &kubectl).unwrap();
+
+        assert_eq!(outcome, ApplyOutcome::Changed);
+        assert_eq!(
+            *kubectl.calls.borrow(),
+            [format!(
+                "config use-context prod-cluster --kubeconfig {}",
+                home.join(".kube/config-client").display()
+            )]
+        );
+        assert_eq!(
+            Kube::on_switch_to(&config(None, None), &home, &kubectl).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        assert!(matches!(
+            Kube::on_switch_to(
+                &KubeConfig {
+                    kubeconfig: Some("~/.kube/missing".to_string()),
+                    ..config(Some("prod-cluster"), None)
+                },
+                &home,
+                &kubectl
+            ),
+            Err(EnvMgrError::Kube(_))
+        ));
+        assert_eq!(kubectl.calls.borrow().len(), 1);
+        std::fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn test_on_use_exports_kubeconfig() {
+        let home = Path::new("/home/me");
+        assert_eq!(
+            Kube::on_use(&config(None, None), Some(home)).env_vars,
+            [(
+                "KUBECONFIG".to_string(),
+                "/home/me/.kube/config-client".to_string()
+            )]
+        );
+        let context_only = KubeConfig {
+            kubeconfig: None,
+            ..config(Some("prod-cluster"), None)
+        };
+        assert!(Kube::on_use(&context_only, Some(home)).env_vars.is_empty());
+    }
+}
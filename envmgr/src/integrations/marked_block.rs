@@ -0,0 +1,108 @@
+//! envmgr's block inside a file the user also edits, e.g. `~/.npmrc`.
+//!
+//! The block is the lines from a begin marker line to an end marker line; everything
+//! around it is the user's and is kept as is. Where a new block goes differs per file,
+//! so adding one is left to the callers.
+
+/// The marker lines around one kind of block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Markers {
+    pub begin: &'static str,
+    pub end: &'static str,
+}
+
+impl Markers {
+    /// `content` without the block, and whether there was one
+    pub fn strip(&self, content: &str) -> (String, bool) {
+        let mut found = false;
+        let out = self.rewrite(content, |_| found = true);
+        (out, found)
+    }
+
+    /// `content` with `block` in place of the block, `None` when there is none. Of more
+    /// than one block, the first is replaced and the others are dropped.
+    pub fn replace(&self, content: &str, block: &str) -> Option<String> {
+        let mut replaced = false;
+        let out = self.rewrite(content, |out| {
+            if !replaced {
+                out.push_str(block);
+                replaced = true;
+            }
+        });
+        replaced.then_some(out)
+    }
+
+    /// Copy `content` without its blocks, calling `at_block` where each one began
+    fn rewrite(&self, content: &str, mut at_block: impl FnMut(&mut String)) -> String {
+        let mut out = String::new();
+        let mut in_block = false;
+        for line in content.split_inclusive('\n') {
+            let trimmed = line.trim_end();
+            if trimmed == self.begin {
+                in_block = true;
+                at_block(&mut out);
+            } else if in_block && trimmed == self.end {
+                in_block = false;
+            } else if !in_block {
+                out.push_str(line);
+            }
+        }
+        out
+    }
+}
+
+/// `content` with `block` appended on a line of its own
+pub fn append(content: &str, block: &str) -> String {
+    let mut out = content.to_string();
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+    out.push_str(block);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MARKERS: Markers = Markers {
+        begin: "# >>> envmgr",
+        end: "# <<< envmgr",
+    };
+
+    #[test]
+    fn test_block_is_replaced_stripped_and_appended() {
+        let content = "a=1\r\n# >>> envmgr  \nb=2\n# <<< envmgr\nc=3";
+
+        assert_eq!(
+            MARKERS.replace(content, "# >>> envmgr\nb=9\n# <<< envmgr\n"),
+            Some("a=1\r\n# >>> envmgr\nb=9\n# <<< envmgr\nc=3".to_string())
+        );
+        assert_eq!(MARKERS.strip(content), ("a=1\r\nc=3".to_string(), true));
+        assert_eq!(MARKERS.replace("a=1\n", "x\n"), None);
+        assert_eq!(MARKERS.strip("a=1"), ("a=1".to_string(), false));
+        assert_eq!(append("a=1", "x\n"), "a=1\nx\n");
+        assert_eq!(append("", "x\n"), "x\n");
+    }
+
+    #[test]
+    fn test_duplicate_blocks_collapse_into_one() {
+        let twice = "# >>> envmgr\nold\n# <<< envmgr\nmine\n# >>> envmgr\nold\n# <<< envmgr\n";
+        assert_eq!(
+            MARKERS.replace(twice, "new\n"),
+            Some("new\nmine\n".to_string())
+        );
+        assert_eq!(MARKERS.strip(twice), ("mine\n".to_string(), true));
+    }
+
+    #[test]
+    fn test_unterminated_block_runs_to_the_end() {
+        let content = "mine\n# >>> envmgr\nold\n";
+        assert_eq!(MARKERS.strip(content), ("mine\n".to_string(), true));
+        // An end marker without a begin marker is the user's line
+        assert_eq!(
+            MARKERS.strip("# <<< envmgr\n"),
+            ("# <<< envmgr\n".to_string(), false)
+        );
+    }
+}
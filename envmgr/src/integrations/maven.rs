@@ -0,0 +1,637 @@
+//! Maven and Gradle settings per environment, e.g. a corporate mirror and its credentials.
+//!
+//! `~/.m2/settings.xml` becomes a link to a settings file kept in the environment dir.
+//! A settings.xml of the user's own is moved aside to `settings.xml.envmgr-personal`
+//! meanwhile and put back when switching to an environment without Maven config.
+//! Gradle properties go into a marked block of `~/.gradle/gradle.properties`, leaving
+//! every other line alone.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+
+use crate::{
+    environment::{Environment, OpCli, SECRET_REFERENCE_PREFIX},
+    error::{EnvMgrError, EnvMgrResult},
+    fs::Fs,
+    integrations::{
+        ApplyOutcome, Integration, IntegrationKind, IntegrationOutcome,
+        marked_block::{self, Markers},
+        write_if_changed,
+    },
+    platform,
+    runner::CommandRunner,
+};
+
+const BLOCK_START: &str =
+    "# >>> envmgr maven: written for the active environment, changes are overwritten";
+const BLOCK_END: &str = "# <<< envmgr maven";
+const MARKERS: Markers = Markers {
+    begin: BLOCK_START,
+    end: BLOCK_END,
+};
+/// gradle.properties holds repository credentials, so whatever envmgr writes is private
+const GRADLE_PROPERTIES_MODE: u32 = 0o600;
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema, Default,
+)]
+pub struct MavenConfig {
+    /// settings.xml linked to `~/.m2/settings.xml`, relative to the environment dir, e.g.
+    /// `maven/settings.xml`. Credentials in it can come from env vars as `${env.NAME}`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settings_source: Option<String>,
+    /// Entries of `~/.gradle/gradle.properties`, e.g. `systemProp.https.proxyHost`; an
+    /// `op://` value is read with the 1Password CLI on switch
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub gradle_properties: BTreeMap<String, String>,
+}
+
+impl std::fmt::Display for MavenConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = vec![];
+        if let Some(source) = &self.settings_source {
+            parts.push(format!("settings {source}"));
+        }
+        if !self.gradle_properties.is_empty() {
+            parts.push(format!(
+                "{} gradle propert{}",
+                self.gradle_properties.len(),
+                if self.gradle_properties.len() == 1 {
+                    "y"
+                } else {
+                    "ies"
+                }
+            ));
+        }
+        f.write_str(&parts.join(", "))
+    }
+}
+
+/// `content` with `block` in place of envmgr's block, appended when there is none yet.
+/// At the end, its properties win over the same keys further up.
+pub fn with_block(content: &str, block: &str) -> String {
+    MARKERS
+        .replace(content, block)
+        .unwrap_or_else(|| marked_block::append(content, block))
+}
+
+/// `value` as a `.properties` value, which treats backslashes as escapes
+fn escape_property(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+/// Changed when either file changed
+fn combine(settings: ApplyOutcome, gradle: ApplyOutcome) -> ApplyOutcome {
+    match (settings, gradle) {
+        (ApplyOutcome::AlreadyInDesiredState, ApplyOutcome::AlreadyInDesiredState) => {
+            ApplyOutcome::AlreadyInDesiredState
+        }
+        _ => ApplyOutcome::Changed,
+    }
+}
+
+pub struct Maven;
+
+impl Maven {
+    pub fn settings_path(home: &Path) -> PathBuf {
+        home.join(".m2").join("settings.xml")
+    }
+
+    /// Where the user's own settings.xml waits while an environment's is linked
+    pub fn personal_settings_path(home: &Path) -> PathBuf {
+        home.join(".m2").join("settings.xml.envmgr-personal")
+    }
+
+    pub fn gradle_properties_path(home: &Path) -> PathBuf {
+        home.join(".gradle").join("gradle.properties")
+    }
+
+    /// The marked block for the already resolved `properties`
+    pub fn render_block(properties: &BTreeMap<String, String>) -> String {
+        let mut out = format!("{BLOCK_START}\n");
+        for (key, value) in properties {
+            out.push_str(&format!("{key}={}\n", escape_property(value)));
+        }
+        out.push_str(BLOCK_END);
+        out.push('\n');
+        out
+    }
+
+    /// The Gradle properties with `op://` references read through `op`
+    pub fn resolve_properties(
+        config: &MavenConfig,
+        runner: &dyn CommandRunner,
+    ) -> EnvMgrResult<BTreeMap<String, String>> {
+        let references: Vec<&str> = config
+            .gradle_properties
+            .values()
+            .map(String::as_str)
+            .filter(|value| value.starts_with(SECRET_REFERENCE_PREFIX))
+            .collect();
+        let mut resolved = match references.is_empty() {
+            true => Default::default(),
+            false => OpCli::new(runner).read_all(&references),
+        };
+        let mut properties = BTreeMap::new();
+        for (key, value) in &config.gradle_properties {
+            let value = match resolved.remove(value) {
+                Some(Ok(secret)) => secret,
+                Some(Err(e)) => {
+                    return Err(EnvMgrError::Maven(format!(
+                        "the gradle property {key} could not be read: {e}"
+                    )));
+                }
+                None => value.clone(),
+            };
+            properties.insert(key.clone(), value);
+        }
+        Ok(properties)
+    }
+
+    /// Whether `path` is a link envmgr placed, i.e. one into the config dir
+    fn is_managed_link(path: &Path, config_dir: &Path) -> bool {
+        std::fs::read_link(path).is_ok_and(|source| source.starts_with(config_dir))
+    }
+
+    /// Apply `config`: link `settings_source` from `env_dir` and write the Gradle block.
+    /// What `config` leaves out is cleared like [`Maven::on_switch_away`] does.
+    pub fn on_switch_to(
+        config: &MavenConfig,
+        env_dir: &Path,
+        home: &Path,
+        config_dir: &Path,
+        fs: &dyn Fs,
+        runner: &dyn CommandRunner,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        let properties = Self::resolve_properties(config, runner)?;
+        let settings = match &config.settings_source {
+            Some(source) => Self::link_settings(&env_dir.join(source), home, config_dir, fs)?,
+            None => Self::restore_settings(home, config_dir, fs)?,
+        };
+        let gradle = match properties.is_empty() {
+            true => Self::remove_gradle_block(home, fs)?,
+            false => Self::write_gradle_block(&properties, home, fs)?,
+        };
+        Ok(combine(settings, gradle))
+    }
+
+    fn link_settings(
+        source: &Path,
+        home: &Path,
+        config_dir: &Path,
+        fs: &dyn Fs,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        if !platform::SUPPORTS_LINKING {
+            return Err(EnvMgrError::Unsupported(
+                "settings_source links ~/.m2/settings.xml, which is not supported on this \
+                 platform yet"
+                    .into(),
+            ));
+        }
+        if !source.is_file() {
+            return Err(EnvMgrError::Maven(format!(
+                "settings_source {} does not exist",
+                source.display()
+            )));
+        }
+        let settings = Self::settings_path(home);
+        if std::fs::read_link(&settings).is_ok_and(|current| current == source) {
+            return Ok(ApplyOutcome::AlreadyInDesiredState);
+        }
+        if settings.symlink_metadata().is_ok() && !Self::is_managed_link(&settings, config_dir) {
+            let personal = Self::personal_settings_path(home);
+            if personal.symlink_metadata().is_ok() {
+                return Err(EnvMgrError::Maven(format!(
+                    "both {} and {} exist, remove one to link {} there",
+                    settings.display(),
+                    personal.display(),
+                    source.display()
+                )));
+            }
+            debug!("Moving {} to {}", settings.display(), personal.display());
+            fs.rename(&settings, &personal)?;
+        }
+        if let Some(dir) = settings.parent() {
+            fs.create_dir_all(dir)?;
+        }
+        debug!("Linking {} to {}", settings.display(), source.display());
+        fs.replace_symlink(source, &settings)?;
+        Ok(ApplyOutcome::Changed)
+    }
+
+    /// Remove envmgr's link to settings.xml and put the user's own one back
+    fn restore_settings(home: &Path, config_dir: &Path, fs: &dyn Fs) -> EnvMgrResult<ApplyOutcome> {
+        let settings = Self::settings_path(home);
+        let mut outcome = ApplyOutcome::AlreadyInDesiredState;
+        if Self::is_managed_link(&settings, config_dir) {
+            fs.remove_file(&settings)?;
+            outcome = ApplyOutcome::Changed;
+        }
+        let personal = Self::personal_settings_path(home);
+        if personal.symlink_metadata().is_ok() && settings.symlink_metadata().is_err() {
+            debug!(
+                "Moving {} back to {}",
+                personal.display(),
+                settings.display()
+            );
+            fs.rename(&personal, &settings)?;
+            outcome = ApplyOutcome::Changed;
+        }
+        Ok(outcome)
+    }
+
+    fn write_gradle_block(
+        properties: &BTreeMap<String, String>,
+        home: &Path,
+        fs: &dyn Fs,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        // A linked gradle.properties, e.g. from a dotfiles repo, is edited where it points
+        let path = Self::gradle_properties_path(home);
+        let path = std::fs::canonicalize(&path).unwrap_or(path);
+        let current = Self::read_properties(&path, fs)?;
+        let updated = with_block(&current, &Self::render_block(properties));
+        if updated == current {
+            return Ok(ApplyOutcome::AlreadyInDesiredState);
+        }
+        debug!("Writing the maven block to {}", path.display());
+        fs.write_atomic_with_mode(&path, updated.as_bytes(), GRADLE_PROPERTIES_MODE)?;
+        Ok(ApplyOutcome::Changed)
+    }
+
+    fn remove_gradle_block(home: &Path, fs: &dyn Fs) -> EnvMgrResult<ApplyOutcome> {
+        let path = Self::gradle_properties_path(home);
+        let path = std::fs::canonicalize(&path).unwrap_or(path);
+        match MARKERS.strip(&Self::read_properties(&path, fs)?) {
+            (rest, true) => write_if_changed(fs, &path, &rest),
+            (_, false) => Ok(ApplyOutcome::AlreadyInDesiredState),
+        }
+    }
+
+    /// Remove what envmgr added: the settings.xml link, restoring the user's own file,
+    /// and the Gradle block
+    pub fn on_switch_away(
+        home: &Path,
+        config_dir: &Path,
+        fs: &dyn Fs,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        let settings = Self::restore_settings(home, config_dir, fs)?;
+        let gradle = Self::remove_gradle_block(home, fs)?;
+        Ok(combine(settings, gradle))
+    }
+
+    /// Describe what [`Maven::on_switch_away`] would change
+    pub fn describe_switch_away(home: &Path, config_dir: &Path) -> Vec<String> {
+        let mut actions = Self::describe_restore_settings(home, config_dir);
+        let gradle = Self::gradle_properties_path(home);
+        if std::fs::read_to_string(&gradle).is_ok_and(|content| MARKERS.strip(&content).1) {
+            actions.push(format!("remove the envmgr block from {}", gradle.display()));
+        }
+        actions
+    }
+
+    /// Describe how the user's own settings.xml would be put back
+    pub fn describe_restore_settings(home: &Path, config_dir: &Path) -> Vec<String> {
+        let mut actions = vec![];
+        let settings = Self::settings_path(home);
+        if Self::is_managed_link(&settings, config_dir) {
+            actions.push(format!("remove the link {}", settings.display()));
+        }
+        let personal = Self::personal_settings_path(home);
+        if personal.symlink_metadata().is_ok()
+            && (settings.symlink_metadata().is_err()
+                || Self::is_managed_link(&settings, config_dir))
+        {
+            actions.push(format!(
+                "move {} back to {}",
+                personal.display(),
+                settings.display()
+            ));
+        }
+        actions
+    }
+
+    fn read_properties(path: &Path, fs: &dyn Fs) -> EnvMgrResult<String> {
+        match fs.read(path)? {
+            Some(bytes) => String::from_utf8(bytes)
+                .map_err(|_| EnvMgrError::Maven(format!("{} is not valid UTF-8", path.display()))),
+            None => Ok(String::new()),
+        }
+    }
+}
+
+/// [`Maven`] behind [`Integration`]
+pub struct MavenIntegration<'a> {
+    pub home: PathBuf,
+    /// Where the settings.xml links point into
+    pub config_dir: PathBuf,
+    pub fs: &'a dyn Fs,
+    pub runner: &'a dyn CommandRunner,
+}
+
+impl Integration for MavenIntegration<'_> {
+    type Config = MavenConfig;
+
+    fn name(&self) -> IntegrationKind {
+        IntegrationKind::Maven
+    }
+
+    fn config<'e>(&self, env: &'e Environment) -> Option<&'e Self::Config> {
+        env.maven.as_ref()
+    }
+
+    fn on_switch_to(
+        &self,
+        env: &Environment,
+        config: &Self::Config,
+    ) -> EnvMgrResult<IntegrationOutcome> {
+        Maven::on_switch_to(
+            config,
+            &env.env_dir(),
+            &self.home,
+            &self.config_dir,
+            self.fs,
+            self.runner,
+        )
+        .map(Into::into)
+    }
+
+    fn describe_switch_to(&self, env: &Environment, config: &Self::Config) -> Vec<String> {
+        let mut actions = vec![];
+        let settings = Maven::settings_path(&self.home);
+        match &config.settings_source {
+            Some(source) => {
+                let source = env.env_dir().join(source);
+                actions.push(match std::fs::read_link(&settings) {
+                    Ok(current) if current == source => format!(
+                        "{} ({} links to {})",
+                        ApplyOutcome::AlreadyInDesiredState,
+                        settings.display(),
+                        source.display()
+                    ),
+                    _ => format!("link {} to {}", settings.display(), source.display()),
+                });
+            }
+            None => actions.extend(Maven::describe_restore_settings(
+                &self.home,
+                &self.config_dir,
+            )),
+        }
+        // Properties are only read from 1Password on switch, so the block is
+        // always listed as written
+        if !config.gradle_properties.is_empty() {
+            actions.push(format!(
+                "write the envmgr block of {} ({config})",
+                Maven::gradle_properties_path(&self.home).display()
+            ));
+        }
+        if actions.is_empty() {
+            actions.push(format!(
+                "{} ({config})",
+                ApplyOutcome::AlreadyInDesiredState
+            ));
+        }
+        actions
+    }
+
+    /// The settings must not leak into the next environment. Base's settings, e.g. a
+    /// personal mirror, are what the others fall back to.
+    fn clear(&self) -> EnvMgrResult<ApplyOutcome> {
+        let base = Environment::load_base_environment()?;
+        if let Some(config) = &base.maven {
+            return Ok(self.on_switch_to(&base, config)?.apply);
+        }
+        Maven::on_switch_away(&self.home, &self.config_dir, self.fs)
+    }
+
+    fn describe_clear(&self) -> Vec<String> {
+        if let Ok(base) = Environment::load_base_environment()
+            && let Some(config) = &base.maven
+        {
+            return self.describe_switch_to(&base, config);
+        }
+        Maven::describe_switch_away(&self.home, &self.config_dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, fs, io};
+
+    use super::*;
+    use crate::{fs::RealFs, runner::CommandOutput};
+
+    /// Reads `op://Work/nexus/password` as `s3cret`
+    #[derive(Default)]
+    struct FakeOp {
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl CommandRunner for FakeOp {
+        fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+            assert_eq!(program, "op");
+            self.calls.borrow_mut().push(args.join(" "));
+            Ok(match args.last() {
+                Some(&"op://Work/nexus/password") => CommandOutput {
+                    success: true,
+                    stdout: "s3cret".to_string(),
+                    ..Default::default()
+                },
+                _ => CommandOutput {
+                    stderr: "no such item".to_string(),
+                    ..Default::default()
+                },
+            })
+        }
+    }
+
+    fn temp_dirs(name: &str) -> (PathBuf, PathBuf, PathBuf) {
+        let root = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&root);
+        let (home, config_dir) = (root.join("home"), root.join("config"));
+        fs::create_dir_all(&home).unwrap();
+        fs::create_dir_all(config_dir.join("environments/work/maven")).unwrap();
+        (root, home, config_dir)
+    }
+
+    fn work() -> MavenConfig {
+        MavenConfig {
+            settings_source: None,
+            gradle_properties: BTreeMap::from([
+                ("nexusUser".to_string(), "ci".to_string()),
+                (
+                    "nexusPassword".to_string(),
+                    "op://Work/nexus/password".to_string(),
+                ),
+            ]),
+        }
+    }
+
+    const PERSONAL_PROPERTIES: &str = "org.gradle.daemon=true\norg.gradle.jvmargs=-Xmx2g\n";
+
+    #[test]
+    fn test_block_is_replaced_in_place() {
+        let block = format!("{BLOCK_START}\na=1\n{BLOCK_END}\n");
+        let added = with_block("org.gradle.daemon=true", &block);
+        assert_eq!(added, format!("org.gradle.daemon=true\n{block}"));
+
+        let moved = format!("{added}org.gradle.caching=true\n");
+        let other = format!("{BLOCK_START}\nb=2\n{BLOCK_END}\n");
+        assert_eq!(
+            with_block(&moved, &other),
+            format!("org.gradle.daemon=true\n{other}org.gradle.caching=true\n")
+        );
+        assert_eq!(
+            MARKERS.strip(&moved),
+            (
+                "org.gradle.daemon=true\norg.gradle.caching=true\n".to_string(),
+                true
+            )
+        );
+    }
+
+    #[test]
+    fn test_properties_are_read_from_1password() {
+        let op = FakeOp::default();
+        let mut config = work();
+
+        let properties = Maven::resolve_properties(&config, &op).unwrap();
+
+        assert_eq!(properties["nexusPassword"], "s3cret");
+        assert_eq!(properties["nexusUser"], "ci");
+        assert_eq!(op.calls.borrow().len(), 1);
+        assert_eq!(
+            Maven::render_block(&BTreeMap::from([(
+                "path".to_string(),
+                "C:\\gradle\nx".to_string()
+            )])),
+            format!("{BLOCK_START}\npath=C:\\\\gradle\\nx\n{BLOCK_END}\n")
+        );
+
+        config.gradle_properties.insert(
+            "proxyPassword".to_string(),
+            "op://Work/missing/password".to_string(),
+        );
+        let Err(EnvMgrError::Maven(message)) = Maven::resolve_properties(&config, &op) else {
+            panic!("expected an error for an unreadable property");
+        };
+        assert!(message.contains("proxyPassword"), "{message}");
+    }
+
+    #[test]
+    fn test_switch_writes_and_removes_the_gradle_block() {
+        let (root, home, config_dir) = temp_dirs("envmgr_test_maven_gradle");
+        let env_dir = config_dir.join("environments/work");
+        let properties = Maven::gradle_properties_path(&home);
+        fs::create_dir_all(properties.parent().unwrap()).unwrap();
+        fs::write(&properties, PERSONAL_PROPERTIES).unwrap();
+        let op = FakeOp::default();
+
+        let outcome = Maven::on_switch_to(&work(), &env_dir, &home, &config_dir, &RealFs, &op);
+
+        assert_eq!(outcome.unwrap(), ApplyOutcome::Changed);
+        assert_eq!(
+            fs::read_to_string(&properties).unwrap(),
+            format!(
+                "{PERSONAL_PROPERTIES}{BLOCK_START}\nnexusPassword=s3cret\nnexusUser=ci\n{BLOCK_END}\n"
+            )
+        );
+        assert_eq!(
+            Maven::on_switch_to(&work(), &env_dir, &home, &config_dir, &RealFs, &op).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&properties).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, GRADLE_PROPERTIES_MODE);
+        }
+
+        assert_eq!(
+            Maven::describe_switch_away(&home, &config_dir),
+            [format!(
+                "remove the envmgr block from {}",
+                properties.display()
+            )]
+        );
+        assert_eq!(
+            Maven::on_switch_away(&home, &config_dir, &RealFs).unwrap(),
+            ApplyOutcome::Changed
+        );
+        assert_eq!(
+            fs::read_to_string(&properties).unwrap(),
+            PERSONAL_PROPERTIES
+        );
+        assert_eq!(
+            Maven::on_switch_away(&home, &config_dir, &RealFs).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_settings_source_is_linked_and_personal_settings_restored() {
+        let (root, home, config_dir) = temp_dirs("envmgr_test_maven_settings");
+        let env_dir = config_dir.join("environments/work");
+        let source = env_dir.join("maven/settings.xml");
+        fs::write(&source, "<settings><mirrors/></settings>\n").unwrap();
+        let settings = Maven::settings_path(&home);
+        fs::create_dir_all(settings.parent().unwrap()).unwrap();
+        fs::write(&settings, "<settings/>\n").unwrap();
+        let config = MavenConfig {
+            settings_source: Some("maven/settings.xml".to_string()),
+            ..Default::default()
+        };
+        let op = FakeOp::default();
+
+        let outcome = Maven::on_switch_to(&config, &env_dir, &home, &config_dir, &RealFs, &op);
+
+        assert_eq!(outcome.unwrap(), ApplyOutcome::Changed);
+        assert_eq!(fs::read_link(&settings).unwrap(), source);
+        let personal = Maven::personal_settings_path(&home);
+        assert_eq!(fs::read_to_string(&personal).unwrap(), "<settings/>\n");
+        assert_eq!(
+            Maven::on_switch_to(&config, &env_dir, &home, &config_dir, &RealFs, &op).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+
+        // An environment with only Gradle properties gets the personal settings back
+        Maven::on_switch_to(&work(), &env_dir, &home, &config_dir, &RealFs, &op).unwrap();
+        assert!(!settings.is_symlink());
+        assert_eq!(fs::read_to_string(&settings).unwrap(), "<settings/>\n");
+        assert!(personal.symlink_metadata().is_err());
+
+        Maven::on_switch_to(&config, &env_dir, &home, &config_dir, &RealFs, &op).unwrap();
+        assert_eq!(
+            Maven::describe_switch_away(&home, &config_dir),
+            [
+                format!("remove the link {}", settings.display()),
+                format!("move {} back to {}", personal.display(), settings.display()),
+            ]
+        );
+        assert_eq!(
+            Maven::on_switch_away(&home, &config_dir, &RealFs).unwrap(),
+            ApplyOutcome::Changed
+        );
+        assert_eq!(fs::read_to_string(&settings).unwrap(), "<settings/>\n");
+
+        // A personal settings.xml created while the link was there is never overwritten
+        Maven::on_switch_to(&config, &env_dir, &home, &config_dir, &RealFs, &op).unwrap();
+        fs::remove_file(&settings).unwrap();
+        fs::write(&settings, "<settings>new</settings>\n").unwrap();
+        let result = Maven::on_switch_to(&config, &env_dir, &home, &config_dir, &RealFs, &op);
+        assert!(matches!(result, Err(EnvMgrError::Maven(_))));
+        assert_eq!(
+            fs::read_to_string(&settings).unwrap(),
+            "<settings>new</settings>\n"
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
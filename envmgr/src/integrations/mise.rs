@@ -0,0 +1,710 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+
+use crate::{
+    environment::Environment,
+    error::{EnvMgrError, EnvMgrResult},
+    fs::Fs,
+    integrations::{
+        ApplyOutcome, Integration, IntegrationKind, IntegrationOutcome, marked_block::Markers,
+        write_if_changed,
+    },
+    runner::CommandRunner,
+};
+
+const HEADER: &str = "# Written by envmgr for the active environment, changes are overwritten\n";
+const BLOCK_START: &str =
+    "# >>> envmgr mise: versions of the active environment, changes are overwritten";
+const BLOCK_END: &str = "# <<< envmgr mise";
+const MARKERS: Markers = Markers {
+    begin: BLOCK_START,
+    end: BLOCK_END,
+};
+
+/// Where the versions go
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    serde::Deserialize,
+    serde::Serialize,
+    schemars::JsonSchema,
+    Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolVersionsFormat {
+    /// `~/.config/mise/conf.d/envmgr.toml`, read by mise as global config
+    #[default]
+    Mise,
+    /// A marked block at the top of `~/.tool-versions`, read by asdf (and mise)
+    Asdf,
+}
+
+impl ToolVersionsFormat {
+    fn is_mise(&self) -> bool {
+        *self == ToolVersionsFormat::Mise
+    }
+
+    /// The version manager's executable
+    pub fn tool(self) -> &'static str {
+        match self {
+            ToolVersionsFormat::Mise => "mise",
+            ToolVersionsFormat::Asdf => "asdf",
+        }
+    }
+
+    /// The command installing `version` of `tool`
+    pub fn install_command(self, tool: &str, version: &str) -> String {
+        match self {
+            ToolVersionsFormat::Mise => format!("mise install {tool}@{version}"),
+            ToolVersionsFormat::Asdf => format!("asdf install {tool} {version}"),
+        }
+    }
+}
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema, Default,
+)]
+pub struct MiseConfig {
+    /// Global tool versions, e.g. `{"node": "20", "terraform": "1.7"}`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub globals: BTreeMap<String, String>,
+    /// `asdf` writes the versions to `~/.tool-versions` instead of mise's `conf.d`
+    #[serde(default, skip_serializing_if = "ToolVersionsFormat::is_mise")]
+    pub format: ToolVersionsFormat,
+}
+
+impl std::fmt::Display for MiseConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let versions = self
+            .globals
+            .iter()
+            .map(|(tool, version)| format!("{tool} {version}"))
+            .collect::<Vec<_>>();
+        match versions.is_empty() {
+            true => f.write_str("no versions")?,
+            false => f.write_str(&versions.join(", "))?,
+        }
+        if self.format == ToolVersionsFormat::Asdf {
+            f.write_str(" in .tool-versions")?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the installed `version` satisfies the `wanted` one, which mise and asdf
+/// also take as a prefix: `20` is satisfied by `20.11.1`. Aliases like `latest` or
+/// `lts` are resolved by the tool, so any installed version counts for them.
+pub fn version_matches(wanted: &str, version: &str) -> bool {
+    if !wanted.starts_with(|c: char| c.is_ascii_digit()) {
+        return true;
+    }
+    version == wanted
+        || version
+            .strip_prefix(wanted)
+            .is_some_and(|rest| rest.starts_with(['.', '-', '+']))
+}
+
+/// Installed versions per tool from `mise ls --json`
+pub fn parse_mise_ls(json: &str) -> EnvMgrResult<BTreeMap<String, Vec<String>>> {
+    #[derive(serde::Deserialize)]
+    struct Entry {
+        version: String,
+        #[serde(default = "installed_by_default")]
+        installed: bool,
+    }
+    fn installed_by_default() -> bool {
+        true
+    }
+
+    let tools: BTreeMap<String, Vec<Entry>> = serde_json::from_str(json)
+        .map_err(|e| EnvMgrError::Mise(format!("unexpected `mise ls --json` output: {e}")))?;
+    Ok(tools
+        .into_iter()
+        .map(|(tool, entries)| {
+            let installed = entries
+                .into_iter()
+                .filter(|entry| entry.installed)
+                .map(|entry| entry.version)
+                .collect();
+            (tool, installed)
+        })
+        .collect())
+}
+
+/// Installed versions from `asdf list <tool>`, which marks the current one with `*`
+pub fn parse_asdf_list(stdout: &str) -> Vec<String> {
+    stdout
+        .lines()
+        .map(|line| line.trim().trim_start_matches('*').trim())
+        .filter(|line| !line.is_empty() && !line.starts_with("No versions"))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Global tool versions per environment, for mise or asdf
+pub struct Mise;
+
+impl Mise {
+    /// `$MISE_CONFIG_DIR`, or `~/.config/mise`
+    pub fn mise_config_dir(home: &Path) -> PathBuf {
+        std::env::var_os("MISE_CONFIG_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| home.join(".config").join("mise"))
+    }
+
+    /// The file envmgr owns in mise's `conf.d`
+    pub fn conf_d_path(mise_dir: &Path) -> PathBuf {
+        mise_dir.join("conf.d").join("envmgr.toml")
+    }
+
+    pub fn tool_versions_path(home: &Path) -> PathBuf {
+        home.join(".tool-versions")
+    }
+
+    /// `conf.d/envmgr.toml` for `config`
+    pub fn render_conf_d(config: &MiseConfig) -> EnvMgrResult<String> {
+        let tools: toml::Table = config
+            .globals
+            .iter()
+            .map(|(tool, version)| (tool.clone(), toml::Value::String(version.clone())))
+            .collect();
+        let mut file = toml::Table::new();
+        file.insert("tools".into(), toml::Value::Table(tools));
+        let rendered = toml::to_string(&file)
+            .map_err(|e| EnvMgrError::Mise(format!("could not write the versions: {e}")))?;
+        Ok(format!("{HEADER}{rendered}"))
+    }
+
+    /// The marked `.tool-versions` block for `config`
+    pub fn render_block(config: &MiseConfig) -> String {
+        let mut out = format!("{BLOCK_START}\n");
+        for (tool, version) in &config.globals {
+            out.push_str(&format!("{tool} {version}\n"));
+        }
+        out.push_str(BLOCK_END);
+        out.push('\n');
+        out
+    }
+
+    /// `content` with envmgr's block replaced by `block`, or put first when there is none.
+    /// An empty `block` removes it.
+    pub fn with_block(content: &str, block: &str) -> String {
+        MARKERS
+            .replace(content, block)
+            .unwrap_or_else(|| format!("{block}{content}"))
+    }
+
+    fn read_tool_versions(path: &Path, fs: &dyn Fs) -> EnvMgrResult<String> {
+        match fs.read(path)? {
+            Some(bytes) => String::from_utf8(bytes)
+                .map_err(|_| EnvMgrError::Mise(format!("{} is not valid UTF-8", path.display()))),
+            None => Ok(String::new()),
+        }
+    }
+
+    /// Set envmgr's block of `~/.tool-versions` to `block`, removing it when empty
+    fn write_tool_versions_block(
+        home: &Path,
+        block: &str,
+        fs: &dyn Fs,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        let link = Self::tool_versions_path(home);
+        let path = std::fs::canonicalize(&link).unwrap_or(link);
+        let current = Self::read_tool_versions(&path, fs)?;
+        let updated = Self::with_block(&current, block);
+        if updated == current {
+            return Ok(ApplyOutcome::AlreadyInDesiredState);
+        }
+        if updated.is_empty() {
+            debug!("Removing {}, only envmgr's block was in it", path.display());
+            fs.remove_file(&path)?;
+            return Ok(ApplyOutcome::Changed);
+        }
+        debug!("Writing the mise block to {}", path.display());
+        fs.write_atomic(&path, updated.as_bytes())?;
+        Ok(ApplyOutcome::Changed)
+    }
+
+    fn remove_conf_d(mise_dir: &Path, fs: &dyn Fs) -> EnvMgrResult<ApplyOutcome> {
+        let path = Self::conf_d_path(mise_dir);
+        if fs.read(&path)?.is_none() {
+            return Ok(ApplyOutcome::AlreadyInDesiredState);
+        }
+        debug!("Removing {}", path.display());
+        fs.remove_file(&path)?;
+        Ok(ApplyOutcome::Changed)
+    }
+
+    /// Write the versions in `config`'s format and drop those of the other format
+    pub fn on_switch_to(
+        config: &MiseConfig,
+        home: &Path,
+        mise_dir: &Path,
+        fs: &dyn Fs,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        let outcomes = match config.format {
+            ToolVersionsFormat::Mise => {
+                let path = Self::conf_d_path(mise_dir);
+                if let Some(dir) = path.parent()
+                    && !dir.exists()
+                {
+                    fs.create_dir_all(dir)?;
+                }
+                [
+                    write_if_changed(fs, &path, &Self::render_conf_d(config)?)?,
+                    Self::write_tool_versions_block(home, "", fs)?,
+                ]
+            }
+            ToolVersionsFormat::Asdf => [
+                Self::write_tool_versions_block(home, &Self::render_block(config), fs)?,
+                Self::remove_conf_d(mise_dir, fs)?,
+            ],
+        };
+        Ok(match outcomes.contains(&ApplyOutcome::Changed) {
+            true => ApplyOutcome::Changed,
+            false => ApplyOutcome::AlreadyInDesiredState,
+        })
+    }
+
+    /// Remove the versions of either format, so they don't apply to the next environment
+    pub fn on_switch_away(home: &Path, mise_dir: &Path, fs: &dyn Fs) -> EnvMgrResult<ApplyOutcome> {
+        let outcomes = [
+            Self::remove_conf_d(mise_dir, fs)?,
+            Self::write_tool_versions_block(home, "", fs)?,
+        ];
+        Ok(match outcomes.contains(&ApplyOutcome::Changed) {
+            true => ApplyOutcome::Changed,
+            false => ApplyOutcome::AlreadyInDesiredState,
+        })
+    }
+
+    /// Describe what [`Mise::on_switch_away`] would change
+    pub fn describe_switch_away(home: &Path, mise_dir: &Path) -> Vec<String> {
+        let mut actions = vec![];
+        let conf_d = Self::conf_d_path(mise_dir);
+        if conf_d.is_file() {
+            actions.push(format!("remove {}", conf_d.display()));
+        }
+        let tool_versions = Self::tool_versions_path(home);
+        if std::fs::read_to_string(&tool_versions).is_ok_and(|content| MARKERS.strip(&content).1) {
+            actions.push(format!(
+                "remove the envmgr block from {}",
+                tool_versions.display()
+            ));
+        }
+        actions
+    }
+
+    /// Whether the files already hold exactly `config`'s versions
+    pub fn is_converged(config: &MiseConfig, home: &Path, mise_dir: &Path) -> bool {
+        let conf_d = std::fs::read_to_string(Self::conf_d_path(mise_dir)).ok();
+        let tool_versions =
+            std::fs::read_to_string(Self::tool_versions_path(home)).unwrap_or_default();
+        match config.format {
+            ToolVersionsFormat::Mise => {
+                conf_d == Self::render_conf_d(config).ok() && !MARKERS.strip(&tool_versions).1
+            }
+            ToolVersionsFormat::Asdf => {
+                conf_d.is_none()
+                    && Self::with_block(&tool_versions, &Self::render_block(config))
+                        == tool_versions
+            }
+        }
+    }
+
+    /// The `(tool, version)` pairs of `config` that aren't installed yet
+    pub fn missing_versions(
+        config: &MiseConfig,
+        runner: &dyn CommandRunner,
+    ) -> EnvMgrResult<Vec<(String, String)>> {
+        let tool = config.format.tool();
+        let not_installed = |e: std::io::Error| match e.kind() {
+            std::io::ErrorKind::NotFound => EnvMgrError::Mise(format!(
+                "{tool} is not installed, but the mise integration needs it"
+            )),
+            _ => e.into(),
+        };
+        let installed: BTreeMap<String, Vec<String>> = match config.format {
+            ToolVersionsFormat::Mise => {
+                let output = runner
+                    .run("mise", &["ls", "--json"])
+                    .map_err(not_installed)?;
+                if !output.success {
+                    return Err(EnvMgrError::Mise(format!(
+                        "mise ls failed: {}",
+                        output.stderr.trim()
+                    )));
+                }
+                parse_mise_ls(&output.stdout)?
+            }
+            ToolVersionsFormat::Asdf => {
+                let mut installed = BTreeMap::new();
+                for name in config.globals.keys() {
+                    let output = runner.run("asdf", &["list", name]).map_err(not_installed)?;
+                    // Fails for a tool without a plugin, which has nothing installed
+                    let versions = match output.success {
+                        true => parse_asdf_list(&output.stdout),
+                        false => vec![],
+                    };
+                    installed.insert(name.clone(), versions);
+                }
+                installed
+            }
+        };
+        Ok(config
+            .globals
+            .iter()
+            .filter(|(name, wanted)| {
+                !installed.get(*name).is_some_and(|versions| {
+                    versions
+                        .iter()
+                        .any(|version| version_matches(wanted, version))
+                })
+            })
+            .map(|(name, wanted)| (name.clone(), wanted.clone()))
+            .collect())
+    }
+}
+
+/// [`Mise`] behind [`Integration`]
+pub struct MiseIntegration<'a> {
+    pub home: PathBuf,
+    pub fs: &'a dyn Fs,
+    pub runner: &'a dyn CommandRunner,
+}
+
+impl Integration for MiseIntegration<'_> {
+    type Config = MiseConfig;
+
+    fn name(&self) -> IntegrationKind {
+        IntegrationKind::Mise
+    }
+
+    fn config<'e>(&self, env: &'e Environment) -> Option<&'e Self::Config> {
+        env.mise.as_ref()
+    }
+
+    fn on_switch_to(
+        &self,
+        _env: &Environment,
+        config: &Self::Config,
+    ) -> EnvMgrResult<IntegrationOutcome> {
+        Mise::on_switch_to(
+            config,
+            &self.home,
+            &Mise::mise_config_dir(&self.home),
+            self.fs,
+        )
+        .map(Into::into)
+    }
+
+    fn describe_switch_to(&self, _env: &Environment, config: &Self::Config) -> Vec<String> {
+        let mise_dir = Mise::mise_config_dir(&self.home);
+        if Mise::is_converged(config, &self.home, &mise_dir) {
+            return vec![format!(
+                "{} ({config})",
+                ApplyOutcome::AlreadyInDesiredState
+            )];
+        }
+        vec![match config.format {
+            ToolVersionsFormat::Mise => format!(
+                "write {} ({config})",
+                Mise::conf_d_path(&mise_dir).display()
+            ),
+            ToolVersionsFormat::Asdf => format!(
+                "write the envmgr block of {} ({config})",
+                Mise::tool_versions_path(&self.home).display()
+            ),
+        }]
+    }
+
+    /// The versions must not leak into the next environment
+    fn clear(&self) -> EnvMgrResult<ApplyOutcome> {
+        Mise::on_switch_away(&self.home, &Mise::mise_config_dir(&self.home), self.fs)
+    }
+
+    fn describe_clear(&self) -> Vec<String> {
+        Mise::describe_switch_away(&self.home, &Mise::mise_config_dir(&self.home))
+    }
+
+    /// Which of the versions are installed. Missing ones only need a `mise install`, so
+    /// they are warnings.
+    fn doctor_check(&self, config: &Self::Config, out: &mut String) -> usize {
+        let missing = match Mise::missing_versions(config, self.runner) {
+            Ok(missing) => missing,
+            Err(e) => {
+                let _ = writeln!(out, "mise: could not check the versions\n  warning: {e}");
+                return 0;
+            }
+        };
+        let _ = writeln!(
+            out,
+            "mise: {} of {} version(s) installed",
+            config.globals.len() - missing.len(),
+            config.globals.len()
+        );
+        for (tool, version) in &missing {
+            let _ = writeln!(
+                out,
+                "  warning: {tool} {version} is not installed, run `{}`",
+                config.format.install_command(tool, version)
+            );
+        }
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io};
+
+    use super::*;
+    use crate::{fs::RealFs, runner::CommandOutput};
+
+    const MISE_LS: &str = r#"{
+      "node": [
+        {"version": "18.19.0", "install_path": "/m/node/18.19.0", "installed": true, "active": false},
+        {"version": "20.11.1", "requested_version": "20", "installed": true, "active": true}
+      ],
+      "terraform": [
+        {"version": "1.7.0", "requested_version": "1.7", "installed": false, "active": true}
+      ]
+    }"#;
+
+    /// mise with [`MISE_LS`] installed, and asdf with node 20.11.1
+    struct FakeVersionManager;
+
+    impl CommandRunner for FakeVersionManager {
+        fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+            let stdout = match (program, args) {
+                ("mise", ["ls", "--json"]) => MISE_LS.to_string(),
+                ("asdf", ["list", "node"]) => "  18.19.0\n *20.11.1\n".to_string(),
+                ("asdf", ["list", _]) => {
+                    return Ok(CommandOutput {
+                        stderr: "No such plugin".into(),
+                        ..Default::default()
+                    });
+                }
+                _ => panic!("unexpected {program} {args:?}"),
+            };
+            Ok(CommandOutput {
+                success: true,
+                stdout,
+                ..Default::default()
+            })
+        }
+    }
+
+    fn config(format: ToolVersionsFormat, globals: &[(&str, &str)]) -> MiseConfig {
+        MiseConfig {
+            globals: globals
+                .iter()
+                .map(|(tool, version)| (tool.to_string(), version.to_string()))
+                .collect(),
+            format,
+        }
+    }
+
+    fn temp_home(name: &str) -> (PathBuf, PathBuf) {
+        let home = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&home);
+        fs::create_dir_all(&home).unwrap();
+        let mise_dir = home.join(".config").join("mise");
+        (home, mise_dir)
+    }
+
+    #[test]
+    fn test_render_conf_d() {
+        let config = config(
+            ToolVersionsFormat::Mise,
+            &[("node", "20"), ("npm:prettier", "3"), ("terraform", "1.7")],
+        );
+        assert_eq!(
+            Mise::render_conf_d(&config).unwrap(),
+            format!(
+                "{HEADER}[tools]\nnode = \"20\"\n\"npm:prettier\" = \"3\"\nterraform = \"1.7\"\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_switching_writes_and_removes_conf_d() {
+        let (home, mise_dir) = temp_home("envmgr_test_mise_conf_d");
+        let config = config(ToolVersionsFormat::Mise, &[("node", "20")]);
+        let conf_d = Mise::conf_d_path(&mise_dir);
+
+        assert_eq!(
+            Mise::on_switch_to(&config, &home, &mise_dir, &RealFs).unwrap(),
+            ApplyOutcome::Changed
+        );
+        assert_eq!(
+            fs::read_to_string(&conf_d).unwrap(),
+            Mise::render_conf_d(&config).unwrap()
+        );
+        assert!(Mise::is_converged(&config, &home, &mise_dir));
+        assert_eq!(
+            Mise::on_switch_to(&config, &home, &mise_dir, &RealFs).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        assert!(!Mise::tool_versions_path(&home).exists());
+
+        assert_eq!(
+            Mise::describe_switch_away(&home, &mise_dir),
+            vec![format!("remove {}", conf_d.display())]
+        );
+        assert_eq!(
+            Mise::on_switch_away(&home, &mise_dir, &RealFs).unwrap(),
+            ApplyOutcome::Changed
+        );
+        assert!(!conf_d.exists());
+        assert_eq!(
+            Mise::on_switch_away(&home, &mise_dir, &RealFs).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn test_asdf_block_keeps_the_users_versions() {
+        let (home, mise_dir) = temp_home("envmgr_test_mise_asdf");
+        let tool_versions = Mise::tool_versions_path(&home);
+        fs::write(&tool_versions, "# mine\npython 3.12.1\n").unwrap();
+        let config = config(
+            ToolVersionsFormat::Asdf,
+            &[("nodejs", "20.11.1"), ("terraform", "1.7.0")],
+        );
+
+        Mise::on_switch_to(&config, &home, &mise_dir, &RealFs).unwrap();
+        assert_eq!(
+            fs::read_to_string(&tool_versions).unwrap(),
+            format!(
+                "{BLOCK_START}\nnodejs 20.11.1\nterraform 1.7.0\n{BLOCK_END}\n# mine\npython 3.12.1\n"
+            )
+        );
+        assert!(Mise::is_converged(&config, &home, &mise_dir));
+
+        // Another environment's versions replace the block in place
+        let other = self::config(ToolVersionsFormat::Asdf, &[("nodejs", "18.19.0")]);
+        Mise::on_switch_to(&other, &home, &mise_dir, &RealFs).unwrap();
+        assert_eq!(
+            fs::read_to_string(&tool_versions).unwrap(),
+            format!("{BLOCK_START}\nnodejs 18.19.0\n{BLOCK_END}\n# mine\npython 3.12.1\n")
+        );
+
+        // Switching to the mise format moves the versions to conf.d
+        let mise = self::config(ToolVersionsFormat::Mise, &[("node", "20")]);
+        Mise::on_switch_to(&mise, &home, &mise_dir, &RealFs).unwrap();
+        assert_eq!(
+            fs::read_to_string(&tool_versions).unwrap(),
+            "# mine\npython 3.12.1\n"
+        );
+        assert!(Mise::conf_d_path(&mise_dir).is_file());
+
+        // A .tool-versions holding only the block goes away with it
+        fs::remove_file(&tool_versions).unwrap();
+        Mise::on_switch_to(&config, &home, &mise_dir, &RealFs).unwrap();
+        assert!(!Mise::conf_d_path(&mise_dir).exists());
+        Mise::on_switch_away(&home, &mise_dir, &RealFs).unwrap();
+        assert!(!tool_versions.exists());
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn test_version_matches() {
+        assert!(version_matches("20", "20.11.1"));
+        assert!(version_matches("20.11.1", "20.11.1"));
+        assert!(version_matches("1.7", "1.7.0"));
+        assert!(!version_matches("1.7", "1.70.0"));
+        assert!(!version_matches("20", "18.19.0"));
+        assert!(version_matches("latest", "1.0.0"));
+        assert!(version_matches("lts", "20.11.1"));
+    }
+
+    #[test]
+    fn test_missing_versions() {
+        let mise = config(
+            ToolVersionsFormat::Mise,
+            &[("node", "20"), ("terraform", "1.7"), ("python", "3.12")],
+        );
+        assert_eq!(
+            Mise::missing_versions(&mise, &FakeVersionManager).unwrap(),
+            vec![
+                ("python".to_string(), "3.12".to_string()),
+                ("terraform".to_string(), "1.7".to_string()),
+            ]
+        );
+
+        let asdf = config(
+            ToolVersionsFormat::Asdf,
+            &[("node", "20.11.1"), ("terraform", "1.7.0")],
+        );
+        assert_eq!(
+            Mise::missing_versions(&asdf, &FakeVersionManager).unwrap(),
+            vec![("terraform".to_string(), "1.7.0".to_string())]
+        );
+        assert_eq!(
+            ToolVersionsFormat::Asdf.install_command("terraform", "1.7.0"),
+            "asdf install terraform 1.7.0"
+        );
+    }
+
+    /// mise with node 20 installed, or no mise at all
+    struct FakeMise(bool);
+
+    impl CommandRunner for FakeMise {
+        fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+            assert_eq!((program, args), ("mise", ["ls", "--json"].as_slice()));
+            if !self.0 {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+            Ok(CommandOutput {
+                success: true,
+                stdout: r#"{"node": [{"version": "20.11.1", "installed": true}]}"#.into(),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[test]
+    fn test_doctor_check_warns_about_missing_versions() {
+        let config = MiseConfig {
+            globals: [("node", "20"), ("terraform", "1.7")]
+                .map(|(tool, version)| (tool.to_string(), version.to_string()))
+                .into(),
+            ..Default::default()
+        };
+        let mise = |installed| MiseIntegration {
+            home: PathBuf::from("/home/u"),
+            fs: &RealFs,
+            runner: if installed {
+                &FakeMise(true)
+            } else {
+                &FakeMise(false)
+            },
+        };
+
+        let mut out = String::new();
+        assert_eq!(mise(true).doctor_check(&config, &mut out), 0);
+        assert_eq!(
+            out,
+            "mise: 1 of 2 version(s) installed\n  \
+             warning: terraform 1.7 is not installed, run `mise install terraform@1.7`\n"
+        );
+
+        let mut out = String::new();
+        assert_eq!(mise(false).doctor_check(&config, &mut out), 0);
+        assert_eq!(
+            out,
+            "mise: could not check the versions\n  \
+             warning: mise Error: mise is not installed, but the mise integration needs it\n"
+        );
+    }
+}
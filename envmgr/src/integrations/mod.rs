@@ -1,16 +1,890 @@
-use std::path::PathBuf;
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fmt::Write as _,
+    path::{Path, PathBuf},
+};
 
+use crate::{
+    config::{EnvVarsConfig, EnvironmentConfig},
+    environment::Environment,
+    error::{EnvMgrError, EnvMgrResult},
+    fs::{Fs, RealFs},
+    runner::{CommandRunner, SystemRunner},
+    state::{IntegrationSnapshot, State},
+};
+
+pub mod aws;
+pub mod cargo;
+pub mod gcloud;
 pub mod gh_cli;
+pub mod git;
+pub mod gpg;
+pub mod kube;
+pub mod marked_block;
+pub mod maven;
+pub mod mise;
+pub mod npm;
+pub mod one_password_documents;
 pub mod one_password_ssh_agent;
+pub mod python;
+pub mod quarantine;
+pub mod rollback;
+pub mod ssh;
 pub mod tailscale;
 
-#[expect(dead_code)]
+use aws::{Aws, AwsIntegration};
+use cargo::CargoIntegration;
+use gcloud::GcloudIntegration;
+use gh_cli::{GhCli, GhCliIntegration};
+use git::GitIntegration;
+use gpg::GpgIntegration;
+use kube::{Kube, KubeIntegration};
+use maven::MavenIntegration;
+use mise::MiseIntegration;
+use npm::NpmIntegration;
+use one_password_ssh_agent::{OnePasswordSSHAgent, OnePasswordSSHAgentIntegration, OpItemList};
+use python::PythonIntegration;
+use ssh::SshIntegration;
+use tailscale::TailscaleIntegration;
+
+/// What an integration contributes to `envmgr use`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct OnUsePluginResult {
-    env_vars: Vec<(String, String)>,
+    /// Exported like `env_vars` of the config, which win over these
+    pub env_vars: Vec<(String, String)>,
+}
+
+/// Env vars the integrations configured in `config` export, e.g. `KUBECONFIG`, each
+/// marked with the integration it comes from
+pub fn integration_env_vars(config: &EnvironmentConfig) -> Vec<EnvVarsConfig> {
+    let home = dirs::home_dir();
+    let results = [
+        (
+            IntegrationKind::Kube,
+            config
+                .kube
+                .as_ref()
+                .map(|kube| Kube::on_use(kube, home.as_deref())),
+        ),
+        (
+            IntegrationKind::Aws,
+            config
+                .aws
+                .as_ref()
+                .map(|aws| Aws::on_use(aws, home.as_deref())),
+        ),
+    ];
+    results
+        .into_iter()
+        .filter_map(|(kind, result)| Some((kind, result?)))
+        .flat_map(|(kind, result)| {
+            result
+                .env_vars
+                .into_iter()
+                .map(move |(key, value)| EnvVarsConfig {
+                    key,
+                    value,
+                    integration: Some(kind.config_key()),
+                    ..Default::default()
+                })
+        })
+        .collect()
 }
 
-#[expect(dead_code)]
-#[derive(Default)]
+/// What an integration contributes to `envmgr switch`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct OnSwitchToPluginResult {
-    files_to_link: Vec<(PathBuf, PathBuf)>,
+    /// `(source, target)` pairs the switch links, replacing whatever is at the target
+    pub files_to_link: Vec<(PathBuf, PathBuf)>,
+}
+
+/// What switching an integration did, and what it hands back to the switch
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrationOutcome {
+    pub apply: ApplyOutcome,
+    /// Env vars `use` exports from then on, below the environment's own
+    pub env: OnUsePluginResult,
+    pub files: OnSwitchToPluginResult,
+}
+
+impl From<ApplyOutcome> for IntegrationOutcome {
+    fn from(apply: ApplyOutcome) -> Self {
+        Self {
+            apply,
+            ..Default::default()
+        }
+    }
+}
+
+/// An integration switches run through [`registry`]
+pub trait Integration {
+    /// Its section of an environment's config
+    type Config;
+
+    fn name(&self) -> IntegrationKind;
+
+    /// Its section of `env`, `None` when `env` doesn't configure it
+    fn config<'e>(&self, env: &'e Environment) -> Option<&'e Self::Config>;
+
+    /// Apply `config` of `env`, leaving things untouched when they already match it
+    fn on_switch_to(
+        &self,
+        env: &Environment,
+        config: &Self::Config,
+    ) -> EnvMgrResult<IntegrationOutcome>;
+
+    /// Describe what [`Integration::on_switch_to`] would change for `config` of `env`.
+    ///
+    /// Only runs read-only probes; probe failures are reported in the descriptions.
+    fn describe_switch_to(&self, env: &Environment, config: &Self::Config) -> Vec<String>;
+
+    /// What [`Integration::on_switch_to`] is about to change for `config`, recorded
+    /// before the integration is first applied. `None` when there is nothing to record,
+    /// which is the default.
+    fn snapshot(&self, _config: &Self::Config) -> EnvMgrResult<Option<String>> {
+        Ok(None)
+    }
+
+    /// Undo what [`Integration::on_switch_to`] did for `config` when switching to an
+    /// environment without the integration, putting back what [`Integration::snapshot`]
+    /// recorded. Integrations without snapshots are cleared instead, which is the default.
+    ///
+    /// Every integration of the outgoing environment is undone before any of the incoming
+    /// one is applied, whatever their `integration_order`.
+    fn on_switch_from(
+        &self,
+        _config: &Self::Config,
+        _snapshot: Option<&str>,
+    ) -> EnvMgrResult<IntegrationOutcome> {
+        self.clear().map(Into::into)
+    }
+
+    /// Undo the integration for an environment that doesn't configure it, so nothing of
+    /// the last one leaks into it. Does nothing by default, which keeps whatever was
+    /// active.
+    fn clear(&self) -> EnvMgrResult<ApplyOutcome> {
+        Ok(ApplyOutcome::AlreadyInDesiredState)
+    }
+
+    /// Describe what [`Integration::clear`] would change
+    fn describe_clear(&self) -> Vec<String> {
+        vec![]
+    }
+
+    /// Write what `envmgr doctor` finds about `config` to `out`, returning the number of
+    /// problems. Drift a switch fixes is only a warning.
+    fn doctor_check(&self, _config: &Self::Config, _out: &mut String) -> usize {
+        0
+    }
+}
+
+/// An [`Integration`] that looks its config up itself, so integrations with different
+/// config types fit in one registry
+pub trait ConfiguredIntegration {
+    fn kind(&self) -> IntegrationKind;
+
+    fn is_configured_in(&self, env: &Environment) -> bool;
+
+    /// [`Integration::on_switch_to`] with the config of `env`, nothing when it has none
+    fn switch_to(&self, env: &Environment) -> EnvMgrResult<IntegrationOutcome>;
+
+    /// [`Integration::describe_switch_to`] with the config of `env`, nothing when it has
+    /// none
+    fn describe_switch_to(&self, env: &Environment) -> Vec<String>;
+
+    /// [`Integration::snapshot`] with the config of `env`, nothing when it has none
+    fn snapshot(&self, env: &Environment) -> EnvMgrResult<Option<String>>;
+
+    /// [`Integration::on_switch_from`] with the config of `env`, nothing when it has none
+    fn switch_from(
+        &self,
+        env: &Environment,
+        snapshot: Option<&str>,
+    ) -> EnvMgrResult<IntegrationOutcome>;
+
+    /// [`Integration::doctor_check`] with the config of `env`
+    fn check(&self, env: &Environment, out: &mut String) -> usize;
+
+    /// [`Integration::clear`]
+    fn clear(&self) -> EnvMgrResult<ApplyOutcome>;
+
+    /// [`Integration::describe_clear`]
+    fn describe_clear(&self) -> Vec<String>;
+}
+
+impl<I: Integration> ConfiguredIntegration for I {
+    fn kind(&self) -> IntegrationKind {
+        self.name()
+    }
+
+    fn is_configured_in(&self, env: &Environment) -> bool {
+        self.config(env).is_some()
+    }
+
+    fn switch_to(&self, env: &Environment) -> EnvMgrResult<IntegrationOutcome> {
+        match self.config(env) {
+            Some(config) => self.on_switch_to(env, config),
+            None => Ok(IntegrationOutcome::default()),
+        }
+    }
+
+    fn describe_switch_to(&self, env: &Environment) -> Vec<String> {
+        match self.config(env) {
+            Some(config) => Integration::describe_switch_to(self, env, config),
+            None => vec![],
+        }
+    }
+
+    fn snapshot(&self, env: &Environment) -> EnvMgrResult<Option<String>> {
+        match self.config(env) {
+            Some(config) => Integration::snapshot(self, config),
+            None => Ok(None),
+        }
+    }
+
+    fn switch_from(
+        &self,
+        env: &Environment,
+        snapshot: Option<&str>,
+    ) -> EnvMgrResult<IntegrationOutcome> {
+        match self.config(env) {
+            Some(config) => self.on_switch_from(config, snapshot),
+            None => Ok(IntegrationOutcome::default()),
+        }
+    }
+
+    fn check(&self, env: &Environment, out: &mut String) -> usize {
+        match self.config(env) {
+            Some(config) => self.doctor_check(config, out),
+            None => {
+                let _ = writeln!(out, "{}: not configured in {}", self.name(), env.key);
+                0
+            }
+        }
+    }
+
+    fn clear(&self) -> EnvMgrResult<ApplyOutcome> {
+        Integration::clear(self)
+    }
+
+    fn describe_clear(&self) -> Vec<String> {
+        Integration::describe_clear(self)
+    }
+}
+
+/// Every integration, in [`IntegrationKind::ALL`] order.
+///
+/// `login` lets them run login commands that need the user, like `tailscale login`.
+pub fn registry<'a>(
+    fs: &'a dyn Fs,
+    runner: &'a dyn CommandRunner,
+    login: bool,
+) -> EnvMgrResult<Vec<Box<dyn ConfiguredIntegration + 'a>>> {
+    let home = dirs::home_dir().ok_or(EnvMgrError::DirError("home".into()))?;
+    let config_dir = crate::config::envmgr_config_dir();
+    Ok(vec![
+        Box::new(OnePasswordSSHAgentIntegration {
+            path: OnePasswordSSHAgent::op_ssh_agent_file_path()?,
+            fs,
+            keys: Box::new(OpItemList::new(runner)),
+        }),
+        Box::new(GhCliIntegration {
+            gh_dir: GhCli::gh_config_dir()?,
+            fs,
+            runner,
+            login,
+        }),
+        Box::new(GitIntegration {
+            home: home.clone(),
+            fs,
+        }),
+        Box::new(KubeIntegration {
+            home: home.clone(),
+            runner,
+        }),
+        Box::new(AwsIntegration {
+            home: home.clone(),
+            runner,
+        }),
+        Box::new(GcloudIntegration { runner }),
+        Box::new(NpmIntegration {
+            home: home.clone(),
+            config_dir: config_dir.clone(),
+            fs,
+            runner,
+        }),
+        Box::new(SshIntegration {
+            home: home.clone(),
+            fs,
+        }),
+        Box::new(GpgIntegration {
+            home: home.clone(),
+            fs,
+            runner,
+        }),
+        Box::new(MiseIntegration {
+            home: home.clone(),
+            fs,
+            runner,
+        }),
+        Box::new(MavenIntegration {
+            home: home.clone(),
+            config_dir,
+            fs,
+            runner,
+        }),
+        Box::new(PythonIntegration {
+            home: home.clone(),
+            fs,
+        }),
+        Box::new(CargoIntegration { home, fs, runner }),
+        Box::new(TailscaleIntegration { runner, login }),
+    ])
+}
+
+/// Record in `snapshots` what the `planned` integrations of `registry` find before
+/// their first switch to `env`, unless an earlier switch already did. One that can't be
+/// recorded is skipped with a warning, it is tried again on the next switch.
+pub fn record_snapshots(
+    registry: &[Box<dyn ConfiguredIntegration + '_>],
+    env: &Environment,
+    planned: &[IntegrationKind],
+    snapshots: &mut BTreeMap<String, IntegrationSnapshot>,
+) {
+    for integration in registry {
+        let kind = integration.kind();
+        if !planned.contains(&kind) || snapshots.contains_key(kind.config_key()) {
+            continue;
+        }
+        match integration.snapshot(env) {
+            Ok(snapshot) => {
+                log::debug!("{kind}: recorded what it had before {}", env.key);
+                let snapshot = IntegrationSnapshot { content: snapshot };
+                snapshots.insert(kind.config_key().to_string(), snapshot);
+            }
+            Err(e) => log::warn!("{kind}: not recording what it had before {}: {e}", env.key),
+        }
+    }
+}
+
+/// The integrations of `registry` that switching from `from` to `to` undoes: those
+/// `from` configures and `to` doesn't, with a snapshot in `snapshots`
+pub fn planned_switch_from(
+    registry: &[Box<dyn ConfiguredIntegration + '_>],
+    from: &Environment,
+    to: &Environment,
+    selection: &IntegrationSelection,
+    snapshots: &BTreeMap<String, IntegrationSnapshot>,
+) -> Vec<IntegrationKind> {
+    registry
+        .iter()
+        .filter(|integration| {
+            let kind = integration.kind();
+            selection.contains(kind)
+                && integration.is_configured_in(from)
+                && !integration.is_configured_in(to)
+                && snapshots.contains_key(kind.config_key())
+        })
+        .map(|integration| integration.kind())
+        .collect()
+}
+
+/// Link the files the integrations of `outcomes` handed back, and record their env vars
+/// in `state` for `use`, replacing what earlier runs of them recorded
+pub fn take_contributions(
+    state: &mut State,
+    outcomes: &[(IntegrationKind, IntegrationOutcome)],
+    fs: &dyn Fs,
+) -> EnvMgrResult<()> {
+    for (kind, outcome) in outcomes {
+        for (source, target) in &outcome.files.files_to_link {
+            if let Some(parent) = target.parent() {
+                fs.create_dir_all(parent)?;
+            }
+            fs.replace_symlink(source, target)?;
+            log::info!(
+                "{kind}: linked {} to {}",
+                target.display(),
+                source.display()
+            );
+        }
+        match outcome.env.env_vars.is_empty() {
+            true => state.integration_env_vars.remove(kind.config_key()),
+            false => state
+                .integration_env_vars
+                .insert(kind.config_key().to_string(), outcome.env.env_vars.clone()),
+        };
+    }
+    Ok(())
+}
+
+/// The env vars `state` recorded for the integrations, marked like
+/// [`integration_env_vars`]
+pub fn recorded_env_vars(state: &State) -> Vec<EnvVarsConfig> {
+    IntegrationKind::ALL
+        .into_iter()
+        .filter_map(|kind| Some((kind, state.integration_env_vars.get(kind.config_key())?)))
+        .flat_map(|(kind, vars)| {
+            vars.iter().map(move |(key, value)| EnvVarsConfig {
+                key: key.clone(),
+                value: value.clone(),
+                integration: Some(kind.config_key()),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+/// Whether applying an integration had anything to do
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ApplyOutcome {
+    /// Reality already matched the config; nothing was written or run
+    #[default]
+    AlreadyInDesiredState,
+    Changed,
+}
+
+impl std::fmt::Display for ApplyOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ApplyOutcome::AlreadyInDesiredState => "already in desired state",
+            ApplyOutcome::Changed => "changed",
+        })
+    }
+}
+
+/// Write `content` to `path` unless it already holds exactly these bytes
+pub fn write_if_changed(fs: &dyn Fs, path: &Path, content: &str) -> EnvMgrResult<ApplyOutcome> {
+    if fs.read(path)?.as_deref() == Some(content.as_bytes()) {
+        return Ok(ApplyOutcome::AlreadyInDesiredState);
+    }
+    fs.write_atomic(path, content.as_bytes())?;
+    Ok(ApplyOutcome::Changed)
+}
+
+/// The integrations envmgr knows about, named by their config key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum IntegrationKind {
+    #[value(name = "op_ssh")]
+    OpSsh,
+    #[value(name = "gh_cli")]
+    GhCli,
+    #[value(name = "git")]
+    Git,
+    #[value(name = "kube")]
+    Kube,
+    #[value(name = "aws")]
+    Aws,
+    #[value(name = "gcloud")]
+    Gcloud,
+    #[value(name = "npm")]
+    Npm,
+    #[value(name = "ssh")]
+    Ssh,
+    #[value(name = "gpg")]
+    Gpg,
+    #[value(name = "mise")]
+    Mise,
+    #[value(name = "maven")]
+    Maven,
+    #[value(name = "python")]
+    Python,
+    #[value(name = "cargo")]
+    Cargo,
+    #[value(name = "tailscale")]
+    Tailscale,
+}
+
+impl IntegrationKind {
+    /// All integrations, in the order a switch applies them
+    pub const ALL: [IntegrationKind; 14] = [
+        IntegrationKind::OpSsh,
+        IntegrationKind::GhCli,
+        IntegrationKind::Git,
+        IntegrationKind::Kube,
+        IntegrationKind::Aws,
+        IntegrationKind::Gcloud,
+        IntegrationKind::Npm,
+        IntegrationKind::Ssh,
+        IntegrationKind::Gpg,
+        IntegrationKind::Mise,
+        IntegrationKind::Maven,
+        IntegrationKind::Python,
+        IntegrationKind::Cargo,
+        IntegrationKind::Tailscale,
+    ];
+
+    /// Key of the integration in `config.yaml`
+    pub fn config_key(self) -> &'static str {
+        match self {
+            IntegrationKind::OpSsh => "op_ssh",
+            IntegrationKind::GhCli => "gh_cli",
+            IntegrationKind::Git => "git",
+            IntegrationKind::Kube => "kube",
+            IntegrationKind::Aws => "aws",
+            IntegrationKind::Gcloud => "gcloud",
+            IntegrationKind::Npm => "npm",
+            IntegrationKind::Ssh => "ssh",
+            IntegrationKind::Gpg => "gpg",
+            IntegrationKind::Mise => "mise",
+            IntegrationKind::Maven => "maven",
+            IntegrationKind::Python => "python",
+            IntegrationKind::Cargo => "cargo",
+            IntegrationKind::Tailscale => "tailscale",
+        }
+    }
+
+    /// Executable the integration relies on, if any.
+    ///
+    /// The 1Password, git, npm and ssh integrations only write config files.
+    pub fn required_tool(self) -> Option<&'static str> {
+        match self {
+            IntegrationKind::OpSsh
+            | IntegrationKind::Git
+            | IntegrationKind::Npm
+            | IntegrationKind::Ssh
+            | IntegrationKind::Maven
+            | IntegrationKind::Python
+            | IntegrationKind::Cargo => None,
+            IntegrationKind::GhCli => Some("gh"),
+            IntegrationKind::Kube => Some("kubectl"),
+            IntegrationKind::Aws => Some("aws"),
+            IntegrationKind::Gcloud => Some("gcloud"),
+            IntegrationKind::Gpg => Some("gpg"),
+            IntegrationKind::Mise => Some("mise"),
+            IntegrationKind::Tailscale => Some("tailscale"),
+        }
+    }
+
+    pub fn is_configured_in(self, env: &Environment) -> bool {
+        match self {
+            IntegrationKind::OpSsh => env.one_password_ssh.is_some(),
+            IntegrationKind::GhCli => env.gh_cli.is_some(),
+            IntegrationKind::Git => env.git.is_some(),
+            IntegrationKind::Kube => env.kube.is_some(),
+            IntegrationKind::Aws => env.aws.is_some(),
+            IntegrationKind::Gcloud => env.gcloud.is_some(),
+            IntegrationKind::Npm => env.npm.is_some(),
+            IntegrationKind::Ssh => env.ssh.is_some(),
+            IntegrationKind::Gpg => env.gpg.is_some(),
+            IntegrationKind::Mise => env.mise.is_some(),
+            IntegrationKind::Maven => env.maven.is_some(),
+            IntegrationKind::Python => env.python.is_some(),
+            IntegrationKind::Cargo => env.cargo.is_some(),
+            IntegrationKind::Tailscale => env.tailscale.is_some(),
+        }
+    }
+
+    /// Describe what applying this integration for `env` would change
+    pub fn describe_actions(self, env: &Environment) -> Vec<String> {
+        match self.integration(&RealFs, &SystemRunner, false) {
+            Ok(integration) => integration.describe_switch_to(env),
+            Err(e) => vec![format!("apply it ({e})")],
+        }
+    }
+
+    /// Apply the integration's configuration for `env`, doing nothing when it isn't configured.
+    ///
+    /// `login` lets it run login commands that need the user, like `tailscale login`.
+    pub fn apply(self, env: &Environment, login: bool) -> EnvMgrResult<IntegrationOutcome> {
+        self.integration(&RealFs, &SystemRunner, login)?
+            .switch_to(env)
+    }
+
+    /// Undo the integration for `from` from the `snapshot` recorded before it was first
+    /// applied
+    pub fn switch_from(
+        self,
+        from: &Environment,
+        snapshot: Option<&str>,
+        login: bool,
+    ) -> EnvMgrResult<IntegrationOutcome> {
+        self.integration(&RealFs, &SystemRunner, login)?
+            .switch_from(from, snapshot)
+    }
+
+    /// When `switch` runs this integration unless `integration_order` in global.yaml
+    /// says otherwise: git after the others, since it can take gpg's key as its
+    /// signing key
+    pub fn default_order(self) -> i32 {
+        match self {
+            IntegrationKind::Git => 1,
+            _ => 0,
+        }
+    }
+
+    /// This integration from [`registry`]
+    pub fn integration<'a>(
+        self,
+        fs: &'a dyn Fs,
+        runner: &'a dyn CommandRunner,
+        login: bool,
+    ) -> EnvMgrResult<Box<dyn ConfiguredIntegration + 'a>> {
+        Ok(registry(fs, runner, login)?
+            .into_iter()
+            .find(|integration| integration.kind() == self)
+            .expect("every integration is in the registry"))
+    }
+
+    /// Undo the integration for an environment that doesn't configure it, see
+    /// [`Integration::clear`]
+    pub fn clear(self) -> EnvMgrResult<ApplyOutcome> {
+        self.integration(&RealFs, &SystemRunner, false)?.clear()
+    }
+
+    /// Describe what [`IntegrationKind::clear`] would change
+    pub fn describe_clear(self) -> Vec<String> {
+        match self.integration(&RealFs, &SystemRunner, false) {
+            Ok(integration) => integration.describe_clear(),
+            Err(_) => vec![],
+        }
+    }
+}
+
+impl std::fmt::Display for IntegrationKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.config_key())
+    }
+}
+
+/// Which integrations a run applies
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum IntegrationSelection {
+    #[default]
+    All,
+    /// Only these, e.g. from `switch --only`; empty for `--no-integrations`
+    Only(Vec<IntegrationKind>),
+}
+
+impl IntegrationSelection {
+    pub fn contains(&self, kind: IntegrationKind) -> bool {
+        match self {
+            IntegrationSelection::All => true,
+            IntegrationSelection::Only(kinds) => kinds.contains(&kind),
+        }
+    }
+}
+
+/// Integrations to apply for `env`: the selected ones it configures, in [`IntegrationKind::ALL`] order
+pub fn plan_integrations(
+    env: &Environment,
+    selection: &IntegrationSelection,
+) -> Vec<IntegrationKind> {
+    IntegrationKind::ALL
+        .into_iter()
+        .filter(|kind| selection.contains(*kind))
+        .filter(|kind| kind.is_configured_in(env))
+        .collect()
+}
+
+/// Apply `planned` integrations in order through `apply`, stopping at the first error.
+///
+/// `integrations run` goes through here, `switch` through [`rollback::run_steps`], which
+/// applies each integration the same way. Each outcome is logged as it happens and
+/// returned for summaries.
+pub fn execute_integrations(
+    env: &Environment,
+    planned: &[IntegrationKind],
+    mut apply: impl FnMut(IntegrationKind, &Environment) -> EnvMgrResult<IntegrationOutcome>,
+) -> EnvMgrResult<Vec<(IntegrationKind, IntegrationOutcome)>> {
+    let mut outcomes = vec![];
+    for kind in planned {
+        log::debug!("Applying integration {kind} for {}", env.key);
+        let outcome = apply(*kind, env)?;
+        log::info!("{kind}: {}", outcome.apply);
+        outcomes.push((*kind, outcome));
+    }
+    Ok(outcomes)
+}
+
+/// Find `tool` in the directories of a `PATH`-style `path_var`
+pub fn find_in_path(tool: &str, path_var: &OsStr) -> Option<PathBuf> {
+    std::env::split_paths(path_var).find_map(|dir| {
+        let candidates = [dir.join(tool), dir.join(format!("{tool}.exe"))];
+        candidates.into_iter().find(|c| is_executable(c))
+    })
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    path.metadata()
+        .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrations::{
+        gh_cli::{GhCliConfig, GhCliHostUser},
+        tailscale::TailscaleConfig,
+    };
+
+    fn env_with_gh_and_tailscale() -> Environment {
+        Environment {
+            key: "work".to_string(),
+            name: "Work".to_string(),
+            gh_cli: Some(GhCliConfig {
+                hosts: vec![GhCliHostUser {
+                    host: "github.com".to_string(),
+                    user: "alice-work".to_string(),
+                    git_protocol: None,
+                }],
+                ..Default::default()
+            }),
+            tailscale: Some(TailscaleConfig {
+                tailnet: "corp.ts.net".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_plan_integrations() {
+        let env = env_with_gh_and_tailscale();
+        assert_eq!(
+            plan_integrations(&env, &IntegrationSelection::All),
+            vec![IntegrationKind::GhCli, IntegrationKind::Tailscale]
+        );
+        assert_eq!(
+            plan_integrations(
+                &env,
+                &IntegrationSelection::Only(vec![IntegrationKind::Tailscale])
+            ),
+            vec![IntegrationKind::Tailscale]
+        );
+        assert!(
+            plan_integrations(
+                &env,
+                &IntegrationSelection::Only(vec![IntegrationKind::OpSsh])
+            )
+            .is_empty()
+        );
+        assert!(plan_integrations(&env, &IntegrationSelection::Only(vec![])).is_empty());
+    }
+
+    #[test]
+    fn test_execute_single_integration() {
+        let env = env_with_gh_and_tailscale();
+        let mut applied = vec![];
+
+        execute_integrations(
+            &env,
+            &plan_integrations(
+                &env,
+                &IntegrationSelection::Only(vec![IntegrationKind::GhCli]),
+            ),
+            |kind, env| {
+                applied.push((kind, env.key.clone()));
+                Ok(IntegrationOutcome::default())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(applied, vec![(IntegrationKind::GhCli, "work".to_string())]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_find_in_path() {
+        use std::fs;
+
+        let dir = std::env::temp_dir().join("envmgr_test_find_in_path");
+        let _ = fs::remove_dir_all(&dir);
+        let (empty, bin) = (dir.join("empty"), dir.join("bin"));
+        fs::create_dir_all(&empty).unwrap();
+        fs::create_dir_all(&bin).unwrap();
+        fs::write(bin.join("gh"), "").unwrap();
+        fs::write(bin.join("notes"), "").unwrap();
+        crate::platform::set_mode(&bin.join("gh"), 0o755).unwrap();
+        let path_var = std::env::join_paths([&empty, &bin]).unwrap();
+
+        assert_eq!(find_in_path("gh", &path_var), Some(bin.join("gh")));
+        assert_eq!(find_in_path("notes", &path_var), None);
+        assert_eq!(find_in_path("tailscale", &path_var), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_registry_finds_configs_like_the_kinds() {
+        let env = env_with_gh_and_tailscale();
+        let registry = registry(&RealFs, &SystemRunner, false).unwrap();
+
+        assert_eq!(
+            registry.iter().map(|i| i.kind()).collect::<Vec<_>>(),
+            IntegrationKind::ALL
+        );
+        for integration in &registry {
+            assert_eq!(
+                integration.is_configured_in(&env),
+                integration.kind().is_configured_in(&env),
+                "{}",
+                integration.kind()
+            );
+        }
+        // Without a config nothing runs, not even op
+        let op_ssh = &registry[0];
+        assert_eq!(
+            op_ssh.switch_to(&env).unwrap(),
+            IntegrationOutcome::default()
+        );
+        let mut out = String::new();
+        assert_eq!(op_ssh.check(&env, &mut out), 0);
+        assert_eq!(out, "op_ssh: not configured in work\n");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_contributions_are_linked_and_recorded() {
+        use std::fs;
+
+        let dir = std::env::temp_dir().join("envmgr_test_integration_contributions");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (source, target) = (
+            dir.join("source.conf"),
+            dir.join("nested").join("target.conf"),
+        );
+        fs::write(&source, "x").unwrap();
+        let outcome = IntegrationOutcome {
+            apply: ApplyOutcome::Changed,
+            env: OnUsePluginResult {
+                env_vars: vec![("GH_HOST".to_string(), "ghe.corp.com".to_string())],
+            },
+            files: OnSwitchToPluginResult {
+                files_to_link: vec![(source.clone(), target.clone())],
+            },
+        };
+        let mut state = State::default();
+
+        take_contributions(&mut state, &[(IntegrationKind::GhCli, outcome)], &RealFs).unwrap();
+
+        assert_eq!(fs::read_link(&target).unwrap(), source);
+        let [var] = &recorded_env_vars(&state)[..] else {
+            panic!("expected the GH_HOST var: {state:?}");
+        };
+        assert_eq!(
+            (var.key.as_str(), var.value.as_str(), var.integration),
+            ("GH_HOST", "ghe.corp.com", Some("gh_cli"))
+        );
+
+        // A run handing back nothing drops what the last one recorded
+        take_contributions(
+            &mut state,
+            &[(IntegrationKind::GhCli, IntegrationOutcome::default())],
+            &RealFs,
+        )
+        .unwrap();
+        assert!(recorded_env_vars(&state).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
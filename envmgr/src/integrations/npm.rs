@@ -0,0 +1,533 @@
+//! npm registries per environment.
+//!
+//! Either the registry keys are written into a marked block of `~/.npmrc`, leaving
+//! every other line alone, or `~/.npmrc` is a link to an npmrc kept in the environment
+//! dir. Switching to an environment without npm config removes only the block or link.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+
+use crate::{
+    environment::{Environment, OpCli, SECRET_REFERENCE_PREFIX},
+    error::{EnvMgrError, EnvMgrResult},
+    fs::Fs,
+    integrations::{
+        ApplyOutcome, Integration, IntegrationKind, IntegrationOutcome,
+        marked_block::{self, Markers},
+        write_if_changed,
+    },
+    platform,
+    runner::CommandRunner,
+};
+
+const BLOCK_START: &str =
+    "# >>> envmgr npm: written for the active environment, changes are overwritten";
+const BLOCK_END: &str = "# <<< envmgr npm";
+const MARKERS: Markers = Markers {
+    begin: BLOCK_START,
+    end: BLOCK_END,
+};
+/// The npmrc holds auth tokens, so whatever envmgr writes is private
+const NPMRC_MODE: u32 = 0o600;
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema, Default,
+)]
+pub struct NpmConfig {
+    /// Default registry, e.g. `https://npm.corp.example/`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registry: Option<String>,
+    /// Registry per scope, e.g. `{"@corp": "https://npm.corp.example/"}`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub scope_registries: BTreeMap<String, String>,
+    /// Auth token per registry URL; an `op://` reference is read with the 1Password CLI
+    /// on switch, so the token doesn't have to be in the config
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub auth_tokens: BTreeMap<String, String>,
+    /// npmrc linked to `~/.npmrc` instead of writing keys, relative to the environment
+    /// dir, e.g. `npm/npmrc-work`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub npmrc_source: Option<String>,
+}
+
+impl std::fmt::Display for NpmConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(source) = &self.npmrc_source {
+            return write!(f, "npmrc {source}");
+        }
+        let mut parts = vec![];
+        if let Some(registry) = &self.registry {
+            parts.push(format!("registry {registry}"));
+        }
+        parts.extend(
+            self.scope_registries
+                .iter()
+                .map(|(scope, registry)| format!("{scope} {registry}")),
+        );
+        if !self.auth_tokens.is_empty() {
+            parts.push(format!("{} token(s)", self.auth_tokens.len()));
+        }
+        f.write_str(&parts.join(", "))
+    }
+}
+
+/// The `//host/path/:_authToken` key npm reads the token of `registry` from
+fn auth_token_key(registry: &str) -> String {
+    let without_scheme = registry
+        .split_once("://")
+        .map_or(registry, |(_, rest)| rest);
+    let trailing = if without_scheme.ends_with('/') {
+        ""
+    } else {
+        "/"
+    };
+    format!("//{without_scheme}{trailing}:_authToken")
+}
+
+/// `content` with `block` in place of envmgr's block, appended when there is none yet.
+/// At the end, its keys win over the same keys further up.
+pub fn with_block(content: &str, block: &str) -> String {
+    MARKERS
+        .replace(content, block)
+        .unwrap_or_else(|| marked_block::append(content, block))
+}
+
+pub struct Npm;
+
+impl Npm {
+    pub fn npmrc_path(home: &Path) -> PathBuf {
+        home.join(".npmrc")
+    }
+
+    /// The marked block for `config`, with the `tokens` already resolved
+    pub fn render_block(config: &NpmConfig, tokens: &BTreeMap<String, String>) -> String {
+        let mut out = format!("{BLOCK_START}\n");
+        if let Some(registry) = &config.registry {
+            out.push_str(&format!("registry={registry}\n"));
+        }
+        for (scope, registry) in &config.scope_registries {
+            out.push_str(&format!("{scope}:registry={registry}\n"));
+        }
+        for (registry, token) in tokens {
+            out.push_str(&format!("{}={token}\n", auth_token_key(registry)));
+        }
+        out.push_str(BLOCK_END);
+        out.push('\n');
+        out
+    }
+
+    /// The auth tokens with `op://` references read through `op`
+    pub fn resolve_tokens(
+        config: &NpmConfig,
+        runner: &dyn CommandRunner,
+    ) -> EnvMgrResult<BTreeMap<String, String>> {
+        let references: Vec<&str> = config
+            .auth_tokens
+            .values()
+            .map(String::as_str)
+            .filter(|token| token.starts_with(SECRET_REFERENCE_PREFIX))
+            .collect();
+        let mut resolved = match references.is_empty() {
+            true => Default::default(),
+            false => OpCli::new(runner).read_all(&references),
+        };
+        let mut tokens = BTreeMap::new();
+        for (registry, token) in &config.auth_tokens {
+            let value = match resolved.remove(token) {
+                Some(Ok(value)) => value,
+                Some(Err(e)) => {
+                    return Err(EnvMgrError::Npm(format!(
+                        "the auth token of {registry} could not be read: {e}"
+                    )));
+                }
+                None => token.clone(),
+            };
+            tokens.insert(registry.clone(), value);
+        }
+        Ok(tokens)
+    }
+
+    /// Whether `path` is a link envmgr placed, i.e. one into the config dir
+    fn is_managed_link(path: &Path, config_dir: &Path) -> bool {
+        std::fs::read_link(path).is_ok_and(|source| source.starts_with(config_dir))
+    }
+
+    /// Apply `config`: link `npmrc_source` from `env_dir` or write the block
+    pub fn on_switch_to(
+        config: &NpmConfig,
+        env_dir: &Path,
+        home: &Path,
+        config_dir: &Path,
+        fs: &dyn Fs,
+        runner: &dyn CommandRunner,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        let npmrc = Self::npmrc_path(home);
+        if let Some(source) = &config.npmrc_source {
+            return Self::link_npmrc(&env_dir.join(source), &npmrc, config_dir, fs);
+        }
+        let tokens = Self::resolve_tokens(config, runner)?;
+        let block = Self::render_block(config, &tokens);
+        let mut outcome = ApplyOutcome::AlreadyInDesiredState;
+        if Self::is_managed_link(&npmrc, config_dir) {
+            // Writing through it would change another environment's npmrc
+            fs.remove_file(&npmrc)?;
+            outcome = ApplyOutcome::Changed;
+        }
+        // A linked npmrc, e.g. from a dotfiles repo, is edited where it points
+        let path = std::fs::canonicalize(&npmrc).unwrap_or(npmrc);
+        let current = Self::read_npmrc(&path, fs)?;
+        let updated = with_block(&current, &block);
+        if updated != current {
+            debug!("Writing the npm block to {}", path.display());
+            fs.write_atomic_with_mode(&path, updated.as_bytes(), NPMRC_MODE)?;
+            outcome = ApplyOutcome::Changed;
+        }
+        Ok(outcome)
+    }
+
+    fn link_npmrc(
+        source: &Path,
+        npmrc: &Path,
+        config_dir: &Path,
+        fs: &dyn Fs,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        if !platform::SUPPORTS_LINKING {
+            return Err(EnvMgrError::Unsupported(
+                "npmrc_source links ~/.npmrc, which is not supported on this platform yet".into(),
+            ));
+        }
+        if !source.is_file() {
+            return Err(EnvMgrError::Npm(format!(
+                "npmrc_source {} does not exist",
+                source.display()
+            )));
+        }
+        if std::fs::read_link(npmrc).is_ok_and(|current| current == source) {
+            return Ok(ApplyOutcome::AlreadyInDesiredState);
+        }
+        if npmrc.symlink_metadata().is_ok() && !Self::is_managed_link(npmrc, config_dir) {
+            let (rest, _) = MARKERS.strip(&Self::read_npmrc(npmrc, fs)?);
+            if !rest.trim().is_empty() {
+                return Err(EnvMgrError::Npm(format!(
+                    "{} has settings envmgr didn't write, move it away to link {} there",
+                    npmrc.display(),
+                    source.display()
+                )));
+            }
+            // Only envmgr's block, from an environment with registry keys
+            fs.remove_file(npmrc)?;
+        }
+        debug!("Linking {} to {}", npmrc.display(), source.display());
+        fs.replace_symlink(source, npmrc)?;
+        Ok(ApplyOutcome::Changed)
+    }
+
+    /// Remove what envmgr added to `~/.npmrc`: its link or its block
+    pub fn on_switch_away(
+        home: &Path,
+        config_dir: &Path,
+        fs: &dyn Fs,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        let npmrc = Self::npmrc_path(home);
+        if Self::is_managed_link(&npmrc, config_dir) {
+            fs.remove_file(&npmrc)?;
+            return Ok(ApplyOutcome::Changed);
+        }
+        let path = std::fs::canonicalize(&npmrc).unwrap_or(npmrc);
+        let current = Self::read_npmrc(&path, fs)?;
+        match MARKERS.strip(&current) {
+            (rest, true) => write_if_changed(fs, &path, &rest),
+            (_, false) => Ok(ApplyOutcome::AlreadyInDesiredState),
+        }
+    }
+
+    /// Describe what [`Npm::on_switch_away`] would change
+    pub fn describe_switch_away(home: &Path, config_dir: &Path) -> Option<String> {
+        let npmrc = Self::npmrc_path(home);
+        if Self::is_managed_link(&npmrc, config_dir) {
+            return Some(format!("remove the link {}", npmrc.display()));
+        }
+        let content = std::fs::read_to_string(&npmrc).ok()?;
+        MARKERS
+            .strip(&content)
+            .1
+            .then(|| format!("remove the envmgr block from {}", npmrc.display()))
+    }
+
+    fn read_npmrc(path: &Path, fs: &dyn Fs) -> EnvMgrResult<String> {
+        match fs.read(path)? {
+            Some(bytes) => String::from_utf8(bytes)
+                .map_err(|_| EnvMgrError::Npm(format!("{} is not valid UTF-8", path.display()))),
+            None => Ok(String::new()),
+        }
+    }
+}
+
+/// [`Npm`] behind [`Integration`]
+pub struct NpmIntegration<'a> {
+    pub home: PathBuf,
+    /// Where the npmrc of the user's own is kept while `~/.npmrc` is a link
+    pub config_dir: PathBuf,
+    pub fs: &'a dyn Fs,
+    pub runner: &'a dyn CommandRunner,
+}
+
+impl Integration for NpmIntegration<'_> {
+    type Config = NpmConfig;
+
+    fn name(&self) -> IntegrationKind {
+        IntegrationKind::Npm
+    }
+
+    fn config<'e>(&self, env: &'e Environment) -> Option<&'e Self::Config> {
+        env.npm.as_ref()
+    }
+
+    fn on_switch_to(
+        &self,
+        env: &Environment,
+        config: &Self::Config,
+    ) -> EnvMgrResult<IntegrationOutcome> {
+        Npm::on_switch_to(
+            config,
+            &env.env_dir(),
+            &self.home,
+            &self.config_dir,
+            self.fs,
+            self.runner,
+        )
+        .map(Into::into)
+    }
+
+    fn describe_switch_to(&self, env: &Environment, config: &Self::Config) -> Vec<String> {
+        let npmrc = Npm::npmrc_path(&self.home);
+        // Tokens are only read from 1Password on switch, so the block is always
+        // listed as written
+        vec![match &config.npmrc_source {
+            Some(source) => {
+                let source = env.env_dir().join(source);
+                match std::fs::read_link(&npmrc) {
+                    Ok(current) if current == source => format!(
+                        "{} ({} links to {})",
+                        ApplyOutcome::AlreadyInDesiredState,
+                        npmrc.display(),
+                        source.display()
+                    ),
+                    _ => format!("link {} to {}", npmrc.display(), source.display()),
+                }
+            }
+            None => format!("write the envmgr block of {} ({config})", npmrc.display()),
+        }]
+    }
+
+    /// Registries and tokens must not leak into the next environment
+    fn clear(&self) -> EnvMgrResult<ApplyOutcome> {
+        Npm::on_switch_away(&self.home, &self.config_dir, self.fs)
+    }
+
+    fn describe_clear(&self) -> Vec<String> {
+        Npm::describe_switch_away(&self.home, &self.config_dir)
+            .into_iter()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, fs, io};
+
+    use super::*;
+    use crate::{fs::RealFs, runner::CommandOutput};
+
+    /// Reads `op://Work/npm/token` as `s3cret`
+    #[derive(Default)]
+    struct FakeOp {
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl CommandRunner for FakeOp {
+        fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+            assert_eq!(program, "op");
+            self.calls.borrow_mut().push(args.join(" "));
+            Ok(match args.last() {
+                Some(&"op://Work/npm/token") => CommandOutput {
+                    success: true,
+                    stdout: "s3cret".to_string(),
+                    ..Default::default()
+                },
+                _ => CommandOutput {
+                    stderr: "no such item".to_string(),
+                    ..Default::default()
+                },
+            })
+        }
+    }
+
+    fn temp_dirs(name: &str) -> (PathBuf, PathBuf, PathBuf) {
+        let root = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&root);
+        let (home, config_dir) = (root.join("home"), root.join("config"));
+        fs::create_dir_all(&home).unwrap();
+        fs::create_dir_all(config_dir.join("environments/work/npm")).unwrap();
+        (root, home, config_dir)
+    }
+
+    fn work() -> NpmConfig {
+        NpmConfig {
+            registry: Some("https://npm.corp.example/".to_string()),
+            scope_registries: BTreeMap::from([(
+                "@corp".to_string(),
+                "https://npm.corp.example/".to_string(),
+            )]),
+            auth_tokens: BTreeMap::from([(
+                "https://npm.corp.example".to_string(),
+                "op://Work/npm/token".to_string(),
+            )]),
+            npmrc_source: None,
+        }
+    }
+
+    const WORK_BLOCK: &str = "registry=https://npm.corp.example/\n\
+        @corp:registry=https://npm.corp.example/\n\
+        //npm.corp.example/:_authToken=s3cret\n";
+
+    #[test]
+    fn test_block_is_replaced_in_place() {
+        let block = format!("{BLOCK_START}\nregistry=https://a/\n{BLOCK_END}\n");
+        let content = "save-exact=true\n";
+
+        let added = with_block(content, &block);
+        assert_eq!(added, format!("save-exact=true\n{block}"));
+        let moved = format!("{added}fund=false\n");
+        let other = format!("{BLOCK_START}\nregistry=https://b/\n{BLOCK_END}\n");
+        assert_eq!(
+            with_block(&moved, &other),
+            format!("save-exact=true\n{other}fund=false\n")
+        );
+        assert_eq!(
+            MARKERS.strip(&moved),
+            ("save-exact=true\nfund=false\n".to_string(), true)
+        );
+        assert_eq!(MARKERS.strip(content), (content.to_string(), false));
+    }
+
+    #[test]
+    fn test_tokens_are_read_from_1password() {
+        let op = FakeOp::default();
+        let mut config = work();
+        config
+            .auth_tokens
+            .insert("https://plain.example/".to_string(), "abc".to_string());
+
+        let tokens = Npm::resolve_tokens(&config, &op).unwrap();
+
+        assert_eq!(tokens["https://npm.corp.example"], "s3cret");
+        assert_eq!(tokens["https://plain.example/"], "abc");
+        assert_eq!(op.calls.borrow().len(), 1);
+
+        config.auth_tokens.insert(
+            "https://other.example/".to_string(),
+            "op://Work/missing/token".to_string(),
+        );
+        let Err(EnvMgrError::Npm(message)) = Npm::resolve_tokens(&config, &op) else {
+            panic!("expected an error for an unreadable token");
+        };
+        assert!(message.contains("https://other.example/"), "{message}");
+    }
+
+    #[test]
+    fn test_switch_writes_and_removes_the_block() {
+        let (root, home, config_dir) = temp_dirs("envmgr_test_npm_block");
+        let env_dir = config_dir.join("environments/work");
+        let npmrc = Npm::npmrc_path(&home);
+        fs::write(
+            &npmrc,
+            "save-exact=true\nregistry=https://registry.npmjs.org/\n",
+        )
+        .unwrap();
+        let op = FakeOp::default();
+
+        let outcome = Npm::on_switch_to(&work(), &env_dir, &home, &config_dir, &RealFs, &op);
+
+        assert_eq!(outcome.unwrap(), ApplyOutcome::Changed);
+        assert_eq!(
+            fs::read_to_string(&npmrc).unwrap(),
+            format!(
+                "save-exact=true\nregistry=https://registry.npmjs.org/\n\
+                 {BLOCK_START}\n{WORK_BLOCK}{BLOCK_END}\n"
+            )
+        );
+        assert_eq!(
+            Npm::on_switch_to(&work(), &env_dir, &home, &config_dir, &RealFs, &op).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&npmrc).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, NPMRC_MODE);
+        }
+
+        assert_eq!(
+            Npm::on_switch_away(&home, &config_dir, &RealFs).unwrap(),
+            ApplyOutcome::Changed
+        );
+        assert_eq!(
+            fs::read_to_string(&npmrc).unwrap(),
+            "save-exact=true\nregistry=https://registry.npmjs.org/\n"
+        );
+        assert_eq!(
+            Npm::on_switch_away(&home, &config_dir, &RealFs).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_npmrc_source_is_linked() {
+        let (root, home, config_dir) = temp_dirs("envmgr_test_npm_link");
+        let env_dir = config_dir.join("environments/work");
+        let source = env_dir.join("npm/npmrc-work");
+        fs::write(&source, "registry=https://npm.corp.example/\n").unwrap();
+        let npmrc = Npm::npmrc_path(&home);
+        let config = NpmConfig {
+            npmrc_source: Some("npm/npmrc-work".to_string()),
+            ..Default::default()
+        };
+        let op = FakeOp::default();
+
+        // An npmrc of its own is not replaced
+        fs::write(&npmrc, "save-exact=true\n").unwrap();
+        let result = Npm::on_switch_to(&config, &env_dir, &home, &config_dir, &RealFs, &op);
+        assert!(matches!(result, Err(EnvMgrError::Npm(_))));
+        assert_eq!(fs::read_to_string(&npmrc).unwrap(), "save-exact=true\n");
+
+        // One holding only envmgr's block is
+        fs::remove_file(&npmrc).unwrap();
+        Npm::on_switch_to(&work(), &env_dir, &home, &config_dir, &RealFs, &op).unwrap();
+        let outcome = Npm::on_switch_to(&config, &env_dir, &home, &config_dir, &RealFs, &op);
+        assert_eq!(outcome.unwrap(), ApplyOutcome::Changed);
+        assert_eq!(fs::read_link(&npmrc).unwrap(), source);
+
+        // Keys for another environment don't end up in the linked file
+        Npm::on_switch_to(&work(), &env_dir, &home, &config_dir, &RealFs, &op).unwrap();
+        assert!(!npmrc.is_symlink());
+        assert_eq!(
+            fs::read_to_string(&source).unwrap(),
+            "registry=https://npm.corp.example/\n"
+        );
+
+        Npm::on_switch_to(&config, &env_dir, &home, &config_dir, &RealFs, &op).unwrap();
+        assert_eq!(
+            Npm::on_switch_away(&home, &config_dir, &RealFs).unwrap(),
+            ApplyOutcome::Changed
+        );
+        assert!(npmrc.symlink_metadata().is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,386 @@
+//! 1Password documents written into the home directory while an environment is active.
+//!
+//! A switch fetches all of them with `op document get` before it changes anything, so
+//! one failing fetch leaves every file as it was. Written documents are recorded with
+//! the hash of their content, and removed on switching away only while they still have
+//! it; like copies, edited documents are left alone.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, info, warn};
+
+use crate::{
+    environment::hash_content,
+    error::{EnvMgrError, EnvMgrResult},
+    fs::Fs,
+    integrations::ApplyOutcome,
+    runner::CommandRunner,
+    state::{ManagedDocument, State},
+};
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema,
+)]
+pub struct OnePasswordDocument {
+    pub vault: String,
+    /// Name or ID of the document item
+    pub item: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Where the document is written, e.g. `~/.kube/config-abc`
+    pub target: String,
+    /// Permission bits of the written file, e.g. `0o600`
+    #[serde(default = "default_mode")]
+    pub mode: u32,
+}
+
+fn default_mode() -> u32 {
+    0o600
+}
+
+impl OnePasswordDocument {
+    /// The target with a leading `~/` expanded to `home`
+    pub fn target_path(&self, home: &Path) -> PathBuf {
+        match self.target.strip_prefix("~/") {
+            Some(rest) => home.join(rest),
+            None if self.target == "~" => home.to_path_buf(),
+            None => PathBuf::from(&self.target),
+        }
+    }
+}
+
+impl std::fmt::Display for OnePasswordDocument {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.vault, self.item)
+    }
+}
+
+/// A document read from 1Password, not written yet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchedDocument {
+    pub target: PathBuf,
+    /// `vault/item` it was fetched from
+    pub item: String,
+    pub mode: u32,
+    pub content: String,
+}
+
+pub struct OnePasswordDocuments;
+
+impl OnePasswordDocuments {
+    /// Fetch every document, failing with all the ones that couldn't be fetched
+    pub fn fetch_all(
+        documents: &[OnePasswordDocument],
+        home: &Path,
+        runner: &dyn CommandRunner,
+    ) -> EnvMgrResult<Vec<FetchedDocument>> {
+        let mut fetched = vec![];
+        let mut failures = vec![];
+        for document in documents {
+            match Self::fetch(document, runner)? {
+                Ok(content) => fetched.push(FetchedDocument {
+                    target: document.target_path(home),
+                    item: document.to_string(),
+                    mode: document.mode,
+                    content,
+                }),
+                Err(reason) => failures.push(format!("{document}: {reason}")),
+            }
+        }
+        if !failures.is_empty() {
+            return Err(EnvMgrError::OnePassword(format!(
+                "{} document(s) could not be fetched, nothing was written: {}",
+                failures.len(),
+                failures.join("; ")
+            )));
+        }
+        Ok(fetched)
+    }
+
+    /// The content of `document`, or why `op` couldn't get it. `op` not being installed
+    /// is an error of its own, as no other document can be fetched either.
+    fn fetch(
+        document: &OnePasswordDocument,
+        runner: &dyn CommandRunner,
+    ) -> EnvMgrResult<Result<String, String>> {
+        let mut args = vec![
+            "document",
+            "get",
+            &document.item,
+            "--vault",
+            &document.vault,
+        ];
+        if let Some(account) = &document.account {
+            args.extend(["--account", account]);
+        }
+        debug!("Fetching 1Password document {document}");
+        match runner.run("op", &args) {
+            Ok(output) if output.success => Ok(Ok(output.stdout)),
+            Ok(output) => Ok(Err(format!(
+                "op document get failed: {}",
+                output.stderr.trim()
+            ))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(EnvMgrError::OnePassword(
+                "op (the 1Password CLI) is not installed, but op_documents needs it; install it \
+                     from https://developer.1password.com/docs/cli/get-started/ or switch with \
+                     --no-link to leave the documents out"
+                    .into(),
+            )),
+            Err(e) => Ok(Err(format!("op could not be run: {e}"))),
+        }
+    }
+
+    /// Write the `fetched` documents of `env_key` and remove the other documents of
+    /// `state`.
+    ///
+    /// New documents are recorded and `store`d before the first write, so an interrupted
+    /// run leaves no document the state doesn't know about. Files that envmgr didn't
+    /// write, or that were changed since, are neither overwritten nor removed.
+    pub fn apply(
+        state: &mut State,
+        env_key: &str,
+        fetched: &[FetchedDocument],
+        fs: &dyn Fs,
+        store: impl Fn(&State) -> EnvMgrResult<()>,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        let mut outcome = ApplyOutcome::AlreadyInDesiredState;
+        let written: HashMap<PathBuf, ManagedDocument> =
+            std::mem::take(&mut state.managed_documents)
+                .into_iter()
+                .map(|managed| (managed.target.clone(), managed))
+                .collect();
+
+        let mut pending = vec![];
+        for document in fetched {
+            let hash = hash_content(document.content.as_bytes());
+            let current = fs
+                .read(&document.target)?
+                .map(|current| hash_content(&current));
+            let previous = written.get(&document.target).map(|managed| &managed.hash);
+            match current {
+                Some(current) if current == hash => {
+                    debug!("Document is up to date: {}", document.target.display());
+                }
+                None => pending.push(document),
+                Some(current) if Some(&current) == previous => pending.push(document),
+                Some(_) => {
+                    let reason = match previous {
+                        Some(_) => "was changed since envmgr wrote it",
+                        None => "exists and was not written by envmgr",
+                    };
+                    warn!(
+                        "{} {reason}, not overwriting it with {}",
+                        document.target.display(),
+                        document.item
+                    );
+                    continue;
+                }
+            }
+            state.managed_documents.push(ManagedDocument {
+                target: document.target.clone(),
+                item: document.item.clone(),
+                env_key: env_key.to_string(),
+                hash,
+            });
+        }
+        if !pending.is_empty() {
+            store(state)?;
+        }
+        for document in pending {
+            info!(
+                "Writing 1Password document {} to {}",
+                document.item,
+                document.target.display()
+            );
+            fs.write_atomic_with_mode(
+                &document.target,
+                document.content.as_bytes(),
+                document.mode,
+            )?;
+            outcome = ApplyOutcome::Changed;
+        }
+
+        let mut stale: Vec<_> = written
+            .into_values()
+            .filter(|managed| {
+                !fetched
+                    .iter()
+                    .any(|document| document.target == managed.target)
+            })
+            .collect();
+        stale.sort_by(|a, b| a.target.cmp(&b.target));
+        for managed in stale {
+            match fs.read(&managed.target)? {
+                None => debug!("Document is already gone: {}", managed.target.display()),
+                Some(current) if hash_content(&current) == managed.hash => {
+                    info!(
+                        "Removing 1Password document {} from {}",
+                        managed.item,
+                        managed.target.display()
+                    );
+                    fs.remove_file(&managed.target)?;
+                    outcome = ApplyOutcome::Changed;
+                }
+                Some(_) => warn!(
+                    "{} was changed since envmgr wrote it, leaving it in place",
+                    managed.target.display()
+                ),
+            }
+        }
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io};
+
+    use super::*;
+    use crate::{fs::MemFs, runner::CommandOutput};
+
+    /// `op` holding `Work/kubeconfig` and `Work/npmrc`, or missing altogether
+    #[derive(Default)]
+    struct FakeOp {
+        missing: bool,
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl CommandRunner for FakeOp {
+        fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+            assert_eq!(program, "op");
+            self.calls.borrow_mut().push(args.join(" "));
+            if self.missing {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+            Ok(match args {
+                [
+                    "document",
+                    "get",
+                    item @ ("kubeconfig" | "npmrc"),
+                    "--vault",
+                    "Work",
+                    ..,
+                ] => CommandOutput {
+                    success: true,
+                    stdout: format!("{item} content\n"),
+                    stderr: String::new(),
+                },
+                _ => CommandOutput {
+                    success: false,
+                    stdout: String::new(),
+                    stderr: format!("[ERROR] \"{}\" isn't an item\n", args[2]),
+                },
+            })
+        }
+    }
+
+    fn document(item: &str, target: &str) -> OnePasswordDocument {
+        OnePasswordDocument {
+            vault: "Work".into(),
+            item: item.into(),
+            account: None,
+            target: target.into(),
+            mode: default_mode(),
+        }
+    }
+
+    fn fetch(documents: &[OnePasswordDocument]) -> Vec<FetchedDocument> {
+        OnePasswordDocuments::fetch_all(documents, Path::new("/home"), &FakeOp::default()).unwrap()
+    }
+
+    #[test]
+    fn test_fetch_all_or_nothing() {
+        let op = FakeOp::default();
+        let documents = [
+            OnePasswordDocument {
+                account: Some("me.1password.com".into()),
+                ..document("kubeconfig", "~/.kube/config-abc")
+            },
+            document("gone", "~/.gone"),
+            document("typo", "/etc/typo"),
+        ];
+        let err = OnePasswordDocuments::fetch_all(&documents, Path::new("/home"), &op).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "1Password Error: 2 document(s) could not be fetched, nothing was written: \
+             Work/gone: op document get failed: [ERROR] \"gone\" isn't an item; \
+             Work/typo: op document get failed: [ERROR] \"typo\" isn't an item"
+        );
+        assert_eq!(
+            op.calls.borrow()[0],
+            "document get kubeconfig --vault Work --account me.1password.com"
+        );
+
+        let fetched = fetch(&documents[..1]);
+        assert_eq!(
+            fetched,
+            [FetchedDocument {
+                target: "/home/.kube/config-abc".into(),
+                item: "Work/kubeconfig".into(),
+                mode: 0o600,
+                content: "kubeconfig content\n".into(),
+            }]
+        );
+
+        let missing = FakeOp {
+            missing: true,
+            ..Default::default()
+        };
+        let err =
+            OnePasswordDocuments::fetch_all(&documents, Path::new("/home"), &missing).unwrap_err();
+        assert!(matches!(err, EnvMgrError::OnePassword(_)));
+        assert!(
+            err.to_string()
+                .contains("op (the 1Password CLI) is not installed")
+        );
+        assert_eq!(missing.calls.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_documents_are_written_and_removed_on_switching_away() {
+        let fs = MemFs::default();
+        let mut state = State::default();
+        let stored = RefCell::new(vec![]);
+        let store = |state: &State| {
+            stored.borrow_mut().push(state.managed_documents.len());
+            Ok(())
+        };
+        let work = fetch(&[
+            document("kubeconfig", "~/.kube/config-abc"),
+            document("npmrc", "~/.npmrc"),
+        ]);
+
+        let outcome = OnePasswordDocuments::apply(&mut state, "work", &work, &fs, store).unwrap();
+        assert_eq!(outcome, ApplyOutcome::Changed);
+        // Recorded before anything was written
+        assert_eq!(*stored.borrow(), [2]);
+        let kubeconfig = Path::new("/home/.kube/config-abc");
+        assert_eq!(fs.content(kubeconfig).unwrap(), "kubeconfig content\n");
+        assert_eq!(fs.modes.borrow()[kubeconfig], 0o600);
+        assert_eq!(state.managed_documents[0].item, "Work/kubeconfig");
+        assert_eq!(state.managed_documents[1].env_key, "work");
+
+        let outcome = OnePasswordDocuments::apply(&mut state, "work", &work, &fs, store).unwrap();
+        assert_eq!(outcome, ApplyOutcome::AlreadyInDesiredState);
+        assert_eq!(fs.writes.borrow().len(), 2);
+
+        // An edited document stays when switching away, the other one goes
+        let npmrc = Path::new("/home/.npmrc");
+        fs.write(npmrc, b"edited").unwrap();
+        let outcome = OnePasswordDocuments::apply(&mut state, "base", &[], &fs, store).unwrap();
+        assert_eq!(outcome, ApplyOutcome::Changed);
+        assert_eq!(fs.content(kubeconfig), None);
+        assert_eq!(fs.content(npmrc).unwrap(), "edited");
+        assert!(state.managed_documents.is_empty());
+
+        // Neither is a file envmgr didn't write overwritten
+        let outcome = OnePasswordDocuments::apply(&mut state, "work", &work, &fs, store).unwrap();
+        assert_eq!(fs.content(npmrc).unwrap(), "edited");
+        assert_eq!(outcome, ApplyOutcome::Changed);
+        let targets: Vec<_> = state.managed_documents.iter().map(|m| &m.target).collect();
+        assert_eq!(targets, [kubeconfig]);
+    }
+}
@@ -1,6 +1,19 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+
 use crate::{
+    environment::Environment,
     error::{EnvMgrError, EnvMgrResult},
-    integrations::OnSwitchToPluginResult,
+    fs::Fs,
+    integrations::{
+        ApplyOutcome, Integration, IntegrationKind, IntegrationOutcome, write_if_changed,
+    },
+    runner::CommandRunner,
 };
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, schemars::JsonSchema, Default)]
@@ -8,23 +21,161 @@ pub struct OnePasswordSSHAgentConfig {
     pub keys: Vec<OnePasswordSSHKey>,
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema,
+)]
 pub struct OnePasswordSSHKey {
     pub vault: Option<String>,
     pub item: Option<String>,
     pub account: Option<String>,
 }
 
+impl std::fmt::Display for OnePasswordSSHKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts: Vec<String> = [
+            ("vault", &self.vault),
+            ("item", &self.item),
+            ("account", &self.account),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.as_ref().map(|value| format!("{name} '{value}'")))
+        .collect();
+        f.write_str(&parts.join(", "))
+    }
+}
+
+/// An SSH Key item as `op item list --format json` lists it
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+pub struct OpSshKeyItem {
+    pub id: String,
+    pub title: String,
+    pub vault: OpVaultRef,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+pub struct OpVaultRef {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+}
+
+impl OpSshKeyItem {
+    /// Whether the agent would serve this item for `key`, which names vaults and items
+    /// by name or id
+    fn matches(&self, key: &OnePasswordSSHKey) -> bool {
+        let vault = key
+            .vault
+            .as_deref()
+            .is_none_or(|vault| vault == self.vault.name || vault == self.vault.id);
+        let item = key
+            .item
+            .as_deref()
+            .is_none_or(|item| item == self.title || item == self.id);
+        vault && item
+    }
+}
+
+/// The SSH Key items 1Password has, so tests can hand in canned ones
+pub trait SshKeyItemSource {
+    /// The items of `account`, the default account for `None`. `Err` explains why they
+    /// can't be listed, e.g. `op` missing or signed out.
+    fn ssh_key_items(&self, account: Option<&str>) -> Result<Vec<OpSshKeyItem>, String>;
+}
+
+/// [`SshKeyItemSource`] running `op item list`
+pub struct OpItemList<'r> {
+    runner: &'r dyn CommandRunner,
+}
+
+impl<'r> OpItemList<'r> {
+    pub fn new(runner: &'r dyn CommandRunner) -> Self {
+        Self { runner }
+    }
+
+    pub fn parse(json: &str) -> Result<Vec<OpSshKeyItem>, String> {
+        serde_json::from_str(json).map_err(|e| format!("could not parse `op item list`: {e}"))
+    }
+}
+
+impl SshKeyItemSource for OpItemList<'_> {
+    fn ssh_key_items(&self, account: Option<&str>) -> Result<Vec<OpSshKeyItem>, String> {
+        let mut args = vec![
+            "item",
+            "list",
+            "--categories",
+            "SSH Key",
+            "--format",
+            "json",
+        ];
+        if let Some(account) = account {
+            args.extend(["--account", account]);
+        }
+        debug!("Running op {}", args.join(" "));
+        match self.runner.run("op", &args) {
+            Ok(output) if output.success => Self::parse(&output.stdout),
+            Ok(output) => Err(format!(
+                "op item list failed: {}. Sign in with `op signin{}`, or turn on \
+                 Settings > Developer > \"Integrate with 1Password CLI\" in the app",
+                output.stderr.trim().trim_end_matches('.'),
+                account
+                    .map(|a| format!(" --account {a}"))
+                    .unwrap_or_default()
+            )),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err("op (the 1Password CLI) is not installed, install it to check the keys".into())
+            }
+            Err(e) => Err(format!("op item list failed: {e}")),
+        }
+    }
+}
+
+/// Why a configured key doesn't resolve to the item it is meant for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyProblem {
+    /// No SSH Key item matches
+    Missing(OnePasswordSSHKey),
+    /// A key naming an item matches several, listed as `title (vault)`
+    Ambiguous(OnePasswordSSHKey, Vec<String>),
+}
+
+impl std::fmt::Display for KeyProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyProblem::Missing(key) => write!(f, "no SSH Key item matches {key}"),
+            KeyProblem::Ambiguous(key, matches) => {
+                write!(
+                    f,
+                    "{key} matches {} items: {}",
+                    matches.len(),
+                    matches.join(", ")
+                )
+            }
+        }
+    }
+}
+
+/// Outcome of checking the configured keys against 1Password
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyCheck {
+    pub problems: Vec<KeyProblem>,
+    /// Accounts whose items couldn't be listed, `None` for the default one, with why
+    pub unavailable: Vec<(Option<String>, String)>,
+}
+
 pub struct OnePasswordSSHAgent;
 
-#[derive(Debug, Clone, serde::Serialize)]
+const BLOCK_START: &str =
+    "# >>> envmgr op_ssh: keys of the active environment, changes are overwritten";
+const BLOCK_END: &str = "# <<< envmgr op_ssh";
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 struct OPAgentFile {
-    #[serde(rename = "ssh-keys")]
+    #[serde(rename = "ssh-keys", default)]
     ssh_keys: Vec<OnePasswordSSHKey>,
 }
 
 impl OnePasswordSSHAgent {
-    fn op_ssh_agent_file_path() -> EnvMgrResult<std::path::PathBuf> {
+    pub(crate) fn op_ssh_agent_file_path() -> EnvMgrResult<std::path::PathBuf> {
         let path = dirs::config_dir()
             .ok_or(EnvMgrError::DirError(
                 "Could not determine config directory".into(),
@@ -35,28 +186,749 @@ impl OnePasswordSSHAgent {
         Ok(path)
     }
 
-    fn ensure_op_ssh_agent_dir_exists() -> EnvMgrResult<()> {
-        let path = Self::op_ssh_agent_file_path()?;
-        if let Some(dir) = path.parent() {
-            std::fs::create_dir_all(dir)?;
+    /// The SSH keys currently configured in the 1Password `agent.toml`
+    pub fn current_keys() -> EnvMgrResult<Vec<OnePasswordSSHKey>> {
+        let content = std::fs::read_to_string(Self::op_ssh_agent_file_path()?)?;
+        Ok(Self::parse_agent_file(&content)?)
+    }
+
+    pub(crate) fn parse_agent_file(
+        content: &str,
+    ) -> Result<Vec<OnePasswordSSHKey>, toml::de::Error> {
+        Ok(toml::from_str::<OPAgentFile>(content)?.ssh_keys)
+    }
+
+    /// envmgr's block of `agent.toml` listing the keys of `config`, empty without keys
+    pub fn render_block(config: &OnePasswordSSHAgentConfig) -> EnvMgrResult<String> {
+        if config.keys.is_empty() {
+            return Ok(String::new());
+        }
+        let keys = toml::to_string_pretty(&OPAgentFile {
+            ssh_keys: config.keys.clone(),
+        })?;
+        Ok(format!("{BLOCK_START}\n{}\n{BLOCK_END}\n", keys.trim_end()))
+    }
+
+    /// `content` of `agent.toml` with envmgr's block replaced by the keys of `config`,
+    /// keeping everything else. A new block goes first, so the agent offers the
+    /// environment's keys before the others.
+    ///
+    /// envmgr used to write the whole file, so one that is exactly what it wrote then
+    /// is replaced whole.
+    pub fn render_agent_file(
+        content: &str,
+        config: &OnePasswordSSHAgentConfig,
+    ) -> EnvMgrResult<String> {
+        let block = Self::render_block(config)?;
+        let has_block = content.lines().any(|line| line.trim_end() == BLOCK_START);
+        let written_by_envmgr = !has_block
+            && Self::parse_agent_file(content).is_ok_and(|keys| {
+                toml::to_string_pretty(&OPAgentFile { ssh_keys: keys })
+                    .is_ok_and(|old| old == content)
+            });
+        if written_by_envmgr {
+            return Ok(block);
         }
-        Ok(())
+
+        let mut out = String::new();
+        let mut in_block = false;
+        // A removed block takes the blank line separating it along
+        let mut after_removed_block = false;
+        for line in content.split_inclusive('\n') {
+            let trimmed = line.trim_end();
+            if trimmed == BLOCK_START {
+                in_block = true;
+                out.push_str(&block);
+            } else if in_block && trimmed == BLOCK_END {
+                in_block = false;
+                after_removed_block = block.is_empty();
+            } else if !in_block {
+                if !(after_removed_block && trimmed.is_empty()) {
+                    out.push_str(line);
+                }
+                after_removed_block = false;
+            }
+        }
+        if !has_block && !block.is_empty() {
+            let rest = out.trim_start_matches('\n');
+            out = match rest.is_empty() {
+                true => block,
+                false => format!("{block}\n{rest}"),
+            };
+        }
+        // Whatever is kept must still be an agent.toml the 1Password app can read
+        Self::parse_agent_file(&out)?;
+        Ok(out)
     }
+
+    /// Check every key of `config` resolves: one naming an item to exactly one SSH Key
+    /// item, one naming only a vault or account to at least one
+    pub fn check_keys(
+        config: &OnePasswordSSHAgentConfig,
+        source: &dyn SshKeyItemSource,
+    ) -> KeyCheck {
+        let mut by_account: BTreeMap<Option<&str>, Vec<&OnePasswordSSHKey>> = BTreeMap::new();
+        for key in &config.keys {
+            by_account
+                .entry(key.account.as_deref())
+                .or_default()
+                .push(key);
+        }
+        let mut check = KeyCheck::default();
+        for (account, keys) in by_account {
+            let items = match source.ssh_key_items(account) {
+                Ok(items) => items,
+                Err(reason) => {
+                    check
+                        .unavailable
+                        .push((account.map(str::to_string), reason));
+                    continue;
+                }
+            };
+            for key in keys {
+                let matches: Vec<&OpSshKeyItem> = items.iter().filter(|i| i.matches(key)).collect();
+                match matches.len() {
+                    0 => check.problems.push(KeyProblem::Missing(key.clone())),
+                    1 => {}
+                    _ if key.item.is_some() => check.problems.push(KeyProblem::Ambiguous(
+                        key.clone(),
+                        matches
+                            .iter()
+                            .map(|i| format!("{} ({})", i.title, i.vault.name))
+                            .collect(),
+                    )),
+                    // A whole vault or account is meant to serve several keys
+                    _ => {}
+                }
+            }
+        }
+        check
+    }
+
+    /// Write [`Self::check_keys`] for `doctor` to `out`, returning the number of problems.
+    /// Keys that can't be checked are warnings, not problems.
+    pub fn write_key_check(
+        config: &OnePasswordSSHAgentConfig,
+        source: &dyn SshKeyItemSource,
+        out: &mut String,
+    ) -> usize {
+        let check = Self::check_keys(config, source);
+        let unchecked = config
+            .keys
+            .iter()
+            .filter(|key| {
+                check
+                    .unavailable
+                    .iter()
+                    .any(|(account, _)| *account == key.account)
+            })
+            .count();
+        let resolved = config.keys.len() - check.problems.len() - unchecked;
+        let _ = writeln!(
+            out,
+            "op_ssh: {resolved} of {} key(s) resolve",
+            config.keys.len()
+        );
+        for problem in &check.problems {
+            let _ = writeln!(out, "  error: {problem}");
+        }
+        for (account, reason) in &check.unavailable {
+            let account = account.as_deref().unwrap_or("the default account");
+            let _ = writeln!(
+                out,
+                "  warning: could not check the keys of {account}: {reason}"
+            );
+        }
+        check.problems.len()
+    }
+
+    /// [`Self::check_keys`] for a switch: problems fail it, while keys that can't be
+    /// checked only warn
+    pub fn verify_keys(
+        config: &OnePasswordSSHAgentConfig,
+        source: &dyn SshKeyItemSource,
+    ) -> EnvMgrResult<()> {
+        let check = Self::check_keys(config, source);
+        for (account, reason) in &check.unavailable {
+            warn!(
+                "Could not check the 1Password SSH keys{}: {reason}",
+                account
+                    .as_ref()
+                    .map(|a| format!(" of account {a}"))
+                    .unwrap_or_default()
+            );
+        }
+        if check.problems.is_empty() {
+            return Ok(());
+        }
+        let problems: Vec<String> = check.problems.iter().map(KeyProblem::to_string).collect();
+        Err(EnvMgrError::OpSshKey(problems.join("; ")))
+    }
+
+    /// Where the 1Password app puts its SSH agent socket on this platform, relative to `home`
+    pub fn agent_socket_path(home: &Path) -> PathBuf {
+        if cfg!(target_os = "macos") {
+            home.join("Library")
+                .join("Group Containers")
+                .join("2BUA8C4S2C.com.1password")
+                .join("t")
+                .join("agent.sock")
+        } else {
+            home.join(".1password").join("agent.sock")
+        }
+    }
+
+    /// Find a running 1Password SSH agent.
+    ///
+    /// Prefers `ssh_auth_sock` when it points at an existing 1Password socket,
+    /// otherwise falls back to the platform's default location.
+    pub fn find_agent_socket(home: &Path, ssh_auth_sock: Option<&Path>) -> Option<PathBuf> {
+        if let Some(sock) = ssh_auth_sock
+            && sock.to_string_lossy().to_lowercase().contains("1password")
+            && sock.exists()
+        {
+            return Some(sock.to_path_buf());
+        }
+        let default = Self::agent_socket_path(home);
+        default.exists().then_some(default)
+    }
+
+    /// Warn when keys are configured but no agent is around to serve them
+    fn check_agent_running() {
+        // Windows serves the agent on a named pipe, which can't be probed like a file
+        if cfg!(windows) {
+            return;
+        }
+        let Some(home) = dirs::home_dir() else {
+            return;
+        };
+        let ssh_auth_sock = std::env::var_os("SSH_AUTH_SOCK").map(PathBuf::from);
+        let expected = Self::agent_socket_path(&home);
+        match Self::find_agent_socket(&home, ssh_auth_sock.as_deref()) {
+            None => warn!(
+                "1Password SSH agent socket not found at {}. The agent config is written, but \
+                 no keys will be served until you enable Settings > Developer > \
+                 \"Use the SSH agent\" in the 1Password app and set SSH_AUTH_SOCK={}",
+                expected.display(),
+                expected.display()
+            ),
+            Some(socket) if ssh_auth_sock.as_deref() != Some(socket.as_path()) => warn!(
+                "The 1Password SSH agent is running, but SSH_AUTH_SOCK does not point at it. \
+                 Set SSH_AUTH_SOCK={} so ssh uses the configured keys",
+                socket.display()
+            ),
+            Some(_) => {}
+        }
+    }
+
+    /// Replace envmgr's block of `agent.toml` with the keys of `config`, leaving the keys
+    /// configured outside envmgr and the file itself untouched when it already matches.
+    /// An empty `keys` list removes the block.
+    ///
+    /// Every write makes the 1Password app reload its agent config.
     pub fn on_switch_to(
         config: &OnePasswordSSHAgentConfig,
-    ) -> EnvMgrResult<OnSwitchToPluginResult> {
-        if config.keys.is_empty() {
-            return Ok(Default::default());
+        path: &Path,
+        fs: &dyn Fs,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        let current = match fs.read(path)? {
+            Some(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+            None if config.keys.is_empty() => return Ok(ApplyOutcome::AlreadyInDesiredState),
+            None => String::new(),
+        };
+        if !config.keys.is_empty() {
+            Self::check_agent_running();
         }
 
-        let content = toml::to_string_pretty(&OPAgentFile {
-            ssh_keys: config.keys.clone(),
-        })?;
+        let content = Self::render_agent_file(&current, config)?;
+        if content == current {
+            return Ok(ApplyOutcome::AlreadyInDesiredState);
+        }
+        write_if_changed(fs, path, &content)
+    }
+}
+
+/// [`OnePasswordSSHAgent`] behind [`Integration`]
+pub struct OnePasswordSSHAgentIntegration<'a> {
+    /// The 1Password app's `agent.toml`
+    pub path: PathBuf,
+    pub fs: &'a dyn Fs,
+    /// Where `doctor` looks the keys up
+    pub keys: Box<dyn SshKeyItemSource + 'a>,
+}
+
+impl Integration for OnePasswordSSHAgentIntegration<'_> {
+    type Config = OnePasswordSSHAgentConfig;
+
+    fn name(&self) -> IntegrationKind {
+        IntegrationKind::OpSsh
+    }
+
+    fn config<'e>(&self, env: &'e Environment) -> Option<&'e Self::Config> {
+        env.one_password_ssh.as_ref()
+    }
+
+    fn on_switch_to(
+        &self,
+        _env: &Environment,
+        config: &Self::Config,
+    ) -> EnvMgrResult<IntegrationOutcome> {
+        OnePasswordSSHAgent::on_switch_to(config, &self.path, self.fs).map(Into::into)
+    }
+
+    fn describe_switch_to(&self, _env: &Environment, config: &Self::Config) -> Vec<String> {
+        let keys = config.keys.len();
+        let current = match self.fs.read(&self.path) {
+            Ok(current) => current.map(|b| String::from_utf8_lossy(&b).into_owned()),
+            Err(e) => {
+                return vec![format!(
+                    "write agent.toml with {keys} key(s) (current file unreadable: {e})"
+                )];
+            }
+        };
+        let Some(current) = current else {
+            return vec![match keys {
+                0 => format!("{} (no keys)", ApplyOutcome::AlreadyInDesiredState),
+                _ => format!("write agent.toml with {keys} key(s)"),
+            }];
+        };
+        let kept = OnePasswordSSHAgent::render_agent_file(&current, &Default::default())
+            .and_then(|rest| Ok(OnePasswordSSHAgent::parse_agent_file(&rest)?.len()))
+            .unwrap_or(0);
+        vec![
+            match OnePasswordSSHAgent::render_agent_file(&current, config) {
+                Ok(rendered) if rendered == current => format!(
+                    "{} (agent.toml lists {keys} key(s) of envmgr, {kept} of yours)",
+                    ApplyOutcome::AlreadyInDesiredState
+                ),
+                Ok(_) if keys == 0 => {
+                    format!("remove envmgr's keys from agent.toml, keeping {kept} of yours")
+                }
+                Ok(_) => {
+                    format!("write envmgr's {keys} key(s) to agent.toml, keeping {kept} of yours")
+                }
+                Err(e) => {
+                    format!("update agent.toml with {keys} key(s) (current file unusable: {e})")
+                }
+            },
+        ]
+    }
+
+    /// The content of `agent.toml`, `None` when there is none yet
+    fn snapshot(&self, _config: &Self::Config) -> EnvMgrResult<Option<String>> {
+        Ok(self
+            .fs
+            .read(&self.path)?
+            .map(|bytes| String::from_utf8_lossy(&bytes).into_owned()))
+    }
+
+    /// Put the recorded `agent.toml` back. Without one there was no file, so only
+    /// envmgr's block is removed, keeping the keys added outside envmgr since.
+    fn on_switch_from(
+        &self,
+        _config: &Self::Config,
+        snapshot: Option<&str>,
+    ) -> EnvMgrResult<IntegrationOutcome> {
+        match snapshot {
+            Some(content) => write_if_changed(self.fs, &self.path, content).map(Into::into),
+            None => OnePasswordSSHAgent::on_switch_to(
+                &OnePasswordSSHAgentConfig { keys: vec![] },
+                &self.path,
+                self.fs,
+            )
+            .map(Into::into),
+        }
+    }
+
+    fn doctor_check(&self, config: &Self::Config, out: &mut String) -> usize {
+        OnePasswordSSHAgent::write_key_check(config, self.keys.as_ref(), out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io};
+
+    use super::*;
+    use crate::{fs::MemFs, runner::CommandOutput};
+
+    /// Captured from op 2.30, shortened
+    const OP_ITEM_LIST: &str = r#"[
+      {
+        "id": "3cqzkpuq4bg2yoyxgstrvyfnxa",
+        "title": "GitHub",
+        "version": 2,
+        "vault": {"id": "tvkpqqvbo2gbsd4wqkkqmwtmhq", "name": "Work"},
+        "category": "SSH_KEY",
+        "last_edited_by": "QJ2JGTCQIRBDHD5PHAUIMGPZTA",
+        "created_at": "2024-03-11T09:12:43Z",
+        "updated_at": "2024-03-11T09:12:43Z",
+        "additional_information": "SHA256:4Mn5KGYXbOu4ZSpTqVcNRQXtQkaSVXYHH1F/ygx2Hfo"
+      },
+      {
+        "id": "ykeqyb6xqdqb3bkxzqswqygcyq",
+        "title": "Deploy key",
+        "version": 1,
+        "vault": {"id": "tvkpqqvbo2gbsd4wqkkqmwtmhq", "name": "Work"},
+        "category": "SSH_KEY",
+        "created_at": "2024-05-02T14:01:09Z",
+        "updated_at": "2024-05-02T14:01:09Z"
+      }
+    ]"#;
+
+    /// `op` signed in to the default account only
+    struct FakeOp;
+
+    impl CommandRunner for FakeOp {
+        fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+            assert_eq!(program, "op");
+            assert_eq!(
+                args[..6],
+                [
+                    "item",
+                    "list",
+                    "--categories",
+                    "SSH Key",
+                    "--format",
+                    "json"
+                ]
+            );
+            Ok(match args.get(7) {
+                None => CommandOutput {
+                    success: true,
+                    stdout: OP_ITEM_LIST.to_string(),
+                    ..Default::default()
+                },
+                Some(account) => CommandOutput {
+                    stderr: format!(
+                        "[ERROR] 2024/06/01 10:00:00 You are not currently signed in to {account}."
+                    ),
+                    ..Default::default()
+                },
+            })
+        }
+    }
+
+    struct NoOp;
+
+    impl CommandRunner for NoOp {
+        fn run(&self, _: &str, _: &[&str]) -> io::Result<CommandOutput> {
+            Err(io::ErrorKind::NotFound.into())
+        }
+    }
+
+    /// Written by hand, in the style of the example 1Password ships
+    const USER_AGENT_TOML: &str = indoc::indoc! {r#"
+        # This is the 1Password SSH agent config file, which allows you to customize the
+        # behavior of the SSH agent running on this machine.
+
+        # Personal key, always offered
+        [[ssh-keys]]
+        item = "Personal SSH Key"
+        vault = "Private" # not the shared one
+
+        [[ssh-keys]]
+        account = "family.1password.com"
+    "#};
+
+    fn key(vault: &str, item: Option<&str>) -> OnePasswordSSHKey {
+        OnePasswordSSHKey {
+            vault: Some(vault.to_string()),
+            item: item.map(str::to_string),
+            account: None,
+        }
+    }
+
+    fn config(keys: Vec<OnePasswordSSHKey>) -> OnePasswordSSHAgentConfig {
+        OnePasswordSSHAgentConfig { keys }
+    }
+
+    fn temp_home(name: &str) -> PathBuf {
+        let home = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&home);
+        fs::create_dir_all(&home).unwrap();
+        home
+    }
+
+    #[test]
+    fn test_parse_agent_file() {
+        let keys = OnePasswordSSHAgent::parse_agent_file(
+            "[[ssh-keys]]\nvault = \"Work\"\n\n[[ssh-keys]]\nitem = \"GitHub\"\naccount = \"me\"\n",
+        )
+        .unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].vault.as_deref(), Some("Work"));
+        assert_eq!(keys[1].item.as_deref(), Some("GitHub"));
+        assert!(
+            OnePasswordSSHAgent::parse_agent_file("")
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_converged_agent_file_is_not_rewritten() {
+        let config = config(vec![key("Work", None)]);
+        let path = OnePasswordSSHAgent::op_ssh_agent_file_path().unwrap();
+        let rendered = OnePasswordSSHAgent::render_agent_file("", &config).unwrap();
+        let fs = MemFs::default().with_file(&path, &rendered);
+
+        let outcome = OnePasswordSSHAgent::on_switch_to(&config, &path, &fs).unwrap();
+
+        assert_eq!(outcome, ApplyOutcome::AlreadyInDesiredState);
+        assert!(fs.writes.borrow().is_empty());
+
+        // A file envmgr wrote before it kept its keys in a block is taken over whole
+        let old = "[[ssh-keys]]\nvault = \"Personal\"\n";
+        let fs = MemFs::default().with_file(&path, old);
+        let outcome = OnePasswordSSHAgent::on_switch_to(&config, &path, &fs).unwrap();
+        assert_eq!(outcome, ApplyOutcome::Changed);
+        assert_eq!(fs.content(&path).unwrap(), rendered);
+        OnePasswordSSHAgent::on_switch_to(&config, &path, &fs).unwrap();
+        assert_eq!(fs.writes.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_user_keys_and_comments_survive() {
+        let work = config(vec![key("Work", None), key("Work", Some("Deploy key"))]);
+
+        let merged = OnePasswordSSHAgent::render_agent_file(USER_AGENT_TOML, &work).unwrap();
+
+        assert_eq!(
+            merged,
+            format!(
+                "{BLOCK_START}\n\
+                 [[ssh-keys]]\n\
+                 vault = \"Work\"\n\n\
+                 [[ssh-keys]]\n\
+                 vault = \"Work\"\n\
+                 item = \"Deploy key\"\n\
+                 {BLOCK_END}\n\n\
+                 {USER_AGENT_TOML}"
+            )
+        );
+        // The environment's keys come first, the user's follow unchanged
+        let keys = OnePasswordSSHAgent::parse_agent_file(&merged).unwrap();
+        assert_eq!(keys.len(), 4);
+        assert_eq!(keys[2].item.as_deref(), Some("Personal SSH Key"));
+
+        // Switching replaces only the block, in place
+        let home = config(vec![key("Home", None)]);
+        let switched = OnePasswordSSHAgent::render_agent_file(&merged, &home).unwrap();
+        assert!(switched.ends_with(USER_AGENT_TOML), "{switched}");
+        assert!(!switched.contains("Work"), "{switched}");
+        assert_eq!(
+            OnePasswordSSHAgent::render_agent_file(&switched, &home).unwrap(),
+            switched
+        );
+
+        // Edits next to the block are kept as well
+        let edited = switched.replace(
+            "# Personal key, always offered",
+            "# Personal key, offered after the environment's",
+        );
+        let back = OnePasswordSSHAgent::render_agent_file(&edited, &work).unwrap();
+        assert!(back.contains("offered after the environment's"), "{back}");
+        assert!(back.contains("vault = \"Work\""), "{back}");
+    }
+
+    #[test]
+    fn test_empty_key_list_clears_the_block() {
+        let path = OnePasswordSSHAgent::op_ssh_agent_file_path().unwrap();
+        let work = config(vec![key("Work", None)]);
+        let merged = OnePasswordSSHAgent::render_agent_file(USER_AGENT_TOML, &work).unwrap();
+        let fs = MemFs::default().with_file(&path, &merged);
+
+        let outcome = OnePasswordSSHAgent::on_switch_to(&config(vec![]), &path, &fs).unwrap();
+
+        assert_eq!(outcome, ApplyOutcome::Changed);
+        assert_eq!(fs.content(&path).unwrap(), USER_AGENT_TOML);
+        assert_eq!(
+            OnePasswordSSHAgent::on_switch_to(&config(vec![]), &path, &fs).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+
+        // Without a file there is nothing to clear, and none is created
+        let fs = MemFs::default();
+        assert_eq!(
+            OnePasswordSSHAgent::on_switch_to(&config(vec![]), &path, &fs).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        assert!(fs.writes.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_agent_file_is_not_overwritten() {
+        let path = OnePasswordSSHAgent::op_ssh_agent_file_path().unwrap();
+        let fs = MemFs::default().with_file(&path, "[[ssh-keys]\nvault = \"Broken\"\n");
+
+        let result =
+            OnePasswordSSHAgent::on_switch_to(&config(vec![key("Work", None)]), &path, &fs);
+
+        assert!(matches!(result, Err(EnvMgrError::TomlDeserialization(_))));
+        assert!(fs.writes.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_check_keys_against_op() {
+        let op = OpItemList::new(&FakeOp);
+        let items = op.ssh_key_items(None).unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[1].vault.name, "Work");
+
+        let config = config(vec![
+            key("Work", Some("GitHub")),
+            key(
+                "tvkpqqvbo2gbsd4wqkkqmwtmhq",
+                Some("ykeqyb6xqdqb3bkxzqswqygcyq"),
+            ),
+            key("Work", None),
+            key("Work", Some("Gitlab")),
+            OnePasswordSSHKey {
+                account: Some("corp.1password.com".to_string()),
+                ..key("Corp", None)
+            },
+        ]);
+
+        let check = OnePasswordSSHAgent::check_keys(&config, &op);
+
+        assert_eq!(
+            check.problems,
+            [KeyProblem::Missing(key("Work", Some("Gitlab")))]
+        );
+        let [(account, reason)] = &check.unavailable[..] else {
+            panic!("expected the corp account to be unavailable: {check:?}");
+        };
+        assert_eq!(account.as_deref(), Some("corp.1password.com"));
+        assert!(
+            reason.contains("op signin --account corp.1password.com"),
+            "{reason}"
+        );
+
+        let result = OnePasswordSSHAgent::verify_keys(&config, &op);
+        let Err(EnvMgrError::OpSshKey(message)) = result else {
+            panic!("expected the missing key to fail verification");
+        };
+        assert_eq!(
+            message,
+            "no SSH Key item matches vault 'Work', item 'Gitlab'"
+        );
+    }
+
+    #[test]
+    fn test_keys_that_cant_be_checked_only_warn() {
+        let config = config(vec![key("Work", Some("GitHub"))]);
+
+        let check = OnePasswordSSHAgent::check_keys(&config, &OpItemList::new(&NoOp));
+
+        assert!(check.problems.is_empty());
+        assert!(check.unavailable[0].1.contains("not installed"));
+        OnePasswordSSHAgent::verify_keys(&config, &OpItemList::new(&NoOp)).unwrap();
+    }
+
+    #[test]
+    fn test_find_agent_socket_absent() {
+        let home = temp_home("envmgr_test_op_socket_absent");
+        assert_eq!(OnePasswordSSHAgent::find_agent_socket(&home, None), None);
+        // SSH_AUTH_SOCK pointing at another agent doesn't count
+        let other = home.join("ssh-agent.sock");
+        fs::write(&other, "").unwrap();
+        assert_eq!(
+            OnePasswordSSHAgent::find_agent_socket(&home, Some(&other)),
+            None
+        );
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn test_find_agent_socket_present() {
+        let home = temp_home("envmgr_test_op_socket_present");
+        let socket = OnePasswordSSHAgent::agent_socket_path(&home);
+        fs::create_dir_all(socket.parent().unwrap()).unwrap();
+        fs::write(&socket, "").unwrap();
+
+        assert_eq!(
+            OnePasswordSSHAgent::find_agent_socket(&home, None),
+            Some(socket.clone())
+        );
+
+        let custom = home.join("1password-custom.sock");
+        fs::write(&custom, "").unwrap();
+        assert_eq!(
+            OnePasswordSSHAgent::find_agent_socket(&home, Some(&custom)),
+            Some(custom)
+        );
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn test_integration_switches_and_checks_like_the_agent() {
+        let config = config(vec![key("Work", None), key("Work", Some("Gitlab"))]);
+        let path = OnePasswordSSHAgent::op_ssh_agent_file_path().unwrap();
+        let direct = MemFs::default().with_file(&path, USER_AGENT_TOML);
+        let fs = MemFs::default().with_file(&path, USER_AGENT_TOML);
+        let integration = OnePasswordSSHAgentIntegration {
+            path: path.clone(),
+            fs: &fs,
+            keys: Box::new(OpItemList::new(&FakeOp)),
+        };
+
+        let expected = OnePasswordSSHAgent::on_switch_to(&config, &path, &direct).unwrap();
+
+        assert_eq!(
+            integration
+                .on_switch_to(&Environment::default(), &config)
+                .unwrap(),
+            expected.into()
+        );
+        assert_eq!(fs.content(&path), direct.content(&path));
+        let mut out = String::new();
+        assert_eq!(integration.doctor_check(&config, &mut out), 1);
+        assert_eq!(
+            out,
+            "op_ssh: 1 of 2 key(s) resolve\n  \
+             error: no SSH Key item matches vault 'Work', item 'Gitlab'\n"
+        );
+    }
 
-        Self::ensure_op_ssh_agent_dir_exists()?;
+    #[test]
+    fn test_switch_from_restores_the_recorded_file() {
+        let config = config(vec![key("Work", None)]);
+        let path = OnePasswordSSHAgent::op_ssh_agent_file_path().unwrap();
+        let fs = MemFs::default().with_file(&path, USER_AGENT_TOML);
+        let integration = OnePasswordSSHAgentIntegration {
+            path: path.clone(),
+            fs: &fs,
+            keys: Box::new(OpItemList::new(&FakeOp)),
+        };
 
-        std::fs::write(Self::op_ssh_agent_file_path()?, content)?;
+        let snapshot = Integration::snapshot(&integration, &config).unwrap();
+        assert_eq!(snapshot.as_deref(), Some(USER_AGENT_TOML));
+        let env = Environment::default();
+        integration.on_switch_to(&env, &config).unwrap();
+        assert_ne!(fs.content(&path).as_deref(), Some(USER_AGENT_TOML));
+        let outcome = integration
+            .on_switch_from(&config, snapshot.as_deref())
+            .unwrap();
+        assert_eq!(outcome.apply, ApplyOutcome::Changed);
+        assert_eq!(fs.content(&path).as_deref(), Some(USER_AGENT_TOML));
 
-        Ok(Default::default())
+        // Without a file before, only the block goes and what was added since stays
+        let fs = MemFs::default();
+        let integration = OnePasswordSSHAgentIntegration {
+            fs: &fs,
+            ..integration
+        };
+        assert_eq!(Integration::snapshot(&integration, &config).unwrap(), None);
+        integration.on_switch_to(&env, &config).unwrap();
+        let with_user_key = format!(
+            "{}\n[[ssh-keys]]\nvault = \"Private\"\n",
+            fs.content(&path).unwrap()
+        );
+        fs.write(&path, with_user_key.as_bytes()).unwrap();
+        integration.on_switch_from(&config, None).unwrap();
+        let content = fs.content(&path).unwrap();
+        assert!(content.contains("vault = \"Private\""));
+        assert!(!content.contains("vault = \"Work\""));
     }
 }
@@ -0,0 +1,580 @@
+//! Python package indexes per environment, for both pip and uv.
+//!
+//! pip gets `index-url` and `extra-index-url` in a marked block of the `[global]` section of
+//! `~/.config/pip/pip.conf`. pip rejects a key that is set twice, so the user's own index
+//! keys in `[global]` are commented out while the block is there and restored with it.
+//! uv gets `[[index]]` entries named `envmgr…` at the front of `~/.config/uv/uv.toml`,
+//! edited in place so everything else in the file keeps its formatting.
+
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+use toml_edit::{ArrayOfTables, DocumentMut, Item, Table, value};
+
+use crate::{
+    environment::Environment,
+    error::{EnvMgrError, EnvMgrResult},
+    fs::Fs,
+    integrations::{
+        ApplyOutcome, Integration, IntegrationKind, IntegrationOutcome, marked_block::Markers,
+    },
+};
+
+const BLOCK_START: &str =
+    "# >>> envmgr python: written for the active environment, changes are overwritten";
+const BLOCK_END: &str = "# <<< envmgr python";
+const MARKERS: Markers = Markers {
+    begin: BLOCK_START,
+    end: BLOCK_END,
+};
+/// Prefix of the user's own index keys while envmgr's block replaces them
+const DISABLED_PREFIX: &str = "# envmgr python disabled: ";
+/// Name of the default uv index envmgr writes, extra ones get a numbered suffix
+const UV_INDEX_NAME: &str = "envmgr";
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema, Default,
+)]
+pub struct PythonConfig {
+    /// Index replacing PyPI, e.g. `https://pypi.corp.example/simple`
+    pub index_url: String,
+    /// Indexes searched in addition to `index_url`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_index_urls: Vec<String>,
+    /// `~/.netrc` machine holding the index credentials, which pip and uv read from there.
+    /// Only checked on switch, so the credentials never have to be in the config.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub netrc_machine: Option<String>,
+}
+
+impl std::fmt::Display for PythonConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "index {}", self.index_url)?;
+        if !self.extra_index_urls.is_empty() {
+            write!(f, ", {} extra", self.extra_index_urls.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `line` sets one of the keys envmgr's block sets. pip treats `_` like `-`.
+fn is_index_key(line: &str) -> bool {
+    let Some((key, _)) = line.split_once(['=', ':']) else {
+        return false;
+    };
+    let key = key.trim().to_ascii_lowercase().replace('_', "-");
+    !line.starts_with(char::is_whitespace) && (key == "index-url" || key == "extra-index-url")
+}
+
+fn is_section_header(line: &str) -> bool {
+    let trimmed = line.trim();
+    trimmed.starts_with('[') && trimmed.ends_with(']')
+}
+
+/// `content` without envmgr's block and with the user's index keys enabled again, and
+/// whether anything of envmgr's was there
+pub fn strip_pip_conf(content: &str) -> (String, bool) {
+    let (rest, mut found) = MARKERS.strip(content);
+    let mut out = String::new();
+    for line in rest.split_inclusive('\n') {
+        match line.strip_prefix(DISABLED_PREFIX) {
+            Some(original) => {
+                out.push_str(original);
+                found = true;
+            }
+            None => out.push_str(line),
+        }
+    }
+    (out, found)
+}
+
+/// `content` with `block` at the top of the `[global]` section, which is added when
+/// there is none. The user's own index keys in `[global]` are disabled.
+pub fn with_pip_block(content: &str, block: &str) -> String {
+    let (content, _) = strip_pip_conf(content);
+    let mut out = String::new();
+    let mut in_global = false;
+    // Continuation lines of a disabled key belong to it
+    let mut in_disabled_key = false;
+    let mut inserted = false;
+    for line in content.split_inclusive('\n') {
+        if is_section_header(line) {
+            in_global = line.trim() == "[global]";
+            in_disabled_key = false;
+            out.push_str(line);
+            if in_global && !inserted {
+                if !line.ends_with('\n') {
+                    out.push('\n');
+                }
+                out.push_str(block);
+                inserted = true;
+            }
+            continue;
+        }
+        let is_continuation = line.starts_with(char::is_whitespace) && !line.trim().is_empty();
+        in_disabled_key = in_global && (is_index_key(line) || (in_disabled_key && is_continuation));
+        if in_disabled_key {
+            out.push_str(DISABLED_PREFIX);
+        }
+        out.push_str(line);
+    }
+    if !inserted {
+        if !out.is_empty() && !out.ends_with('\n') {
+            out.push('\n');
+        }
+        if !out.trim().is_empty() {
+            out.push('\n');
+        }
+        out.push_str("[global]\n");
+        out.push_str(block);
+    }
+    out
+}
+
+/// `content` with envmgr's `[[index]]` entries replaced by `indexes`, `(name, url)` with the
+/// default one first, ahead of the user's so uv prefers them
+pub fn with_uv_indexes(content: &str, indexes: &[(String, String)]) -> EnvMgrResult<String> {
+    let mut document: DocumentMut = content
+        .parse()
+        .map_err(|e| EnvMgrError::Python(format!("uv.toml is not valid TOML: {e}")))?;
+    let user_indexes: Vec<Table> = match document.get("index") {
+        None => vec![],
+        Some(Item::ArrayOfTables(tables)) => tables
+            .iter()
+            .filter(|table| !is_envmgr_index(table))
+            .cloned()
+            .collect(),
+        Some(_) => {
+            return Err(EnvMgrError::Python(
+                "`index` in uv.toml is not an array of tables".into(),
+            ));
+        }
+    };
+    let mut tables = ArrayOfTables::new();
+    for (i, (name, url)) in indexes.iter().enumerate() {
+        let mut table = Table::new();
+        table["name"] = value(name.as_str());
+        table["url"] = value(url.as_str());
+        if i == 0 {
+            table["default"] = value(true);
+        }
+        tables.push(table);
+    }
+    for table in user_indexes {
+        tables.push(table);
+    }
+    if tables.is_empty() {
+        document.remove("index");
+    } else {
+        document["index"] = Item::ArrayOfTables(tables);
+    }
+    Ok(document.to_string())
+}
+
+fn is_envmgr_index(table: &Table) -> bool {
+    table
+        .get("name")
+        .and_then(Item::as_str)
+        .is_some_and(|name| {
+            name == UV_INDEX_NAME || name.starts_with(&format!("{UV_INDEX_NAME}-extra-"))
+        })
+}
+
+/// Whether the netrc `content` has an entry for `machine`
+fn netrc_has_machine(content: &str, machine: &str) -> bool {
+    let mut tokens = content.split_whitespace();
+    while let Some(token) = tokens.next() {
+        if token == "machine" && tokens.next() == Some(machine) {
+            return true;
+        }
+    }
+    false
+}
+
+pub struct Python;
+
+impl Python {
+    pub fn pip_conf_path(home: &Path) -> PathBuf {
+        home.join(".config").join("pip").join("pip.conf")
+    }
+
+    pub fn uv_toml_path(home: &Path) -> PathBuf {
+        home.join(".config").join("uv").join("uv.toml")
+    }
+
+    pub fn netrc_path(home: &Path) -> PathBuf {
+        std::env::var_os("NETRC")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| home.join(".netrc"))
+    }
+
+    /// The marked block of pip's `[global]` section for `config`
+    pub fn render_pip_block(config: &PythonConfig) -> String {
+        let mut out = format!("{BLOCK_START}\nindex-url = {}\n", config.index_url);
+        if !config.extra_index_urls.is_empty() {
+            out.push_str(&format!(
+                "extra-index-url = {}\n",
+                config.extra_index_urls.join(" ")
+            ));
+        }
+        out.push_str(BLOCK_END);
+        out.push('\n');
+        out
+    }
+
+    /// The `(name, url)` of the uv indexes for `config`, the default one first
+    pub fn uv_indexes(config: &PythonConfig) -> Vec<(String, String)> {
+        let mut indexes = vec![(UV_INDEX_NAME.to_string(), config.index_url.clone())];
+        for (i, url) in config.extra_index_urls.iter().enumerate() {
+            indexes.push((format!("{UV_INDEX_NAME}-extra-{}", i + 1), url.clone()));
+        }
+        indexes
+    }
+
+    /// Write the indexes of `config` to pip.conf and uv.toml
+    pub fn on_switch_to(
+        config: &PythonConfig,
+        home: &Path,
+        fs: &dyn Fs,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        if let Some(machine) = &config.netrc_machine {
+            let netrc = Self::netrc_path(home);
+            let content = std::fs::read_to_string(&netrc).unwrap_or_default();
+            if !netrc_has_machine(&content, machine) {
+                warn!(
+                    "{} has no machine {machine}, so pip and uv have no credentials for {}",
+                    netrc.display(),
+                    config.index_url
+                );
+            }
+        }
+        let block = Self::render_pip_block(config);
+        let pip = Self::update(&Self::pip_conf_path(home), fs, |content| {
+            Ok(with_pip_block(content, &block))
+        })?;
+        let indexes = Self::uv_indexes(config);
+        let uv = Self::update(&Self::uv_toml_path(home), fs, |content| {
+            with_uv_indexes(content, &indexes)
+        })?;
+        Ok(match (pip, uv) {
+            (ApplyOutcome::AlreadyInDesiredState, ApplyOutcome::AlreadyInDesiredState) => {
+                ApplyOutcome::AlreadyInDesiredState
+            }
+            _ => ApplyOutcome::Changed,
+        })
+    }
+
+    /// Remove envmgr's indexes from pip.conf and uv.toml, giving the user's own back
+    pub fn on_switch_away(home: &Path, fs: &dyn Fs) -> EnvMgrResult<ApplyOutcome> {
+        let pip = Self::update(&Self::pip_conf_path(home), fs, |content| {
+            Ok(strip_pip_conf(content).0)
+        })?;
+        let uv = Self::update(&Self::uv_toml_path(home), fs, |content| {
+            with_uv_indexes(content, &[])
+        })?;
+        Ok(match (pip, uv) {
+            (ApplyOutcome::AlreadyInDesiredState, ApplyOutcome::AlreadyInDesiredState) => {
+                ApplyOutcome::AlreadyInDesiredState
+            }
+            _ => ApplyOutcome::Changed,
+        })
+    }
+
+    /// Describe what [`Python::on_switch_away`] would change
+    pub fn describe_switch_away(home: &Path) -> Vec<String> {
+        let mut actions = vec![];
+        let pip_conf = Self::pip_conf_path(home);
+        if std::fs::read_to_string(&pip_conf).is_ok_and(|content| strip_pip_conf(&content).1) {
+            actions.push(format!(
+                "remove the envmgr block from {}",
+                pip_conf.display()
+            ));
+        }
+        let uv_toml = Self::uv_toml_path(home);
+        let has_indexes = std::fs::read_to_string(&uv_toml).is_ok_and(|content| {
+            with_uv_indexes(&content, &[]).is_ok_and(|stripped| stripped != content)
+        });
+        if has_indexes {
+            actions.push(format!(
+                "remove the envmgr indexes from {}",
+                uv_toml.display()
+            ));
+        }
+        actions
+    }
+
+    /// Whether pip.conf and uv.toml already hold the indexes of `config`
+    pub fn is_converged(config: &PythonConfig, home: &Path) -> bool {
+        let pip = std::fs::read_to_string(Self::pip_conf_path(home)).is_ok_and(|content| {
+            with_pip_block(&content, &Self::render_pip_block(config)) == content
+        });
+        let uv = std::fs::read_to_string(Self::uv_toml_path(home)).is_ok_and(|content| {
+            with_uv_indexes(&content, &Self::uv_indexes(config)).is_ok_and(|new| new == content)
+        });
+        pip && uv
+    }
+
+    /// Rewrite the file at `path` with `edit`, removing it when nothing but whitespace or
+    /// an empty `[global]` section is left
+    fn update(
+        path: &Path,
+        fs: &dyn Fs,
+        edit: impl FnOnce(&str) -> EnvMgrResult<String>,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        // A linked file, e.g. from a dotfiles repo, is edited where it points
+        let path = std::fs::canonicalize(path).unwrap_or(path.to_path_buf());
+        let current = match fs.read(&path)? {
+            Some(bytes) => Some(String::from_utf8(bytes).map_err(|_| {
+                EnvMgrError::Python(format!("{} is not valid UTF-8", path.display()))
+            })?),
+            None => None,
+        };
+        let updated = edit(current.as_deref().unwrap_or_default())?;
+        let leftover = updated.trim();
+        if leftover.is_empty() || leftover == "[global]" {
+            if current.is_none() {
+                return Ok(ApplyOutcome::AlreadyInDesiredState);
+            }
+            debug!(
+                "Removing {}, only envmgr's indexes were in it",
+                path.display()
+            );
+            fs.remove_file(&path)?;
+            return Ok(ApplyOutcome::Changed);
+        }
+        if current.as_deref() == Some(updated.as_str()) {
+            return Ok(ApplyOutcome::AlreadyInDesiredState);
+        }
+        debug!("Writing the python indexes to {}", path.display());
+        fs.write_atomic(&path, updated.as_bytes())?;
+        Ok(ApplyOutcome::Changed)
+    }
+}
+
+/// [`Python`] behind [`Integration`]
+pub struct PythonIntegration<'a> {
+    pub home: PathBuf,
+    pub fs: &'a dyn Fs,
+}
+
+impl Integration for PythonIntegration<'_> {
+    type Config = PythonConfig;
+
+    fn name(&self) -> IntegrationKind {
+        IntegrationKind::Python
+    }
+
+    fn config<'e>(&self, env: &'e Environment) -> Option<&'e Self::Config> {
+        env.python.as_ref()
+    }
+
+    fn on_switch_to(
+        &self,
+        _env: &Environment,
+        config: &Self::Config,
+    ) -> EnvMgrResult<IntegrationOutcome> {
+        Python::on_switch_to(config, &self.home, self.fs).map(Into::into)
+    }
+
+    fn describe_switch_to(&self, _env: &Environment, config: &Self::Config) -> Vec<String> {
+        if Python::is_converged(config, &self.home) {
+            return vec![format!(
+                "{} ({config})",
+                ApplyOutcome::AlreadyInDesiredState
+            )];
+        }
+        vec![
+            format!(
+                "write the envmgr block of {} ({config})",
+                Python::pip_conf_path(&self.home).display()
+            ),
+            format!(
+                "write the envmgr indexes of {}",
+                Python::uv_toml_path(&self.home).display()
+            ),
+        ]
+    }
+
+    /// The indexes must not leak into the next environment
+    fn clear(&self) -> EnvMgrResult<ApplyOutcome> {
+        Python::on_switch_away(&self.home, self.fs)
+    }
+
+    fn describe_clear(&self) -> Vec<String> {
+        Python::describe_switch_away(&self.home)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::fs::RealFs;
+
+    fn temp_home(name: &str) -> PathBuf {
+        let home = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&home);
+        fs::create_dir_all(&home).unwrap();
+        home
+    }
+
+    fn work() -> PythonConfig {
+        PythonConfig {
+            index_url: "https://pypi.corp.example/simple".to_string(),
+            extra_index_urls: vec!["https://ml.corp.example/simple".to_string()],
+            netrc_machine: None,
+        }
+    }
+
+    const USER_PIP_CONF: &str = indoc::indoc! {"
+        [global]
+        timeout = 60
+        index_url = https://mirror.example/simple
+        extra-index-url =
+            https://a.example/simple
+            https://b.example/simple
+        require-virtualenv = true
+
+        [install]
+        index-url = https://install-only.example/simple
+    "};
+
+    const USER_UV_TOML: &str = indoc::indoc! {r#"
+        # my uv settings
+        native-tls = true
+
+        [[index]]
+        name = "mirror"   # keep this comment
+        url = "https://mirror.example/simple"
+
+        [pip]
+        index-url = "https://pip-only.example/simple"
+    "#};
+
+    #[test]
+    fn test_pip_block_disables_and_restores_user_keys() {
+        let block = Python::render_pip_block(&work());
+
+        let written = with_pip_block(USER_PIP_CONF, &block);
+
+        assert_eq!(
+            written,
+            format!(
+                "[global]\n{block}timeout = 60\n\
+                 {DISABLED_PREFIX}index_url = https://mirror.example/simple\n\
+                 {DISABLED_PREFIX}extra-index-url =\n\
+                 {DISABLED_PREFIX}    https://a.example/simple\n\
+                 {DISABLED_PREFIX}    https://b.example/simple\n\
+                 require-virtualenv = true\n\n\
+                 [install]\nindex-url = https://install-only.example/simple\n"
+            )
+        );
+        assert!(block.contains(
+            "index-url = https://pypi.corp.example/simple\n\
+             extra-index-url = https://ml.corp.example/simple\n"
+        ));
+        assert_eq!(with_pip_block(&written, &block), written);
+        assert_eq!(strip_pip_conf(&written), (USER_PIP_CONF.to_string(), true));
+        assert_eq!(
+            strip_pip_conf(USER_PIP_CONF),
+            (USER_PIP_CONF.to_string(), false)
+        );
+    }
+
+    #[test]
+    fn test_pip_block_adds_a_global_section() {
+        let block = Python::render_pip_block(&work());
+
+        assert_eq!(
+            with_pip_block("[install]\nno-cache-dir = true", &block),
+            format!("[install]\nno-cache-dir = true\n\n[global]\n{block}")
+        );
+        assert_eq!(with_pip_block("", &block), format!("[global]\n{block}"));
+    }
+
+    #[test]
+    fn test_uv_indexes_keep_user_content() {
+        let written = with_uv_indexes(USER_UV_TOML, &Python::uv_indexes(&work())).unwrap();
+
+        assert_eq!(
+            written,
+            indoc::indoc! {r#"
+                # my uv settings
+                native-tls = true
+
+                [[index]]
+                name = "envmgr"
+                url = "https://pypi.corp.example/simple"
+                default = true
+
+                [[index]]
+                name = "envmgr-extra-1"
+                url = "https://ml.corp.example/simple"
+
+                [[index]]
+                name = "mirror"   # keep this comment
+                url = "https://mirror.example/simple"
+
+                [pip]
+                index-url = "https://pip-only.example/simple"
+            "#}
+        );
+        assert_eq!(
+            with_uv_indexes(&written, &Python::uv_indexes(&work())).unwrap(),
+            written
+        );
+        assert_eq!(with_uv_indexes(&written, &[]).unwrap(), USER_UV_TOML);
+        assert!(matches!(
+            with_uv_indexes("index = 1\n", &[]),
+            Err(EnvMgrError::Python(_))
+        ));
+    }
+
+    #[test]
+    fn test_switch_writes_and_removes_the_indexes() {
+        let home = temp_home("envmgr_test_python_switch");
+        let pip_conf = Python::pip_conf_path(&home);
+        fs::create_dir_all(pip_conf.parent().unwrap()).unwrap();
+        fs::write(&pip_conf, USER_PIP_CONF).unwrap();
+        let uv_toml = Python::uv_toml_path(&home);
+
+        assert_eq!(
+            Python::on_switch_to(&work(), &home, &RealFs).unwrap(),
+            ApplyOutcome::Changed
+        );
+        assert!(Python::is_converged(&work(), &home));
+        assert!(
+            fs::read_to_string(&uv_toml)
+                .unwrap()
+                .starts_with("[[index]]\nname = \"envmgr\"\n")
+        );
+        assert_eq!(
+            Python::on_switch_to(&work(), &home, &RealFs).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        assert_eq!(Python::describe_switch_away(&home).len(), 2);
+
+        assert_eq!(
+            Python::on_switch_away(&home, &RealFs).unwrap(),
+            ApplyOutcome::Changed
+        );
+        assert_eq!(fs::read_to_string(&pip_conf).unwrap(), USER_PIP_CONF);
+        // uv.toml only had envmgr's indexes
+        assert!(!uv_toml.exists());
+        assert_eq!(
+            Python::on_switch_away(&home, &RealFs).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn test_netrc_machine_lookup() {
+        let netrc = "machine pypi.corp.example login ci password x\ndefault login anon\n";
+
+        assert!(netrc_has_machine(netrc, "pypi.corp.example"));
+        assert!(!netrc_has_machine(netrc, "ci"));
+        assert!(!netrc_has_machine(netrc, "other.example"));
+    }
+}
@@ -0,0 +1,209 @@
+//! Quarantine of integrations that keep failing for an environment.
+//!
+//! Consecutive failures are counted per (environment, integration) in the state.
+//! Once the count reaches the configured threshold, `switch` skips the integration
+//! for that environment until it is unquarantined or its config changes.
+
+use super::IntegrationKind;
+use crate::environment::Environment;
+
+/// Consecutive failures of one integration in one environment
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, Clone, PartialEq)]
+pub struct IntegrationFailures {
+    pub env: String,
+    /// Config key of the integration, e.g. `tailscale`
+    pub integration: String,
+    pub failures: u32,
+    /// Hash of the integration's config at the last failure, a different config starts over
+    pub config_hash: String,
+    pub quarantined: bool,
+}
+
+/// Hash of the config `kind` applies for `env`, empty when it isn't configured
+pub fn config_hash(kind: IntegrationKind, env: &Environment) -> String {
+    use sha2::{Digest, Sha256};
+
+    let config = match kind {
+        IntegrationKind::OpSsh => serde_json::to_vec(&env.one_password_ssh),
+        IntegrationKind::GhCli => serde_json::to_vec(&env.gh_cli),
+        IntegrationKind::Git => serde_json::to_vec(&env.git),
+        IntegrationKind::Kube => serde_json::to_vec(&env.kube),
+        IntegrationKind::Aws => serde_json::to_vec(&env.aws),
+        IntegrationKind::Gcloud => serde_json::to_vec(&env.gcloud),
+        IntegrationKind::Npm => serde_json::to_vec(&env.npm),
+        IntegrationKind::Ssh => serde_json::to_vec(&env.ssh),
+        IntegrationKind::Gpg => serde_json::to_vec(&env.gpg),
+        IntegrationKind::Mise => serde_json::to_vec(&env.mise),
+        IntegrationKind::Maven => serde_json::to_vec(&env.maven),
+        IntegrationKind::Python => serde_json::to_vec(&env.python),
+        IntegrationKind::Cargo => serde_json::to_vec(&env.cargo),
+        IntegrationKind::Tailscale => serde_json::to_vec(&env.tailscale),
+    };
+    config.map_or_else(
+        |_| String::new(),
+        |bytes| hex::encode(Sha256::digest(bytes)),
+    )
+}
+
+fn position(records: &[IntegrationFailures], env: &str, kind: IntegrationKind) -> Option<usize> {
+    records
+        .iter()
+        .position(|r| r.env == env && r.integration == kind.config_key())
+}
+
+/// The quarantine record of `kind` in `env`, if it is quarantined.
+///
+/// A record whose config hash no longer matches `hash` is dropped: the config was
+/// changed, presumably to fix the failure.
+pub fn quarantined<'a>(
+    records: &'a mut Vec<IntegrationFailures>,
+    env: &str,
+    kind: IntegrationKind,
+    hash: &str,
+) -> Option<&'a IntegrationFailures> {
+    let index = position(records, env, kind)?;
+    if records[index].config_hash != hash {
+        log::info!("{kind} config changed in {env}, clearing its failure count");
+        records.remove(index);
+        return None;
+    }
+    Some(&records[index]).filter(|r| r.quarantined)
+}
+
+/// Forget the failures of `kind` in `env` after it applied successfully
+pub fn record_success(records: &mut Vec<IntegrationFailures>, env: &str, kind: IntegrationKind) {
+    if let Some(index) = position(records, env, kind) {
+        records.remove(index);
+    }
+}
+
+/// Count a failure of `kind` in `env`, returning true when it just got quarantined.
+///
+/// A `threshold` of 0 never quarantines.
+pub fn record_failure(
+    records: &mut Vec<IntegrationFailures>,
+    env: &str,
+    kind: IntegrationKind,
+    hash: &str,
+    threshold: u32,
+) -> bool {
+    let index = match position(records, env, kind) {
+        Some(index) if records[index].config_hash == hash => index,
+        Some(index) => {
+            records.remove(index);
+            records.len()
+        }
+        None => records.len(),
+    };
+    if index == records.len() {
+        records.push(IntegrationFailures {
+            env: env.to_string(),
+            integration: kind.config_key().to_string(),
+            failures: 0,
+            config_hash: hash.to_string(),
+            quarantined: false,
+        });
+    }
+    let record = &mut records[index];
+    record.failures += 1;
+    let newly = !record.quarantined && threshold > 0 && record.failures >= threshold;
+    record.quarantined |= newly;
+    newly
+}
+
+/// Lift the quarantine of `kind` in `env`, returning whether it was quarantined
+pub fn unquarantine(
+    records: &mut Vec<IntegrationFailures>,
+    env: &str,
+    kind: IntegrationKind,
+) -> bool {
+    match position(records, env, kind) {
+        Some(index) => records.remove(index).quarantined,
+        None => false,
+    }
+}
+
+/// Notice printed by each switch that skips a quarantined integration
+pub fn quarantine_notice(record: &IntegrationFailures) -> String {
+    format!(
+        "QUARANTINED: {integration} failed {failures} times in a row in {env} and is skipped. \
+         Fix it, then run `envmgr integrations unquarantine {integration} --env {env}` \
+         (changing its config also lifts the quarantine)",
+        integration = record.integration,
+        failures = record.failures,
+        env = record.env,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TS: IntegrationKind = IntegrationKind::Tailscale;
+
+    #[test]
+    fn test_success_resets_counter() {
+        let mut records = vec![];
+        assert!(!record_failure(&mut records, "work", TS, "h1", 3));
+        assert!(!record_failure(&mut records, "work", TS, "h1", 3));
+        record_success(&mut records, "work", TS);
+        assert!(records.is_empty());
+
+        assert!(!record_failure(&mut records, "work", TS, "h1", 3));
+        assert!(!record_failure(&mut records, "work", TS, "h1", 3));
+        assert!(record_failure(&mut records, "work", TS, "h1", 3));
+        // Only reported once
+        assert!(!record_failure(&mut records, "work", TS, "h1", 3));
+        assert_eq!(records[0].failures, 4);
+        assert!(quarantined(&mut records, "work", TS, "h1").is_some());
+        assert!(quarantined(&mut records, "home", TS, "h1").is_none());
+        assert!(quarantined(&mut records, "work", IntegrationKind::GhCli, "h1").is_none());
+
+        assert!(unquarantine(&mut records, "work", TS));
+        assert!(!unquarantine(&mut records, "work", TS));
+        assert!(quarantined(&mut records, "work", TS, "h1").is_none());
+    }
+
+    #[test]
+    fn test_config_change_clears_quarantine() {
+        let mut records = vec![];
+        for _ in 0..3 {
+            record_failure(&mut records, "work", TS, "old", 3);
+        }
+        assert!(quarantined(&mut records, "work", TS, "old").is_some());
+
+        assert!(quarantined(&mut records, "work", TS, "new").is_none());
+        assert!(records.is_empty());
+
+        // A failure with a new config starts counting from scratch
+        record_failure(&mut records, "work", TS, "old", 3);
+        record_failure(&mut records, "work", TS, "new", 3);
+        assert_eq!(records[0].failures, 1);
+    }
+
+    #[test]
+    fn test_zero_threshold_never_quarantines() {
+        let mut records = vec![];
+        for _ in 0..10 {
+            assert!(!record_failure(&mut records, "work", TS, "h", 0));
+        }
+        assert!(quarantined(&mut records, "work", TS, "h").is_none());
+    }
+
+    #[test]
+    fn test_quarantine_notice() {
+        let record = IntegrationFailures {
+            env: "client".into(),
+            integration: "tailscale".into(),
+            failures: 3,
+            config_hash: "h".into(),
+            quarantined: true,
+        };
+        assert_eq!(
+            quarantine_notice(&record),
+            "QUARANTINED: tailscale failed 3 times in a row in client and is skipped. \
+             Fix it, then run `envmgr integrations unquarantine tailscale --env client` \
+             (changing its config also lifts the quarantine)"
+        );
+    }
+}
@@ -0,0 +1,650 @@
+//! What a switch does when an integration fails.
+//!
+//! A switch first plans its integration steps, each with the action that puts the
+//! integration back the way the outgoing environment had it. With
+//! [`FailurePolicy::Rollback`] the first error undoes the steps that ran, newest first;
+//! with [`FailurePolicy::ContinueOnError`] the remaining steps still run and every error
+//! is reported at the end.
+//!
+//! The steps run in batches, see [`run_steps`], each step of a batch on a thread of its
+//! own with a timeout, so one hung command fails its integration instead of the switch.
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
+    time::{Duration, Instant},
+};
+
+use super::{ApplyOutcome, IntegrationKind, IntegrationOutcome};
+use crate::{
+    environment::Environment,
+    error::{EnvMgrError, EnvMgrResult},
+};
+
+/// How a switch handles a failing integration
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FailurePolicy {
+    /// Stop at the first error and undo the integrations that already ran
+    #[default]
+    Rollback,
+    /// Run the remaining integrations anyway and report every error at the end
+    ContinueOnError,
+}
+
+/// One integration step of a switch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Undo what the outgoing environment applied, see [`super::execute_switch_from`]
+    SwitchFrom(IntegrationKind),
+    /// Apply the incoming environment's config
+    Apply(IntegrationKind),
+    /// Clear it, the incoming environment doesn't configure it
+    Clear(IntegrationKind),
+}
+
+impl Step {
+    pub fn kind(self) -> IntegrationKind {
+        match self {
+            Step::SwitchFrom(kind) | Step::Apply(kind) | Step::Clear(kind) => kind,
+        }
+    }
+}
+
+/// Puts an integration back the way the outgoing environment had it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UndoAction {
+    /// Apply the outgoing environment's config again
+    Reapply(IntegrationKind),
+    /// Put back the snapshot recorded before it was first applied
+    Restore(IntegrationKind),
+    /// Clear it, the outgoing environment doesn't configure it either
+    Clear(IntegrationKind),
+}
+
+impl UndoAction {
+    pub fn kind(self) -> IntegrationKind {
+        match self {
+            UndoAction::Reapply(kind) | UndoAction::Restore(kind) | UndoAction::Clear(kind) => kind,
+        }
+    }
+}
+
+/// A step with the action that undoes it, `None` when there is nothing to undo
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlannedStep {
+    pub step: Step,
+    pub undo: Option<UndoAction>,
+    /// Where its integration goes among the others, see [`IntegrationKind::default_order`]
+    pub order: i32,
+}
+
+impl PlannedStep {
+    /// The steps of a batch run at the same time: the same kind of step of integrations
+    /// with the same order
+    fn batch(&self) -> (u8, i32) {
+        let phase = match self.step {
+            Step::SwitchFrom(_) => 0,
+            Step::Apply(_) => 1,
+            Step::Clear(_) => 2,
+        };
+        (phase, self.order)
+    }
+}
+
+/// The steps of a switch from `outgoing` in the order they run: the integrations
+/// `undone` for the outgoing environment, the `planned` ones of the incoming one, then
+/// the `unconfigured` ones it clears. Within each, integrations run by their `order`,
+/// lowest first.
+///
+/// An integration the outgoing environment configures is undone by applying its config
+/// again. Otherwise one of `restorable`, the integrations that record snapshots, is put
+/// back from its snapshot and any other one is cleared. `outgoing` is `None` when it
+/// couldn't be loaded, leaving nothing to apply again.
+pub fn plan_steps(
+    undone: &[IntegrationKind],
+    planned: &[IntegrationKind],
+    unconfigured: &[IntegrationKind],
+    outgoing: Option<&Environment>,
+    restorable: &[IntegrationKind],
+    order: impl Fn(IntegrationKind) -> i32,
+) -> Vec<PlannedStep> {
+    let configured = |kind: IntegrationKind| outgoing.is_some_and(|env| kind.is_configured_in(env));
+    let undo = |step: Step| {
+        let kind = step.kind();
+        match step {
+            _ if configured(kind) => Some(UndoAction::Reapply(kind)),
+            Step::SwitchFrom(_) | Step::Clear(_) => None,
+            Step::Apply(_) if restorable.contains(&kind) => Some(UndoAction::Restore(kind)),
+            Step::Apply(_) => Some(UndoAction::Clear(kind)),
+        }
+    };
+    let steps = undone
+        .iter()
+        .map(|kind| Step::SwitchFrom(*kind))
+        .chain(planned.iter().map(|kind| Step::Apply(*kind)))
+        .chain(unconfigured.iter().map(|kind| Step::Clear(*kind)));
+    let mut steps: Vec<PlannedStep> = steps
+        .map(|step| PlannedStep {
+            step,
+            undo: undo(step),
+            order: order(step.kind()),
+        })
+        .collect();
+    steps.sort_by_key(PlannedStep::batch);
+    steps
+}
+
+/// What running the steps of a switch came to
+#[derive(Debug, Default)]
+pub struct StepsRun {
+    /// The outcomes of the steps that succeeded, in order
+    pub outcomes: Vec<(Step, IntegrationOutcome)>,
+    /// The steps that failed with their error. Only [`FailurePolicy::ContinueOnError`]
+    /// gets past the batch of the first.
+    pub errors: Vec<(Step, EnvMgrError)>,
+    /// The undo actions that failed during a rollback, with their error
+    pub failed_undos: Vec<(UndoAction, EnvMgrError)>,
+    /// The steps that timed out and couldn't be stopped. They are not rolled back since
+    /// they may still change things.
+    pub still_running: Vec<Step>,
+}
+
+impl StepsRun {
+    /// Whether the first error rolled the steps back
+    pub fn rolled_back(&self, policy: FailurePolicy) -> bool {
+        policy == FailurePolicy::Rollback && !self.errors.is_empty()
+    }
+}
+
+/// The work of a step, run on a thread of its own
+pub type Job<T> = Box<dyn FnOnce() -> EnvMgrResult<T> + Send>;
+
+/// How often a batch checks its deadlines while waiting
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How a job of [`run_concurrently`] ended
+#[derive(Debug)]
+pub struct JobOutcome<T> {
+    pub result: EnvMgrResult<T>,
+    /// It timed out and didn't stop within its timeout again, so it may still change
+    /// things
+    pub still_running: bool,
+}
+
+/// Run `jobs` at the same time, each on its own thread, and wait for their results in
+/// order. Time during which a command is attached to the terminal doesn't count toward
+/// their timeouts.
+///
+/// A job still running after its timeout is an [`EnvMgrError::IntegrationTimeout`]. The
+/// commands it runs are killed, see [`crate::runner::stop_on`], and it gets as long
+/// again to return; one that doesn't is left behind as still running.
+pub fn run_concurrently<T: Send + 'static>(
+    jobs: Vec<(IntegrationKind, Job<T>, Duration)>,
+) -> Vec<JobOutcome<T>> {
+    let (sender, receiver) = mpsc::channel();
+    let mut results: Vec<Option<EnvMgrResult<T>>> = Vec::with_capacity(jobs.len());
+    let mut deadlines = vec![];
+    let mut stop_flags = vec![];
+    let started = Instant::now();
+    for (index, (kind, job, timeout)) in jobs.into_iter().enumerate() {
+        let sender = sender.clone();
+        let stop = Arc::new(AtomicBool::new(false));
+        let job_stop = stop.clone();
+        let spawned = std::thread::Builder::new()
+            .name(format!("integration {kind}"))
+            .spawn(move || {
+                crate::runner::stop_on(job_stop);
+                let _ = sender.send((index, job()));
+            });
+        results.push(spawned.err().map(|e| Err(e.into())));
+        deadlines.push((kind, timeout, started + timeout));
+        stop_flags.push(stop);
+    }
+    drop(sender);
+    // A job is settled once it returned, couldn't be started or gave up stopping
+    let mut settled: Vec<bool> = results.iter().map(Option::is_some).collect();
+    let mut stopping: Vec<Option<Instant>> = vec![None; results.len()];
+    let mut still_running = vec![false; results.len()];
+    let mut last_check = Instant::now();
+    while settled.contains(&false) {
+        let next_wake = (0..results.len())
+            .filter(|index| !settled[*index])
+            .map(|index| stopping[index].unwrap_or(deadlines[index].2))
+            .min()
+            .unwrap_or(last_check);
+        let wait = next_wake.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(wait.min(POLL_INTERVAL)) {
+            Ok((index, result)) => {
+                // A job that timed out already has its result
+                if results[index].is_none() {
+                    results[index] = Some(result);
+                }
+                settled[index] = true;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                for (result, settled) in results.iter_mut().zip(&mut settled) {
+                    result.get_or_insert_with(|| {
+                        Err(EnvMgrError::Other("an integration panicked".into()))
+                    });
+                    *settled = true;
+                }
+            }
+        }
+        let now = Instant::now();
+        if crate::runner::is_interactive() {
+            for (_, _, deadline) in &mut deadlines {
+                *deadline += now - last_check;
+            }
+        }
+        last_check = now;
+        for (index, (kind, timeout, deadline)) in deadlines.iter().enumerate() {
+            if settled[index] {
+                continue;
+            }
+            match stopping[index] {
+                Some(given_up) if given_up <= now => {
+                    still_running[index] = true;
+                    settled[index] = true;
+                }
+                Some(_) => {}
+                None if *deadline <= now => {
+                    results[index] = Some(Err(EnvMgrError::IntegrationTimeout {
+                        integration: kind.to_string(),
+                        secs: timeout.as_secs(),
+                    }));
+                    stop_flags[index].store(true, Ordering::SeqCst);
+                    stopping[index] = Some(now + *timeout);
+                }
+                None => {}
+            }
+        }
+    }
+    results
+        .into_iter()
+        .flatten()
+        .zip(still_running)
+        .map(|(result, still_running)| JobOutcome {
+            result,
+            still_running,
+        })
+        .collect()
+}
+
+/// Run `steps` in batches of the same kind of step and order, see [`plan_steps`]. The
+/// steps of a batch run at the same time on the jobs `start` hands out, each with its
+/// `timeout`, and `finish` sees every result in order once the batch is done.
+///
+/// Under [`FailurePolicy::Rollback`] a batch with an error is the last one. The undo
+/// actions of the steps that ran, the failing ones included since they may have got
+/// halfway, then run one at a time, newest first, on the jobs `undo` hands out. An
+/// undo that fails is recorded and the rollback goes on with the next. A step that is
+/// still running isn't undone, it could change things again after its undo.
+pub fn run_steps(
+    steps: &[PlannedStep],
+    policy: FailurePolicy,
+    timeout: impl Fn(IntegrationKind) -> Duration,
+    mut start: impl FnMut(Step) -> Job<IntegrationOutcome>,
+    mut finish: impl FnMut(Step, &EnvMgrResult<IntegrationOutcome>),
+    mut undo: impl FnMut(UndoAction) -> Job<ApplyOutcome>,
+) -> StepsRun {
+    let mut result = StepsRun::default();
+    let mut ran = vec![];
+    for batch in steps.chunk_by(|a, b| a.batch() == b.batch()) {
+        let jobs = batch
+            .iter()
+            .map(|planned| {
+                let kind = planned.step.kind();
+                log::debug!("Starting {:?}", planned.step);
+                (kind, start(planned.step), timeout(kind))
+            })
+            .collect();
+        for (planned, outcome) in batch.iter().zip(run_concurrently(jobs)) {
+            match (outcome.still_running, planned.undo) {
+                (true, _) => result.still_running.push(planned.step),
+                (false, Some(undo)) => ran.push(undo),
+                (false, None) => {}
+            }
+            finish(planned.step, &outcome.result);
+            match outcome.result {
+                Ok(outcome) => result.outcomes.push((planned.step, outcome)),
+                Err(e) => result.errors.push((planned.step, e)),
+            }
+        }
+        if result.rolled_back(policy) {
+            break;
+        }
+    }
+    if result.rolled_back(policy) {
+        for action in ran.into_iter().rev() {
+            let kind = action.kind();
+            let job = (kind, undo(action), timeout(kind));
+            match run_concurrently(vec![job]).remove(0).result {
+                Ok(outcome) => log::info!("{kind}: {outcome}, rolled back"),
+                Err(e) => result.failed_undos.push((action, e)),
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        error::EnvMgrError,
+        integrations::gh_cli::{GhCliConfig, GhCliHostUser},
+    };
+
+    use IntegrationKind::{GhCli, Git, OpSsh, Tailscale};
+
+    /// The outgoing environment, with only gh_cli
+    fn outgoing() -> Environment {
+        Environment {
+            key: "work".to_string(),
+            name: "Work".to_string(),
+            gh_cli: Some(GhCliConfig {
+                hosts: vec![GhCliHostUser {
+                    host: "github.com".to_string(),
+                    user: "alice-work".to_string(),
+                    git_protocol: None,
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_plan_steps_undo_toward_the_outgoing_environment() {
+        let outgoing = outgoing();
+        let steps = plan_steps(
+            &[OpSsh],
+            &[GhCli, Tailscale, Git],
+            &[Git],
+            Some(&outgoing),
+            &[OpSsh, GhCli, Tailscale],
+            IntegrationKind::default_order,
+        );
+
+        let undos: Vec<_> = steps.iter().map(|s| (s.step, s.undo)).collect();
+        assert_eq!(
+            undos,
+            [
+                (Step::SwitchFrom(OpSsh), None),
+                (Step::Apply(GhCli), Some(UndoAction::Reapply(GhCli))),
+                (Step::Apply(Tailscale), Some(UndoAction::Restore(Tailscale))),
+                (Step::Apply(Git), Some(UndoAction::Clear(Git))),
+                (Step::Clear(Git), None),
+            ]
+        );
+        let steps = plan_steps(
+            &[],
+            &[GhCli],
+            &[],
+            None,
+            &[],
+            IntegrationKind::default_order,
+        );
+        assert_eq!(steps[0].undo, Some(UndoAction::Clear(GhCli)));
+    }
+
+    #[test]
+    fn test_plan_steps_orders_each_phase() {
+        let steps = plan_steps(
+            &[],
+            &[Git, GhCli, Tailscale],
+            &[Git, OpSsh],
+            None,
+            &[],
+            |kind| match kind {
+                Tailscale => -1,
+                kind => kind.default_order(),
+            },
+        );
+
+        let order: Vec<_> = steps.iter().map(|s| s.step).collect();
+        assert_eq!(
+            order,
+            [
+                Step::Apply(Tailscale),
+                Step::Apply(GhCli),
+                Step::Apply(Git),
+                Step::Clear(OpSsh),
+                Step::Clear(Git),
+            ]
+        );
+    }
+
+    /// Runs the steps, failing those of `failing` and hanging in those of `slow` past
+    /// their timeout, and records what ran and what was undone
+    fn run(
+        policy: FailurePolicy,
+        failing: &[IntegrationKind],
+        slow: &[IntegrationKind],
+    ) -> (StepsRun, Vec<Step>, Vec<UndoAction>) {
+        let steps = plan_steps(
+            &[],
+            &[OpSsh, GhCli, Tailscale],
+            &[Git],
+            Some(&outgoing()),
+            &[OpSsh, GhCli, Tailscale],
+            IntegrationKind::default_order,
+        );
+        let (mut ran, mut finished, mut undone) = (vec![], vec![], vec![]);
+        let result = run_steps(
+            &steps,
+            policy,
+            |kind| match slow.contains(&kind) {
+                true => Duration::from_millis(100),
+                false => Duration::from_secs(10),
+            },
+            |step| -> Job<IntegrationOutcome> {
+                ran.push(step);
+                let (fails, hangs) = (failing.contains(&step.kind()), slow.contains(&step.kind()));
+                Box::new(move || {
+                    if hangs {
+                        std::thread::sleep(Duration::from_secs(2));
+                    }
+                    match fails {
+                        true => Err(EnvMgrError::Tailscale(format!("{} failed", step.kind()))),
+                        false => Ok(IntegrationOutcome::default()),
+                    }
+                })
+            },
+            |step, _| finished.push(step),
+            |action| -> Job<ApplyOutcome> {
+                undone.push(action);
+                Box::new(move || match action {
+                    UndoAction::Restore(OpSsh) => Err(EnvMgrError::OpSshKey("gone".into())),
+                    _ => Ok(ApplyOutcome::Changed),
+                })
+            },
+        );
+        assert_eq!(ran, finished);
+        (result, ran, undone)
+    }
+
+    #[test]
+    fn test_first_error_rolls_back_what_ran() {
+        let (result, ran, undone) = run(FailurePolicy::Rollback, &[Tailscale], &[]);
+
+        assert!(result.rolled_back(FailurePolicy::Rollback));
+        assert_eq!(
+            ran,
+            [
+                Step::Apply(OpSsh),
+                Step::Apply(GhCli),
+                Step::Apply(Tailscale)
+            ]
+        );
+        // The failing step may have got halfway, so it is undone as well
+        assert_eq!(
+            undone,
+            [
+                UndoAction::Restore(Tailscale),
+                UndoAction::Reapply(GhCli),
+                UndoAction::Restore(OpSsh),
+            ]
+        );
+        assert_eq!(result.outcomes.len(), 2);
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.failed_undos.len(), 1);
+        assert_eq!(result.failed_undos[0].0, UndoAction::Restore(OpSsh));
+    }
+
+    #[test]
+    fn test_continue_on_error_runs_everything_and_keeps_the_errors() {
+        let (result, ran, undone) = run(FailurePolicy::ContinueOnError, &[GhCli, Tailscale], &[]);
+
+        assert!(!result.rolled_back(FailurePolicy::ContinueOnError));
+        assert_eq!(ran.len(), 4);
+        assert!(undone.is_empty());
+        let failed: Vec<_> = result.errors.iter().map(|(step, _)| *step).collect();
+        assert_eq!(failed, [Step::Apply(GhCli), Step::Apply(Tailscale)]);
+        assert_eq!(result.outcomes.len(), 2);
+    }
+
+    #[test]
+    fn test_nothing_is_undone_without_an_error() {
+        let (result, ran, undone) = run(FailurePolicy::Rollback, &[], &[]);
+
+        assert!(!result.rolled_back(FailurePolicy::Rollback));
+        assert_eq!(ran.len(), 4);
+        assert!(undone.is_empty() && result.errors.is_empty());
+    }
+
+    #[test]
+    fn test_a_slow_integration_times_out_and_rolls_back() {
+        let started = Instant::now();
+        let (result, ran, undone) = run(FailurePolicy::Rollback, &[], &[GhCli]);
+
+        // It can't be stopped, so its thread is left behind and it isn't undone
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(result.rolled_back(FailurePolicy::Rollback));
+        assert_eq!(ran.len(), 3);
+        assert_eq!(
+            undone,
+            [UndoAction::Restore(Tailscale), UndoAction::Restore(OpSsh)]
+        );
+        assert_eq!(result.still_running, [Step::Apply(GhCli)]);
+        let [(step, e)] = &result.errors[..] else {
+            panic!("expected one error, got {:?}", result.errors);
+        };
+        assert_eq!(*step, Step::Apply(GhCli));
+        assert!(matches!(
+            e,
+            EnvMgrError::IntegrationTimeout { integration, secs: 0 } if integration == "gh_cli"
+        ));
+    }
+
+    #[test]
+    fn test_a_timed_out_step_is_undone_only_once_it_returned() {
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+        let steps = plan_steps(&[], &[GhCli], &[], None, &[], |_| 0);
+        let undo_events = events.clone();
+        let result = run_steps(
+            &steps,
+            FailurePolicy::Rollback,
+            |_| Duration::from_millis(300),
+            |step| -> Job<IntegrationOutcome> {
+                let events = events.clone();
+                // Past its timeout, but within the time it gets to stop
+                Box::new(move || {
+                    std::thread::sleep(Duration::from_millis(450));
+                    events.lock().unwrap().push(format!("end {step:?}"));
+                    Ok(IntegrationOutcome::default())
+                })
+            },
+            |_, _| {},
+            |action| -> Job<ApplyOutcome> {
+                undo_events.lock().unwrap().push(format!("undo {action:?}"));
+                Box::new(|| Ok(ApplyOutcome::Changed))
+            },
+        );
+
+        assert!(matches!(
+            result.errors[..],
+            [(_, EnvMgrError::IntegrationTimeout { .. })]
+        ));
+        assert!(result.still_running.is_empty());
+        assert_eq!(
+            events.lock().unwrap()[..],
+            ["end Apply(GhCli)", "undo Clear(GhCli)"]
+        );
+    }
+
+    #[test]
+    fn test_switching_away_finishes_before_anything_is_applied() {
+        let events = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        // Ordered ahead of everything, tailscale still waits for op_ssh to be undone
+        let steps = plan_steps(
+            &[OpSsh],
+            &[Tailscale, GhCli],
+            &[],
+            None,
+            &[],
+            |kind| match kind {
+                Tailscale => -1,
+                _ => 0,
+            },
+        );
+        run_steps(
+            &steps,
+            FailurePolicy::Rollback,
+            |_| Duration::from_secs(10),
+            |step| -> Job<IntegrationOutcome> {
+                let events = events.clone();
+                Box::new(move || {
+                    events.lock().unwrap().push(format!("start {step:?}"));
+                    if let Step::SwitchFrom(_) = step {
+                        std::thread::sleep(Duration::from_millis(50));
+                    }
+                    events.lock().unwrap().push(format!("end {step:?}"));
+                    Ok(IntegrationOutcome::default())
+                })
+            },
+            |_, _| {},
+            |_| -> Job<ApplyOutcome> { Box::new(|| Ok(ApplyOutcome::Changed)) },
+        );
+
+        assert_eq!(
+            events.lock().unwrap()[..],
+            [
+                "start SwitchFrom(OpSsh)",
+                "end SwitchFrom(OpSsh)",
+                "start Apply(Tailscale)",
+                "end Apply(Tailscale)",
+                "start Apply(GhCli)",
+                "end Apply(GhCli)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_steps_of_a_batch_run_at_the_same_time() {
+        // Either job only gets past the barrier once the other one has started
+        let barrier = std::sync::Arc::new(std::sync::Barrier::new(2));
+        let steps = plan_steps(&[], &[OpSsh, GhCli], &[], None, &[], |_| 0);
+        let result = run_steps(
+            &steps,
+            FailurePolicy::Rollback,
+            |_| Duration::from_secs(10),
+            |_| -> Job<IntegrationOutcome> {
+                let barrier = barrier.clone();
+                Box::new(move || {
+                    barrier.wait();
+                    Ok(IntegrationOutcome::default())
+                })
+            },
+            |_, _| {},
+            |_| -> Job<ApplyOutcome> { Box::new(|| Ok(ApplyOutcome::Changed)) },
+        );
+
+        assert!(result.errors.is_empty());
+        assert_eq!(result.outcomes.len(), 2);
+    }
+}
@@ -0,0 +1,396 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    environment::Environment,
+    error::{EnvMgrError, EnvMgrResult},
+    fs::Fs,
+    integrations::{ApplyOutcome, Integration, IntegrationKind, IntegrationOutcome},
+};
+
+/// Where the include file lives, relative to the home directory
+const INCLUDE_FILE: &str = ".ssh/envmgr_env.conf";
+const HEADER: &str = "# Written by envmgr for the active environment, changes are overwritten\n";
+const MARKER: &str = "# Added by envmgr, keep it above the first Host or Match block";
+/// ssh refuses config files others can write to; the fragments may name internal hosts
+const SSH_CONFIG_MODE: u32 = 0o600;
+const SSH_DIR_MODE: u32 = 0o700;
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema, Default,
+)]
+pub struct SshConfig {
+    /// ssh config file of the environment, relative to the environment dir, e.g.
+    /// `ssh/envmgr.conf`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_file: Option<String>,
+    /// ssh config lines, e.g. `Host` blocks, written after those of `config_file`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<String>,
+}
+
+impl std::fmt::Display for SshConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = vec![];
+        if let Some(config_file) = &self.config_file {
+            parts.push(config_file.clone());
+        }
+        let hosts = self
+            .config
+            .as_deref()
+            .map(host_patterns)
+            .unwrap_or_default();
+        if !hosts.is_empty() {
+            parts.push(format!("Host {}", hosts.join(", ")));
+        }
+        f.write_str(&parts.join(", "))
+    }
+}
+
+/// Patterns of the `Host` lines in `config`
+fn host_patterns(config: &str) -> Vec<String> {
+    config
+        .lines()
+        .filter_map(|line| keyword_args(line, "host"))
+        .map(str::to_string)
+        .collect()
+}
+
+/// The arguments of `line` when it sets `keyword`, which ssh matches case-insensitively
+/// and separates by whitespace or `=`
+fn keyword_args<'a>(line: &'a str, keyword: &str) -> Option<&'a str> {
+    let line = line.trim_start();
+    let end = line.find(|c: char| c.is_whitespace() || c == '=')?;
+    let (key, rest) = line.split_at(end);
+    key.eq_ignore_ascii_case(keyword)
+        .then(|| rest.trim_start().strip_prefix('=').unwrap_or(rest).trim())
+}
+
+/// ssh config per environment.
+///
+/// The fragment goes into `~/.ssh/envmgr_env.conf`, which `~/.ssh/config` includes
+/// at its top, so its `Host` blocks come before the ones set there.
+pub struct Ssh;
+
+impl Ssh {
+    pub fn include_file_path(home: &Path) -> PathBuf {
+        home.join(INCLUDE_FILE)
+    }
+
+    pub fn ssh_config_path(home: &Path) -> PathBuf {
+        home.join(".ssh").join("config")
+    }
+
+    /// The include file envmgr writes for `config`, with `config_file` read from
+    /// `env_dir`
+    pub fn render_include_file(config: &SshConfig, env_dir: &Path) -> EnvMgrResult<String> {
+        let mut out = HEADER.to_string();
+        let file = match &config.config_file {
+            Some(config_file) => {
+                let path = env_dir.join(config_file);
+                Some(std::fs::read_to_string(&path).map_err(|e| {
+                    EnvMgrError::Ssh(format!("could not read {}: {e}", path.display()))
+                })?)
+            }
+            None => None,
+        };
+        for fragment in [file.as_deref(), config.config.as_deref()]
+            .into_iter()
+            .flatten()
+        {
+            out.push_str(fragment);
+            if !fragment.is_empty() && !fragment.ends_with('\n') {
+                out.push('\n');
+            }
+        }
+        Ok(out)
+    }
+
+    /// `ssh_config` with an `Include` of the include file put at its top, `None` when
+    /// it already includes it
+    pub fn with_include(ssh_config: &str, home: &Path) -> Option<String> {
+        let absolute = Self::include_file_path(home);
+        let ours = |path: &str| {
+            let path = path.trim_matches('"');
+            path == format!("~/{INCLUDE_FILE}")
+                || Path::new(path) == absolute.as_path()
+                // Relative includes are resolved in ~/.ssh
+                || Path::new(".ssh").join(path) == Path::new(INCLUDE_FILE)
+        };
+        let included = ssh_config.lines().any(|line| {
+            keyword_args(line, "include").is_some_and(|args| args.split_whitespace().any(ours))
+        });
+        if included {
+            return None;
+        }
+        // Only lines before the first Host or Match block apply to every host
+        Some(format!(
+            "{MARKER}\nInclude ~/{INCLUDE_FILE}\n\n{ssh_config}"
+        ))
+    }
+
+    /// Make `~/.ssh/config` include the include file, once. A linked config, e.g. from
+    /// a dotfiles repo, is edited where it points instead of being replaced.
+    fn ensure_included(home: &Path, fs: &dyn Fs) -> EnvMgrResult<ApplyOutcome> {
+        let link = Self::ssh_config_path(home);
+        let path = std::fs::canonicalize(&link).unwrap_or(link);
+        let current = match fs.read(&path)? {
+            Some(bytes) => String::from_utf8(bytes)
+                .map_err(|_| EnvMgrError::Ssh(format!("{} is not valid UTF-8", path.display())))?,
+            None => String::new(),
+        };
+        match Self::with_include(&current, home) {
+            Some(updated) => {
+                fs.write_atomic_with_mode(&path, updated.as_bytes(), SSH_CONFIG_MODE)?;
+                Ok(ApplyOutcome::Changed)
+            }
+            None => Ok(ApplyOutcome::AlreadyInDesiredState),
+        }
+    }
+
+    /// Write `content` to the include file with [`SSH_CONFIG_MODE`], creating
+    /// `~/.ssh` private when it doesn't exist
+    fn write_include_file(home: &Path, content: &str, fs: &dyn Fs) -> EnvMgrResult<ApplyOutcome> {
+        let path = Self::include_file_path(home);
+        let current = fs.read(&path)?;
+        if current.as_deref() == Some(content.as_bytes()) && has_mode(&path, SSH_CONFIG_MODE) {
+            return Ok(ApplyOutcome::AlreadyInDesiredState);
+        }
+        let ssh_dir = home.join(".ssh");
+        if !ssh_dir.exists() {
+            fs.create_dir_all(&ssh_dir)?;
+            fs.set_mode(&ssh_dir, SSH_DIR_MODE)?;
+        }
+        fs.write_atomic_with_mode(&path, content.as_bytes(), SSH_CONFIG_MODE)?;
+        Ok(ApplyOutcome::Changed)
+    }
+
+    /// Write the include file for `config` and make sure `~/.ssh/config` includes it
+    pub fn on_switch_to(
+        config: &SshConfig,
+        env_dir: &Path,
+        home: &Path,
+        fs: &dyn Fs,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        let rendered = Self::render_include_file(config, env_dir)?;
+        let written = Self::write_include_file(home, &rendered, fs)?;
+        let included = Self::ensure_included(home, fs)?;
+        Ok(match (written, included) {
+            (ApplyOutcome::AlreadyInDesiredState, ApplyOutcome::AlreadyInDesiredState) => {
+                ApplyOutcome::AlreadyInDesiredState
+            }
+            _ => ApplyOutcome::Changed,
+        })
+    }
+
+    /// Truncate the include file for an environment without ssh config, keeping the
+    /// `Include` line. Nothing happens when there is none, i.e. the integration was
+    /// never used.
+    pub fn on_switch_away(home: &Path, fs: &dyn Fs) -> EnvMgrResult<ApplyOutcome> {
+        let path = Self::include_file_path(home);
+        match fs.read(&path)? {
+            None => Ok(ApplyOutcome::AlreadyInDesiredState),
+            Some(current) if current == HEADER.as_bytes() => {
+                Ok(ApplyOutcome::AlreadyInDesiredState)
+            }
+            Some(_) => Self::write_include_file(home, HEADER, fs),
+        }
+    }
+
+    /// Whether [`Ssh::on_switch_away`] would truncate the include file
+    pub fn is_blank(home: &Path) -> bool {
+        std::fs::read(Self::include_file_path(home))
+            .map_or(true, |current| current == HEADER.as_bytes())
+    }
+}
+
+#[cfg(unix)]
+fn has_mode(path: &Path, mode: u32) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path).is_ok_and(|metadata| metadata.permissions().mode() & 0o777 == mode)
+}
+
+#[cfg(not(unix))]
+fn has_mode(_path: &Path, _mode: u32) -> bool {
+    true
+}
+
+/// [`Ssh`] behind [`Integration`]
+pub struct SshIntegration<'a> {
+    pub home: PathBuf,
+    pub fs: &'a dyn Fs,
+}
+
+impl Integration for SshIntegration<'_> {
+    type Config = SshConfig;
+
+    fn name(&self) -> IntegrationKind {
+        IntegrationKind::Ssh
+    }
+
+    fn config<'e>(&self, env: &'e Environment) -> Option<&'e Self::Config> {
+        env.ssh.as_ref()
+    }
+
+    fn on_switch_to(
+        &self,
+        env: &Environment,
+        config: &Self::Config,
+    ) -> EnvMgrResult<IntegrationOutcome> {
+        Ssh::on_switch_to(config, &env.env_dir(), &self.home, self.fs).map(Into::into)
+    }
+
+    fn describe_switch_to(&self, env: &Environment, config: &Self::Config) -> Vec<String> {
+        let include = Ssh::include_file_path(&self.home);
+        let mut actions = vec![match Ssh::render_include_file(config, &env.env_dir()) {
+            Ok(rendered) => match self.fs.read(&include) {
+                Ok(Some(current)) if current == rendered.as_bytes() => {
+                    format!("{} ({config})", ApplyOutcome::AlreadyInDesiredState)
+                }
+                Ok(_) => format!("write {} ({config})", include.display()),
+                Err(e) => format!(
+                    "write {} ({config}, current file unreadable: {e})",
+                    include.display()
+                ),
+            },
+            Err(e) => format!("write {} ({e})", include.display()),
+        }];
+        let ssh_config = Ssh::ssh_config_path(&self.home);
+        let included = std::fs::read_to_string(&ssh_config)
+            .is_ok_and(|content| Ssh::with_include(&content, &self.home).is_none());
+        if !included {
+            actions.push(format!(
+                "add an Include of it to the top of {}",
+                ssh_config.display()
+            ));
+        }
+        actions
+    }
+
+    /// The hosts must not leak into the next environment
+    fn clear(&self) -> EnvMgrResult<ApplyOutcome> {
+        Ssh::on_switch_away(&self.home, self.fs)
+    }
+
+    fn describe_clear(&self) -> Vec<String> {
+        if Ssh::is_blank(&self.home) {
+            return vec![];
+        }
+        vec![format!(
+            "blank {}",
+            Ssh::include_file_path(&self.home).display()
+        )]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::fs::RealFs;
+
+    fn temp_dirs(name: &str) -> (PathBuf, PathBuf, PathBuf) {
+        let root = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&root);
+        let (home, env_dir) = (root.join("home"), root.join("work"));
+        fs::create_dir_all(&home).unwrap();
+        fs::create_dir_all(env_dir.join("ssh")).unwrap();
+        (root, home, env_dir)
+    }
+
+    fn config() -> SshConfig {
+        SshConfig {
+            config_file: Some("ssh/envmgr.conf".to_string()),
+            config: Some("Host bastion\n  User alice".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_render_include_file() {
+        let (root, _, env_dir) = temp_dirs("envmgr_test_ssh_render");
+        fs::write(
+            env_dir.join("ssh/envmgr.conf"),
+            "Host *.corp\n  ProxyJump bastion\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            Ssh::render_include_file(&config(), &env_dir).unwrap(),
+            format!("{HEADER}Host *.corp\n  ProxyJump bastion\nHost bastion\n  User alice\n")
+        );
+        let missing = SshConfig {
+            config_file: Some("ssh/missing.conf".to_string()),
+            config: None,
+        };
+        assert!(matches!(
+            Ssh::render_include_file(&missing, &env_dir),
+            Err(EnvMgrError::Ssh(_))
+        ));
+        assert_eq!(config().to_string(), "ssh/envmgr.conf, Host bastion");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_include_is_inserted_once_at_the_top() {
+        let home = Path::new("/home/alice");
+        let ssh_config = "Host github.com\n  IdentitiesOnly yes\n";
+
+        let updated = Ssh::with_include(ssh_config, home).unwrap();
+
+        assert_eq!(
+            updated,
+            format!("{MARKER}\nInclude ~/.ssh/envmgr_env.conf\n\n{ssh_config}")
+        );
+        assert_eq!(Ssh::with_include(&updated, home), None);
+        for included in [
+            "include /home/alice/.ssh/envmgr_env.conf\n",
+            "Include=envmgr_env.conf\n",
+            "Include ~/.ssh/other.conf \"~/.ssh/envmgr_env.conf\"\n",
+        ] {
+            assert_eq!(Ssh::with_include(included, home), None, "{included}");
+        }
+        assert!(Ssh::with_include("Include ~/.ssh/other.conf\n", home).is_some());
+    }
+
+    #[test]
+    fn test_switch_writes_and_truncates() {
+        let (root, home, env_dir) = temp_dirs("envmgr_test_ssh_switch");
+        fs::write(env_dir.join("ssh/envmgr.conf"), "Host *.corp\n").unwrap();
+        let include = Ssh::include_file_path(&home);
+        let ssh_config = Ssh::ssh_config_path(&home);
+
+        let outcome = Ssh::on_switch_to(&config(), &env_dir, &home, &RealFs).unwrap();
+
+        assert_eq!(outcome, ApplyOutcome::Changed);
+        assert_eq!(
+            fs::read_to_string(&include).unwrap(),
+            format!("{HEADER}Host *.corp\nHost bastion\n  User alice\n")
+        );
+        assert!(has_mode(&include, SSH_CONFIG_MODE));
+        assert!(has_mode(&ssh_config, SSH_CONFIG_MODE));
+        assert!(has_mode(&home.join(".ssh"), SSH_DIR_MODE));
+        assert_eq!(
+            Ssh::on_switch_to(&config(), &env_dir, &home, &RealFs).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+
+        assert_eq!(
+            Ssh::on_switch_away(&home, &RealFs).unwrap(),
+            ApplyOutcome::Changed
+        );
+        assert_eq!(fs::read_to_string(&include).unwrap(), HEADER);
+        assert!(Ssh::is_blank(&home));
+        assert_eq!(
+            fs::read_to_string(&ssh_config)
+                .unwrap()
+                .matches("Include")
+                .count(),
+            1
+        );
+        assert_eq!(
+            Ssh::on_switch_away(&home, &RealFs).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
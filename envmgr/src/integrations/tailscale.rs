@@ -1,84 +1,789 @@
-use crate::error::EnvMgrResult;
+use std::{collections::BTreeMap, fmt::Write as _};
 
-#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, schemars::JsonSchema, Default)]
+use log::{debug, info};
+
+use crate::{
+    environment::Environment,
+    error::{EnvMgrError, EnvMgrResult},
+    integrations::{ApplyOutcome, Integration, IntegrationKind, IntegrationOutcome},
+    runner::CommandRunner,
+};
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema, Default,
+)]
 pub struct TailscaleConfig {
     pub tailnet: String,
+    /// Exit node by host name, MagicDNS name or IP; `""` stops using one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_node: Option<String>,
+    /// Whether to accept the subnet routes other nodes advertise
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accept_routes: Option<bool>,
+    /// Whether to block incoming connections
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shields_up: Option<bool>,
+    /// Switch back to the tailnet that was active before the first switch to an
+    /// environment with tailscale, when switching to one without
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub switch_back: bool,
+}
+
+impl TailscaleConfig {
+    /// The settings applied after the tailnet switch, `None` when there are none
+    pub fn settings(&self) -> Option<String> {
+        let mut parts = vec![];
+        match self.exit_node.as_deref() {
+            Some("") => parts.push("no exit node".to_string()),
+            Some(exit_node) => parts.push(format!("exit node {exit_node}")),
+            None => {}
+        }
+        for (name, value) in [
+            ("accept routes", self.accept_routes),
+            ("shields up", self.shields_up),
+        ] {
+            if let Some(value) = value {
+                parts.push(format!("{name} {}", if value { "on" } else { "off" }));
+            }
+        }
+        (!parts.is_empty()).then(|| parts.join(", "))
+    }
+}
+
+impl std::fmt::Display for TailscaleConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "tailnet {}", self.tailnet)?;
+        if let Some(settings) = self.settings() {
+            write!(f, ", {settings}")?;
+        }
+        Ok(())
+    }
 }
 
 pub struct Tailscale;
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 struct TailscaleSwitchListItem {
-    pub _id: String,
+    pub id: String,
     pub tailnet: String,
-    pub _account: String,
+    pub account: String,
     pub active: bool,
 }
 
+/// The accounts in `tailscale switch --list` output. The `ID Tailnet Account` header
+/// is optional and the account is the rest of the line, so it may contain spaces;
+/// the active one ends with `*`.
+fn parse_switch_list(stdout: &str) -> Vec<TailscaleSwitchListItem> {
+    let mut items = vec![];
+    for line in stdout.lines() {
+        let mut parts = line.split_whitespace();
+        let (Some(id), Some(tailnet)) = (parts.next(), parts.next()) else {
+            continue;
+        };
+        if id == "ID" && tailnet == "Tailnet" {
+            continue;
+        }
+        let account = parts.collect::<Vec<_>>().join(" ");
+        let active = account.ends_with('*');
+        items.push(TailscaleSwitchListItem {
+            id: id.to_string(),
+            tailnet: tailnet.to_string(),
+            account: account.trim_end_matches('*').trim_end().to_string(),
+            active,
+        });
+    }
+    items
+}
+
+/// The parts of `tailscale status --json` the settings are compared against
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct TailscaleStatus {
+    #[serde(default)]
+    peer: BTreeMap<String, TailscalePeer>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TailscalePeer {
+    #[serde(default)]
+    host_name: String,
+    #[serde(default, rename = "DNSName")]
+    dns_name: String,
+    #[serde(default, rename = "TailscaleIPs")]
+    tailscale_ips: Vec<String>,
+    /// Whether this peer is the exit node in use
+    #[serde(default)]
+    exit_node: bool,
+}
+
+impl TailscalePeer {
+    /// Whether `name` names this peer the way `tailscale set --exit-node` accepts
+    fn is_named(&self, name: &str) -> bool {
+        let dns_name = self.dns_name.trim_end_matches('.');
+        self.host_name.eq_ignore_ascii_case(name)
+            || dns_name.eq_ignore_ascii_case(name)
+            || dns_name
+                .split('.')
+                .next()
+                .is_some_and(|label| label.eq_ignore_ascii_case(name))
+            || self.tailscale_ips.iter().any(|ip| ip == name)
+    }
+}
+
+impl TailscaleStatus {
+    /// The peer in use as exit node, if any
+    fn exit_node(&self) -> Option<&TailscalePeer> {
+        self.peer.values().find(|peer| peer.exit_node)
+    }
+}
+
+/// The preferences of `tailscale debug prefs` that `tailscale status` doesn't show
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct TailscalePrefs {
+    #[serde(default)]
+    route_all: bool,
+    #[serde(default)]
+    shields_up: bool,
+}
+
 impl Tailscale {
-    fn tailscale_switch_list() -> EnvMgrResult<Vec<TailscaleSwitchListItem>> {
-        let output = std::process::Command::new("tailscale")
-            .arg("switch")
-            .arg("--list")
-            .output()?;
-        if !output.status.success() {
-            return Err(crate::error::EnvMgrError::Other(
-                format!(
-                    "tailscale switch --list failed with status: {}",
-                    output.status
-                )
-                .into(),
-            ));
-        }
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        let mut items = vec![];
-        for line in stdout.lines().skip(1) {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() >= 3 {
-                items.push(TailscaleSwitchListItem {
-                    _id: parts[0].to_string(),
-                    tailnet: parts[1].to_string(),
-                    _account: parts[2].trim_end_matches("*").to_string(),
-                    active: parts[2].ends_with("*"),
-                });
+    fn tailscale_switch_list(
+        runner: &dyn CommandRunner,
+    ) -> EnvMgrResult<Vec<TailscaleSwitchListItem>> {
+        let stdout = Self::run(runner, &["switch", "--list"])?;
+        Ok(parse_switch_list(&stdout))
+    }
+
+    /// Run `tailscale login` so the user can add the account of `tailnet`
+    fn login(tailnet: &str, runner: &dyn CommandRunner) -> EnvMgrResult<()> {
+        info!("Tailnet '{tailnet}' has no account on this machine yet, running tailscale login");
+        match runner.run_interactive("tailscale", &["login"], &[]) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(EnvMgrError::Tailscale("tailscale login failed".into())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(EnvMgrError::Tailscale(
+                "tailscale is not installed, but the tailscale integration needs it".into(),
+            )),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The tailnets of the accounts on this machine, each once
+    pub fn tailnets(runner: &dyn CommandRunner) -> EnvMgrResult<Vec<String>> {
+        let mut tailnets: Vec<String> = vec![];
+        for item in Self::tailscale_switch_list(runner)? {
+            if !tailnets.contains(&item.tailnet) {
+                tailnets.push(item.tailnet);
             }
         }
-        Ok(items)
+        Ok(tailnets)
+    }
+
+    /// The tailnet of the currently active tailscale account, if any
+    pub fn active_tailnet(runner: &dyn CommandRunner) -> EnvMgrResult<Option<String>> {
+        Ok(Self::tailscale_switch_list(runner)?
+            .into_iter()
+            .find(|item| item.active)
+            .map(|item| item.tailnet))
+    }
+
+    pub fn status(runner: &dyn CommandRunner) -> EnvMgrResult<TailscaleStatus> {
+        let stdout = Self::run(runner, &["status", "--json"])?;
+        serde_json::from_str(&stdout).map_err(|e| {
+            EnvMgrError::Tailscale(format!("could not parse `tailscale status --json`: {e}"))
+        })
+    }
+
+    /// The preferences, `None` when this tailscale can't show them
+    pub fn prefs(runner: &dyn CommandRunner) -> Option<TailscalePrefs> {
+        let stdout = Self::run(runner, &["debug", "prefs"])
+            .inspect_err(|e| debug!("Could not read tailscale prefs: {e}"))
+            .ok()?;
+        serde_json::from_str(&stdout).ok()
+    }
+
+    /// The `tailscale set` flags that apply the settings of `config`, leaving out
+    /// those already in effect. A setting of unknown state is always set.
+    pub fn plan_set_flags(
+        config: &TailscaleConfig,
+        status: &TailscaleStatus,
+        prefs: Option<&TailscalePrefs>,
+    ) -> Vec<String> {
+        let mut flags = vec![];
+        match (config.exit_node.as_deref(), status.exit_node()) {
+            (Some(""), Some(_)) => flags.push("--exit-node=".to_string()),
+            (Some(""), None) | (None, _) => {}
+            (Some(wanted), current) => {
+                if !current.is_some_and(|peer| peer.is_named(wanted)) {
+                    flags.push(format!("--exit-node={wanted}"));
+                }
+            }
+        }
+        for (flag, wanted, current) in [
+            (
+                "accept-routes",
+                config.accept_routes,
+                prefs.map(|p| p.route_all),
+            ),
+            ("shields-up", config.shields_up, prefs.map(|p| p.shields_up)),
+        ] {
+            if let Some(wanted) = wanted
+                && current != Some(wanted)
+            {
+                flags.push(format!("--{flag}={wanted}"));
+            }
+        }
+        flags
+    }
+
+    fn switch_to_tailnet(tailnet: &str, runner: &dyn CommandRunner) -> EnvMgrResult<()> {
+        Self::run(runner, &["switch", tailnet])?;
+        Ok(())
+    }
+
+    /// Run `tailscale set` with the settings of `config` that aren't in effect yet
+    fn apply_settings(
+        config: &TailscaleConfig,
+        runner: &dyn CommandRunner,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        if config.settings().is_none() {
+            return Ok(ApplyOutcome::AlreadyInDesiredState);
+        }
+        let status = Self::status(runner)?;
+        let prefs = match config.accept_routes.is_some() || config.shields_up.is_some() {
+            true => Self::prefs(runner),
+            false => None,
+        };
+        let flags = Self::plan_set_flags(config, &status, prefs.as_ref());
+        if flags.is_empty() {
+            return Ok(ApplyOutcome::AlreadyInDesiredState);
+        }
+        let mut args = vec!["set"];
+        args.extend(flags.iter().map(String::as_str));
+        Self::run(runner, &args)?;
+        Ok(ApplyOutcome::Changed)
+    }
+
+    /// Switch to the tailnet, then apply the settings. With `login`, a tailnet without
+    /// an account on this machine is added with `tailscale login` first.
+    pub fn on_switch_to(
+        config: &TailscaleConfig,
+        runner: &dyn CommandRunner,
+        login: bool,
+    ) -> EnvMgrResult<ApplyOutcome> {
+        let find = |items: Vec<TailscaleSwitchListItem>| {
+            items
+                .into_iter()
+                .find(|item| item.tailnet == config.tailnet)
+        };
+        let mut item = find(Self::tailscale_switch_list(runner)?);
+        if item.is_none() && login {
+            Self::login(&config.tailnet, runner)?;
+            item = find(Self::tailscale_switch_list(runner)?);
+        }
+        let Some(item) = item else {
+            let hint = match login {
+                true => "the account added with `tailscale login` is on another tailnet",
+                false => {
+                    "add its account with `tailscale login`, or switch with `--login` to be taken through it"
+                }
+            };
+            return Err(EnvMgrError::Tailscale(format!(
+                "tailnet '{}' is not in `tailscale switch --list`, {hint}",
+                config.tailnet
+            )));
+        };
+        let switched = if item.active {
+            ApplyOutcome::AlreadyInDesiredState
+        } else {
+            debug!(
+                "Switching to tailnet {} as {} (profile {})",
+                item.tailnet, item.account, item.id
+            );
+            Self::switch_to_tailnet(&item.tailnet, runner)?;
+            ApplyOutcome::Changed
+        };
+        // The settings belong to the tailnet's node, so they go after the switch
+        let settings = Self::apply_settings(config, runner)?;
+        Ok(match (switched, settings) {
+            (ApplyOutcome::AlreadyInDesiredState, ApplyOutcome::AlreadyInDesiredState) => {
+                ApplyOutcome::AlreadyInDesiredState
+            }
+            _ => ApplyOutcome::Changed,
+        })
+    }
+
+    fn run(runner: &dyn CommandRunner, args: &[&str]) -> EnvMgrResult<String> {
+        debug!("Running tailscale {}", args.join(" "));
+        match runner.run("tailscale", args) {
+            Ok(output) if output.success => Ok(output.stdout),
+            Ok(output) => Err(EnvMgrError::Tailscale(format!(
+                "tailscale {} failed: {}",
+                args.join(" "),
+                output.stderr.trim()
+            ))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(EnvMgrError::Tailscale(
+                "tailscale is not installed, but the tailscale integration needs it".into(),
+            )),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// [`Tailscale`] behind [`Integration`]
+pub struct TailscaleIntegration<'a> {
+    pub runner: &'a dyn CommandRunner,
+    /// Run `tailscale login` for a tailnet without an account on this machine
+    pub login: bool,
+}
+
+impl Integration for TailscaleIntegration<'_> {
+    type Config = TailscaleConfig;
+
+    fn name(&self) -> IntegrationKind {
+        IntegrationKind::Tailscale
+    }
+
+    fn config<'e>(&self, env: &'e Environment) -> Option<&'e Self::Config> {
+        env.tailscale.as_ref()
     }
 
-    fn switch_to_tailnet(tailnet: &str) -> EnvMgrResult<()> {
-        let status = std::process::Command::new("tailscale")
-            .arg("switch")
-            .arg(tailnet)
-            .status()?;
-        if !status.success() {
-            return Err(crate::error::EnvMgrError::Other(
+    fn on_switch_to(
+        &self,
+        _env: &Environment,
+        config: &Self::Config,
+    ) -> EnvMgrResult<IntegrationOutcome> {
+        Tailscale::on_switch_to(config, self.runner, self.login).map(Into::into)
+    }
+
+    fn describe_switch_to(&self, _env: &Environment, config: &Self::Config) -> Vec<String> {
+        let tailnet = &config.tailnet;
+        let active = Tailscale::active_tailnet(self.runner);
+        let on_tailnet = matches!(&active, Ok(Some(current)) if current == tailnet);
+        let mut actions = vec![match active {
+            Ok(Some(current)) if current == *tailnet => {
                 format!(
-                    "tailscale switch {} failed with status: {}",
-                    tailnet, status
+                    "{} (tailnet {tailnet})",
+                    ApplyOutcome::AlreadyInDesiredState
                 )
-                .into(),
-            ));
+            }
+            Ok(Some(current)) => format!("switch tailnet from {current} to {tailnet}"),
+            Ok(None) => format!("switch to tailnet {tailnet}"),
+            Err(e) => format!("switch to tailnet {tailnet} (tailscale unavailable: {e})"),
+        }];
+        let Some(settings) = config.settings() else {
+            return actions;
+        };
+        // Another tailnet's node has other settings, so only compare on this one
+        let flags = on_tailnet
+            .then(|| Tailscale::status(self.runner).ok())
+            .flatten()
+            .map(|status| {
+                let prefs = Tailscale::prefs(self.runner);
+                Tailscale::plan_set_flags(config, &status, prefs.as_ref())
+            });
+        match flags {
+            Some(flags) if flags.is_empty() => {}
+            Some(flags) => actions.push(format!("tailscale set {}", flags.join(" "))),
+            None => actions.push(format!("apply {settings}")),
         }
-        Ok(())
+        actions
+    }
+
+    /// The active tailnet, only recorded with `switch_back`
+    fn snapshot(&self, config: &Self::Config) -> EnvMgrResult<Option<String>> {
+        if !config.switch_back {
+            return Ok(None);
+        }
+        Ok(Tailscale::tailscale_switch_list(self.runner)?
+            .into_iter()
+            .find(|item| item.active)
+            .map(|item| item.tailnet))
+    }
+
+    /// Switch back to the recorded tailnet; the settings stay, they belong to the node
+    fn on_switch_from(
+        &self,
+        config: &Self::Config,
+        snapshot: Option<&str>,
+    ) -> EnvMgrResult<IntegrationOutcome> {
+        let Some(tailnet) = snapshot.filter(|_| config.switch_back) else {
+            return Ok(IntegrationOutcome::default());
+        };
+        let items = Tailscale::tailscale_switch_list(self.runner)?;
+        if items
+            .iter()
+            .any(|item| item.active && item.tailnet == tailnet)
+        {
+            return Ok(IntegrationOutcome::default());
+        }
+        if !items.iter().any(|item| item.tailnet == tailnet) {
+            return Err(EnvMgrError::Tailscale(format!(
+                "can't switch back to tailnet '{tailnet}', it is no longer in `tailscale switch --list`"
+            )));
+        }
+        debug!("Switching back to tailnet {tailnet}");
+        Tailscale::switch_to_tailnet(tailnet, self.runner)?;
+        Ok(ApplyOutcome::Changed.into())
     }
 
-    pub fn on_switch_to(config: &TailscaleConfig) -> EnvMgrResult<()> {
-        let items = Self::tailscale_switch_list()?;
-        if let Some(item) = items.iter().find(|item| item.tailnet == config.tailnet) {
-            if item.active {
-                // Already on the desired tailnet
-                return Ok(());
-            } else {
-                Self::switch_to_tailnet(&item.tailnet)?;
-                return Ok(());
+    fn doctor_check(&self, config: &Self::Config, out: &mut String) -> usize {
+        let active = match Tailscale::tailscale_switch_list(self.runner) {
+            Ok(items) => items.into_iter().find(|item| item.active),
+            Err(e) => {
+                let _ = writeln!(
+                    out,
+                    "tailscale: could not check the tailnet\n  warning: {e}"
+                );
+                return 0;
+            }
+        };
+        match active {
+            Some(item) if item.tailnet == config.tailnet => {
+                let _ = writeln!(out, "tailscale: on {} as {}", item.tailnet, item.account);
+            }
+            active => {
+                let active = active.map_or("none".to_string(), |item| item.tailnet);
+                let _ = writeln!(
+                    out,
+                    "tailscale: not on {}\n  warning: the active tailnet is {active}, run \
+                     `envmgr integrations run tailscale`",
+                    config.tailnet
+                );
             }
         }
-        Err(crate::error::EnvMgrError::Other(
-            format!(
-                "Tailnet '{}' not found in tailscale switch list",
-                config.tailnet
-            )
-            .into(),
-        ))
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io};
+
+    use super::*;
+    use crate::runner::CommandOutput;
+
+    const SWITCH_LIST: &str = "ID    Tailnet        Account\n\
+        1a2b  corp.ts.net    alice@corp.example*\n\
+        3c4d  home.ts.net    alice@home.example\n";
+
+    const STATUS: &str = r#"{
+      "BackendState": "Running",
+      "Self": {"HostName": "laptop", "DNSName": "laptop.corp.ts.net."},
+      "Peer": {
+        "nodekey:aa": {
+          "HostName": "exit-fra",
+          "DNSName": "exit-fra.corp.ts.net.",
+          "TailscaleIPs": ["100.64.0.7", "fd7a:115c:a1e0::7"],
+          "ExitNode": true,
+          "ExitNodeOption": true
+        },
+        "nodekey:bb": {
+          "HostName": "exit-nyc",
+          "DNSName": "exit-nyc.corp.ts.net.",
+          "TailscaleIPs": ["100.64.0.8"],
+          "ExitNode": false,
+          "ExitNodeOption": true
+        }
+      }
+    }"#;
+
+    /// Captured from tailscale 1.76, whose account column holds display names
+    const SWITCH_LIST_DISPLAY_NAMES: &str = "ID    Tailnet             Account\n\
+        5e6f  example.com         Alice Example (alice@example.com)*\n\
+        7a8b  tail1234.ts.net     alice@gmail.com\n";
+
+    /// corp.ts.net is active, exit-fra is the exit node and routes are accepted. After
+    /// `tailscale login`, lab.ts.net is in the switch list as well.
+    #[derive(Default)]
+    struct FakeTailscale {
+        calls: RefCell<Vec<String>>,
+        logged_in: RefCell<bool>,
+    }
+
+    impl CommandRunner for FakeTailscale {
+        fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+            assert_eq!(program, "tailscale");
+            let args = args.join(" ");
+            let stdout = match args.as_str() {
+                "switch --list" if *self.logged_in.borrow() => {
+                    format!("{SWITCH_LIST}5f6a  lab.ts.net     alice@lab.example\n")
+                }
+                "switch --list" => SWITCH_LIST.to_string(),
+                "status --json" => STATUS.to_string(),
+                "debug prefs" => r#"{"RouteAll": true, "ShieldsUp": false}"#.to_string(),
+                _ => {
+                    self.calls.borrow_mut().push(args);
+                    String::new()
+                }
+            };
+            Ok(CommandOutput {
+                success: true,
+                stdout,
+                ..Default::default()
+            })
+        }
+
+        fn run_interactive(
+            &self,
+            program: &str,
+            args: &[&str],
+            _env: &[(&str, &str)],
+        ) -> io::Result<bool> {
+            assert_eq!((program, args), ("tailscale", &["login"][..]));
+            self.calls.borrow_mut().push("login".to_string());
+            *self.logged_in.borrow_mut() = true;
+            Ok(true)
+        }
+    }
+
+    fn item(id: &str, tailnet: &str, account: &str, active: bool) -> TailscaleSwitchListItem {
+        TailscaleSwitchListItem {
+            id: id.to_string(),
+            tailnet: tailnet.to_string(),
+            account: account.to_string(),
+            active,
+        }
+    }
+
+    fn config(tailnet: &str, exit_node: Option<&str>) -> TailscaleConfig {
+        TailscaleConfig {
+            tailnet: tailnet.to_string(),
+            exit_node: exit_node.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    fn flags(config: &TailscaleConfig, prefs: Option<TailscalePrefs>) -> Vec<String> {
+        let status: TailscaleStatus = serde_json::from_str(STATUS).unwrap();
+        Tailscale::plan_set_flags(config, &status, prefs.as_ref())
+    }
+
+    #[test]
+    fn test_parse_switch_list() {
+        let expected = [
+            item("1a2b", "corp.ts.net", "alice@corp.example", true),
+            item("3c4d", "home.ts.net", "alice@home.example", false),
+        ];
+        assert_eq!(parse_switch_list(SWITCH_LIST), expected);
+        // Without the header, the first account must not be dropped
+        let without_header = SWITCH_LIST.split_once('\n').unwrap().1;
+        assert_eq!(parse_switch_list(without_header), expected);
+        assert_eq!(
+            parse_switch_list(SWITCH_LIST_DISPLAY_NAMES),
+            [
+                item(
+                    "5e6f",
+                    "example.com",
+                    "Alice Example (alice@example.com)",
+                    true
+                ),
+                item("7a8b", "tail1234.ts.net", "alice@gmail.com", false),
+            ]
+        );
+        assert_eq!(parse_switch_list(""), []);
+        assert_eq!(parse_switch_list("\n  \n"), []);
+    }
+
+    #[test]
+    fn test_missing_tailnet_logs_in_and_retries() {
+        let tailscale = FakeTailscale::default();
+
+        let outcome = Tailscale::on_switch_to(&config("lab.ts.net", None), &tailscale, true);
+
+        assert_eq!(outcome.unwrap(), ApplyOutcome::Changed);
+        assert_eq!(*tailscale.calls.borrow(), ["login", "switch lab.ts.net"]);
+
+        // Logging in to another tailnet doesn't help
+        let tailscale = FakeTailscale::default();
+        let result = Tailscale::on_switch_to(&config("other.ts.net", None), &tailscale, true);
+        let Err(EnvMgrError::Tailscale(message)) = result else {
+            panic!("expected an error for a tailnet login didn't add");
+        };
+        assert!(message.contains("other.ts.net"), "{message}");
+        assert_eq!(*tailscale.calls.borrow(), ["login"]);
+    }
+
+    #[test]
+    fn test_tailnets_lists_every_account() {
+        let tailscale = FakeTailscale::default();
+        *tailscale.logged_in.borrow_mut() = true;
+
+        assert_eq!(
+            Tailscale::tailnets(&tailscale).unwrap(),
+            ["corp.ts.net", "home.ts.net", "lab.ts.net"]
+        );
+    }
+
+    #[test]
+    fn test_missing_tailnet_without_login_names_the_command() {
+        let tailscale = FakeTailscale::default();
+
+        let result = Tailscale::on_switch_to(&config("lab.ts.net", None), &tailscale, false);
+
+        let Err(EnvMgrError::Tailscale(message)) = result else {
+            panic!("expected an error for a tailnet without an account");
+        };
+        assert!(message.contains("'lab.ts.net'"), "{message}");
+        assert!(message.contains("`tailscale login`"), "{message}");
+        assert!(message.contains("--login"), "{message}");
+        assert!(tailscale.calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_tailnet_only_config_still_parses() {
+        let config: TailscaleConfig = serde_norway::from_str("tailnet: corp.ts.net\n").unwrap();
+
+        assert_eq!(config, self::config("corp.ts.net", None));
+        assert_eq!(config.settings(), None);
+        assert_eq!(
+            serde_norway::to_string(&config).unwrap(),
+            "tailnet: corp.ts.net\n"
+        );
+    }
+
+    #[test]
+    fn test_plan_set_flags() {
+        for current in ["exit-fra", "EXIT-FRA", "exit-fra.corp.ts.net", "100.64.0.7"] {
+            assert!(
+                flags(&config("corp.ts.net", Some(current)), None).is_empty(),
+                "{current}"
+            );
+        }
+        assert_eq!(
+            flags(&config("corp.ts.net", Some("exit-nyc")), None),
+            ["--exit-node=exit-nyc"]
+        );
+        assert_eq!(
+            flags(&config("corp.ts.net", Some("")), None),
+            ["--exit-node="]
+        );
+        let no_exit_node = TailscaleStatus::default();
+        assert!(
+            Tailscale::plan_set_flags(&config("corp.ts.net", Some("")), &no_exit_node, None)
+                .is_empty()
+        );
+
+        let routes = TailscaleConfig {
+            accept_routes: Some(true),
+            shields_up: Some(true),
+            ..config("corp.ts.net", None)
+        };
+        let prefs = TailscalePrefs {
+            route_all: true,
+            shields_up: false,
+        };
+        assert_eq!(flags(&routes, Some(prefs)), ["--shields-up=true"]);
+        // Without prefs their state is unknown
+        assert_eq!(
+            flags(&routes, None),
+            ["--accept-routes=true", "--shields-up=true"]
+        );
+    }
+
+    #[test]
+    fn test_switch_sets_only_what_differs() {
+        let tailscale = FakeTailscale::default();
+        let config = TailscaleConfig {
+            accept_routes: Some(true),
+            ..config("home.ts.net", Some("exit-nyc"))
+        };
+
+        let outcome = Tailscale::on_switch_to(&config, &tailscale, false).unwrap();
+
+        assert_eq!(outcome, ApplyOutcome::Changed);
+        assert_eq!(
+            *tailscale.calls.borrow(),
+            ["switch home.ts.net", "set --exit-node=exit-nyc"]
+        );
+
+        let tailscale = FakeTailscale::default();
+        let unchanged = TailscaleConfig {
+            accept_routes: Some(true),
+            ..self::config("corp.ts.net", Some("exit-fra"))
+        };
+        assert_eq!(
+            Tailscale::on_switch_to(&unchanged, &tailscale, false).unwrap(),
+            ApplyOutcome::AlreadyInDesiredState
+        );
+        assert!(tailscale.calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_integration_switches_and_checks_like_tailscale() {
+        let home = config("home.ts.net", Some("exit-nyc"));
+        let direct = FakeTailscale::default();
+        let expected = Tailscale::on_switch_to(&home, &direct, false).unwrap();
+        let tailscale = FakeTailscale::default();
+        let integration = TailscaleIntegration {
+            runner: &tailscale,
+            login: false,
+        };
+
+        assert_eq!(
+            integration
+                .on_switch_to(&Environment::default(), &home)
+                .unwrap(),
+            expected.into()
+        );
+        assert_eq!(*tailscale.calls.borrow(), *direct.calls.borrow());
+
+        // The fake stays on corp.ts.net
+        let mut out = String::new();
+        assert_eq!(integration.doctor_check(&home, &mut out), 0);
+        assert_eq!(
+            out,
+            "tailscale: not on home.ts.net\n  \
+             warning: the active tailnet is corp.ts.net, run `envmgr integrations run tailscale`\n"
+        );
+        let mut out = String::new();
+        integration.doctor_check(&config("corp.ts.net", None), &mut out);
+        assert_eq!(out, "tailscale: on corp.ts.net as alice@corp.example\n");
+    }
+
+    #[test]
+    fn test_switch_back_restores_the_recorded_tailnet() {
+        let tailscale = FakeTailscale::default();
+        let integration = TailscaleIntegration {
+            runner: &tailscale,
+            login: false,
+        };
+        let home = TailscaleConfig {
+            switch_back: true,
+            ..config("home.ts.net", None)
+        };
+
+        assert_eq!(
+            Integration::snapshot(&integration, &home)
+                .unwrap()
+                .as_deref(),
+            Some("corp.ts.net")
+        );
+        assert_eq!(
+            Integration::snapshot(&integration, &config("home.ts.net", None)).unwrap(),
+            None
+        );
+
+        // The fake stays on corp.ts.net, so only lab.ts.net needs a switch
+        let outcome = integration
+            .on_switch_from(&home, Some("corp.ts.net"))
+            .unwrap();
+        assert_eq!(outcome.apply, ApplyOutcome::AlreadyInDesiredState);
+        let outcome = integration.on_switch_from(&home, Some("lab.ts.net"));
+        assert!(outcome.unwrap_err().to_string().contains("no longer in"));
+        *tailscale.logged_in.borrow_mut() = true;
+        let outcome = integration
+            .on_switch_from(&home, Some("lab.ts.net"))
+            .unwrap();
+        assert_eq!(outcome.apply, ApplyOutcome::Changed);
+        assert_eq!(*tailscale.calls.borrow(), ["switch lab.ts.net"]);
+
+        // Without switch_back it stays where it is
+        let outcome = integration
+            .on_switch_from(&config("home.ts.net", None), Some("lab.ts.net"))
+            .unwrap();
+        assert_eq!(outcome.apply, ApplyOutcome::AlreadyInDesiredState);
+        assert_eq!(tailscale.calls.borrow().len(), 1);
     }
 }
@@ -1,6 +1,19 @@
 pub mod cli;
+pub mod commands;
 pub mod config;
+pub mod daemon;
 pub mod environment;
 pub mod error;
+pub mod fs;
+pub mod fuzzy;
+pub mod git;
 pub mod integrations;
+pub mod notices;
+pub mod plan;
+pub mod platform;
+pub mod prompt;
+pub mod runner;
 pub mod state;
+pub mod systemd;
+pub mod tmux;
+pub mod watch;
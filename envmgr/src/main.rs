@@ -1,25 +1,66 @@
+use std::io::IsTerminal;
 use std::path::Path;
+use std::process::ExitCode;
+use std::time::Duration;
 
 use clap::{CommandFactory, Parser};
-use envmgr::cli::{Args, Command, Shell};
-use envmgr::config::BASE_ENV_NAME;
-use envmgr::environment::EnvironmentManager;
-use envmgr::error::EnvMgrResult;
-use indoc::indoc;
-use log::info;
+use envmgr::cli::{
+    Args, BackupsCommand, Command, DebugBundleCommand, DirenvCommand, FilesCommand,
+    IntegrationsCommand, NoticesCommand, PromptCommand, TemplateCommand,
+};
+use envmgr::commands::add::{AddOptions, AddOutcome, add_environment};
+use envmgr::commands::backups::{print_backups, restore_backup};
+use envmgr::commands::completions::{dynamic_completions, print_env_keys};
+use envmgr::commands::debug_bundle::{create_bundle, print_bundle_summary, replay_bundle};
+use envmgr::commands::direnv::{direnv_lib, generate_envrc};
+use envmgr::commands::doctor::{DoctorOptions, doctor};
+use envmgr::commands::export_env::{ExportOptions, export_env};
+use envmgr::commands::files::print_file_conflicts;
+use envmgr::commands::history::print_history;
+use envmgr::commands::integrations::{
+    print_integrations, run_integration, test_integration, unquarantine_integration,
+};
+use envmgr::commands::list::{ListOptions, print_list};
+use envmgr::commands::merge::{MergeOptions, MergeOutcome, merge_environments};
+use envmgr::commands::notices::{clear_notices, print_notices};
+use envmgr::commands::prompt::{oh_my_posh_config, print_prompt, starship_config};
+use envmgr::commands::prune::prune;
+use envmgr::commands::schema::{SchemaKind, schemas_dir, write_schemas};
+use envmgr::commands::show::show_environment;
+use envmgr::commands::status::print_status;
+use envmgr::commands::template::{TemplateRegistry, print_templates, trust_environment};
+use envmgr::commands::walkthrough::walkthrough;
+use envmgr::config::validate::validate_all;
+use envmgr::config::{self, BASE_ENV_NAME, GlobalConfig};
+use envmgr::daemon;
+use envmgr::environment::{
+    DynamicOptions, EnvironmentDiff, EnvironmentManager, LinkMode, LinkOptions, SwitchOptions,
+};
+use envmgr::error::{EnvMgrError, EnvMgrResult, ErrorCode};
+use envmgr::integrations::IntegrationSelection;
+use envmgr::integrations::rollback::FailurePolicy;
+use envmgr::notices::{is_hook_context, print_banner, record_hook_error};
+use envmgr::prompt::{TerminalPrompter, pick_environment};
+use envmgr::runner::SystemRunner;
+use envmgr::state::State;
+use envmgr::watch;
+use log::{error, info, warn};
 
-fn make_fish_hook(bin_name: &str) -> String {
-    indoc! {r#"
-    # envmgr fish hook
-
-    # Re-apply env on prompt draw
-    function __envmgr_export_eval --on-event fish_prompt
-        command BIN_NAME use | source
-    end"#}
-    .replace("BIN_NAME", bin_name)
+fn completions_usage_hint(shell: clap_complete::Shell, bin_name: &str) -> String {
+    match shell {
+        clap_complete::Shell::Fish => {
+            format!(
+                "Usage: {bin_name} completions fish > ~/.config/fish/completions/{bin_name}.fish"
+            )
+        }
+        clap_complete::Shell::PowerShell => {
+            format!("Usage: {bin_name} completions powershell | Out-String | Invoke-Expression")
+        }
+        other => format!("Usage: {bin_name} completions {other} > <completions file for {other}>"),
+    }
 }
 
-fn main() -> EnvMgrResult<()> {
+fn main() -> ExitCode {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
         .format_timestamp(None)
         .format_module_path(false)
@@ -27,6 +68,9 @@ fn main() -> EnvMgrResult<()> {
         .format_target(false)
         .init();
     let cli = Args::parse();
+    if let Some(dir) = &cli.config_dir {
+        config::set_config_dir_override(dir.clone());
+    }
 
     let bin_name = std::env::args()
         .next()
@@ -38,59 +82,410 @@ fn main() -> EnvMgrResult<()> {
         .filter(|s: &String| !s.is_empty())
         .unwrap_or_else(|| "envmgr".to_string());
 
+    if cli.command.shows_notices_banner() && std::io::stderr().is_terminal() {
+        print_banner(&bin_name);
+    }
+
+    match run(&cli, &bin_name) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            if cli.command.wants_json() {
+                println!("{}", e.to_json());
+            } else {
+                error!("[{}] {e}", e.code());
+                info!("Run `{bin_name} explain {}` for details", e.code());
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(cli: &Args, bin_name: &str) -> EnvMgrResult<()> {
     match &cli.command {
         Command::Init { force } => {
             info!("Initializing environment manager. Force: {}", force);
             todo!("Implement init functionality");
         }
-        Command::Hook { shell } => match shell {
-            Shell::Fish => {
-                println!("{}", make_fish_hook(&bin_name));
-                Ok(())
-            }
-        },
-        Command::Add { name } => {
+        Command::Hook { shell } => {
+            println!("{}", shell.hook_script(bin_name));
+            Ok(())
+        }
+        Command::Add {
+            name,
+            key,
+            gh_host,
+            gh_user,
+            tailnet,
+            op_vault,
+            op_item,
+            op_account,
+            gcloud_configuration,
+            no_interactive,
+            from_current,
+            template,
+        } => {
             info!("Adding a new environment. Name: {}", name);
-            todo!("Implement add functionality");
-        }
-        Command::List => {
-            info!("Listing all environments.");
-            let environments = EnvironmentManager::list_environments()?;
-            for (current, env) in environments {
-                eprintln!(
-                    "{} {} - {}",
-                    if current { "*" } else { " " },
-                    env.key,
-                    env.name
-                );
+            let opts = AddOptions {
+                name: name.clone(),
+                key: key.clone(),
+                gh_host: gh_host.clone(),
+                gh_user: gh_user.clone(),
+                tailnet: tailnet.clone(),
+                op_vault: op_vault.clone(),
+                op_item: op_item.clone(),
+                op_account: op_account.clone(),
+                gcloud_configuration: gcloud_configuration.clone(),
+                no_interactive: *no_interactive,
+                from_current: *from_current,
+                template: template.clone(),
+            };
+            if add_environment(&opts, &mut TerminalPrompter)? == AddOutcome::Aborted {
+                info!("Add cancelled, nothing was created");
             }
             Ok(())
         }
+        Command::List {
+            json,
+            sort,
+            group_by,
+            columns,
+        } => print_list(&ListOptions {
+            json: *json,
+            sort: *sort,
+            group: *group_by,
+            columns: columns.clone(),
+        }),
+        Command::Show { key, json, live } => show_environment(key.as_deref(), *json, *live),
+        Command::Status { json } => print_status(*json),
         Command::Remove { name } => {
             info!("Removing environment: {}", name);
             todo!("Implement remove functionality");
         }
-        Command::Use => {
-            let em = EnvironmentManager { shell: Shell::Fish };
-            em.use_environment()
+        Command::Merge {
+            source,
+            dest,
+            prefer,
+            yes,
+            confirm_token,
+        } => {
+            let opts = MergeOptions {
+                prefer: *prefer,
+                yes: *yes,
+                dry_run: cli.dry_run,
+                confirm_token: confirm_token.clone(),
+            };
+            match merge_environments(source, dest, &opts, &mut TerminalPrompter)? {
+                MergeOutcome::Merged { .. } => {
+                    if State::get_state()?.current_env_key == *source {
+                        warn!(
+                            "{source} was the current environment, run `{bin_name} switch {dest}`"
+                        );
+                    }
+                }
+                MergeOutcome::Planned { token } => {
+                    println!("Confirmation token: {token}");
+                    info!(
+                        "Apply exactly this plan with `{bin_name} merge {source} {dest} --confirm-token {token}`"
+                    );
+                }
+                MergeOutcome::Aborted => info!("Merge cancelled, nothing was changed"),
+            }
+            Ok(())
+        }
+        Command::Use {
+            shell,
+            skip_dynamic,
+            no_secrets,
+            hook,
+        } => {
+            let em = EnvironmentManager { shell: *shell };
+            let result = GlobalConfig::load().and_then(|global| {
+                let dynamic = DynamicOptions {
+                    skip: *skip_dynamic,
+                    timeout: Duration::from_secs(global.value_command_timeout_secs),
+                    secrets: !no_secrets,
+                };
+                em.use_environment(cli.dry_run, &dynamic)
+            });
+            if let Err(e) = &result
+                && is_hook_context(*hook, std::io::stdout().is_terminal())
+            {
+                record_hook_error(e);
+            }
+            result
+        }
+        Command::Link {
+            prune_only: false,
+            adopt_backups,
+            verbose,
+            only,
+            exclude,
+        } => EnvironmentManager::link_files(&LinkOptions {
+            dry_run: cli.dry_run,
+            adopt_backups: *adopt_backups,
+            verbose: *verbose,
+            only: only.clone(),
+            exclude: exclude.clone(),
+        }),
+        Command::Link {
+            prune_only: true, ..
+        } => {
+            let report = EnvironmentManager::link_files_with(LinkMode::PruneOnly, cli.dry_run)?;
+            info!(
+                "Removed {} managed link(s), skipped {}",
+                report.removed, report.skipped
+            );
+            Ok(())
+        }
+        Command::Unlink { env } => {
+            let mode = match env {
+                Some(env_key) => LinkMode::Unlink {
+                    env_key: env_key.clone(),
+                },
+                None => LinkMode::PruneOnly,
+            };
+            let report = EnvironmentManager::link_files_with(mode, cli.dry_run)?;
+            info!(
+                "Removed {} managed link(s), skipped {}",
+                report.removed, report.skipped
+            );
+            Ok(())
         }
-        Command::Link => EnvironmentManager::link_files(),
-        Command::Switch { name } => {
+        Command::History { json } => print_history(*json),
+        Command::Backups { action } => match action {
+            BackupsCommand::List { json } => print_backups(*json),
+            BackupsCommand::Restore { path } => restore_backup(path, cli.dry_run),
+        },
+        Command::Files {
+            action: FilesCommand::Conflicts { env, json },
+        } => print_file_conflicts(env.as_deref(), *json),
+        Command::Notices { action, json } => match action {
+            Some(NoticesCommand::Clear) => clear_notices(),
+            None => print_notices(*json),
+        },
+        Command::Schema { kind, write } => {
+            if *write {
+                for path in write_schemas(&schemas_dir(), &SchemaKind::ALL)? {
+                    info!("Wrote {}", path.display());
+                }
+            } else {
+                println!("{}", kind.to_json()?);
+            }
+            Ok(())
+        }
+        Command::Integrations { action } => match action {
+            IntegrationsCommand::List => print_integrations(),
+            IntegrationsCommand::Test { name, env } => test_integration(*name, env.as_deref()),
+            IntegrationsCommand::Run { name, env } => run_integration(*name, env.as_deref()),
+            IntegrationsCommand::Unquarantine { name, env } => {
+                unquarantine_integration(*name, env.as_deref())
+            }
+        },
+        Command::Prune => {
+            prune(cli.dry_run)?;
+            Ok(())
+        }
+        Command::Switch {
+            name,
+            no_link,
+            no_integrations,
+            only,
+            adopt_backups,
+            no_hooks,
+            login,
+            check_op_keys,
+            continue_on_error,
+        } => {
+            let opts = SwitchOptions {
+                dry_run: cli.dry_run,
+                link: !no_link,
+                adopt_backups: *adopt_backups,
+                integrations: match only {
+                    Some(kinds) => IntegrationSelection::Only(kinds.clone()),
+                    None if *no_integrations => IntegrationSelection::Only(vec![]),
+                    None => IntegrationSelection::All,
+                },
+                hooks: !no_hooks,
+                login: *login
+                    || (std::io::stdin().is_terminal() && std::io::stderr().is_terminal()),
+                check_op_keys: *check_op_keys,
+                failure_policy: match continue_on_error {
+                    true => FailurePolicy::ContinueOnError,
+                    false => FailurePolicy::Rollback,
+                },
+            };
+            let name = match name {
+                Some(name) => name.clone(),
+                None => {
+                    let environments = EnvironmentManager::list_environments()?;
+                    match pick_environment(&environments)? {
+                        Some(key) => key,
+                        None => {
+                            info!("Switch cancelled");
+                            return Ok(());
+                        }
+                    }
+                }
+            };
+            if name == "-" {
+                return EnvironmentManager::switch_previous_environment(&opts);
+            }
             if name == BASE_ENV_NAME {
-                return EnvironmentManager::switch_base_environment();
+                return EnvironmentManager::switch_base_environment(&opts);
+            }
+            EnvironmentManager::switch_environment_by_key(&name, &opts)
+        }
+        Command::ExportEnv {
+            key,
+            format,
+            output,
+            resolve_secrets,
+            include_secrets,
+        } => export_env(&ExportOptions {
+            key: key.clone(),
+            format: *format,
+            output: output.clone(),
+            resolve_secrets: *resolve_secrets,
+            include_secrets: *include_secrets,
+            timeout: Duration::from_secs(GlobalConfig::load()?.value_command_timeout_secs),
+        }),
+        Command::Direnv { action } => {
+            match action {
+                DirenvCommand::Generate { env, path } => generate_envrc(env, path.as_deref())?,
+                DirenvCommand::Lib => {
+                    print!("{}", direnv_lib(bin_name, &config::envmgr_config_dir()))
+                }
+            }
+            Ok(())
+        }
+        Command::Prompt { json, generate } => {
+            match generate {
+                None => print_prompt(*json)?,
+                Some(PromptCommand::StarshipConfig) => print!("{}", starship_config(bin_name)),
+                Some(PromptCommand::OhMyPoshConfig) => {
+                    println!("{}", oh_my_posh_config(bin_name)?)
+                }
+            }
+            Ok(())
+        }
+        Command::Walkthrough { sandbox, restart } => {
+            walkthrough(*sandbox, *restart, bin_name, &mut TerminalPrompter)
+        }
+        Command::Doctor { skip_op_keys } => doctor(&DoctorOptions {
+            op_keys: !skip_op_keys,
+        }),
+        Command::Template { action } => {
+            let registry = TemplateRegistry::new(&config::envmgr_config_dir(), &SystemRunner);
+            match action {
+                TemplateCommand::Install { url, name } => {
+                    let remote = registry.install(url, name.as_deref())?;
+                    println!("Installed template {} at {}", remote.alias, remote.commit);
+                }
+                TemplateCommand::List => print_templates(&registry)?,
+                TemplateCommand::Update { alias } => {
+                    for remote in registry.update(alias.as_deref())? {
+                        println!("{} at {}", remote.alias, remote.commit);
+                    }
+                }
+                TemplateCommand::Trust { env } => {
+                    let origin = trust_environment(env)?;
+                    println!(
+                        "Trusted {env} (template {} at {}); its integrations apply on the next switch",
+                        origin.template, origin.commit
+                    );
+                }
+            }
+            Ok(())
+        }
+        Command::DebugBundle { action } => match action {
+            DebugBundleCommand::Create { out, include_files } => {
+                let state_dir = envmgr::platform::state_dir()
+                    .ok_or_else(|| EnvMgrError::DirError("state directory".into()))?;
+                let manifest = create_bundle(
+                    &config::envmgr_config_dir(),
+                    &state_dir,
+                    out,
+                    *include_files,
+                )?;
+                print_bundle_summary(out, &manifest);
+                Ok(())
+            }
+            DebugBundleCommand::Replay { bundle, dir } => {
+                let root = replay_bundle(bundle, dir)?;
+                println!("Replayed {} into {}", bundle.display(), dir.display());
+                println!(
+                    "  {}={}",
+                    config::CONFIG_DIR_ENV_VAR,
+                    root.config_dir.display()
+                );
+                println!(
+                    "  {}={}",
+                    envmgr::platform::STATE_DIR_ENV_VAR,
+                    root.state_dir.display()
+                );
+                Ok(())
+            }
+        },
+        Command::Daemon {
+            interval,
+            systemd_unit,
+        } => {
+            let interval = std::time::Duration::from_secs(*interval);
+            if *systemd_unit {
+                let bin_path = std::env::current_exe()?;
+                print!(
+                    "{}",
+                    daemon::systemd_unit(&bin_path.to_string_lossy(), interval)
+                );
+                return Ok(());
+            }
+            daemon::run(interval)
+        }
+        Command::Watch { debounce_ms, exec } => watch::run(
+            std::time::Duration::from_millis(*debounce_ms),
+            exec.as_deref(),
+        ),
+        Command::Explain { code } => match ErrorCode::parse(code) {
+            Some(code) => {
+                print!("{}", code.explanation());
+                Ok(())
+            }
+            None => {
+                let known: Vec<String> = ErrorCode::ALL.iter().map(|c| c.to_string()).collect();
+                Err(EnvMgrError::UnknownErrorCode(
+                    code.clone(),
+                    known.join(", "),
+                ))
+            }
+        },
+        Command::Validate => {
+            let report = validate_all()?;
+            print!("{}", report.render());
+            if report.has_errors() {
+                return Err(EnvMgrError::Validation(report.error_count()));
             }
-            EnvironmentManager::switch_environment_by_key(name)
+            Ok(())
         }
-        Command::Doctor => {
-            info!("Running health check.");
-            todo!("Implement doctor functionality");
+        Command::Diff { env_a, env_b, json } => {
+            let diff = EnvironmentDiff::between_keys(env_a, env_b)?;
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&diff)?);
+            } else {
+                print!("{}", diff.render());
+            }
+            Ok(())
         }
         Command::Completions { shell } => {
             let mut cmd = Args::command();
-            clap_complete::generate(*shell, &mut cmd, &bin_name, &mut std::io::stdout());
-            eprintln!(
-                "Usage: {bin_name} completions fish > ~/.config/fish/completions/{bin_name}.fish"
-            );
+            clap_complete::generate(*shell, &mut cmd, bin_name, &mut std::io::stdout());
+            if let Some(script) = dynamic_completions(*shell, bin_name) {
+                println!("{script}");
+            }
+            eprintln!("{}", completions_usage_hint(*shell, bin_name));
+            Ok(())
+        }
+        Command::CompleteEnvs => {
+            print_env_keys();
             Ok(())
         }
     }
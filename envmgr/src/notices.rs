@@ -0,0 +1,253 @@
+//! Problems hit while `use` ran from the prompt hook, kept until the user sees them.
+//!
+//! The hook's stderr scrolls away with the next prompt, so its errors are also recorded
+//! in `notices.yaml` in the state directory, one entry per error code and subject.
+//! Interactive commands mention entries that are new since they last did, and
+//! `envmgr notices` lists them until `envmgr notices clear` acknowledges them.
+
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+
+use crate::{
+    daemon::unix_now,
+    error::{EnvMgrError, EnvMgrResult, ErrorCode},
+    fs::{Fs, RealFs},
+    state::State,
+};
+
+const NOTICES_FILE_NAME: &str = "notices.yaml";
+
+/// Repeated occurrences of one problem
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Notice {
+    pub code: ErrorCode,
+    /// Environment that was current when it happened
+    pub subject: String,
+    /// Message of the latest occurrence
+    pub message: String,
+    /// Seconds since the unix epoch
+    pub first_seen: u64,
+    pub last_seen: u64,
+    pub count: u32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Notices {
+    #[serde(default)]
+    pub entries: Vec<Notice>,
+    /// When an interactive command last mentioned the entries
+    #[serde(default)]
+    pub announced_at: u64,
+}
+
+/// Whether `use` runs from the prompt hook rather than by hand.
+///
+/// Current hooks pass `--hook`; hooks saved by older versions are recognized by
+/// stdout going to `source` instead of a terminal.
+pub fn is_hook_context(hook_flag: bool, stdout_is_terminal: bool) -> bool {
+    hook_flag || !stdout_is_terminal
+}
+
+impl Notices {
+    fn file_path(dir: &Path) -> PathBuf {
+        dir.join(NOTICES_FILE_NAME)
+    }
+
+    /// Load the notices in `dir`. A missing or unreadable file counts as no notices,
+    /// these are hints and must never fail the command that looks at them.
+    pub(crate) fn load_from_dir(fs: &dyn Fs, dir: &Path) -> Self {
+        let path = Self::file_path(dir);
+        let content = match fs.read(&path) {
+            Ok(Some(content)) => content,
+            Ok(None) => return Self::default(),
+            Err(e) => {
+                debug!("Could not read {}: {e}", path.display());
+                return Self::default();
+            }
+        };
+        serde_norway::from_slice(&content).unwrap_or_else(|e| {
+            debug!("Ignoring unreadable {}: {e}", path.display());
+            Self::default()
+        })
+    }
+
+    /// Replace the file atomically, so a hook killed mid-write leaves the old notices
+    pub(crate) fn store_in_dir(&self, fs: &dyn Fs, dir: &Path) -> EnvMgrResult<()> {
+        let path = Self::file_path(dir);
+        if self.entries.is_empty() {
+            return match fs.remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+        fs.write_atomic(&path, serde_norway::to_string(self)?.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn load() -> Self {
+        Self::load_from_dir(&RealFs, &State::get_state_dir())
+    }
+
+    pub fn store(&self) -> EnvMgrResult<()> {
+        self.store_in_dir(&RealFs, &State::get_state_dir())
+    }
+
+    /// Record an occurrence, folding it into the entry with the same code and subject
+    pub fn record(&mut self, code: ErrorCode, subject: &str, message: &str, now: u64) {
+        match self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.code == code && entry.subject == subject)
+        {
+            Some(entry) => {
+                entry.message = message.to_string();
+                entry.last_seen = now;
+                entry.count = entry.count.saturating_add(1);
+            }
+            None => self.entries.push(Notice {
+                code,
+                subject: subject.to_string(),
+                message: message.to_string(),
+                first_seen: now,
+                last_seen: now,
+                count: 1,
+            }),
+        }
+    }
+
+    /// Entries that occurred since the last announcement
+    pub fn unannounced(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|entry| entry.last_seen > self.announced_at)
+            .count()
+    }
+
+    /// The banner for entries that occurred since the last one, marking them announced
+    pub fn announce(&mut self, bin_name: &str, now: u64) -> Option<String> {
+        let count = self.unannounced();
+        if count == 0 {
+            return None;
+        }
+        self.announced_at = now;
+        let issues = match count {
+            1 => "1 issue".to_string(),
+            _ => format!("{count} issues"),
+        };
+        Some(format!(
+            "{issues} occurred since your last command — run `{bin_name} notices` for details"
+        ))
+    }
+}
+
+/// Record `error` from a hook-driven `use`. Best effort, the hook's own error output
+/// stays as it was.
+pub fn record_hook_error(error: &EnvMgrError) {
+    let dir = State::get_state_dir();
+    let subject = State::get_state()
+        .map(|state| state.current_env_key)
+        .unwrap_or_default();
+    let mut notices = Notices::load_from_dir(&RealFs, &dir);
+    notices.record(error.code(), &subject, &error.to_string(), unix_now());
+    if let Err(e) = notices.store_in_dir(&RealFs, &dir) {
+        debug!("Could not record notice: {e}");
+    }
+}
+
+/// Warn about notices that are new since the last interactive command
+pub fn print_banner(bin_name: &str) {
+    let mut notices = Notices::load();
+    if let Some(banner) = notices.announce(bin_name, unix_now()) {
+        warn!("{banner}");
+        if let Err(e) = notices.store() {
+            debug!("Could not store notices: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::MemFs;
+
+    #[test]
+    fn test_record_deduplicates_by_code_and_subject() {
+        let mut notices = Notices::default();
+        notices.record(ErrorCode::E015, "work", "1 env var value(s)", 100);
+        notices.record(ErrorCode::E015, "work", "2 env var value(s)", 160);
+        notices.record(ErrorCode::E015, "home", "1 env var value(s)", 170);
+        notices.record(ErrorCode::E001, "work", "not found", 180);
+
+        assert_eq!(notices.entries.len(), 3);
+        assert_eq!(
+            notices.entries[0],
+            Notice {
+                code: ErrorCode::E015,
+                subject: "work".into(),
+                message: "2 env var value(s)".into(),
+                first_seen: 100,
+                last_seen: 160,
+                count: 2,
+            }
+        );
+        assert_eq!(notices.entries[1].count, 1);
+        assert_eq!(notices.entries[2].code, ErrorCode::E001);
+    }
+
+    #[test]
+    fn test_hook_context() {
+        assert!(is_hook_context(true, true));
+        assert!(is_hook_context(true, false));
+        // A hook saved before `--hook` existed pipes stdout into `source`
+        assert!(is_hook_context(false, false));
+        assert!(!is_hook_context(false, true));
+    }
+
+    #[test]
+    fn test_banner_only_for_new_occurrences() {
+        let mut notices = Notices::default();
+        assert_eq!(notices.announce("envmgr", 100), None);
+
+        notices.record(ErrorCode::E015, "work", "failed", 100);
+        notices.record(ErrorCode::E001, "home", "failed", 110);
+        assert_eq!(
+            notices.announce("envmgr", 120).as_deref(),
+            Some("2 issues occurred since your last command — run `envmgr notices` for details")
+        );
+        assert_eq!(notices.announce("envmgr", 130), None);
+
+        notices.record(ErrorCode::E015, "work", "failed again", 140);
+        assert_eq!(
+            notices.announce("em", 150).as_deref(),
+            Some("1 issue occurred since your last command — run `em notices` for details")
+        );
+    }
+
+    #[test]
+    fn test_no_banner_after_clear() {
+        let fs = MemFs::default();
+        let dir = Path::new("/state");
+        let mut notices = Notices::default();
+        notices.record(ErrorCode::E015, "work", "failed", 100);
+        notices.store_in_dir(&fs, dir).unwrap();
+        assert_eq!(Notices::load_from_dir(&fs, dir), notices);
+
+        // `notices clear`
+        Notices::default().store_in_dir(&fs, dir).unwrap();
+        assert_eq!(fs.content(&dir.join(NOTICES_FILE_NAME)), None);
+        assert_eq!(
+            Notices::load_from_dir(&fs, dir).announce("envmgr", 200),
+            None
+        );
+    }
+
+    #[test]
+    fn test_unreadable_file_counts_as_empty() {
+        let fs = MemFs::default().with_file("/state/notices.yaml", "entries: [half");
+        assert_eq!(
+            Notices::load_from_dir(&fs, Path::new("/state")),
+            Notices::default()
+        );
+    }
+}
@@ -0,0 +1,49 @@
+//! Confirmation tokens binding a reviewed plan to the run that applies it.
+//!
+//! A dry run prints a token derived from the plan it shows. Passing it back with
+//! `--confirm-token` applies the plan without prompting, but only if the freshly
+//! computed plan is still the same one.
+
+use sha2::{Digest, Sha256};
+
+use crate::error::{EnvMgrError, EnvMgrResult};
+
+/// Hex characters kept from the plan hash; short enough to copy, long enough not to collide
+const TOKEN_LEN: usize = 12;
+
+/// Token for `plan`, the canonical text of everything the run would change
+pub fn plan_token(plan: &str) -> String {
+    let mut token = hex::encode(Sha256::digest(plan.as_bytes()));
+    token.truncate(TOKEN_LEN);
+    token
+}
+
+/// Check that `token` was issued for `plan`
+pub fn verify_token(plan: &str, token: &str) -> EnvMgrResult<()> {
+    let current = plan_token(plan);
+    if current.eq_ignore_ascii_case(token.trim()) {
+        Ok(())
+    } else {
+        Err(EnvMgrError::ConfirmTokenMismatch {
+            token: token.to_string(),
+            current,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_roundtrip() {
+        let token = plan_token("merge old into new\n+ FOO=1");
+        assert_eq!(token.len(), TOKEN_LEN);
+        assert!(verify_token("merge old into new\n+ FOO=1", &token).is_ok());
+        assert!(verify_token("merge old into new\n+ FOO=1", &token.to_uppercase()).is_ok());
+        assert!(matches!(
+            verify_token("merge old into new\n+ FOO=2", &token),
+            Err(EnvMgrError::ConfirmTokenMismatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,89 @@
+//! Thin wrappers around platform specific filesystem operations.
+//!
+//! Everything that needs `std::os::unix` goes through here so the crate keeps
+//! compiling on Windows, where file linking is reported as unsupported at runtime.
+
+use std::path::{Path, PathBuf};
+
+use crate::error::EnvMgrResult;
+
+/// Whether envmgr can link files on this platform
+pub const SUPPORTS_LINKING: bool = cfg!(unix);
+
+/// Environment variable overriding the state directory
+pub const STATE_DIR_ENV_VAR: &str = "ENVMGR_STATE_DIR";
+
+/// Directory holding envmgr's runtime state
+///
+/// `ENVMGR_STATE_DIR` takes precedence. Falls back to the local data directory on
+/// platforms without a dedicated state directory (macOS, Windows).
+pub fn state_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os(STATE_DIR_ENV_VAR).filter(|d| !d.is_empty()) {
+        return Some(PathBuf::from(dir));
+    }
+    dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .map(|d| d.join("envmgr"))
+}
+
+/// Create a symbolic link at `target` pointing to `source`
+#[cfg(unix)]
+pub fn symlink(source: &Path, target: &Path) -> EnvMgrResult<()> {
+    std::os::unix::fs::symlink(source, target)?;
+    Ok(())
+}
+
+/// Create a symbolic link at `target` pointing to `source`
+#[cfg(not(unix))]
+pub fn symlink(_source: &Path, _target: &Path) -> EnvMgrResult<()> {
+    Err(crate::error::EnvMgrError::Unsupported(
+        "file linking is not supported on this platform yet".into(),
+    ))
+}
+
+/// Set the permission bits of `path`, e.g. `0o600` for files holding credentials
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> EnvMgrResult<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+/// Permission bits don't exist on this platform, so this only logs
+#[cfg(not(unix))]
+pub fn set_mode(path: &Path, mode: u32) -> EnvMgrResult<()> {
+    log::debug!(
+        "Ignoring mode {mode:o} for {} on this platform",
+        path.display()
+    );
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_symlink_and_set_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = std::env::temp_dir().join("envmgr_test_platform");
+        let _ = fs::remove_dir_all(&temp_dir);
+        fs::create_dir_all(&temp_dir).unwrap();
+        let source = temp_dir.join("source");
+        let target = temp_dir.join("target");
+        fs::write(&source, "content").unwrap();
+
+        symlink(&source, &target).unwrap();
+        assert_eq!(fs::read_link(&target).unwrap(), source);
+
+        set_mode(&source, 0o600).unwrap();
+        let mode = fs::metadata(&source).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        fs::remove_dir_all(&temp_dir).unwrap();
+    }
+}
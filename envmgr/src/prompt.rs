@@ -0,0 +1,232 @@
+use dialoguer::{Confirm, FuzzySelect, Input, MultiSelect, Select, theme::ColorfulTheme};
+
+use std::path::Path;
+
+use crate::{
+    environment::Environment,
+    error::{EnvMgrError, EnvMgrResult},
+};
+
+/// Source of answers for interactive flows, so they can be replayed in tests
+pub trait Prompter {
+    /// Ask for free text, pre-filled with `default` when given
+    fn input(&mut self, prompt: &str, default: Option<&str>) -> EnvMgrResult<String>;
+    /// Ask a yes/no question
+    fn confirm(&mut self, prompt: &str, default: bool) -> EnvMgrResult<bool>;
+    /// Pick one of `items`, `None` when cancelled
+    fn select(
+        &mut self,
+        prompt: &str,
+        items: &[String],
+        default: usize,
+    ) -> EnvMgrResult<Option<usize>>;
+    /// Pick any of `items`, those of `defaults` ticked at first, `None` when cancelled
+    fn multi_select(
+        &mut self,
+        prompt: &str,
+        items: &[String],
+        defaults: &[bool],
+    ) -> EnvMgrResult<Option<Vec<usize>>>;
+}
+
+/// [`Prompter`] backed by dialoguer on the terminal
+pub struct TerminalPrompter;
+
+impl Prompter for TerminalPrompter {
+    fn input(&mut self, prompt: &str, default: Option<&str>) -> EnvMgrResult<String> {
+        let theme = ColorfulTheme::default();
+        let mut input = Input::<String>::with_theme(&theme).with_prompt(prompt);
+        if let Some(default) = default {
+            input = input.default(default.to_string());
+        }
+        Ok(input.interact_text()?)
+    }
+
+    fn confirm(&mut self, prompt: &str, default: bool) -> EnvMgrResult<bool> {
+        Ok(Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(prompt)
+            .default(default)
+            .interact()?)
+    }
+
+    fn select(
+        &mut self,
+        prompt: &str,
+        items: &[String],
+        default: usize,
+    ) -> EnvMgrResult<Option<usize>> {
+        Ok(Select::with_theme(&ColorfulTheme::default())
+            .with_prompt(prompt)
+            .items(items)
+            .default(default)
+            .interact_opt()?)
+    }
+
+    fn multi_select(
+        &mut self,
+        prompt: &str,
+        items: &[String],
+        defaults: &[bool],
+    ) -> EnvMgrResult<Option<Vec<usize>>> {
+        Ok(MultiSelect::with_theme(&ColorfulTheme::default())
+            .with_prompt(prompt)
+            .items(items)
+            .defaults(defaults)
+            .interact_opt()?)
+    }
+}
+
+/// A scripted answer for [`ReplayPrompter`]
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub(crate) enum Answer {
+    Input(&'static str),
+    /// Accept the default of an input prompt
+    Default,
+    Confirm(bool),
+    Select(Option<usize>),
+    MultiSelect(Option<Vec<usize>>),
+}
+
+/// [`Prompter`] replaying scripted answers, panicking on unexpected prompts
+#[cfg(test)]
+pub(crate) struct ReplayPrompter {
+    answers: std::collections::VecDeque<Answer>,
+    pub prompts: Vec<String>,
+}
+
+#[cfg(test)]
+impl ReplayPrompter {
+    pub fn new(answers: impl IntoIterator<Item = Answer>) -> Self {
+        Self {
+            answers: answers.into_iter().collect(),
+            prompts: vec![],
+        }
+    }
+
+    fn next(&mut self, prompt: &str) -> Answer {
+        self.prompts.push(prompt.to_string());
+        self.answers
+            .pop_front()
+            .unwrap_or_else(|| panic!("no scripted answer for prompt '{prompt}'"))
+    }
+
+    pub fn assert_exhausted(&self) {
+        assert!(
+            self.answers.is_empty(),
+            "unused answers: {:?}",
+            self.answers
+        );
+    }
+}
+
+#[cfg(test)]
+impl Prompter for ReplayPrompter {
+    fn input(&mut self, prompt: &str, default: Option<&str>) -> EnvMgrResult<String> {
+        match self.next(prompt) {
+            Answer::Input(value) => Ok(value.to_string()),
+            Answer::Default => Ok(default
+                .unwrap_or_else(|| panic!("prompt '{prompt}' has no default"))
+                .to_string()),
+            other => panic!("expected input for '{prompt}', got {other:?}"),
+        }
+    }
+
+    fn confirm(&mut self, prompt: &str, _default: bool) -> EnvMgrResult<bool> {
+        match self.next(prompt) {
+            Answer::Confirm(value) => Ok(value),
+            other => panic!("expected confirm for '{prompt}', got {other:?}"),
+        }
+    }
+
+    fn select(
+        &mut self,
+        prompt: &str,
+        _items: &[String],
+        _default: usize,
+    ) -> EnvMgrResult<Option<usize>> {
+        match self.next(prompt) {
+            Answer::Select(value) => Ok(value),
+            other => panic!("expected select for '{prompt}', got {other:?}"),
+        }
+    }
+
+    fn multi_select(
+        &mut self,
+        prompt: &str,
+        _items: &[String],
+        _defaults: &[bool],
+    ) -> EnvMgrResult<Option<Vec<usize>>> {
+        match self.next(prompt) {
+            Answer::MultiSelect(value) => Ok(value),
+            other => panic!("expected multi-select for '{prompt}', got {other:?}"),
+        }
+    }
+}
+
+/// Open `path` in `$VISUAL` / `$EDITOR` and wait for the editor to exit
+pub fn open_in_editor(path: &Path) -> EnvMgrResult<()> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| if cfg!(windows) { "notepad" } else { "vi" }.to_string());
+    // Editors are often configured with arguments, e.g. `code --wait`
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().unwrap_or("vi");
+    let status = std::process::Command::new(program)
+        .args(parts)
+        .arg(path)
+        .status()?;
+    if !status.success() {
+        return Err(EnvMgrError::Other(
+            format!("editor '{editor}' exited with {status}").into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Let the user fuzzy-pick an environment, returning its key.
+///
+/// Returns `None` when the user cancels with escape.
+pub fn pick_environment(environments: &[(bool, Environment)]) -> EnvMgrResult<Option<String>> {
+    let items: Vec<String> = environments
+        .iter()
+        .map(|(current, env)| environment_label(*current, env))
+        .collect();
+    let default = environments
+        .iter()
+        .position(|(current, _)| *current)
+        .unwrap_or(0);
+
+    let selection = FuzzySelect::with_theme(&ColorfulTheme::default())
+        .with_prompt("Switch to environment")
+        .items(&items)
+        .default(default)
+        .interact_opt()?;
+
+    Ok(selection.map(|i| environments[i].1.key.clone()))
+}
+
+fn environment_label(current: bool, env: &Environment) -> String {
+    format!(
+        "{} {} - {}",
+        if current { "*" } else { " " },
+        env.key,
+        env.name
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_environment_label_marks_current() {
+        let env = Environment {
+            key: "work".to_string(),
+            name: "Work".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(environment_label(true, &env), "* work - Work");
+        assert_eq!(environment_label(false, &env), "  work - Work");
+    }
+}
@@ -0,0 +1,228 @@
+//! Running external commands behind a trait, so their output can be canned in tests.
+
+use std::{
+    cell::RefCell,
+    io::{self, Read},
+    process::{Child, Stdio},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+/// Held while a command is attached to the terminal, so concurrent integrations take
+/// turns with the user
+static INTERACTIVE: Mutex<()> = Mutex::new(());
+/// Interactive commands running or waiting for their turn
+static INTERACTIVE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether a command is attached to the terminal or waiting to be. Time spent on one
+/// is the user's, so integration timeouts don't count it.
+pub fn is_interactive() -> bool {
+    INTERACTIVE_COUNT.load(Ordering::SeqCst) > 0
+}
+
+thread_local! {
+    /// Set by [`stop_on`] for the commands this thread runs
+    static STOP: RefCell<Option<Arc<AtomicBool>>> = const { RefCell::new(None) };
+}
+
+/// How often a command that can be stopped is checked on
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Have [`SystemRunner`] kill the commands this thread runs once `flag` is set, e.g.
+/// after the integration running them timed out. Commands started afterwards fail
+/// right away.
+pub fn stop_on(flag: Arc<AtomicBool>) {
+    STOP.with(|stop| *stop.borrow_mut() = Some(flag));
+}
+
+fn stop_flag() -> Option<Arc<AtomicBool>> {
+    STOP.with(|stop| stop.borrow().clone())
+}
+
+fn stopped_error() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "stopped, it took too long")
+}
+
+/// Wait for `child` to exit and collect its output, killing it and waiting for it to
+/// be gone when the thread's [`stop_on`] flag is set
+fn wait_for(mut child: Child) -> io::Result<CommandOutput> {
+    let Some(flag) = stop_flag() else {
+        return CommandOutput::from_output(child.wait_with_output()?);
+    };
+    // Read on threads of their own so a full pipe doesn't block the command
+    let read = |pipe: Option<Box<dyn Read + Send>>| {
+        std::thread::spawn(move || {
+            let mut out = vec![];
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut out);
+            }
+            out
+        })
+    };
+    let stdout = read(child.stdout.take().map(|p| Box::new(p) as _));
+    let stderr = read(child.stderr.take().map(|p| Box::new(p) as _));
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if flag.load(Ordering::SeqCst) {
+            child.kill()?;
+            child.wait()?;
+            // The readers are left behind, a process it started may hold the pipes
+            return Err(stopped_error());
+        }
+        std::thread::sleep(STOP_POLL_INTERVAL);
+    };
+    CommandOutput::from_output(std::process::Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+/// Captured result of a finished command
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandOutput {
+    pub success: bool,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    fn from_output(output: std::process::Output) -> io::Result<Self> {
+        Ok(Self {
+            success: output.status.success(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
+}
+
+pub trait CommandRunner {
+    /// Run `program` with `args` to completion, capturing its output.
+    ///
+    /// A program that cannot be found is an `io::ErrorKind::NotFound` error.
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput>;
+
+    /// Like [`CommandRunner::run`], writing `stdin` to the command's standard input.
+    ///
+    /// Runners that can't feed input report `io::ErrorKind::Unsupported`.
+    fn run_with_stdin(
+        &self,
+        program: &str,
+        args: &[&str],
+        stdin: &str,
+    ) -> io::Result<CommandOutput> {
+        let _ = (program, args, stdin);
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Run `program` attached to the terminal, for commands that need the user, e.g.
+    /// a login. `env` is added to the environment. Returns whether it succeeded.
+    ///
+    /// Runners that can't attach report `io::ErrorKind::Unsupported`.
+    fn run_interactive(
+        &self,
+        program: &str,
+        args: &[&str],
+        env: &[(&str, &str)],
+    ) -> io::Result<bool> {
+        let _ = (program, args, env);
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// [`CommandRunner`] spawning real processes
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+        if stop_flag().is_some_and(|flag| flag.load(Ordering::SeqCst)) {
+            return Err(stopped_error());
+        }
+        let child = std::process::Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        wait_for(child)
+    }
+
+    fn run_with_stdin(
+        &self,
+        program: &str,
+        args: &[&str],
+        stdin: &str,
+    ) -> io::Result<CommandOutput> {
+        use std::io::Write;
+
+        if stop_flag().is_some_and(|flag| flag.load(Ordering::SeqCst)) {
+            return Err(stopped_error());
+        }
+        let mut child = std::process::Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // Dropped right after writing so the command sees the end of its input
+        child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(stdin.as_bytes())?;
+        wait_for(child)
+    }
+
+    fn run_interactive(
+        &self,
+        program: &str,
+        args: &[&str],
+        env: &[(&str, &str)],
+    ) -> io::Result<bool> {
+        INTERACTIVE_COUNT.fetch_add(1, Ordering::SeqCst);
+        let status = {
+            let _turn = INTERACTIVE.lock().unwrap_or_else(|e| e.into_inner());
+            std::process::Command::new(program)
+                .args(args)
+                .envs(env.iter().copied())
+                .status()
+        };
+        INTERACTIVE_COUNT.fetch_sub(1, Ordering::SeqCst);
+        Ok(status?.success())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn test_setting_the_stop_flag_kills_the_command() {
+        let flag = Arc::new(AtomicBool::new(false));
+        let stopper = flag.clone();
+        let started = Instant::now();
+
+        let result = std::thread::spawn(move || {
+            stop_on(flag);
+            let sleeping = SystemRunner.run("sleep", &["5"]);
+            // Nothing else starts once stopped
+            (sleeping, SystemRunner.run("true", &[]))
+        });
+        std::thread::sleep(Duration::from_millis(200));
+        stopper.store(true, Ordering::SeqCst);
+        let (sleeping, next) = result.join().unwrap();
+
+        assert_eq!(sleeping.unwrap_err().kind(), io::ErrorKind::Interrupted);
+        assert_eq!(next.unwrap_err().kind(), io::ErrorKind::Interrupted);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        // Without a flag commands run as before
+        assert!(SystemRunner.run("true", &[]).unwrap().success);
+    }
+}
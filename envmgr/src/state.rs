@@ -1,57 +1,483 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
 
-use crate::error::EnvMgrResult;
+use log::{debug, info, warn};
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+use crate::{
+    config::{AliasConfig, GlobalConfig},
+    environment::Environment,
+    error::EnvMgrResult,
+    fs::{Fs, RealFs},
+    integrations::quarantine::IntegrationFailures,
+};
+
+/// Version of the state file format written by this binary
+pub const STATE_VERSION: u32 = 3;
+const STATE_FILE_NAME: &str = "state.toml";
+/// State file read by envmgr 0.1.x (TOML content despite the extension).
+///
+/// Written next to [`STATE_FILE_NAME`] during the dual-write window so a downgraded
+/// binary keeps its managed files. Remove together with [`LegacyState`] in 0.3.
+const LEGACY_STATE_FILE_NAME: &str = "state.yaml";
+/// Maximum number of switches kept in [`State::history`]
+pub const HISTORY_CAP: usize = 50;
+
+/// A link `link` created or found in place, with what it links to
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, Clone, PartialEq)]
+#[serde(from = "ManagedFileEntry")]
+pub struct ManagedFile {
+    pub target: PathBuf,
+    /// The file or directory in `files/` it links. Empty for entries of state files
+    /// written before sources were recorded.
+    #[serde(default, skip_serializing_if = "is_empty_path")]
+    pub source: PathBuf,
+    /// The environment `source` belongs to, `base` included. Empty when unknown.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub env_key: String,
+}
+
+impl ManagedFile {
+    /// An entry known only by its target, as older state files and binaries have them
+    pub fn legacy(target: PathBuf) -> Self {
+        Self {
+            target,
+            source: PathBuf::new(),
+            env_key: String::new(),
+        }
+    }
+}
+
+fn is_empty_path(path: &Path) -> bool {
+    path.as_os_str().is_empty()
+}
+
+/// `managed_files` entries as read: plain target paths before version 3 of the format
+#[derive(serde::Deserialize, schemars::JsonSchema)]
+#[serde(untagged)]
+enum ManagedFileEntry {
+    Target(PathBuf),
+    Record {
+        target: PathBuf,
+        #[serde(default)]
+        source: PathBuf,
+        #[serde(default)]
+        env_key: String,
+    },
+}
+
+impl From<ManagedFileEntry> for ManagedFile {
+    fn from(entry: ManagedFileEntry) -> Self {
+        match entry {
+            ManagedFileEntry::Target(target) => ManagedFile::legacy(target),
+            ManagedFileEntry::Record {
+                target,
+                source,
+                env_key,
+            } => ManagedFile {
+                target,
+                source,
+                env_key,
+            },
+        }
+    }
+}
+
+/// Layer a variable `use` applied came from, in the order they are layered
+#[derive(
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum EnvVarSource {
+    Global,
+    Base,
+    /// Handed back by an integration, below the environment's own variables
+    Integration,
+    Environment,
+}
+
+/// Where a variable `use` applied came from, so it can be unset in reverse
+#[derive(
+    serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, Clone, Copy, PartialEq, Eq,
+)]
+pub struct AppliedEnvVar {
+    pub source: EnvVarSource,
+    /// The `order` it was set with
+    pub order: i32,
+}
+
+/// A file `link` copied into place instead of symlinking
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, Clone, PartialEq)]
+pub struct CopiedFile {
+    pub target: PathBuf,
+    pub source: PathBuf,
+    /// SHA-256 of the content written. A target that no longer has it was edited since,
+    /// and is neither rewritten nor removed.
+    pub hash: String,
+}
+
+/// A 1Password document a switch wrote, see `op_documents`
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, Clone, PartialEq)]
+pub struct ManagedDocument {
+    pub target: PathBuf,
+    /// `vault/item` it was fetched from
+    pub item: String,
+    /// Environment it belongs to, its documents are removed on switching away
+    pub env_key: String,
+    /// SHA-256 of the content written. A target that no longer has it was edited since,
+    /// and is neither rewritten nor removed.
+    pub hash: String,
+}
+
+/// A symlink in `files/` that `link` re-created as it is at `target`
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, Clone, PartialEq)]
+pub struct MirroredLink {
+    pub target: PathBuf,
+    /// What the created link points to, possibly a relative path
+    pub link: PathBuf,
+}
+
+/// A real file `link --adopt-backups` moved aside to link over it
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, Clone, PartialEq)]
+pub struct Backup {
+    /// Where the file was, and goes back to
+    pub target: PathBuf,
+    /// Where it is kept meanwhile
+    pub path: PathBuf,
+    /// Environment whose link replaced it
+    pub env_key: String,
+    /// Seconds since the unix epoch
+    pub created_at: u64,
+}
+
+/// One successful environment switch
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub from: String,
+    pub to: String,
+}
+
+/// What an integration had before envmgr first applied it
+#[derive(
+    serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, Clone, Default, PartialEq,
+)]
+pub struct IntegrationSnapshot {
+    /// `None` when it recorded nothing, e.g. when there was no file yet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, Clone)]
 pub struct State {
+    #[serde(default = "legacy_state_version")]
+    pub version: u32,
     pub current_env_key: String,
+    /// Name of the current environment, recorded at switch so prompts don't load configs
+    #[serde(default)]
+    pub current_env_name: Option<String>,
+    /// Whether the current environment is marked `danger`, recorded like the name
+    #[serde(default)]
+    pub current_env_danger: bool,
+    /// Environment that was active before the last switch, used by `switch -`
+    #[serde(default)]
+    pub previous_env_key: Option<String>,
     pub applied_env_vars: HashMap<String, String>,
-    pub managed_files: Vec<PathBuf>,
+    /// Source and order of the entries of `applied_env_vars`. State files written before
+    /// they were recorded don't have them.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub applied_env_var_origins: BTreeMap<String, AppliedEnvVar>,
+    /// Aliases `use` defined in the shell, removed again when no longer configured
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub applied_aliases: Vec<AliasConfig>,
+    /// Links envmgr may remove again, in target order
+    pub managed_files: Vec<ManagedFile>,
+    /// The entries of `managed_files` that link a whole directory, see `link_dirs`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub linked_dirs: Vec<PathBuf>,
+    /// The entries of `managed_files` that re-create a symlink of `files/`. They are
+    /// envmgr's while unchanged, even when they point outside the config dir.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mirrored_links: Vec<MirroredLink>,
+    /// Files copied rather than symlinked, see [`crate::config::LinkKind::Copy`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub copied_files: Vec<CopiedFile>,
+    /// 1Password documents written by the last switch
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub managed_documents: Vec<ManagedDocument>,
+    /// Files moved aside for links, oldest first. The latest one of a target is put back
+    /// when its link is removed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub backups: Vec<Backup>,
+    /// Environment a switch or link run is applying, set before its first change and
+    /// cleared after the last. Still set on load means that run was interrupted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub applying: Option<String>,
+    /// Past switches, oldest first, at most [`HISTORY_CAP`] entries
+    #[serde(default)]
+    pub history: Vec<HistoryEntry>,
+    /// Keys envmgr pushed into the systemd user manager, unset again on the next switch
+    #[serde(default)]
+    pub systemd_user_env: Vec<String>,
+    /// Integrations that failed on their last switches, see [`crate::integrations::quarantine`]
+    #[serde(default)]
+    pub integration_failures: Vec<IntegrationFailures>,
+    /// Env vars the integrations handed back when they last ran, by config key. `use`
+    /// exports them below the environment's own.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub integration_env_vars: BTreeMap<String, Vec<(String, String)>>,
+    /// What the integrations had before envmgr first applied them, by config key, put
+    /// back when switching to an environment without them
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub integration_snapshots: BTreeMap<String, IntegrationSnapshot>,
+    /// Steps of `envmgr walkthrough` that were done or skipped, so it can be resumed
+    #[serde(default)]
+    pub walkthrough_steps: Vec<String>,
+    /// SHA-256 of the legacy file this state replaced. A legacy file still matching it
+    /// was left behind by an interrupted write rather than changed by an older binary.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stale_legacy_digest: Option<String>,
+}
+
+fn legacy_state_version() -> u32 {
+    1
 }
 
 impl Default for State {
     fn default() -> Self {
         Self {
+            version: STATE_VERSION,
             current_env_key: crate::config::BASE_ENV_NAME.to_string(),
+            current_env_name: None,
+            current_env_danger: false,
+            previous_env_key: None,
             applied_env_vars: HashMap::new(),
+            applied_env_var_origins: BTreeMap::new(),
+            applied_aliases: Vec::new(),
             managed_files: Vec::new(),
+            linked_dirs: Vec::new(),
+            mirrored_links: Vec::new(),
+            copied_files: Vec::new(),
+            managed_documents: Vec::new(),
+            backups: Vec::new(),
+            applying: None,
+            history: Vec::new(),
+            systemd_user_env: Vec::new(),
+            integration_failures: Vec::new(),
+            integration_env_vars: BTreeMap::new(),
+            integration_snapshots: BTreeMap::new(),
+            walkthrough_steps: Vec::new(),
+            stale_legacy_digest: None,
         }
     }
 }
 
-impl State {
-    fn get_state_file_path() -> PathBuf {
-        let envmgr_state_dir = dirs::state_dir()
-            .expect("Could not determine state directory")
-            .join("envmgr");
-        if !envmgr_state_dir.exists() {
-            std::fs::create_dir_all(&envmgr_state_dir).expect("Could not create state directory");
+/// The subset of [`State`] that envmgr 0.1.x understands
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+struct LegacyState {
+    current_env_key: String,
+    applied_env_vars: HashMap<String, String>,
+    managed_files: Vec<PathBuf>,
+}
+
+impl From<&State> for LegacyState {
+    fn from(state: &State) -> Self {
+        Self {
+            current_env_key: state.current_env_key.clone(),
+            applied_env_vars: state.applied_env_vars.clone(),
+            managed_files: state.managed_target_paths(),
         }
-        envmgr_state_dir.join("state.yaml")
+    }
+}
+
+fn legacy_digest(content: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+
+    hex::encode(Sha256::digest(content))
+}
+
+impl From<LegacyState> for State {
+    fn from(legacy: LegacyState) -> Self {
+        let mut state = State::default();
+        state.apply_legacy(legacy);
+        state
+    }
+}
+
+impl State {
+    pub(crate) fn get_state_dir() -> PathBuf {
+        crate::platform::state_dir().expect("Could not determine state directory")
     }
 
     pub fn get_state() -> EnvMgrResult<Self> {
-        let state_file_path = Self::get_state_file_path();
-        if !state_file_path.exists() {
-            eprintln!("State file does not exist, returning default state");
-            return Ok(State::default());
+        let dual_write = GlobalConfig::load()?.legacy_state_dual_write;
+        Self::load_from_dir(&RealFs, &Self::get_state_dir(), dual_write)
+    }
+
+    pub fn store_state(&self) -> EnvMgrResult<()> {
+        let dual_write = GlobalConfig::load()?.legacy_state_dual_write;
+        self.store_in_dir(&RealFs, &Self::get_state_dir(), dual_write)
+    }
+
+    /// Make `env` the current environment, returning the key of the previous one.
+    ///
+    /// Also records what `envmgr prompt` shows, so it never has to load configs.
+    pub fn set_current(&mut self, env: &Environment) -> String {
+        self.current_env_name = Some(env.name.clone());
+        self.current_env_danger = env.danger;
+        std::mem::replace(&mut self.current_env_key, env.key.clone())
+    }
+
+    /// Record a switch from `from` to `to`, dropping the oldest entries beyond the cap
+    pub fn record_switch(&mut self, from: &str, to: &str, timestamp: u64) {
+        self.history.push(HistoryEntry {
+            timestamp,
+            from: from.to_string(),
+            to: to.to_string(),
+        });
+        Self::cap_history(&mut self.history);
+    }
+
+    fn cap_history(history: &mut Vec<HistoryEntry>) {
+        if history.len() > HISTORY_CAP {
+            history.drain(..history.len() - HISTORY_CAP);
         }
+    }
 
-        let state: State = toml::from_slice(&std::fs::read(state_file_path)?)?;
+    /// The targets of `managed_files`
+    pub fn managed_target_paths(&self) -> Vec<PathBuf> {
+        self.managed_files
+            .iter()
+            .map(|managed| managed.target.clone())
+            .collect()
+    }
 
-        Ok(state)
+    /// Whether `target` is one of `managed_files`
+    pub fn is_managed(&self, target: &Path) -> bool {
+        self.managed_files
+            .iter()
+            .any(|managed| managed.target == target)
     }
 
-    pub fn store_state(&self) -> EnvMgrResult<()> {
-        let envmgr_state_dir = dirs::state_dir()
-            .expect("Could not determine state directory")
-            .join("envmgr");
-        if !envmgr_state_dir.exists() {
-            std::fs::create_dir_all(&envmgr_state_dir).expect("Could not create state directory");
+    /// Overwrite the fields known to older binaries, keeping newer-only fields. Managed
+    /// files the older binary kept keep their sources.
+    fn apply_legacy(&mut self, legacy: LegacyState) {
+        self.current_env_key = legacy.current_env_key;
+        self.applied_env_vars = legacy.applied_env_vars;
+        let mut known: HashMap<PathBuf, ManagedFile> = std::mem::take(&mut self.managed_files)
+            .into_iter()
+            .map(|managed| (managed.target.clone(), managed))
+            .collect();
+        self.managed_files = legacy
+            .managed_files
+            .into_iter()
+            .map(|target| {
+                known
+                    .remove(&target)
+                    .unwrap_or_else(|| ManagedFile::legacy(target))
+            })
+            .collect();
+    }
+
+    /// The legacy file at `path` and the digest of its content
+    fn read_legacy(fs: &dyn Fs, path: &Path) -> Option<(LegacyState, String)> {
+        let content = fs.read(path).ok()??;
+        match toml::from_slice(&content) {
+            Ok(legacy) => Some((legacy, legacy_digest(&content))),
+            Err(e) => {
+                warn!(
+                    "Ignoring unreadable legacy state file {}: {e}",
+                    path.display()
+                );
+                None
+            }
+        }
+    }
+
+    /// Load state from `dir`, preferring the current format.
+    ///
+    /// While `dual_write` is enabled a legacy file that no longer matches the projection of
+    /// the current state means an older binary ran in between; its changes win for the
+    /// fields it knows about.
+    pub(crate) fn load_from_dir(fs: &dyn Fs, dir: &Path, dual_write: bool) -> EnvMgrResult<Self> {
+        let state_file_path = dir.join(STATE_FILE_NAME);
+        let legacy_file_path = dir.join(LEGACY_STATE_FILE_NAME);
+
+        if let Some(content) = fs.read(&state_file_path)? {
+            let mut state: State = toml::from_slice(&content)?;
+            // Written back in the current format
+            state.version = state.version.max(STATE_VERSION);
+            if dual_write
+                && let Some((legacy, digest)) = Self::read_legacy(fs, &legacy_file_path)
+                && state.stale_legacy_digest.as_ref() != Some(&digest)
+                && legacy != LegacyState::from(&state)
+            {
+                info!("State was modified by an older envmgr version, reconciling");
+                state.apply_legacy(legacy);
+            }
+            return Ok(state);
         }
 
-        let state_file_path = envmgr_state_dir.join("state.yaml");
-        std::fs::write(state_file_path, toml::to_string_pretty(self)?)?;
+        if let Some(content) = fs.read(&legacy_file_path)? {
+            info!("Migrating legacy state file {}", legacy_file_path.display());
+            let legacy: LegacyState = toml::from_slice(&content)?;
+            return Ok(legacy.into());
+        }
+
+        debug!("State file does not exist, returning default state");
+        Ok(State::default())
+    }
+
+    /// Write the state to `dir`, creating it on first write only so read-only commands
+    /// and dry runs leave no trace.
+    ///
+    /// Both files are replaced atomically, the state first. A crash in between leaves the
+    /// previous legacy file, which [`State::load_from_dir`] recognizes by its digest.
+    pub(crate) fn store_in_dir(
+        &self,
+        fs: &dyn Fs,
+        dir: &Path,
+        dual_write: bool,
+    ) -> EnvMgrResult<()> {
+        let legacy_file_path = dir.join(LEGACY_STATE_FILE_NAME);
+        let mut stored = self.clone();
+        Self::cap_history(&mut stored.history);
+        stored.stale_legacy_digest = match dual_write {
+            true => fs.read(&legacy_file_path).ok().flatten(),
+            false => None,
+        }
+        .map(|content| legacy_digest(&content));
+        fs.write_atomic(
+            &dir.join(STATE_FILE_NAME),
+            toml::to_string_pretty(&stored)?.as_bytes(),
+        )?;
+
+        if dual_write {
+            // Best effort: failing to write the legacy copy must not fail the command
+            let legacy = toml::to_string_pretty(&LegacyState::from(self))?;
+            if let Err(e) = fs.write_atomic(&legacy_file_path, legacy.as_bytes()) {
+                warn!(
+                    "Could not write legacy state file {}: {e}",
+                    legacy_file_path.display()
+                );
+            }
+        } else {
+            match fs.remove_file(&legacy_file_path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
         Ok(())
     }
 }
@@ -64,6 +490,7 @@ mod tests {
     fn test_state_default() {
         let state = State::default();
         assert_eq!(state.current_env_key, crate::config::BASE_ENV_NAME);
+        assert_eq!(state.previous_env_key, None);
         assert_eq!(state.applied_env_vars.len(), 0);
         assert_eq!(state.managed_files.len(), 0);
     }
@@ -72,6 +499,7 @@ mod tests {
     fn test_state_serialization_roundtrip() {
         let mut state = State {
             current_env_key: "test_env".to_string(),
+            previous_env_key: Some("other_env".to_string()),
             ..State::default()
         };
         state
@@ -80,20 +508,80 @@ mod tests {
         state
             .applied_env_vars
             .insert("KEY2".to_string(), "value2".to_string());
-        state
-            .managed_files
-            .push(PathBuf::from("/home/user/.config"));
+        state.managed_files.push(ManagedFile {
+            target: PathBuf::from("/home/user/.config/git/config"),
+            source: PathBuf::from("/home/user/.config/envmgr/base/files/.config/git/config"),
+            env_key: "base".to_string(),
+        });
 
         let serialized = toml::to_string(&state).unwrap();
         let deserialized: State = toml::from_str(&serialized).unwrap();
 
         assert_eq!(deserialized.current_env_key, "test_env");
+        assert_eq!(deserialized.previous_env_key.as_deref(), Some("other_env"));
         assert_eq!(deserialized.applied_env_vars.len(), 2);
         assert_eq!(
             deserialized.applied_env_vars.get("KEY1"),
             Some(&"value1".to_string())
         );
-        assert_eq!(deserialized.managed_files.len(), 1);
+        assert_eq!(deserialized.managed_files, state.managed_files);
+    }
+
+    #[test]
+    fn test_snapshots_without_content_survive_a_roundtrip() {
+        let mut state = State::default();
+        let snapshot = |content: Option<&str>| IntegrationSnapshot {
+            content: content.map(str::to_string),
+        };
+        state
+            .integration_snapshots
+            .insert("op_ssh".to_string(), snapshot(None));
+        state
+            .integration_snapshots
+            .insert("gh_cli".to_string(), snapshot(Some("{}")));
+
+        let serialized = toml::to_string(&state).unwrap();
+        let deserialized: State = toml::from_str(&serialized).unwrap();
+
+        assert_eq!(
+            deserialized.integration_snapshots,
+            state.integration_snapshots
+        );
+    }
+
+    #[test]
+    fn test_state_with_plain_managed_files_migrates() {
+        let dir = temp_state_dir("envmgr_test_state_plain_managed_files");
+        std::fs::write(
+            dir.join(STATE_FILE_NAME),
+            "version = 2\ncurrent_env_key = \"work\"\nmanaged_files = [\"/home/user/.bashrc\"]\n\n[applied_env_vars]\n",
+        )
+        .unwrap();
+
+        let mut state = State::load_from_dir(&RealFs, &dir, false).unwrap();
+        assert_eq!(
+            state.managed_files,
+            [ManagedFile::legacy(PathBuf::from("/home/user/.bashrc"))]
+        );
+        assert_eq!(state.version, STATE_VERSION);
+
+        // Written back as records, old and new entries alike
+        state.managed_files.push(ManagedFile {
+            target: PathBuf::from("/home/user/.gitconfig"),
+            source: PathBuf::from("/cfg/environments/work/files/.gitconfig"),
+            env_key: "work".to_string(),
+        });
+        state.store_in_dir(&RealFs, &dir, false).unwrap();
+        let stored = std::fs::read_to_string(dir.join(STATE_FILE_NAME)).unwrap();
+        assert!(stored.contains("[[managed_files]]"), "{stored}");
+        assert!(
+            stored.contains("target = \"/home/user/.bashrc\"\n\n"),
+            "{stored}"
+        );
+        let loaded = State::load_from_dir(&RealFs, &dir, false).unwrap();
+        assert_eq!(loaded.managed_files, state.managed_files);
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
     #[test]
@@ -106,4 +594,201 @@ mod tests {
         assert!(deserialized.applied_env_vars.is_empty());
         assert!(deserialized.managed_files.is_empty());
     }
+
+    #[test]
+    fn test_state_without_previous_env_key_deserializes() {
+        let serialized = "current_env_key = \"work\"\nmanaged_files = []\n\n[applied_env_vars]\n";
+        let deserialized: State = toml::from_str(serialized).unwrap();
+
+        assert_eq!(deserialized.current_env_key, "work");
+        assert_eq!(deserialized.previous_env_key, None);
+        assert!(deserialized.history.is_empty());
+    }
+
+    #[test]
+    fn test_history_is_capped() {
+        let mut state = State::default();
+        for i in 0..(HISTORY_CAP as u64 + 5) {
+            state.record_switch("base", &format!("env{i}"), i);
+        }
+        assert_eq!(state.history.len(), HISTORY_CAP);
+        assert_eq!(state.history[0].timestamp, 5);
+        assert_eq!(
+            state.history.last().unwrap().to,
+            format!("env{}", HISTORY_CAP + 4)
+        );
+    }
+
+    #[test]
+    fn test_history_cap_enforced_on_write() {
+        let dir = temp_state_dir("envmgr_test_state_history_cap");
+        let state = State {
+            history: (0..(HISTORY_CAP as u64 * 2))
+                .map(|i| HistoryEntry {
+                    timestamp: i,
+                    from: "a".to_string(),
+                    to: "b".to_string(),
+                })
+                .collect(),
+            ..State::default()
+        };
+
+        state.store_in_dir(&RealFs, &dir, false).unwrap();
+
+        let loaded = State::load_from_dir(&RealFs, &dir, false).unwrap();
+        assert_eq!(loaded.history.len(), HISTORY_CAP);
+        assert_eq!(loaded.history[0].timestamp, HISTORY_CAP as u64);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn temp_state_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_legacy_projection_keeps_known_fields() {
+        let mut state = State {
+            current_env_key: "work".to_string(),
+            ..State::default()
+        };
+        state
+            .applied_env_vars
+            .insert("KEY".to_string(), "value".to_string());
+        state.managed_files.push(ManagedFile {
+            target: PathBuf::from("/home/user/.bashrc"),
+            source: PathBuf::from("/cfg/base/files/.bashrc"),
+            env_key: "base".to_string(),
+        });
+
+        let legacy = LegacyState::from(&state);
+        let serialized = toml::to_string(&legacy).unwrap();
+
+        assert!(!serialized.contains("version"));
+        assert_eq!(legacy.current_env_key, "work");
+        assert_eq!(legacy.applied_env_vars, state.applied_env_vars);
+        assert_eq!(legacy.managed_files, [PathBuf::from("/home/user/.bashrc")]);
+        assert!(serialized.contains("managed_files = [\"/home/user/.bashrc\"]"));
+    }
+
+    #[test]
+    fn test_dual_write_produces_both_files() {
+        let dir = temp_state_dir("envmgr_test_state_dual_write");
+        State::default().store_in_dir(&RealFs, &dir, true).unwrap();
+
+        assert!(dir.join(STATE_FILE_NAME).exists());
+        assert!(dir.join(LEGACY_STATE_FILE_NAME).exists());
+
+        State::default().store_in_dir(&RealFs, &dir, false).unwrap();
+        assert!(!dir.join(LEGACY_STATE_FILE_NAME).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_prefers_current_format() {
+        let dir = temp_state_dir("envmgr_test_state_prefers_current");
+        let state = State {
+            current_env_key: "work".to_string(),
+            ..State::default()
+        };
+        state.store_in_dir(&RealFs, &dir, true).unwrap();
+
+        let loaded = State::load_from_dir(&RealFs, &dir, true).unwrap();
+        assert_eq!(loaded.current_env_key, "work");
+        assert_eq!(loaded.version, STATE_VERSION);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_migrates_legacy_only_state() {
+        let dir = temp_state_dir("envmgr_test_state_migrate_legacy");
+        std::fs::write(
+            dir.join(LEGACY_STATE_FILE_NAME),
+            "current_env_key = \"personal\"\nmanaged_files = [\"/tmp/a\"]\n\n[applied_env_vars]\n",
+        )
+        .unwrap();
+
+        let loaded = State::load_from_dir(&RealFs, &dir, true).unwrap();
+        assert_eq!(loaded.current_env_key, "personal");
+        assert_eq!(
+            loaded.managed_files,
+            [ManagedFile::legacy(PathBuf::from("/tmp/a"))]
+        );
+        assert_eq!(loaded.version, STATE_VERSION);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_legacy_left_behind_by_a_crash_is_not_reconciled() {
+        let dir = temp_state_dir("envmgr_test_state_crash_before_legacy");
+        State::default().store_in_dir(&RealFs, &dir, true).unwrap();
+
+        let state = State {
+            current_env_key: "work".to_string(),
+            ..State::default()
+        };
+        // Dies replacing the legacy file, after the state file was replaced:
+        // read legacy, create dir, write and rename the state, then create dir
+        let fs = crate::fs::CrashingFs::crash_at(4);
+        state.store_in_dir(&fs, &dir, true).unwrap();
+        assert!(fs.crashed());
+
+        let loaded = State::load_from_dir(&RealFs, &dir, true).unwrap();
+        assert_eq!(loaded.current_env_key, "work");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reconcile_after_older_binary_modified_state() {
+        let dir = temp_state_dir("envmgr_test_state_reconcile");
+
+        // New binary writes
+        let state = State {
+            version: 7,
+            current_env_key: "work".to_string(),
+            managed_files: vec![ManagedFile {
+                target: PathBuf::from("/tmp/work"),
+                source: PathBuf::from("/cfg/environments/work/files/work"),
+                env_key: "work".to_string(),
+            }],
+            ..State::default()
+        };
+        state.store_in_dir(&RealFs, &dir, true).unwrap();
+
+        // Old binary runs and only rewrites the legacy file
+        let (mut legacy, _) =
+            State::read_legacy(&RealFs, &dir.join(LEGACY_STATE_FILE_NAME)).unwrap();
+        legacy.current_env_key = "personal".to_string();
+        legacy.managed_files = vec![PathBuf::from("/tmp/work"), PathBuf::from("/tmp/personal")];
+        std::fs::write(
+            dir.join(LEGACY_STATE_FILE_NAME),
+            toml::to_string(&legacy).unwrap(),
+        )
+        .unwrap();
+
+        // New binary reads again
+        let loaded = State::load_from_dir(&RealFs, &dir, true).unwrap();
+        assert_eq!(loaded.current_env_key, "personal");
+        // Entries the older binary kept keep their sources
+        assert_eq!(
+            loaded.managed_files,
+            [
+                state.managed_files[0].clone(),
+                ManagedFile::legacy(PathBuf::from("/tmp/personal"))
+            ]
+        );
+        assert_eq!(loaded.version, 7, "newer-only fields must survive");
+
+        // Without dual-write the legacy file is not consulted
+        let loaded = State::load_from_dir(&RealFs, &dir, false).unwrap();
+        assert_eq!(loaded.current_env_key, "work");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
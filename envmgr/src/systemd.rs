@@ -0,0 +1,346 @@
+//! Pushing env vars into the systemd user manager, so apps started by the desktop
+//! session rather than a shell see them too.
+//!
+//! The running manager gets them through `systemctl --user`, and
+//! `~/.config/environment.d/50-envmgr.conf` keeps them for the next login.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+
+use crate::{config::EnvVarsConfig, error::EnvMgrResult, runner::CommandRunner};
+
+const ENVIRONMENT_D_FILE_NAME: &str = "50-envmgr.conf";
+const ENVIRONMENT_D_HEADER: &str = "# Written by envmgr on switch, changes are overwritten";
+
+/// `environment.d/50-envmgr.conf` in the user's config dir
+pub fn environment_d_path() -> Option<PathBuf> {
+    Some(
+        dirs::config_dir()?
+            .join("environment.d")
+            .join(ENVIRONMENT_D_FILE_NAME),
+    )
+}
+
+/// The environment.d file holding `set`, double quoted like systemd's shell-style parser
+/// expects
+pub fn render_environment_d(env_key: &str, set: &[(String, String)]) -> String {
+    let mut out = format!("{ENVIRONMENT_D_HEADER}\n# Environment: {env_key}\n");
+    for (key, value) in set {
+        let mut quoted = String::with_capacity(value.len() + 2);
+        for c in value.chars() {
+            if matches!(c, '\\' | '"' | '$' | '`') {
+                quoted.push('\\');
+            }
+            quoted.push(c);
+        }
+        out.push_str(&format!("{key}=\"{quoted}\"\n"));
+    }
+    out
+}
+
+/// The assignments of a file written by [`render_environment_d`]
+pub fn parse_environment_d(content: &str) -> BTreeMap<String, String> {
+    let mut vars = BTreeMap::new();
+    let mut lines = content.lines();
+    while let Some(line) = lines.next() {
+        if line.starts_with('#') {
+            continue;
+        }
+        let Some((key, rest)) = line.split_once("=\"") else {
+            continue;
+        };
+        // A value with newlines spans several lines
+        let mut raw = rest.to_string();
+        while !ends_quoted(&raw) {
+            let Some(next) = lines.next() else { break };
+            raw.push('\n');
+            raw.push_str(next);
+        }
+        let raw = raw.strip_suffix('"').unwrap_or(&raw);
+        let mut value = String::with_capacity(raw.len());
+        let mut chars = raw.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => value.extend(chars.next()),
+                c => value.push(c),
+            }
+        }
+        vars.insert(key.to_string(), value);
+    }
+    vars
+}
+
+/// Whether `raw` ends in a closing quote rather than an escaped one
+fn ends_quoted(raw: &str) -> bool {
+    let Some(rest) = raw.strip_suffix('"') else {
+        return false;
+    };
+    rest.chars().rev().take_while(|&c| c == '\\').count() % 2 == 0
+}
+
+/// Write `content` to the environment.d file at `path`, or remove the file when it is
+/// `None`, returning whether anything changed
+pub fn write_environment_d(path: &Path, content: Option<&str>) -> EnvMgrResult<bool> {
+    let current = match std::fs::read_to_string(path) {
+        Ok(current) => Some(current),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    if current.as_deref() == content {
+        return Ok(false);
+    }
+    match content {
+        Some(content) => {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(path, content)?;
+        }
+        None => std::fs::remove_file(path)?,
+    }
+    Ok(true)
+}
+
+/// What `switch` changes in the systemd user manager's environment
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SystemdEnvChanges {
+    pub set: Vec<(String, String)>,
+    /// Keys pushed by an earlier switch that are no longer wanted
+    pub unset: Vec<String>,
+}
+
+impl SystemdEnvChanges {
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty() && self.unset.is_empty()
+    }
+
+    /// Keys envmgr owns in the user manager once these changes are applied
+    pub fn applied_keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.set.iter().map(|(key, _)| key.clone()).collect();
+        keys.sort();
+        keys
+    }
+}
+
+/// Changes taking the user manager from the `applied` keys to `vars`.
+///
+/// Only keys envmgr pushed itself are ever unset, variables set by anything else
+/// are left alone.
+pub fn plan_systemd_env(vars: &[EnvVarsConfig], applied: &[String]) -> SystemdEnvChanges {
+    let mut unset: Vec<String> = applied
+        .iter()
+        .filter(|key| !vars.iter().any(|var| &var.key == *key))
+        .cloned()
+        .collect();
+    unset.sort();
+    SystemdEnvChanges {
+        set: vars
+            .iter()
+            .map(|var| (var.key.clone(), var.value.clone()))
+            .collect(),
+        unset,
+    }
+}
+
+/// Result of [`SystemdUser::apply`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SystemdOutcome {
+    Applied,
+    /// No reachable user manager (containers, non-systemd distros), nothing was changed
+    Unavailable(String),
+}
+
+/// `systemctl --user` behind a [`CommandRunner`]
+pub struct SystemdUser<'r> {
+    runner: &'r dyn CommandRunner,
+}
+
+impl<'r> SystemdUser<'r> {
+    pub fn new(runner: &'r dyn CommandRunner) -> Self {
+        Self { runner }
+    }
+
+    /// Apply `changes` with at most one `unset-environment` and one `set-environment` call
+    pub fn apply(&self, changes: &SystemdEnvChanges) -> EnvMgrResult<SystemdOutcome> {
+        if !changes.unset.is_empty() {
+            let keys: Vec<&str> = changes.unset.iter().map(String::as_str).collect();
+            if let SystemdOutcome::Unavailable(reason) =
+                self.systemctl("unset-environment", &keys)?
+            {
+                return Ok(SystemdOutcome::Unavailable(reason));
+            }
+        }
+        if !changes.set.is_empty() {
+            let assignments: Vec<String> = changes
+                .set
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect();
+            let assignments: Vec<&str> = assignments.iter().map(String::as_str).collect();
+            return self.systemctl("set-environment", &assignments);
+        }
+        Ok(SystemdOutcome::Applied)
+    }
+
+    fn systemctl(&self, verb: &str, args: &[&str]) -> EnvMgrResult<SystemdOutcome> {
+        let mut full = vec!["--user", verb];
+        full.extend_from_slice(args);
+        debug!(
+            "Running systemctl --user {verb} with {} argument(s)",
+            args.len()
+        );
+        let output = match self.runner.run("systemctl", &full) {
+            Ok(output) => output,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(SystemdOutcome::Unavailable(
+                    "systemctl is not installed".into(),
+                ));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if output.success {
+            Ok(SystemdOutcome::Applied)
+        } else {
+            Ok(SystemdOutcome::Unavailable(
+                output.stderr.trim().to_string(),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io};
+
+    use super::*;
+    use crate::runner::CommandOutput;
+
+    /// Records every call, failing all of them with `stderr` when set
+    #[derive(Default)]
+    struct RecordingRunner {
+        calls: RefCell<Vec<Vec<String>>>,
+        stderr: Option<&'static str>,
+    }
+
+    impl CommandRunner for RecordingRunner {
+        fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+            assert_eq!(program, "systemctl");
+            self.calls
+                .borrow_mut()
+                .push(args.iter().map(|a| a.to_string()).collect());
+            Ok(CommandOutput {
+                success: self.stderr.is_none(),
+                stdout: String::new(),
+                stderr: self.stderr.unwrap_or_default().to_string(),
+            })
+        }
+    }
+
+    fn var(key: &str, value: &str) -> EnvVarsConfig {
+        EnvVarsConfig {
+            key: key.to_string(),
+            value: value.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_plan_unsets_only_keys_envmgr_pushed() {
+        let applied = vec!["OLD".to_string(), "KUBECONFIG".to_string()];
+        let changes = plan_systemd_env(&[var("KUBECONFIG", "/k"), var("NEW", "n")], &applied);
+
+        assert_eq!(changes.unset, ["OLD"]);
+        assert_eq!(changes.applied_keys(), ["KUBECONFIG", "NEW"]);
+
+        // Switching to an environment that doesn't propagate cleans everything up
+        let changes = plan_systemd_env(&[], &applied);
+        assert_eq!(changes.unset, ["KUBECONFIG", "OLD"]);
+        assert!(changes.applied_keys().is_empty());
+    }
+
+    #[test]
+    fn test_apply_batches_calls() {
+        let runner = RecordingRunner::default();
+        let changes = SystemdEnvChanges {
+            set: vec![("A".into(), "1".into()), ("B".into(), "two words".into())],
+            unset: vec!["C".into(), "D".into()],
+        };
+
+        let outcome = SystemdUser::new(&runner).apply(&changes).unwrap();
+
+        assert_eq!(outcome, SystemdOutcome::Applied);
+        assert_eq!(
+            *runner.calls.borrow(),
+            [
+                vec!["--user", "unset-environment", "C", "D"],
+                vec!["--user", "set-environment", "A=1", "B=two words"],
+            ]
+        );
+        assert!(
+            SystemdUser::new(&runner)
+                .apply(&SystemdEnvChanges::default())
+                .is_ok()
+        );
+        assert_eq!(runner.calls.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_apply_without_user_manager() {
+        let runner = RecordingRunner {
+            stderr: Some("Failed to connect to bus: No medium found\n"),
+            ..Default::default()
+        };
+        let changes = SystemdEnvChanges {
+            set: vec![("A".into(), "1".into())],
+            unset: vec!["C".into()],
+        };
+
+        let outcome = SystemdUser::new(&runner).apply(&changes).unwrap();
+
+        assert_eq!(
+            outcome,
+            SystemdOutcome::Unavailable("Failed to connect to bus: No medium found".into())
+        );
+        // Gave up after the first call
+        assert_eq!(runner.calls.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_environment_d_round_trip() {
+        let set = vec![
+            ("KUBECONFIG".to_string(), "/home/me/.kube/work".to_string()),
+            ("PS".to_string(), "a \"b\" $HOME `x` \\".to_string()),
+            ("MULTI".to_string(), "one\ntwo\\".to_string()),
+        ];
+
+        let content = render_environment_d("work", &set);
+
+        assert!(content.starts_with(ENVIRONMENT_D_HEADER));
+        assert!(content.contains("KUBECONFIG=\"/home/me/.kube/work\"\n"));
+        assert!(content.contains(r#"PS="a \"b\" \$HOME \`x\` \\""#));
+        assert_eq!(
+            parse_environment_d(&content),
+            set.into_iter().collect::<BTreeMap<_, _>>()
+        );
+    }
+
+    #[test]
+    fn test_write_environment_d_writes_and_removes() {
+        let dir = std::env::temp_dir().join("envmgr_test_environment_d");
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("environment.d").join(ENVIRONMENT_D_FILE_NAME);
+        let content = render_environment_d("work", &[("A".into(), "1".into())]);
+
+        assert!(write_environment_d(&path, Some(&content)).unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), content);
+        assert!(!write_environment_d(&path, Some(&content)).unwrap());
+
+        assert!(write_environment_d(&path, None).unwrap());
+        assert!(!path.exists());
+        assert!(!write_environment_d(&path, None).unwrap());
+    }
+}
@@ -0,0 +1,230 @@
+//! Pushing env var changes into a running tmux server's global environment, so panes
+//! opened after a switch start with the new values instead of the server's old ones.
+
+use std::collections::HashMap;
+
+use log::debug;
+
+use crate::{
+    environment::EnvVarChange,
+    error::{EnvMgrError, EnvMgrResult},
+    runner::CommandRunner,
+};
+
+/// The part of `use`'s `changes` tmux needs: unsets, and sets whose value differs from
+/// `previous`. Keys in `skip`, e.g. secrets, never reach tmux.
+pub fn plan_tmux_env(
+    changes: &[EnvVarChange],
+    previous: &HashMap<String, String>,
+    skip: &[String],
+) -> Vec<EnvVarChange> {
+    changes
+        .iter()
+        .filter(|change| match change {
+            EnvVarChange::Unset(key) => !skip.contains(key),
+            EnvVarChange::Set(key, value) => {
+                !skip.contains(key) && previous.get(key) != Some(value)
+            }
+        })
+        .cloned()
+        .collect()
+}
+
+/// `value` as a tmux argument: one ending in `;` would end the command there
+fn escape_trailing_semicolon(value: &str) -> String {
+    match value.strip_suffix(';') {
+        Some(rest) => format!("{rest}\\;"),
+        None => value.to_string(),
+    }
+}
+
+/// Result of [`Tmux::apply`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TmuxOutcome {
+    Applied,
+    /// No tmux server is running, or tmux isn't installed; nothing was changed
+    NotRunning,
+}
+
+/// `tmux` behind a [`CommandRunner`]
+pub struct Tmux<'r> {
+    runner: &'r dyn CommandRunner,
+}
+
+impl<'r> Tmux<'r> {
+    pub fn new(runner: &'r dyn CommandRunner) -> Self {
+        Self { runner }
+    }
+
+    /// Whether a server is running, which is also the case inside tmux (`$TMUX`)
+    pub fn is_running(&self) -> bool {
+        self.runner
+            .run("tmux", &["has-session"])
+            .is_ok_and(|output| output.success)
+    }
+
+    /// Set and remove the variables of `changes` in the global environment, then
+    /// redraw the status line of every client when `refresh_client` is set
+    pub fn apply(
+        &self,
+        changes: &[EnvVarChange],
+        refresh_client: bool,
+    ) -> EnvMgrResult<TmuxOutcome> {
+        if changes.is_empty() {
+            return Ok(TmuxOutcome::Applied);
+        }
+        if !self.is_running() {
+            return Ok(TmuxOutcome::NotRunning);
+        }
+        for change in changes {
+            match change {
+                EnvVarChange::Set(key, value) => {
+                    let value = escape_trailing_semicolon(value);
+                    self.tmux(&["set-environment", "-g", key, &value])?
+                }
+                // -r also keeps a value the server inherited out of new panes
+                EnvVarChange::Unset(key) => self.tmux(&["set-environment", "-gr", key])?,
+            };
+        }
+        debug!("Updated {} variable(s) in tmux", changes.len());
+        if refresh_client {
+            self.refresh_clients();
+        }
+        Ok(TmuxOutcome::Applied)
+    }
+
+    fn tmux(&self, args: &[&str]) -> EnvMgrResult<String> {
+        let output = self.runner.run("tmux", args)?;
+        if !output.success {
+            return Err(EnvMgrError::Tmux(format!(
+                "tmux {} failed: {}",
+                args[0],
+                output.stderr.trim()
+            )));
+        }
+        Ok(output.stdout)
+    }
+
+    /// Only cosmetic, so a client that went away in between is ignored
+    fn refresh_clients(&self) {
+        let clients = match self.tmux(&["list-clients", "-F", "#{client_name}"]) {
+            Ok(clients) => clients,
+            Err(e) => {
+                debug!("Not refreshing tmux clients: {e}");
+                return;
+            }
+        };
+        for client in clients.lines().filter(|line| !line.is_empty()) {
+            if let Err(e) = self.tmux(&["refresh-client", "-S", "-t", client]) {
+                debug!("Not refreshing tmux client {client}: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io};
+
+    use super::*;
+    use crate::runner::CommandOutput;
+
+    /// A tmux server with two clients, or none when `running` is false
+    #[derive(Default)]
+    struct FakeTmux {
+        running: bool,
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl CommandRunner for FakeTmux {
+        fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+            assert_eq!(program, "tmux");
+            self.calls.borrow_mut().push(args.join(" "));
+            let stdout = match args {
+                ["list-clients", ..] => "/dev/pts/1\n/dev/pts/4\n",
+                _ => "",
+            };
+            Ok(CommandOutput {
+                success: self.running,
+                stdout: stdout.into(),
+                stderr: if self.running {
+                    ""
+                } else {
+                    "no server running"
+                }
+                .into(),
+            })
+        }
+    }
+
+    fn set(key: &str, value: &str) -> EnvVarChange {
+        EnvVarChange::Set(key.into(), value.into())
+    }
+
+    #[test]
+    fn test_plan_tmux_env_only_keeps_changes() {
+        let previous = HashMap::from([
+            ("SAME".to_string(), "1".to_string()),
+            ("CHANGED".to_string(), "old".to_string()),
+        ]);
+        let changes = [
+            EnvVarChange::Unset("GONE".into()),
+            set("SAME", "1"),
+            set("CHANGED", "new"),
+            set("ADDED", "x"),
+            set("TOKEN", "secret"),
+        ];
+
+        assert_eq!(
+            plan_tmux_env(&changes, &previous, &["TOKEN".to_string()]),
+            vec![
+                EnvVarChange::Unset("GONE".into()),
+                set("CHANGED", "new"),
+                set("ADDED", "x"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_sets_removes_and_refreshes() {
+        let tmux = FakeTmux {
+            running: true,
+            ..Default::default()
+        };
+        let changes = [EnvVarChange::Unset("GONE".into()), set("A", "x y;")];
+
+        assert_eq!(
+            Tmux::new(&tmux).apply(&changes, true).unwrap(),
+            TmuxOutcome::Applied
+        );
+        assert_eq!(
+            *tmux.calls.borrow(),
+            [
+                "has-session",
+                "set-environment -gr GONE",
+                "set-environment -g A x y\\;",
+                "list-clients -F #{client_name}",
+                "refresh-client -S -t /dev/pts/1",
+                "refresh-client -S -t /dev/pts/4",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_is_a_no_op_without_a_server() {
+        let tmux = FakeTmux::default();
+
+        assert_eq!(
+            Tmux::new(&tmux).apply(&[set("A", "x")], true).unwrap(),
+            TmuxOutcome::NotRunning
+        );
+        assert_eq!(*tmux.calls.borrow(), ["has-session"]);
+
+        // Nothing to change doesn't even look for a server
+        assert_eq!(
+            Tmux::new(&tmux).apply(&[], true).unwrap(),
+            TmuxOutcome::Applied
+        );
+        assert_eq!(tmux.calls.borrow().len(), 1);
+    }
+}
@@ -0,0 +1,284 @@
+//! `envmgr watch`: re-link the active environment whenever its config changes.
+//!
+//! Changes are debounced: a run starts once no change arrived for the debounce time, so
+//! a `git pull` in the config dir changing many files at once leads to a single run.
+
+use std::{
+    path::{Component, Path},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, RecvTimeoutError},
+    },
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+
+use crate::{
+    config::{
+        BASE_ENV_NAME, ENV_CONFIG_FILE_NAME, ENVS_DIR_NAME, FILES_DIR_NAME,
+        GLOBAL_CONFIG_FILE_NAME, LOCAL_CONFIG_FILE_NAME, envmgr_config_dir,
+        files_manifest::FILES_MANIFEST_FILE_NAME,
+    },
+    environment::{ENV_KEY_VAR, EnvironmentManager, LinkOptions, shell_command},
+    error::{EnvMgrError, EnvMgrResult},
+    platform,
+    state::State,
+};
+
+/// How often the loop checks for Ctrl-C while nothing changes
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Whether a change of `path` can change what the environment `env_key` links: its
+/// or base's config.yaml, local.yaml, files.yaml or anything in their `files/`, or
+/// global.yaml. Nothing inside a `.git` directory counts.
+pub fn is_relevant(path: &Path, config_dir: &Path, env_key: &str) -> bool {
+    let Ok(relative) = path.strip_prefix(config_dir) else {
+        return false;
+    };
+    let parts: Vec<&str> = relative
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => part.to_str(),
+            _ => None,
+        })
+        .collect();
+    if parts.contains(&".git") {
+        return false;
+    }
+    let in_env = match parts.as_slice() {
+        [GLOBAL_CONFIG_FILE_NAME] => return true,
+        [BASE_ENV_NAME, rest @ ..] => rest,
+        [ENVS_DIR_NAME, key, rest @ ..] if *key == env_key => rest,
+        _ => return false,
+    };
+    matches!(
+        in_env,
+        [ENV_CONFIG_FILE_NAME | LOCAL_CONFIG_FILE_NAME | FILES_MANIFEST_FILE_NAME]
+            | [FILES_DIR_NAME, ..]
+    )
+}
+
+/// Whether `event` changed one of the paths `relevant` says matter. Reads are not
+/// changes, re-linking reads the files it links.
+fn is_change(event: &notify::Result<Event>, relevant: &mut impl FnMut(&Path) -> bool) -> bool {
+    match event {
+        Ok(event) if matches!(event.kind, EventKind::Access(_)) => false,
+        Ok(event) => event.paths.iter().any(|path| {
+            let changed = relevant(path);
+            if changed {
+                debug!("Changed: {}", path.display());
+            }
+            changed
+        }),
+        Err(e) => {
+            warn!("Watching the config dir failed: {e}");
+            false
+        }
+    }
+}
+
+/// Call `apply` once no further change arrived for `debounce` after a relevant change
+/// in `events`, until `stop` is set or the watcher goes away. Returns how often
+/// `apply` was called.
+pub fn watch_loop(
+    events: &Receiver<notify::Result<Event>>,
+    debounce: Duration,
+    stop: &AtomicBool,
+    mut relevant: impl FnMut(&Path) -> bool,
+    mut apply: impl FnMut(),
+) -> usize {
+    let mut runs = 0;
+    let mut last_change: Option<Instant> = None;
+    while !stop.load(Ordering::SeqCst) {
+        let timeout = last_change.map_or(STOP_POLL_INTERVAL, |at| {
+            debounce
+                .saturating_sub(at.elapsed())
+                .min(STOP_POLL_INTERVAL)
+        });
+        match events.recv_timeout(timeout) {
+            Ok(event) => {
+                if is_change(&event, &mut relevant) {
+                    last_change = Some(Instant::now());
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                if last_change.is_some() {
+                    apply();
+                    runs += 1;
+                }
+                break;
+            }
+        }
+        if let Some(at) = last_change
+            && at.elapsed() >= debounce
+            && !stop.load(Ordering::SeqCst)
+        {
+            apply();
+            runs += 1;
+            last_change = None;
+        }
+    }
+    runs
+}
+
+/// Re-link the current environment, then run `exec` through the shell
+fn reapply(exec: Option<&str>) {
+    let env_key = match State::get_state() {
+        Ok(state) => state.current_env_key,
+        Err(e) => {
+            warn!("Could not read the state, not re-linking: {e}");
+            return;
+        }
+    };
+    info!("Config of {env_key} changed, re-linking");
+    if let Err(e) = EnvironmentManager::link_files(&LinkOptions::default()) {
+        warn!("Re-linking {env_key} failed: {e}");
+        return;
+    }
+    let Some(command) = exec else {
+        return;
+    };
+    match shell_command(command).env(ENV_KEY_VAR, &env_key).status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("`{command}` exited with {status}"),
+        Err(e) => warn!("`{command}` could not be started: {e}"),
+    }
+}
+
+/// Watch the config dir in the foreground until SIGINT/SIGTERM, re-linking the active
+/// environment after changes have settled for `debounce`
+pub fn run(debounce: Duration, exec: Option<&str>) -> EnvMgrResult<()> {
+    if !platform::SUPPORTS_LINKING {
+        return Err(EnvMgrError::Unsupported(
+            "watch re-links files, which is not supported on this platform yet".into(),
+        ));
+    }
+    let config_dir = envmgr_config_dir();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher =
+        notify::recommended_watcher(tx).map_err(|e| EnvMgrError::Other(Box::new(e)))?;
+    watcher
+        .watch(&config_dir, RecursiveMode::Recursive)
+        .map_err(|e| EnvMgrError::Other(Box::new(e)))?;
+
+    static STOP: AtomicBool = AtomicBool::new(false);
+    ctrlc::set_handler(|| STOP.store(true, Ordering::SeqCst))
+        .map_err(|e| EnvMgrError::Other(e.into()))?;
+
+    info!("Watching {} for changes", config_dir.display());
+    let relevant = |path: &Path| {
+        // Read on every change, a switch in another shell changes what matters
+        State::get_state().is_ok_and(|state| is_relevant(path, &config_dir, &state.current_env_key))
+    };
+    watch_loop(&rx, debounce, &STOP, relevant, || reapply(exec));
+    info!("Stopped watching");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use notify::event::{AccessKind, AccessMode, ModifyKind};
+
+    use super::*;
+
+    #[test]
+    fn test_is_relevant() {
+        let root = Path::new("/cfg");
+        let relevant = |path: &str| is_relevant(&root.join(path), root, "work");
+
+        assert!(relevant("base/config.yaml"));
+        assert!(relevant("base/files/.config/fish/config.fish"));
+        assert!(relevant("environments/work/local.yaml"));
+        assert!(relevant("environments/work/files.yaml"));
+        assert!(relevant("environments/work/files"));
+        assert!(relevant("global.yaml"));
+
+        assert!(!relevant("environments/personal/config.yaml"));
+        assert!(!relevant("environments/work/hooks/post-switch"));
+        assert!(!relevant("environments/work/env.dotenv"));
+        assert!(!relevant("base/files/.git/index"));
+        assert!(!relevant(".git/HEAD"));
+        assert!(!relevant("README.md"));
+        assert!(!is_relevant(
+            Path::new("/elsewhere/base/config.yaml"),
+            root,
+            "work"
+        ));
+    }
+
+    fn event(kind: EventKind, path: &str) -> notify::Result<Event> {
+        Ok(Event::new(kind).add_path(PathBuf::from(path)))
+    }
+
+    fn modified(path: &str) -> notify::Result<Event> {
+        event(EventKind::Modify(ModifyKind::Any), path)
+    }
+
+    fn relevant(path: &Path) -> bool {
+        is_relevant(path, Path::new("/cfg"), "work")
+    }
+
+    #[test]
+    fn test_burst_of_changes_is_applied_once() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let stop = AtomicBool::new(false);
+        let mut applied = 0;
+
+        let runs = std::thread::scope(|scope| {
+            scope.spawn(move || {
+                // A checkout: many files changing in quick succession
+                for path in ["/cfg/.git/index", "/cfg/base/files/a", "/cfg/base/files/b"] {
+                    tx.send(modified(path)).unwrap();
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                std::thread::sleep(Duration::from_millis(500));
+                tx.send(modified("/cfg/base/config.yaml")).unwrap();
+            });
+            watch_loop(&rx, Duration::from_millis(100), &stop, relevant, || {
+                applied += 1
+            })
+        });
+
+        assert_eq!((runs, applied), (2, 2));
+    }
+
+    #[test]
+    fn test_reads_and_irrelevant_changes_are_ignored() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        tx.send(event(
+            EventKind::Access(AccessKind::Open(AccessMode::Any)),
+            "/cfg/base/files/a",
+        ))
+        .unwrap();
+        tx.send(modified("/cfg/environments/personal/config.yaml"))
+            .unwrap();
+        tx.send(Err(notify::Error::generic("lost"))).unwrap();
+        drop(tx);
+        let stop = AtomicBool::new(false);
+
+        let runs = watch_loop(&rx, Duration::ZERO, &stop, relevant, || {
+            panic!("nothing relevant changed")
+        });
+
+        assert_eq!(runs, 0);
+    }
+
+    #[test]
+    fn test_stop() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        tx.send(modified("/cfg/base/config.yaml")).unwrap();
+        let stop = AtomicBool::new(true);
+
+        let runs = watch_loop(&rx, Duration::ZERO, &stop, relevant, || {
+            panic!("stopped before")
+        });
+
+        assert_eq!(runs, 0);
+        drop(tx);
+    }
+}
